@@ -1,31 +1,124 @@
 use clap::ArgMatches;
+use debrepobuilder::config::{ConfigError, Profile};
+use debrepobuilder::{interrupt, BuildOptions, GcArea, Measure, ParsingError, Phase, RepoError};
+use debrepobuilder::Sample;
+use fern;
+use serde_json;
+use std::{env, io};
 
 /// Possible actions that the user may request when running the application.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Action<'a> {
+    /// Records the files of the pool whose provenance is unknown as legacy files.
+    BackfillProvenance,
     Build(Vec<&'a str>, bool),
+    BuildAll,
+    /// Builds the sources whose latest build in the suite failed.
+    BuildFailed,
+    CheckManifest(&'a str),
+    CheckPool,
     Clean,
+    CleanLogs,
+    /// Removes the binary packages which their sources no longer build.
+    CleanObsolete,
     Dist,
+    /// Checks what runs need of this system, without building anything.
+    Doctor,
+    Download(Vec<&'a str>),
+    EffectiveConfig,
     Fetch(&'a str),
     FetchConfig,
+    FetchKeys(Vec<&'a str>),
+    /// Collects the garbage of the working areas, or of every area if none are given.
+    Gc { areas: Vec<GcArea>, dry_run: bool },
+    Init(&'a str),
+    /// Shows where each file of the package came from.
+    Inspect(&'a str),
+    ListKeys,
+    KeyStatus,
+    /// Migrates packages from one suite to another, except those which are skipped, or only
+    /// those which are given.
+    Migrate { from: &'a str, to: &'a str, only: Vec<&'a str>, skip: Vec<&'a str>, dry_run: bool },
     Pool,
+    /// Rebuilds the named sources without changes, or every source, for the reason.
+    Rebuild(Vec<&'a str>, Option<&'a str>),
+    /// Lists every build of the package, newest first.
+    RecordHistory(&'a str),
+    /// Builds the named sources again, and compares what they built with the pool.
+    Reproduce(Vec<&'a str>),
+    /// Lists the latest build of each package of the suites, or only those which failed.
+    RecordList { suites: Vec<&'a str>, failed: bool },
+    Release,
     Remove(Vec<&'a str>),
+    Run(Vec<&'a str>, bool),
+    /// Fetches, builds, and publishes the sources whose latest build in the suite failed.
+    RunFailed,
+    /// Builds packages when they are requested over HTTP, listening on the address if given.
+    Serve(Option<&'a str>),
+    SimulateInstall,
+    /// Reports the metrics of the latest builds of the package in the suites, or how the
+    /// packages grew over their latest builds, by the measure, as a table, CSV, or JSON.
+    Stats {
+        package: Option<&'a str>,
+        suites: Vec<&'a str>,
+        window: usize,
+        by: Measure,
+        format: &'a str,
+    },
+    Status,
     Update(&'a str, &'a str),
     UpdateRepository,
+    /// Uploads the built packages which are queued for their upload targets, or only lists them.
+    Upload { dry_run: bool },
+    VerifyKeys,
 }
 
 impl<'a> Action<'a> {
     pub fn new(matches: &'a ArgMatches) -> Action<'a> {
         match matches.subcommand() {
+            ("run", Some(run)) => match run.subcommand() {
+                ("packages", Some(pkgs)) => {
+                    Action::Run(pkgs.values_of("packages").unwrap().collect(), pkgs.is_present("force"))
+                }
+                _ if run.is_present("failed") => Action::RunFailed,
+                _ => Action::UpdateRepository
+            }
+            ("rebuild", Some(rebuild)) if rebuild.is_present("reproduce") => {
+                Action::Reproduce(rebuild.values_of("reproduce").unwrap().collect())
+            }
+            ("rebuild", Some(rebuild)) => Action::Rebuild(
+                rebuild.values_of("packages").map_or(Vec::new(), |x| x.collect()),
+                rebuild.value_of("reason"),
+            ),
+            ("fetch", Some(fetch)) => match fetch.values_of("packages") {
+                Some(packages) => Action::Download(packages.collect()),
+                None => Action::Pool,
+            }
             ("build", Some(build)) => match build.subcommand() {
                 ("packages", Some(pkgs)) => {
                     Action::Build(pkgs.values_of("packages").unwrap().collect(), pkgs.is_present("force"))
                 }
                 ("pool", _) => Action::Pool,
                 ("dist", _) => Action::Dist,
-                _ => Action::UpdateRepository
+                _ if build.is_present("failed") => Action::BuildFailed,
+                _ => Action::BuildAll
+            }
+            ("publish", _) => Action::Dist,
+            ("release", _) => Action::Release,
+            ("init", Some(init)) => Action::Init(init.value_of("directory").unwrap_or(".")),
+            ("check", Some(check)) if check.is_present("apt-sim") => Action::SimulateInstall,
+            ("check", Some(check)) => match check.value_of("manifest") {
+                Some(manifest) => Action::CheckManifest(manifest),
+                None => Action::CheckPool,
+            }
+            ("clean", Some(clean)) if clean.is_present("logs") => Action::CleanLogs,
+            ("clean", Some(clean)) if clean.is_present("obsolete-binaries") => {
+                Action::CleanObsolete
             }
             ("clean", _) => Action::Clean,
+            ("config", Some(config)) if config.is_present("effective") => {
+                Action::EffectiveConfig
+            }
             ("config", Some(config)) => {
                 config.value_of("key").map_or(Action::FetchConfig, |key| {
                     config.value_of("value").map_or(Action::Fetch(key), |value| {
@@ -33,10 +126,307 @@ impl<'a> Action<'a> {
                     })
                 })
             }
+            ("gc", Some(gc)) => Action::Gc {
+                areas: match gc.values_of("area") {
+                    Some(areas) => areas.filter_map(GcArea::from_name).collect(),
+                    None => GcArea::ALL.to_vec(),
+                },
+                dry_run: gc.is_present("dry-run"),
+            },
+            ("keys", Some(keys)) => match keys.subcommand() {
+                ("fetch", Some(fetch)) => {
+                    Action::FetchKeys(fetch.values_of("keys").map_or(Vec::new(), |x| x.collect()))
+                }
+                ("verify", _) => Action::VerifyKeys,
+                ("status", _) => Action::KeyStatus,
+                _ => Action::ListKeys,
+            }
+            ("migrate", Some(migrate)) => Action::Migrate {
+                from: migrate.value_of("from").unwrap(),
+                to: migrate.value_of("to").unwrap(),
+                only: migrate.values_of("only").map_or(Vec::new(), |x| x.collect()),
+                skip: migrate.values_of("skip").map_or(Vec::new(), |x| x.collect()),
+                dry_run: migrate.is_present("dry-run"),
+            },
+            ("record", Some(record)) => match record.subcommand() {
+                ("history", Some(history)) => {
+                    Action::RecordHistory(history.value_of("package").unwrap())
+                }
+                ("backfill", _) => Action::BackfillProvenance,
+                ("list", Some(list)) => Action::RecordList {
+                    suites: list.values_of("suite").map_or(Vec::new(), |x| x.collect()),
+                    failed: list.is_present("failed"),
+                },
+                _ => unreachable!(),
+            }
+            ("doctor", _) => Action::Doctor,
+            ("inspect", Some(inspect)) => {
+                Action::Inspect(inspect.value_of("provenance").unwrap())
+            }
+            ("serve", Some(serve)) => Action::Serve(serve.value_of("listen")),
+            ("stats", Some(stats)) => Action::Stats {
+                package: stats.value_of("package"),
+                suites: stats.values_of("suite").map_or(Vec::new(), |x| x.collect()),
+                window: stats.value_of("window")
+                    .and_then(|window| window.parse().ok())
+                    .unwrap_or(Sample::DEFAULT_WINDOW),
+                by: match stats.value_of("by") {
+                    Some("deb-size") => Measure::DebSize,
+                    Some("installed-size") => Measure::InstalledSize,
+                    _ => Measure::Duration,
+                },
+                format: stats.value_of("format").unwrap_or("table"),
+            },
+            ("status", _) => Action::Status,
+            ("upload", Some(upload)) => Action::Upload { dry_run: upload.is_present("dry-run") },
             ("remove", Some(pkgs)) => {
                 Action::Remove(pkgs.values_of("packages").unwrap().collect())
             }
             _ => unreachable!()
         }
     }
+
+    /// The packages which the action works on, if it works on packages which were named.
+    pub fn packages(&self) -> Option<&[&'a str]> {
+        match *self {
+            Action::Build(ref packages, _) | Action::Run(ref packages, _) => Some(packages),
+            Action::Rebuild(ref packages, _) if !packages.is_empty() => Some(packages),
+            Action::Reproduce(ref packages) => Some(packages),
+            _ => None,
+        }
+    }
+
+    /// The action, working on these packages instead of those which were named.
+    pub fn with_packages(&self, packages: Vec<&'a str>) -> Action<'a> {
+        match *self {
+            Action::Build(_, force) => Action::Build(packages, force),
+            Action::Run(_, force) => Action::Run(packages, force),
+            Action::Rebuild(_, reason) => Action::Rebuild(packages, reason),
+            Action::Reproduce(_) => Action::Reproduce(packages),
+            ref action => action.clone(),
+        }
+    }
+
+    /// Whether the action changes the repo, so that it holds the repo lock while it works.
+    pub fn locks(&self) -> bool {
+        match *self {
+            Action::BackfillProvenance
+            | Action::Clean
+            | Action::CleanLogs
+            | Action::Gc { .. }
+            | Action::Remove(_) => true,
+            _ => !self.phases().is_empty(),
+        }
+    }
+
+    /// The phases which the action runs, whose commands are checked before it starts.
+    pub fn phases(&self) -> &'static [Phase] {
+        match *self {
+            Action::Run(..)
+            | Action::RunFailed
+            | Action::Rebuild(..)
+            | Action::UpdateRepository => {
+                &[Phase::Fetch, Phase::Build, Phase::Publish, Phase::Upload]
+            }
+            Action::Build(..)
+            | Action::BuildAll
+            | Action::BuildFailed
+            | Action::Reproduce(_) => &[Phase::Build],
+            Action::Download(..) | Action::Pool => &[Phase::Fetch],
+            Action::Dist | Action::Release => &[Phase::Publish, Phase::Upload],
+            Action::Migrate { .. } | Action::CleanObsolete => &[Phase::Publish],
+            Action::Upload { .. } => &[Phase::Upload],
+            _ => &[],
+        }
+    }
+}
+
+/// The suites which were selected with `--suite`, which may be given before or after the
+/// `packages` subcommand.
+pub fn suites<'a>(matches: &'a ArgMatches) -> Vec<&'a str> {
+    let build = ["run", "rebuild", "fetch", "build", "publish", "release", "upload"].iter()
+        .filter_map(|phase| matches.subcommand_matches(phase))
+        .next();
+    let packages = build.and_then(|build| build.subcommand_matches("packages"));
+    build.into_iter()
+        .chain(packages)
+        .flat_map(|matches| matches.values_of("suite").into_iter().flat_map(|suites| suites))
+        .collect()
+}
+
+/// Whether `--dry-run` was given to `run`, `rebuild`, or `build`, before or after the `packages`
+/// subcommand.
+pub fn is_dry_run(matches: &ArgMatches) -> bool {
+    ["run", "rebuild", "build"].iter()
+        .filter_map(|phase| matches.subcommand_matches(phase))
+        .any(|build| {
+            build.is_present("dry-run")
+                || build.subcommand_matches("packages").map_or(false, |x| x.is_present("dry-run"))
+        })
+}
+
+/// Collects the options for the phase which is run, which may be given before or after the
+/// `packages` subcommand.
+pub fn build_options(matches: &ArgMatches) -> BuildOptions {
+    let build = ["run", "rebuild", "build", "publish", "release"].iter()
+        .filter_map(|phase| matches.subcommand_matches(phase))
+        .next();
+    let packages = build.and_then(|build| build.subcommand_matches("packages"));
+    let is_present = |flag: &str| {
+        build.map_or(false, |m| m.is_present(flag)) || packages.map_or(false, |m| m.is_present(flag))
+    };
+
+    // Subprocess output is only written to logs in CI mode.
+    let ci = matches.is_present("ci");
+    BuildOptions {
+        follow: is_present("follow") && !ci,
+        deny_warnings: is_present("deny-warnings"),
+        ci,
+        no_sign: is_present("no-sign"),
+        unsigned: is_present("unsigned"),
+        detect_network: is_present("detect-network"),
+        strict: is_present("strict"),
+        replace_orig: is_present("replace-orig"),
+        replace_published: is_present("replace-published"),
+        offline: matches.is_present("offline") || is_present("offline"),
+        no_upload: matches.is_present("no-upload"),
+        keep_going: is_present("keep-going"),
+        fail_fast: is_present("fail-fast"),
+        no_cache: matches.is_present("no-cache"),
+        ignore_space_check: matches.is_present("ignore-space-check"),
+        override_freeze: ["clean", "remove", "migrate"].iter()
+            .filter_map(|action| matches.subcommand_matches(action))
+            .any(|action| action.is_present("override-freeze")),
+        legacy_records: matches.is_present("legacy-records"),
+        priority: priority(matches).unwrap_or(None),
+    }
+}
+
+/// The profile which was selected with `--profile`, or else with `DEBREP_PROFILE`.
+pub fn profile(matches: &ArgMatches) -> Option<String> {
+    matches.value_of("profile")
+        .map(|profile| profile.to_owned())
+        .or_else(|| {
+            env::var("DEBREP_PROFILE").ok().and_then(|profile| {
+                if profile.is_empty() { None } else { Some(profile) }
+            })
+        })
+}
+
+/// Turns on the flags of runs which the profile enables. Flags which were given on the command
+/// line stay on.
+pub fn apply_profile(options: &mut BuildOptions, profile: &Profile) {
+    let enabled = |flag: Option<bool>| flag.unwrap_or(false);
+    options.ci |= enabled(profile.ci);
+    options.follow = (options.follow || enabled(profile.follow)) && !options.ci;
+    options.deny_warnings |= enabled(profile.deny_warnings);
+    options.no_sign |= enabled(profile.no_sign);
+    options.unsigned |= enabled(profile.unsigned);
+    options.strict |= enabled(profile.strict);
+    options.keep_going |= enabled(profile.keep_going);
+    options.fail_fast |= enabled(profile.fail_fast);
+}
+
+/// The `packages` subcommand of `run` or `build`, if it was given.
+fn packages<'a>(matches: &'a ArgMatches) -> Option<&'a ArgMatches<'a>> {
+    ["run", "build"].iter()
+        .filter_map(|phase| matches.subcommand_matches(phase))
+        .next()
+        .and_then(|build| build.subcommand_matches("packages"))
+}
+
+/// The priority which the named packages are built at, if `--priority` was given.
+pub fn priority(matches: &ArgMatches) -> Result<Option<i32>, String> {
+    match packages(matches).and_then(|packages| packages.value_of("priority")) {
+        Some(value) => value.parse().map(Some)
+            .map_err(|_| format!("--priority must be a number, not {}", value)),
+        None => Ok(None),
+    }
+}
+
+/// Whether the named packages are built before the rest of the builds in progress, with
+/// `--preempt`.
+pub fn preempts(matches: &ArgMatches) -> bool {
+    packages(matches).map_or(false, |packages| packages.is_present("preempt"))
+}
+
+/// Why a command failed, which decides the code that debrep exits with.
+#[derive(Debug, Fail)]
+pub enum CliError {
+    #[fail(display = "cannot change to {}: {}", dir, why)]
+    Directory { dir: String, why: io::Error },
+    #[fail(display = "unable to set up logging: {}", why)]
+    Logging { why: fern::InitError },
+    #[fail(display = "{}", why)]
+    Usage { why: String },
+    #[fail(display = "{}; `debrep init` creates a new repo with an example config", why)]
+    NoConfig { why: ParsingError },
+    #[fail(display = "configuration parsing error: {}", why)]
+    Config { why: ParsingError },
+    #[fail(display = "{}", why)]
+    Show { why: ParsingError },
+    #[fail(display = "config field not found")]
+    NoField,
+    #[fail(display = "failed to update {}: {}", key, why)]
+    Update { key: String, why: ConfigError },
+    #[fail(display = "failed to write config changes: {}", why)]
+    Write { why: ParsingError },
+    #[fail(display = "{} is not a suite of this repo", suite)]
+    UnknownSuite { suite: String },
+    #[fail(display = "no provenance is recorded for {}", package)]
+    NoProvenance { package: String },
+    #[fail(display = "failed to serialize the report: {}", why)]
+    Json { why: serde_json::Error },
+    #[fail(display = "{}", why)]
+    Plan { why: String },
+    #[fail(display = "{}", why)]
+    Serve { why: String },
+    #[fail(display = "failed to ask the builds in progress to pause: {}", why)]
+    Preempt { why: io::Error },
+    #[fail(display = "failed to lock the repo: {}", why)]
+    Lock { why: io::Error },
+    #[fail(
+        display = "another debrep is working on this repo; --preempt builds packages before the \
+                   rest of its builds"
+    )]
+    Locked,
+    #[fail(display = "{}", why)]
+    Repo { why: RepoError },
+    #[fail(display = "{} checks failed", failed)]
+    Checks { failed: usize },
+    #[fail(display = "the run would fetch or build packages")]
+    Changes,
+    #[fail(display = "interrupted")]
+    Interrupted,
+    #[fail(display = "{} warnings were logged, and warnings are denied", count)]
+    Warnings { count: usize },
+}
+
+impl From<RepoError> for CliError {
+    fn from(why: RepoError) -> CliError {
+        CliError::Repo { why }
+    }
+}
+
+impl CliError {
+    /// The code which debrep exits with: 2 if a dry run would do work, so that whether the repo
+    /// is up to date can be checked, 130 if it was interrupted, and otherwise 1.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            CliError::Changes => 2,
+            CliError::Interrupted => 130,
+            _ if interrupt::is_interrupted() => 130,
+            _ => 1,
+        }
+    }
+
+    /// Reports the error. Errors from before logging was set up are written to stderr, and
+    /// those whose outcome was already printed are not reported again.
+    pub fn report(&self) {
+        match *self {
+            CliError::Directory { .. } | CliError::Logging { .. } => eprintln!("debrep: {}", self),
+            CliError::Checks { .. } | CliError::Changes | CliError::Interrupted => (),
+            _ => error!("{}", self),
+        }
+    }
 }
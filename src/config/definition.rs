@@ -0,0 +1,125 @@
+use std::fmt;
+use super::{Config, Direct, Mirror, Ppa, Repack, Source};
+
+/// Where an entry of the config is defined: the file, the TOML path of the entry, such as
+/// `source[3]`, and the line of its table header, so that errors about it can point at it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Definition {
+    pub file: &'static str,
+    pub path: String,
+    /// Unknown for entries which are not defined with a `[[table]]` header of their own.
+    pub line: Option<usize>,
+}
+
+impl fmt::Display for Definition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{} ({})", self.file, line, self.path),
+            None => write!(f, "{} ({})", self.file, self.path),
+        }
+    }
+}
+
+/// Entries of the config which know where they are defined.
+pub trait Defined {
+    fn definition(&mut self) -> &mut Definition;
+}
+
+macro_rules! defined {
+    ($($entry:ty),*) => {$(
+        impl Defined for $entry {
+            fn definition(&mut self) -> &mut Definition { &mut self.defined }
+        }
+    )*}
+}
+
+defined!(Direct, Mirror, Ppa, Repack, Source);
+
+/// Sets where each entry of the config is defined, from the text of the file that it was parsed
+/// from.
+pub fn locate(config: &mut Config, file: &'static str, text: &str) {
+    fn entries<T: Defined>(
+        entries: Option<&mut Vec<T>>,
+        table: &str,
+        file: &'static str,
+        text: &str,
+    ) {
+        let entries = match entries {
+            Some(entries) => entries,
+            None => return,
+        };
+
+        let lines = headers(text, table);
+        for (id, entry) in entries.iter_mut().enumerate() {
+            *entry.definition() = Definition {
+                file,
+                path: format!("{}[{}]", table, id),
+                line: lines.get(id).cloned(),
+            };
+        }
+    }
+
+    entries(config.direct.as_mut(), "direct", file, text);
+    entries(config.mirror.as_mut(), "mirror", file, text);
+    entries(config.ppa.as_mut(), "ppa", file, text);
+    entries(config.repack.as_mut(), "repack", file, text);
+    entries(config.source.as_mut(), "source", file, text);
+}
+
+/// The line numbers of the `[[table]]` headers of the array of tables.
+fn headers(text: &str, table: &str) -> Vec<usize> {
+    let header = ["[[", table, "]]"].concat();
+    text.lines()
+        .enumerate()
+        .filter(|&(_, line)| {
+            let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+            line.starts_with(&header)
+                && line[header.len()..].chars().next().map_or(true, |c| c == '#')
+        })
+        .map(|(number, _)| number + 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    const CONFIG: &str = r#"archive = "bionic"
+version = "18.04"
+origin = "pop-os-staging-ubuntu"
+label = "Pop!_OS Staging"
+email = "debrepbuild@example.com"
+
+[[direct]]
+name = "libfoo"
+version = "1.0"
+
+[[source]]
+name = "pop-shop"
+
+[[source.variants]]
+name = "stable"
+
+[[ source ]] # with a comment
+name = "pop-icons"
+"#;
+
+    #[test]
+    fn entries_know_where_they_are_defined() {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        locate(&mut config, "sources.toml", CONFIG);
+
+        let direct = &config.direct.as_ref().unwrap()[0];
+        assert_eq!(direct.defined.to_string(), "sources.toml:7 (direct[0])");
+
+        let sources = config.source.as_ref().unwrap();
+        assert_eq!(sources[0].defined.line, Some(11));
+        assert_eq!(sources[1].defined.to_string(), "sources.toml:17 (source[1])");
+
+        // Variants are defined where their source is.
+        let units = config.build_units();
+        assert_eq!(units[0].name, "pop-shop@stable");
+        assert_eq!(units[0].defined, sources[0].defined);
+    }
+}
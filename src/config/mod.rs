@@ -1,15 +1,30 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use toml::{self, de};
+use command::{self, Operation};
+use logging::LogTarget;
 use misc;
 
+mod definition;
+mod profile;
 mod source;
+mod suite;
+mod upload;
 
+pub use self::definition::{Defined, Definition};
+pub use self::profile::Profile;
 pub use self::source::*;
+pub use self::suite::*;
+pub use self::upload::*;
 
 /// Currently hard-coded to search for `sources.toml` in the current working directory.
-const SOURCES: &str = "sources.toml";
+pub const SOURCES: &str = "sources.toml";
+
+/// The component which packages are published in, unless their source or entry sets another.
+pub const DEFAULT_COMPONENT: &str = "main";
 
 #[derive(Debug, Fail)]
 pub enum ParsingError {
@@ -21,10 +36,45 @@ pub enum ParsingError {
     Toml { file: &'static str, why:  de::Error },
     #[fail(display = "failed to serialize into TOML: {}", why)]
     TomlSerialize { why: toml::ser::Error },
-    #[fail(display = "source URL and path defined for {}. Only one should be defined.", source)]
-    SourcePathAndUrlDefined { source: String },
-    #[fail(display = "neither a URL or path was defined for the source named {}", source)]
-    SourceNotDefined { source: String }
+    #[fail(
+        display = "{}: source URL and path defined for {}. Only one should be defined.",
+        defined, source
+    )]
+    SourcePathAndUrlDefined { source: String, defined: Definition },
+    #[fail(
+        display = "{}: neither a URL or path was defined for the source named {}",
+        defined, source
+    )]
+    SourceNotDefined { source: String, defined: Definition },
+    #[fail(display = "{}: the path of {}, {:?}, is not a directory", defined, source, path)]
+    SourcePathInvalid { source: String, defined: Definition, path: PathBuf },
+    #[fail(display = "{}: the archive of {}, {:?}, is not a file", defined, source, path)]
+    SourceArchiveInvalid { source: String, defined: Definition, path: PathBuf },
+    #[fail(display = "{}: {} cannot set the control field '{}'", defined, source, field)]
+    ControlField { source: String, defined: Definition, field: String },
+    #[fail(display = "{}: the name of {} is invalid: {}", defined, source, why)]
+    SourceName { source: String, defined: Definition, why: &'static str },
+    #[fail(display = "{}: variant '{}' of {} is invalid: {}", defined, variant, source, why)]
+    Variant { source: String, defined: Definition, variant: String, why: &'static str },
+    #[fail(display = "{} has no profile named {}; its profiles are {}", file, profile, available)]
+    Profile { file: &'static str, profile: String, available: String },
+    #[fail(
+        display = "{}: the component of {}, '{}', must be a name of lowercase letters, digits, \
+                   '.', '+', or '-', which is not 'source' or 'binary-*'",
+        defined,
+        name,
+        component
+    )]
+    Component { name: String, defined: Definition, component: String },
+    #[fail(display = "{}: {} {}", file, field, why)]
+    Setting { file: &'static str, field: &'static str, why: &'static str },
+    #[fail(display = "{}: suite {} {}", file, suite, why)]
+    Suite { file: &'static str, suite: String, why: String },
+    #[fail(
+        display = "{}: {} is uploaded to {}, which is neither an [[upload]] target nor a PPA",
+        defined, source, target
+    )]
+    UploadTarget { source: String, defined: Definition, target: String },
 }
 
 #[derive(Debug, Fail)]
@@ -34,23 +84,151 @@ pub enum ConfigError {
 }
 
 /// An in-memory representation of the Debian repository's TOML spec
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     pub archive: String,
     pub version: String,
     pub origin: String,
     pub label: String,
     pub email: String,
+    /// The suites which this repo publishes. When set, `archive` must be one of them, so that a
+    /// misspelled archive is never published as a new suite. Suites which are defined as tables
+    /// may override the settings above, and inherit those of another suite.
+    pub suites: Option<Suites>,
+    /// The suite which is worked on when no `--suite` is given, instead of every suite.
+    pub default_suite: Option<String>,
+    /// The architectures of the dists. Defaults to `i386`, `amd64`, and `all`.
+    pub architectures: Option<Vec<String>>,
+    /// The fingerprint of the key which signs the dists, instead of the key of `email`.
+    pub release_key: Option<String>,
+    /// The fingerprint of a key which also signs the dists while signing is rotated to it, so
+    /// that apt trusts the dists with either key.
+    pub incoming_release_key: Option<String>,
+    /// The `Description` of the `Release` file of the dists. Defaults to the label, followed by
+    /// the archive and version in parentheses.
+    pub release_description: Option<String>,
+    /// How many days the `Release` file is valid for, which sets its `Valid-Until`, after which
+    /// apt refuses dists which were not published again. Unset, the dists never expire.
+    pub release_valid_days: Option<u64>,
+    /// Lines of context to show around the first error of a failed build's log.
+    #[serde(default = "default_log_excerpt_lines")]
+    pub log_excerpt_lines: usize,
+    /// Determines which old build logs are removed at the start of each build.
+    pub log_retention: Option<LogRetention>,
+    /// How long `debrep gc` keeps what builds leave in the working areas of the repo, and how
+    /// large it lets each area grow.
+    pub gc: Option<GcConfig>,
+    /// How the digest of each run is written, which lists the packages that the run changed in
+    /// each suite.
+    pub digest: Option<DigestConfig>,
+    /// How much space downloads and builds are expected to need, which is checked before they
+    /// start, and again before each package is built.
+    pub space_check: Option<SpaceCheck>,
+    /// Where log messages are written to, if not given on the command line.
+    pub log_target: Option<LogTarget>,
+    /// Signs the `.changes` and `.dsc` files of each package that is built.
+    pub signing: Option<Signing>,
+    /// Signs the checksums of the files which each run publishes with the key of `email`.
+    #[serde(default = "default_sign_checksums")]
+    pub sign_checksums: bool,
+    /// Only uses what was already downloaded and fetched, as if `--offline` was given.
+    #[serde(default = "default_offline")]
+    pub offline: bool,
+    /// Keys which downloads are verified with, fetched into `keys/` when first used.
+    pub keys: Option<Vec<Key>>,
+    /// Set to false to stop packages from accessing the network while they are built, unless
+    /// their source allows it.
+    pub network_during_build: Option<bool>,
+    /// Normalizes the environment of builds, so that packages can be reproduced.
+    pub reproducible: Option<Reproducible>,
+    /// Options for lintian, which checks packages after they are built.
+    pub lintian: Option<LintianConfig>,
+    /// Where autopkgtest runs the tests of sources which enable them, such as
+    /// `schroot:bionic-amd64`, or `null` to run them on this system.
+    pub autopkgtest_backend: Option<String>,
+    /// Installs the packages of each source in a schroot session after they are built.
+    pub install_check: Option<InstallCheck>,
+    /// Options for piuparts, which checks the packages of sources that enable it.
+    pub piuparts: Option<PiupartsConfig>,
+    /// Options for `debrep check --apt-sim`.
+    pub apt_sim: Option<AptSim>,
+    /// Limits on the `prebuild` and `starting_build` commands of sources.
+    pub command_limits: Option<CommandLimits>,
+    /// Kill git and rsync commands which run for longer than this many seconds, so that a stalled
+    /// network or file system cannot hang the run. Defaults to an hour, and 0 disables it.
+    pub subprocess_timeout: Option<u64>,
+    /// Timeouts of each kind of git and rsync command, in place of `subprocess_timeout`.
+    pub subprocess_timeouts: Option<SubprocessTimeouts>,
+    /// The most threads which debrep works with, such as to download and hash files, as if
+    /// `--jobs` was given. Defaults to the number of CPUs.
+    pub jobs: Option<usize>,
+    /// The most packages which are downloaded at once. Defaults to `jobs`.
+    pub download_jobs: Option<usize>,
+    /// What files are downloaded with. Defaults to `blocking`.
+    pub download_engine: Option<DownloadEngine>,
+    /// The most connections which the `async` download engine opens at once. Defaults to 8.
+    pub download_connections: Option<usize>,
+    /// The most sources which are built at once, each with an sbuild of its own. Sources are
+    /// still built after those which they depend on. Defaults to 1.
+    pub build_jobs: Option<usize>,
+    /// Lowers the CPU and I/O priority of sbuild and other heavy commands, so that a rebuild does
+    /// not starve other work on the machine.
+    pub priority: Option<Priority>,
+    /// Copies debian directories and the working copies of `path` sources into `build/` with the
+    /// external rsync, instead of natively. Defaults to false.
+    pub use_rsync: Option<bool>,
+    /// Builds the packages of a run in one schroot session of each chroot, which is reset between
+    /// packages, instead of a new session for each package. Defaults to false.
+    pub reuse_sessions: Option<bool>,
+    /// Generates a `Contents-<arch>.gz` index of the files which each architecture's packages
+    /// install. Defaults to false.
+    pub contents: Option<bool>,
+    /// Where the `-dbgsym` packages of builds are published: `main`, the default, lists them in
+    /// the indices of the suite; `debug-suite` in those of a parallel `<suite>-debug` suite; and
+    /// `discard` does not place them in the pool.
+    pub ddeb_handling: Option<DdebHandling>,
+    /// The maintainer which the `debian/control` of each source is rewritten to before it is
+    /// built, such as `Pop Team <pop@example.com>`, keeping the original in
+    /// `XSBC-Original-Maintainer`.
+    pub maintainer: Option<String>,
+    /// The suffix which `debrep rebuild` appends to a version which the pool already has, where
+    /// `{n}` is the number of the rebuild of the version. Defaults to `+rebuild{n}`.
+    pub rebuild_suffix: Option<String>,
+    /// Removes the binary packages of the pool which the latest builds of their sources no
+    /// longer produce once packages were built, as `debrep clean --obsolete-binaries` does.
+    /// Defaults to false.
+    pub remove_obsolete_binaries: Option<bool>,
+    /// When `debrep migrate` migrates packages between suites.
+    pub migration: Option<MigrationConfig>,
+    /// Enables `debrep serve`.
+    pub serve: Option<ServeConfig>,
+    /// Commands which are run at points of each run, such as to purge a CDN once a suite was
+    /// published.
+    pub hook: Option<Vec<Hook>>,
+    /// Archives which the packages of sources that name them in `upload_targets` are uploaded
+    /// to once they were published.
+    pub upload: Option<Vec<UploadTarget>>,
+    /// Bundles of options, such as for development and production, which `--profile` selects.
+    pub profiles: Option<BTreeMap<String, Profile>>,
     /// Packages which are already Deb packaged.
     pub direct: Option<Vec<Direct>>,
+    /// Packages which are copied from other apt repositories.
+    pub mirror: Option<Vec<Mirror>>,
+    /// Packages which are copied from Launchpad PPAs.
+    pub ppa: Option<Vec<Ppa>>,
+    /// Upstream packages which are republished with modifications.
+    pub repack: Option<Vec<Repack>>,
     /// Projects which can be built from source
     pub source: Option<Vec<Source>>,
 }
 
+fn default_log_excerpt_lines() -> usize { 10 }
+fn default_sign_checksums() -> bool { false }
+fn default_offline() -> bool { false }
+
 impl Config {
     pub fn write_to_disk(&self) -> Result<(), ParsingError> {
-        toml::ser::to_vec(self)
-            .map_err(|why| ParsingError::TomlSerialize { why })
+        self.to_toml()
             .and_then(|data| {
                 File::create(SOURCES)
                     .and_then(|mut file| file.write_all(&data))
@@ -58,6 +236,14 @@ impl Config {
             })
     }
 
+    /// The config as TOML. It is serialized through a `toml::Value`, which emits the tables of
+    /// the config after its values, wherever they are declared in the struct.
+    pub fn to_toml(&self) -> Result<Vec<u8>, ParsingError> {
+        toml::Value::try_from(self)
+            .and_then(|value| toml::ser::to_vec(&value))
+            .map_err(|why| ParsingError::TomlSerialize { why })
+    }
+
     pub fn direct_exists(&self, filename: &str) -> bool {
         self.direct.as_ref()
             .map_or(false, |packages| {
@@ -69,6 +255,50 @@ impl Config {
             })
     }
 
+    /// The timeout of the operation in seconds, where 0 means that it has none.
+    pub fn subprocess_timeout(&self, operation: Operation) -> u64 {
+        self.subprocess_timeouts.as_ref()
+            .and_then(|timeouts| match operation {
+                Operation::GitClone => timeouts.git_clone,
+                Operation::GitFetch => timeouts.git_fetch,
+                Operation::GitMerge => timeouts.git_merge,
+                Operation::Rsync => timeouts.rsync,
+            })
+            .or(self.subprocess_timeout)
+            .unwrap_or(command::DEFAULT_TIMEOUT)
+    }
+
+    /// The space which the build of a source needs, as a multiple of the size of its tree.
+    pub fn build_multiplier(&self) -> f64 {
+        self.space_check.as_ref().and_then(|space| space.build_multiplier).unwrap_or(4.0)
+    }
+
+    /// The bytes which must stay free on each filesystem that a run writes to.
+    pub fn space_headroom(&self) -> u64 {
+        let headroom = self.space_check.as_ref().and_then(|space| space.headroom);
+        headroom.unwrap_or(1024) * 1024 * 1024
+    }
+
+    /// The most packages which are downloaded at once, if it is limited.
+    pub fn download_jobs(&self) -> Option<usize> {
+        self.download_jobs.or(self.jobs)
+    }
+
+    /// What files are downloaded with.
+    pub fn download_engine(&self) -> DownloadEngine {
+        self.download_engine.unwrap_or(DownloadEngine::Blocking)
+    }
+
+    /// The most connections which the `async` download engine opens at once.
+    pub fn download_connections(&self) -> usize {
+        self.download_connections.unwrap_or(8)
+    }
+
+    /// The most sources which are built at once.
+    pub fn build_jobs(&self) -> usize {
+        self.build_jobs.unwrap_or(1)
+    }
+
     pub fn source_exists(&self, filename: &str) -> bool {
         self.source.as_ref()
             .map_or(false, |x| x.iter().any(|x| x.name == filename))
@@ -77,6 +307,14 @@ impl Config {
     pub fn package_exists(&self, filename: &str) -> bool {
         self.direct_exists(filename) || self.source_exists(filename)
     }
+
+    /// The sources to fetch and build for the archive, with each variant of a source as a
+    /// source of its own.
+    pub fn build_units(&self) -> Vec<Source> {
+        self.source.as_ref().map_or_else(Vec::new, |sources| {
+            sources.iter().flat_map(|source| source.units(&self.archive)).collect()
+        })
+    }
 }
 
 /// Methods for fetching and updating values from the in-memory representation of the TOML spec.
@@ -177,7 +415,302 @@ impl ConfigFetch for Config {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Policy for removing old build logs. Logs are kept forever when neither field is set.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LogRetention {
+    /// The number of logs to keep for each package.
+    pub keep: Option<usize>,
+    /// Remove logs that are older than this many days.
+    pub max_age: Option<u64>,
+}
+
+/// The retention policies of the working areas which `debrep gc` collects. Nothing is removed
+/// from an area without a policy.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GcConfig {
+    /// Archives, git mirrors, and vendored dependencies in `assets/cache/`.
+    pub cache: Option<AreaRetention>,
+    /// Checkouts, build trees, and what failed builds left in `build/`.
+    pub build: Option<AreaRetention>,
+    /// The logs of packages and runs in `logs/`.
+    pub logs: Option<AreaRetention>,
+}
+
+/// Policy for removing what an area holds. What the config still uses is always kept, such as
+/// the checkout of a source, or the latest log of a package.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AreaRetention {
+    /// Remove what was last used more than this many days ago.
+    pub max_age: Option<u64>,
+    /// Remove what was used least recently while the area is larger than this many MiB.
+    pub max_size: Option<u64>,
+}
+
+/// Where the debug symbol packages of builds are published.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DdebHandling {
+    Main,
+    DebugSuite,
+    Discard,
+}
+
+/// How the digest of each run is written, and given to `post_publish` hooks.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DigestConfig {
+    /// `markdown`, the default, or `text`.
+    pub format: Option<DigestFormat>,
+    /// Hooks are not given the digest of a suite which the run did not change.
+    pub skip_unchanged: Option<bool>,
+}
+
+/// What files are downloaded with.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadEngine {
+    /// The blocking client, on the thread of each download, with a connection of its own.
+    Blocking,
+    /// The async client, on one thread, which runs each batch of downloads as futures, with at
+    /// most `download_connections` of them at once.
+    Async,
+}
+
+/// The format which the digest of a run is written in.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestFormat {
+    Markdown,
+    Text,
+}
+
+/// How the space which a run needs is estimated.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SpaceCheck {
+    /// The space which the build of a source needs, as a multiple of the size of its source
+    /// tree. Defaults to 4.
+    pub build_multiplier: Option<f64>,
+    /// The MiB which must stay free on each filesystem, beyond what was estimated. Defaults to
+    /// 1024.
+    pub headroom: Option<u64>,
+}
+
+/// Options which are passed to lintian.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LintianConfig {
+    /// The vendor profile to check packages with, such as `ubuntu`.
+    pub profile: Option<String>,
+    /// Tags which are never reported.
+    pub suppress_tags: Option<Vec<String>>,
+}
+
+/// The least severe lintian finding which fails a package.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LintianFailOn {
+    Error,
+    Warning,
+    None,
+}
+
+/// Inputs to builds which are fixed, in addition to `SOURCE_DATE_EPOCH`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Reproducible {
+    /// Variables to set in the build environment. Defaults to `TZ=UTC` and `LC_ALL=C.UTF-8`.
+    pub environment: Option<BTreeMap<String, String>>,
+    /// The octal umask of builds. Defaults to `022`.
+    pub umask: Option<String>,
+}
+
+/// Where, and for how long, built packages are installed to check that they can be.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InstallCheck {
+    /// The schroot to open sessions of. Defaults to the chroot which sbuild builds the archive
+    /// in, `<archive>-<arch>-sbuild`.
+    pub chroot: Option<String>,
+    /// Fail the check if installing takes longer than this many seconds. Defaults to 30 minutes.
+    pub timeout: Option<u64>,
+}
+
+/// The defaults of piuparts runs, which sources enable with `piuparts = true`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PiupartsConfig {
+    /// The schroot that piuparts installs packages in. Defaults to the chroot of sbuild.
+    pub chroot: Option<String>,
+    /// Additional arguments to piuparts.
+    pub args: Option<Vec<String>>,
+    /// Only run piuparts when the maintainer scripts or conffiles of a package changed since its
+    /// previously published version. Defaults to true.
+    pub only_changed: Option<bool>,
+}
+
+/// What `debrep check --apt-sim` simulates installing, and where dependencies come from.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AptSim {
+    /// The packages to install. Defaults to every binary package which is published.
+    pub packages: Option<Vec<String>>,
+    /// The apt sources of the distribution, in the format of `sources.list`.
+    pub sources: Option<Vec<String>>,
+    /// Defaults to the architecture of this system.
+    pub architecture: Option<String>,
+}
+
+/// The gates which packages must pass to migrate from one suite to another.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MigrationConfig {
+    /// How many days a package must have been published in the suite which it migrates from.
+    /// Defaults to 5.
+    pub min_age: Option<u64>,
+    /// Only migrates packages whose tests passed. Packages whose tests failed never migrate.
+    /// Defaults to false.
+    pub require_tests: Option<bool>,
+}
+
+/// Settings of `debrep serve`, which builds packages when they are requested over HTTP.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServeConfig {
+    /// The address to listen on, if `--listen` is not given. Defaults to `127.0.0.1:8090`.
+    pub listen: Option<String>,
+    /// The token which requests must give as `Authorization: Bearer <token>`.
+    pub token: String,
+}
+
+/// A command which is run with `sh -c` at a stage of each run, in the directory of
+/// `sources.toml`, with what it is run for in `DEBREP_*` environment variables.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Hook {
+    pub stage: HookStage,
+    pub command: String,
+    /// Kill the command if it runs for longer than this many seconds. Defaults to ten minutes,
+    /// and 0 disables it.
+    pub timeout: Option<u64>,
+    /// Whether the stage fails if the command fails, or a warning is logged. Defaults to `warn`.
+    pub on_failure: Option<HookFailure>,
+}
+
+/// When a hook is run: before and after each package is built, or once the packages of a run were
+/// moved into the pool, the dists were generated, or the suite was published.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookStage {
+    PreBuild,
+    PostBuild,
+    PostPool,
+    PostMetadata,
+    PostPublish,
+}
+
+impl HookStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HookStage::PreBuild => "pre_build",
+            HookStage::PostBuild => "post_build",
+            HookStage::PostPool => "post_pool",
+            HookStage::PostMetadata => "post_metadata",
+            HookStage::PostPublish => "post_publish",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+    Warn,
+    Fail,
+}
+
+/// Limits on commands which sources run during their builds.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandLimits {
+    /// Kill commands which run for longer than this many seconds. Defaults to an hour.
+    pub timeout: Option<u64>,
+    /// The most virtual memory that each command may use, in MiB.
+    pub memory: Option<u64>,
+}
+
+/// Timeouts in seconds of the git and rsync commands which debrep runs, where 0 disables one.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SubprocessTimeouts {
+    /// Cloning a git source, or the mirror of a pinned source.
+    pub git_clone: Option<u64>,
+    /// Pulling a git source, or updating a mirror.
+    pub git_fetch: Option<u64>,
+    /// Cloning the branch of a source's `debian` directory.
+    pub git_merge: Option<u64>,
+    /// Copying a source's `debian` directory and local working copies into `build/`.
+    pub rsync: Option<u64>,
+}
+
+/// The priority of sbuild, piuparts, lintian, autopkgtest, and apt-ftparchive.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Priority {
+    /// The niceness of the commands, from -20 to 19.
+    pub nice: Option<i32>,
+    /// The I/O scheduling class of the commands. Defaults to `best-effort` if `io_level` is set.
+    pub io_class: Option<IoClass>,
+    /// The priority within the `realtime` or `best-effort` class, from 0, the highest, to 7.
+    pub io_level: Option<u8>,
+}
+
+/// The I/O scheduling classes of `ionice`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl Priority {
+    /// The commands which heavy commands are run through, `nice` and `ionice`, with their
+    /// arguments.
+    pub fn prefix(&self) -> Vec<String> {
+        let mut prefix = Vec::new();
+        if let Some(nice) = self.nice {
+            prefix.extend(vec!["nice".to_owned(), "-n".to_owned(), nice.to_string()]);
+        }
+
+        let class = match (self.io_class, self.io_level) {
+            (Some(class), _) => class,
+            (None, Some(_)) => IoClass::BestEffort,
+            (None, None) => return prefix,
+        };
+
+        let number = match class {
+            IoClass::Realtime => "1",
+            IoClass::BestEffort => "2",
+            IoClass::Idle => "3",
+        };
+
+        prefix.extend(vec!["ionice".to_owned(), "-c".to_owned(), number.to_owned()]);
+        if let (Some(level), false) = (self.io_level, class == IoClass::Idle) {
+            prefix.extend(vec!["-n".to_owned(), level.to_string()]);
+        }
+
+        prefix
+    }
+}
+
+/// The key which built packages are signed with by `debsign`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Signing {
+    /// A key ID or fingerprint which gpg can find.
+    pub key: String,
+    /// The GnuPG home directory containing the key, if not the default.
+    pub gnupg_home: Option<PathBuf>,
+}
+
+/// A key that is pinned by its fingerprint, and may be referred to by name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Key {
+    pub name: String,
+    pub fingerprint: String,
+    /// The keyserver to receive the key from.
+    pub keyserver: Option<String>,
+    /// A URL to download the key from, if there is no keyserver.
+    pub url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Update {
     pub source:     String,
     pub url:        String,
@@ -187,7 +720,7 @@ pub struct Update {
     pub build_from: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DirectPath {
     pub checksum: Option<String>,
     pub arch:     Option<String>,
@@ -196,13 +729,128 @@ pub struct DirectPath {
 }
 
 /// A Debian package which already exists and may be downloaded directly.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Direct {
     pub name:      String,
     pub version:   String,
+    #[serde(default = "default_direct_urls")]
     pub urls:      Vec<DirectPath>,
     pub checksum:  Option<String>,
     pub update:    Option<Update>,
+    /// Downloads a historical version of a package from snapshot.debian.org instead of `urls`.
+    pub snapshot:  Option<Snapshot>,
+    /// The component of the archive which the package is published in. Defaults to `main`.
+    pub component: Option<String>,
+    /// Where the entry is defined in the config.
+    #[serde(skip)]
+    pub defined:   Definition,
+}
+
+fn default_direct_urls() -> Vec<DirectPath> { Vec::new() }
+
+impl Direct {
+    /// The component which the package is published in.
+    pub fn component(&self) -> &str {
+        self.component.as_ref().map_or(DEFAULT_COMPONENT, |x| x.as_str())
+    }
+}
+
+/// A version of a package which is located through the API of snapshot.debian.org.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub package: String,
+    pub version: String,
+    /// The architecture of the binary package, or `source` for every file of the source package.
+    pub arch:    String,
+}
+
+/// An upstream `.deb` which is republished with modifications, under a new version.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Repack {
+    pub name: String,
+    pub url: Option<String>,
+    /// A `.deb` on this system, instead of a URL. Relative paths are resolved against the
+    /// directory of `sources.toml`.
+    pub path: Option<PathBuf>,
+    /// The SHA256 digest of the upstream `.deb`.
+    pub checksum: String,
+    /// Appended to the version of the package, such as `+pop1`.
+    pub version_suffix: String,
+    /// Fields of the control file to set, such as a relaxed `Depends`.
+    pub control: Option<BTreeMap<String, String>>,
+    /// Files to add to the package, or to replace in it.
+    pub files: Option<Vec<RepackFile>>,
+    /// Where the entry is defined in the config.
+    #[serde(skip)]
+    pub defined: Definition,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RepackFile {
+    /// The file within `assets/packages/<name>`.
+    pub src: String,
+    /// Where the file is installed, such as `/usr/share/foo/foo.conf`.
+    pub dst: PathBuf,
+}
+
+/// Packages to copy from another apt repository, at the newest versions which its indices list
+/// that satisfy their constraints.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Mirror {
+    pub name: String,
+    /// The root of the repository, which contains its `dists/` directory.
+    pub url: String,
+    pub suite: String,
+    #[serde(default = "default_mirror_component")]
+    pub component: String,
+    /// The name of the configured key which signs the `InRelease` file of the suite.
+    pub key: String,
+    /// Defaults to the architecture of this system, and `all`.
+    pub architectures: Option<Vec<String>>,
+    pub packages: Vec<MirrorPackage>,
+    /// Copies the binary packages of the given names.
+    #[serde(default = "default_mirror_binaries")]
+    pub binaries: bool,
+    /// Copies the source packages of the given names.
+    #[serde(default = "default_mirror_sources")]
+    pub sources: bool,
+    /// Where the entry is defined in the config.
+    #[serde(skip)]
+    pub defined: Definition,
+}
+
+fn default_mirror_component() -> String { "main".to_owned() }
+fn default_mirror_binaries() -> bool { true }
+fn default_mirror_sources() -> bool { false }
+
+/// Packages to copy from a Launchpad PPA, in the same way as from a mirror.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ppa {
+    pub name: String,
+    /// Such as `ppa:user/name`.
+    pub ppa: String,
+    /// The Ubuntu series to copy packages from, such as `bionic`.
+    pub series: String,
+    /// The fingerprint of the key which signs the PPA, as shown on its Launchpad page. The key is
+    /// received from the Ubuntu keyserver.
+    pub fingerprint: String,
+    /// Defaults to the architecture of this system, and `all`.
+    pub architectures: Option<Vec<String>>,
+    pub packages: Vec<MirrorPackage>,
+    #[serde(default = "default_mirror_binaries")]
+    pub binaries: bool,
+    #[serde(default = "default_mirror_sources")]
+    pub sources: bool,
+    /// Where the entry is defined in the config.
+    #[serde(skip)]
+    pub defined: Definition,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MirrorPackage {
+    pub name: String,
+    /// A constraint such as `>= 1.2` or `<< 2.0`, or a version which must match exactly.
+    pub version: Option<String>,
 }
 
 impl ConfigFetch for Direct {
@@ -227,9 +875,234 @@ impl ConfigFetch for Direct {
 }
 
 pub fn parse() -> Result<Config, ParsingError> {
-    misc::read(SOURCES)
+    misc::read_to_string(SOURCES)
         .map_err(|why| ParsingError::File { file: SOURCES, why })
-        .and_then(|buffer| {
-            toml::from_slice(&buffer).map_err(|why| ParsingError::Toml { file: SOURCES, why })
+        .and_then(|text| {
+            let mut config: Config = toml::from_str(&text)
+                .map_err(|why| ParsingError::Toml { file: SOURCES, why })?;
+            definition::locate(&mut config, SOURCES, &text);
+            Ok(config)
         })
+        .and_then(|config| validate(&config).map(|_| config))
+}
+
+/// Whether the name can be a component of the pool and the dists, beside their sections.
+fn is_component(name: &str) -> bool {
+    !name.is_empty()
+        && name != "source"
+        && !name.starts_with("binary-")
+        && name.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '+' || c == '-'
+        })
+}
+
+/// Checks what the config refers to on this system. Relative paths are relative to the
+/// directory of `sources.toml`, which is the current working directory.
+fn validate(config: &Config) -> Result<(), ParsingError> {
+    let setting = |field, why| Err(ParsingError::Setting { file: SOURCES, field, why });
+    if config.jobs == Some(0) {
+        return setting("jobs", "must be at least 1");
+    } else if config.download_jobs == Some(0) {
+        return setting("download_jobs", "must be at least 1");
+    } else if config.download_connections == Some(0) {
+        return setting("download_connections", "must be at least 1");
+    } else if config.build_jobs == Some(0) {
+        return setting("build_jobs", "must be at least 1");
+    }
+
+    if let Some((suite, why)) = config.suites.as_ref().and_then(Suites::invalid) {
+        return Err(ParsingError::Suite { file: SOURCES, suite, why });
+    }
+
+    if let Some(suites) = config.suite_names() {
+        if let Err((suite, why)) = config.suite_order(&suites) {
+            return Err(ParsingError::Suite { file: SOURCES, suite, why });
+        }
+    }
+
+    for (suite, effective) in config.effective_suites() {
+        let unknown = effective.upload_targets.iter()
+            .find(|target| config.upload_target(target).is_none());
+        if let Some(target) = unknown {
+            let why = format!(
+                "is uploaded to {}, which is neither an [[upload]] target nor a PPA",
+                target
+            );
+            return Err(ParsingError::Suite { file: SOURCES, suite, why });
+        }
+
+        if effective.components.is_empty() {
+            continue;
+        }
+
+        let sources = config.source.iter().flat_map(|x| x)
+            .filter(|source| !source.units(&suite).is_empty());
+        for source in sources {
+            if !effective.components.iter().any(|x| x == source.component()) {
+                let why = format!(
+                    "does not have the component {}, which {} is published in",
+                    source.component(),
+                    source.name
+                );
+                return Err(ParsingError::Suite { file: SOURCES, suite: suite.clone(), why });
+            }
+        }
+    }
+
+    let defaults = config.default_suite.iter()
+        .chain(config.source.iter().flat_map(|x| x).filter_map(|x| x.default_suite.as_ref()));
+    for suite in defaults {
+        if suite == ALL_SUITES || config.selected_suites(&[suite]).is_err() {
+            let why = "is a default_suite, but is not a suite of the repo".to_owned();
+            return Err(ParsingError::Suite { file: SOURCES, suite: suite.clone(), why });
+        }
+    }
+
+    if config.serve.as_ref().map_or(false, |serve| serve.token.trim().is_empty()) {
+        return setting("serve.token", "must not be empty");
+    }
+
+    let hooks = config.hook.iter().flat_map(|hooks| hooks.iter());
+    if hooks.clone().any(|hook| hook.command.trim().is_empty()) {
+        return setting("hook.command", "must not be empty");
+    }
+
+    let multiplier = config.space_check.as_ref().and_then(|space| space.build_multiplier);
+    if multiplier.map_or(false, |multiplier| !(multiplier > 0.0)) {
+        return setting("space_check.build_multiplier", "must be greater than 0");
+    }
+
+    if config.rebuild_suffix.as_ref().map_or(false, |suffix| !suffix.contains("{n}")) {
+        return setting("rebuild_suffix", "must contain {n}, the number of the rebuild");
+    }
+
+    if let Some(ref priority) = config.priority {
+        if priority.nice.map_or(false, |nice| nice < -20 || nice > 19) {
+            return setting("priority.nice", "must be from -20 to 19");
+        } else if priority.io_level.map_or(false, |level| level > 7) {
+            return setting("priority.io_level", "must be from 0 to 7");
+        }
+    }
+
+    for source in config.source.as_ref().map_or(&[][..], |sources| sources.as_slice()) {
+        if !is_component(source.component()) {
+            return Err(ParsingError::Component {
+                name: source.name.clone(),
+                defined: source.defined.clone(),
+                component: source.component().to_owned(),
+            });
+        }
+
+        if source.name.contains('@') {
+            return Err(ParsingError::SourceName {
+                source: source.name.clone(),
+                defined: source.defined.clone(),
+                why: "'@' separates the names of sources from their variants",
+            });
+        }
+
+        for variant in source.variants.iter().flat_map(|variants| variants.iter()) {
+            let why = if variant.name.is_empty()
+                || variant.name.contains(|c: char| c == '/' || c == '@' || c.is_whitespace())
+            {
+                Some("variant names cannot be empty, or contain '/', '@', or whitespace")
+            } else if source.variants.iter().flat_map(|x| x.iter())
+                .filter(|x| x.name == variant.name).count() > 1
+            {
+                Some("variant names must be unique")
+            } else if let Some(SourceLocation::Git { .. }) = source.location {
+                let pins = [&variant.branch, &variant.tag, &variant.commit].iter()
+                    .filter(|pin| pin.is_some())
+                    .count();
+                if pins > 1 { Some("only one of branch, tag, or commit may be set") } else { None }
+            } else {
+                Some("only sources with a git location can have variants")
+            };
+
+            if let Some(why) = why {
+                return Err(ParsingError::Variant {
+                    source: source.name.clone(),
+                    defined: source.defined.clone(),
+                    variant: variant.name.clone(),
+                    why,
+                });
+            }
+        }
+
+        for target in source.upload_targets.iter().flat_map(|targets| targets.iter()) {
+            if config.upload_target(target).is_none() {
+                return Err(ParsingError::UploadTarget {
+                    source: source.name.clone(),
+                    defined: source.defined.clone(),
+                    target: target.clone(),
+                });
+            }
+        }
+
+        match source.location {
+            Some(SourceLocation::Path { ref path }) if !path.is_dir() => {
+                return Err(ParsingError::SourcePathInvalid {
+                    source: source.name.clone(),
+                    defined: source.defined.clone(),
+                    path: path.clone(),
+                });
+            }
+            Some(SourceLocation::Archive { ref path, .. }) if !path.is_file() => {
+                return Err(ParsingError::SourceArchiveInvalid {
+                    source: source.name.clone(),
+                    defined: source.defined.clone(),
+                    path: path.clone(),
+                });
+            }
+            _ => (),
+        }
+    }
+
+    for direct in config.direct.as_ref().map_or(&[][..], |directs| directs.as_slice()) {
+        if !is_component(direct.component()) {
+            return Err(ParsingError::Component {
+                name: direct.name.clone(),
+                defined: direct.defined.clone(),
+                component: direct.component().to_owned(),
+            });
+        }
+    }
+
+    for repack in config.repack.as_ref().map_or(&[][..], |repacks| repacks.as_slice()) {
+        let source = || repack.name.clone();
+        let defined = || repack.defined.clone();
+        match (repack.url.as_ref(), repack.path.as_ref()) {
+            (Some(_), Some(_)) => {
+                let (source, defined) = (source(), defined());
+                return Err(ParsingError::SourcePathAndUrlDefined { source, defined });
+            }
+            (None, None) => {
+                let (source, defined) = (source(), defined());
+                return Err(ParsingError::SourceNotDefined { source, defined });
+            }
+            (None, Some(path)) if !path.is_file() => {
+                return Err(ParsingError::SourceArchiveInvalid {
+                    source: source(),
+                    defined: defined(),
+                    path: path.clone(),
+                });
+            }
+            _ => (),
+        }
+
+        // Fields are single lines, which the version suffix is appended to the `Version` of.
+        for (field, value) in repack.control.iter().flat_map(|control| control.iter()) {
+            let invalid_name = field.is_empty()
+                || field.contains(|c: char| c == ':' || c.is_whitespace());
+            if invalid_name || value.trim().is_empty() || value.contains('\n') {
+                return Err(ParsingError::ControlField {
+                    source: source(),
+                    defined: defined(),
+                    field: field.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,190 @@
+//! Named bundles of options, such as `[profiles.dev]` and `[profiles.prod]`, which are selected
+//! with `--profile`, or `DEBREP_PROFILE`. A profile overrides the options of the config, and the
+//! flags of runs which were not given on the command line, which still take precedence.
+//!
+//! A profile may only set the options of `Profile`, so that an option is only overridden by
+//! profiles once it was added there, and to `Profile::apply`. Any other key is an error.
+
+use logging::LogTarget;
+use super::{validate, Config, GcConfig, LintianConfig, LogRetention, ParsingError, SOURCES};
+
+/// The options which a profile overrides. Options which are not set are left as they are.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub offline: Option<bool>,
+    pub jobs: Option<usize>,
+    pub download_jobs: Option<usize>,
+    pub build_jobs: Option<usize>,
+    pub network_during_build: Option<bool>,
+    pub sign_checksums: Option<bool>,
+    pub remove_obsolete_binaries: Option<bool>,
+    pub log_target: Option<LogTarget>,
+    /// As `--follow`, which is off in CI mode.
+    pub follow: Option<bool>,
+    /// As `--ci`.
+    pub ci: Option<bool>,
+    /// As `--deny-warnings`.
+    pub deny_warnings: Option<bool>,
+    /// As `--no-sign`.
+    pub no_sign: Option<bool>,
+    /// As `--unsigned`.
+    pub unsigned: Option<bool>,
+    /// As `--strict`.
+    pub strict: Option<bool>,
+    /// As `--keep-going`.
+    pub keep_going: Option<bool>,
+    /// As `--fail-fast`.
+    pub fail_fast: Option<bool>,
+    pub log_retention: Option<LogRetention>,
+    pub lintian: Option<LintianConfig>,
+    pub gc: Option<GcConfig>,
+}
+
+impl Profile {
+    /// Overrides the options of the config which the profile sets.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(offline) = self.offline {
+            config.offline = offline;
+        }
+
+        if let Some(sign_checksums) = self.sign_checksums {
+            config.sign_checksums = sign_checksums;
+        }
+
+        if self.jobs.is_some() {
+            config.jobs = self.jobs;
+        }
+
+        if self.download_jobs.is_some() {
+            config.download_jobs = self.download_jobs;
+        }
+
+        if self.build_jobs.is_some() {
+            config.build_jobs = self.build_jobs;
+        }
+
+        if self.network_during_build.is_some() {
+            config.network_during_build = self.network_during_build;
+        }
+
+        if self.remove_obsolete_binaries.is_some() {
+            config.remove_obsolete_binaries = self.remove_obsolete_binaries;
+        }
+
+        if self.log_target.is_some() {
+            config.log_target = self.log_target;
+        }
+
+        if self.log_retention.is_some() {
+            config.log_retention = self.log_retention.clone();
+        }
+
+        if self.lintian.is_some() {
+            config.lintian = self.lintian.clone();
+        }
+
+        if self.gc.is_some() {
+            config.gc = self.gc.clone();
+        }
+    }
+}
+
+impl Config {
+    /// The profile of the name, or an error which lists the profiles of the config.
+    pub fn profile(&self, name: &str) -> Result<&Profile, ParsingError> {
+        let profiles = self.profiles.as_ref();
+        profiles.and_then(|profiles| profiles.get(name)).ok_or_else(|| {
+            let names: Vec<&str> = profiles
+                .map_or_else(Vec::new, |profiles| profiles.keys().map(|x| x.as_str()).collect());
+            ParsingError::Profile {
+                file: SOURCES,
+                profile: name.to_owned(),
+                available: if names.is_empty() { "none".to_owned() } else { names.join(", ") },
+            }
+        })
+    }
+
+    /// Overrides the options of the config with those of the profile, and returns the profile,
+    /// whose flags of runs are applied by the caller.
+    pub fn apply_profile(&mut self, name: &str) -> Result<Profile, ParsingError> {
+        let profile = self.profile(name)?.clone();
+        profile.apply(self);
+        validate(self)?;
+        Ok(profile)
+    }
+
+    /// The options which profiles override, as they are in effect with the profile, which was
+    /// already applied to the config. Flags of runs which are not given are off.
+    pub fn effective_options(&self, profile: Option<&Profile>) -> Profile {
+        let flag = |value: fn(&Profile) -> Option<bool>| profile.and_then(value).unwrap_or(false);
+
+        Profile {
+            offline: Some(self.offline),
+            jobs: self.jobs,
+            download_jobs: self.download_jobs,
+            build_jobs: self.build_jobs,
+            network_during_build: self.network_during_build,
+            sign_checksums: Some(self.sign_checksums),
+            remove_obsolete_binaries: self.remove_obsolete_binaries,
+            log_target: self.log_target,
+            follow: Some(flag(|x| x.follow) && !flag(|x| x.ci)),
+            ci: Some(flag(|x| x.ci)),
+            deny_warnings: Some(flag(|x| x.deny_warnings)),
+            no_sign: Some(flag(|x| x.no_sign)),
+            unsigned: Some(flag(|x| x.unsigned)),
+            strict: Some(flag(|x| x.strict)),
+            keep_going: Some(flag(|x| x.keep_going)),
+            fail_fast: Some(flag(|x| x.fail_fast)),
+            log_retention: self.log_retention.clone(),
+            lintian: self.lintian.clone(),
+            gc: self.gc.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    fn config(profiles: &str) -> Config {
+        let base = "archive = \"stable\"\nversion = \"1\"\norigin = \"o\"\nlabel = \"l\"\n\
+                    email = \"e\"\njobs = 4\n\n[log_retention]\nkeep = 5\n";
+        toml::from_str(&[base, profiles].concat()).unwrap()
+    }
+
+    #[test]
+    fn profiles_override_the_options_which_they_set() {
+        let mut config = config(
+            "[profiles.dev]\noffline = true\nfollow = true\nno_sign = true\n\n\
+             [profiles.prod]\nci = true\njobs = 16\n\n\
+             [profiles.prod.log_retention]\nmax_age = 30\n",
+        );
+
+        let dev = config.clone().apply_profile("dev").unwrap();
+        assert_eq!((dev.follow, dev.ci), (Some(true), None));
+
+        let prod = config.apply_profile("prod").unwrap();
+        assert_eq!(config.jobs, Some(16));
+        assert!(!config.offline);
+        assert_eq!(config.log_retention.as_ref().and_then(|x| x.max_age), Some(30));
+        assert_eq!(config.log_retention.as_ref().and_then(|x| x.keep), None);
+
+        let effective = config.effective_options(Some(&prod));
+        assert_eq!((effective.ci, effective.follow), (Some(true), Some(false)));
+        assert_eq!(effective.offline, Some(false));
+        assert!(toml::to_string(&effective).is_ok());
+    }
+
+    #[test]
+    fn unknown_profiles_and_options_are_errors() {
+        let config = config("[profiles.dev]\noffline = true\n\n[profiles.prod]\nci = true\n");
+        let why = config.profile("staging").unwrap_err().to_string();
+        assert_eq!(why, "sources.toml has no profile named staging; its profiles are dev, prod");
+
+        let base = "archive = \"stable\"\nversion = \"1\"\norigin = \"o\"\nlabel = \"l\"\n\
+                    email = \"e\"\n\n[profiles.dev]\narchive = \"unstable\"\n";
+        assert!(toml::from_str::<Config>(base).is_err());
+    }
+}
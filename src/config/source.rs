@@ -1,10 +1,14 @@
 use std::path::PathBuf;
+use super::{Definition, LintianFailOn, DEFAULT_COMPONENT};
 
 // Files that we want to cache and re-use between runs. These files will be symlinked.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SourceAsset {
     pub src: String,
     pub dst: PathBuf,
+    /// The SHA256 digest of the linked file, or the path to a `SHA256SUMS`-style manifest
+    /// (relative to the shared assets) when the glob matches multiple files.
+    pub sha256: Option<String>,
 }
 
 /// In the event that the source does not have a debian directory, we may designate the location of
@@ -20,24 +24,224 @@ pub enum DebianPath {
 #[serde(untagged)]
 pub enum SourceLocation {
     URL { url: String, checksum: String },
-    Git { url: String, branch: Option<String> },
+    /// A git repository, at the head of a branch, or pinned to a tag or commit.
+    Git { url: String, branch: Option<String>, tag: Option<String>, commit: Option<String> },
+    /// An upstream tarball or zip on this system, which is verified and extracted like a
+    /// downloaded one. Relative paths are resolved against the directory of `sources.toml`.
+    Archive { path: PathBuf, checksum: String },
+    /// A local working copy, which is copied into `build/` for each build. Relative paths are
+    /// resolved against the directory of `sources.toml`.
+    Path { path: PathBuf },
+    /// An archive which a shell command writes to `${path}`, stored in the cache as `file`, and
+    /// verified against its checksum.
+    Command { command: String, file: String, checksum: String },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Package managers whose dependencies can be vendored into a source before it is built.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Vendor {
+    Cargo,
+    Go,
+    Npm,
+}
+
+/// A version of a source which is built from its own pin of the same git repository, such as
+/// `foo 1.x` for a stable suite, and `foo 2.x` for a testing suite.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Variant {
+    pub name:           String,
+    pub branch:         Option<String>,
+    pub tag:            Option<String>,
+    pub commit:         Option<String>,
+    /// The archives which the variant is built for; every archive when unset.
+    pub suites:         Option<Vec<String>>,
+    /// Appended to the version of the changelog, such as `~stable`.
+    pub version_suffix: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Source {
     pub name:           String,
     pub location:       Option<SourceLocation>,
     pub assets:         Option<Vec<SourceAsset>>,
+    /// Resolve the `src` globs of `assets` relative to this subdirectory of `assets/share`.
+    pub shared_prefix:  Option<String>,
+    /// Globs of files within `assets/packages/<name>` to link; all files when unset.
+    pub asset_include:  Option<Vec<String>>,
+    /// Globs of files within `assets/packages/<name>` to never link. Takes precedence over
+    /// `asset_include`.
+    pub asset_exclude:  Option<Vec<String>>,
     pub starting_build: Option<Vec<String>>,
     pub prebuild:       Option<Vec<String>>,
+    /// Set to false to run the `prebuild` commands without access to the network.
+    #[serde(default = "default_prebuild_network")]
+    pub prebuild_network: bool,
     pub build_on:       Option<String>,
+    /// Overrides the global `network_during_build` for this source.
+    pub network_during_build: Option<bool>,
+    /// Vendors the dependencies of the source on this system, with network access, so that
+    /// its build does not need the network.
+    pub vendor:         Option<Vendor>,
     #[serde(default = "default_build_source")]
     pub keep_source:    bool,
     pub debian:         Option<DebianPath>,
     pub depends:        Option<Vec<String>>,
+    /// Sources of higher priorities are built before the others of a batch; 0 when unset.
+    pub priority:       Option<i32>,
     #[serde(default = "default_retain")]
-    pub retain:         usize
+    pub retain:         usize,
+    /// Fails the package if lintian reports findings of this severity. Lintian is run for every
+    /// package when `[lintian]` is configured, and otherwise only for sources which set this.
+    pub lintian_fail_on: Option<LintianFailOn>,
+    /// Overrides whether the packages are installed in a schroot session after they are built,
+    /// which is otherwise done when `[install_check]` is configured.
+    pub install_check:  Option<bool>,
+    /// Runs the DEP-8 tests of the package with autopkgtest after it is built.
+    #[serde(default = "default_autopkgtest")]
+    pub autopkgtest:    bool,
+    /// Set to false to publish the package even if its tests fail.
+    #[serde(default = "default_autopkgtest_blocking")]
+    pub autopkgtest_blocking: bool,
+    /// Checks the packages with piuparts after they are built.
+    #[serde(default = "default_piuparts")]
+    pub piuparts:       bool,
+    /// Set to false to publish the package even if piuparts fails.
+    #[serde(default = "default_piuparts_blocking")]
+    pub piuparts_blocking: bool,
+    /// Allows the source to produce binary packages which another source produced before, such
+    /// as when a package moves to a new source.
+    #[serde(default = "default_takeover")]
+    pub takeover:       bool,
+    /// Set to false for packages which must remain unsigned when signing is configured.
+    #[serde(default = "default_sign")]
+    pub sign:           bool,
+    /// Overrides the global `maintainer` which the source's `debian/control` is rewritten to.
+    pub maintainer:     Option<String>,
+    /// Keeps the maintainer of the source's `debian/control` when a `maintainer` is configured.
+    #[serde(default = "default_keep_maintainer")]
+    pub keep_maintainer: bool,
+    /// Appended to the version of the changelog before the package is built.
+    pub version_suffix: Option<String>,
+    /// Globs of files which are left out of the source package, in addition to the directories
+    /// of version control systems and the `.debrep-*` files, which are always left out.
+    pub source_excludes: Option<Vec<String>>,
+    /// Set to false to leave out only the `source_excludes`, instead of adding them to the
+    /// files which are always left out.
+    #[serde(default = "default_source_excludes")]
+    pub default_source_excludes: bool,
+    /// The `[[upload]]` targets, or PPAs as `ppa:<user>/<name>`, which the `.changes` files of
+    /// the source are uploaded to once they were published.
+    pub upload_targets: Option<Vec<String>>,
+    /// The suites which the source is built for, such as `bionic-backports`; every suite when
+    /// unset. Variants are only built for the suites of both.
+    pub suites:         Option<Vec<String>>,
+    /// The suite which the source is built in when no `--suite` is given, instead of the
+    /// `default_suite` of the config.
+    pub default_suite:  Option<String>,
+    /// Builds the source once for each variant, as `<name>@<variant>`.
+    pub variants:       Option<Vec<Variant>>,
+    /// The architectures which the source is built for, of those of each suite; every
+    /// architecture of the suite when unset. `all` must be given to build its
+    /// architecture-independent packages. Each architecture which is given is built with an
+    /// sbuild of its own, while only the architecture of this system is built when unset.
+    pub architectures:  Option<Vec<String>>,
+    /// The component of the archive which the packages of the source are published in, such
+    /// as `restricted`. Defaults to `main`.
+    pub component:      Option<String>,
+    /// Where the source is defined in the config, which its variants share.
+    #[serde(skip)]
+    pub defined:        Definition,
+}
+
+impl Source {
+    /// The name of the source which a variant was expanded from, or the name of the source.
+    pub fn base_name(&self) -> &str {
+        self.name.find('@').map_or(&self.name, |pos| &self.name[..pos])
+    }
+
+    /// The variant of the source, if it was expanded from one.
+    pub fn variant(&self) -> Option<&str> {
+        self.name.find('@').map(|pos| &self.name[pos + 1..])
+    }
+
+    /// The priority which the source is built at, unless it is overridden.
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or(0)
+    }
+
+    /// The component which the packages of the source are published in.
+    pub fn component(&self) -> &str {
+        self.component.as_ref().map_or(DEFAULT_COMPONENT, |x| x.as_str())
+    }
+
+    /// The architectures of the suite which the source is built for.
+    pub fn architectures_in(&self, suite: &[String]) -> Vec<String> {
+        suite.iter()
+            .filter(|arch| self.architectures.as_ref().map_or(true, |x| x.contains(arch)))
+            .cloned()
+            .collect()
+    }
+
+    /// The architectures of the suite which are built of the source on a system of the `host`
+    /// architecture: those which the source gives, or else the host and `all`.
+    pub fn build_architectures(&self, suite: &[String], host: &str) -> Vec<String> {
+        let matrix = self.architectures_in(suite);
+        if self.architectures.is_some() {
+            return matrix;
+        }
+
+        matrix.into_iter().filter(|arch| arch == host || arch == "all").collect()
+    }
+
+    /// Each variant of the source which is built for the archive, as a source of its own, or
+    /// the source itself if it has no variants. Nothing if the source is not built for the
+    /// archive.
+    pub fn units(&self, archive: &str) -> Vec<Source> {
+        if self.suites.as_ref().map_or(false, |suites| !suites.iter().any(|x| x == archive)) {
+            return Vec::new();
+        }
+
+        let variants = match self.variants {
+            Some(ref variants) => variants,
+            None => return vec![self.clone()],
+        };
+
+        variants.iter()
+            .filter(|variant| {
+                variant.suites.as_ref().map_or(true, |suites| suites.iter().any(|x| x == archive))
+            })
+            .map(|variant| {
+                let mut unit = self.clone();
+                unit.name = [&self.name, "@", &variant.name].concat();
+                unit.variants = None;
+                if variant.version_suffix.is_some() {
+                    unit.version_suffix = variant.version_suffix.clone();
+                }
+
+                if let Some(SourceLocation::Git { ref url, .. }) = self.location {
+                    unit.location = Some(SourceLocation::Git {
+                        url: url.clone(),
+                        branch: variant.branch.clone(),
+                        tag: variant.tag.clone(),
+                        commit: variant.commit.clone(),
+                    });
+                }
+
+                unit
+            })
+            .collect()
+    }
 }
 
 fn default_build_source() -> bool { true }
 fn default_retain() -> usize { 3 }
+fn default_sign() -> bool { true }
+fn default_prebuild_network() -> bool { true }
+fn default_autopkgtest() -> bool { false }
+fn default_autopkgtest_blocking() -> bool { true }
+fn default_piuparts() -> bool { false }
+fn default_takeover() -> bool { false }
+fn default_keep_maintainer() -> bool { false }
+fn default_piuparts_blocking() -> bool { true }
+fn default_source_excludes() -> bool { true }
@@ -0,0 +1,698 @@
+use std::collections::BTreeMap;
+use regex::Regex;
+use toml;
+use super::{is_component, Config, DdebHandling, ParsingError, Profile};
+
+/// The architectures of the dists of a suite which does not set them.
+pub const DEFAULT_ARCHITECTURES: &[&str] = &["i386", "amd64", "all"];
+
+/// The suffix which the versions of packages in a suite that is built on another must have, by
+/// default, such as `1.2-1~bpo18.04+1`, so that they sort before the version which the base suite
+/// will later have.
+pub const DEFAULT_BACKPORTS_VERSION: &str = r"~bpo[0-9.]*\+[0-9]+$";
+
+const BACKPORTS: &str = "-backports";
+
+/// Selects every suite of the repo with `--suite`, so that no suite can be named it.
+pub const ALL_SUITES: &str = "all";
+
+/// The suites of the repo: either a list of their names, which share the settings at the top of
+/// the config, or `[suites.<name>]` tables, which may inherit the settings of another suite.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Suites {
+    Names(Vec<String>),
+    Defined(BTreeMap<String, Suite>),
+}
+
+/// The settings of a suite. Those which it does not set are inherited from the suite that it
+/// `inherits`, and otherwise from the top of the config.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Suite {
+    /// The suite whose settings this suite defaults to.
+    pub inherits: Option<String>,
+    pub version: Option<String>,
+    pub origin: Option<String>,
+    pub label: Option<String>,
+    /// Who signs the dists of the suite, as a gpg user ID.
+    pub email: Option<String>,
+    /// The fingerprint of the key which signs the dists of the suite, instead of that of `email`.
+    pub release_key: Option<String>,
+    /// The fingerprint of a key which also signs the dists of the suite while its signing is
+    /// rotated to it.
+    pub incoming_release_key: Option<String>,
+    /// The architectures of the dists of the suite.
+    pub architectures: Option<Vec<String>>,
+    /// The components of the dists of the suite, which each have indices even before a package
+    /// is published in them. Sources which are built for the suite must be in one of them.
+    pub components: Option<Vec<String>>,
+    /// The `[[upload]]` targets, or PPAs, which the packages that are built for the suite are
+    /// uploaded to, besides the `upload_targets` of their sources.
+    pub upload_targets: Option<Vec<String>>,
+    /// The suite whose chroot packages of this suite are built in, with the pool of this suite
+    /// as an extra source of packages. Defaults to `<base>` for a suite named `<base>-backports`.
+    pub base: Option<String>,
+    /// Suites whose packages the packages of this suite are built with, as extra packages, so
+    /// that they are worked on before it in a run. The base suite is one of them, if it is a
+    /// suite of the repo.
+    pub depends: Option<Vec<String>>,
+    /// Sets `NotAutomatic: yes` in the Release file, so that apt only installs packages from the
+    /// suite when asked to.
+    pub not_automatic: Option<bool>,
+    /// Sets `ButAutomaticUpgrades: yes` in the Release file, so that packages which were
+    /// installed from the suite are upgraded from it.
+    pub but_automatic_upgrades: Option<bool>,
+    /// A regex which the version of each source that is built for the suite must match. Defaults
+    /// to a `~bpo` suffix for suites with a base.
+    pub version_pattern: Option<String>,
+    /// Stages the packages which are built for the suite in `pool-staging/<suite>/`, instead of
+    /// publishing them, until it is released. This is not inherited.
+    pub frozen: Option<bool>,
+    /// Other names of the suite, such as `stable` for `bionic`, which `dists/<alias>` links to
+    /// its dists by. The first is the `Suite` of its Release file. These are not inherited.
+    pub aliases: Option<Vec<String>>,
+}
+
+/// The settings of a suite, after its inheritance is resolved.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EffectiveSuite {
+    pub version: String,
+    pub origin: String,
+    pub label: String,
+    pub email: String,
+    pub release_key: Option<String>,
+    pub incoming_release_key: Option<String>,
+    pub not_automatic: bool,
+    pub but_automatic_upgrades: bool,
+    pub base: Option<String>,
+    pub depends: Vec<String>,
+    pub version_pattern: Option<String>,
+    pub frozen: bool,
+    pub aliases: Vec<String>,
+    pub architectures: Vec<String>,
+    /// Empty when the suite does not set them, so that its dists have the components which
+    /// packages are published in.
+    pub components: Vec<String>,
+    pub upload_targets: Vec<String>,
+}
+
+impl Suites {
+    /// The names of the suites, in order.
+    pub fn names(&self) -> Vec<String> {
+        match *self {
+            Suites::Names(ref names) => names.clone(),
+            Suites::Defined(ref suites) => suites.keys().cloned().collect(),
+        }
+    }
+
+    /// Why a suite is invalid, if one is: it is named `all`, its inheritance cannot be resolved,
+    /// its version pattern is not a regex, or one of its aliases names another suite, or an
+    /// alias of one.
+    pub fn invalid(&self) -> Option<(String, String)> {
+        if self.names().iter().any(|name| name == ALL_SUITES) {
+            let why = "cannot be defined, since `--suite all` selects every suite".to_owned();
+            return Some((ALL_SUITES.to_owned(), why));
+        }
+
+        let suites = match *self {
+            Suites::Names(_) => return None,
+            Suites::Defined(ref suites) => suites,
+        };
+
+        let mut aliased: BTreeMap<&str, &str> = BTreeMap::new();
+        for (name, suite) in suites {
+            for alias in suite.aliases.iter().flat_map(|aliases| aliases) {
+                let why = if alias.is_empty() || alias.contains('/') || alias.starts_with('.') {
+                    format!("has the alias '{}', which is not a name of a directory", alias)
+                } else if suites.contains_key(alias) {
+                    format!("has the alias {}, which is the name of a suite", alias)
+                } else if let Some(other) = aliased.insert(alias, name) {
+                    format!("has the alias {}, which {} also has", alias, other)
+                } else {
+                    continue;
+                };
+
+                return Some((name.clone(), why));
+            }
+        }
+
+        for (name, suite) in suites {
+            for component in suite.components.iter().flat_map(|components| components) {
+                if !is_component(component) {
+                    let why = format!(
+                        "has the component '{}', which must be a name of lowercase letters, \
+                         digits, '.', '+', or '-', which is not 'source' or 'binary-*'",
+                        component
+                    );
+                    return Some((name.clone(), why));
+                }
+            }
+
+            for depends in suite.depends.iter().flat_map(|depends| depends) {
+                if !suites.contains_key(depends) {
+                    let why = format!("depends on {}, which is not a suite", depends);
+                    return Some((name.clone(), why));
+                }
+            }
+
+            if let Some(ref pattern) = suite.version_pattern {
+                if let Err(why) = Regex::new(pattern) {
+                    return Some((name.clone(), format!("has an invalid version_pattern: {}", why)));
+                }
+            }
+
+            let mut chain = vec![name.as_str()];
+            let mut suite = suite;
+            while let Some(ref parent) = suite.inherits {
+                if chain.contains(&parent.as_str()) {
+                    chain.push(parent);
+                    let why = format!("inherits itself: {}", chain.join(" -> "));
+                    return Some((name.clone(), why));
+                }
+
+                suite = match suites.get(parent) {
+                    Some(suite) => suite,
+                    None => {
+                        let why = format!("inherits {}, which is not a suite", parent);
+                        return Some((name.clone(), why));
+                    }
+                };
+
+                chain.push(parent);
+            }
+        }
+
+        None
+    }
+}
+
+impl Config {
+    /// The names of the suites which the config declares, if it declares any.
+    pub fn suite_names(&self) -> Option<Vec<String>> {
+        self.suites.as_ref().map(Suites::names)
+    }
+
+    /// The settings of the suite, which are those at the top of the config, overridden by the
+    /// suites which it inherits from, and then by its own. Suites must be validated first.
+    pub fn effective_suite(&self, name: &str) -> EffectiveSuite {
+        let mut effective = EffectiveSuite {
+            version: self.version.clone(),
+            origin: self.origin.clone(),
+            label: self.label.clone(),
+            email: self.email.clone(),
+            release_key: self.release_key.clone(),
+            incoming_release_key: self.incoming_release_key.clone(),
+            not_automatic: false,
+            but_automatic_upgrades: false,
+            base: None,
+            depends: Vec::new(),
+            version_pattern: None,
+            frozen: false,
+            aliases: Vec::new(),
+            architectures: self.architectures.clone().unwrap_or_else(|| {
+                DEFAULT_ARCHITECTURES.iter().map(|&arch| arch.to_owned()).collect()
+            }),
+            components: Vec::new(),
+            upload_targets: Vec::new(),
+        };
+
+        // The suite comes first, and the root of its inheritance last.
+        let mut chain = Vec::new();
+        if let Some(Suites::Defined(ref suites)) = self.suites {
+            let mut next = suites.get(name);
+            while let Some(suite) = next {
+                chain.push(suite);
+                next = suite.inherits.as_ref().and_then(|parent| suites.get(parent));
+            }
+        }
+
+        if let Some(suite) = chain.first() {
+            effective.frozen = suite.frozen.unwrap_or(false);
+            effective.aliases = suite.aliases.clone().unwrap_or_default();
+        }
+
+        for suite in chain.into_iter().rev() {
+            let set = |field: &mut String, value: &Option<String>| {
+                if let Some(ref value) = *value {
+                    *field = value.clone();
+                }
+            };
+
+            set(&mut effective.version, &suite.version);
+            set(&mut effective.origin, &suite.origin);
+            set(&mut effective.label, &suite.label);
+            set(&mut effective.email, &suite.email);
+            effective.release_key = suite.release_key.clone().or(effective.release_key);
+            effective.incoming_release_key =
+                suite.incoming_release_key.clone().or(effective.incoming_release_key);
+            if let Some(ref architectures) = suite.architectures {
+                effective.architectures = architectures.clone();
+            }
+
+            effective.components = suite.components.clone().unwrap_or(effective.components);
+            effective.upload_targets =
+                suite.upload_targets.clone().unwrap_or(effective.upload_targets);
+
+            effective.base = suite.base.clone().or(effective.base);
+            effective.depends = suite.depends.clone().unwrap_or(effective.depends);
+            effective.version_pattern = suite.version_pattern.clone().or(effective.version_pattern);
+            effective.not_automatic = suite.not_automatic.unwrap_or(effective.not_automatic);
+            effective.but_automatic_upgrades =
+                suite.but_automatic_upgrades.unwrap_or(effective.but_automatic_upgrades);
+        }
+
+        if effective.base.is_none() && name.ends_with(BACKPORTS) {
+            effective.base = Some(name[..name.len() - BACKPORTS.len()].to_owned());
+        }
+
+        if let Some(base) = effective.base.clone() {
+            let is_suite = match self.suites {
+                Some(Suites::Defined(ref suites)) => suites.contains_key(&base),
+                _ => false,
+            };
+
+            if is_suite && !effective.depends.contains(&base) {
+                effective.depends.push(base);
+            }
+        }
+
+        if effective.base.is_some() && effective.version_pattern.is_none() {
+            effective.version_pattern = Some(DEFAULT_BACKPORTS_VERSION.to_owned());
+        }
+
+        effective
+    }
+
+    /// The keys which sign the dists of the archive, as gpg user IDs or fingerprints: its release
+    /// key, or else the key of its email, and then its incoming key.
+    pub fn release_signers(&self) -> Vec<&str> {
+        let mut signers = vec![self.release_key.as_ref().unwrap_or(&self.email).as_str()];
+        signers.extend(self.incoming_release_key.as_ref().map(|x| x.as_str()));
+        signers
+    }
+
+    /// Whether the packages which are built for the archive are staged instead of published.
+    pub fn is_frozen(&self) -> bool {
+        self.effective_suite(&self.archive).frozen
+    }
+
+    /// Where the debug symbol packages of the archive are published.
+    pub fn ddebs(&self) -> DdebHandling {
+        self.ddeb_handling.unwrap_or(DdebHandling::Main)
+    }
+
+    /// The suite whose indices list the debug symbol packages of the archive, if they are
+    /// published in a suite of their own.
+    pub fn debug_suite(&self) -> Option<String> {
+        match self.ddebs() {
+            DdebHandling::DebugSuite => Some([&self.archive, "-debug"].concat()),
+            _ => None,
+        }
+    }
+
+    /// The suite whose chroot the packages of the archive are built in.
+    pub fn build_suite(&self) -> String {
+        self.effective_suite(&self.archive).base.unwrap_or_else(|| self.archive.clone())
+    }
+
+    /// The suites in the order which a run works on them: each after the suites among them which
+    /// it depends on, directly or through others, and otherwise in the order given. Returns the
+    /// suite and a cycle of its dependencies, if there is one.
+    pub fn suite_order(&self, suites: &[String]) -> Result<Vec<String>, (String, String)> {
+        let mut ordered = Vec::new();
+        for suite in suites {
+            let mut chain = Vec::new();
+            self.order_suite(suite, &mut chain, &mut ordered)
+                .map_err(|why| (suite.clone(), why))?;
+        }
+
+        Ok(ordered.into_iter().filter(|suite| suites.contains(suite)).collect())
+    }
+
+    fn order_suite(
+        &self,
+        suite: &str,
+        chain: &mut Vec<String>,
+        ordered: &mut Vec<String>,
+    ) -> Result<(), String> {
+        if ordered.iter().any(|x| x == suite) {
+            return Ok(());
+        } else if chain.iter().any(|x| x == suite) {
+            chain.push(suite.to_owned());
+            return Err(format!("depends on itself: {}", chain.join(" -> ")));
+        }
+
+        chain.push(suite.to_owned());
+        for depends in self.effective_suite(suite).depends {
+            self.order_suite(&depends, chain, ordered)?;
+        }
+
+        chain.pop();
+        ordered.push(suite.to_owned());
+        Ok(())
+    }
+
+    /// The config of a run in the suite, whose archive is the suite, with its effective settings.
+    pub fn for_suite(&self, name: &str) -> Config {
+        let effective = self.effective_suite(name);
+        let mut config = self.clone();
+        config.archive = name.to_owned();
+        config.version = effective.version;
+        config.origin = effective.origin;
+        config.label = effective.label;
+        config.email = effective.email;
+        config.release_key = effective.release_key;
+        config.incoming_release_key = effective.incoming_release_key;
+        config.architectures = Some(effective.architectures);
+        config
+    }
+
+    /// The suites which a run builds: those which were selected, or each suite if `all` was, or
+    /// else the `default_suite`, or else each suite of `[suites.<name>]` tables, or else the
+    /// archive. Returns a selected suite that is unknown.
+    pub fn selected_suites(&self, selected: &[&str]) -> Result<Vec<String>, String> {
+        let names = self.suite_names();
+        let known = |suite: &str| {
+            names.as_ref().map_or(suite == self.archive, |names| names.iter().any(|x| x == suite))
+        };
+
+        let unknown = selected.iter().find(|&&suite| suite != ALL_SUITES && !known(suite));
+        if let Some(unknown) = unknown {
+            return Err(unknown.to_string());
+        }
+
+        if selected.contains(&ALL_SUITES) {
+            return Ok(names.unwrap_or_else(|| vec![self.archive.clone()]));
+        }
+
+        Ok(match (selected.is_empty(), &self.default_suite, &self.suites) {
+            (false, _, _) => selected.iter().map(|&suite| suite.to_owned()).collect(),
+            (true, &Some(ref suite), _) => vec![suite.clone()],
+            (true, _, &Some(Suites::Defined(ref suites))) => suites.keys().cloned().collect(),
+            (true, _, _) => vec![self.archive.clone()],
+        })
+    }
+
+    /// The suites which a package is worked on in when no suite is given: the `default_suite` of
+    /// its source, or else those which `selected_suites` selects by default.
+    pub fn default_suites(&self, package: &str) -> Vec<String> {
+        let source = self.source.iter().flat_map(|sources| sources)
+            .find(|source| source.name == package.split('@').next().unwrap_or(package));
+        match source.and_then(|source| source.default_suite.as_ref()) {
+            Some(suite) => vec![suite.clone()],
+            None => self.selected_suites(&[]).unwrap_or_default(),
+        }
+    }
+
+    /// The suites which the package is built for, if it is a source, or a variant of one.
+    pub fn suites_of(&self, package: &str) -> Option<Vec<String>> {
+        let source = self.source.iter().flat_map(|sources| sources)
+            .find(|source| source.name == package.split('@').next().unwrap_or(package))?;
+        let suites = self.suite_names().unwrap_or_else(|| vec![self.archive.clone()])
+            .into_iter()
+            .filter(|suite| {
+                source.units(suite).iter()
+                    .any(|unit| unit.name == package || unit.base_name() == package)
+            })
+            .collect();
+        Some(suites)
+    }
+
+    /// The effective settings of each suite, as `[suites.<name>]` tables, after the options which
+    /// profiles override, with those of the profile, which was already applied to the config.
+    pub fn show_effective(&self, profile: Option<&str>) -> Result<String, ParsingError> {
+        #[derive(Serialize)]
+        struct Effective {
+            profile: Option<String>,
+            options: Profile,
+            suites: BTreeMap<String, EffectiveSuite>,
+        }
+
+        let selected = match profile {
+            Some(name) => Some(self.profile(name)?),
+            None => None,
+        };
+
+        toml::to_string(&Effective {
+            profile: profile.map(|name| name.to_owned()),
+            options: self.effective_options(selected),
+            suites: self.effective_suites(),
+        }).map_err(|why| ParsingError::TomlSerialize { why })
+    }
+
+    /// The effective settings of each suite which the config declares, or of the archive.
+    pub fn effective_suites(&self) -> BTreeMap<String, EffectiveSuite> {
+        self.suite_names()
+            .unwrap_or_else(|| vec![self.archive.clone()])
+            .into_iter()
+            .map(|name| {
+                let effective = self.effective_suite(&name);
+                (name, effective)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    fn config(suites: &str) -> Config {
+        let base = "archive = \"stable\"\nversion = \"1\"\norigin = \"o\"\nlabel = \"l\"\n\
+                    email = \"e\"\n";
+        toml::from_str(&[base, suites].concat()).unwrap()
+    }
+
+    #[test]
+    fn suites_inherit_settings() {
+        let config = config(
+            "[suites.stable]\narchitectures = [\"amd64\"]\n\
+             [suites.testing]\ninherits = \"stable\"\nversion = \"2\"\n\
+             [suites.experimental]\ninherits = \"testing\"\nemail = \"x\"\n",
+        );
+
+        assert_eq!(config.suites.as_ref().unwrap().invalid(), None);
+        let experimental = config.effective_suite("experimental");
+        assert_eq!(experimental.version, "2");
+        assert_eq!(experimental.email, "x");
+        assert_eq!(experimental.label, "l");
+        assert_eq!(experimental.architectures, vec!["amd64".to_owned()]);
+        assert_eq!(config.effective_suite("stable").version, "1");
+
+        let testing = config.for_suite("testing");
+        assert_eq!((testing.archive.as_str(), testing.version.as_str()), ("testing", "2"));
+
+        let all = config.selected_suites(&[]).unwrap();
+        assert_eq!(all, vec!["experimental".to_owned(), "stable".to_owned(), "testing".to_owned()]);
+        assert_eq!(config.selected_suites(&["testing"]).unwrap(), vec!["testing".to_owned()]);
+        assert_eq!(config.selected_suites(&["unstable"]), Err("unstable".to_owned()));
+    }
+
+    #[test]
+    fn components_and_upload_targets_cascade() {
+        let config = config(
+            "[[upload]]\nname = \"dak\"\nmethod = \"sftp\"\nfqdn = \"d\"\nincoming = \"/\"\n\
+             [[source]]\nname = \"pop\"\nupload_targets = [\"ppa:system76/pop\", \"dak\"]\n\
+             [suites.stable]\ncomponents = [\"main\", \"contrib\"]\nupload_targets = [\"dak\"]\n\
+             [suites.testing]\ninherits = \"stable\"\n\
+             [suites.experimental]\ninherits = \"testing\"\ncomponents = [\"main\"]\n\
+             upload_targets = []\n",
+        );
+
+        assert_eq!(super::super::validate(&config).ok(), Some(()));
+        let testing = config.effective_suite("testing");
+        assert_eq!(testing.components, vec!["main".to_owned(), "contrib".to_owned()]);
+        assert_eq!(testing.upload_targets, vec!["dak".to_owned()]);
+        let experimental = config.effective_suite("experimental");
+        assert_eq!(experimental.components, vec!["main".to_owned()]);
+        assert!(experimental.upload_targets.is_empty());
+
+        // The targets of the suite follow those of the source, which are not repeated.
+        let pop = &config.source.as_ref().unwrap()[0];
+        let targets = vec!["ppa:system76/pop".to_owned(), "dak".to_owned()];
+        assert_eq!(config.for_suite("testing").upload_targets_of(pop), targets);
+        assert_eq!(config.for_suite("experimental").upload_targets_of(pop), targets);
+
+        let shown = config.show_effective(None).unwrap();
+        assert!(shown.contains("[suites.testing]"), "{}", shown);
+        assert!(shown.contains("components = [\"main\", \"contrib\"]"), "{}", shown);
+
+        let contrib = self::config(
+            "[[source]]\nname = \"pop\"\ncomponent = \"contrib\"\nsuites = [\"testing\"]\n\
+             [suites.stable]\ncomponents = [\"main\"]\n\
+             [suites.testing]\ninherits = \"stable\"\n",
+        );
+        match super::super::validate(&contrib) {
+            Err(ParsingError::Suite { suite, why, .. }) => {
+                assert_eq!(suite, "testing");
+                assert_eq!(why, "does not have the component contrib, which pop is published in");
+            }
+            other => panic!("expected the component to be rejected, found {:?}", other),
+        }
+
+        let unknown = self::config("[suites.stable]\nupload_targets = [\"dak\"]\n");
+        match super::super::validate(&unknown) {
+            Err(ParsingError::Suite { suite, why, .. }) => {
+                assert_eq!(suite, "stable");
+                assert!(why.starts_with("is uploaded to dak, which"), "{}", why);
+            }
+            other => panic!("expected the target to be rejected, found {:?}", other),
+        }
+
+        let invalid = self::config("[suites.stable]\ncomponents = [\"binary-amd64\"]\n");
+        assert_eq!(invalid.suites.as_ref().unwrap().invalid().unwrap().0, "stable");
+    }
+
+    #[test]
+    fn defined_suites_are_written_back() {
+        let config = config(
+            "[suites.stable]\narchitectures = [\"amd64\"]\n\
+             [suites.testing]\ninherits = \"stable\"\nversion = \"2\"\n",
+        );
+
+        let data = config.to_toml().unwrap();
+        let written: Config = toml::from_slice(&data).unwrap();
+        assert_eq!(written.archive, "stable");
+        assert_eq!(written.suite_names(), Some(vec!["stable".to_owned(), "testing".to_owned()]));
+        assert_eq!(written.effective_suite("testing").architectures, vec!["amd64".to_owned()]);
+    }
+
+    #[test]
+    fn packages_are_worked_on_in_their_default_suites() {
+        let config = config(
+            "default_suite = \"stable\"\n\
+             [[source]]\nname = \"pop\"\n\
+             [[source]]\nname = \"edge\"\nsuites = [\"testing\"]\ndefault_suite = \"testing\"\n\
+             [suites.stable]\n[suites.testing]\n",
+        );
+
+        assert_eq!(config.selected_suites(&[]).unwrap(), vec!["stable".to_owned()]);
+        let every = vec!["stable".to_owned(), "testing".to_owned()];
+        assert_eq!(config.selected_suites(&["all"]).unwrap(), every);
+        assert_eq!(config.selected_suites(&["stabel"]), Err("stabel".to_owned()));
+
+        assert_eq!(config.default_suites("pop"), vec!["stable".to_owned()]);
+        assert_eq!(config.default_suites("edge"), vec!["testing".to_owned()]);
+        assert_eq!(config.suites_of("pop"), Some(every));
+        assert_eq!(config.suites_of("edge"), Some(vec!["testing".to_owned()]));
+        assert_eq!(config.suites_of("missing"), None);
+
+        let all: Suites = toml::from_str("[all]\n").unwrap();
+        let (suite, _) = all.invalid().unwrap();
+        assert_eq!(suite, "all");
+    }
+
+    #[test]
+    fn invalid_inheritance_is_found() {
+        let cycle = config(
+            "[suites.a]\ninherits = \"b\"\n[suites.b]\ninherits = \"c\"\n\
+             [suites.c]\ninherits = \"a\"\n",
+        );
+        let (suite, why) = cycle.suites.as_ref().unwrap().invalid().unwrap();
+        assert_eq!((suite.as_str(), why.as_str()), ("a", "inherits itself: a -> b -> c -> a"));
+
+        let unknown = config("[suites.testing]\ninherits = \"stabel\"\n");
+        let (suite, why) = unknown.suites.as_ref().unwrap().invalid().unwrap();
+        let expected = ("testing", "inherits stabel, which is not a suite");
+        assert_eq!((suite.as_str(), why.as_str()), expected);
+    }
+
+    #[test]
+    fn backports_are_built_on_their_base_suite() {
+        let config = config(
+            "[[source]]\nname = \"pop\"\nsuites = [\"stable-backports\"]\n\
+             [suites.stable]\n\
+             [suites.stable-backports]\nnot_automatic = true\nbut_automatic_upgrades = true\n\
+             [suites.edge]\nbase = \"stable\"\nversion_pattern = \"~edge[0-9]+$\"\n",
+        );
+
+        let backports = config.for_suite("stable-backports");
+        assert_eq!(backports.build_suite(), "stable");
+        let effective = backports.effective_suite("stable-backports");
+        assert!(effective.not_automatic && effective.but_automatic_upgrades);
+        let pattern = Regex::new(effective.version_pattern.as_ref().unwrap()).unwrap();
+        assert!(pattern.is_match("1.2-1~bpo18.04+1"));
+        assert!(!pattern.is_match("1.2-1"));
+
+        let stable = config.for_suite("stable");
+        assert_eq!(stable.build_suite(), "stable");
+        assert_eq!(stable.effective_suite("stable").version_pattern, None);
+        assert!(stable.build_units().is_empty());
+        assert_eq!(backports.build_units().len(), 1);
+
+        let edge = config.effective_suite("edge");
+        assert_eq!(edge.version_pattern, Some("~edge[0-9]+$".to_owned()));
+
+        let invalid = self::config("[suites.stable-backports]\nversion_pattern = \"~bpo(\"\n");
+        assert!(invalid.suites.as_ref().unwrap().invalid().is_some());
+    }
+
+    #[test]
+    fn freezes_are_not_inherited() {
+        let config = config(
+            "[suites.stable]\nfrozen = true\n[suites.testing]\ninherits = \"stable\"\n",
+        );
+
+        assert!(config.for_suite("stable").is_frozen());
+        assert!(!config.for_suite("testing").is_frozen());
+    }
+
+    #[test]
+    fn aliases_must_be_unique() {
+        let config = config(
+            "[suites.bionic]\naliases = [\"stable\", \"lts\"]\n\
+             [suites.cosmic]\ninherits = \"bionic\"\naliases = [\"testing\"]\n",
+        );
+
+        assert_eq!(config.suites.as_ref().unwrap().invalid(), None);
+        assert_eq!(config.effective_suite("bionic").aliases, vec!["stable", "lts"]);
+        assert_eq!(config.effective_suite("cosmic").aliases, vec!["testing"]);
+
+        let collision = self::config(
+            "[suites.bionic]\naliases = [\"stable\"]\n[suites.cosmic]\naliases = [\"stable\"]\n",
+        );
+        let (suite, why) = collision.suites.as_ref().unwrap().invalid().unwrap();
+        let expected = ("cosmic", "has the alias stable, which bionic also has");
+        assert_eq!((suite.as_str(), why.as_str()), expected);
+
+        let suite_name = self::config("[suites.bionic]\naliases = [\"cosmic\"]\n[suites.cosmic]\n");
+        assert!(suite_name.suites.as_ref().unwrap().invalid().is_some());
+    }
+
+    #[test]
+    fn suites_are_ordered_after_their_dependencies() {
+        let config = config(
+            "[suites.focal]\n[suites.focal-backports]\n\
+             [suites.focal-extras]\ndepends = [\"focal-backports\"]\n",
+        );
+
+        assert_eq!(config.effective_suite("focal-backports").depends, vec!["focal"]);
+        let all = config.selected_suites(&[]).unwrap();
+        let ordered = config.suite_order(&all).unwrap();
+        assert_eq!(ordered, vec!["focal", "focal-backports", "focal-extras"]);
+
+        let selected = vec!["focal-extras".to_owned(), "focal".to_owned()];
+        assert_eq!(config.suite_order(&selected).unwrap(), vec!["focal", "focal-extras"]);
+
+        let cycle = self::config(
+            "[suites.focal]\ndepends = [\"focal-backports\"]\n[suites.focal-backports]\n",
+        );
+        let all = cycle.selected_suites(&[]).unwrap();
+        let (suite, why) = cycle.suite_order(&all).unwrap_err();
+        let expected = ("focal", "depends on itself: focal -> focal-backports -> focal");
+        assert_eq!((suite.as_str(), why.as_str()), expected);
+
+        let unknown = self::config("[suites.focal]\ndepends = [\"bionic\"]\n");
+        assert!(unknown.suites.as_ref().unwrap().invalid().is_some());
+    }
+
+    #[test]
+    fn lists_of_suites_share_the_top_level_settings() {
+        let config = config("suites = [\"stable\", \"testing\"]\n");
+        assert_eq!(config.selected_suites(&[]).unwrap(), vec!["stable".to_owned()]);
+        let effective = config.effective_suites();
+        assert_eq!(effective.len(), 2);
+        assert_eq!(effective["testing"].architectures.len(), DEFAULT_ARCHITECTURES.len());
+    }
+}
@@ -0,0 +1,125 @@
+//! Archives which the packages of sources are uploaded to once they were built, such as a
+//! Launchpad PPA, or a dak instance. Targets are described as hosts of `dput.cf` are, and sources
+//! name those which they are uploaded to in `upload_targets`. A target of the form
+//! `ppa:<user>/<name>` is a PPA, which needs no definition.
+
+use super::{Config, Source};
+
+/// Where Launchpad accepts uploads to PPAs.
+const LAUNCHPAD: &str = "ppa.launchpad.net";
+
+/// How files are written to the incoming directory of a target.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadMethod {
+    Ftp,
+    Sftp,
+    /// An HTTP `PUT` of each file.
+    Https,
+}
+
+/// An archive which built packages are uploaded to.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UploadTarget {
+    /// The name which sources give in `upload_targets`.
+    pub name: String,
+    pub method: UploadMethod,
+    /// The host of the archive.
+    pub fqdn: String,
+    /// The directory which uploads are written to, relative to the root of the server.
+    pub incoming: String,
+    /// The user which logs in: `anonymous` for FTP when unset, and the user of ssh for SFTP.
+    pub login: Option<String>,
+    /// Set to false to upload `.changes` files which are not signed. Defaults to true.
+    #[serde(default = "default_signed")]
+    pub signed: bool,
+}
+
+fn default_signed() -> bool { true }
+
+impl Config {
+    /// The targets which the packages of the source are uploaded to when they are built for the
+    /// archive: those of the source, and then those of the suite.
+    pub fn upload_targets_of(&self, source: &Source) -> Vec<String> {
+        let mut targets = source.upload_targets.clone().unwrap_or_default();
+        for target in self.effective_suite(&self.archive).upload_targets {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+
+        targets
+    }
+
+    /// The target of the name: one which is configured, or else the PPA which it names.
+    pub fn upload_target(&self, name: &str) -> Option<UploadTarget> {
+        let configured = self.upload.iter().flat_map(|x| x).find(|target| target.name == name);
+        if let Some(target) = configured {
+            return Some(target.clone());
+        }
+
+        parse_ppa(name).map(|(user, ppa)| UploadTarget {
+            name: name.to_owned(),
+            method: UploadMethod::Ftp,
+            fqdn: LAUNCHPAD.to_owned(),
+            incoming: ["~", user, "/ubuntu/", ppa].concat(),
+            login: None,
+            signed: true,
+        })
+    }
+}
+
+/// The user and name of a PPA, from `ppa:user/name`.
+pub fn parse_ppa(ppa: &str) -> Option<(&str, &str)> {
+    if !ppa.starts_with("ppa:") {
+        return None;
+    }
+
+    let mut fields = ppa[4..].splitn(2, '/');
+    match (fields.next(), fields.next()) {
+        (Some(user), Some(name)) if !user.is_empty() && !name.is_empty() && !name.contains('/') => {
+            Some((user, name))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    #[test]
+    fn ppa_names() {
+        assert_eq!(parse_ppa("ppa:system76/pop"), Some(("system76", "pop")));
+        assert_eq!(parse_ppa("system76/pop"), None);
+        assert_eq!(parse_ppa("ppa:system76"), None);
+        assert_eq!(parse_ppa("ppa:system76/pop/extra"), None);
+    }
+
+    #[test]
+    fn targets_are_configured_or_ppas() {
+        let config: Config = toml::from_str(r#"
+archive = "bionic"
+version = "18.04"
+origin = "pop-os-staging-ubuntu"
+label = "Pop!_OS Staging"
+email = "debrepbuild@example.com"
+
+[[upload]]
+name = "internal-dak"
+method = "sftp"
+fqdn = "dak.example.com"
+incoming = "/srv/dak/queue/incoming"
+login = "debrep"
+"#).unwrap();
+
+        let dak = config.upload_target("internal-dak").unwrap();
+        assert_eq!(dak.method, UploadMethod::Sftp);
+        assert!(dak.signed);
+
+        let ppa = config.upload_target("ppa:system76/pop").unwrap();
+        assert_eq!((ppa.fqdn.as_str(), ppa.incoming.as_str()), (LAUNCHPAD, "~system76/ubuntu/pop"));
+        assert_eq!(config.upload_target("dak"), None);
+    }
+}
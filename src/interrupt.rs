@@ -0,0 +1,41 @@
+//! Stops runs cleanly when debrep receives SIGINT or SIGTERM.
+//!
+//! The handler only records the signal. Work which is in progress checks for it, and stops
+//! what it is doing, so that builds are not recorded as done, and partial files are removed. A
+//! second signal exits at once.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use libc;
+
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle(signal: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+/// Handles SIGINT and SIGTERM by interrupting the run, instead of exiting.
+pub fn install() {
+    unsafe {
+        libc::signal(libc::SIGINT, handle as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handle as *const () as libc::sighandler_t);
+    }
+}
+
+/// Whether the run was interrupted, after which no new work should be started.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Interrupts the run as if a signal was received, such as to stop a run from another thread.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// The error of work which was stopped because the run was interrupted.
+pub fn error() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "the run was interrupted")
+}
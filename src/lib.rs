@@ -0,0 +1,61 @@
+//! Builds and maintains Debian repositories from a `sources.toml` configuration.
+//!
+//! A [`Repo`](struct.Repo.html) is opened with a parsed [`Config`](config/struct.Config.html),
+//! and each phase of a run is a method of it which returns what it did, or why it failed.
+//! Paths are relative to the current working directory, which is the directory of
+//! `sources.toml`, and is never changed. Progress is logged through the `log` facade.
+
+extern crate crossbeam_utils;
+extern crate deflate;
+extern crate failure;
+extern crate fern;
+extern crate flate2;
+extern crate futures;
+extern crate glob;
+extern crate libc;
+extern crate md5;
+extern crate rayon;
+extern crate regex;
+extern crate reqwest;
+extern crate select;
+extern crate serde;
+extern crate serde_json;
+extern crate sha1;
+extern crate sha2;
+extern crate subprocess;
+extern crate tempdir;
+extern crate tokio_core;
+extern crate toml;
+extern crate walkdir;
+extern crate xz2;
+
+#[macro_use]
+extern crate failure_derive;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod command;
+pub mod config;
+pub mod interrupt;
+pub mod logging;
+pub mod misc;
+pub mod output;
+pub mod repo;
+pub mod retry;
+
+pub use command::CommandError;
+pub use config::{Config, ParsingError, Source};
+pub use output::{Status, Summary};
+pub use repo::{
+    Build, BuildError, BuildFailures, BuildOptions, BuildResult, Change, Check, DownloadFailure,
+    DownloadFailures, FileProvenance, GcArea, GcReport, Growth, Intent, Measure, Migration,
+    OriginKind, Outcome, Phase, Preemption, Queue, QueuedBuild, ReleaseError, Repo, RepoError,
+    RepoLock, Reproduction, Sample, Selection,
+};
+
+pub const SHARED_ASSETS: &str = "assets/share/";
+pub const PACKAGE_ASSETS: &str = "assets/packages/";
@@ -0,0 +1,392 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fern;
+use libc;
+use log::{self, Level, Log, Metadata, Record};
+use output;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+const IDENTIFIER: &str = "debrep";
+
+/// Where the application's own log records are written to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    Journald,
+    Syslog,
+    Stderr,
+}
+
+impl Default for LogTarget {
+    fn default() -> LogTarget { LogTarget::Stderr }
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<LogTarget, String> {
+        match target {
+            "journald" => Ok(LogTarget::Journald),
+            "syslog" => Ok(LogTarget::Syslog),
+            "stderr" => Ok(LogTarget::Stderr),
+            _ => Err(format!("unknown log target: {}", target)),
+        }
+    }
+}
+
+lazy_static! {
+    /// The status line which is kept beneath log messages on the terminal, if any.
+    static ref STATUS: Mutex<Option<String>> = Mutex::new(None);
+    /// The ID of the current run, and the log which every record of the run is copied to.
+    static ref RUN: Mutex<Option<(String, File)>> = Mutex::new(None);
+    /// Every warning which has been logged, so that they can be listed again at the end of a run.
+    static ref WARNINGS: Mutex<Vec<Warning>> = Mutex::new(Vec::new());
+}
+
+/// A warning which was logged during the run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    /// The module which logged the warning.
+    pub category: String,
+    /// The package which was being built, if any.
+    pub package: Option<String>,
+    pub message: String,
+}
+
+/// The warnings which have been logged so far.
+pub fn warnings() -> Vec<Warning> {
+    WARNINGS.lock().unwrap().clone()
+}
+
+/// Tags every following record with the ID of the run, and copies them into the run's log.
+pub fn set_run(id: &str, log: File) {
+    *RUN.lock().unwrap() = Some((id.to_owned(), log));
+}
+
+/// Stops copying records into the log of the run, and forgets its warnings, once it ended.
+pub fn end_run() {
+    *RUN.lock().unwrap() = None;
+    WARNINGS.lock().unwrap().clear();
+}
+
+fn run_id() -> Option<String> {
+    RUN.lock().unwrap().as_ref().map(|&(ref id, _)| id.clone())
+}
+
+/// Erases the current line of the terminal.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+/// Sets the status line which is drawn beneath log messages on the terminal, or clears it.
+pub fn set_status(status: Option<String>) {
+    let mut current = STATUS.lock().unwrap();
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+
+    let mut output = String::new();
+    if current.is_some() {
+        output.push_str(CLEAR_LINE);
+    }
+    if let Some(ref status) = status {
+        output.push_str(status);
+    }
+
+    let _ = stderr.write_all(output.as_bytes()).and_then(|_| stderr.flush());
+    *current = status;
+}
+
+/// Writes a message to stderr, above the status line if there is one.
+pub fn eprint(message: &str) {
+    let message = output::for_terminal(message);
+    let status = STATUS.lock().unwrap();
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+
+    let mut output = String::with_capacity(message.len() + 1);
+    if status.is_some() {
+        output.push_str(CLEAR_LINE);
+    }
+    output.push_str(&message);
+    if !message.ends_with('\n') {
+        output.push('\n');
+    }
+    if let Some(ref status) = *status {
+        output.push_str(status);
+    }
+
+    let _ = stderr.write_all(output.as_bytes()).and_then(|_| stderr.flush());
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<(String, String)>> = RefCell::new(None);
+}
+
+/// Attaches the package and suite to every record logged on this thread until it is dropped.
+pub struct Scope {
+    previous: Option<(String, String)>,
+}
+
+pub fn scope(package: &str, suite: &str) -> Scope {
+    let context = Some((package.to_owned(), suite.to_owned()));
+    let previous = CONTEXT.with(|current| current.replace(context));
+    Scope { previous }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CONTEXT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+fn context() -> Option<(String, String)> {
+    CONTEXT.with(|current| current.borrow().clone())
+}
+
+/// Sets up the logger for the given target. If the journal or syslog sockets cannot be connected
+/// to, logs are written to stderr instead.
+pub fn setup(target: LogTarget, level: log::LevelFilter) -> Result<(), fern::InitError> {
+    let (output, fallback) = match target {
+        LogTarget::Journald => match Journal::connect() {
+            Ok(journal) => (fern::Dispatch::new().chain(Box::new(journal) as Box<Log>), None),
+            Err(why) => (stderr(), Some(("journal", why))),
+        },
+        LogTarget::Syslog => match Syslog::connect() {
+            Ok(syslog) => (fern::Dispatch::new().chain(Box::new(syslog) as Box<Log>), None),
+            Err(why) => (stderr(), Some(("syslog", why))),
+        },
+        LogTarget::Stderr => (stderr(), None),
+    };
+
+    fern::Dispatch::new()
+        // Exclude logs for crates that we use
+        .level(log::LevelFilter::Off)
+        // Include only the logs for this binary
+        .level_for("debrep", level)
+        .chain(output)
+        .chain(Box::new(RunLog) as Box<Log>)
+        .chain(Box::new(Ledger) as Box<Log>)
+        .apply()?;
+
+    if let Some((name, why)) = fallback {
+        warn!("unable to connect to the {}, so logging to stderr instead: {}", name, why);
+    }
+
+    Ok(())
+}
+
+fn stderr() -> fern::Dispatch {
+    fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!("[{}] {}: {}", record.level(), short_target(record), message))
+        })
+        .chain(Box::new(Terminal) as Box<Log>)
+}
+
+fn short_target<'a>(record: &Record<'a>) -> &'a str {
+    let target = record.target();
+    target.find(':').map_or(target, |pos| &target[..pos])
+}
+
+/// Writes formatted records to stderr, keeping the status line beneath them.
+struct Terminal;
+
+impl Log for Terminal {
+    fn enabled(&self, _metadata: &Metadata) -> bool { true }
+
+    fn log(&self, record: &Record) {
+        eprint(&record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Copies records into the log of the current run, if there is one.
+struct RunLog;
+
+impl Log for RunLog {
+    fn enabled(&self, _metadata: &Metadata) -> bool { true }
+
+    fn log(&self, record: &Record) {
+        if let Some((_, ref mut log)) = *RUN.lock().unwrap() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let (level, target) = (record.level(), short_target(record));
+            let _ = writeln!(log, "{} [{}] {}: {}", now, level, target, record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Collects warnings into the ledger.
+struct Ledger;
+
+impl Log for Ledger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() == Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() != Level::Warn {
+            return;
+        }
+
+        let target = record.target();
+        let category = target.find("::").map_or(target, |pos| &target[pos + 2..]);
+        WARNINGS.lock().unwrap().push(Warning {
+            category: category.to_owned(),
+            package: context().map(|(package, _)| package),
+            message: record.args().to_string(),
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// The syslog severity that corresponds to a log level.
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Writes records to the systemd journal using its native datagram protocol.
+struct Journal {
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    fn connect() -> io::Result<Journal> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(Journal { socket })
+    }
+}
+
+/// Appends a field to a journal entry. Values which contain a newline must be written with an
+/// explicit length, rather than being terminated by a newline.
+fn journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        let length = value.len() as u64;
+        entry.push(b'\n');
+        for byte in 0..8 {
+            entry.push((length >> (byte * 8)) as u8);
+        }
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+fn journal_entry(record: &Record, context: Option<(String, String)>) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(256);
+    journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+    journal_field(&mut entry, "PRIORITY", &priority(record.level()).to_string());
+    journal_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    journal_field(&mut entry, "CODE_MODULE", record.target());
+    if let Some(file) = record.file() {
+        journal_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        journal_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    if let Some((package, suite)) = context {
+        journal_field(&mut entry, "PACKAGE", &package);
+        journal_field(&mut entry, "SUITE", &suite);
+    }
+    if let Some(run) = run_id() {
+        journal_field(&mut entry, "RUN_ID", &run);
+    }
+    entry
+}
+
+impl Log for Journal {
+    fn enabled(&self, _metadata: &Metadata) -> bool { true }
+
+    fn log(&self, record: &Record) {
+        let entry = journal_entry(record, context());
+        if self.socket.send(&entry).is_err() {
+            // Entries which are too large for a datagram are not lost.
+            eprint(&format!("[{}] {}", record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Writes records to the local syslog daemon.
+struct Syslog {
+    socket: UnixDatagram,
+    pid: i32,
+}
+
+impl Syslog {
+    fn connect() -> io::Result<Syslog> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SYSLOG_SOCKET)?;
+        Ok(Syslog { socket, pid: unsafe { libc::getpid() } })
+    }
+}
+
+impl Log for Syslog {
+    fn enabled(&self, _metadata: &Metadata) -> bool { true }
+
+    fn log(&self, record: &Record) {
+        // The user facility is 1, and the facility is stored in the upper bits.
+        let mut message = format!("<{}>{}[{}]: ", 8 + priority(record.level()), IDENTIFIER, self.pid);
+        if let Some((package, suite)) = context() {
+            let _ = write!(message, "{} ({}): ", package, suite);
+        }
+        let _ = write!(message, "{}", record.args());
+
+        if self.socket.send(message.as_bytes()).is_err() {
+            eprint(&format!("[{}] {}", record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_fields() {
+        let mut entry = Vec::new();
+        journal_field(&mut entry, "PACKAGE", "pop-desktop");
+        assert_eq!(&entry[..], b"PACKAGE=pop-desktop\n");
+
+        entry.clear();
+        journal_field(&mut entry, "MESSAGE", "first\nsecond");
+        assert_eq!(&entry[..], b"MESSAGE\n\x0c\0\0\0\0\0\0\0first\nsecond\n");
+    }
+
+    #[test]
+    fn scopes_are_restored() {
+        {
+            let _outer = scope("outer", "cosmic");
+            {
+                let _inner = scope("inner", "bionic");
+                assert_eq!(context(), Some(("inner".to_owned(), "bionic".to_owned())));
+            }
+            assert_eq!(context(), Some(("outer".to_owned(), "cosmic".to_owned())));
+        }
+        assert_eq!(context(), None);
+    }
+}
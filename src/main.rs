@@ -1,17 +1,8 @@
-extern crate deflate;
+extern crate debrepobuilder;
 extern crate failure;
 extern crate fern;
-extern crate glob;
-extern crate libc;
-extern crate rayon;
-extern crate reqwest;
-extern crate select;
 extern crate serde;
-extern crate sha2;
-extern crate subprocess;
-extern crate toml;
-extern crate walkdir;
-extern crate xz2;
+extern crate serde_json;
 
 #[macro_use]
 extern crate clap;
@@ -23,142 +14,1212 @@ extern crate log;
 extern crate serde_derive;
 
 mod cli;
-pub mod config;
-pub mod misc;
-mod repo;
-
-use clap::{Arg, App, AppSettings, SubCommand};
-use cli::Action;
-use config::ConfigFetch;
-use repo::{Packages, Repo};
-use std::process::exit;
+mod serve;
 
-pub const SHARED_ASSETS: &str = "assets/share/";
-pub const PACKAGE_ASSETS: &str = "assets/packages/";
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+use cli::{Action, CliError};
+use debrepobuilder::{
+    command, config, interrupt, logging, misc, output, BuildOptions, BuildResult, Change, Check,
+    FileProvenance, GcReport, Growth, Measure, Migration, Preemption, Repo, RepoError, RepoLock,
+    Sample, Selection,
+};
+use debrepobuilder::config::ConfigFetch;
+use debrepobuilder::logging::LogTarget;
+use debrepobuilder::output::ColorChoice;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 
 include!(concat!(env!("OUT_DIR"), "/version.rs"));
 
-fn setup_logger() -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
-        // Exclude logs for crates that we use
-        .level(log::LevelFilter::Off)
-        // Include only the logs for this binary
-        .level_for("debrep", log::LevelFilter::Debug)
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}] {}: {}",
-                record.level(),
-                {
-                    let target = record.target();
-                    target.find(':').map_or(target, |pos| &target[..pos])
-                },
-                message
-            ))
-        })
-        .chain(std::io::stderr())
-        .apply()?;
-    Ok(())
+/// The options of the build phase, which `run` and `build` accept before or after `packages`.
+fn build_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("follow")
+            .long("follow")
+            .help("streams the output of sbuild to the terminal"),
+        Arg::with_name("deny-warnings")
+            .long("deny-warnings")
+            .help("exits with an error if any warnings were logged"),
+        Arg::with_name("no-sign")
+            .long("no-sign")
+            .help("does not sign built packages, even if signing is configured"),
+        unsigned_arg(),
+        Arg::with_name("detect-network")
+            .long("detect-network")
+            .help("logs which hosts builds connect to, instead of blocking them"),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("fails if pool files of direct entries do not match their checksums"),
+        Arg::with_name("replace-orig")
+            .long("replace-orig")
+            .help("replaces published upstream tarballs whose contents changed"),
+        Arg::with_name("replace-published")
+            .long("replace-published")
+            .help("replaces published packages whose contents changed without a new version"),
+        Arg::with_name("keep-going")
+            .long("keep-going")
+            .help("builds and publishes what was fetched when packages fail to download"),
+        Arg::with_name("fail-fast")
+            .long("fail-fast")
+            .help("stops at the first package which fails to build, and publishes nothing"),
+        Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("shows what would be fetched and built in each suite, without doing it, and \
+                   exits with 2 if anything would be"),
+        suite_arg(),
+    ]
+}
+
+fn suite_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("suite")
+        .long("suite")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("works on this suite, which may be given more than once, or on every suite with \
+               `all`; defaults to the default_suite, or to every suite")
+}
+
+fn unsigned_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("unsigned")
+        .long("unsigned")
+        .help("publishes the dists without signing them, for repos which are only used locally")
+}
+
+fn failed_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("failed")
+        .long("failed")
+        .help("only works on the sources whose latest build in the suite failed")
+}
+
+fn override_freeze_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("override-freeze")
+        .long("override-freeze")
+        .help("changes the pool of a frozen suite")
+}
+
+fn packages_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("packages")
+        .about("builds the specified packages")
+        .alias("pkg")
+        .arg(Arg::with_name("packages").multiple(true).required(true))
+        .arg(Arg::with_name("force")
+            .short("f")
+            .long("force")
+            .group("action")
+            .help("forces the package to be built"))
+        .arg(Arg::with_name("priority")
+            .long("priority")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .help("builds the packages at this priority, instead of those of the config"))
+        .arg(Arg::with_name("preempt")
+            .long("preempt")
+            .help("pauses the builds in progress after their current package, until these are \
+                   built"))
 }
 
 fn main() {
-    setup_logger().unwrap();
     let version = format!("{} ({})", crate_version!(), short_sha());
+    let matches = app(&version).get_matches();
+    exit(match execute(&matches) {
+        Ok(()) => 0,
+        Err(why) => {
+            why.report();
+            why.exit_code()
+        }
+    });
+}
 
-    let matches = App::new("Debian Repository Builder")
+fn app<'a>(version: &'a str) -> App<'a, 'a> {
+    App::new("Debian Repository Builder")
         .about("Creates and maintains debian repositories")
         .author(crate_authors!())
-        .version(version.as_str())
+        .version(version)
         .global_setting(AppSettings::ColoredHelp)
         .global_setting(AppSettings::UnifiedHelpMessage)
         .setting(AppSettings::SubcommandRequiredElseHelp)
-        .subcommand(SubCommand::with_name("build")
-            .about("Builds a new repo, or updates an existing one")
+        .arg(Arg::with_name("directory")
+            .short("C")
+            .takes_value(true)
+            .help("runs in this directory, which has the sources.toml of the repo"))
+        .arg(Arg::with_name("log-target")
+            .long("log-target")
+            .takes_value(true)
+            .possible_values(&["journald", "syslog", "stderr"])
+            .help("where debrep's own log messages are written to"))
+        .arg(Arg::with_name("offline")
+            .long("offline")
+            .global(true)
+            .help("only uses what was already downloaded, failing whatever needs the network"))
+        .arg(Arg::with_name("no-upload")
+            .long("no-upload")
+            .global(true)
+            .help("leaves the uploads of built packages to their upload_targets for a later run"))
+        .arg(Arg::with_name("jobs")
+            .short("j")
+            .long("jobs")
+            .takes_value(true)
+            .global(true)
+            .help("the most threads to work with, such as to download and hash files"))
+        .arg(Arg::with_name("no-cache")
+            .long("no-cache")
+            .global(true)
+            .help("hashes every file again, instead of using the digests which were cached"))
+        .arg(Arg::with_name("ignore-space-check")
+            .long("ignore-space-check")
+            .global(true)
+            .help("only warns when downloads and builds may not have the disk space they need"))
+        .arg(Arg::with_name("report-junit")
+            .long("report-junit")
+            .takes_value(true)
+            .value_name("PATH")
+            .global(true)
+            .help("writes the outcome of each package of the run to the path as JUnit XML"))
+        .arg(Arg::with_name("legacy-records")
+            .long("legacy-records")
+            .global(true)
+            .help("also writes the record files of earlier versions, for scripts which read them"))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .takes_value(true)
+            .global(true)
+            .help("overrides options with those of this profile of the config; defaults to \
+                   DEBREP_PROFILE"))
+        .arg(Arg::with_name("ci")
+            .long("ci")
+            .help("writes one line as each package starts and finishes, and only logs warnings"))
+        .arg(Arg::with_name("color")
+            .long("color")
+            .takes_value(true)
+            .possible_values(&["never", "auto", "always"])
+            .default_value("auto")
+            .help("whether to color output; auto disables color if NO_COLOR is set"))
+        .subcommand(SubCommand::with_name("init")
+            .about("Creates the directories of a new repo, with an example sources.toml")
+            .arg(Arg::with_name("directory")
+                .help("where to create the repo; defaults to the current directory"))
+        ).subcommand(SubCommand::with_name("run")
+            .about("Fetches, builds, and publishes a new repo, or updates an existing one")
+            .args(&build_args())
+            .arg(failed_arg())
+            .subcommand(packages_subcommand().args(&build_args()))
+        ).subcommand(SubCommand::with_name("rebuild")
+            .about("Fetches, rebuilds without changes, and publishes sources, as for a new suite")
+            .args(&build_args())
+            .arg(Arg::with_name("packages")
+                .multiple(true)
+                .help("the sources to rebuild; defaults to every source of the suite"))
+            .arg(Arg::with_name("reason")
+                .long("reason")
+                .takes_value(true)
+                .help("why the sources are rebuilt, which their changelogs and records name"))
+            .arg(Arg::with_name("reproduce")
+                .long("reproduce")
+                .takes_value(true)
+                .value_name("PACKAGE")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["packages", "reason"])
+                .help("builds the source again from what was fetched, and compares its packages \
+                       with those of the pool, without changing the pool; may be given more than \
+                       once"))
+        ).subcommand(SubCommand::with_name("fetch")
+            .about("Only downloads packages and fetches sources, without building them")
+            .arg(Arg::with_name("packages").multiple(true))
+            .arg(suite_arg())
+        ).subcommand(SubCommand::with_name("build")
+            .about("Only builds the sources which were fetched, and moves them into the pool")
             .alias("b")
-            .subcommand(SubCommand::with_name("packages")
-                .about("builds the specified packages")
-                .alias("pkg")
-                .arg(Arg::with_name("packages").multiple(true).required(true))
-                .arg(Arg::with_name("force")
-                    .short("f")
-                    .long("force")
-                    .group("action")
-                    .help("forces the package to be built"))
-            )
+            .args(&build_args())
+            .arg(failed_arg())
+            .subcommand(packages_subcommand().args(&build_args()))
             .subcommand(SubCommand::with_name("pool")
                 .alias("p")
-                .about("only builds the pool"))
+                .about("only downloads packages into the pool; the same as `fetch`"))
             .subcommand(SubCommand::with_name("dist")
                 .alias("d")
-                .about("only builds the dist files"))
+                .about("only builds the dist files; the same as `publish`"))
+        ).subcommand(SubCommand::with_name("publish")
+            .about("Only generates the dist files of the pool, and signs them")
+            .arg(Arg::with_name("deny-warnings")
+                .long("deny-warnings")
+                .help("exits with an error if any warnings were logged"))
+            .arg(Arg::with_name("strict")
+                .long("strict")
+                .help("fails if pool files of direct entries do not match their checksums"))
+            .arg(unsigned_arg())
+            .arg(suite_arg())
+        ).subcommand(SubCommand::with_name("release")
+            .about("Publishes the packages which were staged while a suite was frozen")
+            .arg(Arg::with_name("deny-warnings")
+                .long("deny-warnings")
+                .help("exits with an error if any warnings were logged"))
+            .arg(Arg::with_name("strict")
+                .long("strict")
+                .help("fails if pool files of direct entries do not match their checksums"))
+            .arg(Arg::with_name("replace-orig")
+                .long("replace-orig")
+                .help("replaces published upstream tarballs whose contents changed"))
+            .arg(Arg::with_name("replace-published")
+                .long("replace-published")
+                .help("replaces published packages whose contents changed without a new version"))
+            .arg(unsigned_arg())
+            .arg(suite_arg())
+        ).subcommand(SubCommand::with_name("check")
+            .about("checks the upstream tarballs of the pool, or the repo against a manifest")
+            .arg(Arg::with_name("manifest")
+                .long("manifest")
+                .takes_value(true)
+                .help("a checksums-<run>.txt file from logs/runs/<run>/"))
+            .arg(Arg::with_name("apt-sim")
+                .long("apt-sim")
+                .conflicts_with("manifest")
+                .help("simulates installing the published packages with apt"))
         ).subcommand(SubCommand::with_name("clean")
             .about("cleans excess packages from the repository")
+            .arg(Arg::with_name("logs")
+                .long("logs")
+                .help("removes old build logs according to the log retention policy"))
+            .arg(Arg::with_name("obsolete-binaries")
+                .long("obsolete-binaries")
+                .conflicts_with("logs")
+                .help("removes binary packages which the latest builds of their sources did not \
+                       produce"))
+            .arg(override_freeze_arg())
         ).subcommand(SubCommand::with_name("config")
             .about("Gets or sets fields within the repo config")
             .alias("c")
             .arg(Arg::with_name("key").required(false))
             .arg(Arg::with_name("value").required(false))
+            .arg(Arg::with_name("effective")
+                .long("effective")
+                .conflicts_with("value")
+                .help("with `show`, prints the settings of each suite after inheritance"))
+        ).subcommand(SubCommand::with_name("gc")
+            .about("removes what the gc policies allow from the cache, build trees, and logs")
+            .arg(Arg::with_name("area")
+                .long("area")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&["cache", "build", "logs"])
+                .help("collects only this area; defaults to every area"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("reports what would be removed, without removing it"))
+        ).subcommand(SubCommand::with_name("keys")
+            .about("manages the keys which downloads are verified with")
+            .subcommand(SubCommand::with_name("list")
+                .about("lists the configured keys, and whether they are present or expired"))
+            .subcommand(SubCommand::with_name("fetch")
+                .about("fetches keys into keys/, checking their fingerprints")
+                .arg(Arg::with_name("keys").multiple(true)))
+            .subcommand(SubCommand::with_name("verify")
+                .about("checks that every configured key is present and has not expired"))
+            .subcommand(SubCommand::with_name("status")
+                .about("shows which keys signed the InRelease file of each suite"))
+        ).subcommand(SubCommand::with_name("migrate")
+            .about("migrates the packages which are ready from one suite to another")
+            .arg(Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .required(true)
+                .help("the suite which packages migrate from, such as proposed"))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .required(true)
+                .help("the suite which packages migrate to, such as release"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("reports what would migrate, without migrating it"))
+            .arg(Arg::with_name("only")
+                .long("only")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("only migrates this package, which may be given more than once"))
+            .arg(Arg::with_name("skip")
+                .long("skip")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("does not migrate this package, which may be given more than once"))
+            .arg(override_freeze_arg())
+        ).subcommand(SubCommand::with_name("record")
+            .about("shows the builds which are recorded in the state database")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("list")
+                .about("lists the latest build of each package of each suite")
+                .arg(Arg::with_name("failed")
+                    .long("failed")
+                    .help("only lists the packages whose latest build failed"))
+                .arg(suite_arg()))
+            .subcommand(SubCommand::with_name("history")
+                .about("lists every build of the package, newest first, with how long it took")
+                .arg(Arg::with_name("package").required(true)))
+            .subcommand(SubCommand::with_name("backfill")
+                .about("records the files of the pool whose provenance is unknown as legacy files"))
+        ).subcommand(SubCommand::with_name("doctor")
+            .about("checks the tools, schroots, directories, keys, and servers which runs need")
+        ).subcommand(SubCommand::with_name("inspect")
+            .about("shows what is recorded of a package")
+            .arg(Arg::with_name("provenance")
+                .long("provenance")
+                .takes_value(true)
+                .value_name("PACKAGE")
+                .required(true)
+                .help("shows where each file of the package in the pools came from"))
+        ).subcommand(SubCommand::with_name("stats")
+            .about("reports the build times and sizes of the latest builds of packages")
+            .arg(Arg::with_name("package")
+                .required_unless("top-growth")
+                .help("the package whose latest builds are listed"))
+            .arg(Arg::with_name("top-growth")
+                .long("top-growth")
+                .conflicts_with("package")
+                .help("lists the packages which grew the most over their latest builds"))
+            .arg(Arg::with_name("by")
+                .long("by")
+                .takes_value(true)
+                .possible_values(&["duration", "deb-size", "installed-size"])
+                .default_value("duration")
+                .help("what --top-growth measures the growth of packages by"))
+            .arg(Arg::with_name("window")
+                .long("window")
+                .takes_value(true)
+                .validator(|window| match window.parse::<usize>() {
+                    Ok(window) if window >= 2 => Ok(()),
+                    _ => Err("must be a number of builds, of at least 2".to_owned()),
+                })
+                .help("how many of the latest builds of each package are reported; defaults to 10"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["table", "csv", "json"])
+                .default_value("table")
+                .help("prints the report as a table, or as CSV or JSON for plotting"))
+            .arg(suite_arg())
         ).subcommand(SubCommand::with_name("remove")
             .about("removes the specified packages from the repository")
             .alias("r")
             .arg(Arg::with_name("packages").multiple(true).required(true))
+            .arg(override_freeze_arg())
+        ).subcommand(SubCommand::with_name("serve")
+            .about("builds packages when they are requested over HTTP, with the token of [serve]")
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .help("the address to listen on; defaults to serve.listen, or 127.0.0.1:8090"))
+        ).subcommand(SubCommand::with_name("status")
+            .about("shows the architectures which each source is built for in each suite")
+        ).subcommand(SubCommand::with_name("upload")
+            .about("uploads the built packages which are waiting for their upload_targets")
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("lists what would be uploaded, and where, without uploading it"))
+            .arg(suite_arg())
         ).subcommand(SubCommand::with_name("update")
             .about("Updates direct download-based packages in the configuration")
             .alias("u")
-        ).get_matches();
-
-    match config::parse() {
-        Ok(mut sources) => {
-            match Action::new(&matches) {
-                Action::Build(packages, force) => {
-                    Repo::prepare(sources, Packages::Select(&packages, force))
-                        .download()
-                        .build()
-                        .generate();
-                },
-                Action::Clean => {
-                    Repo::prepare(sources, Packages::All).clean();
-                },
-                Action::Dist => {
-                    Repo::prepare(sources, Packages::All).generate();
-                },
-                Action::UpdateRepository => {
-                    Repo::prepare(sources, Packages::All)
-                        .download()
-                        .build()
-                        .generate();
-                },
-                Action::Fetch(key) => match sources.fetch(&key) {
-                    Some(value) => println!("{}: {}", key, value),
-                    None => {
-                        error!("config field not found");
-                        exit(1);
-                    }
-                },
-                Action::FetchConfig => println!("sources.toml: {:#?}", &sources),
-                Action::Pool => {
-                    Repo::prepare(sources, Packages::All).download();
-                },
-                Action::Remove(packages) => {
-                    Repo::prepare(sources, Packages::Select(&packages, false)).remove();
-                },
-                Action::Update(key, value) => match sources.update(key, value.to_owned()) {
-                    Ok(()) => match sources.write_to_disk() {
-                        Ok(()) => info!("successfully wrote config changes to disk"),
-                        Err(why) => {
-                            error!("failed to write config changes: {}", why);
-                            exit(1);
+        )
+}
+
+/// Sets up logging and the config for the command, and performs it.
+fn execute(matches: &ArgMatches) -> Result<(), CliError> {
+    // The report is written relative to where debrep was started, rather than to the repo.
+    let junit = matches.value_of("report-junit")
+        .map(|path| env::current_dir().map_or_else(|_| PathBuf::from(path), |dir| dir.join(path)));
+
+    if let Some(dir) = matches.value_of("directory") {
+        env::set_current_dir(dir).map_err(|why| CliError::Directory { dir: dir.to_owned(), why })?;
+    }
+
+    let action = Action::new(matches);
+    let mut options = cli::build_options(matches);
+
+    // Profiles are applied after the config, and before the flags which were given. The config
+    // is written as it is in sources.toml when it is updated.
+    let profile = match action {
+        Action::Update(..) => None,
+        _ => cli::profile(matches),
+    };
+
+    let config = config::parse().and_then(|mut config| {
+        if let Some(ref name) = profile {
+            cli::apply_profile(&mut options, &config.apply_profile(name)?);
+        }
+
+        Ok(config)
+    });
+    if let Ok(ref config) = config {
+        options.offline |= config.offline;
+    }
+
+    let log_target = matches.value_of("log-target")
+        .and_then(|target| target.parse::<LogTarget>().ok())
+        .or_else(|| config.as_ref().ok().and_then(|config| config.log_target))
+        .unwrap_or_default();
+
+    // CI logs must not contain progress noise, or escape sequences.
+    let (level, color) = if options.ci {
+        (log::LevelFilter::Warn, ColorChoice::Never)
+    } else {
+        let color = value_t!(matches, "color", ColorChoice).unwrap_or(ColorChoice::Auto);
+        (log::LevelFilter::Debug, color)
+    };
+
+    logging::setup(log_target, level).map_err(|why| CliError::Logging { why })?;
+    output::init(color, options.ci);
+    interrupt::install();
+
+    if config.is_ok() {
+        if let Some(ref profile) = profile {
+            info!("using the {} profile", profile);
+        }
+    }
+
+    if options.offline && options.detect_network {
+        let why = "--detect-network cannot be used offline".to_owned();
+        return Err(CliError::Usage { why });
+    }
+
+    cli::priority(matches).map_err(|why| CliError::Usage { why })?;
+
+    let jobs = match matches.value_of("jobs").map(|jobs| jobs.parse::<usize>()) {
+        Some(Ok(0)) | Some(Err(_)) => {
+            let why = "--jobs must be a number of threads, of at least 1".to_owned();
+            return Err(CliError::Usage { why });
+        }
+        Some(Ok(jobs)) => Some(jobs),
+        None => None,
+    };
+
+    if let Action::Init(dir) = action {
+        for path in Repo::init(Path::new(dir))? {
+            println!("created {}", Path::new(dir).join(path).display());
+        }
+
+        return Ok(());
+    }
+
+    let mut sources = match config {
+        Ok(sources) => sources,
+        Err(why) => {
+            return Err(if Path::new("sources.toml").exists() {
+                CliError::Config { why }
+            } else {
+                CliError::NoConfig { why }
+            });
+        }
+    };
+
+    if jobs.is_some() {
+        sources.jobs = jobs;
+    }
+
+    // A stalled command must not hold up a CI pipeline until the pipeline's own timeout. The
+    // config is written as it is when it is updated.
+    let updating = match action {
+        Action::Update(..) => true,
+        _ => false,
+    };
+    if options.ci && !updating && sources.subprocess_timeout.is_none() {
+        sources.subprocess_timeout = Some(command::CI_TIMEOUT);
+    }
+
+    let result = perform(matches, action, sources, profile.as_ref().map(String::as_str), options);
+
+    // The report is written however the run ended, so that CI shows what was built before it
+    // failed, or was interrupted.
+    if let Some(path) = junit {
+        match Repo::write_junit(&path) {
+            Ok(cases) => info!("wrote the outcomes of {} packages to {}", cases, path.display()),
+            Err(why) => {
+                if result.is_ok() {
+                    return Err(why.into());
+                }
+
+                error!("{}", why);
+            }
+        }
+    }
+
+    result?;
+    if options.deny_warnings {
+        let count = logging::warnings().len();
+        if count != 0 {
+            return Err(CliError::Warnings { count });
+        }
+    }
+
+    Ok(())
+}
+
+/// Performs the action, on the repo of the config.
+fn perform(
+    matches: &ArgMatches,
+    action: Action,
+    mut sources: config::Config,
+    profile: Option<&str>,
+    options: BuildOptions,
+) -> Result<(), CliError> {
+    match action {
+        Action::Fetch(key) => {
+            let value = sources.fetch(key).ok_or(CliError::NoField)?;
+            println!("{}: {}", key, value);
+            Ok(())
+        }
+        Action::FetchConfig => {
+            println!("sources.toml: {:#?}", &sources);
+            Ok(())
+        }
+        Action::EffectiveConfig => {
+            print!("{}", sources.show_effective(profile).map_err(|why| CliError::Show { why })?);
+            Ok(())
+        }
+        Action::Status => {
+            print_status(&Repo::read_only(sources));
+            Ok(())
+        }
+        Action::RecordList { ref suites, failed } => {
+            print_builds(&Repo::read_only(sources), suites, failed)
+        }
+        Action::Stats { package, ref suites, window, by, format } => {
+            let repo = Repo::read_only(sources);
+            match package {
+                Some(package) => print_stats(&repo, suites, package, window, format),
+                None => print_growth(&repo, suites, window, by, format),
+            }
+        }
+        Action::RecordHistory(package) => {
+            for build in Repo::read_only(sources).build_history(package)? {
+                println!(
+                    "{:<10} {:<12} {:<11} {:<24} {:>7}  {}",
+                    build.date(),
+                    if build.suite.is_empty() { "-" } else { &build.suite },
+                    build.result.as_str(),
+                    build.version.as_ref().map_or("-", |x| x.as_str()),
+                    output::format_duration(build.seconds),
+                    build.run.as_ref().map_or("-", |x| x.as_str())
+                );
+            }
+
+            Ok(())
+        }
+        Action::Doctor => {
+            let checks = Repo::read_only(sources).doctor(options);
+            print!("{}", Check::render(&checks));
+            match checks.iter().filter(|check| check.failed()).count() {
+                0 => Ok(()),
+                failed => Err(CliError::Checks { failed }),
+            }
+        }
+        Action::Inspect(package) => {
+            let records = Repo::read_only(sources).provenance(package)?;
+            if records.is_empty() {
+                return Err(CliError::NoProvenance { package: package.to_owned() });
+            }
+
+            print!("{}", FileProvenance::render(&records));
+            Ok(())
+        }
+        Action::Serve(listen) => {
+            serve::serve(sources, listen, options).map_err(|why| CliError::Serve { why })
+        }
+        Action::Update(key, value) => {
+            sources.update(key, value.to_owned())
+                .map_err(|why| CliError::Update { key: key.to_owned(), why })?;
+            sources.write_to_disk().map_err(|why| CliError::Write { why })?;
+            info!("successfully wrote config changes to disk");
+            Ok(())
+        }
+        action => {
+            // Phases work on each selected suite in turn, and other actions on the archive.
+            // Migrations publish the suite which packages migrate to.
+            let plan = if let Action::Migrate { from, to, .. } = action {
+                sources.selected_suites(&[from, to])
+                    .map_err(|suite| CliError::UnknownSuite { suite })?;
+                vec![(sources.for_suite(to), action.clone())]
+            } else if action.phases().is_empty() {
+                vec![(sources, action.clone())]
+            } else {
+                plan(&sources, &action, &cli::suites(matches))
+                    .map_err(|why| CliError::Plan { why })?
+            };
+
+            if cli::is_dry_run(matches) {
+                return dry_run(plan, options.priority);
+            }
+
+            // The request to preempt the builds in progress is withdrawn once these are done.
+            let preemption = if action.locks() && cli::preempts(matches) {
+                let requested = Preemption::request(Path::new("."));
+                Some(requested.map_err(|why| CliError::Preempt { why })?)
+            } else {
+                None
+            };
+
+            let _lock = if action.locks() {
+                let failed = |why| CliError::Lock { why };
+                match RepoLock::acquire(Path::new("."), false).map_err(&failed)? {
+                    Some(lock) => Some(lock),
+                    None if preemption.is_some() => {
+                        info!("waiting for the builds in progress to pause");
+                        match RepoLock::wait(Path::new(".")).map_err(&failed)? {
+                            Some(lock) => Some(lock),
+                            None => return Err(CliError::Interrupted),
                         }
-                    },
-                    Err(why) => {
-                        error!("failed to update {}: {}", key, why);
-                        exit(1);
                     }
-                },
+                    None => return Err(CliError::Locked),
+                }
+            } else {
+                None
+            };
+
+            work(plan, options).map_err(CliError::from)
+        }
+    }
+}
+
+/// Works on each suite of the plan in turn. A suite which cannot be signed does not stop the
+/// others, but they are reported as unpublished at the end.
+fn work(plan: Vec<(config::Config, Action)>, options: BuildOptions) -> Result<(), RepoError> {
+    let several = plan.len() > 1;
+    let mut unpublished = Vec::new();
+    for (config, action) in plan {
+        let suite = config.archive.clone();
+        if several {
+            info!("working on the {} suite", suite);
+        }
+
+        let worked = Repo::open(config).and_then(|repo| {
+            repo.check_prerequisites(action.phases(), options)?;
+            run(&repo, action, options)
+        });
+
+        match worked {
+            Err(ref why) if several && why.is_suite_only() => {
+                error!("{}", why);
+                unpublished.push(suite);
+            }
+            Err(why) => return Err(why),
+            Ok(()) => (),
+        }
+    }
+
+    if unpublished.is_empty() {
+        Ok(())
+    } else {
+        Err(RepoError::Unpublished { suites: unpublished.join(", ") })
+    }
+}
+
+/// The suites which the action works on, in the order that they are worked on in, with the
+/// action for each. Packages which are named without `--suite` are worked on in their default
+/// suites. A named source which is not built for any suite that it would be worked on in is an
+/// error, which lists the suites that it is built for.
+fn plan<'a>(
+    sources: &config::Config,
+    action: &Action<'a>,
+    selected: &[&str],
+) -> Result<Vec<(config::Config, Action<'a>)>, String> {
+    let unknown = |suite| format!("{} is not a suite of this repo", suite);
+    let mut assigned: Vec<(String, Vec<&'a str>)> = Vec::new();
+    match action.packages() {
+        Some(packages) => {
+            for &package in packages {
+                let suites = if selected.is_empty() {
+                    sources.default_suites(package)
+                } else {
+                    sources.selected_suites(selected).map_err(&unknown)?
+                };
+
+                if let Some(targets) = sources.suites_of(package) {
+                    if !suites.iter().any(|suite| targets.contains(suite)) {
+                        let built = match targets.len() {
+                            0 => "no suite".to_owned(),
+                            _ => targets.join(", "),
+                        };
+
+                        return Err(format!(
+                            "{} is not built for {}; it is built for {}",
+                            package,
+                            suites.join(", "),
+                            built
+                        ));
+                    }
+                }
+
+                for suite in suites {
+                    match assigned.iter().position(|&(ref name, _)| *name == suite) {
+                        Some(pos) => assigned[pos].1.push(package),
+                        None => assigned.push((suite, vec![package])),
+                    }
+                }
             }
-        },
-        Err(why) => {
-            error!("configuration parsing error: {}", why);
-            exit(1);
         }
+        None => {
+            let suites = sources.selected_suites(selected).map_err(&unknown)?;
+            assigned.extend(suites.into_iter().map(|suite| (suite, Vec::new())));
+        }
+    }
+
+    let suites: Vec<String> = assigned.iter().map(|&(ref suite, _)| suite.clone()).collect();
+    let ordered = sources.suite_order(&suites)
+        .map_err(|(suite, why)| format!("suite {} {}", suite, why))?;
+
+    ordered.into_iter()
+        .map(|suite| {
+            let config = sources.for_suite(&suite);
+            let action = match action.packages() {
+                Some(_) => {
+                    let packages = assigned.iter()
+                        .find(|&&(ref name, _)| *name == suite)
+                        .map(|&(_, ref packages)| packages.clone())
+                        .ok_or_else(|| format!("suite {} was not selected", suite))?;
+                    action.with_packages(packages)
+                }
+                None => action.clone(),
+            };
+
+            Ok((config, action))
+        })
+        .collect()
+}
+
+/// Prints what the action would do in each suite of the plan, without doing it. A dry run fails
+/// with `CliError::Changes` if the run would fetch or build anything, so that whether the repo is
+/// up to date can be checked.
+fn dry_run(plan: Vec<(config::Config, Action)>, priority: Option<i32>) -> Result<(), CliError> {
+    let plan: Vec<(Repo, Action)> = plan.into_iter()
+        .map(|(config, action)| (Repo::read_only(config), action))
+        .collect();
+
+    print_plan(&plan, priority);
+    if print_dry_run(&plan)? {
+        Err(CliError::Changes)
+    } else {
+        Ok(())
+    }
+}
+
+/// Prints the suites which would be worked on, in order, with the suites which each is worked on
+/// after, and the sources which would be built for each, in the order of their priorities.
+fn print_plan(plan: &[(Repo, Action)], priority: Option<i32>) {
+    for &(ref repo, ref action) in plan {
+        let config = repo.config();
+        let after: Vec<String> = config.effective_suite(&config.archive).depends.into_iter()
+            .filter(|suite| plan.iter().any(|&(ref repo, _)| repo.config().archive == *suite))
+            .collect();
+
+        let selection = match action.packages() {
+            Some(packages) => Selection::Packages(packages, false),
+            None => Selection::All,
+        };
+
+        let sources: Vec<String> = repo.build_order(&selection, priority).into_iter()
+            .map(|(name, priority)| match priority {
+                0 => name,
+                _ => format!("{} (priority {})", name, priority),
+            })
+            .collect();
+
+        if after.is_empty() {
+            println!("{}: {}", config.archive, sources.join(" "));
+        } else {
+            println!("{} (after {}): {}", config.archive, after.join(", "), sources.join(" "));
+        }
+    }
+}
+
+/// Prints what the action would fetch and build in each suite of the plan, returning whether it
+/// would do anything.
+fn print_dry_run(plan: &[(Repo, Action)]) -> Result<bool, RepoError> {
+    let mut work = false;
+    for &(ref repo, ref action) in plan {
+        let failed = match *action {
+            Action::BuildFailed | Action::RunFailed => repo.failed()?,
+            _ => Vec::new(),
+        };
+
+        let failed: Vec<&str> = failed.iter().map(|x| x.as_str()).collect();
+        let selection = match *action {
+            Action::Build(ref packages, force) | Action::Run(ref packages, force) => {
+                Selection::Packages(packages, force)
+            }
+            Action::Rebuild(ref packages, reason) => Selection::Rebuild(packages, reason),
+            Action::Reproduce(ref packages) => Selection::Packages(packages, true),
+            Action::BuildFailed | Action::RunFailed => Selection::Packages(&failed, false),
+            _ => Selection::All,
+        };
+
+        for intent in repo.dry_run(&selection, action.phases())? {
+            work |= intent.is_work();
+            println!("{}: {}", repo.config().archive, intent);
+        }
+    }
+
+    Ok(work)
+}
+
+/// Prints the architectures of each suite, and those which each of its sources is built for,
+/// marking the sources which this system does not build, along with the latest build of each.
+fn print_status(repo: &Repo) {
+    print_queues(repo);
+    let sources = repo.config();
+    let host = misc::host_architecture().ok();
+    for (name, suite) in sources.effective_suites() {
+        println!("{}: {}", name, suite.architectures.join(" "));
+        let builds = repo.latest_builds(&name).unwrap_or_else(|why| {
+            warn!("failed to read the builds of {}: {}", name, why);
+            Vec::new()
+        });
+
+        for item in sources.for_suite(&name).build_units() {
+            let matrix = item.architectures_in(&suite.architectures);
+            let built = host.as_ref().map_or(true, |host| {
+                matrix.iter().any(|arch| arch == host || arch == "all")
+            });
+
+            let architectures =
+                if matrix.is_empty() { "none".to_owned() } else { matrix.join(" ") };
+            let latest = match builds.iter().find(|build| build.package == item.name) {
+                Some(build) => {
+                    let version = build.version.as_ref().map_or("", |x| x.as_str());
+                    format!("{} {} {}", build.result.as_str(), version, build.date())
+                }
+                None => "never built".to_owned(),
+            };
+
+            if built {
+                println!("  {:<24} {:<16} {}", item.name, architectures, latest);
+            } else {
+                println!("  {:<24} {:<16} {} (not built on this system)", item.name, architectures,
+                    latest);
+            }
+        }
+    }
+}
+
+/// Prints the queues of the batches which are being built, with the position and priority of
+/// each source which is still to be built.
+fn print_queues(repo: &Repo) {
+    let queues = repo.build_queues().unwrap_or_else(|why| {
+        warn!("{}", why);
+        Vec::new()
+    });
+
+    for queue in queues {
+        let state = match (queue.urgent, queue.paused) {
+            (true, _) => "urgent",
+            (false, true) => "paused for urgent builds",
+            (false, false) => "in progress",
+        };
+
+        println!("queue ({}):", state);
+        let building = queue.building.iter().map(|build| ("building".to_owned(), build));
+        let queued = queue.queued.iter()
+            .enumerate()
+            .map(|(pos, build)| ((pos + 1).to_string(), build));
+        for (position, build) in building.chain(queued) {
+            println!(
+                "  {:<8} {:<24} {:<16} priority {}",
+                position,
+                build.package,
+                build.suite,
+                build.priority
+            );
+        }
+    }
+}
+
+/// Prints the latest build of each package of the suites, or only of those which failed.
+fn print_builds(repo: &Repo, suites: &[&str], failed: bool) -> Result<(), CliError> {
+    for suite in selected_suites(repo, suites)? {
+        let builds = repo.latest_builds(&suite)?;
+
+        println!("{}:", suite);
+        for build in builds.iter().filter(|build| !failed || build.result != BuildResult::Built) {
+            println!(
+                "  {:<24} {:<11} {:<24} {:>7}  {}",
+                build.package,
+                build.result.as_str(),
+                build.version.as_ref().map_or("-", |x| x.as_str()),
+                output::format_duration(build.seconds),
+                build.run.as_ref().map_or("-", |x| x.as_str())
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the latest builds of the package in the suites, oldest first, with what was measured
+/// of each.
+fn print_stats(
+    repo: &Repo,
+    suites: &[&str],
+    package: &str,
+    window: usize,
+    format: &str,
+) -> Result<(), CliError> {
+    let mut samples = Vec::new();
+    for suite in selected_suites(repo, suites)? {
+        samples.extend(repo.build_stats(&suite, package, window)?);
+    }
+
+    match format {
+        "csv" => print_csv(Sample::CSV_HEADER, samples.iter().map(Sample::to_csv)),
+        "json" => print_json(&samples)?,
+        _ => {
+            let optional = |value: Option<u64>| value.map_or("-".to_owned(), |x| x.to_string());
+            println!(
+                "{:<10} {:<12} {:<11} {:<24} {:>7} {:>10} {:>10} {:>8}",
+                "date", "suite", "result", "version", "time", "debs (KiB)", "installed", "warnings"
+            );
+            for sample in &samples {
+                println!(
+                    "{:<10} {:<12} {:<11} {:<24} {:>7} {:>10} {:>10} {:>8}",
+                    sample.date,
+                    sample.suite,
+                    sample.result,
+                    sample.version.as_ref().map_or("-", |x| x.as_str()),
+                    output::format_duration(sample.seconds),
+                    optional(sample.deb_bytes.map(|bytes| bytes / 1024)),
+                    optional(sample.installed_size),
+                    optional(sample.warnings)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints how the packages of the suites changed over their latest builds, from the package
+/// which grew the most by the measure.
+fn print_growth(
+    repo: &Repo,
+    suites: &[&str],
+    window: usize,
+    by: Measure,
+    format: &str,
+) -> Result<(), CliError> {
+    let mut growth = Vec::new();
+    for suite in selected_suites(repo, suites)? {
+        growth.extend(repo.build_growth(&suite, window, by)?);
+    }
+
+    match format {
+        "csv" => print_csv(Growth::CSV_HEADER, growth.iter().map(Growth::to_csv)),
+        "json" => print_json(&growth)?,
+        _ => {
+            println!(
+                "{:<24} {:<12} {:>6} {:>17} {:>8} {:>21} {:>8} {:>21} {:>8}",
+                "package", "suite", "builds", "time", "", "debs (KiB)", "", "installed (KiB)", ""
+            );
+            for package in &growth {
+                println!(
+                    "{:<24} {:<12} {:>6} {:>7} -> {:<7} {:>+7.1}% {} {}",
+                    package.package,
+                    package.suite,
+                    package.builds,
+                    output::format_duration(package.seconds.first),
+                    output::format_duration(package.seconds.last),
+                    package.seconds.percent(),
+                    change(Some(package.deb_bytes), 1024),
+                    change(package.installed_size, 1)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A change of a size, in units of `unit` bytes, as `<first> -> <last> <percent>`.
+fn change(change: Option<Change>, unit: u64) -> String {
+    match change {
+        Some(change) => format!(
+            "{:>9} -> {:<9} {:>+7.1}%",
+            change.first / unit,
+            change.last / unit,
+            change.percent()
+        ),
+        None => format!("{:>9} -> {:<9} {:>8}", "-", "-", "-"),
+    }
+}
+
+/// Prints the size of each area which was collected, and what was, or would be, removed from it.
+fn print_gc(reports: &[GcReport], dry_run: bool) {
+    let row = |area: &str, entries: &str, size: &str, reclaimable: &str, reclaimed: &str| {
+        let row = format!(
+            "{:<6} {:>8} {:>11} {:>16} {:>11}",
+            area, entries, size, reclaimable, reclaimed
+        );
+        println!("{}", row.trim_right());
+    };
+
+    row("area", "entries", "size", "reclaimable", if dry_run { "" } else { "reclaimed" });
+    for report in reports {
+        let reclaimable = if report.limited {
+            format!("{} ({})", output::format_size(report.reclaimable_bytes), report.reclaimable)
+        } else {
+            "no policy".to_owned()
+        };
+
+        let reclaimed = if dry_run {
+            String::new()
+        } else {
+            output::format_size(report.reclaimed_bytes)
+        };
+
+        row(
+            report.area.as_str(),
+            &report.entries.to_string(),
+            &output::format_size(report.bytes),
+            &reclaimable,
+            &reclaimed,
+        );
+    }
+}
+
+fn selected_suites(repo: &Repo, suites: &[&str]) -> Result<Vec<String>, CliError> {
+    repo.config().selected_suites(suites).map_err(|suite| CliError::UnknownSuite { suite })
+}
+
+fn print_csv<I: Iterator<Item = String>>(header: &str, rows: I) {
+    println!("{}", header);
+    for row in rows {
+        println!("{}", row);
+    }
+}
+
+fn print_json<T: serde::Serialize>(value: &T) -> Result<(), CliError> {
+    let json = serde_json::to_string_pretty(value).map_err(|why| CliError::Json { why })?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Runs an action which operates on the repository.
+fn run(repo: &Repo, action: Action, options: BuildOptions) -> Result<(), RepoError> {
+    match action {
+        Action::Build(packages, force) => {
+            repo.build(&Selection::Packages(&packages, force), options).map(|_| ())
+        }
+        Action::BuildAll => repo.build(&Selection::All, options).map(|_| ()),
+        Action::BuildFailed => {
+            let failed = repo.failed()?;
+            let failed: Vec<&str> = failed.iter().map(|x| x.as_str()).collect();
+            if failed.is_empty() {
+                info!("no sources of {} failed their latest build", repo.config().archive);
+                return Ok(());
+            }
+
+            repo.build(&Selection::Packages(&failed, false), options).map(|_| ())
+        }
+        Action::Download(packages) => {
+            repo.fetch(&Selection::Packages(&packages, false), options).map(|_| ())
+        }
+        Action::CheckManifest(manifest) => repo.check_manifest(Path::new(manifest), options),
+        Action::SimulateInstall => repo.simulate_install(options),
+        Action::CheckPool => repo.check_pool(options),
+        Action::Clean => repo.clean(options),
+        Action::CleanLogs => repo.clean_logs(),
+        Action::CleanObsolete => repo.clean_obsolete(options),
+        Action::Gc { areas, dry_run } => {
+            repo.gc(&areas, dry_run).map(|reports| print_gc(&reports, dry_run))
+        }
+        Action::Dist => repo.publish(options).map(|_| ()),
+        Action::Run(packages, force) => {
+            repo.update(&Selection::Packages(&packages, force), options)
+        }
+        Action::UpdateRepository => repo.update(&Selection::All, options),
+        Action::RunFailed => {
+            let failed = repo.failed()?;
+            let failed: Vec<&str> = failed.iter().map(|x| x.as_str()).collect();
+            if failed.is_empty() {
+                info!("no sources of {} failed their latest build", repo.config().archive);
+                return Ok(());
+            }
+
+            repo.update(&Selection::Packages(&failed, false), options)
+        }
+        Action::Rebuild(packages, reason) => {
+            repo.update(&Selection::Rebuild(&packages, reason), options)
+        }
+        Action::Reproduce(packages) => repo.reproduce(&packages, options).and_then(|reproduced| {
+            for reproduction in &reproduced {
+                print!("{}", reproduction);
+            }
+
+            let unreproducible: Vec<&str> = reproduced.iter()
+                .filter(|reproduction| !reproduction.is_reproducible())
+                .map(|reproduction| reproduction.package.as_str())
+                .collect();
+            if unreproducible.is_empty() {
+                Ok(())
+            } else {
+                Err(RepoError::Unreproducible { packages: unreproducible.join(", ") })
+            }
+        }),
+        Action::FetchKeys(keys) => repo.fetch_keys(&keys, options),
+        Action::ListKeys => {
+            for key in repo.list_keys() {
+                println!(
+                    "{:<20} {:<8} {} {}",
+                    key.name,
+                    key.state,
+                    key.fingerprint,
+                    key.user_id.unwrap_or_default()
+                );
+            }
+
+            Ok(())
+        }
+        Action::VerifyKeys => repo.verify_keys(),
+        Action::KeyStatus => {
+            for key in repo.suite_keys() {
+                println!(
+                    "{:<16} {:<11} {:<40} {:<10} {}",
+                    key.suite,
+                    key.state,
+                    key.fingerprint,
+                    key.expires,
+                    key.user_id.unwrap_or_default()
+                );
+            }
+
+            Ok(())
+        }
+        Action::Migrate { from, only, skip, dry_run, .. } => {
+            let migration = Migration { from, only: &only, skip: &skip, dry_run };
+            repo.migrate(&migration, options).map(|report| print!("{}", report))
+        }
+        Action::BackfillProvenance => repo.backfill_provenance().map(|_| ()),
+        Action::Pool => repo.fetch(&Selection::All, options).map(|_| ()),
+        Action::Release => repo.release(options).map(|_| ()),
+        Action::Remove(packages) => repo.remove(&packages, options),
+        Action::Upload { dry_run: false } => repo.upload(options),
+        Action::Upload { dry_run: true } => repo.planned_uploads().map(|planned| {
+            for upload in planned {
+                println!("{}", upload);
+            }
+        }),
+        Action::Fetch(_)
+        | Action::FetchConfig
+        | Action::EffectiveConfig
+        | Action::Doctor
+        | Action::Init(_)
+        | Action::Inspect(_)
+        | Action::RecordHistory(_)
+        | Action::RecordList { .. }
+        | Action::Serve(_)
+        | Action::Stats { .. }
+        | Action::Status
+        | Action::Update(..) => unreachable!(),
     }
 }
@@ -3,35 +3,10 @@ use std::fs::File;
 use std::io::{self, Read, Write};
 use std::os::unix::ffi::OsStringExt;
 use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
 
 use libc;
-use walkdir::{DirEntry, WalkDir};
-
-pub fn walk_debs(path: &Path) -> Box<Iterator<Item = DirEntry>> {
-    fn is_deb(entry: &DirEntry) -> bool {
-        if entry.path().is_dir() {
-            true
-        } else {
-            entry.file_name().to_str().map_or(false, |e| e.ends_with(".deb"))
-        }
-    }
-
-    Box::new(WalkDir::new(path).into_iter().filter_entry(|e| is_deb(e)).flat_map(|e| e.ok()))
-}
-
-pub fn match_deb(entry: &DirEntry, packages: &[String]) -> Option<(String, usize)> {
-    let path = entry.path();
-    if path.is_dir() {
-        return None
-    }
-
-    entry.file_name().to_str().and_then(|package| {
-        let package = &package[..package.find('_').expect("debian package lacks _ character")];
-
-        packages.iter().position(|x| x.as_str() == package)
-            .and_then(|pos| path.to_str().map(|path| (path.to_owned(), pos)))
-    })
-}
 
 pub fn unlink(link: &Path) -> io::Result<()> {
     CString::new(link.to_path_buf().into_os_string().into_vec())
@@ -51,6 +26,54 @@ pub fn get_arch_from_stem(stem: &str) -> &str {
     arch.find('-').map_or(arch, |pos| &arch[..pos])
 }
 
+/// The architecture of this system, as dpkg names it.
+pub fn host_architecture() -> io::Result<String> {
+    let output = Command::new("dpkg").arg("--print-architecture").output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// The name of this host.
+pub fn hostname() -> io::Result<String> {
+    let mut buffer = [0u8; 256];
+    let name = buffer.as_mut_ptr() as *mut libc::c_char;
+    if unsafe { libc::gethostname(name, buffer.len()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let length = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+    Ok(String::from_utf8_lossy(&buffer[..length]).into_owned())
+}
+
+/// The whole milliseconds of the duration, for logging how long something took.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
+
+/// The value of a field of a control file, or of a stanza of an index, whose name is matched
+/// regardless of case, as dpkg matches it. The continuation lines of a folded field are joined
+/// to its value with spaces.
+pub fn control_field(control: &str, name: &str) -> Option<String> {
+    let is_field = |line: &&str| {
+        line.get(..name.len()).map_or(false, |x| x.eq_ignore_ascii_case(name))
+            && line[name.len()..].starts_with(':')
+    };
+
+    let mut lines = control.lines().skip_while(|line| !is_field(line));
+    let mut value = lines.next()?[name.len() + 1..].trim().to_owned();
+    for line in lines.take_while(|line| line.starts_with(' ') || line.starts_with('\t')) {
+        let line = line.trim();
+        if !line.is_empty() && line != "." {
+            if !value.is_empty() {
+                value.push(' ');
+            }
+
+            value.push_str(line);
+        }
+    }
+
+    Some(value)
+}
+
 // NOTE: The following functions are implemented within Rust's standard in 1.26.0
 
 fn initial_buffer_size(file: &File) -> usize {
@@ -74,3 +97,23 @@ pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
 pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
     File::create(path)?.write_all(contents.as_ref())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_fields_are_read_regardless_of_case_and_folding() {
+        let control = "Package: libfoo1\nversion: 1.2-3\n\
+                       Depends: libbar (>= 2.0),\n libbaz (= 1.2-3)\n\
+                       Description: foo\n Package: not a field\n";
+        assert_eq!(control_field(control, "Package"), Some("libfoo1".to_owned()));
+        assert_eq!(control_field(control, "VERSION"), Some("1.2-3".to_owned()));
+        assert_eq!(
+            control_field(control, "depends"),
+            Some("libbar (>= 2.0), libbaz (= 1.2-3)".to_owned())
+        );
+        assert_eq!(control_field(control, "Pack"), None);
+        assert_eq!(control_field(control, "Maintainer"), None);
+    }
+}
@@ -0,0 +1,409 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use libc;
+use logging::{self, Warning};
+
+static COLOR: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Whether what is written to the terminal is stripped of escape sequences and carriage returns,
+/// such as those of the output of commands which errors and log excerpts include, for CI logs.
+static PLAIN: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Whether statuses and summaries are written to the terminal, rather than logged, which is only
+/// the case once `init` was called.
+static TERMINAL: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The width which status labels are right-aligned to.
+const LABEL_WIDTH: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Never,
+    Auto,
+    Always,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(choice: &str) -> Result<ColorChoice, String> {
+        match choice {
+            "never" => Ok(ColorChoice::Never),
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            _ => Err(format!("unknown color choice: {}", choice)),
+        }
+    }
+}
+
+/// Writes statuses and summaries to the terminal, and decides whether they will be colored. With
+/// `auto`, output is colored when stderr is a terminal, unless the `NO_COLOR` environment
+/// variable is set. Output is written as plain text if `plain`.
+pub fn init(choice: ColorChoice, plain: bool) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            env::var_os("NO_COLOR").map_or(true, |value| value.is_empty())
+                && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1
+        }
+    };
+
+    COLOR.store(enabled && !plain, Ordering::Relaxed);
+    PLAIN.store(plain, Ordering::Relaxed);
+    TERMINAL.store(true, Ordering::Relaxed);
+}
+
+/// The text as it is written to the terminal, which is plain text if output is.
+pub fn for_terminal<'a>(text: &'a str) -> Cow<'a, str> {
+    if PLAIN.load(Ordering::Relaxed) {
+        Cow::Owned(plain(text))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Removes the escape sequences and control characters of the text, besides newlines and tabs.
+/// Of a line which carriage returns wrote over, such as the progress of a download, only what
+/// was written last is kept, as a terminal would show it.
+pub fn plain(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut line = 0;
+    let mut returned = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // Control sequences, such as colors, end at a byte of `@` to `~`.
+                Some('[') => while let Some(c) = chars.next() {
+                    if c >= '@' && c <= '~' {
+                        break;
+                    }
+                },
+                // Operating system commands, such as titles, end at a bell or `ESC \`.
+                Some(']') => while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next() == Some('\\')) {
+                        break;
+                    }
+                },
+                _ => (),
+            },
+            '\r' => returned = true,
+            '\n' => {
+                plain.push('\n');
+                line = plain.len();
+                returned = false;
+            }
+            c if c.is_control() && c != '\t' => (),
+            c => {
+                if returned {
+                    plain.truncate(line);
+                    returned = false;
+                }
+
+                plain.push(c);
+            }
+        }
+    }
+
+    plain
+}
+
+/// Writes a message to the terminal, or logs it if output is not written to the terminal.
+pub fn message(message: &str) {
+    if TERMINAL.load(Ordering::Relaxed) {
+        logging::eprint(message);
+    } else {
+        info!("{}", message);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Building,
+    Built,
+    Skipped,
+    Failed,
+    Interrupted,
+    Downloaded,
+    Fetched,
+    Published,
+    Frozen,
+    Uploaded,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Building => "Building",
+            Status::Built => "Built",
+            Status::Skipped => "Skipped",
+            Status::Failed => "Failed",
+            Status::Interrupted => "Interrupted",
+            Status::Downloaded => "Downloaded",
+            Status::Fetched => "Fetched",
+            Status::Published => "Published",
+            Status::Frozen => "Frozen",
+            Status::Uploaded => "Uploaded",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Status::Skipped | Status::Frozen => "\x1b[1;33m",
+            Status::Failed | Status::Interrupted => "\x1b[1;31m",
+            _ => "\x1b[1;32m",
+        }
+    }
+}
+
+/// Pads the label to the given width, coloring it if color is enabled.
+fn paint(status: Status, width: usize, right: bool) -> String {
+    paint_if(COLOR.load(Ordering::Relaxed), status, width, right)
+}
+
+fn paint_if(color: bool, status: Status, width: usize, right: bool) -> String {
+    let label = if right {
+        format!("{:>1$}", status.label(), width)
+    } else {
+        format!("{:<1$}", status.label(), width)
+    };
+
+    if color {
+        [status.color(), &label, "\x1b[0m"].concat()
+    } else {
+        label
+    }
+}
+
+fn status_line(status: Status, name: &str, detail: &str) -> String {
+    let mut line = [&paint(status, LABEL_WIDTH, true), " ", name].concat();
+    if !detail.is_empty() {
+        line.push_str(" (");
+        line.push_str(detail);
+        line.push(')');
+    }
+    line
+}
+
+/// Writes the status of a package, such as `     Built pop-desktop (3m12s)`. Each status is
+/// written as a complete line, so that logs of a run can be searched with grep.
+pub fn status(status: Status, name: &str, detail: &str) {
+    message(&status_line(status, name, detail));
+}
+
+/// Collects the status of each package, so that they can be summarized at the end of a run.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    rows: Vec<(String, Status, String)>,
+    /// The bytes of the packages which were built, and of their debug symbols.
+    sizes: (u64, u64),
+}
+
+impl Summary {
+    /// Writes the status of a package, and records it for the summary.
+    pub fn add(&mut self, status: Status, name: &str, detail: String) {
+        self::status(status, name, &detail);
+        self.rows.push((name.to_owned(), status, detail));
+    }
+
+    /// Records the sizes of the packages of a build, and of their debug symbols.
+    pub fn add_sizes(&mut self, packages: u64, debug: u64) {
+        self.sizes = (self.sizes.0 + packages, self.sizes.1 + debug);
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.rows.iter().filter(|row| row.1 == status).count()
+    }
+
+    /// The last status of the package, if it is in the summary.
+    pub fn status_of(&self, name: &str) -> Option<Status> {
+        self.rows.iter().rev().find(|row| row.0 == name).map(|row| row.1)
+    }
+
+    pub fn print(&self) {
+        if !self.rows.is_empty() {
+            message(&self.table(COLOR.load(Ordering::Relaxed)));
+        }
+    }
+
+    /// The summary table without color, to be written to a file.
+    pub fn report(&self) -> String {
+        self.table(false)
+    }
+
+    fn table(&self, color: bool) -> String {
+        let name_width = self.rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+        let status_width = self.rows.iter().map(|row| row.1.label().len()).max().unwrap_or(0);
+
+        let mut table = String::from("summary:\n");
+        for &(ref name, status, ref detail) in &self.rows {
+            let _ = writeln!(
+                table,
+                "  {:<3$}  {}  {}",
+                name,
+                paint_if(color, status, status_width, false),
+                detail,
+                name_width
+            );
+        }
+
+        let mut totals = Vec::new();
+        for &status in
+            &[Status::Built, Status::Skipped, Status::Downloaded, Status::Fetched, Status::Uploaded]
+        {
+            let count = self.count(status);
+            if count != 0 {
+                totals.push(format!("{} {}", count, status.label().to_lowercase()));
+            }
+        }
+        totals.push(format!("{} failed", self.count(Status::Failed)));
+        if self.count(Status::Interrupted) != 0 {
+            totals.push(format!("{} interrupted", self.count(Status::Interrupted)));
+        }
+
+        // Debug symbols are counted apart, since they are often most of what is built.
+        if self.sizes.0 != 0 {
+            totals.push(format!("{} of packages", format_size(self.sizes.0)));
+        }
+        if self.sizes.1 != 0 {
+            totals.push(format!("{} of debug symbols", format_size(self.sizes.1)));
+        }
+
+        let _ = write!(table, "  {}", totals.join(", "));
+        table.lines().map(|line| line.trim_right()).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Lists warnings grouped by the module which logged them.
+pub fn warnings(warnings: &[Warning]) -> String {
+    let mut categories: BTreeMap<&str, Vec<&Warning>> = BTreeMap::new();
+    for warning in warnings {
+        categories.entry(&warning.category).or_insert_with(Vec::new).push(warning);
+    }
+
+    let mut output = format!("{} warnings:", warnings.len());
+    for (category, warnings) in categories {
+        let _ = write!(output, "\n  {}:", category);
+        for warning in warnings {
+            match warning.package {
+                Some(ref package) => {
+                    let _ = write!(output, "\n    {}: {}", package, warning.message);
+                }
+                None => {
+                    let _ = write!(output, "\n    {}", warning.message);
+                }
+            }
+        }
+    }
+
+    output
+}
+
+/// Formats a number of seconds as a short duration, such as `3m12s`.
+pub fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours != 0 {
+        format!("{}h{:02}m", hours, minutes)
+    } else if minutes != 0 {
+        format!("{}m{:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Formats a number of bytes in the largest binary unit which it has one of, such as `1.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    let (mut size, mut unit) = (bytes as f64, None);
+    for next in &units {
+        if size < 1024.0 {
+            break;
+        }
+
+        size /= 1024.0;
+        unit = Some(next);
+    }
+
+    match unit {
+        Some(unit) => format!("{:.1} {}", size, unit),
+        None => format!("{} B", bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_has_no_escape_sequences_or_carriage_returns() {
+        assert_eq!(plain("\x1b[1;31m    Failed\x1b[0m mesa"), "    Failed mesa");
+        assert_eq!(plain("\x1b]0;sbuild\x07fatal: \x1b]2;x\x1b\\no ref"), "fatal: no ref");
+        assert_eq!(plain("10%\r50%\r100%\ndone\r\n"), "100%\ndone\n");
+        assert_eq!(plain("a\tb\x08\x00c"), "a\tbc");
+        assert_eq!(plain("Downloading \u{1b}[K\u{2026}"), "Downloading \u{2026}");
+    }
+
+    #[test]
+    fn summary_table() {
+        let mut summary = Summary {
+            rows: vec![
+                ("pop-desktop".to_owned(), Status::Built, "3m12s".to_owned()),
+                ("linux".to_owned(), Status::Skipped, String::new()),
+                ("mesa".to_owned(), Status::Failed, String::new()),
+            ],
+            sizes: (0, 0),
+        };
+
+        assert_eq!(
+            summary.report(),
+            "summary:\n  \
+             pop-desktop  Built    3m12s\n  \
+             linux        Skipped\n  \
+             mesa         Failed\n  \
+             1 built, 1 skipped, 1 failed"
+        );
+
+        summary.add_sizes(3 * 1024 * 1024, 0);
+        summary.add_sizes(1024 * 1024, 40 * 1024 * 1024);
+        assert!(summary.report().ends_with(
+            "1 failed, 4.0 MiB of packages, 40.0 MiB of debug symbols"
+        ));
+
+        assert_eq!(status_line(Status::Built, "linux", "5s"), "     Built linux (5s)");
+        assert_eq!(format_duration(3660), "1h01m");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(3 * 1024 * 1024 / 2), "1.5 MiB");
+    }
+
+    #[test]
+    fn warnings_are_grouped() {
+        let warning = |category: &str, package: Option<&str>, message: &str| Warning {
+            category: category.to_owned(),
+            package: package.map(|x| x.to_owned()),
+            message: message.to_owned(),
+        };
+
+        let list = warnings(&[
+            warning("repo::build", Some("mesa"), "asset glob matched nothing"),
+            warning("repo", None, "failed to remove expired logs"),
+            warning("repo::build", Some("linux"), "asset glob matched nothing"),
+        ]);
+
+        assert_eq!(
+            list,
+            "3 warnings:\n  \
+             repo:\n    \
+             failed to remove expired logs\n  \
+             repo::build:\n    \
+             mesa: asset glob matched nothing\n    \
+             linux: asset glob matched nothing"
+        );
+    }
+}
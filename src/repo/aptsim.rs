@@ -0,0 +1,215 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use command::Cmd;
+use misc;
+use tempdir::TempDir;
+use super::generate;
+
+/// Whether apt could install a package from the repo.
+#[derive(Debug, PartialEq)]
+pub enum Simulation {
+    Installable,
+    /// The dependencies of the package cannot be satisfied, with apt's explanation.
+    Unsatisfiable(String),
+    /// Apt would keep these packages back instead of installing the package.
+    HeldBack(Vec<String>),
+}
+
+impl fmt::Display for Simulation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Simulation::Installable => f.write_str("installable"),
+            Simulation::Unsatisfiable(ref why) => write!(f, "unsatisfiable {}", why),
+            Simulation::HeldBack(ref packages) => write!(f, "held-back {}", packages.join(" ")),
+        }
+    }
+}
+
+/// A temporary apt state, which lets apt be run without root, and without touching the state of
+/// the system. Nothing is installed in it, so every dependency must come from its sources.
+pub struct AptState {
+    dir: TempDir,
+    arch: String,
+}
+
+impl AptState {
+    /// Creates a state whose sources are the generated dists of the repo, and the base sources,
+    /// which are lines in the format of `sources.list`.
+    pub fn new(repo: &Path, archive: &str, base: &[String], arch: &str) -> io::Result<AptState> {
+        let dir = TempDir::new("debrep-apt-sim")?;
+        for path in &["state/lists/partial", "cache/archives/partial", "etc/preferences.d"] {
+            fs::create_dir_all(dir.path().join(path))?;
+        }
+
+        misc::write(dir.path().join("state/status"), b"")?;
+        misc::write(dir.path().join("etc/sources.list"), sources(repo, archive, base)?)?;
+        Ok(AptState { dir, arch: arch.to_owned() })
+    }
+
+    /// The arguments which confine apt-get to the state.
+    fn args(&self) -> Vec<String> {
+        let path = |name: &str| self.dir.path().join(name).display().to_string();
+        let options = [
+            ["APT::Architecture=", &self.arch].concat(),
+            ["Dir::State=", &path("state")].concat(),
+            ["Dir::State::status=", &path("state/status")].concat(),
+            ["Dir::Cache=", &path("cache")].concat(),
+            ["Dir::Etc::SourceList=", &path("etc/sources.list")].concat(),
+            ["Dir::Etc::SourceParts=", &path("etc/sources.list.d")].concat(),
+            ["Dir::Etc::Preferences=", &path("etc/preferences")].concat(),
+            ["Dir::Etc::PreferencesParts=", &path("etc/preferences.d")].concat(),
+            "Debug::NoLocking=1".to_owned(),
+        ];
+
+        let mut args = Vec::new();
+        for option in &options {
+            args.push("-o".to_owned());
+            args.push(option.clone());
+        }
+
+        args.push("-q".to_owned());
+        args
+    }
+
+    /// Fetches the indices of the sources.
+    pub fn update(&self) -> io::Result<()> {
+        Cmd::new("apt-get").args(&self.args()).arg("update").run().map_err(io::Error::from)
+    }
+
+    /// Simulates installing the package.
+    pub fn simulate(&self, package: &str) -> io::Result<Simulation> {
+        let output = Command::new("apt-get")
+            .args(&self.args())
+            .args(&["install", "--simulate", package])
+            .stdin(Stdio::null())
+            .output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Ok(parse(&stdout, &stderr, output.status.success()))
+    }
+}
+
+/// The `sources.list` of the state, which trusts the repo, since it was generated locally.
+fn sources(repo: &Path, archive: &str, base: &[String]) -> io::Result<String> {
+    let components = generate::components(&repo.join("dists").join(archive))?;
+    let repo = repo.canonicalize()?;
+    let mut sources = format!(
+        "deb [trusted=yes] file://{} {} {}\n",
+        repo.display(),
+        archive,
+        components.join(" ")
+    );
+    for line in base {
+        sources.push_str(line);
+        sources.push('\n');
+    }
+
+    Ok(sources)
+}
+
+/// Reads the outcome of `apt-get install --simulate` from its output.
+fn parse(stdout: &str, stderr: &str, success: bool) -> Simulation {
+    let mut lines = stdout.lines();
+    if lines.any(|line| line.starts_with("The following packages have been kept back")) {
+        let held = lines.take_while(|line| line.starts_with(' '))
+            .flat_map(|line| line.split_whitespace())
+            .map(|package| package.to_owned())
+            .collect();
+
+        return Simulation::HeldBack(held);
+    }
+
+    if success {
+        return Simulation::Installable;
+    }
+
+    // Apt explains which dependencies are missing in indented lines such as
+    // ` pop-shop : Depends: libappstream4 (>= 0.12) but it is not installable`.
+    let mut reasons: Vec<&str> = stdout.lines()
+        .filter(|line| line.contains(" : ") || line.starts_with("   "))
+        .map(|line| line.trim())
+        .collect();
+
+    if reasons.is_empty() {
+        reasons = stderr.lines()
+            .filter(|line| line.starts_with("E: "))
+            .map(|line| line[3..].trim())
+            .collect();
+    }
+
+    Simulation::Unsatisfiable(reasons.join("; "))
+}
+
+/// The names of the binary packages in the generated indices of the archive.
+pub fn published_packages(repo: &Path, archive: &str) -> io::Result<Vec<String>> {
+    let mut packages = Vec::new();
+    for index in generate::packages_indices(repo, archive)? {
+        packages.extend(
+            misc::read_to_string(&index)?.lines()
+                .filter(|line| line.starts_with("Package: "))
+                .map(|line| line[9..].trim().to_owned())
+        );
+    }
+
+    packages.sort();
+    packages.dedup();
+    Ok(packages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulations_are_parsed() {
+        assert_eq!(parse("Inst pop-shop (1.0 local [amd64])\n", "", true), Simulation::Installable);
+
+        let stdout = "Some packages could not be installed.\n\
+                      The following packages have unmet dependencies:\n \
+                      pop-shop : Depends: libappstream4 (>= 0.12) but it is not installable\n";
+        assert_eq!(
+            parse(stdout, "E: Unable to correct problems, you have held broken packages.\n", false),
+            Simulation::Unsatisfiable(
+                "pop-shop : Depends: libappstream4 (>= 0.12) but it is not installable".to_owned()
+            )
+        );
+
+        assert_eq!(
+            parse("", "E: Unable to locate package pop-shop\n", false).to_string(),
+            "unsatisfiable Unable to locate package pop-shop"
+        );
+
+        let stdout = "The following packages have been kept back:\n  pop-shop pop-shop-data\n\
+                      0 upgraded, 0 newly installed, 0 to remove and 2 not upgraded.\n";
+        assert_eq!(
+            parse(stdout, "", true),
+            Simulation::HeldBack(vec!["pop-shop".to_owned(), "pop-shop-data".to_owned()])
+        );
+    }
+
+    #[test]
+    fn packages_are_listed_from_indices() {
+        let repo = TempDir::new("debrep-apt-sim").unwrap();
+        let main = repo.path().join("dists/bionic/main");
+        for arch in &["binary-amd64", "binary-all"] {
+            fs::create_dir_all(main.join(arch)).unwrap();
+        }
+
+        misc::write(main.join("binary-amd64/Packages"), b"Package: b\nVersion: 1\n\nPackage: a\n")
+            .unwrap();
+        misc::write(main.join("binary-all/Packages"), b"Package: b\n").unwrap();
+
+        // The packages of every component are published.
+        let restricted = repo.path().join("dists/bionic/restricted/binary-amd64");
+        fs::create_dir_all(&restricted).unwrap();
+        misc::write(restricted.join("Packages"), b"Package: c\n").unwrap();
+
+        assert_eq!(published_packages(repo.path(), "bionic").unwrap(), vec!["a", "b", "c"]);
+        let sources = sources(repo.path(), "bionic", &[]).unwrap();
+        assert!(sources.ends_with(" bionic main restricted\n"));
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use misc::{self, control_field};
+use walkdir::WalkDir;
+use super::version::ChangelogEntry;
+
+/// Caches what the audit reads from each package of the pool, between runs.
+pub const PACKAGE_CACHE: &str = "assets/cache/.packages";
+
+/// What the audit needs of a source or binary package of the pool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    /// The source package, and the version of it which the package was built from.
+    pub source: String,
+    pub source_version: String,
+    /// The newest entry of the changelog of the package.
+    pub changelog: Option<ChangelogEntry>,
+}
+
+impl Package {
+    /// Reads a `.dsc`, and the changelog from its debian tarball.
+    fn of_dsc(dsc: &Path) -> io::Result<Package> {
+        let data = misc::read_to_string(dsc)?;
+        let field = |name: &str| control_field(&data, name).unwrap_or_default();
+        let (name, version) = (field("Source"), field("Version"));
+
+        let dir = dsc.parent().unwrap_or_else(|| Path::new("."));
+        let mut changelog = None;
+        for line in data.lines().skip_while(|line| !line.starts_with("Files:")).skip(1) {
+            let tarball = match line.split_whitespace().nth(2) {
+                Some(tarball) if line.starts_with(' ') => tarball,
+                _ => break,
+            };
+
+            // Native packages contain `<dir>/debian/`, and others `debian/` in a separate tarball.
+            let pattern = if tarball.contains(".debian.tar.") {
+                "debian/changelog"
+            } else if !tarball.contains(".orig") && tarball.contains(".tar.") {
+                "*/debian/changelog"
+            } else {
+                continue;
+            };
+
+            let output = Command::new("tar")
+                .args(&["--wildcards", "--no-wildcards-match-slash", "-xOf"])
+                .arg(dir.join(tarball))
+                .arg(pattern)
+                .output()?;
+
+            if output.status.success() {
+                changelog = ChangelogEntry::latest(&String::from_utf8_lossy(&output.stdout));
+                break;
+            }
+        }
+
+        Ok(Package {
+            source: name.clone(),
+            source_version: version.clone(),
+            name,
+            version,
+            changelog,
+        })
+    }
+
+    /// Reads the control fields of a `.deb`, and the changelog from `/usr/share/doc`.
+    fn of_deb(deb: &Path) -> io::Result<Package> {
+        let output = Command::new("dpkg-deb").arg("-f").arg(deb).output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("dpkg-deb failed: {}", String::from_utf8_lossy(&output.stderr).trim())
+            ));
+        }
+
+        let control = String::from_utf8_lossy(&output.stdout);
+        let name = control_field(&control, "Package").unwrap_or_default();
+        let version = control_field(&control, "Version").unwrap_or_default();
+
+        // `Source` is omitted when it matches the package, and its version when it matches too.
+        let source = control_field(&control, "Source").unwrap_or_else(|| name.clone());
+        let (source, source_version) = match source.find(" (") {
+            Some(pos) => (
+                source[..pos].to_owned(),
+                source[pos + 2..].trim_right_matches(')').to_owned(),
+            ),
+            None => (source.clone(), version.clone()),
+        };
+
+        let mut changelog = None;
+        for file in &["changelog.Debian.gz", "changelog.gz"] {
+            let path = ["./usr/share/doc/", &name, "/", file].concat();
+            let output = Command::new("sh")
+                .args(&["-c", "dpkg-deb --fsys-tarfile \"$1\" | tar -xO \"$2\" | gzip -dc", "sh"])
+                .arg(deb)
+                .arg(&path)
+                .output()?;
+
+            if output.status.success() {
+                changelog = ChangelogEntry::latest(&String::from_utf8_lossy(&output.stdout));
+                break;
+            }
+        }
+
+        Ok(Package { name, version, source, source_version, changelog })
+    }
+
+    fn to_line(&self) -> String {
+        let (version, distributions) = match self.changelog {
+            Some(ref entry) => (entry.version.as_str(), entry.distributions.join(" ")),
+            None => ("", String::new()),
+        };
+
+        [
+            &self.name, "\t", &self.version, "\t", &self.source, "\t", &self.source_version, "\t",
+            version, "\t", &distributions,
+        ].concat()
+    }
+
+    fn from_fields<'a, I: Iterator<Item = &'a str>>(mut fields: I) -> Option<Package> {
+        let name = fields.next()?.to_owned();
+        let version = fields.next()?.to_owned();
+        let source = fields.next()?.to_owned();
+        let source_version = fields.next()?.to_owned();
+        let changelog_version = fields.next()?;
+        let distributions = fields.next()?;
+        let changelog = if changelog_version.is_empty() {
+            None
+        } else {
+            Some(ChangelogEntry {
+                package: source.clone(),
+                version: changelog_version.to_owned(),
+                distributions: distributions.split_whitespace().map(|x| x.to_owned()).collect(),
+            })
+        };
+
+        Some(Package { name, version, source, source_version, changelog })
+    }
+}
+
+
+/// The packages that were read from the pool, keyed on the path, size, and modification time of
+/// each file, so that packages which have not changed are not extracted on every audit.
+pub struct PackageCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, (u64, i64, Package)>,
+    modified: bool,
+}
+
+impl PackageCache {
+    /// Opens the cache at the given path. Lines which cannot be parsed are not cached.
+    pub fn open<P: Into<PathBuf>>(path: P) -> PackageCache {
+        let path = path.into();
+        let mut entries = HashMap::new();
+        if let Ok(data) = misc::read_to_string(&path) {
+            for line in data.lines() {
+                let mut fields = line.split('\t');
+                let file = fields.next().map(PathBuf::from);
+                let size = fields.next().and_then(|x| x.parse::<u64>().ok());
+                let mtime = fields.next().and_then(|x| x.parse::<i64>().ok());
+                if let (Some(file), Some(size), Some(mtime)) = (file, size, mtime) {
+                    if let Some(package) = Package::from_fields(fields) {
+                        entries.insert(file, (size, mtime, package));
+                    }
+                }
+            }
+        }
+
+        PackageCache { path, entries, modified: false }
+    }
+
+    /// Reads the package, unless it was cached since it last changed.
+    pub fn get(&mut self, path: &Path) -> io::Result<Package> {
+        let metadata = path.metadata()?;
+        let (size, mtime) = (metadata.len(), metadata.mtime());
+        if let Some(&(cached_size, cached_mtime, ref package)) = self.entries.get(path) {
+            if cached_size == size && cached_mtime == mtime {
+                return Ok(package.clone());
+            }
+        }
+
+        debug!("reading {}", path.display());
+        let package = if path.extension().map_or(false, |ext| ext == "dsc") {
+            Package::of_dsc(path)?
+        } else {
+            Package::of_deb(path)?
+        };
+
+        self.entries.insert(path.to_path_buf(), (size, mtime, package.clone()));
+        self.modified = true;
+        Ok(package)
+    }
+
+    /// Writes the cache back to disk, if any packages were read.
+    pub fn save(&self) -> io::Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+
+        let mut data = String::new();
+        for (path, &(size, mtime, ref package)) in &self.entries {
+            if let Some(path) = path.to_str() {
+                let _ = writeln!(data, "{}\t{}\t{}\t{}", path, size, mtime, package.to_line());
+            }
+        }
+
+        misc::write(&self.path, data)
+    }
+}
+
+/// Whether a changelog which targets the distributions may be published in the suite, which
+/// includes its pockets, such as `bionic-updates`.
+fn targets(distributions: &[String], suite: &str) -> bool {
+    distributions.iter().any(|dist| {
+        dist == suite || (dist.starts_with(suite) && dist[suite.len()..].starts_with('-'))
+    })
+}
+
+/// Checks that the changelog of each source package in the pool targets the suite, and that each
+/// binary package was built from the version of its source's changelog. The mismatches are
+/// grouped by source package.
+pub fn audit(
+    pool: &Path,
+    suite: &str,
+    cache: &mut PackageCache,
+) -> io::Result<BTreeMap<String, Vec<String>>> {
+    let mut sources = Vec::new();
+    let mut binaries = Vec::new();
+    for entry in WalkDir::new(pool).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("dsc") => sources.push(cache.get(path)?),
+            Some("deb") => binaries.push(cache.get(path)?),
+            _ => (),
+        }
+    }
+
+    Ok(check(&sources, &binaries, suite))
+}
+
+fn check(sources: &[Package], binaries: &[Package], suite: &str) -> BTreeMap<String, Vec<String>> {
+    let mut mismatches: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    {
+        let mut report = |source: &str, message: String| {
+            mismatches.entry(source.to_owned()).or_insert_with(Vec::new).push(message);
+        };
+
+        for source in sources {
+            match source.changelog {
+                None => {
+                    let message = format!("{} {} has no changelog", source.name, source.version);
+                    report(&source.name, message);
+                }
+                Some(ref entry) => {
+                    if entry.version != source.version {
+                        report(&source.name, format!(
+                            "{} {} has a changelog at version {}",
+                            source.name, source.version, entry.version
+                        ));
+                    }
+
+                    if !targets(&entry.distributions, suite) {
+                        report(&source.name, format!(
+                            "the changelog of {} {} targets {}, but it is published in {}",
+                            source.name, source.version, entry.distributions.join(" "), suite
+                        ));
+                    }
+                }
+            }
+        }
+
+        for binary in binaries {
+            let from_source = sources.iter()
+                .find(|x| x.name == binary.source && x.version == binary.source_version);
+
+            // Without its source package, the changelog of the binary package must be used.
+            let changelog = match from_source {
+                Some(source) => source.changelog.as_ref(),
+                None => {
+                    if let Some(ref entry) = binary.changelog {
+                        if !targets(&entry.distributions, suite) {
+                            report(&binary.source, format!(
+                                "the changelog of {} {} targets {}, but it is published in {}",
+                                binary.name, binary.version, entry.distributions.join(" "), suite
+                            ));
+                        }
+                    }
+
+                    binary.changelog.as_ref()
+                }
+            };
+
+            match changelog {
+                Some(entry) if entry.version != binary.source_version => {
+                    report(&binary.source, format!(
+                        "{} {} was built from {} {}, but its changelog is at version {}",
+                        binary.name, binary.version, binary.source, binary.source_version,
+                        entry.version
+                    ));
+                }
+                Some(_) => (),
+                None => report(&binary.source, format!(
+                    "{} {} has no changelog, and neither does its source package",
+                    binary.name, binary.version
+                )),
+            }
+        }
+    }
+
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn package(name: &str, version: &str, source: (&str, &str), changelog: Option<(&str, &str)>)
+        -> Package
+    {
+        Package {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            source: source.0.to_owned(),
+            source_version: source.1.to_owned(),
+            changelog: changelog.map(|(version, dist)| ChangelogEntry {
+                package: source.0.to_owned(),
+                version: version.to_owned(),
+                distributions: vec![dist.to_owned()],
+            }),
+        }
+    }
+
+    #[test]
+    fn mismatches_are_grouped_by_source() {
+        let sources = vec![
+            package("pop-shop", "1.1", ("pop-shop", "1.1"), Some(("1.1", "bionic-updates"))),
+            package("pop-theme", "2.0", ("pop-theme", "2.0"), Some(("2.0", "cosmic"))),
+        ];
+
+        let binaries = vec![
+            package("pop-shop", "1.1", ("pop-shop", "1.1"), None),
+            package("pop-shop-data", "1.0", ("pop-shop", "1.0"), Some(("1.0", "bionic"))),
+            package("pop-icons", "3.0", ("pop-icons", "3.0"), Some(("2.9", "bionic"))),
+        ];
+
+        let mismatches = check(&sources, &binaries, "bionic");
+        assert_eq!(mismatches.keys().collect::<Vec<_>>(), vec!["pop-icons", "pop-theme"]);
+        assert_eq!(
+            mismatches["pop-theme"],
+            vec!["the changelog of pop-theme 2.0 targets cosmic, but it is published in bionic"]
+        );
+        assert_eq!(
+            mismatches["pop-icons"],
+            vec!["pop-icons 3.0 was built from pop-icons 3.0, but its changelog is at version 2.9"]
+        );
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let dir = TempDir::new("debrep-audit").unwrap();
+        let file = dir.path().join("pop-shop_1.1.dsc");
+        misc::write(&file, b"Source: pop-shop\nVersion: 1.1\n").unwrap();
+
+        let mut cache = PackageCache::open(dir.path().join("packages"));
+        let package = cache.get(&file).unwrap();
+        assert_eq!(package, self::package("pop-shop", "1.1", ("pop-shop", "1.1"), None));
+        cache.save().unwrap();
+
+        let mut cache = PackageCache::open(dir.path().join("packages"));
+        cache.entries.get_mut(&file).unwrap().2.version = "cached".to_owned();
+        assert_eq!(cache.get(&file).unwrap().version, "cached");
+        assert!(!cache.modified);
+    }
+}
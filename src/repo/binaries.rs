@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use misc;
+
+/// Records which binary packages each source produced when it was last built.
+pub const BINARIES: &str = "record/.binaries";
+
+/// A binary package which two configured sources produce.
+#[derive(Debug, Fail, PartialEq)]
+#[fail(display = "binary package {} is produced by both {} and {}", binary, owner, claimant)]
+pub struct Conflict {
+    pub binary: String,
+    /// The source which produced the binary first.
+    pub owner: String,
+    pub claimant: String,
+}
+
+/// The binary packages of each source, by the name of the source.
+#[derive(Debug, Default, PartialEq)]
+pub struct Binaries {
+    sources: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Binaries {
+    /// Reads the binaries of each source. Lines which cannot be parsed are ignored.
+    pub fn open(path: &Path) -> Binaries {
+        let mut sources = BTreeMap::new();
+        if let Ok(data) = misc::read_to_string(path) {
+            for line in data.lines() {
+                let mut fields = line.splitn(2, '\t');
+                if let (Some(source), Some(binaries)) = (fields.next(), fields.next()) {
+                    sources.insert(
+                        source.to_owned(),
+                        binaries.split_whitespace().map(|x| x.to_owned()).collect()
+                    );
+                }
+            }
+        }
+
+        Binaries { sources }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut data = String::new();
+        for (source, binaries) in &self.sources {
+            let binaries: Vec<&str> = binaries.iter().map(|x| x.as_str()).collect();
+            let _ = writeln!(data, "{}\t{}", source, binaries.join(" "));
+        }
+
+        misc::write(path, data)
+    }
+
+    /// Records the binaries which a source produced. If another configured source already
+    /// produces one of them, the source may only take it over when `takeover` is set. Sources
+    /// which are no longer configured do not own their binaries.
+    pub fn claim(
+        &mut self,
+        source: &str,
+        binaries: &[String],
+        takeover: bool,
+        configured: &[&str],
+    ) -> Result<(), Conflict> {
+        for (owner, owned) in &mut self.sources {
+            if owner == source || !configured.contains(&owner.as_str()) {
+                continue;
+            }
+
+            for binary in binaries {
+                if !owned.contains(binary) {
+                    continue;
+                }
+
+                if !takeover {
+                    return Err(Conflict {
+                        binary: binary.clone(),
+                        owner: owner.clone(),
+                        claimant: source.to_owned(),
+                    });
+                }
+
+                info!("{} takes over {} from {}", source, binary, owner);
+                owned.remove(binary);
+            }
+        }
+
+        self.sources.insert(source.to_owned(), binaries.iter().cloned().collect());
+        Ok(())
+    }
+
+    /// The source which produced the binary in its last build.
+    pub fn source_of(&self, binary: &str) -> Option<&str> {
+        self.sources.iter()
+            .find(|&(_, binaries)| binaries.contains(binary))
+            .map(|(source, _)| source.as_str())
+    }
+
+    /// The binaries which more than one of the configured sources produced in their last builds.
+    pub fn conflicts(&self, configured: &[&str]) -> Vec<Conflict> {
+        let mut owners: BTreeMap<&str, &str> = BTreeMap::new();
+        let mut conflicts = Vec::new();
+        for source in configured {
+            let binaries = match self.sources.get(*source) {
+                Some(binaries) => binaries,
+                None => continue,
+            };
+
+            for binary in binaries {
+                if let Some(owner) = owners.get(binary.as_str()) {
+                    conflicts.push(Conflict {
+                        binary: binary.clone(),
+                        owner: owner.to_string(),
+                        claimant: source.to_string(),
+                    });
+                }
+
+                owners.entry(binary).or_insert(source);
+            }
+        }
+
+        conflicts
+    }
+}
+
+/// The binary packages which a `.changes` file lists in its `Binary` field.
+pub fn changes_binaries(path: &Path) -> io::Result<Vec<String>> {
+    let changes = misc::read_to_string(path)?;
+    Ok(changes.lines()
+        .find(|line| line.starts_with("Binary:"))
+        .map_or_else(Vec::new, |line| {
+            line[7..].split_whitespace().map(|x| x.to_owned()).collect()
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn conflicting_claims() {
+        let dir = TempDir::new("debrep-binaries").unwrap();
+        let path = dir.path().join(".binaries");
+        let configured = ["pop-shop", "pop-shop-next", "old-shop"];
+
+        let mut binaries = Binaries::default();
+        binaries.claim("pop-shop", &names(&["pop-shop", "pop-shop-data"]), false, &configured)
+            .unwrap();
+        binaries.save(&path).unwrap();
+        assert_eq!(Binaries::open(&path), binaries);
+
+        let conflict = Conflict {
+            binary: "pop-shop-data".to_owned(),
+            owner: "pop-shop".to_owned(),
+            claimant: "pop-shop-next".to_owned(),
+        };
+        assert_eq!(
+            binaries.claim("pop-shop-next", &names(&["pop-shop-data"]), false, &configured),
+            Err(conflict)
+        );
+
+        // Sources which were removed from the config no longer own their binaries.
+        binaries.claim("pop-shop-next", &names(&["pop-shop-data"]), false, &["pop-shop-next"])
+            .unwrap();
+        assert_eq!(binaries.conflicts(&configured).len(), 1);
+
+        binaries.claim("pop-shop-next", &names(&["pop-shop-data"]), true, &configured).unwrap();
+        assert!(binaries.conflicts(&configured).is_empty());
+        assert!(binaries.claim("pop-shop", &names(&["pop-shop-data"]), false, &configured).is_err());
+    }
+
+    #[test]
+    fn binaries_of_changes() {
+        let dir = TempDir::new("debrep-binaries").unwrap();
+        let path = dir.path().join("pop-shop_1.0_amd64.changes");
+        misc::write(&path, "Source: pop-shop\nBinary: pop-shop pop-shop-data\nArchitecture: amd64\n")
+            .unwrap();
+
+        assert_eq!(changes_binaries(&path).unwrap(), names(&["pop-shop", "pop-shop-data"]));
+    }
+}
@@ -29,14 +29,13 @@ pub fn link_artifact(src: &Path, dst: &Path) -> Result<LinkedArtifact, LinkError
 
     if let Some(dst_ino) = dst.as_ref().metadata().ok().map(|m| m.ino()) {
         if let Some(src_ino) = src.metadata().ok().map(|m| m.ino()) {
-            if src_ino == dst_ino
-                && !dst
-                    .as_ref()
-                    .symlink_metadata()
-                    .unwrap()
-                    .file_type()
-                    .is_symlink()
-            {
+            let is_symlink = dst.as_ref()
+                .symlink_metadata()
+                .map_err(|why| LinkError::new(src, &dst, why))?
+                .file_type()
+                .is_symlink();
+
+            if src_ino == dst_ino && !is_symlink {
                 return Ok(LinkedArtifact(dst.to_owned().to_path_buf()));
             } else {
                 info!("removing link at {}", dst.display());
@@ -51,7 +50,7 @@ pub fn link_artifact(src: &Path, dst: &Path) -> Result<LinkedArtifact, LinkError
         .map_err(|why| LinkError::new(src, &dst, why))
 }
 
-fn resolve_destination<'a>(mut src: &'a Path, dst: &'a Path) -> Cow<'a, Path> {
+pub fn resolve_destination<'a>(mut src: &'a Path, dst: &'a Path) -> Cow<'a, Path> {
     let src_is_file = src.is_file();
     for component in dst.components().map(|comp| comp.as_os_str()) {
         if let Ok(path) = src.strip_prefix("/") {
@@ -65,11 +64,18 @@ fn resolve_destination<'a>(mut src: &'a Path, dst: &'a Path) -> Cow<'a, Path> {
         }
     }
 
-    if dst.is_dir() && src_is_file {
-        Cow::Owned(dst.join(src.file_name().unwrap()))
-    } else if dst.is_dir() && src.is_dir() {
-        Cow::Owned(dst.join(src.parent().unwrap()))
+    let within = if !dst.is_dir() {
+        None
+    } else if src_is_file {
+        src.file_name().map(Path::new)
+    } else if src.is_dir() {
+        src.parent()
     } else {
-        Cow::Borrowed(dst)
+        None
+    };
+
+    match within {
+        Some(path) => Cow::Owned(dst.join(path)),
+        None => Cow::Borrowed(dst),
     }
 }
@@ -0,0 +1,618 @@
+use config::SourceAsset;
+use glob::{glob, MatchOptions, Pattern, PatternError};
+use rayon::{self, ThreadPoolBuilder};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use misc;
+use repo::checksum::{is_sha256, HashCache};
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::{self, DirEntry, WalkDir};
+use super::artifacts::{link_artifact, resolve_destination, LinkedArtifact};
+use super::BuildError;
+
+/// Include / exclude rules that are applied when walking a package's asset directory.
+///
+/// Patterns containing a `/` are matched against the path relative to the asset root, whereas
+/// patterns without one are matched against each individual component of that path. A pattern
+/// which matches a directory applies to everything within that directory. Excludes always win.
+#[derive(Debug, Default)]
+pub struct AssetFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl AssetFilter {
+    pub fn new(include: Option<&[String]>, exclude: Option<&[String]>) -> Result<AssetFilter, (String, PatternError)> {
+        fn compile(patterns: Option<&[String]>) -> Result<Vec<Pattern>, (String, PatternError)> {
+            patterns.map_or(Ok(Vec::new()), |patterns| {
+                patterns.iter()
+                    .map(|pattern| Pattern::new(pattern).map_err(|why| (pattern.clone(), why)))
+                    .collect()
+            })
+        }
+
+        Ok(AssetFilter { include: compile(include)?, exclude: compile(exclude)? })
+    }
+
+    /// Returns true if the relative path, or any of its parent directories, was excluded.
+    pub fn is_excluded(&self, relative: &Path) -> bool {
+        self.exclude.iter().any(|pattern| matches(pattern, relative))
+    }
+
+    /// Returns true if the relative path of a file should be included in the build.
+    pub fn is_included(&self, relative: &Path) -> bool {
+        !self.is_excluded(relative)
+            && (self.include.is_empty() || self.include.iter().any(|pattern| matches(pattern, relative)))
+    }
+}
+
+fn matches(pattern: &Pattern, relative: &Path) -> bool {
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+
+    if pattern.as_str().contains('/') {
+        let mut path = PathBuf::new();
+        relative.components().any(|component| {
+            path.push(component.as_os_str());
+            pattern.matches_path_with(&path, &options)
+        })
+    } else {
+        relative.components()
+            .filter_map(|component| component.as_os_str().to_str())
+            .any(|component| pattern.matches_with(component, &options))
+    }
+}
+
+/// Walks the asset directory at `src`, skipping any directories that have been excluded, and
+/// yielding only the files which the filter includes, along with any entries which could not be
+/// read.
+pub fn walk<'a>(
+    src: &'a Path,
+    filter: &'a AssetFilter,
+) -> Box<Iterator<Item = walkdir::Result<DirEntry>> + 'a> {
+    Box::new(
+        WalkDir::new(src)
+            .min_depth(1)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_entry(move |entry| !filter.is_excluded(relative(src, entry)))
+            .filter(move |entry| entry.as_ref().map_or(true, |entry| {
+                entry.file_type().is_dir() || filter.is_included(relative(src, entry))
+            }))
+    )
+}
+
+fn relative<'a>(src: &Path, entry: &'a DirEntry) -> &'a Path {
+    entry.path().strip_prefix(src).unwrap_or_else(|_| entry.path())
+}
+
+/// The most files which are linked at the same time. Linking is bound by the file system, which
+/// may be on the network, rather than by the CPU.
+const LINK_THREADS: usize = 8;
+
+/// Linking is also limited to the threads of the global pool, which `--jobs` sets.
+fn link_threads() -> usize {
+    LINK_THREADS.min(rayon::current_num_threads())
+}
+
+/// Links every file from the package's asset directory which passes the filter into the root of
+/// the build directory, where a file which has the name of another file that was walked before it
+/// replaces it. The directories of the asset directory are created at the same relative location
+/// within the build directory.
+///
+/// Directories are created in the order that they are walked, so that parents exist before their
+/// children, and files are then linked in parallel. If anything fails, the links and directories
+/// which were created are removed again, and the first failure in the order of the walk is
+/// returned.
+pub fn fetch_assets(
+    linked: &mut Vec<LinkedArtifact>,
+    src: &Path,
+    dst: &Path,
+    filter: &AssetFilter,
+) -> Result<(), BuildError> {
+    let mut created = Vec::new();
+    let result = link_tree(linked, &mut created, src, dst, filter);
+    if result.is_err() {
+        for dir in created.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+
+    result
+}
+
+fn link_tree(
+    linked: &mut Vec<LinkedArtifact>,
+    created: &mut Vec<PathBuf>,
+    src: &Path,
+    dst: &Path,
+    filter: &AssetFilter,
+) -> Result<(), BuildError> {
+    let mut files: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for entry in walk(src, filter) {
+        let entry = entry.map_err(|why| BuildError::Read {
+            file: why.path().unwrap_or(src).to_path_buf(),
+            why: io::Error::from(why),
+        })?;
+
+        if entry.file_type().is_dir() {
+            let new_path = dst.join(relative(src, &entry));
+            if !new_path.exists() {
+                fs::create_dir(&new_path)
+                    .map_err(|why| BuildError::Directory { path: new_path.clone(), why })?;
+                created.push(new_path);
+            }
+        } else {
+            let new_path = dst.join(entry.file_name());
+            files.retain(|&(_, ref linked)| *linked != new_path);
+            files.push((entry.path().to_path_buf(), new_path));
+        }
+    }
+
+    fn link(&(ref path, ref new_path): &(PathBuf, PathBuf)) -> Result<LinkedArtifact, BuildError> {
+        // Symlinked assets are linked from what they point to, which must exist.
+        let src = path.canonicalize()
+            .map_err(|why| BuildError::AssetLink { file: path.clone(), why })?;
+        Ok(link_artifact(&src, new_path)?)
+    }
+
+    let results: Vec<Result<LinkedArtifact, BuildError>> =
+        match ThreadPoolBuilder::new().num_threads(link_threads()).build() {
+            Ok(pool) => pool.install(|| files.par_iter().map(link).collect()),
+            Err(why) => {
+                debug!("linking assets serially, as a thread pool was not created: {}", why);
+                files.iter().map(link).collect()
+            }
+        };
+
+    // Links which were created are removed when they are dropped, if any other link failed.
+    let mut artifacts = Vec::with_capacity(results.len());
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok(artifact) => artifacts.push(artifact),
+            Err(why) => failures.push(why),
+        }
+    }
+
+    let mut failures = failures.into_iter();
+    match failures.next() {
+        Some(why) => {
+            for other in failures {
+                warn!("failed to link an asset: {}", other);
+            }
+            Err(why)
+        }
+        None => {
+            linked.extend(artifacts);
+            Ok(())
+        }
+    }
+}
+
+/// A file from the shared assets which was matched by an asset entry of a source.
+#[derive(Debug)]
+pub struct SharedAsset<'a> {
+    pub entry: &'a SourceAsset,
+    pub src: PathBuf,
+    pub dst: PathBuf,
+}
+
+/// Two shared asset entries which would be linked to the same destination in the build tree.
+#[derive(Debug)]
+pub struct AssetCollision {
+    pub dst: PathBuf,
+    pub first: (String, PathBuf),
+    pub second: (String, PathBuf),
+}
+
+/// The directory which the asset globs of a source are resolved from, which is a subdirectory of
+/// the shared assets if the source has a prefix.
+pub fn shared_root(shared: &Path, prefix: Option<&str>) -> PathBuf {
+    prefix.map_or_else(|| shared.to_path_buf(), |prefix| shared.join(prefix))
+}
+
+/// Resolves the shared asset globs of a source to the files that they match, and the destinations
+/// that they will be linked to within the build tree.
+pub fn resolve_shared<'a>(root: &Path, assets: &'a [SourceAsset], project: &Path) -> Vec<SharedAsset<'a>> {
+    let root = Pattern::escape(&root.to_string_lossy());
+
+    let mut resolved = Vec::new();
+    for entry in assets {
+        let pattern = [root.as_str(), "/", &entry.src].concat();
+        match glob(&pattern) {
+            Ok(globs) => for file in globs.flat_map(|x| x.ok()) {
+                let dst = resolve_destination(&file, &project.join(&entry.dst)).into_owned();
+                resolved.push(SharedAsset { entry, src: file, dst });
+            },
+            Err(why) => warn!("invalid shared asset glob '{}': {}", entry.src, why),
+        }
+    }
+
+    resolved
+}
+
+/// Ensures that no two shared assets would be linked to the same destination.
+pub fn check_collisions(assets: &[SharedAsset]) -> Result<(), AssetCollision> {
+    let mut claimed: HashMap<&Path, &SharedAsset> = HashMap::new();
+    for asset in assets {
+        if let Some(previous) = claimed.insert(&asset.dst, asset) {
+            return Err(AssetCollision {
+                dst: asset.dst.clone(),
+                first: (previous.entry.src.clone(), previous.src.clone()),
+                second: (asset.entry.src.clone(), asset.src.clone()),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Verifies each shared asset which has a `sha256` field against the expected digest. Assets which
+/// are not cached are hashed in parallel.
+pub fn verify_checksums(
+    root: &Path,
+    assets: &[SharedAsset],
+    cache: &mut HashCache,
+) -> Result<(), BuildError> {
+    let mut manifests: HashMap<PathBuf, Vec<(String, String)>> = HashMap::new();
+    let mut expected_digests = Vec::new();
+
+    for asset in assets {
+        let checksum = match asset.entry.sha256 {
+            Some(ref checksum) => checksum,
+            None => continue,
+        };
+
+        let expected = if is_sha256(checksum) {
+            checksum.to_lowercase()
+        } else {
+            let manifest = root.join(checksum);
+            if !manifests.contains_key(&manifest) {
+                let data = misc::read_to_string(&manifest)
+                    .map_err(|why| BuildError::AssetManifest { manifest: manifest.clone(), why })?;
+                manifests.insert(manifest.clone(), parse_manifest(&data));
+            }
+
+            let base = manifest.parent().unwrap_or(root);
+            let relative = asset.src.strip_prefix(base).unwrap_or(&asset.src);
+            match manifests[&manifest].iter().find(|&&(ref path, _)| Path::new(path) == relative) {
+                Some(&(_, ref digest)) => digest.clone(),
+                None => return Err(BuildError::AssetUnlisted { file: asset.src.clone(), manifest }),
+            }
+        };
+
+        expected_digests.push((asset, expected));
+    }
+
+    let paths: Vec<&Path> = expected_digests.iter()
+        .map(|&(asset, _)| asset.src.as_path())
+        .collect();
+    let digests = cache.sha256_all(&paths);
+    for ((asset, expected), actual) in expected_digests.into_iter().zip(digests) {
+        let actual = actual.map_err(|why| BuildError::Read { file: asset.src.clone(), why })?;
+        if actual != expected {
+            return Err(BuildError::AssetChecksum { file: asset.src.clone(), expected, actual });
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses the `<digest>  <path>` lines of a `SHA256SUMS` file into `(path, digest)` pairs.
+fn parse_manifest(data: &str) -> Vec<(String, String)> {
+    data.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let pos = line.find(char::is_whitespace)?;
+            let (digest, path) = line.split_at(pos);
+            let path = path.trim_left();
+            let path = if path.starts_with('*') { &path[1..] } else { path };
+            let path = if path.starts_with("./") { &path[2..] } else { path };
+            if is_sha256(digest) && !path.is_empty() {
+                Some((path.to_owned(), digest.to_lowercase()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Links the shared assets of a source into the build tree, after checking for collisions.
+pub fn link_shared(
+    linked: &mut Vec<LinkedArtifact>,
+    package: &str,
+    assets: &[SharedAsset],
+) -> Result<(), BuildError> {
+    check_collisions(assets).map_err(|collision| BuildError::AssetCollision {
+        package: package.to_owned(),
+        dst: collision.dst,
+        first: collision.first.0,
+        first_src: collision.first.1,
+        second: collision.second.0,
+        second_src: collision.second.1,
+    })?;
+
+    for asset in assets {
+        linked.push(link_artifact(&asset.src, &asset.dst)?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repo::checksum::tree_digest;
+    use std::fs::{self, File};
+    use tempdir::TempDir;
+
+    fn tree() -> TempDir {
+        let dir = TempDir::new("debrep-assets").unwrap();
+        for file in &[
+            "README.md",
+            "notes.txt",
+            "data/a.bin",
+            "data/README.md",
+            "fixtures/large.bin",
+            "fixtures/nested/deep.bin",
+            "usr/share/app/config.toml",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+
+        dir
+    }
+
+    fn files(dir: &Path, filter: &AssetFilter) -> Vec<String> {
+        walk(dir, filter)
+            .flat_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.path().strip_prefix(dir).unwrap().to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    fn strings(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|&x| x.to_owned()).collect()
+    }
+
+    fn shared_tree() -> (TempDir, TempDir) {
+        let shared = TempDir::new("debrep-shared").unwrap();
+        for file in &[
+            "configs/config.toml",
+            "configs/config.json",
+            "configs/extra.toml",
+            "pkg/configs/config.toml",
+        ] {
+            let path = shared.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+
+        let project = TempDir::new("debrep-project").unwrap();
+        fs::create_dir_all(project.path().join("etc")).unwrap();
+        (shared, project)
+    }
+
+    fn asset(src: &str, dst: &str) -> SourceAsset {
+        SourceAsset { src: src.to_owned(), dst: PathBuf::from(dst), sha256: None }
+    }
+
+    #[test]
+    fn overlapping_globs_collide() {
+        let (shared, project) = shared_tree();
+        let assets = vec![asset("configs/*.toml", "etc"), asset("configs/config.*", "etc")];
+        let resolved = resolve_shared(shared.path(), &assets, project.path());
+        assert_eq!(resolved.len(), 4);
+
+        let collision = check_collisions(&resolved).unwrap_err();
+        assert_eq!(collision.dst, project.path().join("etc/config.toml"));
+        assert_eq!(collision.first.0, "configs/*.toml");
+        assert_eq!(collision.second.0, "configs/config.*");
+    }
+
+    #[test]
+    fn a_single_glob_matching_files_with_the_same_name_collides() {
+        let (shared, project) = shared_tree();
+        let assets = vec![asset("**/config.toml", "etc")];
+        let resolved = resolve_shared(shared.path(), &assets, project.path());
+        let collision = check_collisions(&resolved).unwrap_err();
+        assert_eq!(collision.first.0, collision.second.0);
+        assert_ne!(collision.first.1, collision.second.1);
+    }
+
+    #[test]
+    fn disjoint_globs_do_not_collide() {
+        let (shared, project) = shared_tree();
+        let assets = vec![asset("configs/*.toml", "etc"), asset("configs/*.json", "etc")];
+        let resolved = resolve_shared(shared.path(), &assets, project.path());
+        assert_eq!(resolved.len(), 3);
+        assert!(check_collisions(&resolved).is_ok());
+    }
+
+    #[test]
+    fn prefix_scopes_the_globs() {
+        let (shared, project) = shared_tree();
+        let assets = vec![asset("**/config.toml", "etc")];
+        let resolved = resolve_shared(&shared_root(shared.path(), Some("pkg")), &assets, project.path());
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].src, shared.path().join("pkg/configs/config.toml"));
+        assert!(check_collisions(&resolved).is_ok());
+    }
+
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn manifest_parsing() {
+        let manifest = format!("{0}  configs/a.toml\n{0} *b.bin\n\nnot a digest  c\n{0}  ./d\n", EMPTY);
+        assert_eq!(
+            parse_manifest(&manifest),
+            vec![
+                ("configs/a.toml".to_owned(), EMPTY.to_owned()),
+                ("b.bin".to_owned(), EMPTY.to_owned()),
+                ("d".to_owned(), EMPTY.to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn checksums_are_verified() {
+        let (shared, project) = shared_tree();
+        let mut cache = HashCache::open(shared.path().join("hashes"), false);
+        misc::write(
+            shared.path().join("SHA256SUMS"),
+            format!("{0}  configs/config.toml\n{0}  configs/extra.toml\n", EMPTY),
+        ).unwrap();
+
+        let mut assets = vec![asset("configs/*.toml", "etc")];
+        assets[0].sha256 = Some("SHA256SUMS".to_owned());
+        let resolved = resolve_shared(shared.path(), &assets, project.path());
+        assert!(verify_checksums(shared.path(), &resolved, &mut cache).is_ok());
+
+        assets[0].sha256 = Some(EMPTY.to_owned());
+        let resolved = resolve_shared(shared.path(), &assets, project.path());
+        assert!(verify_checksums(shared.path(), &resolved, &mut cache).is_ok());
+
+        misc::write(shared.path().join("configs/extra.toml"), b"changed").unwrap();
+        let resolved = resolve_shared(shared.path(), &assets, project.path());
+        match verify_checksums(shared.path(), &resolved, &mut cache) {
+            Err(BuildError::AssetChecksum { ref file, ref expected, .. }) => {
+                assert_eq!(file, &shared.path().join("configs/extra.toml"));
+                assert_eq!(expected, EMPTY);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assets[0].src = "configs/*".to_owned();
+        assets[0].sha256 = Some("SHA256SUMS".to_owned());
+        let resolved = resolve_shared(shared.path(), &assets, project.path());
+        match verify_checksums(shared.path(), &resolved, &mut cache) {
+            Err(BuildError::AssetUnlisted { ref file, .. }) => {
+                assert_eq!(file, &shared.path().join("configs/config.json"));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    fn listing(dir: &Path) -> Vec<String> {
+        WalkDir::new(dir)
+            .min_depth(1)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .map(|entry| relative(dir, &entry.unwrap()).to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn assets_are_linked_into_the_root_of_the_tree() {
+        let src = tree();
+        let dst = TempDir::new("debrep-build").unwrap();
+        let exclude = strings(&["fixtures/nested"]);
+        let filter = AssetFilter::new(None, Some(&exclude[..])).unwrap();
+
+        let mut linked = Vec::new();
+        fetch_assets(&mut linked, src.path(), dst.path(), &filter).unwrap();
+        assert_eq!(linked.len(), 5);
+        assert_eq!(
+            listing(dst.path()),
+            vec![
+                "README.md",
+                "a.bin",
+                "config.toml",
+                "data",
+                "fixtures",
+                "large.bin",
+                "notes.txt",
+                "usr",
+                "usr/share",
+                "usr/share/app",
+            ]
+        );
+
+        // Of the files with the same name, the last which was walked is linked.
+        misc::write(src.path().join("data/README.md"), b"data").unwrap();
+        assert_eq!(misc::read_to_string(dst.path().join("README.md")).unwrap(), "data");
+    }
+
+    #[test]
+    fn excluded_assets_do_not_change_the_checksum_of_the_tree() {
+        let src = tree();
+        let exclude = strings(&["fixtures", "README*"]);
+        let filter = AssetFilter::new(None, Some(&exclude[..])).unwrap();
+        let digest = || {
+            let dst = TempDir::new("debrep-build").unwrap();
+            let mut linked = Vec::new();
+            fetch_assets(&mut linked, src.path(), dst.path(), &filter).unwrap();
+            tree_digest(dst.path()).unwrap()
+        };
+
+        let before = digest();
+        misc::write(src.path().join("fixtures/nested/deep.bin"), b"changed").unwrap();
+        misc::write(src.path().join("README.md"), b"changed").unwrap();
+        assert_eq!(digest(), before);
+
+        misc::write(src.path().join("notes.txt"), b"changed").unwrap();
+        assert_ne!(digest(), before);
+    }
+
+    #[test]
+    fn nothing_is_left_behind_when_linking_fails() {
+        let src = tree();
+        let dst = TempDir::new("debrep-build").unwrap();
+        fs::create_dir(dst.path().join("data")).unwrap();
+        ::std::os::unix::fs::symlink("missing", src.path().join("usr/share/app/dangling")).unwrap();
+
+        let mut linked = Vec::new();
+        match fetch_assets(&mut linked, src.path(), dst.path(), &AssetFilter::default()) {
+            Err(BuildError::AssetLink { ref file, .. }) => {
+                assert_eq!(file, &src.path().join("usr/share/app/dangling"));
+            }
+            Err(why) => panic!("unexpected error: {}", why),
+            Ok(()) => panic!("a dangling asset was linked"),
+        }
+
+        // Only what existed before is left.
+        assert!(linked.is_empty());
+        assert_eq!(listing(dst.path()), vec!["data"]);
+    }
+
+    #[test]
+    fn everything_included_by_default() {
+        let dir = tree();
+        assert_eq!(files(dir.path(), &AssetFilter::default()).len(), 7);
+    }
+
+    #[test]
+    fn excluding_a_directory_excludes_its_contents() {
+        let dir = tree();
+        let exclude = strings(&["fixtures", "README*"]);
+        let filter = AssetFilter::new(None, Some(&exclude[..])).unwrap();
+        assert_eq!(
+            files(dir.path(), &filter),
+            vec!["data/a.bin", "notes.txt", "usr/share/app/config.toml"]
+        );
+    }
+
+    #[test]
+    fn rooted_patterns_only_match_from_the_asset_root() {
+        let dir = tree();
+        let exclude = strings(&["data/README.md", "fixtures/nested"]);
+        let filter = AssetFilter::new(None, Some(&exclude[..])).unwrap();
+        assert_eq!(
+            files(dir.path(), &filter),
+            vec!["README.md", "data/a.bin", "fixtures/large.bin", "notes.txt", "usr/share/app/config.toml"]
+        );
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let dir = tree();
+        let include = strings(&["usr", "data"]);
+        let exclude = strings(&["*.md"]);
+        let filter = AssetFilter::new(Some(&include[..]), Some(&exclude[..])).unwrap();
+        assert_eq!(files(dir.path(), &filter), vec!["data/a.bin", "usr/share/app/config.toml"]);
+    }
+}
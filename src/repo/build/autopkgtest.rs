@@ -0,0 +1,91 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use command;
+
+/// The outcome of running the tests of a package, as autopkgtest reports it in its exit status.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TestResult {
+    Passed,
+    /// Every test which ran passed, but some were skipped.
+    Skipped,
+    /// The package does not declare any tests.
+    NoTests,
+    Failed(i32),
+}
+
+impl TestResult {
+    fn from_status(status: i32) -> TestResult {
+        match status {
+            0 => TestResult::Passed,
+            2 => TestResult::Skipped,
+            8 => TestResult::NoTests,
+            status => TestResult::Failed(status),
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        match *self {
+            TestResult::Failed(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TestResult::Passed => f.write_str("tests passed"),
+            TestResult::Skipped => f.write_str("tests passed, some skipped"),
+            TestResult::NoTests => f.write_str("no tests"),
+            TestResult::Failed(status) => write!(f, "tests failed with status {}", status),
+        }
+    }
+}
+
+/// The arguments of the virtualization server for a backend such as `schroot:bionic-amd64`.
+pub fn backend_args(backend: &str) -> Vec<&str> {
+    backend.split(':').filter(|arg| !arg.is_empty()).collect()
+}
+
+/// Runs the tests of the packages in the `.changes` files, writing the output of autopkgtest
+/// to the log.
+pub fn run(changes: &[PathBuf], backend: &str, log: File) -> io::Result<TestResult> {
+    let status = command::heavy("autopkgtest")
+        .args(changes)
+        .arg("--")
+        .args(&backend_args(backend))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .status()?;
+
+    match status.code() {
+        Some(status) => Ok(TestResult::from_status(status)),
+        None => Err(io::Error::new(io::ErrorKind::Other, "autopkgtest was killed by a signal")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_statuses() {
+        assert_eq!(TestResult::from_status(0), TestResult::Passed);
+        assert_eq!(TestResult::from_status(2), TestResult::Skipped);
+        assert_eq!(TestResult::from_status(8), TestResult::NoTests);
+        assert!(TestResult::from_status(4).failed());
+        assert!(TestResult::from_status(16).failed());
+        assert_eq!(TestResult::from_status(4).to_string(), "tests failed with status 4");
+    }
+
+    #[test]
+    fn backends() {
+        assert_eq!(backend_args("schroot:bionic-amd64"), vec!["schroot", "bionic-amd64"]);
+        assert_eq!(backend_args("null"), vec!["null"]);
+    }
+}
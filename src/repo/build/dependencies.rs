@@ -0,0 +1,267 @@
+//! The build dependencies which sbuild installed for a build, as its log shows them, and how they
+//! changed since the last build of the package. A package which suddenly fails to build most
+//! often does so because a build dependency changed in the chroot, so the changes are logged for
+//! each build, and shown first with the excerpt of a build which failed.
+//!
+//! The versions are those of the packages which dpkg set up in the section of the log which
+//! installs the build dependencies, which the apt and the aptitude resolvers of sbuild both
+//! write, and else those which either resolver downloaded.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use repo::logs;
+use repo::state::State;
+use repo::version;
+
+/// The version of each build dependency, by its name.
+pub type Dependencies = BTreeMap<String, String>;
+
+/// The dummy packages which sbuild builds to depend on the build dependencies.
+const DUMMY: &str = "sbuild-build-depends-";
+
+/// How the build dependencies of a build differ from those of the last build of the package.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changes {
+    /// Each build dependency which was not installed before, with its version.
+    pub new: Vec<(String, String)>,
+    /// Each build dependency which is no longer installed, with the version which was.
+    pub removed: Vec<(String, String)>,
+    /// Each build dependency whose version went up, with the version before and now.
+    pub upgraded: Vec<(String, String, String)>,
+    pub downgraded: Vec<(String, String, String)>,
+}
+
+impl Changes {
+    /// How the dependencies changed from those before, which is nothing if they are the same.
+    pub fn between(before: &Dependencies, now: &Dependencies) -> Option<Changes> {
+        let mut changes = Changes::default();
+        for (name, version) in now {
+            match before.get(name) {
+                None => changes.new.push((name.clone(), version.clone())),
+                Some(old) if old != version => {
+                    let change = (name.clone(), old.clone(), version.clone());
+                    // Versions which dpkg cannot compare are taken to have gone up.
+                    if version::compare(version, "lt", old).unwrap_or(false) {
+                        changes.downgraded.push(change);
+                    } else {
+                        changes.upgraded.push(change);
+                    }
+                }
+                Some(_) => (),
+            }
+        }
+
+        for (name, version) in before {
+            if !now.contains_key(name) {
+                changes.removed.push((name.clone(), version.clone()));
+            }
+        }
+
+        if changes.is_empty() { None } else { Some(changes) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.new.is_empty()
+            && self.removed.is_empty()
+            && self.upgraded.is_empty()
+            && self.downgraded.is_empty()
+    }
+
+    /// How many build dependencies changed in each way, such as `2 new, 1 upgraded`.
+    pub fn counts(&self) -> String {
+        let counts = [
+            (self.new.len(), "new"),
+            (self.removed.len(), "removed"),
+            (self.upgraded.len(), "upgraded"),
+            (self.downgraded.len(), "downgraded"),
+        ];
+
+        let counts: Vec<String> = counts.iter()
+            .filter(|&&(count, _)| count != 0)
+            .map(|&(count, what)| format!("{} {}", count, what))
+            .collect();
+        counts.join(", ")
+    }
+
+    /// A line for each build dependency which changed.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        lines.extend(self.new.iter().map(|&(ref name, ref version)| {
+            format!("new: {} {}", name, version)
+        }));
+        lines.extend(self.removed.iter().map(|&(ref name, ref version)| {
+            format!("removed: {} {}", name, version)
+        }));
+        lines.extend(self.upgraded.iter().map(|&(ref name, ref old, ref new)| {
+            format!("upgraded: {} {} -> {}", name, old, new)
+        }));
+        lines.extend(self.downgraded.iter().map(|&(ref name, ref old, ref new)| {
+            format!("downgraded: {} {} -> {}", name, old, new)
+        }));
+        lines
+    }
+}
+
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.lines().join("; "))
+    }
+}
+
+/// The build dependencies which the log of the build shows were installed, and how they changed
+/// since the last build of the package in the suite which recorded its own. Logs which cannot be
+/// read, or which installed nothing, have no changes.
+pub fn compare(
+    pwd: &Path,
+    suite: &str,
+    package: &str,
+    log: &Path,
+) -> (Dependencies, Option<Changes>) {
+    let installed = match logs::read(log) {
+        Ok(data) => parse(&String::from_utf8_lossy(&data)),
+        Err(why) => {
+            warn!("failed to read the build dependencies of {} from {:?}: {}", package, log, why);
+            return (Dependencies::new(), None);
+        }
+    };
+
+    if installed.is_empty() {
+        return (installed, None);
+    }
+
+    let before = State::open(pwd).and_then(|state| state.build_dependencies(suite, package));
+    let changes = match before {
+        Ok(Some(ref before)) => Changes::between(before, &installed),
+        Ok(None) => None,
+        Err(why) => {
+            warn!("failed to read the last build dependencies of {}: {}", package, why);
+            None
+        }
+    };
+
+    (installed, changes)
+}
+
+/// The build dependencies which the sbuild log installed, from the section which installs them
+/// until the next section of the log.
+pub fn parse(log: &str) -> Dependencies {
+    let mut set_up = Dependencies::new();
+    let mut downloaded = Dependencies::new();
+    let mut in_section = false;
+    for line in log.lines() {
+        if line.starts_with("| ") {
+            in_section = line.contains("build dependencies") || line.contains("build-essential");
+            continue;
+        }
+
+        if !in_section {
+            continue;
+        }
+
+        if line.starts_with("Setting up ") {
+            set_up.extend(set_up_package(&line[11..]));
+        } else if line.starts_with("Get:") {
+            downloaded.extend(downloaded_package(&line[4..]));
+        }
+    }
+
+    if set_up.is_empty() { downloaded } else { set_up }
+}
+
+/// The name and version of a package from dpkg's `Setting up <name>[:<arch>] (<version>) ...`.
+fn set_up_package(line: &str) -> Option<(String, String)> {
+    let mut fields = line.split_whitespace();
+    let name = fields.next()?;
+    let version = fields.next()?;
+    if !version.starts_with('(') || !version.ends_with(')') {
+        return None;
+    }
+
+    package(name, &version[1..version.len() - 1])
+}
+
+/// The name and version of a package from what apt writes as it downloads it,
+/// `Get:<n> <uri> <suite>/<component> <arch> <name> <arch> <version> [<size>]`, which aptitude
+/// writes with a space after `Get:`. Indices, and the files of the archive of the resolver, which
+/// have no section and architecture, are left out.
+fn downloaded_package(line: &str) -> Option<(String, String)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let size = fields.iter().position(|field| field.starts_with('['))?;
+    if size < 6 {
+        return None;
+    }
+
+    package(fields[size - 3], fields[size - 1])
+}
+
+fn package(name: &str, version: &str) -> Option<(String, String)> {
+    let name = name.split(':').next().unwrap_or(name);
+    if name.is_empty() || version.is_empty() || name.starts_with(DUMMY) {
+        return None;
+    }
+
+    Some((name.to_owned(), version.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APT: &str = include_str!("fixtures/sbuild-build-deps-apt.log");
+    const APTITUDE: &str = include_str!("fixtures/sbuild-build-deps-aptitude.log");
+
+    fn dependencies(pairs: &[(&str, &str)]) -> Dependencies {
+        pairs.iter().map(|&(name, version)| (name.to_owned(), version.to_owned())).collect()
+    }
+
+    #[test]
+    fn dependencies_of_the_apt_resolver() {
+        assert_eq!(
+            parse(APT),
+            dependencies(&[
+                ("cargo", "0.27.0-0ubuntu1"),
+                ("debhelper", "11.1.6ubuntu2"),
+                ("libgtk-3-dev", "3.22.30-1ubuntu1"),
+                ("libstd-rust-1.26", "1.26.1+dfsg0ubuntu0.18.04.1"),
+                ("rustc", "1.26.1+dfsg0ubuntu0.18.04.1"),
+            ])
+        );
+    }
+
+    #[test]
+    fn dependencies_of_the_aptitude_resolver() {
+        assert_eq!(
+            parse(APTITUDE),
+            dependencies(&[
+                ("cargo", "0.28.0-0ubuntu1"),
+                ("debhelper", "11.1.6ubuntu2"),
+                ("libstd-rust-1.27", "1.27.1+dfsg1+llvm-0ubuntu2~18.04.1"),
+                ("rustc", "1.27.1+dfsg1+llvm-0ubuntu2~18.04.1"),
+            ])
+        );
+
+        // Logs which were cut off before dpkg set anything up have what was downloaded.
+        let end = APTITUDE.find("Selecting previously unselected").unwrap();
+        assert_eq!(parse(&APTITUDE[..end]).len(), 4);
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn changes_since_the_last_build() {
+        let changes = Changes::between(&parse(APT), &parse(APTITUDE)).unwrap();
+        assert_eq!(
+            changes.lines(),
+            vec![
+                "new: libstd-rust-1.27 1.27.1+dfsg1+llvm-0ubuntu2~18.04.1",
+                "removed: libgtk-3-dev 3.22.30-1ubuntu1",
+                "removed: libstd-rust-1.26 1.26.1+dfsg0ubuntu0.18.04.1",
+                "upgraded: cargo 0.27.0-0ubuntu1 -> 0.28.0-0ubuntu1",
+                "upgraded: rustc 1.26.1+dfsg0ubuntu0.18.04.1 -> 1.27.1+dfsg1+llvm-0ubuntu2~18.04.1",
+            ]
+        );
+        assert_eq!(changes.counts(), "1 new, 2 removed, 2 upgraded");
+        assert_eq!(Changes::between(&parse(APT), &parse(APT)), None);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use config::Reproducible;
+use misc;
+
+/// The umask of builds, unless configured otherwise.
+const DEFAULT_UMASK: u32 = 0o022;
+
+/// The inputs to a build which are normalized, so that packages can be reproduced.
+#[derive(Debug, PartialEq)]
+pub struct Environment {
+    pub variables: BTreeMap<String, String>,
+    pub umask: u32,
+}
+
+impl Environment {
+    /// The configured environment, or `TZ=UTC`, `LC_ALL=C.UTF-8`, and a umask of `022`.
+    pub fn normalized(config: Option<&Reproducible>) -> Environment {
+        let variables = config.and_then(|config| config.environment.clone()).unwrap_or_else(|| {
+            let mut variables = BTreeMap::new();
+            variables.insert("TZ".to_owned(), "UTC".to_owned());
+            variables.insert("LC_ALL".to_owned(), "C.UTF-8".to_owned());
+            variables
+        });
+
+        let umask = config.and_then(|config| config.umask.as_ref()).map_or(DEFAULT_UMASK, |umask| {
+            u32::from_str_radix(umask, 8).unwrap_or_else(|_| {
+                warn!("invalid umask {:?}; using {:03o}", umask, DEFAULT_UMASK);
+                DEFAULT_UMASK
+            })
+        });
+
+        Environment { variables, umask }
+    }
+
+    /// The values which are written to the record of a package that was built.
+    pub fn record(&self) -> String {
+        let mut record = format!("umask {:03o}\n", self.umask);
+        for (key, value) in &self.variables {
+            let _ = writeln!(record, "{}={}", key, value);
+        }
+
+        record
+    }
+}
+
+/// Writes an sbuild config which adds the variables to the build environment, and sets the umask
+/// of sbuild, which the build inherits from it within the chroot. The user's own `~/.sbuildrc` is
+/// read first, so that it still applies. An `isolated` build is run by sbuild's
+/// `unshare` backend with the network disabled, so that the build only has a loopback interface.
+pub fn write_sbuild_config<'a, I>(
+    path: &Path,
+    variables: I,
+    umask: u32,
+    isolated: bool,
+) -> io::Result<()>
+    where I: IntoIterator<Item = (&'a String, &'a String)>
+{
+    let escape = |value: &str| value.replace('\\', "\\\\").replace('\'', "\\'");
+    let mut config = String::from(
+        "do \"$ENV{HOME}/.sbuildrc\" if -f \"$ENV{HOME}/.sbuildrc\";\n\
+         $build_environment = {\n    %{$build_environment // {}},\n"
+    );
+
+    for (key, value) in variables {
+        let _ = writeln!(config, "    '{}' => '{}',", escape(key), escape(value));
+    }
+
+    let _ = writeln!(config, "}};\numask(0{:03o});", umask);
+    if isolated {
+        config.push_str("$chroot_mode = 'unshare';\n$enable_network = 0;\n");
+    }
+
+    config.push_str("1;\n");
+    misc::write(path, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn sbuild_config_sets_variables() {
+        let dir = TempDir::new("debrep-environment").unwrap();
+        let path = dir.path().join("sbuildrc");
+        let environment = Environment::normalized(None);
+        write_sbuild_config(&path, &environment.variables, environment.umask, false).unwrap();
+
+        assert_eq!(
+            misc::read_to_string(&path).unwrap(),
+            "do \"$ENV{HOME}/.sbuildrc\" if -f \"$ENV{HOME}/.sbuildrc\";\n\
+             $build_environment = {\n    \
+             %{$build_environment // {}},\n    \
+             'LC_ALL' => 'C.UTF-8',\n    \
+             'TZ' => 'UTC',\n\
+             };\n\
+             umask(0022);\n\
+             1;\n"
+        );
+    }
+
+    #[test]
+    fn configured_environment() {
+        let mut variables = BTreeMap::new();
+        variables.insert("TZ".to_owned(), "UTC".to_owned());
+        let config = Reproducible { environment: Some(variables), umask: Some("027".to_owned()) };
+
+        let environment = Environment::normalized(Some(&config));
+        assert_eq!(environment.umask, 0o027);
+        assert_eq!(environment.record(), "umask 027\nTZ=UTC\n");
+    }
+}
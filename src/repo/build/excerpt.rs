@@ -0,0 +1,234 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use repo::logs;
+use super::dependencies::Changes;
+
+/// The parts of a failed sbuild log that are most likely to explain why the build failed.
+#[derive(Debug, Default, PartialEq)]
+pub struct LogExcerpt {
+    /// The summary block which sbuild writes at the end of the log.
+    pub summary: Vec<String>,
+    /// The last command which reported that it had failed.
+    pub last_command: Option<String>,
+    /// Lines surrounding the first line which looked like an error.
+    pub context: Vec<String>,
+    /// How the build dependencies changed since the last build, which is shown first, since it
+    /// is the most common reason for a package to suddenly fail.
+    pub dependencies: Option<Changes>,
+}
+
+impl LogExcerpt {
+    /// Reads the log at the given path, which may have been compressed, or only partially written.
+    pub fn from_log(path: &Path, context: usize) -> io::Result<LogExcerpt> {
+        logs::read(path).map(|data| LogExcerpt::parse(&String::from_utf8_lossy(&data), context))
+    }
+
+    pub fn parse(log: &str, context: usize) -> LogExcerpt {
+        let mut lines: Vec<&str> = log.lines().collect();
+
+        // The last line of a log from a killed build may have been cut off mid-write.
+        if !log.ends_with('\n') {
+            lines.pop();
+        }
+
+        let summary_start = lines.iter()
+            .position(|line| line.starts_with("| Summary"))
+            .map(|pos| pos + 2);
+
+        let summary = summary_start.map_or(Vec::new(), |start| {
+            lines.iter()
+                .skip(start)
+                .take_while(|line| !line.starts_with("-----"))
+                .filter(|line| !line.is_empty())
+                .map(|&line| line.to_owned())
+                .collect()
+        });
+
+        let body = &lines[..summary_start.map_or(lines.len(), |start| start.saturating_sub(3))];
+
+        let last_command = body.iter()
+            .rev()
+            .find(|line| is_failed_command(line))
+            .map(|&line| line.to_owned());
+
+        let context = body.iter()
+            .position(|line| is_failure(line))
+            .map_or(Vec::new(), |pos| {
+                let start = pos.saturating_sub(context);
+                let end = (pos + context + 1).min(body.len());
+                body[start..end].iter().map(|&line| line.to_owned()).collect()
+            });
+
+        LogExcerpt { summary, last_command, context, dependencies: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.summary.is_empty()
+            && self.last_command.is_none()
+            && self.context.is_empty()
+            && self.dependencies.is_none()
+    }
+}
+
+impl fmt::Display for LogExcerpt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref changes) = self.dependencies {
+            writeln!(f, "build dependencies which changed since the last build:")?;
+            for line in changes.lines() {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+
+        if !self.context.is_empty() {
+            writeln!(f, "first error:")?;
+            for line in &self.context {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+
+        if let Some(ref command) = self.last_command {
+            writeln!(f, "last failed command:\n    {}", command)?;
+        }
+
+        if !self.summary.is_empty() {
+            writeln!(f, "sbuild summary:")?;
+            for line in &self.summary {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lines which indicate the first occurrence of a problem.
+fn is_failure(line: &str) -> bool {
+    line.contains("error:")
+        || line.contains("error[")
+        || line.starts_with("E: ")
+        || (line.starts_with("dh_") && (line.contains("returned exit code") || line.contains("failed")))
+        || line.contains("unmet dependencies")
+        || line.contains("unsat-dependency")
+        || line.contains("Unsatisfied build dependencies")
+        || line.starts_with("debrep: ")
+}
+
+/// Lines which are written when a command exits unsuccessfully.
+fn is_failed_command(line: &str) -> bool {
+    line.contains("returned exit code")
+        || line.contains("returned exit status")
+        || (line.starts_with("make") && line.contains(": *** "))
+        || line == "apt-get failed."
+        || line.starts_with("debrep: ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPILE_ERROR: &str = include_str!("fixtures/sbuild-compile-error.log");
+    const UNSATISFIABLE: &str = include_str!("fixtures/sbuild-unsatisfiable.log");
+    const KILLED: &str = include_str!("fixtures/sbuild-killed.log");
+
+    #[test]
+    fn compile_error() {
+        let excerpt = LogExcerpt::parse(COMPILE_ERROR, 2);
+        assert_eq!(
+            excerpt.context,
+            vec![
+                "  = note: #[warn(unused_imports)] on by default",
+                "",
+                "error[E0425]: cannot find value `greeting` in this scope",
+                " --> src/main.rs:4:20",
+                "  |",
+            ]
+        );
+        assert_eq!(
+            excerpt.last_command.as_ref().map(|x| x.as_str()),
+            Some("dpkg-buildpackage: error: debian/rules build-arch subprocess returned exit status 2")
+        );
+        assert_eq!(excerpt.summary.len(), 16);
+        assert_eq!(excerpt.summary[5], "Fail-Stage: build");
+    }
+
+    #[test]
+    fn unsatisfiable_build_dependencies() {
+        let excerpt = LogExcerpt::parse(UNSATISFIABLE, 1);
+        assert_eq!(
+            excerpt.context,
+            vec![
+                "",
+                "The following packages have unmet dependencies:",
+                " sbuild-build-depends-main-dummy : Depends: nvidia-driver-396 but it is not installable",
+            ]
+        );
+        assert_eq!(excerpt.last_command.as_ref().map(|x| x.as_str()), Some("apt-get failed."));
+        assert!(excerpt.summary.contains(&"Fail-Stage: install-deps".to_owned()));
+    }
+
+    #[test]
+    fn killed_build() {
+        let excerpt = LogExcerpt::parse(KILLED, 0);
+        assert!(excerpt.summary.is_empty());
+        assert_eq!(
+            excerpt.context,
+            vec!["foo.c:42:5: error: implicit declaration of function 'foo_reset' [-Werror=implicit-function-declaration]"]
+        );
+        assert_eq!(
+            excerpt.last_command.as_ref().map(|x| x.as_str()),
+            Some("make: *** [debian/rules:6: build-arch] Error 2")
+        );
+    }
+
+    #[test]
+    fn failed_prebuild_command() {
+        let log = "Pre Build Commands\n\
+            ------------------\n\
+            \n\
+            npm ERR! network request failed\n\
+            debrep: failed the prebuild command of pop-shop: npm install\n\
+            E: Failed to execute \u{201c}pre-build-commands\u{201d}\n";
+
+        let excerpt = LogExcerpt::parse(log, 1);
+        assert_eq!(
+            excerpt.context,
+            vec![
+                "npm ERR! network request failed",
+                "debrep: failed the prebuild command of pop-shop: npm install",
+                "E: Failed to execute \u{201c}pre-build-commands\u{201d}",
+            ]
+        );
+        assert_eq!(
+            excerpt.last_command.as_ref().map(|x| x.as_str()),
+            Some("debrep: failed the prebuild command of pop-shop: npm install")
+        );
+    }
+
+    #[test]
+    fn changed_build_dependencies_come_first() {
+        let mut excerpt = LogExcerpt::parse(UNSATISFIABLE, 0);
+        excerpt.dependencies = Some(Changes {
+            removed: vec![("nvidia-driver-390".to_owned(), "390.77-0ubuntu0.18.04.1".to_owned())],
+            ..Changes::default()
+        });
+
+        let text = excerpt.to_string();
+        assert!(text.starts_with(
+            "build dependencies which changed since the last build:\n    \
+             removed: nvidia-driver-390 390.77-0ubuntu0.18.04.1\n\
+             first error:\n"
+        ));
+    }
+
+    #[test]
+    fn truncated_at_every_line() {
+        let lines: Vec<&str> = COMPILE_ERROR.lines().collect();
+        for end in 0..lines.len() {
+            let log = lines[..end].join("\n");
+            let _ = LogExcerpt::parse(&log, 5);
+        }
+
+        assert!(LogExcerpt::parse("", 5).is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+//! The files which are left out of the source packages of sources, with `source_excludes`, such
+//! as vendored test fixtures, or editor files which the debian overlay synced. Each glob is given
+//! to dpkg-source twice: as a pattern of `-I`, which leaves matching files out of the tarballs,
+//! and as a regex of `--extend-diff-ignore`, which leaves them out of the diff of the debian
+//! directory, so that neither carries them into the `.dsc`.
+//!
+//! A glob without a `/` matches files of that name in any directory, as tar's exclude patterns
+//! do, and a glob which matches a directory matches everything under it.
+
+use config::Source;
+use regex::Regex;
+
+/// What is left out of every source package, unless a source sets `default_source_excludes =
+/// false`: the directories of version control systems, and the files of debrep itself.
+pub const DEFAULT_EXCLUDES: &[&str] = &[".git", ".bzr", ".hg", ".svn", "_darcs", ".debrep-*"];
+
+/// Paths of the debian directory which an exclude should not match, as excluding them changes
+/// how the package is built.
+const DEBIAN: &[&str] = &["debian", "debian/control", "debian/rules", "debian/changelog"];
+
+/// The globs of the files which are left out of the source package of the source.
+pub fn patterns(item: &Source) -> Vec<&str> {
+    let defaults: &[&str] = if item.default_source_excludes { DEFAULT_EXCLUDES } else { &[] };
+    let mut patterns: Vec<&str> = defaults.to_vec();
+    for pattern in item.source_excludes.iter().flat_map(|x| x) {
+        if !patterns.contains(&pattern.as_str()) {
+            patterns.push(pattern);
+        }
+    }
+
+    patterns
+}
+
+/// The options of dpkg-source which leave out the files of the globs.
+pub fn dpkg_source_options(patterns: &[&str]) -> Vec<String> {
+    let mut options = Vec::with_capacity(patterns.len() * 2);
+    for pattern in patterns {
+        // Tar matches `*` across directories in exclude patterns already.
+        options.push(["-I", &pattern.replace("**", "*")].concat());
+        options.push(["--extend-diff-ignore=", &regex(pattern)].concat());
+    }
+
+    options
+}
+
+/// The globs which match the debian directory, or the files of it which the build needs, which is
+/// almost always a mistake.
+pub fn matching_debian<'a>(patterns: &[&'a str]) -> Vec<&'a str> {
+    patterns.iter()
+        .filter(|pattern| {
+            Regex::new(&regex(pattern))
+                .map(|regex| DEBIAN.iter().any(|path| regex.is_match(path)))
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect()
+}
+
+/// The Perl regex of the glob, which matches the paths relative to the root of the source which
+/// it matches, and everything under them.
+fn regex(glob: &str) -> String {
+    let glob = glob.trim_matches('/');
+    let mut regex = String::from(if glob.contains('/') { "^" } else { "(?:^|/)" });
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '\\' | '.' | '+' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '^' | '$' => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            c => regex.push(c),
+        }
+    }
+
+    regex.push_str("(?:/|$)");
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+    use misc;
+    use tempdir::TempDir;
+
+    #[test]
+    fn globs_are_regexes_of_paths() {
+        assert_eq!(regex(".debrep-*"), "(?:^|/)\\.debrep-[^/]*(?:/|$)");
+        assert_eq!(regex("tests/fixtures/**/*.bin"), "^tests/fixtures/.*/[^/]*\\.bin(?:/|$)");
+        assert_eq!(
+            matching_debian(&["*.swp", "debian/**", "rules", "debian/patches/*.orig"]),
+            vec!["debian/**", "rules"]
+        );
+        assert_eq!(
+            dpkg_source_options(&["tests/**/fixtures"]),
+            vec!["-Itests/*/fixtures", "--extend-diff-ignore=^tests/.*/fixtures(?:/|$)"]
+        );
+    }
+
+    #[test]
+    fn excluded_files_are_left_out_of_the_source_package() {
+        let dir = TempDir::new("debrep-excludes").unwrap();
+        let tree = dir.path().join("pop-shop-1.0");
+        for file in &[
+            "debian/source/format",
+            "src/main.rs",
+            "tests/fixtures/large.bin",
+            ".debrep-assets",
+            "src/main.rs.swp",
+            ".git/HEAD",
+        ] {
+            let path = tree.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            misc::write(&path, "fixture\n").unwrap();
+        }
+
+        misc::write(tree.join("debian/source/format"), "3.0 (native)\n").unwrap();
+        misc::write(tree.join("debian/changelog"), "pop-shop (1.0) bionic; urgency=medium\n\n  \
+            * Initial release.\n\n -- Pop Builder <pop@example.com>  Mon, 15 Oct 2018 12:00:00 \
+            +0000\n").unwrap();
+        misc::write(tree.join("debian/control"), "Source: pop-shop\nMaintainer: Pop Builder \
+            <pop@example.com>\n\nPackage: pop-shop\nArchitecture: all\nDescription: shop\n \
+            The shop.\n").unwrap();
+
+        let mut source: Source = ::toml::from_str(
+            "name = \"pop-shop\"\nsource_excludes = [\"tests/fixtures\", \"*.swp\"]"
+        ).unwrap();
+        let options = dpkg_source_options(&patterns(&source));
+        let built = Command::new("dpkg-source")
+            .args(&options)
+            .arg("-b")
+            .arg("pop-shop-1.0")
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        assert!(built.status.success(), "{}", String::from_utf8_lossy(&built.stderr));
+
+        let listed = Command::new("tar")
+            .args(&["-tf", "pop-shop_1.0.tar.xz"])
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        let listed = String::from_utf8_lossy(&listed.stdout);
+        let mut files: Vec<&str> = listed.lines().filter(|line| !line.ends_with('/')).collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "pop-shop-1.0/debian/changelog",
+                "pop-shop-1.0/debian/control",
+                "pop-shop-1.0/debian/source/format",
+                "pop-shop-1.0/src/main.rs",
+            ]
+        );
+
+        // Only the globs of the source are left out when the defaults are replaced.
+        source.default_source_excludes = false;
+        assert_eq!(patterns(&source), vec!["tests/fixtures", "*.swp"]);
+    }
+}
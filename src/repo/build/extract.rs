@@ -1,20 +1,37 @@
 use std::{fs, io};
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use command::Cmd;
 
 pub fn extract(src: &Path, dst: &Path) -> io::Result<()>  {
     match src.file_name().and_then(|x| x.to_str()) {
-        Some(filename) => {
-            if filename.ends_with(".zip") {
-                unzip(src, dst)
-            } else if filename.ends_with(".tar.gz") || filename.ends_with(".tar.xz") {
-                untar(src, dst)
-            } else {
-                unimplemented!()
-            }
+        Some(filename) if filename.ends_with(".zip") => unzip(src, dst),
+        Some(filename) if filename.ends_with(".tar.gz") || filename.ends_with(".tar.xz") => {
+            untar(src, dst)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a .zip, .tar.gz, or .tar.xz archive", src.display())
+        )),
+    }
+}
+
+/// The debian directory of an extracted archive of one, which is the directory itself when the
+/// archive held the files of `debian/` at its root, or in a directory such as `debian/`, and else
+/// the `debian/` of a directory in it, as in `pkgname-1.0/debian/`. It is the first of those which
+/// has a `control` file.
+pub fn debian_dir(dir: &Path) -> io::Result<Option<PathBuf>> {
+    let mut candidates = vec![dir.to_path_buf(), dir.join("debian")];
+    let mut nested = Vec::new();
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.is_dir() {
+            nested.push(path.join("debian"));
         }
-        None => unimplemented!()
     }
+
+    nested.sort();
+    candidates.extend(nested);
+    Ok(candidates.into_iter().find(|path| path.join("control").is_file()))
 }
 
 fn unzip(path: &Path, dst: &Path) -> io::Result<()> {
@@ -23,18 +40,13 @@ fn unzip(path: &Path, dst: &Path) -> io::Result<()> {
     }
 
     fs::create_dir_all(dst)
-        .and_then(|_| Command::new("unzip")
+        .and_then(|_| Cmd::new("unzip")
             .arg("-qq")
             .arg(path)
             .arg("-d")
             .arg(dst)
-            .status()
-            .and_then(|x| if x.success() {
-                Ok(())
-            } else {
-                Err(io::Error::new(io::ErrorKind::Other, "unzip command failed"))
-            })
-        )
+            .run()
+            .map_err(io::Error::from))
 }
 
 fn untar(path: &Path, dst: &Path) -> io::Result<()> {
@@ -43,17 +55,70 @@ fn untar(path: &Path, dst: &Path) -> io::Result<()> {
     }
 
     fs::create_dir_all(dst)
-        .and_then(|_| Command::new("tar")
+        .and_then(|_| Cmd::new("tar")
             .arg("-xf")
             .arg(path)
             .arg("-C")
             .arg(dst)
             .args(&["--strip-components", "1"])
-            .status()
-            .and_then(|x| if x.success() {
-                Ok(())
-            } else {
-                Err(io::Error::new(io::ErrorKind::Other, "tar command failed"))
-            })
-        )
+            .run()
+            .map_err(io::Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use tempdir::TempDir;
+
+    /// An archive of the files, with `./` before each path if `dotted`.
+    fn archive(dir: &Path, name: &str, files: &[&str], dotted: bool) -> PathBuf {
+        let root = dir.join("root");
+        let mut members: Vec<&str> = Vec::new();
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            misc::write(&path, file).unwrap();
+            let top = file.split('/').next().unwrap();
+            if !members.contains(&top) {
+                members.push(top);
+            }
+        }
+
+        let archive = dir.join(name);
+        Cmd::new("tar")
+            .arg("-cJf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&root)
+            .args(if dotted { &["."] } else { members.as_slice() })
+            .run()
+            .unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        archive
+    }
+
+    #[test]
+    fn debian_directories_are_found_wherever_archives_put_them() {
+        let dir = TempDir::new("debrep-extract").unwrap();
+        let nested = &["pop-shop-1.0/debian/control", "pop-shop-1.0/README"];
+        let cases: &[(&str, &[&str], bool, &str)] = &[
+            ("flat.tar.xz", &["debian/control", "debian/rules"], false, ""),
+            ("dotted.tar.xz", &["debian/control", "debian/rules"], true, "debian"),
+            ("nested.tar.xz", nested, false, "debian"),
+            ("deep.tar.xz", &[nested[0], "README"], true, "pop-shop-1.0/debian"),
+        ];
+
+        for &(name, files, dotted, expected) in cases {
+            let archive = archive(dir.path(), name, files, dotted);
+            let dst = dir.path().join(name.split('.').next().unwrap());
+            extract(&archive, &dst).unwrap();
+            assert_eq!(debian_dir(&dst).unwrap(), Some(dst.join(expected)), "{}", name);
+        }
+
+        let archive = archive(dir.path(), "empty.tar.xz", &["pop-shop-1.0/README"], false);
+        let dst = dir.path().join("empty");
+        extract(&archive, &dst).unwrap();
+        assert_eq!(debian_dir(&dst).unwrap(), None);
+    }
 }
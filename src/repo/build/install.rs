@@ -0,0 +1,320 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use misc;
+use super::super::pool::PoolIndex;
+use tempdir::TempDir;
+
+/// Where the packages are unpacked within the session.
+const STAGING: &str = "/srv/debrep-install";
+
+/// How long installing may take, unless configured otherwise.
+pub const DEFAULT_TIMEOUT: u64 = 30 * 60;
+
+/// A schroot session, which is ended when it is dropped.
+pub struct Session {
+    name: String,
+}
+
+impl Session {
+    pub fn begin(chroot: &str) -> io::Result<Session> {
+        let output = Command::new("schroot")
+            .args(&["--begin-session", "-c", chroot])
+            .stdin(Stdio::null())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "failed to begin a session of {}: {}",
+                    chroot,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )
+            ));
+        }
+
+        let name = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        Ok(Session { name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Takes over a session which was begun by another process, such as sbuild, to end it.
+    pub fn adopt(name: String) -> Session {
+        Session { name }
+    }
+
+    /// The names of the sessions which are open.
+    pub fn open() -> Vec<String> {
+        let output = Command::new("schroot")
+            .args(&["--list", "--all-sessions"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+
+        match output {
+            Ok(ref output) if output.status.success() => {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .map(|line| line.trim())
+                    .map(|line| line.trim_left_matches("session:").to_owned())
+                    .filter(|line| !line.is_empty())
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Runs the script as root within the session, writing its output to the log, and returning
+    /// whether it succeeded. The script is killed if it outlives the timeout.
+    fn run(&self, script: &str, stdin: Stdio, log: &mut File, timeout: u64) -> io::Result<bool> {
+        writeln!(log, "debrep: {}", script)?;
+        let status = Command::new("timeout")
+            .args(&["--kill-after=10", &timeout.to_string(), "schroot", "--run-session", "-c"])
+            .arg(&self.name)
+            .args(&["--directory", "/", "-u", "root", "--", "sh", "-c", script])
+            .stdin(stdin)
+            .stdout(log.try_clone()?)
+            .stderr(log.try_clone()?)
+            .status()?;
+
+        if status.code() == Some(124) || status.code() == Some(137) {
+            writeln!(log, "debrep: timed out after {}s", timeout)?;
+        }
+
+        Ok(status.success())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let ended = Command::new("schroot")
+            .args(&["--end-session", "-c", &self.name])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+
+        match ended {
+            Ok(ref status) if status.success() => (),
+            _ => warn!("failed to end the schroot session {}", self.name),
+        }
+    }
+}
+
+/// The configured chroot, or otherwise the chroot which sbuild builds packages for the archive
+/// in, as `sbuild-createchroot` names it.
+pub fn chroot(configured: Option<&String>, archive: &str) -> io::Result<String> {
+    if let Some(chroot) = configured {
+        return Ok(chroot.clone());
+    }
+
+    Ok(chroot_of(archive, &misc::host_architecture()?))
+}
+
+/// The chroot which sbuild builds packages of the architecture for the archive in.
+pub fn chroot_of(archive: &str, arch: &str) -> String {
+    [archive, "-", arch, "-sbuild"].concat()
+}
+
+/// The name of the binary package of a `.deb` file.
+fn package_name(deb: &Path) -> Option<&str> {
+    let filename = deb.file_name()?.to_str()?;
+    filename.find('_').map(|pos| &filename[..pos])
+}
+
+/// The `.deb` files of the architecture, and of `all`, which sbuild wrote into the directory,
+/// except for debug symbols.
+pub fn built_debs(dir: &Path, arch: &str) -> io::Result<Vec<PathBuf>> {
+    let mut debs = Vec::new();
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        let is_deb = path.extension().map_or(false, |ext| ext == "deb") && path.is_file();
+        let of = path.file_stem().and_then(|x| x.to_str()).map(misc::get_arch_from_stem);
+        let is_of_arch = of == Some(arch) || of == Some("all");
+        if is_deb && is_of_arch && package_name(&path).map_or(false, |x| !x.ends_with("-dbgsym")) {
+            debs.push(path);
+        }
+    }
+
+    debs.sort();
+    Ok(debs)
+}
+
+/// The versions of the packages which were previously published to the pool of the archive.
+pub fn previous_debs(pool: &PoolIndex, debs: &[PathBuf]) -> Vec<PathBuf> {
+    let built: Vec<(&str, String)> = debs.iter()
+        .filter_map(|deb| {
+            let name = package_name(deb)?;
+            let stem = deb.file_stem()?.to_str()?;
+            Some((name, ["binary-", misc::get_arch_from_stem(stem)].concat()))
+        })
+        .collect();
+
+    let mut previous: Vec<PathBuf> = pool.files()
+        .into_iter()
+        .filter(|file| file.path.extension().map_or(false, |ext| ext == "deb"))
+        .filter(|file| {
+            built.iter().any(|&(name, ref section)| file.name == name && file.section == *section)
+        })
+        .map(|file| file.path.clone())
+        .collect();
+
+    previous.sort();
+    previous
+}
+
+/// Installs the packages in a session of the chroot, after installing their previously published
+/// versions if there are any, and returns whether that succeeded. The session is ended whether or
+/// not it does.
+pub fn check(
+    chroot: &str,
+    debs: &[PathBuf],
+    previous: &[PathBuf],
+    log: &mut File,
+    timeout: u64,
+) -> io::Result<bool> {
+    let staging = TempDir::new("debrep-install")?;
+    stage(&staging.path().join("new"), debs)?;
+    if !previous.is_empty() {
+        stage(&staging.path().join("previous"), previous)?;
+    }
+
+    let session = Session::begin(chroot)?;
+    info!("installing {} packages in the schroot session {}", debs.len(), session.name);
+
+    let mut tar = Command::new("tar")
+        .arg("-C")
+        .arg(staging.path())
+        .args(&["-c", "."])
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let archive = match tar.stdout.take() {
+        Some(stdout) => Stdio::from(stdout),
+        None => return Err(io::Error::new(io::ErrorKind::Other, "tar has no output")),
+    };
+
+    let unpacked = session.run(
+        &["mkdir -p ", STAGING, " && tar -x -C ", STAGING].concat(),
+        archive,
+        log,
+        timeout,
+    );
+
+    let packed = tar.wait()?.success();
+    if !unpacked? || !packed {
+        return Ok(false);
+    }
+
+    for script in scripts(&names(previous), &names(debs)) {
+        if !session.run(&script, Stdio::null(), log, timeout)? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn names(debs: &[PathBuf]) -> Vec<&str> {
+    let mut names: Vec<&str> = debs.iter().filter_map(|deb| package_name(deb)).collect();
+    names.dedup();
+    names
+}
+
+/// Copies the packages into a directory, along with an index which apt can read them from.
+fn stage(dir: &Path, debs: &[PathBuf]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for deb in debs {
+        if let Some(filename) = deb.file_name() {
+            fs::copy(deb, dir.join(filename))?;
+        }
+    }
+
+    let output = Command::new("apt-ftparchive").args(&["packages", "."]).current_dir(dir).output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("apt-ftparchive failed: {}", String::from_utf8_lossy(&output.stderr).trim())
+        ));
+    }
+
+    misc::write(dir.join("Packages"), &output.stdout)
+}
+
+/// The scripts which install the previous packages, and then upgrade to and install the new ones.
+fn scripts(previous: &[&str], new: &[&str]) -> Vec<String> {
+    let source = |name: &str| {
+        [
+            "echo 'deb [trusted=yes] file:", STAGING, "/", name, " ./' \
+             > /etc/apt/sources.list.d/debrep-", name, ".list && apt-get update",
+        ].concat()
+    };
+
+    let apt = "DEBIAN_FRONTEND=noninteractive apt-get -y";
+    let mut scripts = Vec::new();
+    if !previous.is_empty() {
+        scripts.push([&source("previous"), " && ", apt, " install ", &previous.join(" ")].concat());
+        scripts.push([&source("new"), " && ", apt, " upgrade"].concat());
+        scripts.push([apt, " install ", &new.join(" ")].concat());
+    } else {
+        scripts.push([&source("new"), " && ", apt, " install ", &new.join(" ")].concat());
+    }
+
+    scripts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previous_versions_are_found() {
+        let dir = TempDir::new("debrep-install").unwrap();
+        let pool = dir.path().join("pool/bionic/main");
+        let published = pool.join("binary-amd64/p/pop-shop");
+        fs::create_dir_all(&published).unwrap();
+        for name in &["pop-shop_1.0_amd64.deb", "pop-shop-dbgsym_1.0_amd64.deb"] {
+            misc::write(published.join(name), b"deb").unwrap();
+        }
+
+        let build = dir.path().join("build");
+        fs::create_dir_all(&build).unwrap();
+        for name in &[
+            "pop-shop_1.1_amd64.deb",
+            "pop-shop_1.1_i386.deb",
+            "pop-shop-dbgsym_1.1_amd64.deb",
+            "pop.changes",
+        ] {
+            misc::write(build.join(name), b"deb").unwrap();
+        }
+
+        let debs = built_debs(&build, "amd64").unwrap();
+        assert_eq!(debs, vec![build.join("pop-shop_1.1_amd64.deb")]);
+        let pool = PoolIndex::open(pool.parent().unwrap()).unwrap();
+        assert_eq!(previous_debs(&pool, &debs), vec![published.join("pop-shop_1.0_amd64.deb")]);
+    }
+
+    #[test]
+    fn upgrades_from_previous_versions() {
+        assert_eq!(
+            scripts(&[], &["pop-shop"]),
+            vec![
+                "echo 'deb [trusted=yes] file:/srv/debrep-install/new ./' \
+                 > /etc/apt/sources.list.d/debrep-new.list && apt-get update && \
+                 DEBIAN_FRONTEND=noninteractive apt-get -y install pop-shop",
+            ]
+        );
+
+        let scripts = scripts(&["pop-shop"], &["pop-shop", "pop-shop-data"]);
+        assert_eq!(scripts.len(), 3);
+        assert!(scripts[0].ends_with("apt-get -y install pop-shop"));
+        assert!(scripts[1].ends_with("apt-get -y upgrade"));
+        assert!(scripts[2].ends_with("apt-get -y install pop-shop pop-shop-data"));
+    }
+}
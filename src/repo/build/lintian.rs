@@ -0,0 +1,186 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use command;
+use config::{LintianConfig, LintianFailOn};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+    Pedantic,
+    Experimental,
+    Overridden,
+}
+
+impl Severity {
+    fn from_code(code: &str) -> Option<Severity> {
+        match code {
+            "E" => Some(Severity::Error),
+            "W" => Some(Severity::Warning),
+            "I" => Some(Severity::Info),
+            "P" => Some(Severity::Pedantic),
+            "X" => Some(Severity::Experimental),
+            "O" => Some(Severity::Overridden),
+            _ => None,
+        }
+    }
+}
+
+/// A tag which lintian emitted for a package.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The package, followed by ` source` for tags about the source package.
+    pub package: String,
+    pub tag: String,
+    pub info: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.package, self.tag)?;
+        if !self.info.is_empty() {
+            write!(f, " {}", self.info)?;
+        }
+        Ok(())
+    }
+}
+
+/// How many findings of each severity lintian reported, besides those which were overridden.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    pub errors: usize,
+    pub warnings: usize,
+    pub info: usize,
+}
+
+impl Counts {
+    pub fn of(findings: &[Finding]) -> Counts {
+        let count = |severity| findings.iter().filter(|x| x.severity == severity).count();
+        Counts {
+            errors: count(Severity::Error),
+            warnings: count(Severity::Warning),
+            info: count(Severity::Info) + count(Severity::Pedantic) + count(Severity::Experimental),
+        }
+    }
+
+    /// Whether the findings fail a package with the given policy.
+    pub fn fails(&self, fail_on: LintianFailOn) -> bool {
+        match fail_on {
+            LintianFailOn::Error => self.errors != 0,
+            LintianFailOn::Warning => self.errors + self.warnings != 0,
+            LintianFailOn::None => false,
+        }
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+        write!(
+            f,
+            "{} error{}, {} warning{}",
+            self.errors,
+            plural(self.errors),
+            self.warnings,
+            plural(self.warnings)
+        )?;
+
+        if self.info != 0 {
+            write!(f, ", {} info", self.info)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs lintian on the `.changes` files of a build. Lintian exits with an error when it finds
+/// errors, so only its output decides whether the package passed.
+pub fn check(config: Option<&LintianConfig>, files: &[PathBuf]) -> io::Result<Vec<Finding>> {
+    let mut command = command::heavy("lintian");
+    if let Some(config) = config {
+        if let Some(ref profile) = config.profile {
+            command.args(&["--profile", profile]);
+        }
+
+        if let Some(ref tags) = config.suppress_tags {
+            if !tags.is_empty() {
+                command.args(&["--suppress-tags", &tags.join(",")]);
+            }
+        }
+    }
+
+    let output = command.args(files).output()?;
+    let findings = parse(&String::from_utf8_lossy(&output.stdout));
+    if findings.is_empty() && !output.status.success() && !output.stderr.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            String::from_utf8_lossy(&output.stderr).trim().to_owned()
+        ));
+    }
+
+    Ok(findings)
+}
+
+/// Parses lines such as `W: pop-shop source: ancient-standards-version 3.9.8`. Notes and other
+/// lines without a tag are skipped.
+pub fn parse(output: &str) -> Vec<Finding> {
+    output.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ": ");
+            let severity = Severity::from_code(fields.next()?)?;
+            let package = fields.next()?;
+            let mut rest = fields.next()?.splitn(2, ' ');
+            let tag = rest.next()?;
+            Some(Finding {
+                severity,
+                package: package.to_owned(),
+                tag: tag.to_owned(),
+                info: rest.next().unwrap_or("").to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = include_str!("fixtures/lintian.txt");
+
+    #[test]
+    fn findings_are_parsed() {
+        let findings = parse(OUTPUT);
+        assert_eq!(findings.len(), 8);
+        assert_eq!(findings[1], Finding {
+            severity: Severity::Error,
+            package: "pop-shop".into(),
+            tag: "embedded-javascript-library".into(),
+            info: "usr/share/pop-shop/jquery.js please use libjs-jquery".into(),
+        });
+        assert_eq!(findings[3].package, "pop-shop source");
+        assert_eq!(
+            findings[3].to_string(),
+            "pop-shop source: ancient-standards-version 3.9.8 (released 2016-04-06) \
+             (current is 4.6.2)"
+        );
+        assert_eq!(findings[7].severity, Severity::Overridden);
+    }
+
+    #[test]
+    fn counts_gate_on_severity() {
+        let counts = Counts::of(&parse(OUTPUT));
+        assert_eq!(counts, Counts { errors: 2, warnings: 2, info: 3 });
+        assert_eq!(counts.to_string(), "2 errors, 2 warnings, 3 info");
+        assert!(counts.fails(LintianFailOn::Error));
+        assert!(!counts.fails(LintianFailOn::None));
+
+        let counts = Counts::of(&parse("W: pop-shop: binary-without-manpage [usr/bin/pop-shop]\n"));
+        assert_eq!(counts.to_string(), "0 errors, 1 warning");
+        assert!(!counts.fails(LintianFailOn::Error));
+        assert!(counts.fails(LintianFailOn::Warning));
+    }
+}
@@ -0,0 +1,231 @@
+//! Rewrites the `Maintainer` of the sources which are rebuilt from upstream to the team which
+//! builds them, as Ubuntu's `update-maintainer` does, so that their packages do not look as if
+//! the upstream maintainers shipped them. The replaced maintainer is kept in
+//! `XSBC-Original-Maintainer`, unless the source already has one.
+//!
+//! Only the source paragraph of `debian/control` is changed, and the rest of the file is kept as
+//! it was, including comments and folded fields.
+
+use std::io;
+use std::iter;
+use std::path::Path;
+
+use misc;
+
+/// The field which the replaced maintainer is kept in, by the convention of Ubuntu.
+const ORIGINAL: &str = "XSBC-Original-Maintainer";
+
+/// A field of a paragraph, with the lines which it is folded over. Comments have no name.
+struct Field<'a> {
+    name: &'a str,
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Field<'a> {
+    fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// The value of the field, unfolded.
+    fn value(&self) -> String {
+        let first = &self.lines[0][self.name.len() + 1..];
+        let values: Vec<&str> = iter::once(first)
+            .chain(self.lines[1..].iter().cloned())
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect();
+
+        values.join(" ")
+    }
+}
+
+/// Rewrites the maintainer of the `debian/control` of the build tree, unless it already is the
+/// maintainer, returning the maintainer which it replaced.
+pub fn rewrite(dir: &Path, maintainer: &str) -> io::Result<Option<String>> {
+    let path = dir.join("debian/control");
+    let control = misc::read_to_string(&path)?;
+    let rewritten = rewrite_control(&control, maintainer)
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+    match rewritten {
+        Some((control, original)) => misc::write(&path, control).map(|()| Some(original)),
+        None => Ok(None),
+    }
+}
+
+/// The control file with the maintainer of its source paragraph rewritten, and the maintainer
+/// which it replaced, or `None` if it already is the maintainer. The control file which results
+/// must parse.
+fn rewrite_control(control: &str, maintainer: &str) -> Result<Option<(String, String)>, String> {
+    let lines: Vec<&str> = control.lines().collect();
+    let start = lines.iter()
+        .position(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| "debian/control has no source paragraph".to_owned())?;
+    let end = lines[start..].iter()
+        .position(|line| line.trim().is_empty())
+        .map_or(lines.len(), |length| start + length);
+
+    let source = parse(&lines[start..end])?;
+    let current = source.iter()
+        .find(|field| field.is("Maintainer"))
+        .map(|field| field.value())
+        .ok_or_else(|| "the source paragraph of debian/control has no Maintainer".to_owned())?;
+
+    if normalize(&current) == normalize(maintainer) {
+        return Ok(None);
+    }
+
+    let has_original = source.iter().any(|field| field.is(ORIGINAL));
+    let mut output: Vec<String> = lines[..start].iter().map(|x| x.to_string()).collect();
+    for field in &source {
+        if field.is("Maintainer") {
+            output.push(["Maintainer: ", maintainer].concat());
+            if !has_original {
+                output.push([ORIGINAL, ": ", &current].concat());
+            }
+        } else {
+            output.extend(field.lines.iter().map(|x| x.to_string()));
+        }
+    }
+
+    output.extend(lines[end..].iter().map(|x| x.to_string()));
+    let mut rewritten = output.join("\n");
+    if control.ends_with('\n') {
+        rewritten.push('\n');
+    }
+
+    validate(&rewritten)?;
+    Ok(Some((rewritten, current)))
+}
+
+/// Checks that each paragraph of the control file is made of fields, that its source paragraph
+/// has a single `Source` and `Maintainer`, and that each paragraph after it is a binary package.
+fn validate(control: &str) -> Result<(), String> {
+    let lines: Vec<&str> = control.lines().collect();
+    let paragraphs: Vec<&[&str]> = lines.split(|line| line.trim().is_empty())
+        .filter(|paragraph| paragraph.iter().any(|line| !line.starts_with('#')))
+        .collect();
+
+    if paragraphs.len() < 2 {
+        return Err("debian/control has no binary packages".to_owned());
+    }
+
+    for (index, paragraph) in paragraphs.iter().enumerate() {
+        let fields = parse(paragraph)?;
+        let count = |name: &str| fields.iter().filter(|field| field.is(name)).count();
+        if index == 0 && (count("Source") != 1 || count("Maintainer") != 1) {
+            return Err(
+                "the source paragraph of debian/control must have one Source and one Maintainer"
+                    .to_owned()
+            );
+        } else if index != 0 && count("Package") != 1 {
+            return Err(format!(
+                "paragraph {} of debian/control is not a binary package",
+                index + 1
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The fields of a paragraph, in their order.
+fn parse<'a>(paragraph: &[&'a str]) -> Result<Vec<Field<'a>>, String> {
+    let mut fields: Vec<Field<'a>> = Vec::new();
+    for &line in paragraph {
+        if line.starts_with('#') {
+            fields.push(Field { name: "", lines: vec![line] });
+        } else if line.starts_with(' ') || line.starts_with('\t') {
+            match fields.iter_mut().rev().find(|field| !field.name.is_empty()) {
+                Some(field) => field.lines.push(line),
+                None => return Err(format!("`{}` continues no field", line.trim())),
+            }
+        } else {
+            match line.find(':') {
+                Some(colon) if colon != 0 && !line[..colon].contains(char::is_whitespace) => {
+                    fields.push(Field { name: &line[..colon], lines: vec![line] });
+                }
+                _ => return Err(format!("`{}` is not a field", line)),
+            }
+        }
+    }
+
+    Ok(fields)
+}
+
+fn normalize(maintainer: &str) -> String {
+    maintainer.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEAM: &str = "Pop Team <pop@example.com>";
+
+    #[test]
+    fn the_maintainer_of_the_source_paragraph_is_rewritten() {
+        let control = "# Generated from control.in\n\
+                       Source: pop-shop\n\
+                       Section: admin\n\
+                       Maintainer: Jane Doe\n \
+                       <jane@debian.org>\n\
+                       Build-Depends: debhelper (>= 11),\n \
+                       meson\n\
+                       \n\
+                       Package: pop-shop\n\
+                       Architecture: any\n\
+                       Maintainer: Not The Source <x@example.com>\n\
+                       Description: shop\n \
+                       Installs software.\n\
+                       \n\
+                       Package: pop-shop-dev\n\
+                       Architecture: all\n\
+                       Description: headers\n";
+
+        let (rewritten, original) = rewrite_control(control, TEAM).unwrap().unwrap();
+        assert_eq!(original, "Jane Doe <jane@debian.org>");
+        assert_eq!(
+            rewritten,
+            "# Generated from control.in\n\
+             Source: pop-shop\n\
+             Section: admin\n\
+             Maintainer: Pop Team <pop@example.com>\n\
+             XSBC-Original-Maintainer: Jane Doe <jane@debian.org>\n\
+             Build-Depends: debhelper (>= 11),\n \
+             meson\n\
+             \n\
+             Package: pop-shop\n\
+             Architecture: any\n\
+             Maintainer: Not The Source <x@example.com>\n\
+             Description: shop\n \
+             Installs software.\n\
+             \n\
+             Package: pop-shop-dev\n\
+             Architecture: all\n\
+             Description: headers\n"
+        );
+
+        // Rewriting it again changes nothing.
+        assert_eq!(rewrite_control(&rewritten, TEAM).unwrap(), None);
+    }
+
+    #[test]
+    fn original_maintainers_are_kept_and_invalid_files_are_errors() {
+        let control = "Source: foo\nMaintainer: Someone Else <else@example.com>\n\
+                       XSBC-Original-Maintainer: Jane Doe <jane@debian.org>\n\nPackage: foo\n";
+        let (rewritten, _) = rewrite_control(control, TEAM).unwrap().unwrap();
+        assert_eq!(
+            rewritten,
+            "Source: foo\nMaintainer: Pop Team <pop@example.com>\n\
+             XSBC-Original-Maintainer: Jane Doe <jane@debian.org>\n\nPackage: foo\n"
+        );
+
+        assert!(rewrite_control("Source: foo\n\nPackage: foo\n", TEAM).is_err());
+        assert!(rewrite_control("Source: foo\nMaintainer: x\n", TEAM).is_err());
+        assert!(rewrite_control("Source: foo\nMaintainer: x\nnot a field\n\nPackage: foo\n", TEAM)
+            .is_err());
+        let binary = "Source: foo\nMaintainer: x\n\nArchitecture: any\n";
+        assert!(rewrite_control(binary, TEAM).is_err());
+    }
+}
@@ -1,88 +1,1083 @@
 mod artifacts;
+mod assets;
+mod autopkgtest;
+mod dependencies;
+mod environment;
+mod excerpt;
+mod excludes;
 mod extract;
+mod install;
+mod lintian;
+mod maintainer;
+mod network;
+mod piuparts;
+mod progress;
+mod rebuild;
+pub mod record;
+mod reproduce;
 mod rsync;
+mod sandbox;
+mod schedule;
+mod session;
+mod sign;
+mod tee;
+mod vendor;
+mod workspace;
 
 use super::super::SHARED_ASSETS;
-use self::artifacts::{link_artifact, LinkedArtifact, LinkError};
-use super::version::{changelog, git};
-use self::rsync::rsync;
-use config::{Config, DebianPath, Source, SourceLocation};
-use glob::glob;
+use self::artifacts::{LinkedArtifact, LinkError};
+use self::assets::AssetFilter;
+use self::autopkgtest::TestResult;
+use self::environment::Environment;
+use self::dependencies::Changes;
+use self::excerpt::LogExcerpt;
+pub use self::install::{chroot, chroot_of};
+use self::lintian::Counts;
+use self::network::AuditProxy;
+use self::piuparts::Verdict;
+use self::progress::Progress;
+use self::record::{BuildRecord, Record};
+pub use self::reproduce::Reproduction;
+use self::sandbox::{Isolation, Sandbox};
+use self::schedule::Schedule;
+pub use self::schedule::{order, read as queue, Queue, QueuedBuild};
+use self::vendor::VendorError;
+pub use self::vendor::VENDOR_CACHE;
+use self::workspace::Workspace;
+use super::dry_run::Intent;
+pub use self::workspace::{path as workspace_path, WORKSPACES};
+use super::binaries::{self, Binaries, BINARIES};
+use super::checksum::{tree_digest, HashCache, HASHES};
+use super::download::{fetcher, DownloadError};
+use super::download::engine::Engine;
+use super::hooks::{self, HookError};
+use super::junit::{CaseResult, TestCase};
+use super::version::{self, changelog, changelog_date, git};
+use self::rsync::{mirror, overlay};
+use command::{self, Cmd, CommandError, Operation};
+use crossbeam_utils::scoped;
+use config::{
+    Config, DdebHandling, DebianPath, HookStage, LintianFailOn, Signing, Source, SourceLocation,
+    Suites,
+};
+use glob::PatternError;
+use interrupt;
+use logging;
 use misc;
-use super::pool::mv_to_pool;
+use output::{self, format_duration, format_size, Status, Summary};
+use regex::Regex;
+use super::lock;
+use super::logs;
+use super::migrate;
+use super::pool::{self, mv_to_pool, PoolIndex, Sizes};
+use super::provenance;
+use super::run;
+use super::space;
+use super::state::{Build, BuildResult, Built, FileProvenance, Metrics, State};
+use super::stats;
+use super::upload;
 use std::env;
-use std::fs::{self, OpenOptions};
-use std::io::{self, Write};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::process::{exit, Command};
-use subprocess::{Exec, Redirection};
-use walkdir::WalkDir;
-
-pub fn all(config: &Config) {
-    let pwd = env::current_dir().unwrap();
-    if let Some(ref sources) = config.source {
-        for source in sources {
-            if let Err(why) = build(source, &pwd, &config.archive, false) {
-                error!("package '{}' failed to build: {}", source.name, why);
-                exit(1);
+use std::ptr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempdir::TempDir;
+use subprocess::{Exec, ExitStatus, NullFile, Popen, PopenError, Redirection};
+
+/// The environment that each package was last built with, in `record/`.
+const ENVIRONMENTS: &str = "record/.environment";
+
+/// The records of each suite, when suites are defined as tables.
+const SUITE_RECORDS: &str = "record/.suites";
+
+/// How long sbuild may take to clean up after it is asked to stop, before it is killed.
+const SBUILD_STOP_TIMEOUT: u64 = 30;
+
+/// Options which change how packages are built.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BuildOptions {
+    /// Stream the output of sbuild to the terminal, in addition to writing it to the log.
+    pub follow: bool,
+    /// Exit with an error if any warnings were logged.
+    pub deny_warnings: bool,
+    /// Write a single line as each package starts and finishes, and nothing interactive.
+    pub ci: bool,
+    /// Do not sign packages, even if signing is configured.
+    pub no_sign: bool,
+    /// Publish the dists without signing their `Release` files, for repos which are only used
+    /// locally.
+    pub unsigned: bool,
+    /// Allow network access during builds, but log which hosts each package connected to.
+    pub detect_network: bool,
+    /// Fail instead of leaving pool files which do not match their checksums out of the index.
+    pub strict: bool,
+    /// Replace upstream tarballs in the pool whose contents differ from the new ones.
+    pub replace_orig: bool,
+    /// Replace other packages in the pool whose contents differ from the new ones of the same
+    /// name, such as when a version is built again.
+    pub replace_published: bool,
+    /// Fail instead of accessing the network, with the package which needed it, so that builds
+    /// only use what was fetched beforehand.
+    pub offline: bool,
+    /// Build and publish what was fetched when packages fail to download, instead of stopping
+    /// after the download phase.
+    pub keep_going: bool,
+    /// Stop starting builds once a package failed to build, instead of building the rest of the
+    /// batch, and publish nothing.
+    pub fail_fast: bool,
+    /// Hash every file again, instead of trusting the digests which were cached.
+    pub no_cache: bool,
+    /// Only warn when there may not be enough disk space for downloads and builds, instead of
+    /// refusing to start them.
+    pub ignore_space_check: bool,
+    /// Remove, clean, and migrate packages in a frozen suite.
+    pub override_freeze: bool,
+    /// Also write the record files of earlier versions of debrep, for scripts which read them.
+    pub legacy_records: bool,
+    /// Build the sources at this priority, instead of those of the config.
+    pub priority: Option<i32>,
+    /// Leave the uploads of built packages to their targets queued for a later run.
+    pub no_upload: bool,
+}
+
+/// Whether a package was built, or skipped because it had already been built.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// The version is only known for sources with a `build_on` rule. `signed` is the fingerprint
+    /// of the key that the package was signed with, and `lintian`, `piuparts`, and `tests` are
+    /// only set if the package was checked with lintian or piuparts, or tested with autopkgtest.
+    /// `sizes` are those of the packages which were placed in the pool, and `dependencies` how
+    /// the build dependencies changed since the last build, if they did.
+    Built {
+        version: Option<String>,
+        log: PathBuf,
+        signed: Option<String>,
+        lintian: Option<Counts>,
+        piuparts: Option<Verdict>,
+        tests: Option<TestResult>,
+        sizes: Sizes,
+        dependencies: Option<Changes>,
+    },
+    Skipped { version: String },
+}
+
+/// The packages which failed to build in a batch, and the summary of the batch.
+#[derive(Debug)]
+pub struct Failed {
+    pub failures: BuildFailures,
+    pub summary: Summary,
+}
+
+impl Failed {
+    fn new(package: &str, why: BuildError, summary: Summary) -> Failed {
+        Failed { failures: BuildFailures(vec![(package.to_owned(), why)]), summary }
+    }
+}
+
+/// Each package which failed to build, with why, in the order that they failed.
+#[derive(Debug, Default)]
+pub struct BuildFailures(pub Vec<(String, BuildError)>);
+
+impl BuildFailures {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for BuildFailures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.len() == 1 {
+            let (ref package, ref why) = self.0[0];
+            return write!(f, "package '{}' failed to build: {}", package, why);
+        }
+
+        write!(f, "{} packages failed to build:", self.0.len())?;
+        for &(ref package, ref why) in &self.0 {
+            write!(f, "\n  {}: {}", package, why)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether packages are built even if their records show that they were already built.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Force<'a> {
+    No,
+    Yes,
+    /// Rebuilds the packages without changes, and the reason for it.
+    Rebuild(Option<&'a str>),
+}
+
+pub fn all(
+    config: &Config,
+    pool: &mut PoolIndex,
+    engine: &Engine,
+    options: BuildOptions,
+) -> Result<Summary, Failed> {
+    build_units(config, pool, engine, &config.build_units(), Force::No, options)
+}
+
+/// Builds the named sources. Naming a source builds each of its variants, and a single variant
+/// may be named as `<name>@<variant>`.
+pub fn packages(
+    config: &Config,
+    pool: &mut PoolIndex,
+    engine: &Engine,
+    packages: &[&str],
+    force: bool,
+    options: BuildOptions,
+) -> Result<Summary, Failed> {
+    if config.source.is_none() {
+        warn!("no packages built");
+        return Ok(Summary::default());
+    }
+
+    let force = if force { Force::Yes } else { Force::No };
+    build_units(config, pool, engine, &named(config, packages), force, options)
+}
+
+/// The build units of the named sources, where naming a source names each of its variants.
+pub fn named(config: &Config, packages: &[&str]) -> Vec<Source> {
+    config.build_units()
+        .into_iter()
+        .filter(|item| {
+            packages.contains(&item.name.as_str()) || packages.contains(&item.base_name())
+        })
+        .collect()
+}
+
+/// Rebuilds the named sources without changes, or every source if none are named.
+pub fn rebuild(
+    config: &Config,
+    pool: &mut PoolIndex,
+    engine: &Engine,
+    packages: &[&str],
+    reason: Option<&str>,
+    options: BuildOptions,
+) -> Result<Summary, Failed> {
+    let items = if packages.is_empty() { config.build_units() } else { named(config, packages) };
+    build_units(config, pool, engine, &items, Force::Rebuild(reason), options)
+}
+
+/// Builds the named sources again from what was fetched, without placing or recording what they
+/// built, and compares their binary packages with those of the pool. What differs is reported by
+/// diffoscope, when it is installed.
+pub fn reproduce(
+    config: &Config,
+    pool: &PoolIndex,
+    engine: &Engine,
+    packages: &[&str],
+    options: BuildOptions,
+) -> Result<Vec<Reproduction>, Failed> {
+    let failed = |package: &str, why| Failed::new(package, why, Summary::default());
+    let first = packages.first().map_or("", |package| *package);
+    let pwd = env::current_dir()
+        .map_err(|why| failed(first, BuildError::WorkingDirectory { why }))?;
+    let host = misc::host_architecture().map_err(|why| {
+        failed(first, BuildError::Environment { package: first.to_owned(), why })
+    })?;
+
+    // Signing only changes the .changes files, which are not compared.
+    let options = BuildOptions { no_sign: true, ..options };
+    let items = named(config, packages);
+    for &package in packages {
+        if !items.iter().any(|item| item.name == package || item.base_name() == package) {
+            warn!("{} is not a source of {}, so it was not reproduced", package, config.archive);
+        }
+    }
+
+    let mut reproductions = Vec::new();
+    for item in &items {
+        let staged = stage(config, pool, engine, item, &pwd, &host, Force::Yes, options)
+            .map_err(|why| failed(&item.name, why))?;
+        let outgoing = staged.workspace.outgoing();
+        if config.ddebs() == DdebHandling::Discard {
+            pool::discard_debug_symbols(&outgoing).map_err(|why| {
+                failed(&item.name, BuildError::Pool { package: item.name.clone(), why })
+            })?;
+        }
+
+        let files = reproduce::compare(pool, &outgoing).map_err(|why| {
+            failed(&item.name, BuildError::Read { file: outgoing.clone(), why })
+        })?;
+
+        let mut reproduction = Reproduction { package: item.name.clone(), files, report: None };
+        if !reproduction.is_reproducible() && command::on_path("diffoscope") {
+            let files = &reproduction.files;
+            let explained = logs::create_step(&pwd, &item.name, "reproduce")
+                .and_then(|(log, file)| reproduce::explain(files, &file).map(|()| log));
+            match explained {
+                Ok(log) => reproduction.report = Some(log),
+                Err(why) => warn!("failed to compare the packages of {} with diffoscope: {}",
+                    item.name, why),
+            }
+        }
+
+        reproductions.push(reproduction);
+    }
+
+    Ok(reproductions)
+}
+
+/// What building the sources would do, in the order that they would be built in, from what is
+/// cheap to find out: whether this system builds them, whether they were fetched, and whether
+/// their records show that the changelog version, or the commit, of their checkouts was built
+/// for each of their architectures. Nothing is written, not even to their workspaces.
+pub fn dry_run(
+    config: &Config,
+    pwd: &Path,
+    host: &str,
+    items: &[Source],
+    force: Force,
+) -> Vec<Intent> {
+    let architectures = config.effective_suite(&config.archive).architectures;
+    let schedule = Schedule::new(items, None);
+    let mut intents = Vec::new();
+    for (item, _) in schedule.order() {
+        let name = item.name.clone();
+        intents.push(match unbuildable(item, &architectures, host, &config.archive) {
+            Some(why) => Intent::Skip { name, why },
+            None => match force {
+                Force::Yes => {
+                    Intent::Build { name, why: "it is built whether or not it was before".into() }
+                }
+                Force::Rebuild(_) => {
+                    Intent::Build { name, why: "it is rebuilt without changes".into() }
+                }
+                Force::No => intend(config, pwd, host, item),
+            },
+        });
+    }
+
+    intents
+}
+
+/// Whether the record of the source shows that what its checkout is at was built. Sources whose
+/// tree must be prepared to tell, such as those built on its checksum, may be built.
+fn intend(config: &Config, pwd: &Path, host: &str, item: &Source) -> Intent {
+    let name = item.name.clone();
+    let checkout = match item.location {
+        Some(SourceLocation::Path { ref path }) => Some(pwd.join(path)),
+        _ => fetcher::of(item).map(|fetcher| pwd.join(fetcher.resolve())),
+    };
+
+    // Archives are only extracted into the trees of builds.
+    let tree = match checkout {
+        Some(ref checkout) if !checkout.exists() => {
+            return Intent::Build { name, why: "it is built once it is fetched".into() };
+        }
+        Some(ref checkout) if checkout.is_dir() => Some(checkout.as_path()),
+        _ => None,
+    };
+
+    let unknown = |why: &str| Intent::Check { name: item.name.clone(), why: why.to_owned() };
+    let recorded = State::open(pwd).and_then(|state| state.record(record_scope(config), &name));
+    let recorded = match recorded {
+        Ok(contents) => contents.and_then(|contents| record::load(&contents, &name)),
+        Err(why) => return unknown(&format!("its record cannot be read: {}", why)),
+    };
+
+    let (built, what) = match build_on(item) {
+        None => return Intent::Build { name, why: "it has no build_on rule".into() },
+        Some("changelog") => {
+            let overlay = unit_path(pwd, "debian", item).join("changelog");
+            let path = match (item.debian.as_ref(), tree) {
+                (None, _) if overlay.exists() => overlay,
+                (None, Some(tree)) => tree.join("debian/changelog"),
+                _ => return unknown("its changelog is only read once its tree is prepared"),
+            };
+
+            let mut version = match changelog(&path, 1).map(|x| x.into_iter().next()) {
+                Ok(Some(version)) => version,
+                Ok(None) => return unknown("its changelog has no version"),
+                Err(why) => return unknown(&format!("its changelog cannot be read: {}", why)),
+            };
+
+            if let Some(ref suffix) = item.version_suffix {
+                if !version.ends_with(suffix.as_str()) {
+                    version.push_str(suffix);
+                }
+            }
+
+            let built = match recorded.as_ref().map(|x| &x.record) {
+                Some(&Record::Changelog { version: ref recorded, .. }) => *recorded == version,
+                _ => false,
+            };
+
+            (built, ["version ", &version].concat())
+        }
+        Some("commit") => {
+            let (branch, commit) = match tree.map(git) {
+                Some(Ok(head)) => head,
+                Some(Err(why)) => return unknown(&format!("its commit cannot be read: {}", why)),
+                None => return unknown("it is not a git checkout"),
+            };
+
+            let what = ["commit ", &commit_version(&branch, &commit)].concat();
+            let built = recorded.as_ref()
+                .map_or(false, |x| x.record.has_latest_commit(&branch, &commit));
+            let older = recorded.as_ref().map_or(false, |x| x.record.has_commit(&branch, &commit));
+            if !built && older {
+                return Intent::Skip { name, why: ["already built at ", &what].concat() };
             }
+
+            (built, what)
         }
+        Some("checksum") => return unknown("its checksum is only known once its tree is prepared"),
+        Some(rule) => return unknown(&format!("its build_on rule {} is unknown", rule)),
+    };
+
+    let entry = match recorded {
+        Some(entry) if built => entry,
+        _ => return Intent::Build { name, why: [&what, " was not built"].concat() },
+    };
+
+    let suite = config.effective_suite(&config.archive);
+    let missing = entry.missing(&item.build_architectures(&suite.architectures, host), host);
+    if missing.is_empty() {
+        Intent::Skip { name, why: ["already built at ", &what].concat() }
+    } else {
+        let why = format!("{} was built, but not for {}", what, missing.join(" "));
+        Intent::Build { name, why }
     }
 }
 
-pub fn packages(config: &Config, packages: &[&str], force: bool) {
-    let pwd = env::current_dir().unwrap();
-    let mut built = 0;
-    match config.source.as_ref() {
-        Some(items) => {
-            for item in items.into_iter().filter(|item| packages.contains(&item.name.as_str())) {
-                if let Err(why) = build(item, &pwd, &config.archive, force) {
-                    error!("package '{}' failed to build: {}", item.name, why);
-                    exit(1);
+fn build_units(
+    config: &Config,
+    pool: &mut PoolIndex,
+    engine: &Engine,
+    items: &[Source],
+    force: Force,
+    options: BuildOptions,
+) -> Result<Summary, Failed> {
+    let mut summary = Summary::default();
+    if items.is_empty() {
+        return Ok(summary);
+    }
+
+    let pwd = match env::current_dir() {
+        Ok(pwd) => pwd,
+        Err(why) => {
+            let why = BuildError::WorkingDirectory { why };
+            error!("no packages were built: {}", why);
+            return Err(Failed::new(&items[0].name, why, summary));
+        }
+    };
+
+    let host = match misc::host_architecture() {
+        Ok(host) => host,
+        Err(why) => {
+            let why = BuildError::Environment { package: items[0].name.clone(), why };
+            error!("no packages were built: {}", why);
+            return Err(Failed::new(&items[0].name, why, summary));
+        }
+    };
+
+    let sources: Vec<&Source> = items.iter().collect();
+    let what = format!("build the {} sources of {}", items.len(), config.archive);
+    if let Err(why) = check_space(config, &sources, &pwd, &host, options, what) {
+        error!("no packages were built: {}", why);
+        return Err(Failed::new(&items[0].name, why, summary));
+    }
+
+    let architectures = config.effective_suite(&config.archive).architectures;
+    let mut schedule = Schedule::new(items, options.priority);
+    let order = schedule.order().into_iter().map(|(item, _)| item.name.clone()).collect();
+    let progress = Progress::new(order, options);
+    let _sessions = session::Guard;
+    let _queue = QueueGuard { urgent: lock::is_urgent() };
+
+    // Up to `build_jobs` sources are built at once, on threads of their own. Their packages are
+    // moved into the pool on this thread, one build at a time, as each of them finishes.
+    let jobs = config.build_jobs();
+    let (sender, receiver) = mpsc::channel();
+    let mut building: Vec<(&Source, i32)> = Vec::new();
+    let mut failures = Vec::new();
+    let mut stopped = false;
+    let (shared_pwd, shared_host, shared_progress) = (&pwd, &host, &progress);
+    scoped::scope(|scope| loop {
+        while !stopped && building.len() < jobs && !schedule.is_empty() {
+            // Urgent builds which asked to preempt the batch are built before the rest of it,
+            // once the builds which were started finish.
+            if lock::preempted(&pwd) {
+                if !building.is_empty() {
+                    break;
                 }
 
-                built += 1;
-                if built == packages.len() {
-                    break
+                info!("pausing the builds of {} for urgent builds", config.archive);
+                write_queue(&Queue::new(&config.archive, &[], &schedule.order(), true));
+                let resumed = lock::pause_for_urgent(&pwd).and_then(|_| pool.reopen());
+                if let Err(why) = resumed {
+                    let package = config.archive.clone();
+                    failures.push((package.clone(), BuildError::Pause { package, why }));
+                    stopped = true;
+                    break;
                 }
+
+                info!("resuming the builds of {}", config.archive);
             }
-        },
-        None => warn!("no packages built")
+
+            let (item, priority) = match schedule.next() {
+                Some(next) => next,
+                None => break,
+            };
+
+            if interrupt::is_interrupted() {
+                let why = BuildError::Interrupted { package: item.name.clone() };
+                failures.push((item.name.clone(), why));
+                stopped = true;
+                break;
+            }
+
+            if let Some(reason) = unbuildable(item, &architectures, &host, &config.archive) {
+                info!("{} was not built: {}", item.name, reason);
+                progress.skip(&item.name);
+                add_case(config, item, &host, Instant::now(), CaseResult::Skipped(reason.clone()));
+                summary.add(Status::Skipped, &item.name, reason);
+                schedule.finish(item);
+                continue;
+            }
+
+            // Sources whose input failed to download, or was never fetched, cannot be built.
+            if let Some(fetcher) = fetcher::of(item) {
+                let path = fetcher.resolve();
+                if !pwd.join(&path).exists() {
+                    warn!("{} was not built, because {} is missing", item.name, path.display());
+                    progress.skip(&item.name);
+                    let detail = ["not fetched from ", &fetcher.origin()].concat();
+                    let case = CaseResult::Skipped(detail.clone());
+                    add_case(config, item, &host, Instant::now(), case);
+                    summary.add(Status::Skipped, &item.name, detail);
+                    schedule.finish(item);
+                    continue;
+                }
+            }
+
+            // What other builds left behind may have used up the space of this build.
+            let what = ["build ", &item.name].concat();
+            if let Err(why) = check_space(config, &[item], &pwd, &host, options, what) {
+                failures.push((item.name.clone(), why));
+                stopped = true;
+                break;
+            }
+
+            building.push((item, priority));
+            write_queue(&Queue::new(&config.archive, &building, &schedule.order(), false));
+
+            let snapshot = pool.clone();
+            let sender = sender.clone();
+            scope.spawn(move || {
+                let started = Instant::now();
+                let staged = panic::catch_unwind(AssertUnwindSafe(|| {
+                    begin(
+                        config,
+                        &snapshot,
+                        engine,
+                        item,
+                        shared_pwd,
+                        shared_host,
+                        force,
+                        options,
+                        shared_progress,
+                    )
+                }));
+                let _ = sender.send((item, started, staged));
+            });
+        }
+
+        if building.is_empty() {
+            break;
+        }
+
+        let (item, started, staged) = receiver.recv().expect("the builds hold a sender");
+        building.retain(|&(other, _)| !ptr::eq(other, item));
+        let staged = staged.unwrap_or_else(|payload| panic::resume_unwind(payload));
+        let result = conclude(
+            config,
+            pool,
+            item,
+            &pwd,
+            &host,
+            options,
+            &progress,
+            &mut summary,
+            started,
+            staged,
+        );
+
+        // Each failure is in the summary. The sources which need the package are skipped, and
+        // the rest of the batch is built, unless the first failure ends the batch once the
+        // builds which are running finish.
+        match result {
+            Ok(()) => schedule.finish(item),
+            Err(why) => {
+                error!("package '{}' failed to build: {}", item.name, why);
+                for dependent in schedule.fail(item) {
+                    let detail = format!("needs {}, which failed to build", item.name);
+                    progress.skip(&dependent.name);
+                    let case = CaseResult::Skipped(detail.clone());
+                    add_case(config, dependent, &host, Instant::now(), case);
+                    summary.add(Status::Skipped, &dependent.name, detail);
+                }
+
+                failures.push((item.name.clone(), why));
+                stopped |= options.fail_fast;
+            }
+        }
+    });
+
+    progress.finish();
+    if !failures.is_empty() {
+        finish_run(&summary);
+        return Err(Failed { failures: BuildFailures(failures), summary });
+    }
+
+    if config.is_frozen() {
+        let staging = pool::staging_root(&config.archive);
+        let detail = format!("not published; staged in {} until it is released", staging.display());
+        summary.add(Status::Frozen, &config.archive, detail);
+    }
+
+    finish_run(&summary);
+    Ok(summary)
+}
+
+/// Checks that the filesystem of `build/` has the space which building the sources needs.
+fn check_space(
+    config: &Config,
+    items: &[&Source],
+    pwd: &Path,
+    host: &str,
+    options: BuildOptions,
+    what: String,
+) -> Result<(), BuildError> {
+    let multiplier = config.build_multiplier();
+    let needed = items.iter()
+        .map(|item| {
+            let workspace = Workspace::new(pwd, &config.archive, host, &item.name);
+            space::build(item, pwd, &workspace.tree(), multiplier)
+        })
+        .sum();
+    let headroom = config.space_headroom();
+    space::require(&[(pwd.join("build"), needed)], headroom, options.ignore_space_check, &what)
+        .map_err(|shortfalls| BuildError::Space { what, shortfalls })
+}
+
+/// Why the source is not built in a suite of the architectures on a system of the `host`
+/// architecture, if it is not. Sources which do not give their architectures are only built for
+/// that of this system, and `all`.
+fn unbuildable(item: &Source, architectures: &[String], host: &str, suite: &str) -> Option<String> {
+    let matrix = item.architectures_in(architectures);
+    if !item.build_architectures(architectures, host).is_empty() {
+        None
+    } else if matrix.is_empty() {
+        Some(["none of its architectures are built for ", suite].concat())
+    } else {
+        let matrix = matrix.join(" ");
+        Some(format!("built for {} in {}, but this system builds {}", matrix, suite, host))
+    }
+}
+
+/// Starts the build of a package, reporting its progress, and builds it in its workspace.
+fn begin<'a>(
+    config: &Config,
+    pool: &PoolIndex,
+    engine: &Engine,
+    item: &Source,
+    pwd: &Path,
+    host: &str,
+    force: Force<'a>,
+    options: BuildOptions,
+    progress: &Progress,
+) -> Result<Staged<'a>, BuildError> {
+    progress.start(&item.name);
+    if options.ci {
+        output::status(Status::Building, &item.name, "");
+    }
+
+    let vars = [("DEBREP_PACKAGE", item.name.as_str())];
+    hooks::run(config, HookStage::PreBuild, &vars)
+        .map_err(|why| BuildError::Hook { package: item.name.clone(), why })
+        .and_then(|()| stage(config, pool, engine, item, pwd, host, force, options))
+}
+
+/// Moves what the build of a package, which started at `started`, staged into the pool, and
+/// reports its outcome.
+fn conclude(
+    config: &Config,
+    pool: &mut PoolIndex,
+    item: &Source,
+    pwd: &Path,
+    host: &str,
+    options: BuildOptions,
+    progress: &Progress,
+    summary: &mut Summary,
+    started: Instant,
+    staged: Result<Staged, BuildError>,
+) -> Result<(), BuildError> {
+    let result = staged.and_then(|staged| place(config, pool, item, pwd, host, options, staged));
+    let built = match result {
+        Ok(Outcome::Built { .. }) => true,
+        _ => false,
+    };
+    progress.complete(&item.name, built);
+    let hooked = post_build(config, item, pwd, &result);
+
+    let case = match result {
+        Ok(Outcome::Built { .. }) => CaseResult::Passed,
+        Ok(Outcome::Skipped { ref version }) => {
+            CaseResult::Skipped(["already built at ", version].concat())
+        }
+        Err(BuildError::Interrupted { .. }) => CaseResult::Interrupted,
+        Err(ref why) => {
+            let text = match *why {
+                BuildError::Build { ref excerpt, .. } if !excerpt.is_empty() => excerpt.to_string(),
+                _ => why.to_string(),
+            };
+            CaseResult::Failed { message: why.to_string(), text }
+        }
+    };
+    add_case(config, item, host, started, case);
+
+    let elapsed = format_duration(started.elapsed().as_secs());
+    match result {
+        Ok(Outcome::Built {
+            version,
+            log,
+            signed,
+            lintian,
+            piuparts,
+            tests,
+            sizes,
+            dependencies,
+        }) => {
+            let mut detail = Vec::new();
+            detail.extend(version);
+            detail.push(elapsed);
+            if sizes.packages != 0 {
+                detail.push(format_size(sizes.packages));
+            }
+            if sizes.debug != 0 {
+                detail.push([&format_size(sizes.debug), " of debug symbols"].concat());
+            }
+            summary.add_sizes(sizes.packages, sizes.debug);
+            detail.push(log.strip_prefix(pwd).unwrap_or(&log).display().to_string());
+            detail.extend(signed.map(|fingerprint| ["signed by ", &fingerprint].concat()));
+            detail.extend(lintian.map(|counts| format!("lintian: {}", counts)));
+            detail.extend(piuparts.map(|verdict| verdict.to_string()));
+            detail.extend(tests.map(|result| result.to_string()));
+            detail.extend(dependencies.map(|changes| ["build-deps: ", &changes.counts()].concat()));
+            summary.add(Status::Built, &item.name, detail.join(", "));
+        }
+        Ok(Outcome::Skipped { version }) => summary.add(Status::Skipped, &item.name, version),
+        Err(why) => {
+            progress.finish();
+            let detail = match why {
+                BuildError::Build { ref log, ref excerpt, .. } => {
+                    let log = log.strip_prefix(pwd).unwrap_or(log);
+                    let mut detail = [&elapsed, ", ", &log.display().to_string()].concat();
+                    if let Some(ref changes) = excerpt.dependencies {
+                        detail.push_str(&[", build-deps: ", &changes.counts()].concat());
+                    }
+                    detail
+                }
+                BuildError::InstallFailed { ref log, .. } => {
+                    let log = log.strip_prefix(pwd).unwrap_or(log);
+                    let log = log.display().to_string();
+                    ["build ok, install failed, ", &elapsed, ", ", &log].concat()
+                }
+                BuildError::PiupartsFailed { ref log, .. } => {
+                    let log = log.strip_prefix(pwd).unwrap_or(log);
+                    let log = log.display().to_string();
+                    ["build ok, piuparts failed, ", &elapsed, ", ", &log].concat()
+                }
+                BuildError::Tests { ref log, .. } => {
+                    let log = log.strip_prefix(pwd).unwrap_or(log);
+                    let log = log.display().to_string();
+                    ["build ok, tests failed, ", &elapsed, ", ", &log].concat()
+                }
+                _ => elapsed,
+            };
+
+            let (status, result) = match why {
+                BuildError::Interrupted { .. } => (Status::Interrupted, BuildResult::Interrupted),
+                _ => (Status::Failed, BuildResult::Failed),
+            };
+            summary.add(status, &item.name, detail);
+
+            // Failures are recorded so that `--failed` selects the package.
+            let log = failure_log(&why).map(|log| log.strip_prefix(pwd).unwrap_or(log));
+            let build = finished(config, item, host, started, result, None, log);
+            if let Err(why) = State::open(pwd).and_then(|mut state| state.add_build(&build, None)) {
+                warn!("failed to record the failed build of {}: {}", item.name, why);
+            }
+
+            return Err(why);
+        }
+    }
+
+    hooked
+}
+
+/// Adds the outcome of the package, whose build started at `started`, to the current run, for
+/// `--report-junit`.
+fn add_case(config: &Config, item: &Source, host: &str, started: Instant, result: CaseResult) {
+    if let Some(run) = run::current() {
+        let elapsed = started.elapsed();
+        run.add_case(TestCase {
+            suite: config.archive.clone(),
+            package: item.name.clone(),
+            architecture: host.to_owned(),
+            seconds: elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9,
+            result,
+        });
+    }
+}
+
+/// Runs the `post_build` hooks of the package, with its version, the result of its build, and
+/// its log. The hooks of a package whose build failed cannot fail it again.
+fn post_build(
+    config: &Config,
+    item: &Source,
+    pwd: &Path,
+    result: &Result<Outcome, BuildError>,
+) -> Result<(), BuildError> {
+    let (version, status, log) = match *result {
+        Ok(Outcome::Built { ref version, ref log, .. }) => {
+            (version.as_ref().map(|x| x.as_str()), "built", Some(log.as_path()))
+        }
+        Ok(Outcome::Skipped { ref version }) => (Some(version.as_str()), "skipped", None),
+        Err(BuildError::Interrupted { .. }) => (None, "interrupted", None),
+        Err(ref why) => (None, "failed", failure_log(why)),
+    };
+
+    let log = log.map_or(String::new(), |log| pwd.join(log).display().to_string());
+    let vars = [
+        ("DEBREP_PACKAGE", item.name.as_str()),
+        ("DEBREP_VERSION", version.unwrap_or("")),
+        ("DEBREP_STATUS", status),
+        ("DEBREP_LOG", log.as_str()),
+    ];
+
+    match hooks::run(config, HookStage::PostBuild, &vars) {
+        Err(why) if result.is_ok() => Err(BuildError::Hook { package: item.name.clone(), why }),
+        Err(why) => {
+            warn!("{}", why);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+/// The log of the build which failed, if it got as far as writing one.
+fn failure_log(why: &BuildError) -> Option<&Path> {
+    match *why {
+        BuildError::Build { ref log, .. }
+        | BuildError::InstallFailed { ref log, .. }
+        | BuildError::PiupartsFailed { ref log, .. }
+        | BuildError::Tests { ref log, .. } => Some(log),
+        _ => None,
+    }
+}
+
+/// The build of the item, which started at `started`, and just ended with the result.
+fn finished(
+    config: &Config,
+    item: &Source,
+    host: &str,
+    started: Instant,
+    result: BuildResult,
+    version: Option<String>,
+    log: Option<&Path>,
+) -> Build {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Build {
+        suite: config.archive.clone(),
+        package: item.name.clone(),
+        architecture: host.to_owned(),
+        run: run::current().map(|run| run.id.clone()),
+        finished: now,
+        seconds: started.elapsed().as_secs(),
+        result,
+        version,
+        log: log.map(|log| log.display().to_string()),
+    }
+}
+
+/// Writes the queue of the batch, for `debrep status`.
+fn write_queue(queue: &Queue) {
+    if let Err(why) = queue.write(Path::new(".")) {
+        warn!("failed to write the build queue: {}", why);
+    }
+}
+
+/// Removes the queue of the batch when it stops, however it stops.
+struct QueueGuard {
+    urgent: bool,
+}
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        Queue::clear(Path::new("."), self.urgent);
+    }
+}
+
+/// Prints the summary of the run and the warnings which were logged during it, and writes them
+/// to the run's report.
+fn finish_run(summary: &Summary) {
+    summary.print();
+    let warnings = logging::warnings();
+    let warnings = if warnings.is_empty() { String::new() } else { output::warnings(&warnings) };
+    if !warnings.is_empty() {
+        output::message(&warnings);
+    }
+
+    if let Some(run) = run::current() {
+        let report = if warnings.is_empty() {
+            summary.report()
+        } else {
+            [&summary.report(), "\n\n", &warnings].concat()
+        };
+
+        if let Err(why) = run.write_report(&report) {
+            warn!("failed to write the report of run {}: {}", run.id, why);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let recorded = State::open(Path::new("."))
+            .and_then(|mut state| state.finish_run(&run.id, now, &logging::warnings()));
+        if let Err(why) = recorded {
+            warn!("failed to record the end of run {}: {}", run.id, why);
+        }
     }
 }
 
 #[derive(Debug, Fail)]
 pub enum BuildError {
-    #[fail(display = "build failed for {}", package)]
-    Build { package: String },
+    #[fail(display = "build failed for {}: see {:?}", package, log)]
+    Build { package: String, log: PathBuf, excerpt: LogExcerpt },
     #[fail(display = "failed to get changelog for {}: {}", package, why)]
     Changelog { package: String, why: io::Error },
-    #[fail(display = "{} command failed to execute: {}", cmd, why)]
-    Command { cmd: &'static str, why: io::Error },
+    #[fail(display = "{}", why)]
+    Command { why: CommandError },
+    #[fail(display = "failed to prepare the build environment of {}: {}", package, why)]
+    Environment { package: String, why: io::Error },
+    #[fail(display = "failed to install {} in a schroot session: {}", package, why)]
+    Install { package: String, why: io::Error },
+    #[fail(display = "the build of {} was interrupted", package)]
+    Interrupted { package: String },
+    #[fail(display = "installing {} failed: see {:?}", package, log)]
+    InstallFailed { package: String, log: PathBuf },
+    #[fail(display = "failed to check {} with lintian: {}", package, why)]
+    Lintian { package: String, why: io::Error },
+    #[fail(display = "lintian found {} in {}", counts, package)]
+    LintianFailed { package: String, counts: Counts },
+    #[fail(display = "failed to test {} with autopkgtest: {}", package, why)]
+    Autopkgtest { package: String, why: io::Error },
+    #[fail(display = "tests of {} failed: see {:?}", package, log)]
+    Tests { package: String, log: PathBuf },
+    #[fail(
+        display = "binary package {} of {} is already produced by {}; set `takeover = true` on \
+                   {} if it is moving to it",
+        binary, package, owner, package
+    )]
+    BinaryConflict { package: String, binary: String, owner: String },
     #[fail(display = "unsupported conditional build rule: {}", rule)]
     ConditionalRule { rule: String },
+    #[fail(display = "failed to copy {:?} to {:?}: {}", src, dst, why)]
+    Copy { src: PathBuf, dst: PathBuf, why: io::Error },
     #[fail(display = "failed to create directory for {:?}: {}", path, why)]
     Directory { path: PathBuf, why: io::Error },
     #[fail(display = "failed to extract {:?} to {:?}: {}", src, dst, why)]
     Extract { src: PathBuf, dst: PathBuf, why: io::Error },
     #[fail(display = "failed to switch to branch {} on {}: {}", branch, package, why)]
-    GitBranch { package: String, branch: String, why: io::Error },
+    GitBranch { package: String, branch: String, why: CommandError },
     #[fail(display = "failed to get git commit for {}: {}", package, why)]
     GitCommit { package: String, why: io::Error },
+    #[fail(display = "checksum of asset {:?} is invalid -- expected {}, but found {}", file, expected, actual)]
+    AssetChecksum { file: PathBuf, expected: String, actual: String },
+    #[fail(display = "failed to read asset manifest at {:?}: {}", manifest, why)]
+    AssetManifest { manifest: PathBuf, why: io::Error },
+    #[fail(display = "asset {:?} is not listed in the manifest at {:?}", file, manifest)]
+    AssetUnlisted { file: PathBuf, manifest: PathBuf },
+    #[fail(
+        display = "assets '{}' ({:?}) and '{}' ({:?}) of {} both link to {:?}",
+        first, first_src, second, second_src, package, dst
+    )]
+    AssetCollision {
+        package: String,
+        dst: PathBuf,
+        first: String,
+        first_src: PathBuf,
+        second: String,
+        second_src: PathBuf,
+    },
+    #[fail(display = "invalid asset pattern '{}' for {}: {}", pattern, package, why)]
+    AssetPattern { package: String, pattern: String, why: PatternError },
+    #[fail(display = "asset {:?} could not be resolved; is it a dangling symlink? {}", file, why)]
+    AssetLink { file: PathBuf, why: io::Error },
+    #[fail(
+        display = "checksum of the debian tarball of {} from {} is invalid -- expected {}, but \
+                   received {}",
+        package, url, expected, received
+    )]
+    DebianChecksum { package: String, url: String, expected: String, received: String },
+    #[fail(display = "failed to fetch the debian tarball of {}: {}", package, why)]
+    DebianFetch { package: String, why: DownloadError },
+    #[fail(display = "the debian tarball of {} at {:?} has no debian/control", package, archive)]
+    DebianMissing { package: String, archive: PathBuf },
     #[fail(display = "failed to link {:?} to {:?}: {}", src, dst, why)]
     Link { src: PathBuf, dst: PathBuf, why: io::Error },
     #[fail(display = "no version listed in changelog for {}", package)]
     NoChangelogVersion { package: String },
+    #[fail(display = "failed to rewrite the maintainer of {}: {}", package, why)]
+    Maintainer { package: String, why: io::Error },
+    #[fail(display = "{} needs network access to {}, but the build is offline", package, what)]
+    Offline { package: String, what: &'static str },
     #[fail(display = "failed to open file at {:?}: {}", file, why)]
     Open { file: PathBuf, why: io::Error },
+    #[fail(display = "{}: {}", package, why)]
+    Hook { package: String, why: HookError },
+    #[fail(display = "failed to pause for urgent builds before {}: {}", package, why)]
+    Pause { package: String, why: io::Error },
+    #[fail(display = "failed to check {} with piuparts: {}", package, why)]
+    Piuparts { package: String, why: io::Error },
+    #[fail(display = "piuparts failed for {}: see {:?}", package, log)]
+    PiupartsFailed { package: String, log: PathBuf },
     #[fail(display = "failed to move {} to pool: {}", package, why)]
     Pool { package: String, why: io::Error },
     #[fail(display = "failed to read file at {:?}: {}", file, why)]
     Read { file: PathBuf, why: io::Error },
+    #[fail(display = "cannot rebuild {}: {}", package, why)]
+    Rebuild { package: String, why: String },
     #[fail(display = "failed to update record for {}: {}", package, why)]
     RecordUpdate { package: String, why: io::Error },
-    #[fail(display = "rsyncing {:?} to {:?} failed: {}", src, dst, why)]
-    Rsync { src: PathBuf, dst: PathBuf, why: io::Error },
+    #[fail(display = "failed to sign {:?} for {}: {}", file, package, why)]
+    Sign { package: String, file: PathBuf, why: io::Error },
+    #[fail(display = "signing key {} is not usable: {}", key, why)]
+    SigningKey { key: String, why: io::Error },
+    #[fail(display = "there is not enough disk space to {}:\n  {}", what, shortfalls)]
+    Space { what: String, shortfalls: String },
+    #[fail(
+        display = "version {} of {} does not match the version pattern of {}, `{}`",
+        version, package, suite, pattern
+    )]
+    SuiteVersion { package: String, version: String, suite: String, pattern: String },
+    #[fail(display = "{}", why)]
+    Vendor { why: VendorError },
+    #[fail(display = "failed to read the current working directory: {}", why)]
+    WorkingDirectory { why: io::Error },
 }
 
 impl From<LinkError> for BuildError {
@@ -91,82 +1086,133 @@ impl From<LinkError> for BuildError {
     }
 }
 
-fn fetch_assets(
-    linked: &mut Vec<LinkedArtifact>,
-    src: &Path,
-    dst: &Path,
-) -> Result<(), BuildError> {
-    for entry in WalkDir::new(src).into_iter().flat_map(|e| e.ok()) {
-        let path = entry.path();
-        if path.is_dir() {
-            let relative = path.strip_prefix(src).unwrap();
-            let new_path = dst.join(relative);
-            if !new_path.exists() {
-                fs::create_dir(&new_path)
-                    .map_err(|why| BuildError::Directory { path: new_path, why })?;
-            }
-        } else {
-            let src = path.canonicalize().unwrap();
-            linked.push(link_artifact(&src, dst)?);
-        }
+impl From<VendorError> for BuildError {
+    fn from(why: VendorError) -> BuildError {
+        BuildError::Vendor { why }
     }
+}
 
-    Ok(())
+/// A source which was built and checked in its workspace, whose packages are yet to be moved
+/// into the pool.
+struct Staged<'a> {
+    outcome: Outcome,
+    record: Option<PendingRecord>,
+    workspace: Workspace,
+    /// Why the source was rebuilt without changes, if it was.
+    rebuilt: Option<Option<&'a str>>,
+    started: Instant,
 }
 
-/// Attempts to build Debian packages from a given software repository.
-pub fn build(item: &Source, pwd: &Path, branch: &str, force: bool) -> Result<(), BuildError> {
+/// Builds the source in its workspace, and checks what it built. Builds of other sources may
+/// run alongside, each with its own workspace, and a copy of the pool index as it was when the
+/// build started, which has the packages of the sources which it depends on.
+fn stage<'a>(
+    config: &Config,
+    pool: &PoolIndex,
+    engine: &Engine,
+    item: &Source,
+    pwd: &Path,
+    host: &str,
+    force: Force<'a>,
+    options: BuildOptions,
+) -> Result<Staged<'a>, BuildError> {
+    let started = Instant::now();
+    let branch = &config.archive;
+    let _scope = logging::scope(&item.name, branch);
     info!("attempting to build {}", &item.name);
-    let project_directory = pwd.join(&["build/", &item.name].concat());
-    let _ = fs::create_dir_all(&project_directory);
+    let workspace = Workspace::new(pwd, branch, host, &item.name);
+    workspace.prepare()
+        .map_err(|why| BuildError::Directory { path: workspace.root().to_path_buf(), why })?;
+    let project_directory = workspace.tree();
+
+    // The tree is a copy of the checkout, so that what the build changes stays in its workspace.
+    let external = config.use_rsync == Some(true);
+    let checkout = match item.location {
+        Some(SourceLocation::Path { ref path }) => Some(pwd.join(path)),
+        _ => fetcher::of(item)
+            .and_then(|fetcher| if fetcher.is_archive() { None } else { Some(fetcher) })
+            .map(|fetcher| pwd.join(fetcher.resolve())),
+    };
 
-    {
-        if let Some(SourceLocation::URL { ref url, .. }) = item.location {
-            let filename = &url[url.rfind('/').map_or(0, |x| x + 1)..];
-            let src = PathBuf::from(["assets/cache/", &item.name, "_", &filename].concat());
-            extract::extract(&src, &project_directory)
-                .map_err(|why| BuildError::Extract { src, dst: project_directory.clone(), why })?;
+    if let Some(src) = checkout {
+        mirror(&src, &project_directory, external)
+            .map_err(|why| BuildError::Copy { src, dst: project_directory.clone(), why })?;
+    }
+
+    // Archives were stored in the cache by the fetchers of their locations.
+    match fetcher::of(item) {
+        Some(ref fetcher) if fetcher.is_archive() => {
+            let src = fetcher.resolve();
+            extract::extract(&src, &project_directory).map_err(|why| BuildError::Extract {
+                src,
+                dst: project_directory.clone(),
+                why
+            })?;
         }
+        _ => (),
     }
 
     let mut linked: Vec<LinkedArtifact> = Vec::new();
 
-    match pwd.join(&["assets/packages/", &item.name].concat()) {
+    match unit_path(pwd, "assets/packages", item) {
         ref local_assets if local_assets.exists() => {
-            fetch_assets(&mut linked, local_assets, &project_directory)?;
+            let filter = AssetFilter::new(
+                item.asset_include.as_ref().map(|x| x.as_slice()),
+                item.asset_exclude.as_ref().map(|x| x.as_slice()),
+            ).map_err(|(pattern, why)| BuildError::AssetPattern {
+                package: item.name.clone(),
+                pattern,
+                why
+            })?;
+
+            let started = Instant::now();
+            assets::fetch_assets(&mut linked, local_assets, &project_directory, &filter)?;
+            let elapsed = misc::millis(started.elapsed());
+            debug!("linked {} assets of {} in {}ms", linked.len(), item.name, elapsed);
         },
         _ => ()
     }
 
     if let Some(ref assets) = item.assets {
-        for asset in assets {
-            if let Ok(globs) = glob(&[SHARED_ASSETS, &asset.src].concat()) {
-                for file in globs.flat_map(|x| x.ok()) {
-                    let dst = project_directory.join(&asset.dst);
-                    linked.push(link_artifact(&file, &dst)?);
-                }
-            }
+        let root = assets::shared_root(
+            &pwd.join(SHARED_ASSETS),
+            item.shared_prefix.as_ref().map(|x| x.as_str()),
+        );
+
+        let shared = assets::resolve_shared(&root, assets, &project_directory);
+
+        let mut cache = HashCache::open(pwd.join(HASHES), options.no_cache);
+        let started = Instant::now();
+        let verified = assets::verify_checksums(&root, &shared, &mut cache);
+        let elapsed = misc::millis(started.elapsed());
+        debug!("verified {} shared assets of {} in {}ms", shared.len(), item.name, elapsed);
+        if let Err(why) = cache.save() {
+            warn!("failed to save the asset hash cache: {}", why);
         }
+
+        verified?;
+        assets::link_shared(&mut linked, &item.name, &shared)?;
     }
 
     match item.debian {
-        Some(DebianPath::URL { ref url, ref checksum }) => {
-            unimplemented!()
+        Some(DebianPath::URL { .. }) => {
+            unpack_debian(item, &workspace, engine, options.offline)?;
+        }
+        Some(DebianPath::Branch { .. }) if options.offline => {
+            return Err(BuildError::Offline {
+                package: item.name.clone(),
+                what: "clone its debian branch",
+            });
         }
         Some(DebianPath::Branch { ref url, ref branch }) => {
-            merge_branch(url, branch)
-                .map_err(|why| BuildError::GitBranch {
-                    package: item.name.clone(),
-                    branch: branch.clone(),
-                    why
-                })?;
+            merge_branch(&item.name, url, branch, &workspace)?;
         }
         None => {
-            let debian_path = pwd.join(&["debian/", &item.name, "/"].concat());
+            let debian_path = unit_path(pwd, "debian", item).join("");
             if debian_path.exists() {
                 let project_debian_path = project_directory.join("debian");
-                rsync(&debian_path, &project_debian_path)
-                    .map_err(|why| BuildError::Rsync {
+                overlay(&debian_path, &project_debian_path, external)
+                    .map_err(|why| BuildError::Copy {
                         src: debian_path,
                         dst: project_debian_path,
                         why
@@ -175,79 +1221,548 @@ pub fn build(item: &Source, pwd: &Path, branch: &str, force: bool) -> Result<(),
         }
     }
 
-    let _ = env::set_current_dir("build");
+    if let Some(ref suffix) = item.version_suffix {
+        version::append_suffix(&project_directory.join("debian/changelog"), suffix)
+            .map_err(|why| BuildError::Changelog { package: item.name.clone(), why })?;
+    }
+
+    // The rewrite comes before the sources are hashed, so that changing it builds them again.
+    let maintainer = item.maintainer.as_ref().or(config.maintainer.as_ref());
+    if let (Some(maintainer), false) = (maintainer, item.keep_maintainer) {
+        let replaced = maintainer::rewrite(&project_directory, maintainer)
+            .map_err(|why| BuildError::Maintainer { package: item.name.clone(), why })?;
+        if let Some(original) = replaced {
+            info!("rewrote the maintainer of {}, which was {}", item.name, original);
+        }
+    }
+
+    let suite = config.effective_suite(&config.archive);
+    if let Some(ref pattern) = suite.version_pattern {
+        check_version(item, &project_directory, &config.archive, pattern)?;
+    }
+
+    let rebuilt = match force {
+        Force::Rebuild(reason) => {
+            prepare_rebuild(config, pool, item, &project_directory, reason)?;
+            Some(reason)
+        }
+        _ => None,
+    };
 
-    pre_flight(
+    let (outcome, record) = pre_flight(
+        config,
+        pool,
         item,
         &pwd,
-        branch,
-        &project_directory,
+        host,
+        &workspace,
         force,
+        options,
     )?;
 
-    let _ = env::set_current_dir("..");
-    mv_to_pool("build", branch, item.keep_source)
-        .map_err(|why| BuildError::Pool { package: item.name.clone(), why })
-}
-
-fn merge_branch(url: &str, branch: &str) -> io::Result<()> {
-    fs::create_dir_all("/tmp/debrep")?;
-    fs::remove_dir_all("/tmp/debrep/repo")?;
-    Command::new("git")
-        .args(&["clone", "-b", branch, url, "/tmp/debrep/repo"])
-        .status()?;
-
-    Command::new("cp")
-        .args(&["-r", "/tmp/debrep/repo/debian", "."])
-        .status()?;
-
-    Ok(())
+    interrupted(item)?;
+    Ok(Staged { outcome, record, workspace, rebuilt, started })
 }
 
-fn pre_flight(
+/// Moves the packages of the staged build into the pool, and records the build. Builds are
+/// placed one at a time.
+fn place(
+    config: &Config,
+    pool: &mut PoolIndex,
     item: &Source,
     pwd: &Path,
-    branch: &str,
-    dir: &Path,
-    force: bool
-) -> Result<(), BuildError> {
-    let name = &item.name;
-    let build_on = item.build_on.as_ref().map(|x| x.as_str());
-    let record_path = PathBuf::from(["../record/", &name].concat());
+    host: &str,
+    options: BuildOptions,
+    staged: Staged,
+) -> Result<Outcome, BuildError> {
+    let _scope = logging::scope(&item.name, &config.archive);
+    let Staged { mut outcome, record, workspace, rebuilt, started } = staged;
+    let outgoing = workspace.outgoing();
+    if let Outcome::Built { .. } = outcome {
+        claim_binaries(config, item, pwd, &outgoing)?;
+    }
 
-    enum Record {
-        Changelog(String),
-        Commit(String, String),
-        CommitAppend(String, String),
+    if config.ddebs() == DdebHandling::Discard {
+        let discarded = pool::discard_debug_symbols(&outgoing)
+            .map_err(|why| BuildError::Pool { package: item.name.clone(), why })?;
+        debug!("discarded {} debug symbol packages of {}", discarded, item.name);
     }
 
-    let record = match build_on {
-        Some("changelog") => {
-            let version = changelog(&dir.join("debian/changelog"), 1)
-                .map_err(|why| BuildError::Changelog {
-                    package: item.name.clone(),
-                    why
-                }).and_then(|x| x.into_iter().next().ok_or_else(|| BuildError::NoChangelogVersion {
+    // The .changes files are spooled before the pool takes the files which they list, some of
+    // which it may not keep.
+    let targets = config.upload_targets_of(item);
+    let uploads: Vec<(String, String)> = match outcome {
+        Outcome::Built { .. } if !targets.is_empty() => {
+            match upload::spool(pwd, &config.archive, &outgoing) {
+                Ok(spooled) => spooled.iter()
+                    .flat_map(|changes| {
+                        targets.iter().map(move |target| (target.clone(), changes.clone()))
+                    })
+                    .collect(),
+                Err(why) => {
+                    warn!("{} will not be uploaded, since it could not be spooled: {}",
+                        item.name, why);
+                    Vec::new()
+                }
+            }
+        }
+        _ => Vec::new(),
+    };
+
+    if record.as_ref().map_or(false, |record| record.added) {
+        let kept = pool::keep_published_source(&outgoing, pool)
+            .map_err(|why| BuildError::Pool { package: item.name.clone(), why })?;
+        debug!("kept the published source package of {}, instead of {} rebuilt files", item.name,
+            kept);
+    }
+
+    let placed = mv_to_pool(
+        &outgoing,
+        pool,
+        item.component(),
+        item.keep_source,
+        options.replace_orig,
+        options.replace_published,
+    ).map_err(|why| BuildError::Pool { package: item.name.clone(), why })?;
+
+    for path in &placed {
+        debug!("placed {} in the pool", path.display());
+    }
+
+    if let Outcome::Built { ref mut sizes, .. } = outcome {
+        *sizes = Sizes::of(&placed);
+    }
+
+    if let Outcome::Built { tests: Some(ref tests), .. } = outcome {
+        let result = match *tests {
+            TestResult::Passed => "passed",
+            TestResult::Skipped => "skipped",
+            TestResult::NoTests => "none",
+            TestResult::Failed(_) => "failed",
+        };
+
+        if let Err(why) = migrate::record_tests(&placed, result) {
+            warn!("failed to record the test results of {}: {}", item.name, why);
+        }
+    }
+
+    // Only packages which were completely built, checked, and moved into the pool are recorded,
+    // so that anything less is built again by the next run.
+    if let (Some(mut record), &Outcome::Built { ref version, ref log, .. }) = (record, &outcome) {
+        let log = log.strip_prefix(pwd).unwrap_or(log);
+        let result = BuildResult::Built;
+        let build = finished(config, item, host, started, result, version.clone(), Some(log));
+        let metrics = stats::measure(&placed, &item.name);
+        let start = build.finished.saturating_sub(build.seconds);
+        let provenance = provenance::built(config, item, &placed, start, log);
+        record.uploads = uploads;
+        record.write(config, pwd, &build, &placed, &metrics, &provenance, options.legacy_records)
+            .map_err(|why| BuildError::RecordUpdate { package: item.name.clone(), why })?;
+    }
+
+    if let Some(reason) = rebuilt {
+        let version = changelog(&workspace.tree().join("debian/changelog"), 1)
+            .ok()
+            .and_then(|versions| versions.into_iter().next())
+            .unwrap_or_default();
+        let run = run::current().map(|run| run.id.clone());
+        let run = run.as_ref().map(|x| x.as_str());
+        if let Err(why) = rebuild::record(pwd, &config.archive, &item.name, &version, run, reason) {
+            warn!("failed to record why {} was rebuilt: {}", item.name, why);
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Adds a changelog entry for the next rebuild of the version of the source when the pool
+/// already has the version, so that the rebuild replaces it.
+fn prepare_rebuild(
+    config: &Config,
+    pool: &PoolIndex,
+    item: &Source,
+    dir: &Path,
+    reason: Option<&str>,
+) -> Result<(), BuildError> {
+    let path = dir.join("debian/changelog");
+    let changelog = misc::read_to_string(&path)
+        .map_err(|why| BuildError::Changelog { package: item.name.clone(), why })?;
+    let entry = version::ChangelogEntry::latest(&changelog)
+        .ok_or_else(|| BuildError::NoChangelogVersion { package: item.name.clone() })?;
+
+    let scheme = config.rebuild_suffix.as_ref().map_or(rebuild::DEFAULT_SCHEME, |x| x.as_str());
+    let n = match rebuild::next_number(pool, &entry.package, &entry.version, scheme) {
+        Some(n) => n,
+        None => {
+            info!("rebuilding {} at {}, which is not in the pool", item.name, entry.version);
+            return Ok(());
+        }
+    };
+
+    let rebuilt = rebuild::version(scheme, &entry.version, n);
+    rebuild::check(&entry.version, &rebuilt)
+        .map_err(|why| BuildError::Rebuild { package: item.name.clone(), why })?;
+
+    info!("rebuilding {} as {}", item.name, rebuilt);
+    rebuild::add_entry(&path, &rebuilt, &config.archive, reason, &config.email)
+        .map_err(|why| BuildError::Changelog { package: item.name.clone(), why })
+}
+
+/// Fails the package if the run was interrupted, so that it is not recorded as built.
+fn interrupted(item: &Source) -> Result<(), BuildError> {
+    if interrupt::is_interrupted() {
+        Err(BuildError::Interrupted { package: item.name.clone() })
+    } else {
+        Ok(())
+    }
+}
+
+/// The directory of the source in `dir`, where a variant may have its own directory, and otherwise
+/// shares the directory of its source.
+fn unit_path(pwd: &Path, dir: &str, item: &Source) -> PathBuf {
+    let path = pwd.join(dir).join(&item.name);
+    if item.variant().is_some() && !path.exists() {
+        pwd.join(dir).join(item.base_name())
+    } else {
+        path
+    }
+}
+
+/// Records the binary packages which were just built for the source in `dir`, failing if another
+/// configured source produces any of them, so that one does not silently replace the other in the
+/// pool.
+fn claim_binaries(
+    config: &Config,
+    item: &Source,
+    pwd: &Path,
+    dir: &Path,
+) -> Result<(), BuildError> {
+    let mut produced = Vec::new();
+    let changes_files = sign::changes_files(dir)
+        .map_err(|why| BuildError::Read { file: dir.to_path_buf(), why })?;
+    for changes in changes_files {
+        let names = binaries::changes_binaries(&changes)
+            .map_err(|why| BuildError::Read { file: changes.clone(), why })?;
+        produced.extend(names);
+    }
+
+    let path = pwd.join(BINARIES);
+    let mut record = Binaries::open(&path);
+    let configured: Vec<&str> = config.source.as_ref()
+        .map_or_else(Vec::new, |sources| sources.iter().map(|x| x.name.as_str()).collect());
+
+    record.claim(item.base_name(), &produced, item.takeover, &configured)
+        .map_err(|conflict| BuildError::BinaryConflict {
+            package: item.name.clone(),
+            binary: conflict.binary,
+            owner: conflict.owner,
+        })?;
+
+    record.save(&path).map_err(|why| BuildError::RecordUpdate { package: item.name.clone(), why })
+}
+
+/// Runs the command with its output piped through to both its log and the terminal.
+fn run_followed(command: Exec, log: File, name: &str) -> Result<ExitStatus, PopenError> {
+    let mut child = command.stdout(Redirection::Pipe).stderr(Redirection::Merge).popen()?;
+    let tee = child.stdout.take().map(|output| {
+        let name = name.to_owned();
+        thread::spawn(move || {
+            let stderr = io::stderr();
+            tee::tee(output, log, stderr.lock(), &name)
+        })
+    });
+
+    let status = wait_interruptible(&mut child)?;
+    if let Some(Ok(Err(why))) = tee.map(|tee| tee.join()) {
+        return Err(PopenError::IoError(why));
+    }
+
+    Ok(status)
+}
+
+/// Waits for sbuild to exit. If the run is interrupted, sbuild is asked to stop, which ends its
+/// schroot session, and is killed if it does not stop in time. Sessions which it left behind are
+/// then ended.
+fn wait_interruptible(child: &mut Popen) -> Result<ExitStatus, PopenError> {
+    let sessions = install::Session::open();
+    loop {
+        if let Some(status) = child.wait_timeout(Duration::from_millis(250))? {
+            return Ok(status);
+        }
+
+        if interrupt::is_interrupted() {
+            break;
+        }
+    }
+
+    warn!("the run was interrupted; stopping sbuild");
+    child.terminate()?;
+    let status = match child.wait_timeout(Duration::from_secs(SBUILD_STOP_TIMEOUT))? {
+        Some(status) => status,
+        None => {
+            warn!("sbuild did not stop within {}s; killing it", SBUILD_STOP_TIMEOUT);
+            child.kill()?;
+            child.wait()?
+        }
+    };
+
+    for session in install::Session::open() {
+        if !sessions.contains(&session) {
+            info!("ending the schroot session {}, which sbuild left behind", session);
+            drop(install::Session::adopt(session));
+        }
+    }
+
+    Ok(status)
+}
+
+/// The reason that a command could not be started by `subprocess`.
+fn popen_error(why: PopenError) -> io::Error {
+    match why {
+        PopenError::IoError(why) => why,
+        why => io::Error::new(io::ErrorKind::Other, why.to_string()),
+    }
+}
+
+/// Clones the branch of a git repository into the workspace, and copies its `debian` directory
+/// into the tree of the workspace.
+fn merge_branch(
+    package: &str,
+    url: &str,
+    branch: &str,
+    workspace: &Workspace,
+) -> Result<(), BuildError> {
+    let temp = workspace.temp("debian-branch")
+        .map_err(|why| BuildError::Directory { path: workspace.root().join("tmp"), why })?;
+    let clone = temp.path().join("repo");
+
+    Cmd::new("git").args(&["clone", "-b", branch, url]).arg(&clone)
+        .operation(Operation::GitMerge)
+        .run()
+        .map_err(|why| BuildError::GitBranch {
+            package: package.to_owned(),
+            branch: branch.to_owned(),
+            why
+        })?;
+
+    Cmd::new("cp").arg("-r").arg(clone.join("debian")).arg(workspace.tree()).run()
+        .map_err(|why| BuildError::Command { why })
+}
+
+/// Fetches the tarball of the debian directory of the source into the cache, unless it is there
+/// with its checksum, and replaces the debian directory of the tree with what it holds, wherever
+/// in the tarball that is.
+fn unpack_debian(
+    item: &Source,
+    workspace: &Workspace,
+    engine: &Engine,
+    offline: bool,
+) -> Result<(), BuildError> {
+    let fetcher = match fetcher::debian(item) {
+        Some(fetcher) => fetcher,
+        None => return Ok(()),
+    };
+
+    fetcher::fetch(&item.name, &*fetcher, engine, offline).map_err(|why| match why {
+        DownloadError::ChecksumMismatch { expected, found, .. } => BuildError::DebianChecksum {
+            package: item.name.clone(),
+            url: fetcher.origin(),
+            expected,
+            received: found,
+        },
+        why => BuildError::DebianFetch { package: item.name.clone(), why },
+    })?;
+
+    let archive = fetcher.resolve();
+    let temp = workspace.temp("debian-tarball")
+        .map_err(|why| BuildError::Directory { path: workspace.root().join("tmp"), why })?;
+    let unpacked = temp.path().join("debian");
+    let extract_error = |why| BuildError::Extract {
+        src: archive.clone(),
+        dst: unpacked.clone(),
+        why
+    };
+
+    extract::extract(&archive, &unpacked).map_err(&extract_error)?;
+    let src = extract::debian_dir(&unpacked)
+        .map_err(&extract_error)?
+        .ok_or_else(|| BuildError::DebianMissing {
+            package: item.name.clone(),
+            archive: archive.clone(),
+        })?;
+
+    // The tree is kept between builds, so files of an older tarball must not be left in it.
+    let dst = workspace.tree().join("debian");
+    if dst.exists() {
+        fs::remove_dir_all(&dst)
+            .map_err(|why| BuildError::Directory { path: dst.clone(), why })?;
+    }
+
+    fs::rename(&src, &dst).map_err(|why| BuildError::Copy { src, dst, why })
+}
+
+/// What a package is recorded as having been built with, which is only written once the package
+/// was moved into the pool.
+struct PendingRecord {
+    /// Where the record file is saved with `--legacy-records`, which `record_path` gives.
+    path: PathBuf,
+    /// The record of the package.
+    record: Option<BuildRecord>,
+    /// Whether the build only added architectures to what was built before, whose source package
+    /// is already in the pool.
+    added: bool,
+    /// The environment that the package was built with.
+    environment: String,
+    /// The targets which each `.changes` file of the build in the upload spool is uploaded to.
+    uploads: Vec<(String, String)>,
+    /// The version of each build dependency which sbuild installed.
+    dependencies: Vec<(String, String)>,
+}
+
+impl PendingRecord {
+    /// Records the build, along with what the package was built from and with, the files which
+    /// it placed in the pool, and what was measured of it.
+    fn write(
+        &self,
+        config: &Config,
+        pwd: &Path,
+        build: &Build,
+        placed: &[PathBuf],
+        metrics: &Metrics,
+        provenance: &[FileProvenance],
+        legacy: bool,
+    ) -> io::Result<()> {
+        let built = Built {
+            record: self.record.as_ref().map(|record| (record_scope(config), record.serialize())),
+            environment: &self.environment,
+            artifacts: placed,
+            metrics,
+            provenance,
+            uploads: &self.uploads,
+            dependencies: &self.dependencies,
+        };
+
+        State::open(pwd)?.add_build(build, Some(built))?;
+        if legacy {
+            self.write_files(pwd, &build.package)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the record files of earlier versions of debrep.
+    fn write_files(&self, pwd: &Path, package: &str) -> io::Result<()> {
+        let environments = pwd.join(ENVIRONMENTS);
+        fs::create_dir_all(&environments)?;
+        record::write_atomic(&environments.join(package), self.environment.as_bytes())?;
+
+        if let Some(ref entry) = self.record {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            record::save(&self.path, entry)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The scope of the records of what packages were last built from. Suites which are defined as
+/// tables each have records of their own, since each of them builds the package.
+fn record_scope(config: &Config) -> &str {
+    match config.suites {
+        Some(Suites::Defined(_)) => &config.archive,
+        _ => "",
+    }
+}
+
+/// Where the record file of what a package was last built from is kept with `--legacy-records`,
+/// in the scope of `record_scope`.
+fn record_path(config: &Config, pwd: &Path, package: &str) -> PathBuf {
+    match config.suites {
+        Some(Suites::Defined(_)) => pwd.join(SUITE_RECORDS).join(&config.archive).join(package),
+        _ => pwd.join("record").join(package),
+    }
+}
+
+/// The record of what the package was last built from. A record of an earlier format is
+/// rewritten in the current one when it is first read, and a corrupt one is ignored.
+fn read_record(config: &Config, pwd: &Path, name: &str) -> Result<Option<BuildRecord>, BuildError> {
+    let database = || pwd.join(super::state::DATABASE);
+    let mut state = State::open(pwd).map_err(|why| BuildError::Read { file: database(), why })?;
+    let scope = record_scope(config);
+    let contents = match state.record(scope, name) {
+        Ok(Some(contents)) => contents,
+        Ok(None) => return Ok(None),
+        Err(why) => return Err(BuildError::Read { file: database(), why }),
+    };
+
+    let recorded = record::load(&contents, name);
+    if let Some(ref recorded) = recorded {
+        if !record::is_current(&contents) {
+            info!("rewriting the record of {} in the current format", name);
+            state.set_record(scope, name, &recorded.serialize())
+                .map_err(|why| BuildError::RecordUpdate { package: name.to_owned(), why })?;
+        }
+    }
+
+    Ok(recorded)
+}
+
+/// The rule which decides whether the source is built again. A local working copy may have
+/// neither a new changelog entry nor a new commit for changes, so it is built on the checksum of
+/// its tree unless it gives a rule.
+fn build_on(item: &Source) -> Option<&str> {
+    item.build_on.as_ref().map(|x| x.as_str()).or_else(|| match item.location {
+        Some(SourceLocation::Path { .. }) => Some("checksum"),
+        _ => None,
+    })
+}
+
+fn pre_flight(
+    config: &Config,
+    pool: &PoolIndex,
+    item: &Source,
+    pwd: &Path,
+    host: &str,
+    workspace: &Workspace,
+    force: Force,
+    options: BuildOptions,
+) -> Result<(Outcome, Option<PendingRecord>), BuildError> {
+    let name = &item.name;
+    let tree = workspace.tree();
+    let dir = tree.as_path();
+    let build_on = build_on(item);
+    let record_path = record_path(config, pwd, name);
+    let recorded = read_record(config, pwd, name)?;
+
+    // A forced build ignores what was built before, but keeps its history, such as the commits
+    // which were built.
+    let forced = force != Force::No;
+
+    // The ID of the run which built the package is recorded after the version that was built.
+    // What was built before is skipped, unless it is now built for architectures which it was
+    // not built for then.
+    let run_id = run::current().map(|run| run.id.clone());
+    let (record, version, built) = match build_on {
+        Some("changelog") => {
+            let version = changelog(&dir.join("debian/changelog"), 1)
+                .map_err(|why| BuildError::Changelog {
+                    package: item.name.clone(),
+                    why
+                }).and_then(|x| x.into_iter().next().ok_or_else(|| BuildError::NoChangelogVersion {
                     package: item.name.clone(),
                 }))?;
 
-            if !force && record_path.exists() {
-                let record = misc::read_to_string(&record_path)
-                    .map_err(|why| BuildError::Read { file: record_path.clone(), why })?;
-                let mut record = record.lines();
-
-                if let Some(source) = record.next() {
-                    if let Some(recorded_version) = record.next() {
-                        if source == "changelog" && recorded_version == version {
-                            info!("{} has already been built -- skipping", name);
-                            return Ok(());
-                        }
-                    }
-                }
-            }
+            let built = !forced && match recorded.as_ref().map(|x| &x.record) {
+                Some(&Record::Changelog { version: ref recorded, .. }) => *recorded == version,
+                _ => false,
+            };
 
             info!("building {} at changelog version {}", name, version);
-            Some(Record::Changelog(version))
+            let record = Record::Changelog { version: version.clone(), run: run_id };
+            (Some(record), Some(version), built)
         }
         Some("commit") => {
             let (branch, commit) = git(dir).map_err(|why| BuildError::GitCommit {
@@ -255,126 +1770,745 @@ fn pre_flight(
                 why
             })?;
 
-            let mut append = false;
-
-            if !force && record_path.exists() {
-                let record = misc::read_to_string(&record_path)
-                    .map_err(|why| BuildError::Read { file: record_path.clone(), why })?;
-                let mut record = record.lines();
-
-                if let Some(source) = record.next() {
-                    if source == "commit" {
-                        for branch_entry in record {
-                            let mut fields = branch_entry.split_whitespace();
-                            if let (Some(rec_branch), Some(rec_commit)) =
-                                (fields.next(), fields.next())
-                            {
-                                if rec_branch == branch && rec_commit == commit {
-                                    info!("{} has already been built -- skipping", name);
-                                    return Ok(());
-                                }
-                            }
-                        }
-                        append = true;
-                    }
-                }
+            // The commits which were built before are kept, so that returning to one of them does
+            // not build it again. Only the last of them may be built for more architectures.
+            let version = commit_version(&branch, &commit);
+            let mut commits = match recorded {
+                Some(BuildRecord { record: Record::Commits(ref commits), .. }) => commits.clone(),
+                _ => Vec::new(),
+            };
+
+            let latest = recorded.as_ref()
+                .map_or(false, |x| x.record.has_latest_commit(&branch, &commit));
+            let older = recorded.as_ref().map_or(false, |x| x.record.has_commit(&branch, &commit));
+            if !forced && !latest && older {
+                info!("{} has already been built -- skipping", name);
+                return Ok((Outcome::Skipped { version }, None));
             }
 
             info!(
                 "building {} at git branch {}; commit {}",
                 name, branch, commit
             );
-            Some(if append {
-                Record::CommitAppend(branch, commit)
-            } else {
-                Record::Commit(branch, commit)
-            })
+
+            if !latest {
+                commits.push(record::Commit { branch, commit, run: run_id });
+            }
+
+            (Some(Record::Commits(commits)), Some(version), latest && !forced)
+        }
+        Some("checksum") => {
+            let started = Instant::now();
+            let digest = tree_digest(dir).map_err(|why| BuildError::Read {
+                file: dir.to_path_buf(),
+                why
+            })?;
+            debug!("hashed the sources of {} in {}ms", name, misc::millis(started.elapsed()));
+
+            let version = checksum_version(&digest);
+            let built = !forced && match recorded.as_ref().map(|x| &x.record) {
+                Some(&Record::Checksum { digest: ref recorded, .. }) => *recorded == digest,
+                _ => false,
+            };
+
+            info!("building {} with sources of checksum {}", name, digest);
+            (Some(Record::Checksum { digest, run: run_id }), Some(version), built)
         }
         Some(rule) => {
             return Err(BuildError::ConditionalRule { rule: rule.to_owned() });
         }
-        None => None,
+        None => (None, None, false),
     };
 
-    sbuild(item, &pwd, branch, dir)?;
-
-    let result = match record {
-        Some(Record::Changelog(version)) => {
-            misc::write(record_path, ["changelog\n", &version].concat().as_bytes())
-        }
-        Some(Record::Commit(branch, commit)) => misc::write(
-            record_path,
-            ["commit\n", &branch, " ", &commit].concat().as_bytes(),
-        ),
-        Some(Record::CommitAppend(branch, commit)) => OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(record_path)
-            .and_then(|mut file| file.write_all([&branch, " ", &commit].concat().as_bytes())),
-        None => return Ok(()),
+    let suite = config.effective_suite(&config.archive);
+    let mut architectures = item.build_architectures(&suite.architectures, host);
+    let mut recorded_architectures = architectures.clone();
+    let added = match recorded {
+        Some(ref entry) if built => {
+            let missing = entry.missing(&architectures, host);
+            if missing.is_empty() {
+                info!("{} has already been built -- skipping", name);
+                let version = version.unwrap_or_default();
+                return Ok((Outcome::Skipped { version }, None));
+            }
+
+            info!("{} was already built, but not for {}", name, missing.join(" "));
+            recorded_architectures = entry.architectures(host);
+            recorded_architectures.extend(missing.iter().cloned());
+            architectures = missing;
+            true
+        }
+        _ => false,
+    };
+
+    let mut environment = Environment::normalized(config.reproducible.as_ref());
+    match changelog_date(&dir.join("debian/changelog")) {
+        Ok(Some(date)) => {
+            environment.variables.insert("SOURCE_DATE_EPOCH".to_owned(), date.to_string());
+        }
+        Ok(None) => warn!("the changelog of {} has no date for SOURCE_DATE_EPOCH", name),
+        Err(why) => {
+            warn!("failed to read the changelog of {} for SOURCE_DATE_EPOCH: {}", name, why)
+        }
+    }
+
+    if let Some(kind) = item.vendor {
+        vendor::vendor(kind, name, dir, &pwd.join(VENDOR_CACHE), options.offline)?;
+    }
+
+    interrupted(item)?;
+    let log = sbuild(config, pool, item, &pwd, workspace, &environment, &architectures, options)?;
+    let (installed, changes) = dependencies::compare(pwd, &config.archive, name, &log);
+    if let Some(ref changes) = changes {
+        info!("the build dependencies of {} changed since its last build: {}", name, changes);
+    }
+
+    let outgoing = workspace.outgoing();
+    let lintian = if config.lintian.is_some() || item.lintian_fail_on.is_some() {
+        lint(config, item, &outgoing)?
+    } else {
+        None
+    };
+
+    if item.install_check.unwrap_or(config.install_check.is_some()) {
+        check_install(config, pool, item, pwd, host, &outgoing)?;
+    }
+
+    let piuparts = if item.piuparts {
+        Some(check_piuparts(config, pool, item, pwd, host, &outgoing)?)
+    } else {
+        None
+    };
+
+    let tests = if item.autopkgtest {
+        Some(test(config, item, pwd, host, &outgoing)?)
+    } else {
+        None
+    };
+
+    let signed = match config.signing {
+        Some(ref signing) if item.sign && !options.no_sign => {
+            Some(sign_changes(signing, &item.name, &outgoing)?)
+        }
+        _ => None,
+    };
+
+    let built = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok();
+    let record = PendingRecord {
+        path: record_path,
+        record: record.map(|record| BuildRecord {
+            record,
+            architectures: Some(recorded_architectures),
+            built,
+            distribution: Some(config.archive.clone()),
+        }),
+        added,
+        environment: environment.record(),
+        uploads: Vec::new(),
+        dependencies: installed.into_iter().collect(),
+    };
+    let sizes = Sizes::default();
+    let outcome = Outcome::Built {
+        version,
+        log,
+        signed,
+        lintian,
+        piuparts,
+        tests,
+        sizes,
+        dependencies: changes,
+    };
+    Ok((outcome, Some(record)))
+}
+
+/// Checks a package which was just built with lintian, failing it if it has findings which are at
+/// least as severe as its `lintian_fail_on`.
+fn lint(config: &Config, item: &Source, dir: &Path) -> Result<Option<Counts>, BuildError> {
+    let fail_on = item.lintian_fail_on.unwrap_or(LintianFailOn::None);
+    let files = sign::changes_files(dir)
+        .map_err(|why| BuildError::Read { file: dir.to_path_buf(), why })?;
+
+    let findings = match lintian::check(config.lintian.as_ref(), &files) {
+        Ok(findings) => findings,
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound && fail_on == LintianFailOn::None => {
+            warn!("lintian is not installed, so {} was not checked", item.name);
+            return Ok(None);
+        }
+        Err(why) => return Err(BuildError::Lintian { package: item.name.clone(), why }),
     };
 
-    result.map_err(|why| BuildError::RecordUpdate { package: item.name.to_string(), why })
+    for finding in findings.iter().filter(|x| x.severity != lintian::Severity::Overridden) {
+        info!("lintian: {}", finding);
+    }
+
+    let counts = Counts::of(&findings);
+    if counts.fails(fail_on) {
+        return Err(BuildError::LintianFailed { package: item.name.clone(), counts });
+    }
+
+    Ok(Some(counts))
 }
 
-fn sbuild<P: AsRef<Path>>(
+/// Installs the packages which were just built in a session of a schroot, failing the package if
+/// they cannot be installed, or upgraded to from the versions which were previously published.
+/// Only the packages of the `host` architecture, and of `all`, are installed.
+fn check_install(
+    config: &Config,
+    pool: &PoolIndex,
     item: &Source,
     pwd: &Path,
-    branch: &str,
-    path: P,
+    host: &str,
+    dir: &Path,
 ) -> Result<(), BuildError> {
-    let log_path = pwd.join(["logs/", &item.name].concat());
-    let mut command = Exec::cmd("sbuild")
-        .args(&["-v", "--log-external-command-output", "--log-external-command-error", "-d", branch])
-        .stdout(Redirection::Merge)
-        .stderr(Redirection::File(
-            fs::OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(&log_path)
-                .map_err(|why| BuildError::Open { file: log_path, why })?
-        ));
+    let settings = config.install_check.as_ref();
+    let error = |why| BuildError::Install { package: item.name.clone(), why };
+    let configured = settings.and_then(|settings| settings.chroot.as_ref());
+    let chroot = install::chroot(configured, &config.build_suite()).map_err(&error)?;
+
+    let debs = install::built_debs(dir, host)
+        .map_err(|why| BuildError::Read { file: dir.to_path_buf(), why })?;
+    if debs.is_empty() {
+        return Ok(());
+    }
+
+    let previous = install::previous_debs(pool, &debs);
+    let (log, mut file) = logs::create_step(pwd, &item.name, "install")
+        .map_err(|why| BuildError::Open { file: pwd.join(logs::LOGS).join(&item.name), why })?;
+
+    info!("installing the packages of {} in {}", item.name, chroot);
+    let timeout = settings.and_then(|settings| settings.timeout)
+        .unwrap_or(install::DEFAULT_TIMEOUT);
+    if !install::check(&chroot, &debs, &previous, &mut file, timeout).map_err(&error)? {
+        return Err(BuildError::InstallFailed { package: item.name.clone(), log });
+    }
+
+    Ok(())
+}
+
+/// Checks the packages of the `host` architecture which were just built with piuparts, unless
+/// nothing which it checks has changed since they were last published.
+fn check_piuparts(
+    config: &Config,
+    pool: &PoolIndex,
+    item: &Source,
+    pwd: &Path,
+    host: &str,
+    dir: &Path,
+) -> Result<Verdict, BuildError> {
+    let settings = config.piuparts.as_ref();
+    let error = |why| BuildError::Piuparts { package: item.name.clone(), why };
+    let debs = install::built_debs(dir, host)
+        .map_err(|why| BuildError::Read { file: dir.to_path_buf(), why })?;
+
+    if settings.and_then(|settings| settings.only_changed).unwrap_or(true) {
+        let previous = install::previous_debs(pool, &debs);
+        if !piuparts::changed(&debs, &previous).map_err(&error)? {
+            info!("the maintainer scripts and conffiles of {} did not change", item.name);
+            return Ok(Verdict::Unchanged);
+        }
+    }
+
+    let configured = settings.and_then(|settings| settings.chroot.as_ref());
+    let chroot = install::chroot(configured, &config.build_suite()).map_err(&error)?;
+    let (log, file) = logs::create_step(pwd, &item.name, "piuparts")
+        .map_err(|why| BuildError::Open { file: pwd.join(logs::LOGS).join(&item.name), why })?;
+
+    info!("checking {} with piuparts in {}", item.name, chroot);
+    let repo = pwd.join("repo");
+    let verdict = piuparts::run(settings, &chroot, &repo, &config.archive, &debs, file)
+        .map_err(&error)?;
+
+    if verdict == Verdict::Failed {
+        if item.piuparts_blocking {
+            return Err(BuildError::PiupartsFailed { package: item.name.clone(), log });
+        }
+
+        warn!("piuparts failed for {}, but does not block it: see {}", item.name, log.display());
+    }
+
+    Ok(verdict)
+}
+
+/// Runs the tests of a package which was just built for the `host` architecture with autopkgtest.
+/// Failed tests fail the package, unless the source does not block on them.
+fn test(
+    config: &Config,
+    item: &Source,
+    pwd: &Path,
+    host: &str,
+    dir: &Path,
+) -> Result<TestResult, BuildError> {
+    let backend = config.autopkgtest_backend.as_ref().ok_or_else(|| BuildError::Autopkgtest {
+        package: item.name.clone(),
+        why: io::Error::new(io::ErrorKind::NotFound, "no autopkgtest_backend is configured"),
+    })?;
+
+    let suffix = ["_", host, ".changes"].concat();
+    let files: Vec<PathBuf> = sign::changes_files(dir)
+        .map_err(|why| BuildError::Read { file: dir.to_path_buf(), why })?
+        .into_iter()
+        .filter(|file| file.to_string_lossy().ends_with(&suffix))
+        .collect();
+
+    if files.is_empty() {
+        info!("{} was not tested, since it was not built for {}", item.name, host);
+        return Ok(TestResult::Skipped);
+    }
+
+    let (log, file) = logs::create_step(pwd, &item.name, "autopkgtest")
+        .map_err(|why| BuildError::Open { file: pwd.join(logs::LOGS).join(&item.name), why })?;
+
+    info!("testing {} with autopkgtest on {}", item.name, backend);
+    let result = autopkgtest::run(&files, backend, file)
+        .map_err(|why| BuildError::Autopkgtest { package: item.name.clone(), why })?;
+
+    info!("{}: {}", item.name, result);
+    if result.failed() {
+        if item.autopkgtest_blocking {
+            return Err(BuildError::Tests { package: item.name.clone(), log });
+        }
 
+        warn!("tests of {} failed, but do not block it: see {}", item.name, log.display());
+    }
+
+    Ok(result)
+}
+
+/// Signs the `.changes` files of a package which was just built, returning the key's fingerprint.
+fn sign_changes(signing: &Signing, package: &str, dir: &Path) -> Result<String, BuildError> {
+    let fingerprint = sign::fingerprint(signing)
+        .map_err(|why| BuildError::SigningKey { key: signing.key.clone(), why })?;
+
+    let files = sign::changes_files(dir)
+        .map_err(|why| BuildError::Read { file: dir.to_path_buf(), why })?;
+
+    for file in files {
+        sign::debsign(signing, &fingerprint, &file)
+            .map_err(|why| BuildError::Sign { package: package.to_owned(), file, why })?;
+    }
+
+    Ok(fingerprint)
+}
+
+/// Checks that the version of the source matches the version pattern of the suite, so that a
+/// backport never outranks the version which its base suite will later have.
+fn check_version(item: &Source, dir: &Path, suite: &str, pattern: &str) -> Result<(), BuildError> {
+    let version = changelog(&dir.join("debian/changelog"), 1)
+        .map_err(|why| BuildError::Changelog { package: item.name.clone(), why })?
+        .into_iter()
+        .next()
+        .ok_or_else(|| BuildError::NoChangelogVersion { package: item.name.clone() })?;
+
+    if Regex::new(pattern).map_or(false, |pattern| pattern.is_match(&version)) {
+        Ok(())
+    } else {
+        Err(BuildError::SuiteVersion {
+            package: item.name.clone(),
+            version,
+            suite: suite.to_owned(),
+            pattern: pattern.to_owned(),
+        })
+    }
+}
+
+/// Describes sources which were built by their checksum, as `sha256:<short digest>`.
+fn checksum_version(digest: &str) -> String {
+    ["sha256:", &digest[..digest.len().min(12)]].concat()
+}
+
+/// Describes a commit which was built, as `<branch>@<short commit>`.
+fn commit_version(branch: &str, commit: &str) -> String {
+    [branch, "@", &commit[..commit.len().min(12)]].concat()
+}
+
+fn sbuild(
+    config: &Config,
+    pool: &PoolIndex,
+    item: &Source,
+    pwd: &Path,
+    workspace: &Workspace,
+    environment: &Environment,
+    architectures: &[String],
+    options: BuildOptions,
+) -> Result<PathBuf, BuildError> {
+    let branch = &config.archive;
+    let (log_path, log) = logs::create(pwd, &item.name)
+        .map_err(|why| BuildError::Open { file: pwd.join(logs::LOGS).join(&item.name), why })?;
+
+    let mut args: Vec<OsString> = [
+        "-v",
+        "--log-external-command-output",
+        "--log-external-command-error",
+        "-d",
+        branch,
+    ].iter().map(OsString::from).collect();
+
+    // The packages which the build installs, by their sections, of which each build takes those
+    // of its architecture.
+    let mut extra: Vec<(String, PathBuf)> = Vec::new();
     if let Some(ref depends) = item.depends {
-        let mut temp = misc::walk_debs(&pwd.join(&["repo/pool/", branch, "/main"].concat()))
-            .flat_map(|deb| misc::match_deb(&deb, depends))
-            .collect::<Vec<(String, usize)>>();
+        extra.extend(pool.debs_of(depends).into_iter().map(|deb| {
+            (deb.section.clone(), pwd.join(&deb.path))
+        }));
+    }
+
+    // A suite which is built on another suite is built in the chroot of that suite, with the
+    // packages of its own pool, such as backports which depend on other backports, and with the
+    // packages of the suites which it depends on, which a run builds before it.
+    let base = config.build_suite();
+    let environment_error = |why| BuildError::Environment { package: item.name.clone(), why };
+    let suite = config.effective_suite(branch);
+    let host = misc::host_architecture().map_err(&environment_error)?;
+    let depends = suite.depends;
+    if base != *branch || !depends.is_empty() {
+        let mut add = |pool: &PoolIndex| {
+            extra.extend(pool.files().into_iter()
+                .filter(|file| file.path.extension().map_or(false, |ext| ext == "deb"))
+                .map(|file| (file.section.clone(), pwd.join(&file.path))));
+        };
 
-        temp.sort_by(|a, b| a.1.cmp(&b.1));
-        for &(ref p, _) in &temp {
-            command = command.arg(&["--extra-package=", &p].concat());
+        if base != *branch {
+            add(pool);
+        }
+
+        for suite in &depends {
+            add(&PoolIndex::open(&pool::root(suite)).map_err(&environment_error)?);
         }
     }
 
+    let tree = workspace.tree();
+    let sandbox = Sandbox {
+        isolation: Isolation::detect(),
+        package: &item.name,
+        workdir: &tree,
+        network: item.prebuild_network,
+        limits: config.command_limits.as_ref(),
+    };
+
     if let Some(commands) = item.prebuild.as_ref() {
         for cmd in commands {
-            command = command.arg(&["--pre-build-commands=", &cmd].concat());
+            args.push(["--pre-build-commands=", &sandbox.prebuild(cmd)].concat().into());
         }
     }
 
     if let Some(commands) = item.starting_build.as_ref() {
         for cmd in commands {
-            command = command.arg(&["--starting-build-commands=", &cmd].concat());
+            args.push(["--starting-build-commands=", &sandbox.starting_build(cmd)].concat().into());
         }
     }
 
-    command = command.arg(path.as_ref());
+    let excludes = excludes::patterns(item);
+    for pattern in excludes::matching_debian(&excludes) {
+        warn!("the source_excludes pattern '{}' of {} matches its debian directory", pattern,
+            item.name);
+    }
+
+    for option in excludes::dpkg_source_options(&excludes) {
+        args.push(["--dpkg-source-opt=", &option].concat().into());
+    }
+
+    // Vendored dependencies are new files in the upstream source, which may be binaries.
+    if item.vendor.is_some() {
+        args.push("--dpkg-source-opt=--auto-commit".into());
+        args.push("--dpkg-source-opt=--include-binaries".into());
+    }
+
+    // The proxy and the sbuild config which points to it must outlive the build.
+    let audit = if options.detect_network {
+        Some(AuditProxy::start().map_err(&environment_error)?)
+    } else {
+        None
+    };
+
+    // Builds without the network are run in a network namespace of their own by sbuild, and
+    // their proxies refuse every connection besides. A build which is audited needs the network
+    // to reach the audit proxy.
+    let mut variables = environment.variables.clone();
+    let allowed = !options.offline
+        && item.network_during_build.or(config.network_during_build).unwrap_or(true);
+    let isolated = audit.is_none() && !allowed;
+    match audit {
+        Some(ref audit) => variables.extend(network::proxy_variables(&audit.url())),
+        None if isolated => variables.extend(network::proxy_variables(network::BLACKHOLE)),
+        None => (),
+    }
+
+    let sbuild_dir = TempDir::new("debrep-sbuild").map_err(&environment_error)?;
+    let sbuild_config = sbuild_dir.path().join("sbuildrc");
+    environment::write_sbuild_config(&sbuild_config, &variables, environment.umask, isolated)
+        .map_err(&environment_error)?;
 
-    debug!("executing {:#?}", command);
+    // Each architecture is built by an sbuild of its own, which appends to the log, and the
+    // architecture-independent packages by the first of them, so that they are built once. A
+    // source of only `all` is built on this system's architecture.
+    let indep = architectures.iter().any(|arch| arch == "all");
+    let mut builds: Vec<&str> = architectures.iter()
+        .map(|arch| arch.as_str())
+        .filter(|&arch| arch != "all")
+        .collect();
+    let only_indep = builds.is_empty();
+    if only_indep {
+        builds.push(&host);
+    }
+
+    info!("building {} for {}", item.name, architectures.join(" "));
+    let mut exit_status = ExitStatus::Exited(0);
+    for (pos, &arch) in builds.iter().enumerate() {
+        let mut args = args.clone();
+        let section = ["binary-", arch].concat();
+        for &(ref of, ref deb) in &extra {
+            if *of == section || of == "binary-all" {
+                let mut arg = OsString::from("--extra-package=");
+                arg.push(deb);
+                args.push(arg);
+            }
+        }
+
+        args.push(["--arch=", arch].concat().into());
+        args.push(if indep && pos == 0 { "--arch-all" } else { "--no-arch-all" }.into());
+        if only_indep {
+            args.push("--no-arch-any".into());
+        }
 
-    let exit_status = command.join()
-        .map_err(|why| BuildError::Command {
-            cmd: "sbuild",
-            why: io::Error::new(
-                io::ErrorKind::Other,
-                format!("{:?}", why)
-            )
+        // Sessions are only kept of the chroot of this system's architecture, and are schroot
+        // sessions, which isolated builds do not use.
+        let reused = if config.reuse_sessions == Some(true) && arch == host && !isolated {
+            match install::chroot(None, &base) {
+                Ok(chroot) => session::take(&chroot),
+                Err(why) => {
+                    warn!("failed to determine the chroot of {} to reuse: {}", item.name, why);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        match reused {
+            Some(ref reused) => args.push(reused.chroot_arg().into()),
+            None if base != *branch => {
+                args.push(["--chroot=", &install::chroot_of(&base, arch)].concat().into());
+            }
+            None => (),
+        }
+
+        args.push(tree.as_os_str().to_owned());
+
+        // The packages which sbuild builds are written to its working directory.
+        let line = command::prioritized("sbuild");
+        let mut command = Exec::cmd(&line[0])
+            .cwd(workspace.outgoing())
+            .args(&line[1..])
+            .args(&args)
+            .env("SBUILD_CONFIG", &sbuild_config);
+
+        debug!("executing {:#?}", command);
+
+        if options.ci {
+            command = command.stdin(NullFile).env("DEBIAN_FRONTEND", "noninteractive");
+        }
+
+        let log = log.try_clone().map_err(&environment_error)?;
+        exit_status = if options.follow {
+            run_followed(command, log, &item.name)
+        } else {
+            command.stdout(Redirection::Merge)
+                .stderr(Redirection::File(log))
+                .popen()
+                .and_then(|mut child| wait_interruptible(&mut child))
+        }.map_err(|why| BuildError::Command {
+            why: CommandError::spawn("sbuild", &args, popen_error(why)),
         })?;
 
+        // A session which sbuild was stopped in is ended instead of kept, since it may be in any
+        // state.
+        if let Some(reused) = reused {
+            if !interrupt::is_interrupted() {
+                session::give_back(reused);
+            }
+        }
+
+        if !exit_status.success() || interrupt::is_interrupted() {
+            break;
+        }
+    }
+
+    let log_path = match logs::finish(&log_path) {
+        Ok(path) => path,
+        Err(why) => {
+            warn!("failed to compress the build log at {:?}: {}", log_path, why);
+            log_path
+        }
+    };
+
+    if let Some(run) = run::current() {
+        if let Err(why) = run.link_log(&item.name, &log_path) {
+            warn!("failed to link the build log into run {}: {}", run.id, why);
+        }
+    }
+
+    if let Some(audit) = audit {
+        let attempts = audit.attempts();
+        if attempts.is_empty() {
+            info!("{} did not access the network during its build", item.name);
+        } else {
+            warn!("{} accessed the network during its build: {}", item.name, attempts.join(", "));
+        }
+    }
+
+    interrupted(item)?;
     if exit_status.success() {
-        Ok(())
+        Ok(log_path)
     } else {
-        Err(BuildError::Build { package: item.name.clone() })
+        let mut excerpt = match LogExcerpt::from_log(&log_path, config.log_excerpt_lines) {
+            Ok(excerpt) => excerpt,
+            Err(why) => {
+                warn!("failed to read the build log at {:?}: {}", log_path, why);
+                LogExcerpt::default()
+            }
+        };
+
+        let (_, changes) = dependencies::compare(pwd, &config.archive, &item.name, &log_path);
+        excerpt.dependencies = changes;
+
+        if !excerpt.is_empty() {
+            output::message(&format!("{} failed to build:\n{}", item.name, excerpt));
+        }
+
+        Err(BuildError::Build { package: item.name.clone(), log: log_path, excerpt })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn suites_defined_as_tables_have_records_of_their_own() {
+        let base = "archive = \"stable\"\nversion = \"1\"\norigin = \"o\"\nlabel = \"l\"\n\
+                    email = \"e\"\n";
+        let config: Config = ::toml::from_str(base).unwrap();
+        assert_eq!(record_path(&config, Path::new("."), "pop"), Path::new("./record/pop"));
+
+        let tables = [base, "[suites.stable]\n[suites.testing]\ninherits = \"stable\"\n"].concat();
+        let config: Config = ::toml::from_str(&tables).unwrap();
+        let testing = config.for_suite("testing");
+        let expected = Path::new("./record/.suites/testing/pop");
+        assert_eq!(record_path(&testing, Path::new("."), "pop"), expected);
+    }
+
+    #[test]
+    fn sources_are_built_for_the_architectures_of_both_their_suite_and_themselves() {
+        let base = "archive = \"jammy\"\nversion = \"1\"\norigin = \"o\"\nlabel = \"l\"\n\
+                    email = \"e\"\narchitectures = [\"amd64\", \"arm64\", \"all\"]\n";
+        let sources = [
+            base,
+            "[[source]]\nname = \"any\"\n",
+            "[[source]]\nname = \"i386\"\narchitectures = [\"i386\"]\n",
+            "[[source]]\nname = \"arm64\"\narchitectures = [\"arm64\", \"i386\"]\n",
+            "[[source]]\nname = \"indep\"\narchitectures = [\"all\"]\n",
+        ].concat();
+        let config: Config = ::toml::from_str(&sources).unwrap();
+        let suite = config.architectures.clone().unwrap();
+        let units = config.build_units();
+        let unbuildable: Vec<Option<String>> = units.iter()
+            .map(|item| unbuildable(item, &suite, "amd64", "jammy"))
+            .collect();
+
+        // Architectures which a source gives are built with sbuild's --arch.
+        assert_eq!(unbuildable, vec![
+            None,
+            Some("none of its architectures are built for jammy".to_owned()),
+            None,
+            None,
+        ]);
+
+        let built: Vec<Vec<String>> = units.iter()
+            .map(|item| item.build_architectures(&suite, "amd64"))
+            .collect();
+        assert_eq!(built, vec![
+            vec!["amd64".to_owned(), "all".to_owned()],
+            vec![],
+            vec!["arm64".to_owned()],
+            vec!["all".to_owned()],
+        ]);
+
+        let arm64 = vec!["arm64".to_owned()];
+        assert_eq!(
+            super::unbuildable(&units[0], &arm64, "amd64", "jammy"),
+            Some("built for arm64 in jammy, but this system builds amd64".to_owned())
+        );
+    }
+
+    #[test]
+    fn dry_runs_compare_the_commits_of_checkouts_to_their_records() {
+        let dir = TempDir::new("debrep-dry-run").unwrap();
+        let pwd = dir.path();
+        let config: Config = ::toml::from_str(
+            "archive = \"bionic\"\nversion = \"1\"\norigin = \"o\"\nlabel = \"l\"\n\
+             email = \"e\"\narchitectures = [\"amd64\", \"all\"]\n\
+             [[source]]\nname = \"pop-shop\"\nbuild_on = \"commit\"\n\
+             location = { url = \"https://github.com/pop-os/shop.git\" }\n\
+             [[source]]\nname = \"pop-icons\"\nbuild_on = \"commit\"\n\
+             location = { url = \"https://github.com/pop-os/icons.git\" }\n"
+        ).unwrap();
+        let items = config.build_units();
+        let intent = |name: &str| {
+            dry_run(&config, pwd, "amd64", &items, Force::No).into_iter()
+                .find(|intent| match *intent {
+                    Intent::Build { name: ref x, .. } | Intent::Skip { name: ref x, .. } => {
+                        x == name
+                    }
+                    _ => false,
+                })
+                .unwrap()
+        };
+
+        let why = "it is built once it is fetched".to_owned();
+        assert_eq!(intent("pop-shop"), Intent::Build { name: "pop-shop".into(), why });
+
+        fs::create_dir_all(pwd.join("record")).unwrap();
+        for &(name, architectures) in &[("shop", "amd64"), ("icons", "amd64 all")] {
+            let git = pwd.join("build").join(name).join(".git");
+            fs::create_dir_all(git.join("refs/heads")).unwrap();
+            misc::write(git.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+            misc::write(git.join("refs/heads/master"), "1a2b3c4d5e6f7a8b\n").unwrap();
+
+            let commit = record::Commit {
+                branch: "master".into(),
+                commit: "1a2b3c4d5e6f7a8b".into(),
+                run: None,
+            };
+            let architectures = architectures.split(' ').map(String::from).collect();
+            let entry = BuildRecord {
+                record: Record::Commits(vec![commit]),
+                architectures: Some(architectures),
+                built: None,
+                distribution: None,
+            };
+            record::save(&pwd.join("record").join(["pop-", name].concat()), &entry).unwrap();
+        }
+
+        let why = "commit master@1a2b3c4d5e6f was built, but not for all".to_owned();
+        assert_eq!(intent("pop-shop"), Intent::Build { name: "pop-shop".into(), why });
+        let why = "already built at commit master@1a2b3c4d5e6f".to_owned();
+        assert_eq!(intent("pop-icons"), Intent::Skip { name: "pop-icons".into(), why });
+    }
+
+    #[test]
+    fn git_errors_include_stderr() {
+        let dir = TempDir::new("debrep-git").unwrap();
+        let why = Cmd::new("git")
+            .arg("clone")
+            .arg(dir.path().join("missing"))
+            .arg(dir.path().join("clone"))
+            .run()
+            .unwrap_err();
+
+        let error = BuildError::GitBranch { package: "pkg".into(), branch: "master".into(), why };
+        let message = error.to_string();
+        assert!(message.starts_with("failed to switch to branch master on pkg: "), "{}", message);
+        assert!(message.contains("fatal: repository"), "{}", message);
     }
 }
@@ -0,0 +1,213 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+/// A proxy which refuses every connection, since nothing listens on the discard port.
+pub const BLACKHOLE: &str = "http://127.0.0.1:9/";
+
+/// The most that is read of a request to the proxy before giving up on it.
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// The variables which point the tools of a build at the given proxy.
+pub fn proxy_variables(proxy: &str) -> Vec<(String, String)> {
+    let names = ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"];
+    let mut variables: Vec<(String, String)> = names.iter()
+        .map(|variable| (variable.to_string(), proxy.to_owned()))
+        .collect();
+
+    variables.push(("no_proxy".to_owned(), String::new()));
+    variables
+}
+
+/// A local HTTP proxy which lets every connection through, but records where each one went.
+pub struct AuditProxy {
+    port: u16,
+    attempts: Arc<Mutex<Vec<String>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl AuditProxy {
+    pub fn start() -> io::Result<AuditProxy> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        {
+            let attempts = attempts.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for client in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break
+                    }
+
+                    if let Ok(client) = client {
+                        let attempts = attempts.clone();
+                        thread::spawn(move || {
+                            if let Err(why) = relay(client, &attempts) {
+                                debug!("audit proxy connection failed: {}", why);
+                            }
+                        });
+                    }
+                }
+            });
+        }
+
+        Ok(AuditProxy { port, attempts, stopped })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}/", self.port)
+    }
+
+    /// The hosts which were connected to, in the order they were first connected to.
+    pub fn attempts(&self) -> Vec<String> {
+        self.attempts.lock().unwrap().clone()
+    }
+}
+
+impl Drop for AuditProxy {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes the listener so that it sees that it was stopped.
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+/// Records the destination of a request to the proxy, and relays it there.
+fn relay(mut client: TcpStream, attempts: &Mutex<Vec<String>>) -> io::Result<()> {
+    let head = read_head(&mut client)?;
+    let (connect, host) = match parse_request(&head) {
+        Some(request) => request,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed request")),
+    };
+
+    {
+        let mut attempts = attempts.lock().unwrap();
+        if !attempts.contains(&host) {
+            attempts.push(host.clone());
+        }
+    }
+
+    let mut upstream = match TcpStream::connect(host.as_str()) {
+        Ok(upstream) => upstream,
+        Err(why) => {
+            client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")?;
+            return Err(why);
+        }
+    };
+
+    if connect {
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+    } else {
+        upstream.write_all(&head)?;
+    }
+
+    let mut client_reader = client.try_clone()?;
+    let mut upstream_writer = upstream.try_clone()?;
+    let sending = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut upstream_writer);
+        let _ = upstream_writer.shutdown(Shutdown::Write);
+    });
+
+    let _ = io::copy(&mut upstream, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+    let _ = sending.join();
+    Ok(())
+}
+
+fn read_head(client: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_REQUEST_HEAD || client.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete request"));
+        }
+        head.push(byte[0]);
+    }
+
+    Ok(head)
+}
+
+/// Whether the request is a `CONNECT`, and the `host:port` that it is for.
+fn parse_request(head: &[u8]) -> Option<(bool, String)> {
+    let head = String::from_utf8_lossy(head);
+    let mut fields = head.lines().next()?.split_whitespace();
+    let method = fields.next()?;
+    let target = fields.next()?;
+
+    if method == "CONNECT" {
+        return Some((true, target.to_owned()));
+    }
+
+    let authority = target.splitn(2, "://").nth(1)?.split('/').next()?;
+    let host = if authority.contains(':') {
+        authority.to_owned()
+    } else {
+        [authority, ":80"].concat()
+    };
+
+    Some((false, host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use std::collections::BTreeMap;
+    use super::super::environment::write_sbuild_config;
+    use tempdir::TempDir;
+
+    #[test]
+    fn requests_are_parsed() {
+        assert_eq!(
+            parse_request(b"CONNECT registry.npmjs.org:443 HTTP/1.1\r\n\r\n"),
+            Some((true, "registry.npmjs.org:443".to_owned()))
+        );
+        assert_eq!(
+            parse_request(b"GET http://deb.debian.org/debian/ HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Some((false, "deb.debian.org:80".to_owned()))
+        );
+        assert_eq!(parse_request(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn connections_are_relayed_and_recorded() {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = origin.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = origin.accept().unwrap();
+            let _ = read_head(&mut stream);
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nhello").unwrap();
+        });
+
+        let proxy = AuditProxy::start().unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", proxy.port)).unwrap();
+        write!(client, "GET http://{}/file HTTP/1.0\r\n\r\n", address).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("hello"));
+        assert_eq!(proxy.attempts(), vec![address]);
+    }
+
+    #[test]
+    fn sbuild_config_sets_proxies() {
+        let dir = TempDir::new("debrep-network").unwrap();
+        let path = dir.path().join("sbuildrc");
+        let variables: BTreeMap<String, String> = proxy_variables(BLACKHOLE).into_iter().collect();
+        write_sbuild_config(&path, &variables, 0o022, true).unwrap();
+
+        let config = misc::read_to_string(&path).unwrap();
+        assert!(config.starts_with("do \"$ENV{HOME}/.sbuildrc\""));
+        assert!(config.contains("    'https_proxy' => 'http://127.0.0.1:9/',\n"));
+        assert!(config.contains("    'no_proxy' => '',\n"));
+
+        // The proxies only stop tools which honor them, so the build has no network either.
+        let isolation = "$chroot_mode = 'unshare';\n$enable_network = 0;\n1;\n";
+        assert!(config.ends_with(isolation));
+    }
+}
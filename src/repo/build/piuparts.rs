@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use command::{self, Cmd};
+use config::PiupartsConfig;
+use libc;
+use misc;
+use tempdir::TempDir;
+
+/// The members of a control archive which are run while a package is installed or removed.
+const SCRIPTS: &[&str] = &["preinst", "postinst", "prerm", "postrm", "config", "triggers"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Passed,
+    Failed,
+    /// Piuparts was not run, because nothing it checks changed since the previous version.
+    Unchanged,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Verdict::Passed => "piuparts passed",
+            Verdict::Failed => "piuparts failed",
+            Verdict::Unchanged => "piuparts skipped, no maintainer scripts or conffiles changed",
+        })
+    }
+}
+
+/// What piuparts checks of a package: its maintainer scripts, and its conffiles and their
+/// digests.
+#[derive(Debug, Default, PartialEq)]
+pub struct Control {
+    scripts: BTreeMap<String, Vec<u8>>,
+    conffiles: BTreeMap<String, Option<String>>,
+}
+
+impl Control {
+    /// Reads the control archive of a `.deb` file.
+    pub fn of(deb: &Path) -> io::Result<Control> {
+        let dir = TempDir::new("debrep-control")?;
+        Cmd::new("dpkg-deb").arg("--control").arg(deb).arg(dir.path()).run()?;
+        Control::read(dir.path())
+    }
+
+    /// Reads a control archive which was extracted into the directory.
+    fn read(dir: &Path) -> io::Result<Control> {
+        let mut control = Control::default();
+        for script in SCRIPTS {
+            let path = dir.join(script);
+            if path.exists() {
+                control.scripts.insert(script.to_string(), misc::read(&path)?);
+            }
+        }
+
+        let conffiles = dir.join("conffiles");
+        if conffiles.exists() {
+            let digests = match misc::read_to_string(dir.join("md5sums")) {
+                Ok(digests) => digests,
+                Err(ref why) if why.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(why) => return Err(why),
+            };
+
+            for conffile in misc::read_to_string(&conffiles)?.lines().map(|x| x.trim()) {
+                if conffile.is_empty() {
+                    continue;
+                }
+
+                // `md5sums` lists paths without their leading slash.
+                let digest = digests.lines()
+                    .filter_map(|line| {
+                        let mut fields = line.splitn(2, "  ");
+                        Some((fields.next()?, fields.next()?))
+                    })
+                    .find(|&(_, path)| path == conffile.trim_left_matches('/'))
+                    .map(|(digest, _)| digest.to_owned());
+
+                control.conffiles.insert(conffile.to_owned(), digest);
+            }
+        }
+
+        Ok(control)
+    }
+}
+
+/// Whether any of the packages differ from their previously published versions in what piuparts
+/// checks. Packages which were not published before have always changed.
+pub fn changed(debs: &[PathBuf], previous: &[PathBuf]) -> io::Result<bool> {
+    for deb in debs {
+        let last = match latest_previous(deb, previous)? {
+            Some(last) => last,
+            None => return Ok(true),
+        };
+
+        if Control::of(deb)? != Control::of(&last)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// The most recently published version of the package.
+fn latest_previous(deb: &Path, previous: &[PathBuf]) -> io::Result<Option<PathBuf>> {
+    let name = |path: &Path| -> Option<String> {
+        let filename = path.file_name()?.to_str()?;
+        filename.find('_').map(|pos| filename[..pos].to_owned())
+    };
+
+    let mut latest = None;
+    for path in previous.iter().filter(|path| name(path) == name(deb)) {
+        let modified = path.metadata()?.modified()?;
+        if latest.as_ref().map_or(true, |&(time, _)| modified > time) {
+            latest = Some((modified, path.clone()));
+        }
+    }
+
+    Ok(latest.map(|(_, path)| path))
+}
+
+/// Runs piuparts on the packages in the chroot, with the repo as an additional apt source once it
+/// has been published, writing its output to the log. Piuparts is run with `sudo` unless this is
+/// already running as root.
+pub fn run(
+    config: Option<&PiupartsConfig>,
+    chroot: &str,
+    repo: &Path,
+    archive: &str,
+    debs: &[PathBuf],
+    log: File,
+) -> io::Result<Verdict> {
+    let mut command = if unsafe { libc::geteuid() } == 0 {
+        command::heavy("piuparts")
+    } else {
+        let mut command = command::heavy("sudo");
+        command.args(&["-n", "piuparts"]);
+        command
+    };
+
+    command.arg(["--schroot=", chroot].concat());
+    if repo.join("dists").join(archive).join("Release").exists() {
+        let repo = repo.canonicalize()?;
+        let source = format!("deb [trusted=yes] file://{} {} main", repo.display(), archive);
+        command.arg(["--extra-repo=", &source].concat()).arg("--bindmount").arg(&repo);
+    }
+
+    if let Some(args) = config.and_then(|config| config.args.as_ref()) {
+        command.args(args);
+    }
+
+    let status = command.args(debs)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .status()?;
+
+    Ok(if status.success() { Verdict::Passed } else { Verdict::Failed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn control_archives_are_compared() {
+        let root = TempDir::new("debrep-piuparts").unwrap();
+        let old = root.path().join("old");
+        let new = root.path().join("new");
+        for dir in &[&old, &new] {
+            fs::create_dir(dir).unwrap();
+            misc::write(dir.join("postinst"), b"#!/bin/sh\nset -e\n").unwrap();
+            misc::write(dir.join("conffiles"), b"/etc/pop-shop.conf\n").unwrap();
+            misc::write(dir.join("control"), b"Package: pop-shop\n").unwrap();
+        }
+
+        misc::write(old.join("md5sums"), b"aaaa  etc/pop-shop.conf\nbbbb  usr/bin/pop-shop\n")
+            .unwrap();
+        misc::write(new.join("md5sums"), b"aaaa  etc/pop-shop.conf\ncccc  usr/bin/pop-shop\n")
+            .unwrap();
+
+        let control = Control::read(&old).unwrap();
+        assert_eq!(control.conffiles.get("/etc/pop-shop.conf"), Some(&Some("aaaa".to_owned())));
+        assert_eq!(control, Control::read(&new).unwrap());
+
+        misc::write(new.join("md5sums"), b"dddd  etc/pop-shop.conf\n").unwrap();
+        assert!(control != Control::read(&new).unwrap());
+
+        misc::write(new.join("md5sums"), b"aaaa  etc/pop-shop.conf\n").unwrap();
+        misc::write(new.join("prerm"), b"#!/bin/sh\n").unwrap();
+        assert!(control != Control::read(&new).unwrap());
+    }
+}
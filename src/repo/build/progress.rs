@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+use logging;
+use output::format_duration;
+use super::BuildOptions;
+use super::super::state;
+
+/// How long each package took to build the last time that it was built, in seconds, which is
+/// written with `--legacy-records`. The durations are otherwise taken from the state database.
+const DURATIONS: &str = "record/.durations";
+
+/// Tracks how far along a run is, and displays it either as a status line on the terminal, or as
+/// a log message whenever a package is started.
+pub struct Progress {
+    state: Arc<Mutex<State>>,
+    /// Where durations are written with `--legacy-records`.
+    history: Option<PathBuf>,
+    status_line: bool,
+}
+
+struct State {
+    total: usize,
+    completed: usize,
+    started: Instant,
+    /// Units of work which are currently in progress, and when they were started.
+    current: Vec<(String, Instant)>,
+    pending: Vec<String>,
+    durations: HashMap<String, u64>,
+    finished: bool,
+}
+
+impl Progress {
+    pub fn new(packages: Vec<String>, options: BuildOptions) -> Progress {
+        let pwd = env::current_dir().unwrap_or_default();
+        let durations = state::State::open(&pwd)
+            .and_then(|state| state.durations())
+            .unwrap_or_else(|why| {
+                warn!("failed to read how long packages took to build before: {}", why);
+                HashMap::new()
+            });
+        let history = if options.legacy_records { Some(pwd.join(DURATIONS)) } else { None };
+
+        let state = Arc::new(Mutex::new(State {
+            total: packages.len(),
+            completed: 0,
+            started: Instant::now(),
+            current: Vec::new(),
+            pending: packages,
+            durations,
+            finished: false,
+        }));
+
+        // Streamed build output would be written over the status line.
+        let status_line = !options.follow && !options.ci && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+        if status_line {
+            let state = state.clone();
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+                let state = state.lock().unwrap();
+                if state.finished {
+                    break
+                }
+                logging::set_status(Some(state.status(Instant::now(), terminal_width())));
+            });
+        }
+
+        Progress { state, history, status_line }
+    }
+
+    /// Marks a unit of work as having started.
+    pub fn start(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.pending.retain(|x| x != name);
+        state.current.push((name.to_owned(), now));
+
+        if self.status_line {
+            logging::set_status(Some(state.status(now, terminal_width())));
+        } else {
+            info!("{}", state.status(now, 0));
+        }
+    }
+
+    /// Marks a unit of work as completed, recording how long it took if it was built.
+    pub fn complete(&self, name: &str, built: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.completed += 1;
+
+        let position = state.current.iter().position(|&(ref x, _)| x == name);
+        if let Some(position) = position {
+            let (_, started) = state.current.remove(position);
+            if built {
+                state.durations.insert(name.to_owned(), started.elapsed().as_secs());
+                if let Some(ref history) = self.history {
+                    if let Err(why) = save_durations(history, &state.durations) {
+                        warn!("failed to record build durations in {:?}: {}", history, why);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Marks a unit of work as completed without starting it.
+    pub fn skip(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.retain(|x| x != name);
+        state.completed += 1;
+    }
+
+    /// Stops updating the status line, and removes it from the terminal.
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !mem::replace(&mut state.finished, true) && self.status_line {
+            logging::set_status(None);
+        }
+    }
+}
+
+impl State {
+    /// The estimated number of seconds until every unit of work is complete. Units which have
+    /// never been built before are assumed to take as long as the average of those which have.
+    fn eta(&self, now: Instant) -> Option<u64> {
+        let average = if self.durations.is_empty() {
+            None
+        } else {
+            Some(self.durations.values().sum::<u64>() / self.durations.len() as u64)
+        };
+
+        let estimate = |name: &str| self.durations.get(name).cloned().or(average);
+
+        let mut remaining = 0;
+        for name in &self.pending {
+            remaining += estimate(name)?;
+        }
+
+        for &(ref name, started) in &self.current {
+            remaining += estimate(name)?.saturating_sub((now - started).as_secs());
+        }
+
+        Some(remaining)
+    }
+
+    /// Describes the progress of the run, limited to `width` characters if it is non-zero.
+    fn status(&self, now: Instant, width: usize) -> String {
+        let mut status = format!("[{}/{}]", self.completed, self.total);
+
+        if !self.current.is_empty() {
+            status.push_str(" building ");
+            let names: Vec<&str> = self.current.iter().map(|&(ref name, _)| name.as_str()).collect();
+            status.push_str(&names.join(", "));
+        }
+
+        // Sources are started in the order of the queue.
+        if let Some(next) = self.pending.first() {
+            status.push_str("; next ");
+            status.push_str(next);
+        }
+
+        status.push_str(&format!("; {} elapsed", format_duration((now - self.started).as_secs())));
+        match self.eta(now) {
+            Some(eta) => status.push_str(&format!(", about {} left", format_duration(eta))),
+            None => status.push_str(", time left unknown"),
+        }
+
+        if width != 0 && status.chars().count() >= width {
+            status = status.chars().take(width - 1).collect();
+        }
+
+        status
+    }
+}
+
+fn save_durations(path: &Path, durations: &HashMap<String, u64>) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    for (name, seconds) in durations {
+        writeln!(file, "{} {}", seconds, name)?;
+    }
+
+    Ok(())
+}
+
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col != 0 => size.ws_col as usize,
+        _ => 80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(now: Instant) -> State {
+        let mut durations = HashMap::new();
+        durations.insert("a".to_owned(), 100);
+        durations.insert("b".to_owned(), 300);
+
+        State {
+            total: 4,
+            completed: 1,
+            started: now - Duration::from_secs(150),
+            current: vec![("b".to_owned(), now - Duration::from_secs(50))],
+            pending: vec!["a".to_owned(), "c".to_owned()],
+            durations,
+            finished: false,
+        }
+    }
+
+    #[test]
+    fn eta_uses_recorded_durations() {
+        let now = Instant::now();
+        let state = state(now);
+        // 250 seconds left of b, 100 for a, and the average of 200 for c.
+        assert_eq!(state.eta(now), Some(550));
+        assert_eq!(
+            state.status(now, 0),
+            "[1/4] building b; next a; 2m30s elapsed, about 9m10s left"
+        );
+        assert_eq!(state.status(now, 12), "[1/4] build");
+    }
+
+    #[test]
+    fn eta_is_unknown_without_history() {
+        let now = Instant::now();
+        let mut state = state(now);
+        state.durations.clear();
+        assert_eq!(state.eta(now), None);
+    }
+}
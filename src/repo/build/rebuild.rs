@@ -0,0 +1,190 @@
+//! No-change rebuilds of sources, such as for a new suite, or against updated dependencies.
+//!
+//! A source is rebuilt at the version of its changelog, unless the pool already has that
+//! version, in which case a changelog entry is added for the version with the suffix of the
+//! `rebuild_suffix` scheme, as a binNMU is. The suffixed version must sort after the version, and
+//! before the next revision of it, so that the rebuild replaces the package, and is replaced in
+//! turn by the next real change to it.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use misc;
+use super::super::keys;
+use super::super::pool::PoolIndex;
+use super::super::version::{self, ChangelogEntry};
+
+/// The reasons of rebuilds, with the suite, package, and version which was rebuilt.
+pub const REBUILDS: &str = "record/.rebuilds";
+
+/// The suffix of rebuilds when `rebuild_suffix` is unset, where `{n}` is the number of the
+/// rebuild of the version.
+pub const DEFAULT_SCHEME: &str = "+rebuild{n}";
+
+/// The version of the `n`th rebuild of the version.
+pub fn version(scheme: &str, version: &str, n: u32) -> String {
+    [version, &scheme.replace("{n}", &n.to_string())].concat()
+}
+
+/// The number of the next rebuild of the version of the source package, if the pool already has
+/// the version.
+pub fn next_number(pool: &PoolIndex, package: &str, base: &str, scheme: &str) -> Option<u32> {
+    // The names of pool files have no epoch.
+    let base = base.find(':').map_or(base, |pos| &base[pos + 1..]);
+    let versions: Vec<String> = pool.files().into_iter()
+        .filter(|file| file.section == "source" && file.name == package)
+        .filter_map(|file| file.path.file_name().map(|x| x.to_string_lossy().into_owned()))
+        .filter(|name| name.ends_with(".dsc"))
+        .filter_map(|name| {
+            let start = name.find('_')? + 1;
+            Some(name[start..name.len() - 4].to_owned())
+        })
+        .collect();
+
+    if !versions.iter().any(|x| x == base) {
+        return None;
+    }
+
+    let mut n = 1;
+    while versions.contains(&version(scheme, base, n)) {
+        n += 1;
+    }
+
+    Some(n)
+}
+
+/// Checks with dpkg that the rebuilt version sorts after the version, and before its next
+/// revision.
+pub fn check(base: &str, rebuilt: &str) -> Result<(), String> {
+    let compare = |a: &str, operator, b: &str| {
+        version::compare(a, operator, b).map_err(|why| format!("failed to run dpkg: {}", why))
+    };
+
+    if !version::is_valid(rebuilt) {
+        return Err(format!("{} is not a valid version", rebuilt));
+    } else if !compare(rebuilt, "gt", base)? {
+        return Err(format!("{} does not sort after {}", rebuilt, base));
+    }
+
+    let next = next_revision(base);
+    if !compare(rebuilt, "lt", &next)? {
+        return Err(format!("{} does not sort before the next revision, {}", rebuilt, next));
+    }
+
+    Ok(())
+}
+
+/// The version with its last number incremented, such as `1.0-2` for `1.0-1`, which is the least
+/// that a real change to the package would be versioned as.
+fn next_revision(version: &str) -> String {
+    let end = version.rfind(|c: char| c.is_ascii_digit()).map_or(version.len(), |x| x + 1);
+    let start = version[..end].rfind(|c: char| !c.is_ascii_digit()).map_or(0, |x| x + 1);
+    match version[start..end].parse::<u64>() {
+        Ok(number) => [&version[..start], &(number + 1).to_string(), &version[end..]].concat(),
+        Err(_) => [version, "1"].concat(),
+    }
+}
+
+/// Adds an entry for the rebuilt version to the top of the changelog, by the maintainer of the
+/// newest entry, or else the email of the repo.
+pub fn add_entry(
+    path: &Path,
+    rebuilt: &str,
+    suite: &str,
+    reason: Option<&str>,
+    email: &str,
+) -> io::Result<()> {
+    let changelog = misc::read_to_string(path)?;
+    let entry = ChangelogEntry::latest(&changelog).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "the changelog has no entries")
+    })?;
+
+    let maintainer = changelog.lines()
+        .find(|line| line.starts_with(" -- "))
+        .and_then(|line| line.find(">  ").map(|pos| line[4..pos + 1].to_owned()))
+        .unwrap_or_else(|| ["<", email, ">"].concat());
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    let header = [&entry.package, " (", rebuilt, ") ", suite, "; urgency=medium\n\n"].concat();
+    let body = match reason {
+        Some(reason) => ["  * No-change rebuild: ", reason, "\n\n"].concat(),
+        None => "  * No-change rebuild.\n\n".to_owned(),
+    };
+    let trailer = [" -- ", &maintainer, "  ", &changelog_date(now), "\n\n"].concat();
+    misc::write(path, [header, body, trailer, changelog].concat().as_bytes())
+}
+
+/// A date as changelogs have them, such as `Mon, 16 Jul 2018 12:00:00 +0000`.
+fn changelog_date(seconds: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let date = keys::date(seconds);
+    let mut fields = date.split('-').map(|x| x.parse::<usize>().unwrap_or(1));
+    let (year, month, day) = (
+        fields.next().unwrap_or(1970),
+        fields.next().unwrap_or(1),
+        fields.next().unwrap_or(1),
+    );
+
+    let time = seconds % 86_400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(seconds / 86_400 % 7) as usize],
+        day,
+        MONTHS[month - 1],
+        year,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Records why the version of the package was rebuilt for the suite, and by which run.
+pub fn record(
+    pwd: &Path,
+    suite: &str,
+    package: &str,
+    rebuilt: &str,
+    run: Option<&str>,
+    reason: Option<&str>,
+) -> io::Result<()> {
+    let fields = [suite, package, rebuilt, run.unwrap_or("-"), reason.unwrap_or("-")];
+    let line = [&fields.join("\t"), "\n"].concat();
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(pwd.join(REBUILDS))
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebuilds_sort_between_the_version_and_its_next_revision() {
+        assert_eq!(next_revision("1.0-1"), "1.0-2");
+        assert_eq!(next_revision("0pop9"), "0pop10");
+        assert_eq!(next_revision("2:1.0-1ubuntu1"), "2:1.0-1ubuntu2");
+
+        let rebuilt = version(DEFAULT_SCHEME, "1.0-1", 2);
+        assert_eq!(rebuilt, "1.0-1+rebuild2");
+        assert_eq!(check("1.0-1", &rebuilt), Ok(()));
+        assert_eq!(check("1.0-1", &version("{n}", "1.0-1", 1)), Err(
+            "1.0-11 does not sort before the next revision, 1.0-2".to_owned()
+        ));
+        assert_eq!(check("1.0-1", &version("~rebuild{n}", "1.0-1", 1)), Err(
+            "1.0-1~rebuild1 does not sort after 1.0-1".to_owned()
+        ));
+    }
+
+    #[test]
+    fn changelog_dates() {
+        assert_eq!(changelog_date(1_531_742_400), "Mon, 16 Jul 2018 12:00:00 +0000");
+        assert_eq!(changelog_date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+    }
+}
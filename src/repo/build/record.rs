@@ -0,0 +1,442 @@
+//! The records of what each package was last built from, which are kept in the state database,
+//! and in `record/<package>` with `--legacy-records`.
+//!
+//! A record starts with a header, which is followed by a `BuildRecord` as TOML: the `build_on`
+//! rule which the package was built on, with the changelog version, the checksum of the tree, or
+//! each commit which was built, when it was built, the distribution which sbuild built it for, and
+//! the architectures which were built, so that adding an architecture to a source only builds
+//! that architecture. It ends with the SHA256 digest of what precedes it, so that a record which
+//! was cut short or changed is detected. Record files are written to a temporary file which is
+//! synced, and renamed over the record.
+//!
+//! Records of earlier formats, which listed the rule and what was built on lines of their own,
+//! are still read, and are rewritten in the current format when they are first read by a build.
+//! Those which have no architectures were built for the architecture of the system, and `all`.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use toml;
+
+/// The first line of every record of the current format.
+const HEADER: &str = "debrep-record 2";
+
+/// The first line of records which listed what was built on lines, and had a digest.
+const LINES_HEADER: &str = "debrep-record 1";
+
+/// The start of the line of the architectures which were built, in records of lines. Branches
+/// cannot have a `:` in their names, so it is never taken for a commit.
+const ARCHITECTURES: &str = "architectures:";
+
+/// What a package was last built from, when, and for which distribution and architectures.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildRecord {
+    pub record: Record,
+    pub architectures: Option<Vec<String>>,
+    /// When it was built, in seconds since the Unix epoch, which records of earlier formats lack.
+    pub built: Option<u64>,
+    /// The distribution which sbuild built it for.
+    pub distribution: Option<String>,
+}
+
+/// What a package was last built from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    /// The version of the changelog, and the run which built it.
+    Changelog { version: String, run: Option<String> },
+    /// The digest of the source tree, and the run which built it.
+    Checksum { digest: String, run: Option<String> },
+    /// Each commit which was built, oldest first.
+    Commits(Vec<Commit>),
+}
+
+/// A commit of a branch which was built, and the run which built it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Commit {
+    pub branch: String,
+    pub commit: String,
+    pub run: Option<String>,
+}
+
+/// A record as it is saved, after its header.
+#[derive(Debug, Deserialize, Serialize)]
+struct Stored {
+    /// The `build_on` rule: `changelog`, `checksum`, or `commit`.
+    rule: String,
+    version: Option<String>,
+    digest: Option<String>,
+    run: Option<String>,
+    built: Option<u64>,
+    distribution: Option<String>,
+    architectures: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    commits: Vec<Commit>,
+}
+
+impl Record {
+    /// Whether the package was built at this commit of the branch.
+    pub fn has_commit(&self, branch: &str, commit: &str) -> bool {
+        match *self {
+            Record::Commits(ref commits) => {
+                commits.iter().any(|x| x.branch == branch && x.commit == commit)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the last commit which the package was built at is this commit of the branch.
+    pub fn has_latest_commit(&self, branch: &str, commit: &str) -> bool {
+        match *self {
+            Record::Commits(ref commits) => {
+                commits.last().map_or(false, |x| x.branch == branch && x.commit == commit)
+            }
+            _ => false,
+        }
+    }
+
+    /// Parses the lines of a record of an earlier format, or `None` if they are not a record.
+    fn parse(body: &str) -> Option<Record> {
+        let mut lines = body.lines().map(str::trim);
+        let field = |line: Option<&str>| match line {
+            Some(line) if !line.is_empty() => Some(line.to_owned()),
+            _ => None,
+        };
+
+        match lines.next()? {
+            "changelog" => {
+                let version = field(lines.next())?;
+                Some(Record::Changelog { version, run: field(lines.next()) })
+            }
+            "checksum" => {
+                let digest = field(lines.next())?;
+                Some(Record::Checksum { digest, run: field(lines.next()) })
+            }
+            "commit" => {
+                let mut commits = Vec::new();
+                for line in lines.filter(|x| !x.is_empty()) {
+                    let mut fields = line.split_whitespace();
+                    let branch = fields.next()?.to_owned();
+                    let commit = fields.next()?.to_owned();
+                    commits.push(Commit { branch, commit, run: fields.next().map(String::from) });
+                }
+
+                if commits.is_empty() { None } else { Some(Record::Commits(commits)) }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl BuildRecord {
+    /// The architectures which were built, or those which builds built before they were
+    /// recorded.
+    pub fn architectures(&self, host: &str) -> Vec<String> {
+        self.architectures.clone().unwrap_or_else(|| vec![host.to_owned(), "all".to_owned()])
+    }
+
+    /// Those of the architectures which were not built.
+    pub fn missing(&self, architectures: &[String], host: &str) -> Vec<String> {
+        let built = self.architectures(host);
+        architectures.iter().filter(|arch| !built.contains(arch)).cloned().collect()
+    }
+
+    fn stored(&self) -> Stored {
+        let mut stored = Stored {
+            rule: String::new(),
+            version: None,
+            digest: None,
+            run: None,
+            built: self.built,
+            distribution: self.distribution.clone(),
+            architectures: self.architectures.clone(),
+            commits: Vec::new(),
+        };
+
+        match self.record {
+            Record::Changelog { ref version, ref run } => {
+                stored.rule = "changelog".into();
+                stored.version = Some(version.clone());
+                stored.run = run.clone();
+            }
+            Record::Checksum { ref digest, ref run } => {
+                stored.rule = "checksum".into();
+                stored.digest = Some(digest.clone());
+                stored.run = run.clone();
+            }
+            Record::Commits(ref commits) => {
+                stored.rule = "commit".into();
+                stored.commits = commits.clone();
+            }
+        }
+
+        stored
+    }
+
+    fn from_stored(stored: Stored) -> Result<BuildRecord, &'static str> {
+        let record = match (stored.rule.as_str(), stored.version, stored.digest) {
+            ("changelog", Some(version), _) => Record::Changelog { version, run: stored.run },
+            ("checksum", _, Some(digest)) => Record::Checksum { digest, run: stored.run },
+            ("commit", _, _) if !stored.commits.is_empty() => Record::Commits(stored.commits),
+            ("changelog", _, _) | ("checksum", _, _) | ("commit", _, _) => {
+                return Err("it is incomplete")
+            }
+            _ => return Err("its rule is unknown"),
+        };
+
+        Ok(BuildRecord {
+            record,
+            architectures: stored.architectures,
+            built: stored.built,
+            distribution: stored.distribution,
+        })
+    }
+
+    /// Parses the lines of a record of an earlier format.
+    fn parse_lines(body: &str) -> Option<BuildRecord> {
+        let mut architectures = None;
+        let mut lines = Vec::new();
+        for line in body.lines() {
+            if line.starts_with(ARCHITECTURES) {
+                let listed = line[ARCHITECTURES.len()..].split_whitespace().map(String::from);
+                architectures = Some(listed.collect());
+            } else {
+                lines.push(line);
+            }
+        }
+
+        Record::parse(&lines.join("\n")).map(|record| BuildRecord {
+            record,
+            architectures,
+            built: None,
+            distribution: None,
+        })
+    }
+
+    /// The record as it is saved, with its header and digest.
+    pub fn serialize(&self) -> String {
+        // The fields of a record are all of types which TOML has.
+        let body = toml::to_string(&self.stored()).expect("a record is always valid TOML");
+        let contents = [HEADER, "\n", &body].concat();
+        let digest = digest(&contents);
+        [&contents, "sha256 ", &digest, "\n"].concat()
+    }
+
+    /// Reads a record from the contents of its file, or why it is not a record.
+    fn deserialize(contents: &str) -> Result<BuildRecord, &'static str> {
+        let header = [HEADER, LINES_HEADER].iter().cloned().find(|&x| contents.starts_with(x));
+        let header = match header {
+            Some(header) => header,
+            None => {
+                // Records from before the header have no digest, and are trusted as they are.
+                let legacy = ["changelog\n", "checksum\n", "commit\n"];
+                if !legacy.iter().any(|kind| contents.starts_with(kind)) {
+                    return Err("it has no header");
+                }
+
+                return BuildRecord::parse_lines(contents).ok_or("it is incomplete");
+            }
+        };
+
+        if !contents.ends_with('\n') {
+            return Err("it was cut short");
+        }
+
+        let trailer = contents[..contents.len() - 1].rfind('\n').map_or(0, |x| x + 1);
+        let (signed, trailer) = contents.split_at(trailer);
+        let mut fields = trailer.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("sha256"), Some(expected), None) if expected == digest(signed) => (),
+            (Some("sha256"), Some(_), None) => return Err("its digest does not match"),
+            _ => return Err("it was cut short"),
+        }
+
+        let body = signed.get(header.len() + 1..).ok_or("it is incomplete")?;
+        if header == LINES_HEADER {
+            return BuildRecord::parse_lines(body).ok_or("it is incomplete");
+        }
+
+        toml::from_str(body)
+            .map_err(|_| "it is not valid TOML")
+            .and_then(BuildRecord::from_stored)
+    }
+}
+
+/// Whether the record was saved in the current format, rather than one which is rewritten when
+/// it is next read by a build.
+pub fn is_current(contents: &str) -> bool {
+    contents.starts_with(HEADER)
+}
+
+fn digest(contents: &str) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(contents.as_bytes());
+    format!("{:x}", hasher.result())
+}
+
+/// Reads the record of the package from what was saved of it. A record which is corrupt is
+/// `None`, so that the package is built as if it never was.
+pub fn load(contents: &str, package: &str) -> Option<BuildRecord> {
+    match BuildRecord::deserialize(contents) {
+        Ok(record) => Some(record),
+        Err(why) => {
+            warn!("the record of {} is corrupt, so it is ignored: {}", package, why);
+            None
+        }
+    }
+}
+
+/// Writes the record to the path, as the record files of `--legacy-records` are.
+pub fn save(path: &Path, entry: &BuildRecord) -> io::Result<()> {
+    write_atomic(path, entry.serialize().as_bytes())
+}
+
+/// Writes the file next to the path, syncs it, and moves it over the path, so that the file is
+/// never half-written if debrep is stopped, or the system crashes.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let name = path.file_name().map_or_else(String::new, |x| x.to_string_lossy().into_owned());
+    let temporary = path.with_file_name([".", &name, ".partial"].concat());
+    {
+        let mut file = File::create(&temporary)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+
+    fs::rename(&temporary, path)?;
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use tempdir::TempDir;
+
+    fn commits() -> Record {
+        Record::Commits(vec![
+            Commit { branch: "master".into(), commit: "0a1b2c".into(), run: None },
+            Commit {
+                branch: "master".into(),
+                commit: "3d4e5f".into(),
+                run: Some("20181016-101500-1234".into()),
+            },
+        ])
+    }
+
+    fn entry(record: Record) -> BuildRecord {
+        BuildRecord { record, architectures: None, built: None, distribution: None }
+    }
+
+    #[test]
+    fn records_are_saved_and_loaded() {
+        let dir = TempDir::new("debrep-record").unwrap();
+        let path = dir.path().join("pop-shop");
+
+        let architectures = Some(vec!["amd64".to_owned(), "i386".to_owned(), "all".to_owned()]);
+        let records = vec![
+            entry(commits()),
+            BuildRecord {
+                architectures: architectures.clone(),
+                built: Some(1_539_685_500),
+                distribution: Some("bionic".into()),
+                ..entry(commits())
+            },
+            entry(Record::Changelog {
+                version: "1.0.0".into(),
+                run: Some("20181016-101500-1234".into()),
+            }),
+            BuildRecord {
+                architectures,
+                ..entry(Record::Checksum { digest: "ab".repeat(32), run: None })
+            },
+        ];
+        for record in records {
+            save(&path, &record).unwrap();
+            let contents = misc::read_to_string(&path).unwrap();
+            assert!(is_current(&contents));
+            assert_eq!(load(&contents, "pop-shop"), Some(record));
+        }
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn records_are_toml() {
+        let record = BuildRecord {
+            architectures: Some(vec!["amd64".to_owned(), "all".to_owned()]),
+            built: Some(1_539_685_500),
+            distribution: Some("bionic".into()),
+            ..entry(commits())
+        };
+
+        let contents = record.serialize();
+        let end = contents.rfind("sha256 ").unwrap();
+        assert_eq!(
+            &contents[..end],
+            "debrep-record 2\n\
+             rule = \"commit\"\n\
+             built = 1539685500\n\
+             distribution = \"bionic\"\n\
+             architectures = [\"amd64\", \"all\"]\n\
+             \n\
+             [[commits]]\n\
+             branch = \"master\"\n\
+             commit = \"0a1b2c\"\n\
+             \n\
+             [[commits]]\n\
+             branch = \"master\"\n\
+             commit = \"3d4e5f\"\n\
+             run = \"20181016-101500-1234\"\n"
+        );
+    }
+
+    #[test]
+    fn records_of_earlier_formats_are_read() {
+        let legacy = "commit\nmaster 0a1b2c\nmaster 3d4e5f 20181016-101500-1234";
+        assert_eq!(BuildRecord::deserialize(legacy), Ok(entry(commits())));
+        assert_eq!(
+            BuildRecord::deserialize("changelog\n1.0.0\n"),
+            Ok(entry(Record::Changelog { version: "1.0.0".into(), run: None }))
+        );
+        assert!(BuildRecord::deserialize("commit\n").is_err());
+        assert!(!is_current(legacy));
+
+        // Records of lines with a digest, which listed the architectures last.
+        let lines = "debrep-record 1\nchangelog\n1.0.0\n\narchitectures: amd64 all\n";
+        let contents = [lines, "sha256 ", &digest(lines), "\n"].concat();
+        assert!(!is_current(&contents));
+        assert_eq!(
+            load(&contents, "pop-shop"),
+            Some(BuildRecord {
+                architectures: Some(vec!["amd64".to_owned(), "all".to_owned()]),
+                ..entry(Record::Changelog { version: "1.0.0".into(), run: None })
+            })
+        );
+
+        // What records from before the architectures were recorded built.
+        assert_eq!(entry(commits()).architectures("amd64"), vec!["amd64", "all"]);
+    }
+
+    #[test]
+    fn truncated_records_are_corrupt() {
+        let changelog = Record::Changelog { version: "1.0.0".into(), run: None };
+        for record in &[entry(commits()), entry(changelog)] {
+            let contents = record.serialize();
+            assert_eq!(BuildRecord::deserialize(&contents).as_ref(), Ok(record));
+            for end in 0..contents.len() {
+                let truncated = &contents[..end];
+                assert!(BuildRecord::deserialize(truncated).is_err(), "{:?}", truncated);
+            }
+        }
+
+        let changed = entry(commits()).serialize().replace("3d4e5f", "3d4e5e");
+        assert_eq!(BuildRecord::deserialize(&changed), Err("its digest does not match"));
+        assert_eq!(load(&changed, "pop-shop"), None);
+    }
+}
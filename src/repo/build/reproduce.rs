@@ -0,0 +1,204 @@
+//! Builds of sources which are compared with the packages of the pool, instead of being placed in
+//! it, to check that the pool can be reproduced from what was fetched.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::super::pool::PoolIndex;
+
+/// How a binary package which was built again compares with the package of the pool.
+#[derive(Debug, PartialEq)]
+pub enum Comparison {
+    Identical,
+    /// The package differs from the package of the pool at the path.
+    Differs(PathBuf),
+    /// The pool has no package of the name, such as when the source changed since it was built.
+    Missing,
+}
+
+/// The binary packages which a source built again, with how each compares with the pool.
+#[derive(Debug)]
+pub struct Reproduction {
+    pub package: String,
+    pub files: Vec<(PathBuf, Comparison)>,
+    /// The log which diffoscope reported the differences to, if it is installed.
+    pub report: Option<PathBuf>,
+}
+
+impl Reproduction {
+    /// Whether the source built packages, and each was identical to the package of the pool.
+    pub fn is_reproducible(&self) -> bool {
+        !self.files.is_empty()
+            && self.files.iter().all(|&(_, ref comparison)| *comparison == Comparison::Identical)
+    }
+}
+
+impl fmt::Display for Reproduction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.files.is_empty() {
+            return writeln!(f, "{} built no binary packages", self.package);
+        }
+
+        let reproduced = if self.is_reproducible() { "was" } else { "was not" };
+        writeln!(f, "{} {} reproduced:", self.package, reproduced)?;
+        for &(ref path, ref comparison) in &self.files {
+            let name = path.file_name().map_or_else(|| path.as_os_str(), |name| name);
+            let name = name.to_string_lossy();
+            match *comparison {
+                Comparison::Identical => writeln!(f, "  {} is identical", name)?,
+                Comparison::Differs(ref published) => {
+                    writeln!(f, "  {} differs from {}", name, published.display())?
+                }
+                Comparison::Missing => writeln!(f, "  {} is not in the pool", name)?,
+            }
+        }
+
+        if let Some(ref report) = self.report {
+            writeln!(f, "  diffoscope reported the differences to {}", report.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Compares each binary package in `dir` with the package of the same name in the pool, byte
+/// for byte.
+pub fn compare(pool: &PoolIndex, dir: &Path) -> io::Result<Vec<(PathBuf, Comparison)>> {
+    let mut built = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let binary = path.extension()
+            .map_or(false, |ext| ext == "deb" || ext == "udeb" || ext == "ddeb");
+        if binary {
+            built.push(path);
+        }
+    }
+
+    built.sort();
+    let files = pool.files();
+    built.into_iter()
+        .map(|path| {
+            let published = files.iter()
+                .find(|file| file.path.file_name() == path.file_name())
+                .map(|file| file.path.clone());
+            let comparison = match published {
+                Some(published) => if identical(&path, &published)? {
+                    Comparison::Identical
+                } else {
+                    Comparison::Differs(published)
+                },
+                None => Comparison::Missing,
+            };
+
+            Ok((path, comparison))
+        })
+        .collect()
+}
+
+/// Writes what diffoscope finds between each package which differs and the package of the pool
+/// to the log.
+pub fn explain(files: &[(PathBuf, Comparison)], log: &File) -> io::Result<()> {
+    for &(ref built, ref comparison) in files {
+        if let Comparison::Differs(ref published) = *comparison {
+            let status = Command::new("diffoscope")
+                .arg(published)
+                .arg(built)
+                .stdin(Stdio::null())
+                .stdout(log.try_clone()?)
+                .stderr(log.try_clone()?)
+                .status()?;
+
+            // diffoscope exits with 1 when the files differ, and with 2 when it fails.
+            if status.code().map_or(true, |code| code > 1) {
+                warn!("diffoscope failed to compare {} with {}: {}", built.display(),
+                    published.display(), status);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn identical(a: &Path, b: &Path) -> io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    let (mut a, mut b) = (BufReader::new(File::open(a)?), BufReader::new(File::open(b)?));
+    loop {
+        let read = {
+            let (x, y) = (a.fill_buf()?, b.fill_buf()?);
+            let read = x.len().min(y.len());
+            if read == 0 {
+                return Ok(x.len() == y.len());
+            } else if x[..read] != y[..read] {
+                return Ok(false);
+            }
+
+            read
+        };
+
+        a.consume(read);
+        b.consume(read);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use tempdir::TempDir;
+
+    #[test]
+    fn packages_are_compared_with_those_of_the_pool() {
+        let dir = TempDir::new("debrep-reproduce").unwrap();
+        let root = dir.path().join("pool");
+        let published = root.join("main/binary-amd64/f/foo");
+        fs::create_dir_all(&published).unwrap();
+        misc::write(published.join("foo_1.0_amd64.deb"), "same").unwrap();
+        misc::write(published.join("foo-data_1.0_all.deb"), "published").unwrap();
+
+        let outgoing = dir.path().join("outgoing");
+        fs::create_dir(&outgoing).unwrap();
+        misc::write(outgoing.join("foo_1.0_amd64.deb"), "same").unwrap();
+        misc::write(outgoing.join("foo-data_1.0_all.deb"), "rebuilt").unwrap();
+        misc::write(outgoing.join("foo-dbgsym_1.0_amd64.ddeb"), "symbols").unwrap();
+        misc::write(outgoing.join("foo_1.0_amd64.changes"), "changes").unwrap();
+
+        let pool = PoolIndex::open(&root).unwrap();
+        let files = compare(&pool, &outgoing).unwrap();
+        assert_eq!(files, vec![
+            (
+                outgoing.join("foo-data_1.0_all.deb"),
+                Comparison::Differs(published.join("foo-data_1.0_all.deb")),
+            ),
+            (outgoing.join("foo-dbgsym_1.0_amd64.ddeb"), Comparison::Missing),
+            (outgoing.join("foo_1.0_amd64.deb"), Comparison::Identical),
+        ]);
+
+        let reproduction = Reproduction { package: "foo".into(), files, report: None };
+        assert!(!reproduction.is_reproducible());
+        let report = reproduction.to_string();
+        assert!(report.starts_with("foo was not reproduced:\n  foo-data_1.0_all.deb differs from"));
+        assert!(report.ends_with(
+            "  foo-dbgsym_1.0_amd64.ddeb is not in the pool\n  foo_1.0_amd64.deb is identical\n"
+        ));
+    }
+
+    #[test]
+    fn files_of_the_same_length_are_compared_by_content() {
+        let dir = TempDir::new("debrep-reproduce").unwrap();
+        let (a, b, c) = (dir.path().join("a"), dir.path().join("b"), dir.path().join("c"));
+        misc::write(&a, vec![7; 100_000]).unwrap();
+        misc::write(&b, vec![7; 100_000]).unwrap();
+        let mut changed = vec![7; 100_000];
+        changed[99_999] = 8;
+        misc::write(&c, changed).unwrap();
+
+        assert!(identical(&a, &b).unwrap());
+        assert!(!identical(&a, &c).unwrap());
+    }
+}
@@ -1,22 +1,355 @@
-use std::path::Path;
-use std::{io, fs};
-use std::process::Command;
+//! Copies debian directories and local working copies into the workspaces of builds.
+//!
+//! Files are copied natively, as `rsync -a` would copy them: files whose size, modification time,
+//! or mode differ are replaced, symlinks are copied as symlinks, and modes and modification times
+//! are kept. The external rsync is still used if the config sets `use_rsync`.
 
-pub fn rsync(src: &Path, dst: &Path) -> io::Result<()> {
-    info!("rsyncing {} to {}", src.display(), dst.display());
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::{self, Metadata};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
-    if src.is_dir() {
-        fs::create_dir_all(src)?;
+use command::{Cmd, CommandError, Operation};
+use libc;
+
+/// The paths which a copy changed, relative to its destination.
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    /// Files, directories, and symlinks which were created or replaced, or whose mode changed.
+    pub copied: Vec<PathBuf>,
+    /// What was removed from the destination because it is not in the source.
+    pub removed: Vec<PathBuf>,
+}
+
+/// Copies the contents of the `src` directory over `dst`, keeping what else is in `dst`.
+pub fn overlay(src: &Path, dst: &Path, external: bool) -> io::Result<()> {
+    info!("copying {} to {}", src.display(), dst.display());
+    if external {
+        rsync(src, dst, false).map_err(io::Error::from)
+    } else {
+        sync(src, dst, false).map(|changes| report(dst, &changes))
+    }
+}
+
+/// Makes `dst` a copy of the contents of the `src` directory, removing files which are no longer
+/// in `src`, such as what the last build left behind. `src` is only read.
+pub fn mirror(src: &Path, dst: &Path, external: bool) -> io::Result<()> {
+    info!("copying {} to {}", src.display(), dst.display());
+    if external {
+        rsync(src, dst, true).map_err(io::Error::from)
+    } else {
+        sync(src, dst, true).map(|changes| report(dst, &changes))
+    }
+}
+
+fn report(dst: &Path, changes: &Changes) {
+    debug!(
+        "{} paths were copied into {}, and {} were removed",
+        changes.copied.len(),
+        dst.display(),
+        changes.removed.len()
+    );
+
+    for path in &changes.copied {
+        debug!("    copied {}", path.display());
     }
 
-    Command::new("rsync")
-        .arg("-avz")
-        .arg(src)
-        .arg(dst)
-        .status()
-        .and_then(|x| if x.success() {
-            Ok(())
+    for path in &changes.removed {
+        debug!("    removed {}", path.display());
+    }
+}
+
+fn rsync(src: &Path, dst: &Path, delete: bool) -> Result<(), CommandError> {
+    let mut src = src.as_os_str().to_owned();
+    src.push("/");
+
+    let mut command = Cmd::new("rsync");
+    command.arg("-a");
+    if delete {
+        command.arg("--delete");
+    }
+
+    command.arg(src).arg(dst).operation(Operation::Rsync).run()
+}
+
+/// Copies the contents of `src` into `dst`, which is created if it does not exist, and removes
+/// what is not in `src` from `dst` if `delete` is set.
+pub fn sync(src: &Path, dst: &Path, delete: bool) -> io::Result<Changes> {
+    let mut changes = Changes::default();
+    let metadata = src.metadata()?;
+    if !metadata.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the source is not a directory"));
+    }
+
+    if !dst.is_dir() {
+        fs::create_dir_all(dst)?;
+    }
+
+    sync_dir(src, dst, Path::new(""), delete, &mut changes)?;
+    set_attributes(dst, &metadata, &mut changes, Path::new("."))?;
+    Ok(changes)
+}
+
+fn sync_dir(
+    src: &Path,
+    dst: &Path,
+    relative: &Path,
+    delete: bool,
+    changes: &mut Changes,
+) -> io::Result<()> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(src)? {
+        names.push(entry?.file_name());
+    }
+
+    names.sort();
+    for name in &names {
+        let (from, to, path) = (src.join(name), dst.join(name), relative.join(name));
+        let metadata = from.symlink_metadata()?;
+        let existing = to.symlink_metadata().ok();
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            match existing {
+                Some(ref existing) if existing.is_dir() => (),
+                existing => {
+                    if let Some(existing) = existing {
+                        remove(&to, &existing)?;
+                    }
+
+                    fs::create_dir(&to)?;
+                    changes.copied.push(path.clone());
+                }
+            }
+
+            sync_dir(&from, &to, &path, delete, changes)?;
+            set_attributes(&to, &metadata, changes, &path)?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&from)?;
+            if let Some(existing) = existing {
+                let unchanged = existing.file_type().is_symlink() && fs::read_link(&to)? == target;
+                if unchanged {
+                    continue;
+                }
+
+                remove(&to, &existing)?;
+            }
+
+            symlink(&target, &to)?;
+            chown(&to, &metadata)?;
+            changes.copied.push(path);
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, "tar command failed"))
-        })
+            let unchanged = existing.as_ref().map_or(false, |existing| {
+                existing.file_type().is_file()
+                    && existing.len() == metadata.len()
+                    && existing.mtime() == metadata.mtime()
+                    && existing.mtime_nsec() == metadata.mtime_nsec()
+            });
+
+            if unchanged {
+                set_attributes(&to, &metadata, changes, &path)?;
+                continue;
+            }
+
+            // Files are replaced rather than written to, so that files which are hard links to
+            // something else, such as assets, are never changed through their links.
+            let temporary = dst.join([".", &name.to_string_lossy(), ".debrep"].concat());
+            fs::copy(&from, &temporary)?;
+            chown(&temporary, &metadata)?;
+            set_times(&temporary, &metadata)?;
+            if let Some(existing) = existing {
+                if existing.is_dir() {
+                    remove(&to, &existing)?;
+                }
+            }
+
+            fs::rename(&temporary, &to)?;
+            changes.copied.push(path);
+        }
+    }
+
+    if delete {
+        let names: HashSet<_> = names.into_iter().collect();
+        let mut extra = Vec::new();
+        for entry in fs::read_dir(dst)? {
+            let entry = entry?;
+            if !names.contains(&entry.file_name()) {
+                extra.push(entry.file_name());
+            }
+        }
+
+        extra.sort();
+        for name in extra {
+            let path = dst.join(&name);
+            remove(&path, &path.symlink_metadata()?)?;
+            changes.removed.push(relative.join(name));
+        }
+    }
+
+    Ok(())
+}
+
+fn remove(path: &Path, metadata: &Metadata) -> io::Result<()> {
+    if metadata.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) }
+}
+
+/// Gives the copy the mode, and modification time of the original. Changes of the mode are
+/// recorded, and those of the time are not, since copying a directory changes its time.
+fn set_attributes(
+    path: &Path,
+    metadata: &Metadata,
+    changes: &mut Changes,
+    relative: &Path,
+) -> io::Result<()> {
+    let mode = metadata.permissions().mode() & 0o7777;
+    if path.metadata()?.permissions().mode() & 0o7777 != mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        if !changes.copied.iter().any(|x| x == relative) {
+            changes.copied.push(relative.to_path_buf());
+        }
+    }
+
+    chown(path, metadata)?;
+    set_times(path, metadata)
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, format!("{}", why)))
+}
+
+fn set_times(path: &Path, metadata: &Metadata) -> io::Result<()> {
+    let time = libc::timespec {
+        tv_sec: metadata.mtime() as libc::time_t,
+        tv_nsec: metadata.mtime_nsec() as libc::c_long,
+    };
+
+    let path = c_path(path)?;
+    let times = [time, time];
+    match unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Keeps the owner of the original, as rsync does when it is run as root.
+fn chown(path: &Path, metadata: &Metadata) -> io::Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    let path = c_path(path)?;
+    match unsafe { libc::lchown(path.as_ptr(), metadata.uid(), metadata.gid()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use tempdir::TempDir;
+
+    /// Every path of the tree, with its kind, mode, modification time, and contents or target.
+    fn snapshot(root: &Path) -> Vec<(PathBuf, String, u32, i64, Vec<u8>)> {
+        let mut paths = Vec::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let metadata = path.symlink_metadata().unwrap();
+                let relative = path.strip_prefix(root).unwrap().to_path_buf();
+                let (kind, contents) = if metadata.file_type().is_symlink() {
+                    let target = fs::read_link(&path).unwrap();
+                    ("symlink", target.as_os_str().as_bytes().to_vec())
+                } else if metadata.is_dir() {
+                    pending.push(path.clone());
+                    ("dir", Vec::new())
+                } else {
+                    ("file", misc::read(&path).unwrap())
+                };
+
+                let mode = metadata.permissions().mode() & 0o7777;
+                let mtime = if kind == "file" { metadata.mtime() } else { 0 };
+                paths.push((relative, kind.to_owned(), mode, mtime, contents));
+            }
+        }
+
+        paths.sort();
+        paths
+    }
+
+    /// A source tree, and a destination which has stale, extra, and conflicting paths.
+    fn trees() -> (TempDir, TempDir) {
+        let src = TempDir::new("debrep-sync-src").unwrap();
+        let dst = TempDir::new("debrep-sync-dst").unwrap();
+        let (s, d) = (src.path(), dst.path());
+
+        fs::create_dir_all(s.join("debian/source")).unwrap();
+        misc::write(s.join("debian/rules"), b"#!/usr/bin/make -f\n").unwrap();
+        fs::set_permissions(s.join("debian/rules"), fs::Permissions::from_mode(0o755)).unwrap();
+        misc::write(s.join("debian/source/format"), b"3.0 (native)\n").unwrap();
+        misc::write(s.join("debian/changelog"), b"changelog\n").unwrap();
+        symlink("changelog", s.join("debian/NEWS")).unwrap();
+        symlink("../missing", s.join("debian/dangling")).unwrap();
+        misc::write(s.join("conflict"), b"now a file\n").unwrap();
+
+        fs::create_dir_all(d.join("debian/source")).unwrap();
+        fs::create_dir_all(d.join("conflict")).unwrap();
+        fs::create_dir_all(d.join("stale/nested")).unwrap();
+        misc::write(d.join("debian/rules"), b"#!/usr/bin/make -f\n").unwrap();
+        misc::write(d.join("debian/changelog"), b"an older changelog\n").unwrap();
+        misc::write(d.join("stale/nested/file"), b"left by the last build\n").unwrap();
+        symlink("rules", d.join("debian/NEWS")).unwrap();
+        (src, dst)
+    }
+
+    #[test]
+    fn trees_are_copied_natively() {
+        let (src, dst) = trees();
+        let asset = dst.path().join("asset");
+        misc::write(&asset, b"asset\n").unwrap();
+        fs::hard_link(&asset, dst.path().join("debian/changelog.linked")).unwrap();
+        misc::write(src.path().join("debian/changelog.linked"), b"replaced\n").unwrap();
+
+        let changes = sync(src.path(), dst.path(), false).unwrap();
+        assert!(changes.removed.is_empty());
+        assert!(changes.copied.contains(&PathBuf::from("debian/rules")));
+        assert!(changes.copied.contains(&PathBuf::from("debian/NEWS")));
+        assert!(changes.copied.contains(&PathBuf::from("debian/source/format")));
+        assert!(dst.path().join("stale/nested/file").exists());
+        assert_eq!(misc::read(&asset).unwrap(), b"asset\n");
+
+        let changes = sync(src.path(), dst.path(), true).unwrap();
+        assert!(changes.copied.is_empty());
+        assert_eq!(changes.removed, vec![PathBuf::from("asset"), PathBuf::from("stale")]);
+        assert_eq!(snapshot(dst.path()), snapshot(src.path()));
+
+        // Nothing changes when nothing did.
+        assert_eq!(sync(src.path(), dst.path(), true).unwrap(), Changes::default());
+
+        fs::set_permissions(src.path().join("debian/rules"), fs::Permissions::from_mode(0o700))
+            .unwrap();
+        let changes = sync(src.path(), dst.path(), true).unwrap();
+        assert_eq!(changes.copied, vec![PathBuf::from("debian/rules")]);
+        assert_eq!(snapshot(dst.path()), snapshot(src.path()));
+    }
+
+    // Ignored because it needs rsync installed, which builders of the crate may not have. Run it
+    // with `cargo test -- --ignored` where rsync is, such as after changing how trees are synced.
+    #[test]
+    #[ignore]
+    fn native_copies_match_rsync() {
+        for &delete in &[false, true] {
+            let (src, native) = trees();
+            let (_, external) = trees();
+            sync(src.path(), native.path(), delete).unwrap();
+            rsync(src.path(), external.path(), delete).unwrap();
+            assert_eq!(snapshot(native.path()), snapshot(external.path()));
+        }
+    }
 }
@@ -0,0 +1,196 @@
+use std::path::Path;
+use std::sync::{Once, ONCE_INIT};
+
+use command::on_path;
+use config::CommandLimits;
+
+/// Commands are killed if they are still running after this many seconds, unless configured.
+const DEFAULT_TIMEOUT: u64 = 3600;
+
+/// The only environment variables which commands receive, besides `HOME`.
+const ENVIRONMENT: &[&str] = &[
+    "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    "LC_ALL=C.UTF-8",
+    "DEBIAN_FRONTEND=noninteractive",
+];
+
+static UNCONFINED: Once = ONCE_INIT;
+
+/// How commands are isolated from the host, depending on which tools are installed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Isolation {
+    /// Only the working directory and a private `/tmp` are writable, and the network can be
+    /// removed.
+    Bwrap,
+    /// The network can be removed, but the filesystem is not restricted.
+    Unshare,
+    /// Commands are only given a minimal environment and limits.
+    Unconfined,
+}
+
+impl Isolation {
+    pub fn detect() -> Isolation {
+        if on_path("bwrap") {
+            Isolation::Bwrap
+        } else if on_path("unshare") {
+            Isolation::Unshare
+        } else {
+            UNCONFINED.call_once(|| {
+                warn!("neither bwrap or unshare are installed, so prebuild commands can write \
+                       anywhere the build user can");
+            });
+            Isolation::Unconfined
+        }
+    }
+}
+
+/// Wraps the `prebuild` and `starting_build` commands of a source before they are handed to
+/// sbuild, which runs them with a shell.
+pub struct Sandbox<'a> {
+    pub isolation: Isolation,
+    pub package: &'a str,
+    /// The only directory outside of `/tmp` that prebuild commands may write to.
+    pub workdir: &'a Path,
+    /// Whether prebuild commands may access the network.
+    pub network: bool,
+    pub limits: Option<&'a CommandLimits>,
+}
+
+impl<'a> Sandbox<'a> {
+    /// Pre-build commands are run on the host, and so are isolated as far as possible.
+    pub fn prebuild(&self, command: &str) -> String {
+        let workdir = self.workdir.to_string_lossy();
+        let prefix = match self.isolation {
+            Isolation::Bwrap => {
+                let mut prefix = format!(
+                    "bwrap --ro-bind / / --dev /dev --proc /proc --tmpfs /tmp --bind {0} {0} \
+                     --chdir {0} --unshare-pid --die-with-parent",
+                    quote(&workdir)
+                );
+                if !self.network {
+                    prefix.push_str(" --unshare-net");
+                }
+                prefix + " -- "
+            }
+            Isolation::Unshare if !self.network => {
+                format!("cd {} && unshare --net --map-root-user ", quote(&workdir))
+            }
+            _ => {
+                if !self.network {
+                    warn!(
+                        "cannot disable the network for the prebuild commands of {}",
+                        self.package
+                    );
+                }
+                format!("cd {} && ", quote(&workdir))
+            }
+        };
+
+        self.wrap("prebuild", command, &prefix, &workdir)
+    }
+
+    /// Starting-build commands are run within the chroot, which already isolates them from the
+    /// host's filesystem, so only the environment and limits are applied.
+    pub fn starting_build(&self, command: &str) -> String {
+        self.wrap("starting_build", command, "", "/tmp")
+    }
+
+    fn wrap(&self, kind: &str, command: &str, prefix: &str, home: &str) -> String {
+        let timeout = self.limits.and_then(|limits| limits.timeout).unwrap_or(DEFAULT_TIMEOUT);
+        let inner = match self.limits.and_then(|limits| limits.memory) {
+            Some(memory) => format!("ulimit -v {}; {}", memory * 1024, command),
+            None => command.to_owned(),
+        };
+
+        let described = format!("the {} command of {}: {}", kind, self.package, command);
+        format!(
+            "{}env -i HOME={} {} timeout --kill-after=10 {} sh -c {} || {{ status=$?; \
+             if [ $status -eq 124 ]; then echo {} >&2; else echo {} >&2; fi; exit $status; }}",
+            prefix,
+            quote(home),
+            ENVIRONMENT.join(" "),
+            timeout,
+            quote(&inner),
+            quote(&format!("debrep: timed out after {}s, {}", timeout, described)),
+            quote(&format!("debrep: failed {}", described)),
+        )
+    }
+}
+
+/// Quotes a string so that a shell reads it as a single word.
+fn quote(string: &str) -> String {
+    ["'", &string.replace('\'', "'\\''"), "'"].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    fn sandbox<'a>(workdir: &'a Path, limits: Option<&'a CommandLimits>) -> Sandbox<'a> {
+        Sandbox {
+            isolation: Isolation::Unconfined,
+            package: "pop-desktop",
+            workdir,
+            network: true,
+            limits,
+        }
+    }
+
+    fn run(script: &str) -> (bool, String) {
+        let output = Command::new("sh").arg("-c").arg(script).env("SECRET", "1").output().unwrap();
+        (output.status.success(), String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("echo 'a b'"), "'echo '\\''a b'\\'''");
+    }
+
+    #[test]
+    fn bwrap_confines_writes_and_network() {
+        let mut sandbox = sandbox(Path::new("/srv/build/pop-desktop"), None);
+        sandbox.isolation = Isolation::Bwrap;
+        sandbox.network = false;
+
+        let wrapped = sandbox.prebuild("make assets");
+        assert!(wrapped.starts_with(
+            "bwrap --ro-bind / / --dev /dev --proc /proc --tmpfs /tmp \
+             --bind '/srv/build/pop-desktop' '/srv/build/pop-desktop' \
+             --chdir '/srv/build/pop-desktop' --unshare-pid --die-with-parent --unshare-net \
+             -- env -i"
+        ));
+        assert!(wrapped.contains("timeout --kill-after=10 3600 sh -c 'make assets'"));
+    }
+
+    #[test]
+    fn commands_run_in_workdir_with_minimal_environment() {
+        let dir = TempDir::new("debrep-sandbox").unwrap();
+        let sandbox = sandbox(dir.path(), None);
+
+        let (success, _) = run(&sandbox.prebuild("test -z \"$SECRET\" && touch created"));
+        assert!(success);
+        assert!(dir.path().join("created").exists());
+    }
+
+    #[test]
+    fn failures_name_the_package_and_command() {
+        let dir = TempDir::new("debrep-sandbox").unwrap();
+        let limits = CommandLimits { timeout: Some(1), memory: None };
+        let sandbox = sandbox(dir.path(), Some(&limits));
+
+        let (success, stderr) = run(&sandbox.prebuild("echo oops >&2; exit 3"));
+        assert!(!success);
+        assert!(stderr.contains("oops\n"));
+        assert!(stderr.contains(
+            "debrep: failed the prebuild command of pop-desktop: echo oops >&2; exit 3"
+        ));
+
+        let (success, stderr) = run(&sandbox.starting_build("sleep 5"));
+        assert!(!success);
+        assert!(stderr.contains(
+            "debrep: timed out after 1s, the starting_build command of pop-desktop: sleep 5"
+        ));
+    }
+}
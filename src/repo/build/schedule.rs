@@ -0,0 +1,373 @@
+//! The order which the sources of a batch are built in, and the queue of the batch in
+//! `record/.queue`, which `debrep status` shows while the batch is built. An urgent batch, which
+//! preempted another, writes its queue to `record/.queue-urgent` instead.
+//!
+//! The next source is the one of the highest priority whose `depends` are not among the sources
+//! which remain to be built, or are being built, so that a source is still built after those
+//! which it needs, even when several are built at once. A source
+//! which another source depends on is built at the priority of that source, if it is higher, so
+//! that an urgent source is not held back by a dependency of a low priority. Sources of the same
+//! priority are built in the order of the config.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use config::Source;
+use misc;
+use super::super::lock;
+
+/// The queue of the batch which is being built.
+pub const QUEUE: &str = "record/.queue";
+
+/// The queue of the urgent batch which preempted it.
+pub const URGENT_QUEUE: &str = "record/.queue-urgent";
+
+/// The sources of a batch which remain to be built, with their priorities.
+#[derive(Clone, Debug)]
+pub struct Schedule<'a> {
+    pending: Vec<(&'a Source, i32)>,
+    /// The sources which were taken, and are still being built.
+    building: Vec<&'a Source>,
+}
+
+impl<'a> Schedule<'a> {
+    /// Schedules the sources at their own priorities, or all at `priority` if it is given.
+    pub fn new(items: &'a [Source], priority: Option<i32>) -> Schedule<'a> {
+        let mut pending: Vec<(&Source, i32)> = items.iter()
+            .map(|item| (item, priority.unwrap_or_else(|| item.priority())))
+            .collect();
+
+        loop {
+            let mut raised = false;
+            for pos in 0..pending.len() {
+                let needed = pending.iter()
+                    .filter(|&&(other, _)| depends_on(other, pending[pos].0))
+                    .map(|&(_, priority)| priority)
+                    .max();
+                if let Some(priority) = needed {
+                    if priority > pending[pos].1 {
+                        pending[pos].1 = priority;
+                        raised = true;
+                    }
+                }
+            }
+
+            if !raised {
+                break;
+            }
+        }
+
+        Schedule { pending, building: Vec::new() }
+    }
+
+    /// Takes the source which is built next, with its priority, until it is `finish`ed. Nothing
+    /// is taken while every source waits for one which is being built. When every source is
+    /// waiting for another which is not, as when they depend on each other, the one of the
+    /// highest priority is taken.
+    pub fn next(&mut self) -> Option<(&'a Source, i32)> {
+        let ready = (0..self.pending.len())
+            .filter(|&pos| self.is_ready(pos))
+            .fold(None, |best, pos| self.higher(best, pos));
+        let pos = match ready {
+            Some(pos) => pos,
+            None if self.building.is_empty() => {
+                (0..self.pending.len()).fold(None, |best, pos| self.higher(best, pos))?
+            }
+            None => return None,
+        };
+
+        let next = self.pending.remove(pos);
+        self.building.push(next.0);
+        Some(next)
+    }
+
+    /// Marks the source as built, so that the sources which depend on it may be taken.
+    pub fn finish(&mut self, item: &Source) {
+        self.building.retain(|&building| !::std::ptr::eq(building, item));
+    }
+
+    /// Marks the source as failed, and takes the sources which depend on it, directly or through
+    /// others, which cannot be built without it.
+    pub fn fail(&mut self, item: &Source) -> Vec<&'a Source> {
+        self.finish(item);
+        let mut failed: Vec<&Source> = vec![item];
+        let mut dependents = Vec::new();
+        while let Some(pos) = self.pending.iter()
+            .position(|&(source, _)| failed.iter().any(|&other| depends_on(source, other)))
+        {
+            let (dependent, _) = self.pending.remove(pos);
+            failed.push(dependent);
+            dependents.push(dependent);
+        }
+
+        dependents
+    }
+
+    /// Whether every source was taken.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// The order which the remaining sources would be built in, one at a time, once the sources
+    /// which are being built were.
+    pub fn order(&self) -> Vec<(&'a Source, i32)> {
+        let mut schedule = self.clone();
+        schedule.building.clear();
+        let mut order = Vec::with_capacity(self.pending.len());
+        while let Some(next) = schedule.next() {
+            schedule.finish(next.0);
+            order.push(next);
+        }
+
+        order
+    }
+
+    /// Of the best source so far and the source at `pos`, the one to build first. The earlier
+    /// source wins a tie.
+    fn higher(&self, best: Option<usize>, pos: usize) -> Option<usize> {
+        match best {
+            Some(best) if self.pending[best].1 >= self.pending[pos].1 => Some(best),
+            _ => Some(pos),
+        }
+    }
+
+    fn is_ready(&self, pos: usize) -> bool {
+        let item = self.pending[pos].0;
+        let waits = self.pending.iter()
+            .enumerate()
+            .any(|(other, &(source, _))| other != pos && depends_on(item, source));
+        !waits && !self.building.iter().any(|&source| depends_on(item, source))
+    }
+}
+
+/// Whether the source installs the packages of the other when it is built.
+fn depends_on(item: &Source, other: &Source) -> bool {
+    !::std::ptr::eq(item, other) && item.depends.as_ref().map_or(false, |depends| {
+        depends.iter().any(|name| *name == other.name || name == other.base_name())
+    })
+}
+
+/// The order which the sources would be built in, with the priority which each is built at.
+pub fn order(items: &[Source], priority: Option<i32>) -> Vec<(String, i32)> {
+    Schedule::new(items, priority).order().into_iter()
+        .map(|(item, priority)| (item.name.clone(), priority))
+        .collect()
+}
+
+/// The queue of a batch of builds, as `debrep status` shows it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Queue {
+    /// Whether the batch preempted another.
+    pub urgent: bool,
+    /// Whether the batch is paused for urgent builds.
+    pub paused: bool,
+    /// The sources which are being built, unless the batch is paused.
+    pub building: Vec<QueuedBuild>,
+    /// The sources which remain, in the order which they would be built in.
+    pub queued: Vec<QueuedBuild>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedBuild {
+    pub suite: String,
+    pub package: String,
+    pub priority: i32,
+}
+
+impl Queue {
+    pub fn new(
+        suite: &str,
+        building: &[(&Source, i32)],
+        queued: &[(&Source, i32)],
+        paused: bool,
+    ) -> Queue {
+        let entry = |&(item, priority): &(&Source, i32)| QueuedBuild {
+            suite: suite.to_owned(),
+            package: item.name.clone(),
+            priority,
+        };
+
+        Queue {
+            urgent: lock::is_urgent(),
+            paused,
+            building: building.iter().map(&entry).collect(),
+            queued: queued.iter().map(&entry).collect(),
+        }
+    }
+
+    fn serialize(&self) -> String {
+        let mut out = if self.paused { "paused\n" } else { "running\n" }.to_owned();
+        let line = |state: &str, build: &QueuedBuild| {
+            format!("{}\t{}\t{}\t{}\n", state, build.suite, build.priority, build.package)
+        };
+
+        for build in &self.building {
+            out.push_str(&line("building", build));
+        }
+
+        for build in &self.queued {
+            out.push_str(&line("queued", build));
+        }
+
+        out
+    }
+
+    fn parse(contents: &str, urgent: bool) -> Queue {
+        let mut lines = contents.lines();
+        let paused = lines.next() == Some("paused");
+        let mut queue = Queue { urgent, paused, ..Queue::default() };
+        for line in lines {
+            let fields: Vec<&str> = line.splitn(4, '\t').collect();
+            if fields.len() != 4 {
+                continue;
+            }
+
+            let build = QueuedBuild {
+                suite: fields[1].to_owned(),
+                package: fields[3].to_owned(),
+                priority: fields[2].parse().unwrap_or(0),
+            };
+
+            if fields[0] == "building" {
+                queue.building.push(build);
+            } else {
+                queue.queued.push(build);
+            }
+        }
+
+        queue
+    }
+
+    /// Writes the queue, so that `debrep status` shows what will be built next.
+    pub fn write(&self, root: &Path) -> io::Result<()> {
+        misc::write(root.join(path(self.urgent)), self.serialize().as_bytes())
+    }
+
+    /// Removes the queue of the batch, which finished.
+    pub fn clear(root: &Path, urgent: bool) {
+        match fs::remove_file(root.join(path(urgent))) {
+            Err(ref why) if why.kind() != io::ErrorKind::NotFound => {
+                warn!("failed to remove the build queue: {}", why);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn path(urgent: bool) -> &'static str {
+    if urgent { URGENT_QUEUE } else { QUEUE }
+}
+
+/// The queues of the batches which are being built, the urgent batch first.
+pub fn read(root: &Path) -> io::Result<Vec<Queue>> {
+    let mut queues = Vec::new();
+    for &urgent in &[true, false] {
+        match misc::read_to_string(root.join(path(urgent))) {
+            Ok(contents) => queues.push(Queue::parse(&contents, urgent)),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => (),
+            Err(why) => return Err(why),
+        }
+    }
+
+    Ok(queues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use toml;
+
+    fn sources() -> Vec<Source> {
+        let config: Config = toml::from_str(r#"
+            archive = "stable"
+            version = "1"
+            origin = "o"
+            label = "l"
+            email = "e@example.com"
+
+            [[source]]
+            name = "nightly"
+
+            [[source]]
+            name = "libfoo"
+
+            [[source]]
+            name = "security-fix"
+            priority = 100
+            depends = ["libfoo"]
+
+            [[source]]
+            name = "tool"
+            priority = 10
+        "#).unwrap();
+        config.build_units()
+    }
+
+    fn names(order: &[(&Source, i32)]) -> Vec<String> {
+        order.iter().map(|&(item, priority)| format!("{}:{}", item.name, priority)).collect()
+    }
+
+    #[test]
+    fn higher_priorities_are_built_first_once_their_dependencies_are() {
+        let items = sources();
+        let schedule = Schedule::new(&items, None);
+        assert_eq!(
+            names(&schedule.order()),
+            vec!["libfoo:100", "security-fix:100", "tool:10", "nightly:0"]
+        );
+
+        // Sources of the same priority keep the order of the config.
+        let schedule = Schedule::new(&items[..2], None);
+        assert_eq!(names(&schedule.order()), vec!["nightly:0", "libfoo:0"]);
+
+        let overridden = Schedule::new(&items[..2], Some(5));
+        assert_eq!(names(&overridden.order()), vec!["nightly:5", "libfoo:5"]);
+    }
+
+    #[test]
+    fn sources_wait_for_the_builds_of_their_dependencies() {
+        let items = sources();
+        let mut schedule = Schedule::new(&items, None);
+        let libfoo = schedule.next().unwrap();
+        assert_eq!(libfoo.0.name, "libfoo");
+
+        // Sources which do not depend on libfoo are built alongside it, but security-fix waits.
+        assert_eq!(schedule.next().map(|(item, _)| item.name.as_str()), Some("tool"));
+        assert_eq!(schedule.next().map(|(item, _)| item.name.as_str()), Some("nightly"));
+        assert!(schedule.next().is_none());
+        assert!(!schedule.is_empty());
+        assert_eq!(names(&schedule.order()), vec!["security-fix:100"]);
+
+        schedule.finish(libfoo.0);
+        assert_eq!(schedule.next().map(|(item, _)| item.name.as_str()), Some("security-fix"));
+        assert!(schedule.is_empty());
+    }
+
+    #[test]
+    fn sources_which_need_a_failed_build_are_taken_with_it() {
+        let items = sources();
+        let mut schedule = Schedule::new(&items, None);
+        let libfoo = schedule.next().unwrap();
+        let dependents = schedule.fail(libfoo.0);
+        assert_eq!(dependents.len(), 1);
+        assert_eq!(dependents[0].name, "security-fix");
+        assert_eq!(names(&schedule.order()), vec!["tool:10", "nightly:0"]);
+    }
+
+    #[test]
+    fn queues_are_written_and_read() {
+        let items = sources();
+        let mut schedule = Schedule::new(&items, None);
+        let building = vec![schedule.next().unwrap(), schedule.next().unwrap()];
+        let queue = Queue::new("stable", &building, &schedule.order(), false);
+        let packages: Vec<&str> = queue.building.iter().map(|x| x.package.as_str()).collect();
+        assert_eq!(packages, vec!["libfoo", "tool"]);
+        assert_eq!(queue.queued.len(), 2);
+        assert_eq!(Queue::parse(&queue.serialize(), false), queue);
+
+        let paused = Queue::new("stable", &[], &schedule.order(), true);
+        assert_eq!(Queue::parse(&paused.serialize(), false), paused);
+    }
+}
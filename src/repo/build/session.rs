@@ -0,0 +1,139 @@
+//! Schroot sessions which are kept for the packages of a run when `reuse_sessions` is set, so
+//! that sbuild does not set up a chroot for every package.
+//!
+//! A session is taken by one build at a time, and a build which finds no idle session of its
+//! chroot begins a new one. Before a session is used again, the packages which were installed
+//! into it since it began are purged, and `/build` is emptied. A session which cannot be reset is
+//! ended, and the package is built in a session of its own, as sbuild does by default.
+
+use std::collections::HashMap;
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use super::install::Session;
+
+/// Lists the packages which are installed in the session as it begins.
+const RECORD_BASELINE: &str =
+    "dpkg-query -W -f '${binary:Package}\\n' | sort > /var/lib/debrep-baseline";
+
+/// Purges what was installed in the session since it began, and removes what the last build left.
+const RESET: &str = "set -e
+dpkg --configure -a
+dpkg-query -W -f '${binary:Package}\\n' | sort > /tmp/debrep-installed
+comm -13 /var/lib/debrep-baseline /tmp/debrep-installed | xargs -r apt-get -y purge
+apt-get -y autoremove --purge
+apt-get clean
+rm -rf /build/* /tmp/debrep-installed";
+
+lazy_static! {
+    /// The sessions which are not being built in, by their chroot.
+    static ref IDLE: Mutex<HashMap<String, Reused>> = Mutex::new(HashMap::new());
+}
+
+/// A session which is kept for the builds of a run.
+pub struct Reused {
+    chroot: String,
+    session: Session,
+}
+
+impl Reused {
+    /// How sbuild is told to build in the session.
+    pub fn chroot_arg(&self) -> String {
+        ["--chroot=session:", self.session.name()].concat()
+    }
+}
+
+/// Ends the sessions which are idle when it is dropped, at the end of the builds of a run,
+/// including runs which were interrupted or failed.
+pub struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        end_all();
+    }
+}
+
+/// Takes the idle session of the chroot after resetting it, or begins one. `None` means that the
+/// package should be built in a session of its own.
+pub fn take(chroot: &str) -> Option<Reused> {
+    let idle = IDLE.lock().unwrap().remove(chroot);
+    match idle {
+        Some(reused) => match run(&reused.session, RESET) {
+            Ok(()) => Some(reused),
+            Err(why) => {
+                warn!(
+                    "failed to reset the schroot session {}, so the package is built in a session \
+                     of its own: {}",
+                    reused.session.name(),
+                    why
+                );
+                None
+            }
+        },
+        None => match begin(chroot) {
+            Ok(reused) => {
+                info!("building in the schroot session {}", reused.session.name());
+                Some(reused)
+            }
+            Err(why) => {
+                warn!("failed to begin a schroot session of {} to reuse: {}", chroot, why);
+                None
+            }
+        },
+    }
+}
+
+/// Returns the session after a build, for the next build of its chroot. If another build already
+/// returned a session of the chroot, this one is ended.
+pub fn give_back(reused: Reused) {
+    let mut idle = IDLE.lock().unwrap();
+    if !idle.contains_key(&reused.chroot) {
+        idle.insert(reused.chroot.clone(), reused);
+    }
+}
+
+/// Ends every idle session.
+pub fn end_all() {
+    let sessions: Vec<Reused> = IDLE.lock().unwrap().drain().map(|(_, reused)| reused).collect();
+    for reused in sessions {
+        info!("ending the schroot session {}", reused.session.name());
+    }
+}
+
+fn begin(chroot: &str) -> io::Result<Reused> {
+    let session = Session::begin(chroot)?;
+    run(&session, RECORD_BASELINE)?;
+    Ok(Reused { chroot: chroot.to_owned(), session })
+}
+
+/// Runs the script as root in the session.
+fn run(session: &Session, script: &str) -> io::Result<()> {
+    let output = Command::new("schroot")
+        .args(&["--run-session", "-c", session.name(), "--directory", "/", "-u", "root"])
+        .args(&["--", "sh", "-c", script])
+        .env("DEBIAN_FRONTEND", "noninteractive")
+        .stdin(Stdio::null())
+        .output()?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim())
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_fall_back_to_sessions_of_their_own() {
+        let _guard = Guard;
+        assert!(take("debrep-no-such-chroot").is_none());
+        assert!(IDLE.lock().unwrap().is_empty());
+    }
+}
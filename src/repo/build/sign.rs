@@ -0,0 +1,87 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use command::Cmd;
+use config::Signing;
+
+/// Finds the fingerprint of the signing key, which also ensures that the key is available.
+pub fn fingerprint(signing: &Signing) -> io::Result<String> {
+    let mut command = Command::new("gpg");
+    command.args(&["--batch", "--with-colons", "--fingerprint", &signing.key]);
+    if let Some(ref home) = signing.gnupg_home {
+        command.env("GNUPGHOME", home);
+    }
+
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "gpg could not find the key {}: {}",
+                signing.key,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        ));
+    }
+
+    parse_fingerprint(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("no fingerprint for the key {}", signing.key))
+    })
+}
+
+/// The fingerprint of the primary key, from the output of `gpg --with-colons`.
+fn parse_fingerprint(output: &str) -> Option<String> {
+    output.lines()
+        .filter(|line| line.starts_with("fpr:"))
+        .filter_map(|line| line.split(':').nth(9))
+        .find(|fingerprint| !fingerprint.is_empty())
+        .map(|fingerprint| fingerprint.to_owned())
+}
+
+/// The `.changes` files which sbuild wrote into the directory.
+pub fn changes_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().map_or(false, |ext| ext == "changes") {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Signs the `.changes` file, and the `.dsc` and `.buildinfo` files that it lists, with debsign.
+pub fn debsign(signing: &Signing, fingerprint: &str, changes: &Path) -> io::Result<()> {
+    info!("signing {} with {}", changes.display(), fingerprint);
+    let mut command = Cmd::new("debsign");
+    command.args(&["--no-conf", "--re-sign"]).arg(["-k", fingerprint].concat()).arg(changes);
+    if let Some(ref home) = signing.gnupg_home {
+        command.env("GNUPGHOME", home);
+    }
+
+    command.run().map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_of_primary_key() {
+        let output = "tru::1:1531500000:0:3:1:5\n\
+            pub:u:4096:1:204DD8AEC33A7AFF:1499200000:::u:::scESC::::::23::0:\n\
+            fpr:::::::::63C46DF0140D738961429F4E204DD8AEC33A7AFF:\n\
+            uid:u::::1499200000::6E6F1F5C4A4B6F9D1B5E0D2A8B6A59A8C0F8C2E1::Pop OS:::::::::0:\n\
+            sub:u:4096:1:5B1D1F5E3C2B4A1A:1499200000::::::e::::::23:\n\
+            fpr:::::::::0E5C5B7F1E0A2C3E4D9F6A8B5B1D1F5E3C2B4A1A:\n";
+
+        assert_eq!(
+            parse_fingerprint(output),
+            Some("63C46DF0140D738961429F4E204DD8AEC33A7AFF".to_owned())
+        );
+        assert_eq!(parse_fingerprint("tru::1:1531500000:0:3:1:5\n"), None);
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::{self, Read, Write};
+
+/// The longest partial line that will be held before it is written to the terminal regardless.
+const MAX_LINE: usize = 4096;
+
+/// Copies all output from a child process into its log, while also writing each line to the
+/// terminal, prefixed with the name of the package being built.
+///
+/// Each prefixed line is written with a single call so that lines from builds which are running
+/// concurrently will not be interleaved mid-line.
+pub fn tee<R: Read, L: Write, T: Write>(
+    mut input: R,
+    mut log: L,
+    mut terminal: T,
+    prefix: &str,
+) -> io::Result<()> {
+    let mut buffer = [0u8; 8192];
+    let mut line = Vec::with_capacity(MAX_LINE);
+
+    let emit = |line: &mut Vec<u8>, terminal: &mut T| -> io::Result<()> {
+        let mut output = Vec::with_capacity(prefix.len() + line.len() + 4);
+        output.push(b'[');
+        output.extend_from_slice(prefix.as_bytes());
+        output.extend_from_slice(b"] ");
+        output.extend_from_slice(line);
+        output.push(b'\n');
+        line.clear();
+        terminal.write_all(&output)
+    };
+
+    loop {
+        let read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref why) if why.kind() == io::ErrorKind::Interrupted => continue,
+            Err(why) => return Err(why),
+        };
+
+        let data = &buffer[..read];
+        log.write_all(data)?;
+
+        for &byte in data {
+            if byte == b'\n' {
+                emit(&mut line, &mut terminal)?;
+            } else {
+                line.push(byte);
+                if line.len() == MAX_LINE {
+                    emit(&mut line, &mut terminal)?;
+                }
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        emit(&mut line, &mut terminal)?;
+    }
+
+    log.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tee_prefixes_lines() {
+        let input = b"first line\nsecond line\npartial";
+        let (mut log, mut terminal) = (Vec::new(), Vec::new());
+        tee(&input[..], &mut log, &mut terminal, "pkg").unwrap();
+
+        assert_eq!(&log[..], &input[..]);
+        assert_eq!(
+            String::from_utf8(terminal).unwrap(),
+            "[pkg] first line\n[pkg] second line\n[pkg] partial\n"
+        );
+    }
+
+    #[test]
+    fn long_lines_are_not_held_indefinitely() {
+        let input = vec![b'x'; MAX_LINE * 2 + 10];
+        let (mut log, mut terminal) = (Vec::new(), Vec::new());
+        tee(&input[..], &mut log, &mut terminal, "pkg").unwrap();
+
+        assert_eq!(log.len(), input.len());
+        let terminal = String::from_utf8(terminal).unwrap();
+        let lines: Vec<&str> = terminal.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2], "[pkg] xxxxxxxxxx");
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use command::Cmd;
+use config::Vendor;
+use misc;
+use repo::checksum::sha2_256_digest;
+
+/// Vendored dependencies are kept here, in a directory for each source and lockfile digest.
+pub const VENDOR_CACHE: &str = "assets/cache/vendor";
+
+#[derive(Debug, Fail)]
+pub enum VendorError {
+    #[fail(display = "{} has no {} to vendor its dependencies with", package, lockfile)]
+    NoLockfile { package: String, lockfile: &'static str },
+    #[fail(display = "the dependencies of {} are not vendored for its {}, and the build is offline",
+           package, lockfile)]
+    Offline { package: String, lockfile: &'static str },
+    #[fail(display = "failed to vendor the dependencies of {}: {}", package, why)]
+    Command { package: String, why: io::Error },
+    #[fail(display = "failed to cache the vendored dependencies of {} in {:?}: {}", package, path,
+           why)]
+    Cache { package: String, path: PathBuf, why: io::Error },
+}
+
+/// Whether the dependencies were vendored, or copied from the cache.
+#[derive(Debug, PartialEq)]
+pub enum Vendored {
+    Fetched,
+    Cached,
+}
+
+/// The file which pins the dependencies, which the vendored dependencies are cached by.
+fn lockfile(vendor: Vendor) -> &'static str {
+    match vendor {
+        Vendor::Cargo => "Cargo.lock",
+        Vendor::Go => "go.sum",
+        Vendor::Npm => "package-lock.json",
+    }
+}
+
+/// What vendoring adds to the source, relative to it.
+fn outputs(vendor: Vendor) -> &'static [&'static str] {
+    match vendor {
+        Vendor::Cargo => &["vendor", ".cargo/config"],
+        Vendor::Go => &["vendor"],
+        Vendor::Npm => &[".npm-cache"],
+    }
+}
+
+fn fetch(vendor: Vendor, dir: &Path) -> io::Result<()> {
+    match vendor {
+        Vendor::Cargo => {
+            // `cargo vendor` prints the config which replaces crates.io with the vendored crates.
+            let output = Cmd::new("cargo")
+                .args(&["vendor", "--locked", "vendor"])
+                .current_dir(dir)
+                .output()?;
+
+            fs::create_dir_all(dir.join(".cargo"))?;
+            misc::write(dir.join(".cargo/config"), &output.stdout)
+        }
+        Vendor::Go => {
+            Cmd::new("go").args(&["mod", "vendor"]).current_dir(dir).run().map_err(io::Error::from)
+        }
+        Vendor::Npm => {
+            // Builds install from the primed cache with `npm ci --offline --cache .npm-cache`.
+            Cmd::new("npm")
+                .args(&["ci", "--ignore-scripts", "--cache", ".npm-cache"])
+                .current_dir(dir)
+                .run()?;
+
+            fs::remove_dir_all(dir.join("node_modules"))
+        }
+    }
+}
+
+/// Copies the paths from one directory to another, with their parents.
+fn copy(paths: &[&str], from: &Path, to: &Path) -> io::Result<()> {
+    for path in paths {
+        let src = from.join(path);
+        if !src.exists() {
+            continue;
+        }
+
+        let dst = to.join(path);
+        if dst.is_dir() {
+            fs::remove_dir_all(&dst)?;
+        } else if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        Cmd::new("cp").arg("-a").arg(&src).arg(&dst).run()?;
+    }
+
+    Ok(())
+}
+
+/// Vendors the dependencies of the source in `dir`, unless dependencies which were vendored with
+/// the same lockfile are cached in `cache`. Dependencies vendored with other lockfiles are removed
+/// from the cache. When `offline`, dependencies which are not cached are an error.
+pub fn vendor(
+    vendor: Vendor,
+    package: &str,
+    dir: &Path,
+    cache: &Path,
+    offline: bool,
+) -> Result<Vendored, VendorError> {
+    let lockfile = lockfile(vendor);
+    let digest = match File::open(dir.join(lockfile)) {
+        Ok(file) => sha2_256_digest(file)
+            .map_err(|why| VendorError::Command { package: package.to_owned(), why })?,
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => {
+            return Err(VendorError::NoLockfile { package: package.to_owned(), lockfile });
+        }
+        Err(why) => return Err(VendorError::Command { package: package.to_owned(), why }),
+    };
+
+    let cached = cache.join(package).join(&digest);
+    let cache_error = |why: io::Error| VendorError::Cache {
+        package: package.to_owned(),
+        path: cached.clone(),
+        why
+    };
+
+    if cached.is_dir() {
+        info!("using the dependencies of {} which were vendored for its {}", package, lockfile);
+        copy(outputs(vendor), &cached, dir).map_err(&cache_error)?;
+        return Ok(Vendored::Cached);
+    }
+
+    if offline {
+        return Err(VendorError::Offline { package: package.to_owned(), lockfile });
+    }
+
+    info!("vendoring the dependencies of {}", package);
+    fetch(vendor, dir).map_err(|why| VendorError::Command { package: package.to_owned(), why })?;
+
+    let parent = cache.join(package);
+    if parent.exists() {
+        fs::remove_dir_all(&parent).map_err(&cache_error)?;
+    }
+
+    // The cache is only used once it is complete.
+    let partial = parent.join([&digest, ".partial"].concat());
+    fs::create_dir_all(&partial)
+        .and_then(|_| copy(outputs(vendor), dir, &partial))
+        .and_then(|_| fs::rename(&partial, &cached))
+        .map_err(&cache_error)?;
+
+    Ok(Vendored::Fetched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn vendored_dependencies_are_cached_by_lockfile() {
+        let root = TempDir::new("debrep-vendor").unwrap();
+        let dir = root.path().join("pop-shop");
+        let cache = root.path().join("cache");
+        fs::create_dir_all(&dir).unwrap();
+
+        match vendor(Vendor::Go, "pop-shop", &dir, &cache, false) {
+            Err(VendorError::NoLockfile { lockfile, .. }) => assert_eq!(lockfile, "go.sum"),
+            other => panic!("expected a missing lockfile, found {:?}", other),
+        }
+
+        misc::write(dir.join("go.sum"), b"example.com/dep v1.0.0 h1:abc=\n").unwrap();
+        let digest = sha2_256_digest(File::open(dir.join("go.sum")).unwrap()).unwrap();
+        let cached = cache.join("pop-shop").join(&digest);
+
+        match vendor(Vendor::Go, "pop-shop", &dir, &cache, true) {
+            Err(VendorError::Offline { package, .. }) => assert_eq!(package, "pop-shop"),
+            other => panic!("expected an offline error, found {:?}", other),
+        }
+
+        fs::create_dir_all(cached.join("vendor/example.com/dep")).unwrap();
+        misc::write(cached.join("vendor/modules.txt"), b"# example.com/dep v1.0.0\n").unwrap();
+
+        assert_eq!(vendor(Vendor::Go, "pop-shop", &dir, &cache, true).unwrap(), Vendored::Cached);
+        assert!(dir.join("vendor/modules.txt").exists());
+        assert!(dir.join("vendor/example.com/dep").is_dir());
+    }
+}
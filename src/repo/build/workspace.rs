@@ -0,0 +1,148 @@
+//! The working directories of builds. Each build unit, which is a source that is built for a
+//! suite on an architecture, has a workspace of its own in
+//! `build/workspaces/<suite>/<arch>/<source>/`, so that no two builds write to the same path:
+//!
+//! - `outgoing/` is where sbuild runs, and where the packages which it built are written. They
+//!   are moved into the pool from there, and from nowhere else.
+//! - `outgoing/<source>/` is the tree which is built: a copy of the checkout of the source, with
+//!   its `debian` directory laid over it. It is kept in `outgoing/`, since dpkg-source writes
+//!   the source package beside the tree which it is built from, and directories are never moved
+//!   into the pool.
+//! - `tmp/` holds what a build only needs for a while, such as the clone of a `debian` branch.
+//!
+//! The checkouts which fetchers keep in `build/` are only read by builds.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tempdir::TempDir;
+
+/// Where the workspaces of build units are kept.
+pub const WORKSPACES: &str = "build/workspaces";
+
+/// The workspace of a build unit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Workspace {
+    root: PathBuf,
+    name: String,
+}
+
+impl Workspace {
+    pub fn new(pwd: &Path, suite: &str, arch: &str, name: &str) -> Workspace {
+        Workspace { root: path(pwd, suite, arch, name), name: name.to_owned() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where sbuild runs, and writes the packages which it built.
+    pub fn outgoing(&self) -> PathBuf {
+        self.root.join("outgoing")
+    }
+
+    /// The tree of the source which is built.
+    pub fn tree(&self) -> PathBuf {
+        self.outgoing().join(&self.name)
+    }
+
+    /// Creates the directories of the workspace, and removes the files which an earlier build
+    /// left in `outgoing/`, which would otherwise be moved into the pool along with those of this
+    /// build. The tree is kept, so that it is only updated where its source changed.
+    pub fn prepare(&self) -> io::Result<()> {
+        fs::create_dir_all(self.tree())?;
+        fs::create_dir_all(self.root.join("tmp"))?;
+        for entry in self.outgoing().read_dir()? {
+            let path = entry?.path();
+            if !path.symlink_metadata()?.is_dir() {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A directory in `tmp/` of the workspace, which is removed when it is dropped.
+    pub fn temp(&self, prefix: &str) -> io::Result<TempDir> {
+        TempDir::new_in(self.root.join("tmp"), prefix)
+    }
+}
+
+/// The path of the workspace of a build unit.
+pub fn path(pwd: &Path, suite: &str, arch: &str, name: &str) -> PathBuf {
+    pwd.join(WORKSPACES).join(suite).join(arch).join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use repo::pool::{mv_to_pool, PoolIndex};
+    use std::thread;
+    use tempdir::TempDir;
+
+    /// Builds the source in the suite as sbuild would, by writing its packages beside its tree,
+    /// and moves them into the pool of the suite.
+    fn fake_build(pwd: PathBuf, suite: &'static str, version: &'static str) -> Vec<PathBuf> {
+        let workspace = Workspace::new(&pwd, suite, "amd64", "pop-shop");
+        workspace.prepare().unwrap();
+        misc::write(workspace.tree().join("Cargo.toml"), suite).unwrap();
+
+        let outgoing = workspace.outgoing();
+        let branch = workspace.temp("debian-branch").unwrap();
+        misc::write(branch.path().join("control"), suite).unwrap();
+        for file in &[
+            ["pop-shop_", version, ".dsc"].concat(),
+            ["pop-shop_", version, "_amd64.deb"].concat(),
+            ["pop-shop_", version, "_amd64.changes"].concat(),
+        ] {
+            misc::write(outgoing.join(file), suite).unwrap();
+        }
+
+        let root = pwd.join("repo/pool").join(suite);
+        fs::create_dir_all(&root).unwrap();
+        let mut index = PoolIndex::open(&root).unwrap();
+        let mut placed = mv_to_pool(&outgoing, &mut index, "main", true, false, false).unwrap();
+        placed.sort();
+        placed
+    }
+
+    #[test]
+    fn concurrent_builds_do_not_share_their_workspaces() {
+        let dir = TempDir::new("debrep-workspace").unwrap();
+        let pwd = dir.path().to_path_buf();
+
+        // A package which an interrupted build left behind is not published by the next one.
+        let bionic = Workspace::new(&pwd, "bionic", "amd64", "pop-shop");
+        fs::create_dir_all(bionic.outgoing()).unwrap();
+        misc::write(bionic.outgoing().join("pop-shop_0.9_amd64.deb"), b"stale").unwrap();
+
+        let builds: Vec<_> = [("bionic", "1.0"), ("cosmic", "1.1")].iter()
+            .map(|&(suite, version)| {
+                let pwd = pwd.clone();
+                thread::spawn(move || fake_build(pwd, suite, version))
+            })
+            .collect();
+        let placed: Vec<Vec<PathBuf>> = builds.into_iter().map(|x| x.join().unwrap()).collect();
+
+        let pool = pwd.join("repo/pool");
+        assert_eq!(placed[0], vec![
+            pool.join("bionic/main/binary-amd64/p/pop-shop/pop-shop_1.0_amd64.changes"),
+            pool.join("bionic/main/binary-amd64/p/pop-shop/pop-shop_1.0_amd64.deb"),
+            pool.join("bionic/main/source/p/pop-shop/pop-shop_1.0.dsc"),
+        ]);
+        assert_eq!(placed[1].len(), 3);
+        assert!(placed[1].iter().all(|path| path.starts_with(pool.join("cosmic"))));
+        assert!(placed[1].iter().all(|path| misc::read_to_string(path).unwrap() == "cosmic"));
+
+        // The trees are kept, and the temporary directories removed.
+        let cosmic = Workspace::new(&pwd, "cosmic", "amd64", "pop-shop");
+        for (workspace, suite) in vec![(bionic, "bionic"), (cosmic, "cosmic")] {
+            let tree = misc::read_to_string(workspace.tree().join("Cargo.toml")).unwrap();
+            assert_eq!(tree, suite);
+            assert_eq!(workspace.outgoing().read_dir().unwrap().count(), 1);
+            assert_eq!(workspace.root().join("tmp").read_dir().unwrap().count(), 0);
+        }
+    }
+}
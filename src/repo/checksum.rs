@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use md5::{Digest as Md5Digest, Md5};
+use misc;
+use rayon::prelude::*;
+use sha1::Sha1;
+use sha2::{Sha256, Digest};
+use walkdir::WalkDir;
+
+pub fn sha2_256_digest(file: File) -> io::Result<String> {
+    let mut hasher = Sha256::default();
+    let data = &mut BufReader::new(file);
+    loop {
+        let read = {
+            let buffer = data.fill_buf()?;
+            if buffer.is_empty() { break }
+            hasher.input(buffer);
+            buffer.len()
+        };
+
+        data.consume(read);
+    }
+
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// The MD5, SHA1, and SHA256 digests of a file, which the indices of a repo list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Digests {
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+}
+
+/// Hashes the file with MD5, SHA1, and SHA256 as it is read once.
+pub fn digests(file: File) -> io::Result<Digests> {
+    let (mut md5, mut sha1, mut sha256) = (Md5::default(), Sha1::default(), Sha256::default());
+    let data = &mut BufReader::new(file);
+    loop {
+        let read = {
+            let buffer = data.fill_buf()?;
+            if buffer.is_empty() { break }
+            Md5Digest::input(&mut md5, buffer);
+            sha1.input(buffer);
+            sha256.input(buffer);
+            buffer.len()
+        };
+
+        data.consume(read);
+    }
+
+    Ok(Digests {
+        md5: format!("{:x}", md5.result()),
+        sha1: format!("{:x}", sha1.result()),
+        sha256: format!("{:x}", sha256.result()),
+    })
+}
+
+/// Returns true if the value has the form of a hex-encoded SHA256 digest.
+pub fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// A SHA256 digest of the paths, permissions, and contents of the files in a directory, and of
+/// the targets of its symlinks. Version control directories are left out. The contents of files
+/// are hashed in parallel, and combined in the order of the walk.
+pub fn tree_digest(dir: &Path) -> io::Result<String> {
+    let entries = WalkDir::new(dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Linked assets are hashed by their contents, so that changing them is a change.
+    let contents = entries.par_iter()
+        .map(|entry| {
+            let path = entry.path();
+            if path.is_file() { sha2_256_digest(File::open(path)?).map(Some) } else { Ok(None) }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut hasher = Sha256::default();
+    for (entry, contents) in entries.iter().zip(contents) {
+        let path = entry.path();
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        hasher.input(relative.as_os_str().as_bytes());
+        hasher.input(format!("\0{:o}\0", entry.metadata()?.mode()).as_bytes());
+
+        if entry.file_type().is_symlink() {
+            hasher.input(path.read_link()?.as_os_str().as_bytes());
+        }
+
+        if let Some(contents) = contents {
+            hasher.input(contents.as_bytes());
+        }
+
+        hasher.input(b"\n");
+    }
+
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Where the digests of pool files and shared assets are cached.
+pub const HASHES: &str = "assets/cache/.sha256";
+
+/// The first line of the cache, which changes with its format. Caches in another format are
+/// ignored, and rebuilt as files are hashed.
+const CACHE_HEADER: &str = "debrep-hashes 2";
+
+/// A file, by its device and inode, so that every hard link of a file shares its digest, and a
+/// file which was moved within the pool is not hashed again.
+type FileId = (u64, u64);
+
+#[derive(Debug, PartialEq)]
+struct CachedDigest {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    digest: String,
+}
+
+/// A persistent cache of SHA256 digests, keyed on the device, inode, size, and modification time
+/// of each file, so that large files which have not changed are not hashed again on every run.
+pub struct HashCache {
+    path: PathBuf,
+    entries: HashMap<FileId, CachedDigest>,
+    /// Hash every file again, replacing what was cached.
+    rehash: bool,
+    modified: bool,
+}
+
+impl HashCache {
+    /// Opens the cache at the given path. Missing or unreadable lines are simply not cached, and
+    /// nothing is if the cache is in another format. With `rehash`, every file is hashed again.
+    pub fn open<P: Into<PathBuf>>(path: P, rehash: bool) -> HashCache {
+        let path = path.into();
+        let mut entries = HashMap::new();
+
+        if let Ok(data) = misc::read_to_string(&path) {
+            let mut lines = data.lines();
+            if lines.next() == Some(CACHE_HEADER) {
+                entries.extend(lines.filter_map(parse_entry));
+            } else {
+                debug!("{} is in another format, and will be rebuilt", path.display());
+            }
+        }
+
+        HashCache { path, entries, rehash, modified: false }
+    }
+
+    /// Returns the SHA256 digest of the file, hashing it only if it changed since it was cached.
+    pub fn sha256(&mut self, path: &Path) -> io::Result<String> {
+        let mut digests = self.sha256_all(&[path]);
+        digests.pop().expect("a digest for each path")
+    }
+
+    /// Returns the SHA256 digest of each file, in the same order. Files which changed since they
+    /// were cached are hashed in parallel.
+    pub fn sha256_all(&mut self, paths: &[&Path]) -> Vec<io::Result<String>> {
+        let files: Vec<io::Result<(FileId, CachedDigest, bool)>> = paths.iter()
+            .map(|path| {
+                let metadata = path.metadata()?;
+                let id = (metadata.dev(), metadata.ino());
+                let (size, mtime, mtime_nsec) =
+                    (metadata.len(), metadata.mtime(), metadata.mtime_nsec());
+                let cached = self.entries.get(&id).and_then(|cached| {
+                    let unchanged = !self.rehash
+                        && cached.size == size
+                        && cached.mtime == mtime
+                        && cached.mtime_nsec == mtime_nsec;
+                    if unchanged { Some(cached.digest.clone()) } else { None }
+                });
+
+                let fresh = cached.is_some();
+                let digest = cached.unwrap_or_else(String::new);
+                Ok((id, CachedDigest { size, mtime, mtime_nsec, digest }, fresh))
+            })
+            .collect();
+
+        let files: Vec<io::Result<(FileId, CachedDigest, bool)>> = files.into_par_iter()
+            .zip(paths.par_iter())
+            .map(|(file, path)| {
+                let (id, mut entry, fresh) = file?;
+                if !fresh {
+                    debug!("hashing {}", path.display());
+                    entry.digest = sha2_256_digest(File::open(path)?)?;
+                }
+                Ok((id, entry, fresh))
+            })
+            .collect();
+
+        files.into_iter()
+            .map(|file| {
+                let (id, entry, fresh) = file?;
+                let digest = entry.digest.clone();
+                if !fresh {
+                    self.entries.insert(id, entry);
+                    self.modified = true;
+                }
+                Ok(digest)
+            })
+            .collect()
+    }
+
+    /// Writes the cache back to disk, if any new digests were computed.
+    pub fn save(&self) -> io::Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+
+        let mut file = File::create(&self.path)?;
+        writeln!(file, "{}", CACHE_HEADER)?;
+        for (&(dev, ino), entry) in &self.entries {
+            writeln!(
+                file,
+                "{} {} {} {} {}.{}",
+                entry.digest, dev, ino, entry.size, entry.mtime, entry.mtime_nsec
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a line of the cache, which is `<digest> <device> <inode> <size> <mtime>.<nsec>`.
+fn parse_entry(line: &str) -> Option<(FileId, CachedDigest)> {
+    let mut fields = line.split_whitespace();
+    let digest = fields.next()?;
+    if !is_sha256(digest) {
+        return None;
+    }
+
+    let dev = fields.next()?.parse::<u64>().ok()?;
+    let ino = fields.next()?.parse::<u64>().ok()?;
+    let size = fields.next()?.parse::<u64>().ok()?;
+    let mut mtime = fields.next()?.splitn(2, '.');
+    let secs = mtime.next()?.parse::<i64>().ok()?;
+    let nsecs = mtime.next()?.parse::<i64>().ok()?;
+    if fields.next().is_some() {
+        return None;
+    }
+
+    let entry = CachedDigest { size, mtime: secs, mtime_nsec: nsecs, digest: digest.to_owned() };
+    Some(((dev, ino), entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn files_are_hashed_with_each_digest() {
+        let dir = TempDir::new("debrep-hashes").unwrap();
+        let path = dir.path().join("abc");
+        misc::write(&path, "abc").unwrap();
+
+        assert_eq!(digests(File::open(&path).unwrap()).unwrap(), Digests {
+            md5: "900150983cd24fb0d6963f7d28e17f72".into(),
+            sha1: "a9993e364706816aba3e25717850c26c9cd0d89d".into(),
+            sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".into(),
+        });
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let dir = TempDir::new("debrep-hashes").unwrap();
+        let file = dir.path().join("asset with spaces.bin");
+        misc::write(&file, b"contents").unwrap();
+        let expected = sha2_256_digest(File::open(&file).unwrap()).unwrap();
+
+        let mut cache = HashCache::open(dir.path().join("hashes"), false);
+        assert_eq!(cache.sha256(&file).unwrap(), expected);
+        cache.save().unwrap();
+
+        let mut cache = HashCache::open(dir.path().join("hashes"), false);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.sha256(&file).unwrap(), expected);
+        assert!(!cache.modified);
+    }
+
+    #[test]
+    fn trees_are_hashed_by_content() {
+        let dir = TempDir::new("debrep-hashes").unwrap();
+        fs::create_dir_all(dir.path().join("tree/src")).unwrap();
+        fs::create_dir_all(dir.path().join("tree/.git")).unwrap();
+        misc::write(dir.path().join("tree/src/main.c"), b"int main() {}").unwrap();
+        let tree = dir.path().join("tree");
+        let before = tree_digest(&tree).unwrap();
+
+        misc::write(dir.path().join("tree/.git/HEAD"), b"ref: refs/heads/master").unwrap();
+        assert_eq!(tree_digest(&tree).unwrap(), before);
+
+        misc::write(dir.path().join("tree/src/main.c"), b"int main() { return 1; }").unwrap();
+        assert_ne!(tree_digest(&tree).unwrap(), before);
+    }
+
+    #[test]
+    fn changed_files_are_hashed_again() {
+        let dir = TempDir::new("debrep-hashes").unwrap();
+        let file = dir.path().join("asset");
+        misc::write(&file, b"before").unwrap();
+
+        let mut cache = HashCache::open(dir.path().join("hashes"), false);
+        let before = cache.sha256(&file).unwrap();
+        misc::write(&file, b"after the change").unwrap();
+        let after = cache.sha256(&file).unwrap();
+
+        assert_ne!(before, after);
+        assert_eq!(after, sha2_256_digest(File::open(&file).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn files_are_hashed_together_in_order() {
+        let dir = TempDir::new("debrep-hashes").unwrap();
+        let names = ["a", "b", "c", "d"];
+        for name in &names {
+            misc::write(dir.path().join(name), name.repeat(1000).as_bytes()).unwrap();
+        }
+
+        let mut cache = HashCache::open(dir.path().join("hashes"), false);
+        let cached = cache.sha256(&dir.path().join("c")).unwrap();
+        let mut paths: Vec<PathBuf> = names.iter().map(|name| dir.path().join(name)).collect();
+        paths.insert(2, dir.path().join("missing"));
+        let paths: Vec<&Path> = paths.iter().map(|path| path.as_path()).collect();
+        let digests = cache.sha256_all(&paths);
+
+        assert_eq!(digests.len(), 5);
+        assert!(digests[2].is_err());
+        assert_eq!(digests[3].as_ref().unwrap(), &cached);
+        for (path, digest) in paths.iter().zip(&digests).filter(|&(_, x)| x.is_ok()) {
+            let expected = sha2_256_digest(File::open(path).unwrap()).unwrap();
+            assert_eq!(digest.as_ref().unwrap(), &expected);
+        }
+
+        assert_eq!(cache.entries.len(), 4);
+    }
+
+    #[test]
+    fn caches_of_other_formats_and_rehashing_hash_again() {
+        let dir = TempDir::new("debrep-hashes").unwrap();
+        let file = dir.path().join("pool.deb");
+        misc::write(&file, b"contents").unwrap();
+        let expected = sha2_256_digest(File::open(&file).unwrap()).unwrap();
+        let path = dir.path().join("hashes");
+
+        // A cache from before files were keyed on their inode.
+        misc::write(&path, format!("{} 8 0.0 {}\n", "0".repeat(64), file.display())).unwrap();
+        let mut cache = HashCache::open(&path, false);
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.sha256(&file).unwrap(), expected);
+        cache.save().unwrap();
+
+        // Corrupt lines are ignored, and the rest of the cache is used.
+        let mut data = misc::read_to_string(&path).unwrap();
+        data.push_str("not a digest 1 2 3 4.5\n");
+        data.push_str(&"f".repeat(64));
+        misc::write(&path, data).unwrap();
+        let mut cache = HashCache::open(&path, false);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.sha256(&file).unwrap(), expected);
+        assert!(!cache.modified);
+
+        // Hard links share the digest of the file.
+        let link = dir.path().join("linked.deb");
+        fs::hard_link(&file, &link).unwrap();
+        assert_eq!(cache.sha256(&link).unwrap(), expected);
+        assert!(!cache.modified);
+
+        let mut cache = HashCache::open(&path, true);
+        assert_eq!(cache.sha256(&file).unwrap(), expected);
+        assert!(cache.modified);
+    }
+}
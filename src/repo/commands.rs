@@ -0,0 +1,230 @@
+use std::io;
+use std::process::{Command, Stdio};
+
+use config::{Config, DebianPath, Source, SourceLocation, UploadMethod, Vendor};
+use misc;
+use super::build::{chroot, chroot_of};
+use super::BuildOptions;
+
+/// The phases of a run, which each need their own external commands.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Fetch,
+    Build,
+    Publish,
+    /// Uploads the packages of sources to the archives of their `upload_targets`.
+    Upload,
+}
+
+/// The external commands which the phases need, according to the features which are configured.
+pub fn required(config: &Config, phases: &[Phase], options: BuildOptions) -> Vec<&'static str> {
+    let units = config.build_units();
+    let any = |predicate: &Fn(&Source) -> bool| units.iter().any(|item| predicate(item));
+    let mut commands = Vec::new();
+
+    if phases.contains(&Phase::Fetch) {
+        let git = |item: &Source| match item.location {
+            Some(SourceLocation::Git { .. }) => true,
+            _ => false,
+        };
+
+        if !options.offline && any(&git) {
+            commands.push("git");
+        }
+
+        if config.repack.as_ref().map_or(false, |repack| !repack.is_empty()) {
+            commands.extend_from_slice(&["dpkg-deb", "md5sum"]);
+        }
+
+        if config.mirror.is_some() || config.ppa.is_some() {
+            commands.push("gpgv");
+        }
+    }
+
+    if phases.contains(&Phase::Build) && !units.is_empty() {
+        commands.push("sbuild");
+        if config.use_rsync == Some(true) {
+            commands.push("rsync");
+        }
+
+        let branch = |item: &Source| match item.debian {
+            Some(DebianPath::Branch { .. }) => true,
+            _ => false,
+        };
+
+        if any(&branch) {
+            commands.push("git");
+        }
+
+        if config.lintian.is_some() || any(&|item| item.lintian_fail_on.is_some()) {
+            commands.push("lintian");
+        }
+
+        let install_check = config.install_check.is_some();
+        if any(&|item| item.install_check.unwrap_or(install_check)) {
+            commands.push("schroot");
+        }
+
+        if any(&|item| item.piuparts) {
+            commands.extend_from_slice(&["piuparts", "dpkg-deb"]);
+        }
+
+        if any(&|item| item.autopkgtest) {
+            commands.push("autopkgtest");
+        }
+
+        if config.signing.is_some() && !options.no_sign {
+            commands.extend_from_slice(&["debsign", "gpg"]);
+        }
+
+        for vendor in units.iter().filter_map(|item| item.vendor) {
+            commands.push(match vendor {
+                Vendor::Cargo => "cargo",
+                Vendor::Go => "go",
+                Vendor::Npm => "npm",
+            });
+        }
+    }
+
+    if phases.contains(&Phase::Publish) {
+        commands.push("apt-ftparchive");
+        if !options.unsigned || config.sign_checksums {
+            commands.push("gpg");
+        }
+    }
+
+    if phases.contains(&Phase::Upload) && !options.no_upload && !options.offline {
+        let targets = units.iter().flat_map(|item| config.upload_targets_of(item));
+        for target in targets {
+            commands.push(match config.upload_target(&target).map(|target| target.method) {
+                Some(UploadMethod::Sftp) => "sftp",
+                _ => "curl",
+            });
+        }
+    }
+
+    commands.sort();
+    commands.dedup();
+    commands
+}
+
+/// The schroots which the phases need: the chroots which sbuild builds the archive in, one for
+/// each architecture which a source is built for, and the chroots which packages are installed
+/// in, and checked with piuparts in.
+pub fn chroots(config: &Config, phases: &[Phase]) -> io::Result<Vec<String>> {
+    let units = config.build_units();
+    if !phases.contains(&Phase::Build) || units.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let suite = config.build_suite();
+    let mut chroots = vec![chroot(None, &suite)?];
+    let host = misc::host_architecture()?;
+    let architectures = config.effective_suite(&config.archive).architectures;
+    for item in &units {
+        for arch in item.build_architectures(&architectures, &host) {
+            if arch != "all" {
+                chroots.push(chroot_of(&suite, &arch));
+            }
+        }
+    }
+
+    let install_check = config.install_check.is_some();
+    if units.iter().any(|item| item.install_check.unwrap_or(install_check)) {
+        let configured = config.install_check.as_ref().and_then(|x| x.chroot.as_ref());
+        chroots.push(chroot(configured, &suite)?);
+    }
+
+    if units.iter().any(|item| item.piuparts) {
+        let configured = config.piuparts.as_ref().and_then(|x| x.chroot.as_ref());
+        chroots.push(chroot(configured, &suite)?);
+    }
+
+    chroots.sort();
+    chroots.dedup();
+    Ok(chroots)
+}
+
+/// The chroots which schroot does not know of, by their names or aliases.
+pub fn missing_chroots(chroots: &[String]) -> Vec<String> {
+    chroots.iter()
+        .filter(|chroot| {
+            let status = Command::new("schroot")
+                .args(&["--info", "--chroot", chroot.as_str()])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            !status.map(|status| status.success()).unwrap_or(false)
+        })
+        .cloned()
+        .collect()
+}
+
+/// The chroots which sbuild can build in, which are named `<archive>-<arch>-sbuild`.
+pub fn sbuild_chroots() -> Vec<String> {
+    Command::new("schroot")
+        .arg("--list")
+        .stderr(Stdio::null())
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(|line| line.trim().trim_left_matches("chroot:"))
+                .filter(|chroot| chroot.ends_with("-sbuild"))
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    const CONFIG: &str = r#"
+archive = "bionic"
+version = "18.04"
+origin = "pop-os-staging-ubuntu"
+label = "Pop!_OS Staging"
+email = "debrepbuild@example.com"
+
+[signing]
+key = "debrepbuild@example.com"
+
+[[source]]
+name = "pop-shop"
+vendor = "cargo"
+piuparts = true
+
+upload_targets = ["ppa:system76/pop"]
+
+[source.location]
+url = "https://github.com/pop-os/shop.git"
+branch = "master"
+"#;
+
+    #[test]
+    fn commands_follow_the_config() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let options = BuildOptions::default();
+        assert_eq!(required(&config, &[Phase::Fetch], options), vec!["git"]);
+        assert_eq!(
+            required(&config, &[Phase::Build], options),
+            vec!["cargo", "debsign", "dpkg-deb", "gpg", "piuparts", "sbuild"]
+        );
+        assert_eq!(
+            required(&config, &[Phase::Publish], options),
+            vec!["apt-ftparchive", "gpg"]
+        );
+        assert_eq!(required(&config, &[Phase::Upload], options), vec!["curl"]);
+
+        let options = BuildOptions { no_sign: true, offline: true, ..options };
+        assert!(required(&config, &[Phase::Fetch], options).is_empty());
+        assert!(!required(&config, &[Phase::Build], options).contains(&"debsign"));
+        assert!(required(&config, &[Phase::Upload], options).is_empty());
+
+        let options = BuildOptions { unsigned: true, ..options };
+        assert!(!required(&config, &[Phase::Publish], options).contains(&"gpg"));
+    }
+}
@@ -0,0 +1,421 @@
+//! Reads the paths of the files which `.deb` packages install, for the `Contents` indices, and
+//! the control files of packages, for the metrics of builds.
+//!
+//! Packages are streamed: the ar archive is read member by member, its `data.tar` is read through
+//! its decompressor, and only the headers of the tar entries are kept, so that a package of any
+//! size is read with a fixed amount of memory. Extended headers, which are read into memory, are
+//! limited in size and number, so that a malformed package fails alone, instead of exhausting
+//! memory.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use xz2::read::XzDecoder;
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+
+const BLOCK: usize = 512;
+
+/// The largest extended header, such as a long name, which is read into memory.
+const MAX_EXTENDED: u64 = 64 * 1024;
+
+/// The most extended headers which may precede an entry.
+const MAX_EXTENDED_HEADERS: usize = 8;
+
+/// The largest control file which is read into memory.
+const MAX_CONTROL: u64 = 1024 * 1024;
+
+/// The paths of the files and symlinks in the package, without a leading `./`, in the order of
+/// the archive.
+pub fn paths(deb: &Path) -> io::Result<Vec<String>> {
+    paths_of(BufReader::new(File::open(deb)?))
+}
+
+pub fn paths_of<R: Read>(deb: R) -> io::Result<Vec<String>> {
+    entries(member(deb, "data.tar")?)
+}
+
+/// The `control` file of the package, from its `control.tar`.
+pub fn control(deb: &Path) -> io::Result<String> {
+    control_of(BufReader::new(File::open(deb)?))
+}
+
+pub fn control_of<R: Read>(deb: R) -> io::Result<String> {
+    control_file(member(deb, "control.tar")?)
+}
+
+/// A member of a package, which is read through its decompressor.
+enum Member<R: Read> {
+    Plain(R),
+    Gz(GzDecoder<R>),
+    Xz(XzDecoder<R>),
+}
+
+impl<R: Read> Read for Member<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Member::Plain(ref mut member) => member.read(buffer),
+            Member::Gz(ref mut member) => member.read(buffer),
+            Member::Xz(ref mut member) => member.read(buffer),
+        }
+    }
+}
+
+/// The member of the package whose name begins with `prefix`, such as `data.tar`.
+fn member<R: Read>(mut deb: R, prefix: &str) -> io::Result<Member<io::Take<R>>> {
+    let mut magic = [0; 8];
+    if read_full(&mut deb, &mut magic)? != magic.len() || magic != AR_MAGIC {
+        return Err(invalid("it is not an ar archive"));
+    }
+
+    loop {
+        let mut header = [0; 60];
+        match read_full(&mut deb, &mut header)? {
+            0 => return Err(invalid(&format!("it has no {} member", prefix))),
+            60 if &header[58..] == b"`\n" => (),
+            _ => return Err(invalid("an ar header is malformed")),
+        }
+
+        let name = String::from_utf8_lossy(&header[..16]);
+        let name = name.trim_right().trim_right_matches('/');
+        let size = String::from_utf8_lossy(&header[48..58]).trim().parse::<u64>()
+            .map_err(|_| invalid("an ar header has an invalid size"))?;
+
+        if name.starts_with(prefix) {
+            return match &name[prefix.len()..] {
+                "" => Ok(Member::Plain(deb.take(size))),
+                ".gz" => Ok(Member::Gz(GzDecoder::new(deb.take(size)))),
+                ".xz" => Ok(Member::Xz(XzDecoder::new(deb.take(size)))),
+                other => Err(invalid(&format!("{}{} is not supported", prefix, other))),
+            };
+        }
+
+        // Members are padded to an even length.
+        skip(&mut deb, size + size % 2)?;
+    }
+}
+
+/// The paths of the files and symlinks of a tar archive, read one header at a time.
+fn entries<R: Read>(mut tar: R) -> io::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    let mut block = [0; BLOCK];
+    let mut long_name = None;
+    let mut extended = 0;
+    loop {
+        if read_full(&mut tar, &mut block)? != BLOCK {
+            return Err(invalid("the data.tar was cut short"));
+        }
+
+        if block.iter().all(|&byte| byte == 0) {
+            return Ok(paths);
+        }
+
+        if checksum(&block) != octal(&block[148..156])? {
+            return Err(invalid("a tar header has an invalid checksum"));
+        }
+
+        let size = size(&block[124..136])?;
+        let padded = (size + BLOCK as u64 - 1) / BLOCK as u64 * BLOCK as u64;
+        match block[156] {
+            kind @ b'L' | kind @ b'x' | kind @ b'g' | kind @ b'K' => {
+                extended += 1;
+                if extended > MAX_EXTENDED_HEADERS {
+                    return Err(invalid("too many extended tar headers precede an entry"));
+                }
+
+                if size > MAX_EXTENDED {
+                    return Err(invalid("an extended tar header is too large"));
+                }
+
+                let mut data = vec![0; size as usize];
+                tar.read_exact(&mut data)?;
+                skip(&mut tar, padded - size)?;
+                match kind {
+                    b'L' => long_name = Some(c_string(&data)),
+                    b'x' => long_name = pax_path(&data).or(long_name),
+                    _ => (),
+                }
+            }
+            kind => {
+                let name = long_name.take().unwrap_or_else(|| header_name(&block));
+                extended = 0;
+
+                // Regular files, hard links, symlinks, and contiguous files are listed.
+                if [b'0', b'\0', b'1', b'2', b'7'].contains(&kind) {
+                    let name = name.trim_left_matches("./").trim_left_matches('/');
+                    if !name.is_empty() {
+                        paths.push(name.to_owned());
+                    }
+                }
+
+                skip(&mut tar, padded)?;
+            }
+        }
+    }
+}
+
+/// The contents of the `control` file of a `control.tar`.
+fn control_file<R: Read>(mut tar: R) -> io::Result<String> {
+    let mut block = [0; BLOCK];
+    loop {
+        if read_full(&mut tar, &mut block)? != BLOCK || block.iter().all(|&byte| byte == 0) {
+            return Err(invalid("the control.tar has no control file"));
+        }
+
+        if checksum(&block) != octal(&block[148..156])? {
+            return Err(invalid("a tar header has an invalid checksum"));
+        }
+
+        let size = size(&block[124..136])?;
+        let name = header_name(&block);
+        if name.trim_left_matches("./") == "control" && [b'0', b'\0'].contains(&block[156]) {
+            if size > MAX_CONTROL {
+                return Err(invalid("the control file is too large"));
+            }
+
+            let mut data = vec![0; size as usize];
+            tar.read_exact(&mut data)?;
+            return Ok(String::from_utf8_lossy(&data).into_owned());
+        }
+
+        skip(&mut tar, (size + BLOCK as u64 - 1) / BLOCK as u64 * BLOCK as u64)?;
+    }
+}
+
+/// The sum of the bytes of the header, with its checksum field counted as spaces.
+fn checksum(block: &[u8; BLOCK]) -> u64 {
+    block.iter()
+        .enumerate()
+        .map(|(id, &byte)| if id >= 148 && id < 156 { b' ' } else { byte } as u64)
+        .sum()
+}
+
+fn octal(field: &[u8]) -> io::Result<u64> {
+    let field = String::from_utf8_lossy(field);
+    let field = field.trim_matches(|c: char| c == '\0' || c == ' ');
+    u64::from_str_radix(field, 8).map_err(|_| invalid("a tar header has an invalid number"))
+}
+
+/// The size of an entry, which is in octal, or big-endian binary for sizes of 8 GiB and more.
+fn size(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 == 0 {
+        return octal(field);
+    }
+
+    if field[1..4].iter().any(|&byte| byte != 0) {
+        return Err(invalid("a tar entry is too large"));
+    }
+
+    Ok(field[4..].iter().fold(0, |size, &byte| size << 8 | byte as u64))
+}
+
+/// The name of an entry, with the prefix of the ustar format.
+fn header_name(block: &[u8; BLOCK]) -> String {
+    let name = c_string(&block[..100]);
+    if &block[257..262] == b"ustar" {
+        let prefix = c_string(&block[345..500]);
+        if !prefix.is_empty() {
+            return [&prefix, "/", &name].concat();
+        }
+    }
+
+    name
+}
+
+/// The `path` of a pax extended header, whose records are `<length> <key>=<value>\n`.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let data = String::from_utf8_lossy(data);
+    data.lines()
+        .filter_map(|record| record.splitn(2, ' ').nth(1))
+        .filter_map(|record| {
+            let mut fields = record.splitn(2, '=');
+            match (fields.next(), fields.next()) {
+                (Some("path"), Some(path)) => Some(path.to_owned()),
+                _ => None,
+            }
+        })
+        .last()
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Reads until the buffer is full or the reader ends, returning how much was read.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(bytes) => read += bytes,
+            Err(ref why) if why.kind() == io::ErrorKind::Interrupted => (),
+            Err(why) => return Err(why),
+        }
+    }
+
+    Ok(read)
+}
+
+/// Reads past bytes which are not needed, such as the contents of files.
+fn skip<R: Read>(reader: &mut R, bytes: u64) -> io::Result<()> {
+    if io::copy(&mut reader.take(bytes), &mut io::sink())? != bytes {
+        return Err(invalid("the package was cut short"));
+    }
+
+    Ok(())
+}
+
+fn invalid(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why.to_owned())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use deflate::write::GzEncoder;
+    use deflate::Compression;
+    use misc;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use tempdir::TempDir;
+
+    /// A tar header of an entry of the kind, with a valid checksum.
+    fn tar_header(name: &str, kind: u8, size: u64) -> Vec<u8> {
+        let mut block = [0; BLOCK];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..107].copy_from_slice(b"0000644");
+        block[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        block[136..147].copy_from_slice(b"00000000000");
+        block[156] = kind;
+        block[257..263].copy_from_slice(b"ustar\0");
+        let sum = checksum(&block);
+        block[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        block[155] = b' ';
+        block.to_vec()
+    }
+
+    /// A tar archive of the entries, which are `(name, kind, contents)`.
+    pub fn tar(entries: &[(&str, u8, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for &(name, kind, contents) in entries {
+            tar.extend(tar_header(name, kind, contents.len() as u64));
+            tar.extend_from_slice(contents);
+            let padding = (BLOCK - contents.len() % BLOCK) % BLOCK;
+            tar.extend(vec![0; padding]);
+        }
+
+        tar.extend(vec![0; BLOCK * 2]);
+        tar
+    }
+
+    fn ar_header(name: &str, size: u64) -> Vec<u8> {
+        format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 100644, size).into_bytes()
+    }
+
+    /// A package whose data.tar is the member.
+    pub fn deb(data_name: &str, data: &[u8]) -> Vec<u8> {
+        let mut deb = AR_MAGIC.to_vec();
+        for &(name, contents) in &[("debian-binary", &b"2.0\n"[..]), (data_name, data)] {
+            deb.extend(ar_header(name, contents.len() as u64));
+            deb.extend_from_slice(contents);
+            if contents.len() % 2 == 1 {
+                deb.push(b'\n');
+            }
+        }
+
+        deb
+    }
+
+    fn peak_memory() -> u64 {
+        let status = misc::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|line| line.starts_with("VmHWM:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse::<u64>().unwrap() * 1024
+    }
+
+    #[test]
+    fn the_paths_of_packages_are_read() {
+        let pax = b"31 path=usr/share/doc/pop/NEWS\n";
+        let long = ["./usr/share/", &"long/".repeat(30), "file"].concat();
+        let tar = tar(&[
+            ("./", b'5', b""),
+            ("./usr/bin/pop", b'0', b"#!/bin/sh\n"),
+            ("./usr/bin/pop-link", b'2', b""),
+            ("././@LongLink", b'L', long.as_bytes()),
+            ("./usr/share/truncated", b'0', b"long"),
+            ("./PaxHeaders/NEWS", b'x', &pax[..]),
+            ("./usr/share/doc/pop/NEW", b'0', b"news"),
+        ]);
+
+        let expected = vec![
+            "usr/bin/pop".to_owned(),
+            "usr/bin/pop-link".to_owned(),
+            long[2..].to_owned(),
+            "usr/share/doc/pop/NEWS".to_owned(),
+        ];
+        assert_eq!(paths_of(&deb("data.tar", &tar)[..]).unwrap(), expected);
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::Default);
+        gz.write_all(&tar).unwrap();
+        let gz = gz.finish().unwrap();
+        assert_eq!(paths_of(&deb("data.tar.gz/", &gz)[..]).unwrap(), expected);
+    }
+
+    #[test]
+    fn control_files_are_read() {
+        let control = "Package: pop\nInstalled-Size: 42\n";
+        let tar = tar(&[
+            ("./", b'5', b""),
+            ("./md5sums", b'0', b"0a1b2c  usr/bin/pop\n"),
+            ("./control", b'0', control.as_bytes()),
+        ]);
+
+        assert_eq!(control_of(&deb("control.tar", &tar)[..]).unwrap(), control);
+        assert!(control_of(&deb("data.tar", &tar)[..]).is_err());
+    }
+
+    #[test]
+    fn malformed_packages_fail_alone() {
+        let mut tar = tar(&[("./usr/bin/pop", b'0', b"#!/bin/sh\n")]);
+        tar[0] = b'X';
+        let why = paths_of(&deb("data.tar", &tar)[..]).unwrap_err();
+        assert_eq!(why.kind(), io::ErrorKind::InvalidData);
+        assert!(why.to_string().contains("checksum"), "{}", why);
+
+        let chained: Vec<(&str, u8, &[u8])> = vec![("././@LongLink", b'L', b"name"); 9];
+        let why = paths_of(&deb("data.tar", &self::tar(&chained))[..]).unwrap_err();
+        assert!(why.to_string().contains("too many"), "{}", why);
+
+        // An extended header which claims to be huge is not read into memory.
+        let mut huge = tar_header("././@LongLink", b'L', 1 << 32);
+        huge.extend(vec![0; BLOCK * 2]);
+        let why = paths_of(&deb("data.tar", &huge)[..]).unwrap_err();
+        assert!(why.to_string().contains("too large"), "{}", why);
+
+        assert!(paths_of(&b"!<arch>\ndebian-binary   "[..]).is_err());
+        assert!(paths_of(&b"not a package"[..]).is_err());
+    }
+
+    #[test]
+    fn large_packages_are_read_with_flat_memory() {
+        // The contents of the file are a hole, which takes no space on disk.
+        const SIZE: u64 = 3 << 30;
+        let dir = TempDir::new("debrep-deb").unwrap();
+        let path = dir.path().join("large_1.0_amd64.deb");
+        let data_size = BLOCK as u64 + SIZE + BLOCK as u64 * 2;
+        let mut file = OpenOptions::new().create(true).write(true).open(&path).unwrap();
+        file.write_all(AR_MAGIC).unwrap();
+        file.write_all(&ar_header("data.tar", data_size)).unwrap();
+        file.write_all(&tar_header("./usr/share/large", b'0', SIZE)).unwrap();
+        file.seek(SeekFrom::Current((SIZE + BLOCK as u64 * 2) as i64)).unwrap();
+        file.set_len(AR_MAGIC.len() as u64 + 60 + data_size).unwrap();
+        drop(file);
+
+        let before = peak_memory();
+        assert_eq!(paths(&path).unwrap(), vec!["usr/share/large".to_owned()]);
+        let growth = peak_memory().saturating_sub(before);
+        assert!(growth < 64 << 20, "reading the package used {} bytes", growth);
+    }
+}
@@ -0,0 +1,360 @@
+//! The digest of a run, which compares the binary packages that the dists of each suite published
+//! before the run with those which they publish after it: the packages which are new, upgraded,
+//! downgraded, or removed, and those which are now built for other architectures. It is written
+//! to `digest.md`, or `digest.txt`, in the directory of the run, with a section for each suite
+//! which the run published, and is given to the `post_publish` hooks which deliver it.
+//!
+//! A frozen suite is not published, so its section only tells how many packages are staged for
+//! its release. What a run placed in its pool before it was frozen is published along with them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use config::DigestFormat;
+use misc::{self, control_field};
+use super::generate;
+use super::run::Run;
+use super::version;
+
+/// The binary packages which the dists of a suite publish, by name, with the newest version of
+/// the package for each architecture.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    packages: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Snapshot {
+    /// Reads the `Packages` indices of the suite, of which there are none before it is first
+    /// published.
+    pub fn read(repo: &Path, archive: &str) -> io::Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        for index in generate::packages_indices(repo, archive)? {
+            snapshot.add(&misc::read_to_string(&index)?);
+        }
+
+        Ok(snapshot)
+    }
+
+    fn add(&mut self, index: &str) {
+        for stanza in index.split("\n\n") {
+            let fields = (control_field(stanza, "Package"), control_field(stanza, "Version"));
+            let (name, version) = match fields {
+                (Some(name), Some(version)) => (name, version),
+                _ => continue,
+            };
+
+            let arch = control_field(stanza, "Architecture").unwrap_or_else(|| "all".to_owned());
+            let versions = self.packages.entry(name).or_insert_with(BTreeMap::new);
+            let newer = versions.get(&arch).map_or(true, |current| is_newer(&version, current));
+            if newer {
+                versions.insert(arch, version);
+            }
+        }
+    }
+}
+
+/// How a package changed, in the order which the sections of a digest list them in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    New,
+    Upgraded,
+    Downgraded,
+    Removed,
+    /// The version is the same, but it is built for other architectures.
+    Architectures,
+}
+
+impl Kind {
+    const ALL: [Kind; 5] =
+        [Kind::New, Kind::Upgraded, Kind::Downgraded, Kind::Removed, Kind::Architectures];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::New => "new",
+            Kind::Upgraded => "upgraded",
+            Kind::Downgraded => "downgraded",
+            Kind::Removed => "removed",
+            Kind::Architectures => "architectures changed",
+        }
+    }
+}
+
+/// A package which the run changed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub package: String,
+    pub kind: Kind,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// The architectures which the package is now built for, but was not.
+    pub added: Vec<String>,
+    /// The architectures which the package is no longer built for.
+    pub removed: Vec<String>,
+}
+
+impl Change {
+    fn describe(&self, package: &str, arrow: &str) -> String {
+        let versions = match (self.from.as_ref(), self.to.as_ref()) {
+            (Some(from), Some(to)) if from != to => [from.as_str(), arrow, to].join(" "),
+            (_, Some(version)) | (Some(version), None) => version.clone(),
+            (None, None) => String::new(),
+        };
+
+        let architectures = if self.kind == Kind::New {
+            self.added.join(", ")
+        } else {
+            self.added.iter().map(|x| ["+", x].concat())
+                .chain(self.removed.iter().map(|x| ["-", x].concat()))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        if architectures.is_empty() {
+            [package, " ", &versions].concat()
+        } else {
+            [package, " ", &versions, " (", &architectures, ")"].concat()
+        }
+    }
+}
+
+/// The section of a suite in the digest of a run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub suite: String,
+    pub changes: Vec<Change>,
+    /// How many packages the suite publishes after the run.
+    pub packages: usize,
+    /// How many packages are staged for the release of the suite, if it is frozen.
+    pub staged: Option<usize>,
+}
+
+impl Section {
+    /// Compares what the suite published before the run with what it publishes after it.
+    pub fn compare(suite: &str, before: &Snapshot, after: &Snapshot) -> Section {
+        let names: BTreeSet<&String> =
+            before.packages.keys().chain(after.packages.keys()).collect();
+
+        let mut changes = Vec::new();
+        for name in names {
+            let old = before.packages.get(name);
+            let new = after.packages.get(name);
+            let arches = |versions: Option<&BTreeMap<String, String>>| -> BTreeSet<String> {
+                versions.map_or_else(BTreeSet::new, |x| x.keys().cloned().collect())
+            };
+
+            let (old_arches, new_arches) = (arches(old), arches(new));
+            let from = old.and_then(newest);
+            let to = new.and_then(newest);
+            let kind = match (from.as_ref(), to.as_ref()) {
+                (None, Some(_)) => Kind::New,
+                (Some(_), None) => Kind::Removed,
+                (Some(from), Some(to)) if from != to => {
+                    if is_newer(to, from) { Kind::Upgraded } else { Kind::Downgraded }
+                }
+                _ if old_arches != new_arches => Kind::Architectures,
+                _ => continue,
+            };
+
+            changes.push(Change {
+                package: name.clone(),
+                kind,
+                from,
+                to,
+                added: new_arches.difference(&old_arches).cloned().collect(),
+                removed: if kind == Kind::Removed {
+                    Vec::new()
+                } else {
+                    old_arches.difference(&new_arches).cloned().collect()
+                },
+            });
+        }
+
+        Section { suite: suite.to_owned(), changes, packages: after.packages.len(), staged: None }
+    }
+
+    /// The section of a suite which was frozen, and thus not published.
+    pub fn frozen(suite: &str, staged: usize) -> Section {
+        Section { suite: suite.to_owned(), changes: Vec::new(), packages: 0, staged: Some(staged) }
+    }
+
+    /// Whether the run changed nothing which the suite publishes, or stages for its release.
+    pub fn is_unchanged(&self) -> bool {
+        self.changes.is_empty() && self.staged.map_or(true, |staged| staged == 0)
+    }
+
+    fn of(&self, kind: Kind) -> Vec<&Change> {
+        self.changes.iter().filter(|change| change.kind == kind).collect()
+    }
+
+    /// The number of changes of each kind, and of the packages of the suite.
+    fn totals(&self) -> String {
+        if let Some(staged) = self.staged {
+            return format!(
+                "not published, since it is frozen; {} packages are staged for its release",
+                staged
+            );
+        }
+
+        let counts: Vec<String> = Kind::ALL.iter()
+            .map(|&kind| (kind, self.of(kind).len()))
+            .filter(|&(_, count)| count != 0)
+            .map(|(kind, count)| format!("{} {}", count, kind.as_str()))
+            .collect();
+
+        let counts = if counts.is_empty() { "no changes".to_owned() } else { counts.join(", ") };
+        format!("{}; {} packages are published", counts, self.packages)
+    }
+
+    pub fn render(&self, format: DigestFormat) -> String {
+        let mut out = String::new();
+        match format {
+            DigestFormat::Markdown => {
+                out.push_str(&format!("## {}\n\n{}\n", self.suite, self.totals()));
+                for &kind in &Kind::ALL {
+                    let changes = self.of(kind);
+                    if changes.is_empty() {
+                        continue;
+                    }
+
+                    let title = kind.as_str();
+                    let title = [&title[..1].to_uppercase(), &title[1..]].concat();
+                    out.push_str(&format!("\n### {}\n\n", title));
+                    for change in changes {
+                        let package = ["`", &change.package, "`"].concat();
+                        out.push_str(&format!("- {}\n", change.describe(&package, "→")));
+                    }
+                }
+            }
+            DigestFormat::Text => {
+                out.push_str(&format!("{}: {}\n", self.suite, self.totals()));
+                for &kind in &Kind::ALL {
+                    for change in self.of(kind) {
+                        let line = change.describe(&change.package, "->");
+                        out.push_str(&format!("  {:<22}{}\n", kind.as_str(), line));
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Adds the section to the digest of the run, after the sections of the suites before it,
+/// returning the path of the digest.
+pub fn write(run: &Run, section: &Section, format: DigestFormat) -> io::Result<PathBuf> {
+    let (name, title) = match format {
+        DigestFormat::Markdown => ("digest.md", format!("# Digest of run {}\n", run.id)),
+        DigestFormat::Text => ("digest.txt", format!("digest of run {}\n", run.id)),
+    };
+
+    let path = run.dir.join(name);
+    let mut contents = if path.exists() { String::new() } else { title };
+    contents.push('\n');
+    contents.push_str(&section.render(format));
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map(|()| path)
+}
+
+
+/// The newest version of a package among its architectures.
+fn newest(versions: &BTreeMap<String, String>) -> Option<String> {
+    versions.values().fold(None, |newest: Option<&String>, version| match newest {
+        Some(newest) if !is_newer(version, newest) => Some(newest),
+        _ => Some(version),
+    }).cloned()
+}
+
+/// Whether the version is newer than the other, by `dpkg`, or by their text if it fails.
+fn is_newer(version: &str, other: &str) -> bool {
+    version != other && version::compare(version, "gt", other).unwrap_or(version > other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn snapshot(packages: &[(&str, &str, &str)]) -> Snapshot {
+        let index: Vec<String> = packages.iter()
+            .map(|&(name, version, arch)| {
+                format!("Package: {}\nVersion: {}\nArchitecture: {}\n", name, version, arch)
+            })
+            .collect();
+
+        let mut snapshot = Snapshot::default();
+        snapshot.add(&index.join("\n"));
+        snapshot
+    }
+
+    #[test]
+    fn changes_of_the_published_packages_are_listed() {
+        let before = snapshot(&[
+            ("linux", "4.18.0-10", "amd64"),
+            ("foo", "1.0", "amd64"),
+            ("foo", "1.0", "i386"),
+            ("foo-doc", "1.0", "all"),
+            ("same", "2.0", "all"),
+        ]);
+        let after = snapshot(&[
+            ("linux", "4.18.0-10", "amd64"),
+            ("linux", "4.18.0-11", "amd64"),
+            ("foo", "1.0", "amd64"),
+            ("foo", "1.0", "arm64"),
+            ("pop-shop", "1.0", "amd64"),
+            ("pop-shop", "1.0", "i386"),
+            ("same", "2.0", "all"),
+        ]);
+
+        let section = Section::compare("bionic", &before, &after);
+        let kinds: Vec<(&str, Kind)> = section.changes.iter()
+            .map(|change| (change.package.as_str(), change.kind))
+            .collect();
+        assert_eq!(kinds, vec![
+            ("foo", Kind::Architectures),
+            ("foo-doc", Kind::Removed),
+            ("linux", Kind::Upgraded),
+            ("pop-shop", Kind::New),
+        ]);
+
+        assert_eq!(
+            section.render(DigestFormat::Text),
+            "bionic: 1 new, 1 upgraded, 1 removed, 1 architectures changed; 4 packages are \
+             published\n  \
+             new                   pop-shop 1.0 (amd64, i386)\n  \
+             upgraded              linux 4.18.0-10 -> 4.18.0-11\n  \
+             removed               foo-doc 1.0\n  \
+             architectures changed foo 1.0 (+arm64, -i386)\n"
+        );
+
+        let markdown = section.render(DigestFormat::Markdown);
+        assert!(markdown.starts_with("## bionic\n\n1 new, 1 upgraded"));
+        assert!(markdown.contains("\n### Upgraded\n\n- `linux` 4.18.0-10 → 4.18.0-11\n"));
+        assert!(Section::compare("bionic", &after, &after).is_unchanged());
+    }
+
+    #[test]
+    fn sections_of_each_suite_are_appended() {
+        let dir = TempDir::new("debrep-digest").unwrap();
+        let run = Run::new("1-abcdef".into(), dir.path().to_path_buf());
+        let after = snapshot(&[("pop-shop", "1.0", "amd64")]);
+        let section = Section::compare("cosmic", &Snapshot::default(), &after);
+        write(&run, &section, DigestFormat::Text).unwrap();
+        let path = write(&run, &Section::frozen("bionic", 2), DigestFormat::Text).unwrap();
+
+        assert_eq!(
+            misc::read_to_string(path).unwrap(),
+            "digest of run 1-abcdef\n\n\
+             cosmic: 1 new; 1 packages are published\n  \
+             new                   pop-shop 1.0 (amd64)\n\n\
+             bionic: not published, since it is frozen; 2 packages are staged for its release\n"
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! `debrep doctor`, which checks what runs need of this system without building anything: the
+//! external commands of the configured features, the schroots which builds enter, write access
+//! to the directories of the repo, the keys which sign, the free space of the filesystems which
+//! builds write to, and whether the servers which fetching downloads from answer.
+//!
+//! Each check passes, warns, or fails. Only what a run cannot do without fails; what may only
+//! slow a run down, or fail it some of the time, such as an unreachable server, warns.
+
+use std::env;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use command::{self, Cmd};
+use config::Config;
+use output::format_size;
+use reqwest::Client;
+use tempdir::TempDir;
+use super::commands::{self, Phase};
+use super::{download, keys, prepare, space, BuildOptions};
+
+/// The directories which runs write to, relative to the root of the repo.
+const WRITTEN: &[&str] = &["repo/pool", "logs", "record", "state", "build"];
+
+/// How long entering a schroot may take, in seconds.
+const ENTER_TIMEOUT: u64 = 120;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    /// Runs may work, but not as well as they should.
+    Warn,
+    /// Runs cannot work until it is fixed.
+    Fail,
+}
+
+/// A check of the system, and what to do about it if it did not pass.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub outcome: Outcome,
+    pub what: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(what: String) -> Check {
+        Check { outcome: Outcome::Pass, what, hint: None }
+    }
+
+    fn warn(what: String, hint: String) -> Check {
+        Check { outcome: Outcome::Warn, what, hint: Some(hint) }
+    }
+
+    fn fail(what: String, hint: String) -> Check {
+        Check { outcome: Outcome::Fail, what, hint: Some(hint) }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.outcome == Outcome::Fail
+    }
+}
+
+/// Checks what runs of every suite of the config need, in the repo at the working directory.
+pub fn run(config: &Config, options: BuildOptions) -> Vec<Check> {
+    let suites: Vec<Config> = match config.suite_names() {
+        Some(names) => names.iter().map(|name| config.for_suite(name)).collect(),
+        None => vec![config.clone()],
+    };
+
+    let mut checks = programs(&suites, options);
+    checks.extend(chroots(&suites));
+    checks.extend(WRITTEN.iter().map(|dir| writable(Path::new(dir))));
+    checks.extend(signers(&suites, options));
+    checks.extend(free_space(config.space_headroom()));
+    if options.offline {
+        checks.push(Check::pass("servers are not checked, since fetching is offline".to_owned()));
+    } else {
+        let urls: Vec<String> = suites.iter().flat_map(download::urls).collect();
+        let client = Client::new();
+        checks.extend(hosts(&urls).into_iter().map(|(host, url)| server(&client, host, url)));
+    }
+
+    checks
+}
+
+/// The checklist, with the hint of each check which did not pass under it, and a count of the
+/// checks of each outcome at the end.
+pub fn render(checks: &[Check]) -> String {
+    let mut text = String::new();
+    for check in checks {
+        let label = match check.outcome {
+            Outcome::Pass => "pass",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        };
+
+        let _ = writeln!(text, "[{}] {}", label, check.what);
+        if let Some(ref hint) = check.hint {
+            let _ = writeln!(text, "       {}", hint);
+        }
+    }
+
+    let count = |outcome| checks.iter().filter(|check| check.outcome == outcome).count();
+    let _ = writeln!(
+        text,
+        "{} passed, {} warned, {} failed",
+        count(Outcome::Pass),
+        count(Outcome::Warn),
+        count(Outcome::Fail)
+    );
+
+    text
+}
+
+/// The external commands which fetching, building, and publishing need, and dpkg, which tells
+/// the architecture of this system.
+fn programs(suites: &[Config], options: BuildOptions) -> Vec<Check> {
+    let phases = [Phase::Fetch, Phase::Build, Phase::Publish, Phase::Upload];
+    let mut programs = vec!["dpkg"];
+    for config in suites {
+        programs.extend(commands::required(config, &phases, options));
+    }
+
+    programs.sort();
+    programs.dedup();
+    programs.into_iter()
+        .map(|program| if command::on_path(program) {
+            Check::pass(format!("{} is installed", program))
+        } else {
+            let package = command::package_of(program);
+            Check::fail(
+                format!("{} is not installed", program),
+                format!("install the {} package, or add it to the PATH", package)
+            )
+        })
+        .collect()
+}
+
+/// The schroots which the builds of each suite enter, which must exist, and which this user must
+/// be able to enter.
+fn chroots(suites: &[Config]) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut chroots = Vec::new();
+    for config in suites {
+        match commands::chroots(config, &[Phase::Build]) {
+            Ok(needed) => chroots.extend(needed),
+            Err(why) => checks.push(Check::fail(
+                format!("the schroots of {} are unknown: {}", config.archive, why),
+                "check that `dpkg --print-architecture` works".to_owned()
+            )),
+        }
+    }
+
+    chroots.sort();
+    chroots.dedup();
+    if chroots.is_empty() || !command::on_path("schroot") {
+        return checks;
+    }
+
+    let missing = commands::missing_chroots(&chroots);
+    let existing = if missing.is_empty() { Vec::new() } else { commands::sbuild_chroots() };
+    for chroot in chroots {
+        if missing.contains(&chroot) {
+            let hint = match prepare::closest(&chroot, &existing) {
+                Some(closest) => format!("did you mean {}? ", closest),
+                None => String::new(),
+            };
+
+            checks.push(Check::fail(
+                format!("the {} schroot does not exist", chroot),
+                [&hint, "create it with sbuild-createchroot"].concat()
+            ));
+            continue;
+        }
+
+        let entered = Cmd::new("schroot")
+            .args(&["-c", &chroot, "--", "true"])
+            .timeout(ENTER_TIMEOUT)
+            .output();
+
+        checks.push(match entered {
+            Ok(_) => Check::pass(format!("the {} schroot can be entered", chroot)),
+            Err(why) => Check::fail(
+                format!("the {} schroot cannot be entered: {}", chroot, why),
+                "add this user to the sbuild group with `sudo sbuild-adduser $USER`, and log in \
+                 again"
+                    .to_owned()
+            ),
+        });
+    }
+
+    checks
+}
+
+/// Whether files can be created in the directory, or in the closest of its parents which exists,
+/// where it would be created.
+fn writable(dir: &Path) -> Check {
+    let mut existing = dir;
+    while !existing.is_dir() {
+        match existing.parent() {
+            Some(parent) if parent != Path::new("") => existing = parent,
+            _ => existing = Path::new("."),
+        }
+    }
+
+    match TempDir::new_in(existing, ".debrep-doctor") {
+        Ok(_) if existing == dir => Check::pass(format!("{} is writable", dir.display())),
+        Ok(_) => Check::pass(format!("{} can be created", dir.display())),
+        Err(why) => Check::fail(
+            format!("{} is not writable: {}", existing.display(), why),
+            format!(
+                "give this user write access to it, such as with `sudo chown -R $USER {}`",
+                existing.display()
+            )
+        ),
+    }
+}
+
+/// The keys which sign the dists and the checksums of each suite, and which sign the packages
+/// which are built, unless they are not signed.
+fn signers(suites: &[Config], options: BuildOptions) -> Vec<Check> {
+    let mut signers: Vec<(&str, Option<&Path>)> = Vec::new();
+    for config in suites {
+        if !options.unsigned {
+            signers.extend(config.release_signers().into_iter().map(|signer| (signer, None)));
+        }
+
+        if config.sign_checksums {
+            signers.push((&config.email, None));
+        }
+
+        if let Some(ref signing) = config.signing {
+            if !options.no_sign {
+                signers.push((&signing.key, signing.gnupg_home.as_ref().map(PathBuf::as_path)));
+            }
+        }
+    }
+
+    signers.sort();
+    signers.dedup();
+    if !command::on_path("gpg") {
+        return Vec::new();
+    }
+
+    signers.into_iter()
+        .map(|(signer, home)| match keys::check_secret_key(signer, home) {
+            Ok(()) => Check::pass(format!("the key of {} can sign", signer)),
+            Err(why) => Check::fail(
+                format!("the key of {} {}", signer, why),
+                match home {
+                    Some(home) => format!("import its secret key into {}", home.display()),
+                    None => "import its secret key with `gpg --import`, or extend it with \
+                             `gpg --quick-set-expire`"
+                        .to_owned(),
+                }
+            ),
+        })
+        .collect()
+}
+
+/// Whether the filesystems which builds write to have the headroom which their space checks keep
+/// free. Builds need more than that, so too little only warns.
+fn free_space(headroom: u64) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for dir in &[PathBuf::from("build"), env::temp_dir()] {
+        let path = if dir.exists() { dir.as_path() } else { Path::new(".") };
+        checks.push(match space::available(path) {
+            Ok(free) if free < headroom => Check::warn(
+                format!(
+                    "only {} is free on the filesystem of {}, less than the {} to keep free",
+                    format_size(free),
+                    dir.display(),
+                    format_size(headroom)
+                ),
+                "free some space, such as with `debrep gc`".to_owned()
+            ),
+            Ok(free) => Check::pass(format!("{} is free for {}", format_size(free), dir.display())),
+            Err(why) => Check::warn(
+                format!("the free space of {} is unknown: {}", dir.display(), why),
+                "check that the filesystem is mounted".to_owned()
+            ),
+        });
+    }
+
+    checks
+}
+
+/// The hosts of the HTTP URLs, each with its first URL, in the order of the URLs. URLs of other
+/// schemes, such as those of git over SSH, are left out.
+fn hosts(urls: &[String]) -> Vec<(&str, &str)> {
+    let mut hosts: Vec<(&str, &str)> = Vec::new();
+    for url in urls {
+        let rest = if url.starts_with("https://") {
+            &url[8..]
+        } else if url.starts_with("http://") {
+            &url[7..]
+        } else {
+            continue;
+        };
+
+        let authority = rest.split('/').next().unwrap_or(rest);
+        let host = authority.rsplit('@').next().unwrap_or(authority);
+        if !host.is_empty() && !hosts.iter().any(|&(known, _)| known == host) {
+            hosts.push((host, url));
+        }
+    }
+
+    hosts
+}
+
+/// Whether the server of the host answers a `HEAD` request of the URL.
+fn server(client: &Client, host: &str, url: &str) -> Check {
+    match download::reachable(client, url) {
+        Ok(()) => Check::pass(format!("{} is reachable", host)),
+        Err(why) => Check::warn(
+            format!("{} is unreachable: {}", host, why),
+            "check the network, and its proxy; --offline works without it".to_owned()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn checks_are_listed_with_their_hints() {
+        let dir = TempDir::new("debrep-doctor").unwrap();
+        fs::create_dir(dir.path().join("logs")).unwrap();
+        let logs = dir.path().join("logs");
+        let state = dir.path().join("state");
+        assert_eq!(writable(&logs), Check::pass(format!("{} is writable", logs.display())));
+        assert_eq!(writable(&state), Check::pass(format!("{} can be created", state.display())));
+        assert_eq!(fs::read_dir(&logs).unwrap().count(), 0);
+        assert!(!state.exists());
+
+        let urls = vec![
+            "https://github.com/pop-os/shop.git".to_owned(),
+            "git@github.com:pop-os/icons.git".to_owned(),
+            "http://user@example.com".to_owned(),
+            "https://github.com/pop-os/icons.git".to_owned(),
+        ];
+        assert_eq!(
+            hosts(&urls),
+            vec![
+                ("github.com", "https://github.com/pop-os/shop.git"),
+                ("example.com", "http://user@example.com"),
+            ]
+        );
+
+        let checks = vec![
+            Check::pass("sbuild is installed".to_owned()),
+            Check::fail("lintian is not installed".to_owned(), "install lintian".to_owned()),
+            Check::warn("github.com is unreachable".to_owned(), "check the network".to_owned()),
+        ];
+        assert_eq!(
+            render(&checks),
+            "[pass] sbuild is installed\n\
+             [FAIL] lintian is not installed\n       \
+             install lintian\n\
+             [warn] github.com is unreachable\n       \
+             check the network\n\
+             1 passed, 1 warned, 1 failed\n"
+        );
+        assert!(checks.iter().any(Check::failed));
+    }
+}
@@ -1,82 +1,238 @@
 use rayon::prelude::*;
 use reqwest::Client;
+use std::fs::File;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use config::Direct;
+use config::{Direct, DirectPath};
 use misc;
-use super::request;
+use repo::checksum::sha2_256_digest;
+use repo::run;
+use super::DownloadError;
+use super::engine::Engine;
+use super::request::Request;
+use super::snapshot::{self, SnapshotError};
 use super::url::UrlTokenizer;
 
 /// Possible messages that may be returned when a download has succeeded.
 pub enum DownloadResult {
-    Downloaded(u64)
+    /// The bytes which were received, which leaves out what resumed downloads had before.
+    Downloaded(u64),
+    /// Every file was already in the pool, and either matched its checksum, or downloads are
+    /// offline.
+    AlreadyExists,
 }
 
-/// Given an item with a URL, download the item if the item does not already exist.
-pub fn download(client: &Client, item: &Direct, branch: &str) -> io::Result<DownloadResult> {
-    info!("checking if {} needs to be downloaded", item.name);
+/// What is left to download of an entry, once what is in the pool was checked.
+enum Plan {
+    /// Nothing, as the entry was handled on its own, such as a snapshot.
+    Done(DownloadResult),
+    /// The files which are requested, whether each of them existed, and how many of the files
+    /// of the entry matched their checksums, so that they were not.
+    Files(Vec<(Request, bool)>, usize),
+}
 
-    fn gen_filename(name: &str, version: &str, arch: &str, ext: &str) -> String {
-        [name, if ext == "ddeb" { "-dbgsym_" } else { "_" }, version, "_", arch, ".", ext].concat()
+/// Given an item with a URL, download the item if the item does not already exist. When
+/// `offline`, files must already be in the pool.
+pub fn download(
+    engine: &Engine,
+    item: &Direct,
+    branch: &str,
+    offline: bool,
+) -> Result<DownloadResult, DownloadError> {
+    let (requests, cached) = match plan(engine, item, branch, offline)? {
+        Plan::Done(result) => return Ok(result),
+        Plan::Files(requests, cached) => (requests, cached),
+    };
+
+    let batch: Vec<Request> = requests.iter().map(|&(ref request, _)| request.clone()).collect();
+    finish(item, requests, cached, engine.files(&batch))
+}
+
+/// Checks which files of the entry are in the pool, and resolves snapshots.
+fn plan(
+    engine: &Engine,
+    item: &Direct,
+    branch: &str,
+    offline: bool,
+) -> Result<Plan, DownloadError> {
+    info!("checking if {} needs to be downloaded", item.name);
+    if let Some(ref snapshot) = item.snapshot {
+        let client = Client::new();
+        return match snapshot::download(&client, engine, item, snapshot, branch, offline) {
+            Ok(_) if offline => Ok(Plan::Done(DownloadResult::AlreadyExists)),
+            Ok(bytes) => Ok(Plan::Done(DownloadResult::Downloaded(bytes))),
+            Err(SnapshotError::Offline { url, .. }) => {
+                Err(DownloadError::Offline { name: item.name.clone(), what: url })
+            }
+            Err(why) => Err(DownloadError::Snapshot { name: item.name.clone(), why }),
+        };
     }
 
-    let mut downloaded = 0;
+    let mut requests = Vec::new();
+    let mut cached = 0;
     for file_item in &item.urls {
-        let name: &str = file_item.name.as_ref().map_or(&item.name, |x| &x);
-        let url = UrlTokenizer::finalize(&file_item.url, name, &item.version)
-            .map_err(|text|
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("unsupported variable: {}", text)
-                )
-            )?;
-
-        let destination = {
-            let file = &url[url.rfind('/').unwrap_or(0) + 1..];
-
-            let ext_pos = {
-                let mut ext_pos = file.rfind('.').unwrap_or_else(|| file.len()) + 1;
-                match &file[ext_pos..] {
-                    "gz" | "xz" => if "tar" == &file[ext_pos - 4..ext_pos - 1] {
-                        ext_pos -= 4;
-                    }
-                    _ => ()
-                }
-                ext_pos
-            };
+        let (url, destination) = locate(item, file_item, branch)
+            .map_err(|why| DownloadError::Url { name: item.name.clone(), why })?;
+        let open_error = |why| DownloadError::Open { file: destination.clone(), why };
+        let checksum = file_item.checksum.as_ref().map(|x| x.as_str());
+        if offline {
+            if !is_cached(&destination, checksum).map_err(&open_error)? {
+                return Err(DownloadError::Offline { name: item.name.clone(), what: url });
+            }
+
+            continue;
+        }
 
-            let extension = &file[ext_pos..];
-            let arch = match file_item.arch.as_ref() {
-                Some(ref arch) => arch.as_str(),
-                None => misc::get_arch_from_stem(&file[..ext_pos - 1]),
-            };
+        // A file which matches its checksum is not requested again.
+        if checksum.is_some() && is_cached(&destination, checksum).map_err(&open_error)? {
+            cached += 1;
+            continue;
+        }
 
-            let filename = &gen_filename(name, &item.version, arch, extension);
+        let existed = destination.exists();
+        requests.push((Request::new(&url, checksum, &destination), existed));
+    }
 
-            let dst = match extension {
-                "tar.gz" | "tar.xz" | "dsc" => "/main/source/".into(),
-                _ => ["/main/binary-", arch, "/"].concat()
-            };
+    if offline {
+        return Ok(Plan::Done(DownloadResult::AlreadyExists));
+    }
 
-            PathBuf::from(
-                [ "repo/pool/", branch, &dst, &name[0..1], "/", name, "/", &filename ].concat()
-            )
-        };
+    Ok(Plan::Files(requests, cached))
+}
 
-        let checksum = file_item.checksum.as_ref().map(|x| x.as_str());
-        downloaded += request::file(client, &url, checksum, &destination)?;
+/// Records the files of the entry which were downloaded, with what became of their requests.
+fn finish(
+    item: &Direct,
+    requests: Vec<(Request, bool)>,
+    cached: usize,
+    results: Vec<Result<u64, DownloadError>>,
+) -> Result<DownloadResult, DownloadError> {
+    let mut downloaded = 0;
+    for ((request, existed), result) in requests.into_iter().zip(results) {
+        let bytes = result?;
+        if bytes != 0 || !existed {
+            if let Some(run) = run::current() {
+                let destination = request.path.canonicalize()
+                    .map_err(|why| DownloadError::Open { file: request.path.clone(), why })?;
+                run.add_published(destination);
+            }
+        }
+
+        downloaded += bytes;
+    }
+
+    if cached != 0 && cached == item.urls.len() {
+        return Ok(DownloadResult::AlreadyExists);
     }
 
     info!("finished downloading {}", &item.name);
     Ok(DownloadResult::Downloaded(downloaded))
 }
 
-/// Downloads pre-built Debian packages in parallel
-pub fn parallel(items: &[Direct], branch: &str) -> Vec<io::Result<DownloadResult>> {
-    let client = Client::new();
-    items
+/// Whether the file is in the pool, with its checksum if it has one.
+fn is_cached(destination: &Path, checksum: Option<&str>) -> io::Result<bool> {
+    if !destination.exists() {
+        return Ok(false);
+    }
+
+    match checksum {
+        Some(checksum) => Ok(sha2_256_digest(File::open(destination)?)? == checksum),
+        None => Ok(true),
+    }
+}
+
+/// The pool files which the entry downloads, and the URLs that they are downloaded from.
+pub fn files<'a>(item: &'a Direct, branch: &str) -> io::Result<Vec<(&'a DirectPath, PathBuf)>> {
+    item.urls.iter()
+        .map(|file_item| locate(item, file_item, branch).map(|(_, path)| (file_item, path)))
+        .collect()
+}
+
+/// The URL of a file, and where it is stored within the pool.
+pub fn locate(
+    item: &Direct,
+    file_item: &DirectPath,
+    branch: &str,
+) -> io::Result<(String, PathBuf)> {
+    fn gen_filename(name: &str, version: &str, arch: &str, ext: &str) -> String {
+        [name, if ext == "ddeb" { "-dbgsym_" } else { "_" }, version, "_", arch, ".", ext].concat()
+    }
+
+    let name: &str = file_item.name.as_ref().map_or(&item.name, |x| &x);
+    let url = UrlTokenizer::finalize(&file_item.url, name, &item.version)
+        .map_err(|text|
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported variable: {}", text)
+            )
+        )?;
+
+    let destination = {
+        let file = &url[url.rfind('/').unwrap_or(0) + 1..];
+
+        let ext_pos = {
+            let mut ext_pos = file.rfind('.').unwrap_or_else(|| file.len()) + 1;
+            match &file[ext_pos..] {
+                "gz" | "xz" => if "tar" == &file[ext_pos - 4..ext_pos - 1] {
+                    ext_pos -= 4;
+                }
+                _ => ()
+            }
+            ext_pos
+        };
+
+        let extension = &file[ext_pos..];
+        let arch = match file_item.arch.as_ref() {
+            Some(ref arch) => arch.as_str(),
+            None => misc::get_arch_from_stem(&file[..ext_pos - 1]),
+        };
+
+        let filename = &gen_filename(name, &item.version, arch, extension);
+
+        let dst = match extension {
+            "tar.gz" | "tar.xz" | "dsc" => ["/", item.component(), "/source/"].concat(),
+            _ => ["/", item.component(), "/binary-", arch, "/"].concat()
+        };
+
+        PathBuf::from(
+            [ "repo/pool/", branch, &dst, &name[0..1], "/", name, "/", &filename ].concat()
+        )
+    };
+
+    Ok((url, destination))
+}
+
+/// Downloads pre-built Debian packages, with the files of every entry in one batch. Snapshots
+/// are resolved in parallel beforehand.
+pub fn parallel(
+    engine: &Engine,
+    items: &[Direct],
+    branch: &str,
+    offline: bool,
+) -> Vec<Result<DownloadResult, DownloadError>> {
+    let plans: Vec<Result<Plan, DownloadError>> = items
         .par_iter()
-        .map(|item| download(&client, item, branch))
+        .map(|item| plan(engine, item, branch, offline))
+        .collect();
+
+    let batch: Vec<Request> = plans.iter()
+        .flat_map(|plan| match *plan {
+            Ok(Plan::Files(ref requests, _)) => &requests[..],
+            _ => &[][..],
+        })
+        .map(|&(ref request, _)| request.clone())
+        .collect();
+
+    let mut results = engine.files(&batch).into_iter();
+    plans.into_iter()
+        .zip(items)
+        .map(|(plan, item)| match plan? {
+            Plan::Done(result) => Ok(result),
+            Plan::Files(requests, cached) => {
+                let results = results.by_ref().take(requests.len()).collect();
+                finish(item, requests, cached, results)
+            }
+        })
         .collect()
 }
@@ -0,0 +1,331 @@
+//! The engines which files are requested with. Every download goes through the engine of the
+//! repo, and resumes, verifies, and retries the same way with either.
+
+use config::DownloadEngine;
+use futures::{Async as Poll, Future, Stream};
+use futures::future::{self, Either, Loop};
+use futures::sync::{mpsc, oneshot};
+use interrupt;
+use rayon::prelude::*;
+use reqwest::{self, Client};
+use reqwest::header::{ByteRangeSpec, ContentLength, ContentRange, Range};
+use reqwest::unstable::async as http;
+use retry;
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::DownloadError;
+use super::request::{self, Download, Head, Next, Request};
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+/// Seconds without a response, or without a chunk of its body, after which a request of the
+/// async engine times out, as those of the blocking client do.
+const TIMEOUT: u64 = 30;
+
+/// Starts the engine of the kind, which the blocking engine replaces if the async engine cannot
+/// be started.
+pub fn start(kind: DownloadEngine, connections: usize) -> Arc<Engine> {
+    match kind {
+        DownloadEngine::Blocking => Arc::new(Blocking::new()),
+        DownloadEngine::Async => match Async::new(connections) {
+            Ok(engine) => Arc::new(engine),
+            Err(why) => {
+                warn!("unable to start the async download engine, so downloads block: {}", why);
+                Arc::new(Blocking::new())
+            }
+        },
+    }
+}
+
+/// Downloads files over HTTP.
+pub trait Engine: Send + Sync {
+    /// Downloads each file of the batch, as `request::file` downloads one, and returns what
+    /// became of each, in the order of the batch.
+    fn files(&self, batch: &[Request]) -> Vec<Result<u64, DownloadError>>;
+}
+
+fn range(offset: u64) -> Range {
+    Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)])
+}
+
+/// Downloads each file with the blocking client, on the threads of the current pool.
+pub struct Blocking {
+    client: Client,
+}
+
+impl Blocking {
+    pub fn new() -> Blocking {
+        Blocking { client: Client::new() }
+    }
+
+    /// Requests the file at the URL, from `offset` on if it is not 0, and returns the head of
+    /// the response once it was received, with the response to read its body from.
+    pub fn get(&self, url: &str, offset: u64) -> Result<(Head, reqwest::Response), DownloadError> {
+        let mut request = self.client.get(url);
+        if offset != 0 {
+            request.header(range(offset));
+        }
+
+        let response = request
+            .send()
+            .map_err(|why| DownloadError::Request { name: url.to_owned(), why })?;
+
+        let head = Head {
+            status: response.status(),
+            url: response.url().to_string(),
+            length: response.headers().get::<ContentLength>().map(|length| length.0),
+            range: response.headers().get::<ContentRange>().map(|range| range.0.clone()),
+        };
+
+        Ok((head, response))
+    }
+}
+
+impl Engine for Blocking {
+    fn files(&self, batch: &[Request]) -> Vec<Result<u64, DownloadError>> {
+        batch.par_iter().map(|request| request::download(self, request)).collect()
+    }
+}
+
+/// Downloads files with the async client, on a thread of its own, which runs the downloads of
+/// every batch as futures, with at most `connections` of them at once. Each batch waits on the
+/// thread which requested it until all of its downloads finished.
+pub struct Async {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+/// A download for the thread of the async engine, and where what became of it is sent.
+struct Job {
+    request: Request,
+    done: oneshot::Sender<Result<u64, DownloadError>>,
+}
+
+impl Async {
+    /// Starts the thread of the engine, and its client.
+    pub fn new(connections: usize) -> io::Result<Async> {
+        let (jobs, queue) = mpsc::unbounded::<Job>();
+        let (started, start) = oneshot::channel::<io::Result<()>>();
+        thread::Builder::new().name("debrep-downloads".into()).spawn(move || {
+            let mut core = match Core::new() {
+                Ok(core) => core,
+                Err(why) => {
+                    let _ = started.send(Err(why));
+                    return;
+                }
+            };
+
+            let handle = core.handle();
+            let client = match http::Client::builder().build(&handle) {
+                Ok(client) => client,
+                Err(why) => {
+                    let why = io::Error::new(io::ErrorKind::Other, why.to_string());
+                    let _ = started.send(Err(why));
+                    return;
+                }
+            };
+
+            let _ = started.send(Ok(()));
+
+            // Runs until every sender of jobs was dropped, with the engine.
+            let downloads = queue
+                .map(|job| {
+                    let Job { request, done } = job;
+                    download(&client, &handle, request).map(move |result| {
+                        let _ = done.send(result);
+                    })
+                })
+                .buffer_unordered(connections.max(1));
+            let _ = core.run(downloads.for_each(|()| Ok(())));
+        })?;
+
+        start.wait().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::Other, "the thread of the engine exited"))
+        })?;
+
+        Ok(Async { jobs })
+    }
+}
+
+impl Engine for Async {
+    fn files(&self, batch: &[Request]) -> Vec<Result<u64, DownloadError>> {
+        // Files which are already downloaded are hashed on the threads of the current pool,
+        // rather than on the thread of the engine, where they would hold up every download.
+        let checked: Vec<Result<bool, DownloadError>> =
+            batch.par_iter().map(request::downloaded).collect();
+
+        let pending = checked.into_iter().zip(batch).map(|(checked, request)| match checked {
+            Ok(true) => Either::A(future::ok(Ok(0))),
+            Ok(false) => {
+                let (done, receiver) = oneshot::channel();
+                let job = Job { request: request.clone(), done };
+                let _ = self.jobs.unbounded_send(job);
+                let url = request.url.clone();
+                Either::B(receiver.or_else(move |_| Ok::<_, ()>(Err(stopped(url)))))
+            }
+            Err(why) => Either::A(future::ok(Err(why))),
+        });
+
+        let pending: Vec<_> = pending.collect();
+        future::join_all(pending).wait().unwrap_or_else(|()| Vec::new())
+    }
+}
+
+fn stopped(url: String) -> DownloadError {
+    let why = io::Error::new(io::ErrorKind::Other, "the async download engine stopped");
+    DownloadError::Transfer { url, why }
+}
+
+/// Downloads the file, retrying when the request fails for a reason which may not last, as
+/// `retry::network` does.
+fn download(
+    client: &http::Client,
+    handle: &Handle,
+    request: Request,
+) -> Box<Future<Item = Result<u64, DownloadError>, Error = ()>> {
+    let (client, handle) = (client.clone(), handle.clone());
+    let backoff = Duration::from_secs(retry::NETWORK_BACKOFF);
+    Box::new(future::loop_fn((1, backoff), move |(attempt, wait)| {
+        let (request, handle) = (request.clone(), handle.clone());
+        let restart = (client.clone(), handle.clone(), request.clone());
+        fetch(&client, &handle, request.clone(), true)
+            .and_then(move |fetched| match fetched {
+                Some(received) => Either::A(future::ok(received)),
+                None => {
+                    let (client, handle, request) = restart;
+                    Either::B(fetch(&client, &handle, request, false).map(|x| x.unwrap_or(0)))
+                }
+            })
+            .then(move |result| {
+                let attempts = retry::NETWORK_ATTEMPTS;
+                let url = &request.url;
+                match result {
+                    Err(ref why) if retry::should_retry(url, attempt, attempts, wait, why) => {
+                        let next = Loop::Continue((attempt + 1, wait * 2));
+                        match Timeout::new(wait, &handle) {
+                            Ok(timer) => Either::A(timer.then(move |_| Ok(next))),
+                            Err(_) => Either::B(future::ok(next)),
+                        }
+                    }
+                    result => Either::B(future::ok(Loop::Break(result))),
+                }
+            })
+    }))
+}
+
+/// Downloads the file into its partial file, as the blocking engine does, while the body is
+/// written to it as each of its chunks is received.
+fn fetch(
+    client: &http::Client,
+    handle: &Handle,
+    request: Request,
+    resumable: bool,
+) -> Box<Future<Item = Option<u64>, Error = DownloadError>> {
+    let download = match request::open(&request, resumable) {
+        Ok(download) => download,
+        Err(why) => return Box::new(future::err(why)),
+    };
+
+    let timer = match Timeout::new(Duration::from_secs(TIMEOUT), handle) {
+        Ok(timer) => timer,
+        Err(why) => {
+            return Box::new(future::err(DownloadError::Transfer { url: request.url, why }))
+        }
+    };
+
+    let mut sent = client.get(request.url.as_str());
+    if download.offset != 0 {
+        sent.header(range(download.offset));
+    }
+
+    let url = request.url.clone();
+    let response = sent.send().select2(timer).then(move |result| match result {
+        Ok(Either::A((response, timer))) => Ok((response, timer)),
+        Ok(Either::B(_)) => {
+            let why = io::Error::new(io::ErrorKind::TimedOut, "timed out awaiting a response");
+            Err(DownloadError::Transfer { url, why })
+        }
+        Err(Either::A((why, _))) => Err(DownloadError::Request { name: url, why }),
+        Err(Either::B((why, _))) => Err(DownloadError::Transfer { url, why }),
+    });
+
+    Box::new(response.and_then(move |(response, timer)| {
+        let head = Head {
+            status: response.status(),
+            url: response.url().to_string(),
+            length: response.headers().get::<ContentLength>().map(|length| length.0),
+            range: response.headers().get::<ContentRange>().map(|range| range.0.clone()),
+        };
+
+        let download = match request::accept(&request, download, head) {
+            Ok(Next::Write(download)) => download,
+            Ok(Next::Done(done)) => return Either::A(future::ok(done)),
+            Err(why) => return Either::A(future::err(why)),
+        };
+
+        let written = Written { download, hasher: Sha256::default(), received: 0, logged: 0 };
+        let (url, failed) = (request.url.clone(), request.url.clone());
+        let body = Deadline { body: response.into_body(), timer };
+        Either::B(body
+            .fold(written, move |mut written, chunk| {
+                written.write(&url, &chunk).map(|()| written)
+            })
+            .map_err(move |why| DownloadError::Transfer { url: failed, why })
+            .and_then(move |written| {
+                let digest = format!("{:x}", written.hasher.result());
+                request::finish(&request, written.download, written.received, digest)
+            }))
+    }))
+}
+
+/// What was written of the body of a download so far.
+struct Written {
+    download: Download,
+    hasher: Sha256,
+    received: u64,
+    logged: u64,
+}
+
+impl Written {
+    /// Writes a chunk of the body to the partial file, hashing it as it is written.
+    fn write(&mut self, url: &str, chunk: &[u8]) -> io::Result<()> {
+        if interrupt::is_interrupted() {
+            return Err(interrupt::error());
+        }
+
+        self.hasher.input(chunk);
+        self.download.file.write_all(chunk)?;
+        self.received += chunk.len() as u64;
+        request::progress(url, self.received, &mut self.logged);
+        Ok(())
+    }
+}
+
+/// The body of a response, which times out when none of it is received for `TIMEOUT`.
+struct Deadline {
+    body: http::Decoder,
+    timer: Timeout,
+}
+
+impl Stream for Deadline {
+    type Item = http::Chunk;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Poll<Option<http::Chunk>>, io::Error> {
+        match self.body.poll() {
+            Ok(Poll::Ready(Some(chunk))) => {
+                self.timer.reset(Instant::now() + Duration::from_secs(TIMEOUT));
+                Ok(Poll::Ready(Some(chunk)))
+            }
+            Ok(Poll::Ready(None)) => Ok(Poll::Ready(None)),
+            Ok(Poll::NotReady) => match self.timer.poll()? {
+                Poll::Ready(()) => {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "timed out receiving the body"))
+                }
+                Poll::NotReady => Ok(Poll::NotReady),
+            },
+            Err(why) => Err(io::Error::new(io::ErrorKind::Other, why.to_string())),
+        }
+    }
+}
@@ -0,0 +1,653 @@
+use command::{Cmd, Operation};
+use config::{DebianPath, Source, SourceLocation};
+use interrupt;
+use repo::checksum::sha2_256_digest;
+use retry;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use super::DownloadError;
+use super::engine::Engine;
+use super::request::{self, Partial};
+
+/// Pinned git sources are checked out from mirrors of their repositories, which their variants
+/// share.
+const GIT_MIRRORS: &str = "assets/cache/git";
+
+lazy_static! {
+    /// The mirrors which were updated by this process. Variants are fetched in parallel, and
+    /// must not update the same mirror at the same time.
+    static ref UPDATED_MIRRORS: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// Whether what a fetcher stored at its path can be used as it is.
+#[derive(Debug, PartialEq)]
+pub enum Freshness {
+    /// It is what the config asks for, such as an archive with its checksum.
+    Fresh,
+    /// It can be used, but may be outdated, such as a git checkout which was not pulled.
+    Stale,
+    /// It does not exist, or is not what the config asks for.
+    Missing,
+}
+
+/// Fetches the input of a source, such as an archive into `assets/cache/`, or a checkout into
+/// `build/`. Each kind of location is fetched by its own implementation.
+pub trait Fetcher {
+    /// What is fetched, such as a URL, for messages.
+    fn origin(&self) -> String;
+
+    /// Where the fetched input is stored.
+    fn resolve(&self) -> PathBuf;
+
+    /// Whether what is stored at the path is up to date.
+    fn freshness(&self, path: &Path) -> Result<Freshness, DownloadError>;
+
+    /// Fetches the input into the path, downloading it with the engine if it is downloaded, and
+    /// verifies it.
+    fn fetch(&self, engine: &Engine, path: &Path) -> Result<(), DownloadError>;
+
+    /// Why fetching would change what is stored at the path, if it would, without fetching it.
+    fn outdated(&self, path: &Path) -> Result<Option<String>, DownloadError> {
+        Ok(match self.freshness(path)? {
+            Freshness::Fresh => None,
+            Freshness::Stale => Some(format!("{} may have changed", self.origin())),
+            Freshness::Missing => Some(format!("{} was not fetched", self.origin())),
+        })
+    }
+
+    /// Whether the input is an archive, which is extracted into `build/` for each build.
+    fn is_archive(&self) -> bool { true }
+
+    /// Whether the input is fetched from this system, so that it can be fetched offline.
+    fn is_local(&self) -> bool { false }
+}
+
+/// The fetcher of the location of a source. Sources without a location, and local working copies,
+/// have nothing to fetch.
+pub fn of(item: &Source) -> Option<Box<Fetcher>> {
+    match item.location {
+        Some(SourceLocation::URL { ref url, ref checksum }) => Some(Box::new(Http {
+            name: item.name.clone(),
+            url: url.clone(),
+            checksum: checksum.clone(),
+        })),
+        Some(SourceLocation::Git { ref url, ref branch, ref tag, ref commit }) => {
+            Some(Box::new(Git {
+                name: item.name.clone(),
+                url: url.clone(),
+                branch: branch.clone(),
+                tag: tag.clone(),
+                commit: commit.clone(),
+                shared: item.variant().is_some(),
+            }))
+        }
+        Some(SourceLocation::Archive { ref path, ref checksum }) => Some(Box::new(Archive {
+            name: item.name.clone(),
+            path: path.clone(),
+            checksum: checksum.clone(),
+        })),
+        Some(SourceLocation::Command { ref command, ref file, ref checksum }) => {
+            Some(Box::new(Shell {
+                name: item.name.clone(),
+                command: command.clone(),
+                file: file.clone(),
+                checksum: checksum.clone(),
+            }))
+        }
+        Some(SourceLocation::Path { .. }) | None => None,
+    }
+}
+
+/// The fetcher of the tarball of the debian directory of a source, which is stored in the cache
+/// as `<name>_debian_<filename>`, apart from the archive of its location.
+pub fn debian(item: &Source) -> Option<Box<Fetcher>> {
+    match item.debian {
+        Some(DebianPath::URL { ref url, ref checksum }) => Some(Box::new(Http {
+            name: [&item.name, "_debian"].concat(),
+            url: url.clone(),
+            checksum: checksum.clone(),
+        })),
+        _ => None,
+    }
+}
+
+/// Fetches the input unless it is fresh. When `offline`, stale inputs are used with a warning,
+/// and inputs which are missing are an error, unless they are on this system.
+pub fn fetch(
+    name: &str,
+    fetcher: &Fetcher,
+    engine: &Engine,
+    offline: bool,
+) -> Result<(), DownloadError> {
+    if interrupt::is_interrupted() {
+        return Err(DownloadError::Interrupted { name: name.to_owned() });
+    }
+
+    let path = fetcher.resolve();
+    match fetcher.freshness(&path)? {
+        Freshness::Fresh => Ok(()),
+        Freshness::Stale if offline => {
+            let path = path.display();
+            warn!("using {} for {} without updating it, which may be outdated", path, name);
+            Ok(())
+        }
+        Freshness::Missing if offline && !fetcher.is_local() => {
+            Err(DownloadError::Offline { name: name.to_owned(), what: fetcher.origin() })
+        }
+        _ => fetcher.fetch(engine, &path),
+    }
+}
+
+/// Where archives of a source are stored once they are fetched.
+fn cache_path(name: &str, filename: &str) -> PathBuf {
+    PathBuf::from(["assets/cache/", name, "_", filename].concat())
+}
+
+/// Hashes the file, and compares it to the checksum.
+fn verify(name: &str, path: &Path, checksum: &str) -> Result<(), DownloadError> {
+    let digest = File::open(path)
+        .and_then(sha2_256_digest)
+        .map_err(|why| DownloadError::Open { file: path.to_path_buf(), why })?;
+
+    compare(name, checksum, digest)
+}
+
+/// Compares the digest of what was fetched to the checksum.
+fn compare(name: &str, checksum: &str, digest: String) -> Result<(), DownloadError> {
+    if digest == checksum {
+        Ok(())
+    } else {
+        Err(DownloadError::ChecksumMismatch {
+            item: name.to_owned(),
+            expected: checksum.to_owned(),
+            found: digest
+        })
+    }
+}
+
+/// Fresh if the file has its checksum.
+fn cached_freshness(path: &Path, checksum: &str) -> Result<Freshness, DownloadError> {
+    if !path.is_file() {
+        return Ok(Freshness::Missing);
+    }
+
+    let digest = File::open(path)
+        .and_then(sha2_256_digest)
+        .map_err(|why| DownloadError::Open { file: path.to_path_buf(), why })?;
+
+    Ok(if digest == checksum { Freshness::Fresh } else { Freshness::Missing })
+}
+
+/// An archive which is downloaded over HTTP.
+pub struct Http {
+    name: String,
+    url: String,
+    checksum: String,
+}
+
+impl Fetcher for Http {
+    fn origin(&self) -> String { self.url.clone() }
+
+    fn resolve(&self) -> PathBuf {
+        let filename = &self.url[self.url.rfind('/').map_or(0, |x| x + 1)..];
+        cache_path(&self.name, filename)
+    }
+
+    fn freshness(&self, path: &Path) -> Result<Freshness, DownloadError> {
+        cached_freshness(path, &self.checksum)
+    }
+
+    fn fetch(&self, engine: &Engine, path: &Path) -> Result<(), DownloadError> {
+        warn!("checksum did not match for {}. downloading from {}", &self.name, self.url);
+        request::file(engine, &self.url, Some(&self.checksum), path).map(|_| ())
+    }
+}
+
+/// A repository which is cloned into `build/`, and pulled on each fetch. Variants, and sources
+/// which are pinned to a tag or commit, are checked out from a mirror of the repository instead.
+pub struct Git {
+    name: String,
+    url: String,
+    /// Defaults to `master`.
+    branch: Option<String>,
+    tag: Option<String>,
+    commit: Option<String>,
+    /// Whether the source is a variant, whose checkout is named after it.
+    shared: bool,
+}
+
+/// The mirror of the repository of a git source, which is only cloned if the source is pinned,
+/// or has variants.
+pub fn mirror(item: &Source) -> Option<PathBuf> {
+    match item.location {
+        Some(SourceLocation::Git { ref url, .. }) => Some(mirror_path(url)),
+        _ => None,
+    }
+}
+
+/// The commit which the checkout or working copy of a source is at, if it is a git repository.
+pub fn checked_out(item: &Source) -> Option<String> {
+    match item.location {
+        Some(SourceLocation::Git { .. }) => of(item).and_then(|git| Git::head(&git.resolve())),
+        Some(SourceLocation::Path { ref path }) if path.join(".git").exists() => Git::head(path),
+        _ => None,
+    }
+}
+
+fn mirror_path(url: &str) -> PathBuf {
+    Path::new(GIT_MIRRORS).join([&repository(url), ".git"].concat())
+}
+
+/// The name of a repository, from its URL.
+fn repository(url: &str) -> String {
+    url.split_at(url.rfind('/').map_or(0, |x| x + 1)).1.replace(".git", "")
+}
+
+impl Git {
+    fn repository(&self) -> String {
+        repository(&self.url)
+    }
+
+    fn branch(&self) -> &str {
+        self.branch.as_ref().map_or("master", |x| x.as_str())
+    }
+
+    fn is_pinned(&self) -> bool {
+        self.shared || self.tag.is_some() || self.commit.is_some()
+    }
+
+    /// What is checked out of the mirror.
+    fn revision(&self) -> String {
+        match (self.commit.as_ref(), self.tag.as_ref()) {
+            (Some(commit), _) => commit.clone(),
+            (None, Some(tag)) => ["refs/tags/", tag].concat(),
+            (None, None) => ["origin/", self.branch()].concat(),
+        }
+    }
+
+    /// Clones the mirror of the repository, or updates it once per process.
+    fn update_mirror(&self) -> Result<PathBuf, DownloadError> {
+        let mirror = mirror_path(&self.url);
+        let git_error = |why| DownloadError::GitFailed { name: self.name.clone(), why };
+        let mirror_error = |why| DownloadError::Mirror { path: mirror.clone(), why };
+        let mut updated = UPDATED_MIRRORS.lock().unwrap();
+        if updated.contains(&mirror) {
+            return Ok(mirror);
+        }
+
+        if mirror.exists() {
+            info!("updating the mirror of {}", self.url);
+            retry::network(&self.url, || {
+                Cmd::new("git").arg("-C").arg(&mirror).args(&["remote", "update"])
+                    .operation(Operation::GitFetch)
+                    .run()
+            }).map_err(&git_error)?;
+        } else {
+            info!("mirroring {}", self.url);
+            fs::create_dir_all(GIT_MIRRORS).map_err(&mirror_error)?;
+            retry::network(&self.url, || {
+                Cmd::new("git").args(&["clone", "--mirror", &self.url]).arg(&mirror)
+                    .operation(Operation::GitClone)
+                    .run()
+            }).map_err(&git_error)?;
+        }
+
+        updated.insert(mirror.clone());
+        Ok(mirror)
+    }
+
+    /// The commit which the branch or tag that the checkout follows is at in the repository,
+    /// which `git ls-remote` asks for without fetching anything. Annotated tags are peeled to the
+    /// commit which they tag.
+    fn remote_commit(&self, reference: &str) -> Result<Option<String>, DownloadError> {
+        let peeled = [reference, "^{}"].concat();
+        let output = retry::network(&self.url, || {
+            Cmd::new("git").args(&["ls-remote", &self.url, reference, &peeled])
+                .operation(Operation::GitFetch)
+                .output()
+        }).map_err(|why| DownloadError::GitFailed { name: self.name.clone(), why })?;
+
+        let mut commit = None;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some(hash), Some(name)) = (fields.next(), fields.next()) {
+                if name == peeled || commit.is_none() {
+                    commit = Some(hash.to_owned());
+                }
+            }
+        }
+
+        Ok(commit)
+    }
+
+    fn head(path: &Path) -> Option<String> {
+        let output = Command::new("git").arg("-C").arg(path).args(&["rev-parse", "HEAD"])
+            .output()
+            .ok()?;
+        if output.status.success() {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+impl Fetcher for Git {
+    fn origin(&self) -> String { self.url.clone() }
+
+    fn resolve(&self) -> PathBuf {
+        let checkout = if self.shared { self.name.clone() } else { self.repository() };
+        PathBuf::from(["build/", &checkout].concat())
+    }
+
+    fn freshness(&self, path: &Path) -> Result<Freshness, DownloadError> {
+        if !path.exists() {
+            return Ok(Freshness::Missing);
+        }
+
+        // The remote may have new commits, which are only known once it is fetched.
+        let pinned = self.commit.as_ref().map_or(false, |commit| {
+            Git::head(path).map_or(false, |head| head.starts_with(commit.as_str()))
+        });
+
+        Ok(if pinned { Freshness::Fresh } else { Freshness::Stale })
+    }
+
+    fn outdated(&self, path: &Path) -> Result<Option<String>, DownloadError> {
+        if !path.exists() {
+            return Ok(Some(format!("{} was not cloned", self.url)));
+        }
+
+        let head = Git::head(path).unwrap_or_default();
+        if let Some(ref commit) = self.commit {
+            let why = format!("the checkout is not at commit {}", commit);
+            return Ok(if head.starts_with(commit.as_str()) { None } else { Some(why) });
+        }
+
+        let (reference, what) = match self.tag {
+            Some(ref tag) => (["refs/tags/", tag].concat(), ["tag ", tag].concat()),
+            None => (["refs/heads/", self.branch()].concat(), self.branch().to_owned()),
+        };
+
+        Ok(match self.remote_commit(&reference)? {
+            None => Some(format!("{} has no {}", self.url, what)),
+            Some(ref commit) if *commit != head => {
+                let short = &commit[..commit.len().min(12)];
+                Some(format!("{} is at {}, which is not checked out", what, short))
+            }
+            Some(_) => None,
+        })
+    }
+
+    fn fetch(&self, _: &Engine, path: &Path) -> Result<(), DownloadError> {
+        let git_error = |why| DownloadError::GitFailed { name: self.name.clone(), why };
+        if self.is_pinned() {
+            let mirror = self.update_mirror()?;
+            let mirror = mirror.canonicalize()
+                .map_err(|why| DownloadError::Mirror { path: mirror.clone(), why })?;
+            if path.exists() {
+                Cmd::new("git").arg("-C").arg(path).args(&["fetch", "--tags", "origin"])
+                    .operation(Operation::GitFetch)
+                    .run()
+                    .map_err(&git_error)?;
+            } else {
+                info!("checking out {} from the mirror of {}", self.name, self.url);
+                Cmd::new("git").args(&["clone", "--shared"]).arg(&mirror).arg(path)
+                    .operation(Operation::GitClone)
+                    .run()
+                    .map_err(&git_error)?;
+            }
+
+            let revision = self.revision();
+            info!("checking out {} at {}", self.name, revision);
+            return Cmd::new("git").arg("-C").arg(path).args(&["checkout", "--force", "--detach"])
+                .arg(&revision)
+                .run()
+                .map_err(&git_error);
+        }
+
+        let name = self.repository();
+        if path.exists() {
+            info!("pulling {}", name);
+            retry::network(&self.url, || {
+                Cmd::new("git").arg("-C").arg(path).args(&["pull", "origin", self.branch()])
+                    .operation(Operation::GitFetch)
+                    .run()
+            }).map_err(|why| DownloadError::GitFailed { name, why })
+        } else {
+            info!("cloning {}", name);
+            let clone = ["-C", "build", "clone", "-b", self.branch(), &self.url];
+            retry::network(&self.url, || {
+                Cmd::new("git").args(&clone).operation(Operation::GitClone).run()
+            })
+                .map_err(|why| DownloadError::GitFailed { name, why })
+        }
+    }
+
+    fn is_archive(&self) -> bool { false }
+}
+
+/// An archive on this system, which is verified and linked into the cache where downloaded
+/// archives are stored, so that it is extracted in the same way as they are.
+pub struct Archive {
+    name: String,
+    path: PathBuf,
+    checksum: String,
+}
+
+impl Fetcher for Archive {
+    fn origin(&self) -> String { self.path.display().to_string() }
+
+    fn resolve(&self) -> PathBuf {
+        cache_path(&self.name, self.path.file_name().and_then(|x| x.to_str()).unwrap_or(""))
+    }
+
+    fn freshness(&self, path: &Path) -> Result<Freshness, DownloadError> {
+        cached_freshness(path, &self.checksum)
+    }
+
+    fn fetch(&self, _: &Engine, path: &Path) -> Result<(), DownloadError> {
+        verify(&self.name, &self.path, &self.checksum)?;
+        if path.exists() {
+            fs::remove_file(path)
+                .map_err(|why| DownloadError::Open { file: path.to_path_buf(), why })?;
+        }
+
+        link_or_copy(&self.path, path, |from, to| fs::hard_link(from, to))
+            .map_err(|why| DownloadError::Open { file: path.to_path_buf(), why })
+    }
+
+    fn is_local(&self) -> bool { true }
+}
+
+/// Links the file to `to` with `link`, or copies it when it cannot be linked, as when it is on
+/// another file system, which it cannot be hard linked from.
+fn link_or_copy<F>(from: &Path, to: &Path, link: F) -> io::Result<()>
+    where F: Fn(&Path, &Path) -> io::Result<()>
+{
+    link(from, to).or_else(|_| fs::copy(from, to).map(|_| ()))
+}
+
+/// An archive which a command writes into the cache, such as from an artifact store which has
+/// its own client.
+pub struct Shell {
+    name: String,
+    command: String,
+    file: String,
+    checksum: String,
+}
+
+impl Shell {
+    /// The command, with `${path}`, `${name}`, and `${checksum}` replaced.
+    fn command(&self, path: &Path) -> String {
+        self.command
+            .replace("${path}", &path.to_string_lossy())
+            .replace("${name}", &self.name)
+            .replace("${checksum}", &self.checksum)
+    }
+}
+
+impl Fetcher for Shell {
+    fn origin(&self) -> String { ["`", &self.command, "`"].concat() }
+
+    fn resolve(&self) -> PathBuf { cache_path(&self.name, &self.file) }
+
+    fn freshness(&self, path: &Path) -> Result<Freshness, DownloadError> {
+        cached_freshness(path, &self.checksum)
+    }
+
+    fn fetch(&self, _: &Engine, path: &Path) -> Result<(), DownloadError> {
+        let open_error = |why| DownloadError::Open { file: path.to_path_buf(), why };
+        let partial = Partial::new(path).map_err(&open_error)?;
+        let command = self.command(partial.path());
+        info!("fetching {} with `{}`", self.name, command);
+        Cmd::new("sh").args(&["-c", &command]).run()
+            .map_err(|why| DownloadError::Command { name: self.name.clone(), why })?;
+
+        if !partial.path().is_file() {
+            return Err(DownloadError::NotWritten {
+                name: self.name.clone(),
+                file: path.to_path_buf(),
+            });
+        }
+
+        verify(&self.name, partial.path(), &self.checksum)?;
+        partial.complete().map_err(&open_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use std::os::unix::fs::MetadataExt;
+    use super::super::engine::Blocking;
+    use tempdir::TempDir;
+
+    fn shell(command: &str, checksum: &str) -> Shell {
+        Shell {
+            name: "pop-shop".into(),
+            command: command.into(),
+            file: "pop-shop.tar.xz".into(),
+            checksum: checksum.into(),
+        }
+    }
+
+    #[test]
+    fn commands_fetch_into_the_cache() {
+        let dir = TempDir::new("debrep-fetcher").unwrap();
+        let path = dir.path().join("pop-shop.tar.xz");
+        misc::write(&path, b"artifact\n").unwrap();
+        let digest = sha2_256_digest(File::open(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        match shell("true", &digest).fetch(&Blocking::new(), &path) {
+            Err(DownloadError::NotWritten { file, .. }) => assert_eq!(file, path),
+            other => panic!("expected the file to be missing, found {:?}", other),
+        }
+
+        let fetcher = shell("echo artifact > ${path}", &digest);
+        assert_eq!(fetcher.freshness(&path).unwrap(), Freshness::Missing);
+        fetcher.fetch(&Blocking::new(), &path).unwrap();
+        assert_eq!(fetcher.freshness(&path).unwrap(), Freshness::Fresh);
+
+        match shell("echo other > ${path}", &digest).fetch(&Blocking::new(), &path) {
+            Err(DownloadError::ChecksumMismatch { .. }) => (),
+            other => panic!("expected an invalid checksum, found {:?}", other),
+        }
+
+        // What was fetched is only replaced by a file which has the checksum.
+        assert_eq!(fetcher.freshness(&path).unwrap(), Freshness::Fresh);
+        assert!(!dir.path().join("pop-shop.tar.xz.partial").exists());
+
+        let failing = shell("echo 'no such artifact: ${name}' >&2; exit 3", &digest);
+        match failing.fetch(&Blocking::new(), &path) {
+            Err(DownloadError::Command { why, .. }) => {
+                assert_eq!(why.stderr, "no such artifact: pop-shop");
+                assert!(why.to_string().contains("failed with exit status 3"), "{}", why);
+            }
+            other => panic!("expected the command to fail, found {:?}", other),
+        }
+    }
+
+    fn archive(dir: &Path, checksum: &str) -> Archive {
+        Archive {
+            name: "pop-shop".into(),
+            path: dir.join("staged/pop-shop.tar.xz"),
+            checksum: checksum.into(),
+        }
+    }
+
+    #[test]
+    fn archives_are_verified_before_they_are_staged() {
+        let dir = TempDir::new("debrep-fetcher").unwrap();
+        let staged = dir.path().join("staged");
+        fs::create_dir(&staged).unwrap();
+        misc::write(staged.join("pop-shop.tar.xz"), b"archive\n").unwrap();
+        let digest = sha2_256_digest(File::open(staged.join("pop-shop.tar.xz")).unwrap()).unwrap();
+
+        let cached = dir.path().join("pop-shop.tar.xz");
+        let fetcher = archive(dir.path(), &digest);
+        assert_eq!(fetcher.freshness(&cached).unwrap(), Freshness::Missing);
+        fetcher.fetch(&Blocking::new(), &cached).unwrap();
+        assert_eq!(fetcher.freshness(&cached).unwrap(), Freshness::Fresh);
+        assert_eq!(misc::read(&cached).unwrap(), b"archive\n");
+
+        // An archive which does not have its checksum does not replace what was staged. The
+        // cached archive is a link to the staged one, which is replaced rather than written to.
+        fs::remove_file(staged.join("pop-shop.tar.xz")).unwrap();
+        misc::write(staged.join("pop-shop.tar.xz"), b"changed\n").unwrap();
+        match fetcher.fetch(&Blocking::new(), &cached) {
+            Err(DownloadError::ChecksumMismatch { item, expected, .. }) => {
+                assert_eq!(item, "pop-shop");
+                assert_eq!(expected, digest);
+            }
+            other => panic!("expected an invalid checksum, found {:?}", other),
+        }
+        assert_eq!(misc::read(&cached).unwrap(), b"archive\n");
+
+        fs::remove_file(staged.join("pop-shop.tar.xz")).unwrap();
+        match fetcher.fetch(&Blocking::new(), &cached) {
+            Err(DownloadError::Open { file, why }) => {
+                assert_eq!(file, staged.join("pop-shop.tar.xz"));
+                assert_eq!(why.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("expected the archive to be missing, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn archives_are_copied_when_they_cannot_be_linked() {
+        let dir = TempDir::new("debrep-fetcher").unwrap();
+        let (from, to) = (dir.path().join("archive"), dir.path().join("cached"));
+        misc::write(&from, b"archive\n").unwrap();
+
+        fn cross_device(_: &Path, _: &Path) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Other, "invalid cross-device link"))
+        }
+
+        link_or_copy(&from, &to, cross_device).unwrap();
+        assert_eq!(misc::read(&to).unwrap(), b"archive\n");
+
+        // The copy is a file of its own, rather than a link to the archive.
+        fs::remove_file(&to).unwrap();
+        link_or_copy(&from, &to, |from, to| fs::hard_link(from, to)).unwrap();
+        assert_eq!(fs::metadata(&from).unwrap().nlink(), 2);
+        fs::remove_file(&to).unwrap();
+        link_or_copy(&from, &to, cross_device).unwrap();
+        assert_eq!(fs::metadata(&from).unwrap().nlink(), 1);
+    }
+
+    #[test]
+    fn missing_inputs_are_an_error_offline() {
+        let fetcher = shell("exit 1", &"0".repeat(64));
+        match fetch("pop-shop", &fetcher, &Blocking::new(), true) {
+            Err(DownloadError::Offline { name, .. }) => assert_eq!(name, "pop-shop"),
+            other => panic!("expected an offline error, found {:?}", other),
+        }
+    }
+}
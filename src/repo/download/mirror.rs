@@ -0,0 +1,537 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use config::{Config, Mirror, MirrorPackage};
+use misc::{self, control_field};
+use repo::keys::{self, KeyError};
+use repo::provenance::{Origin, Provenance};
+use repo::run;
+use repo::version::compare;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use tempdir::TempDir;
+use xz2::read::XzDecoder;
+use super::DownloadError;
+use super::engine::Engine;
+use super::request::Request;
+
+#[derive(Debug, Fail)]
+pub enum MirrorError {
+    #[fail(display = "failed to resolve the key of {}: {}", mirror, why)]
+    Key { mirror: String, why: KeyError },
+    #[fail(display = "failed to fetch {}: {}", url, why)]
+    Fetch { url: String, why: io::Error },
+    #[fail(display = "the InRelease file of {} is not signed by its key: {}", mirror, why)]
+    Signature { mirror: String, why: io::Error },
+    #[fail(display = "the Release file of {} does not list {}", mirror, index)]
+    NoIndex { mirror: String, index: String },
+    #[fail(display = "failed to read {}: {}", url, why)]
+    Index { url: String, why: io::Error },
+    #[fail(display = "{} of {} is not a valid version constraint", constraint, package)]
+    Constraint { package: String, constraint: String },
+    #[fail(display = "failed to compare versions of {}: {}", package, why)]
+    Compare { package: String, why: io::Error },
+    #[fail(display = "failed to download {}: {}", url, why)]
+    Download { url: String, why: DownloadError },
+    #[fail(display = "failed to remove {}: {}", file, why)]
+    Remove { file: String, why: io::Error },
+    #[fail(display = "failed to get the architecture of this system: {}", why)]
+    Architecture { why: io::Error },
+    #[fail(display = "{} is not a PPA of the form ppa:user/name", ppa)]
+    Ppa { ppa: String },
+    #[fail(display = "{} has not been copied from {}, and downloads are offline", mirror, url)]
+    Offline { mirror: String, url: String },
+}
+
+impl MirrorError {
+    /// What kind of failure this is, for the report of failed downloads.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            MirrorError::Key { .. } | MirrorError::Signature { .. } => "signature",
+            MirrorError::Fetch { .. } | MirrorError::Index { .. } => "network",
+            MirrorError::Download { ref why, .. } => why.kind(),
+            MirrorError::NoIndex { .. }
+            | MirrorError::Constraint { .. }
+            | MirrorError::Ppa { .. } => "config",
+            MirrorError::Compare { .. } | MirrorError::Architecture { .. } => "command",
+            MirrorError::Remove { .. } => "file",
+            MirrorError::Offline { .. } => "offline",
+        }
+    }
+}
+
+/// A package from a `Packages` or `Sources` index. The architecture of source packages is
+/// `source`.
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    package: String,
+    version: String,
+    architecture: String,
+    /// The paths of the files of the package within the repository, and their SHA256 digests.
+    files: Vec<(String, String)>,
+}
+
+/// Copies the packages of the mirror into the pool, with the key of the mirror.
+pub fn download(
+    client: &Client,
+    engine: &Engine,
+    config: &Config,
+    mirror: &Mirror,
+    provenance: &mut Provenance,
+) -> Result<u64, MirrorError> {
+    let keyring = keys::resolve(config, Path::new("."), &mirror.key)
+        .map_err(|why| MirrorError::Key { mirror: mirror.name.clone(), why })?;
+
+    copy(client, engine, &config.archive, mirror, &keyring, provenance)
+}
+
+/// Copies the packages of the mirror into the pool, removing versions which were copied before
+/// and are no longer the newest that satisfy their constraints. The files of every package are
+/// downloaded in one batch. Returns the bytes downloaded.
+pub fn copy(
+    client: &Client,
+    engine: &Engine,
+    archive: &str,
+    mirror: &Mirror,
+    keyring: &Path,
+    provenance: &mut Provenance,
+) -> Result<u64, MirrorError> {
+    info!("checking {} {} for updates to {}", mirror.url, mirror.suite, mirror.name);
+    let release = release(client, mirror, keyring)?;
+    let architectures = match mirror.architectures {
+        Some(ref architectures) => architectures.clone(),
+        None => {
+            let host = misc::host_architecture().map_err(|why| MirrorError::Architecture { why })?;
+            vec![host, "all".to_owned()]
+        }
+    };
+
+    let mut entries: Vec<Entry> = Vec::new();
+    if mirror.binaries {
+        for arch in &architectures {
+            // Packages of `all` are also listed in the indices of each architecture.
+            let required = arch != "all" || architectures.len() == 1;
+            let base = [&mirror.component, "/binary-", arch, "/Packages"].concat();
+            let index = index(client, mirror, &release, &base, required)?;
+            for entry in parse_packages(&index) {
+                if architectures.contains(&entry.architecture)
+                    && !entries.iter().any(|x| x.files == entry.files)
+                {
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+
+    if mirror.sources {
+        let base = [&mirror.component, "/source/Sources"].concat();
+        entries.extend(parse_sources(&index(client, mirror, &release, &base, true)?));
+    }
+
+    let mut selections = Vec::new();
+    for package in &mirror.packages {
+        let selected = select(&entries, package)?;
+        let previous: Vec<String> = provenance.files_of(&mirror.name)
+            .into_iter()
+            .filter(|file| package_of(file) == Some(package.name.as_str()))
+            .collect();
+
+        if selected.is_empty() {
+            match package.version {
+                Some(ref constraint) => warn!(
+                    "no version of {} in {} {} satisfies {}; keeping {} files in the pool",
+                    package.name, mirror.url, mirror.suite, constraint, previous.len()
+                ),
+                None => warn!(
+                    "{} is no longer in {} {}; keeping {} files in the pool",
+                    package.name, mirror.url, mirror.suite, previous.len()
+                ),
+            }
+
+            continue;
+        }
+
+        let mut requests = Vec::new();
+        for entry in selected {
+            for &(ref path, ref sha256) in &entry.files {
+                let url = [mirror.url.trim_right_matches('/'), "/", path].concat();
+                requests.push(Request::new(&url, Some(sha256), &pool_path(archive, entry, path)));
+            }
+        }
+
+        selections.push((previous, requests));
+    }
+
+    let batch: Vec<Request> = selections.iter().flat_map(|x| x.1.iter().cloned()).collect();
+    let mut results = engine.files(&batch).into_iter();
+    let mut downloaded = 0;
+    for (previous, requests) in selections {
+        let mut current = Vec::new();
+        for (request, result) in requests.into_iter().zip(results.by_ref()) {
+            let Request { url, path: destination, .. } = request;
+            let bytes = result.map_err(|why| MirrorError::Download { url: url.clone(), why })?;
+            if bytes != 0 {
+                if let Some(run) = run::current() {
+                    if let Ok(path) = destination.canonicalize() {
+                        run.add_published(path);
+                    }
+                }
+            }
+
+            if let Ok(file) = destination.strip_prefix("repo") {
+                let file = file.to_string_lossy().into_owned();
+                provenance.insert(file.clone(), Origin { entry: mirror.name.clone(), url });
+                current.push(file);
+            }
+
+            downloaded += bytes;
+        }
+
+        for file in previous.into_iter().filter(|file| !current.contains(file)) {
+            info!("removing {}, which {} no longer selects", file, mirror.name);
+            match fs::remove_file(Path::new("repo").join(&file)) {
+                Ok(()) => (),
+                Err(ref why) if why.kind() == io::ErrorKind::NotFound => (),
+                Err(why) => return Err(MirrorError::Remove { file, why }),
+            }
+
+            provenance.remove(&file);
+        }
+    }
+
+    Ok(downloaded)
+}
+
+/// Checks that the packages of the mirror were copied before, since its indices cannot be fetched
+/// offline. The copied packages are kept as they are.
+pub fn cached(name: &str, url: &str, provenance: &Provenance) -> Result<(), MirrorError> {
+    let files = provenance.files_of(name);
+    if files.is_empty() || !files.iter().all(|file| Path::new("repo").join(file).exists()) {
+        return Err(MirrorError::Offline { mirror: name.to_owned(), url: url.to_owned() });
+    }
+
+    warn!("using the packages which were copied for {}, which may be outdated", name);
+    Ok(())
+}
+
+fn fetch(client: &Client, url: &str) -> Result<Vec<u8>, MirrorError> {
+    let fetch_error = |why: io::Error| MirrorError::Fetch { url: url.to_owned(), why };
+    let mut response = client.get(url)
+        .send()
+        .map_err(|why| fetch_error(io::Error::new(io::ErrorKind::Other, format!("{}", why))))?;
+
+    if !response.status().is_success() {
+        return Err(fetch_error(io::Error::new(io::ErrorKind::NotFound, "request failed")));
+    }
+
+    let mut data = Vec::new();
+    response.copy_to(&mut data)
+        .map_err(|why| fetch_error(io::Error::new(io::ErrorKind::Other, format!("{}", why))))?;
+    Ok(data)
+}
+
+/// Fetches the `InRelease` file of the suite, and returns its contents once its signature is
+/// verified with the keyring.
+fn release(client: &Client, mirror: &Mirror, keyring: &Path) -> Result<String, MirrorError> {
+    let url = [mirror.url.trim_right_matches('/'), "/dists/", &mirror.suite, "/InRelease"].concat();
+    let signed = fetch(client, &url)?;
+    verify(&signed, keyring)
+        .map_err(|why| MirrorError::Signature { mirror: mirror.name.clone(), why })
+}
+
+/// Verifies a clearsigned file with `gpgv`, and returns the text which was signed.
+fn verify(signed: &[u8], keyring: &Path) -> io::Result<String> {
+    let dir = TempDir::new("debrep-mirror")?;
+    let path = dir.path().join("InRelease");
+    misc::write(&path, signed)?;
+
+    let output = Command::new("gpgv")
+        .arg("--keyring")
+        .arg(keyring)
+        .args(&["--output", "-"])
+        .arg(&path)
+        .stdin(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, stderr.trim().to_owned()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The SHA256 digests of the files which a `Release` file lists, by their path within the suite.
+fn release_digests(release: &str) -> BTreeMap<String, String> {
+    release.lines()
+        .skip_while(|line| !line.starts_with("SHA256:"))
+        .skip(1)
+        .take_while(|line| line.starts_with(' '))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let digest = fields.next()?;
+            let path = fields.nth(1)?;
+            Some((path.to_owned(), digest.to_owned()))
+        })
+        .collect()
+}
+
+/// Fetches an index, such as `main/binary-amd64/Packages`, preferring compressed indices, and
+/// verifies it against the digest which the `Release` file lists for it. Indices which are not
+/// listed are empty, unless they are required.
+fn index(
+    client: &Client,
+    mirror: &Mirror,
+    release: &str,
+    base: &str,
+    required: bool,
+) -> Result<String, MirrorError> {
+    let digests = release_digests(release);
+    let path = match [".xz", ".gz", ""].iter()
+        .map(|ext| [&base, *ext].concat())
+        .find(|path| digests.contains_key(path))
+    {
+        Some(path) => path,
+        None if required => {
+            let index = base.to_owned();
+            return Err(MirrorError::NoIndex { mirror: mirror.name.clone(), index });
+        }
+        None => return Ok(String::new()),
+    };
+
+    let url = [mirror.url.trim_right_matches('/'), "/dists/", &mirror.suite, "/", &path].concat();
+    let data = fetch(client, &url)?;
+
+    let mut hasher = Sha256::default();
+    hasher.input(&data);
+    let found = format!("{:x}", hasher.result());
+    if Some(&found) != digests.get(&path) {
+        let why = io::Error::new(io::ErrorKind::InvalidData, "checksum does not match the Release");
+        return Err(MirrorError::Index { url, why });
+    }
+
+    decompress(&path, &data).map_err(|why| MirrorError::Index { url, why })
+}
+
+fn decompress(path: &str, data: &[u8]) -> io::Result<String> {
+    let mut text = String::new();
+    if path.ends_with(".xz") {
+        XzDecoder::new(data).read_to_string(&mut text)?;
+    } else if path.ends_with(".gz") {
+        let mut child = Command::new("gzip")
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        // gzip writes while it reads, so its output is read from another thread.
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = thread::spawn(move || {
+            let mut text = String::new();
+            stdout.read_to_string(&mut text).map(|_| text)
+        });
+
+        let written = child.stdin.take().unwrap().write_all(data);
+        let status = child.wait()?;
+        text = reader.join()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to read from gzip"))??;
+        written?;
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "gzip failed"));
+        }
+    } else {
+        text = String::from_utf8_lossy(data).into_owned();
+    }
+
+    Ok(text)
+}
+
+
+fn parse_packages(index: &str) -> Vec<Entry> {
+    index.split("\n\n")
+        .filter_map(|stanza| {
+            let file = (control_field(stanza, "Filename")?, control_field(stanza, "SHA256")?);
+            Some(Entry {
+                package: control_field(stanza, "Package")?,
+                version: control_field(stanza, "Version")?,
+                architecture: control_field(stanza, "Architecture")?,
+                files: vec![file],
+            })
+        })
+        .collect()
+}
+
+fn parse_sources(index: &str) -> Vec<Entry> {
+    index.split("\n\n")
+        .filter_map(|stanza| {
+            let directory = control_field(stanza, "Directory")?;
+            let files = stanza.lines()
+                .skip_while(|line| !line.starts_with("Checksums-Sha256:"))
+                .skip(1)
+                .take_while(|line| line.starts_with(' '))
+                .filter_map(|line| {
+                    let mut fields = line.split_whitespace();
+                    let digest = fields.next()?;
+                    let name = fields.nth(1)?;
+                    Some(([&directory, "/", name].concat(), digest.to_owned()))
+                })
+                .collect::<Vec<_>>();
+
+            if files.is_empty() {
+                return None;
+            }
+
+            Some(Entry {
+                package: control_field(stanza, "Package")?,
+                version: control_field(stanza, "Version")?,
+                architecture: "source".to_owned(),
+                files,
+            })
+        })
+        .collect()
+}
+
+/// Parses a version constraint into the operator of `dpkg --compare-versions`, and a version.
+fn constraint(text: &str) -> Option<(&'static str, &str)> {
+    let operators = [(">=", "ge"), ("<=", "le"), (">>", "gt"), ("<<", "lt"), ("=", "eq")];
+    let text = text.trim();
+    for &(prefix, operator) in &operators {
+        if text.starts_with(prefix) {
+            let version = text[prefix.len()..].trim();
+            return if version.is_empty() { None } else { Some((operator, version)) };
+        }
+    }
+
+    if text.is_empty() || text.starts_with('<') || text.starts_with('>') {
+        None
+    } else {
+        Some(("eq", text))
+    }
+}
+
+/// The newest version of the package for each architecture, which satisfies its constraint.
+fn select<'a>(
+    entries: &'a [Entry],
+    package: &MirrorPackage,
+) -> Result<Vec<&'a Entry>, MirrorError> {
+    let constraint = match package.version {
+        Some(ref text) => Some(constraint(text).ok_or_else(|| MirrorError::Constraint {
+            package: package.name.clone(),
+            constraint: text.clone(),
+        })?),
+        None => None,
+    };
+
+    let compare_error = |why: io::Error| {
+        MirrorError::Compare { package: package.name.clone(), why }
+    };
+    let mut newest: BTreeMap<&str, &Entry> = BTreeMap::new();
+    for entry in entries.iter().filter(|entry| entry.package == package.name) {
+        if let Some((operator, version)) = constraint {
+            if !compare(&entry.version, operator, version).map_err(&compare_error)? {
+                continue;
+            }
+        }
+
+        let newer = match newest.get(entry.architecture.as_str()) {
+            Some(current) => compare(&entry.version, "gt", &current.version)
+                .map_err(&compare_error)?,
+            None => true,
+        };
+
+        if newer {
+            newest.insert(&entry.architecture, entry);
+        }
+    }
+
+    Ok(newest.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// Where a file of a mirrored package is stored within the pool.
+fn pool_path(archive: &str, entry: &Entry, path: &str) -> PathBuf {
+    let name = &entry.package;
+    let filename = &path[path.rfind('/').map_or(0, |x| x + 1)..];
+    let dst = if entry.architecture == "source" {
+        "/main/source/".to_owned()
+    } else {
+        ["/main/binary-", &entry.architecture, "/"].concat()
+    };
+
+    PathBuf::from(["repo/pool/", archive, &dst, &name[0..1], "/", name, "/", filename].concat())
+}
+
+/// The name of the package of a `.deb` in the pool.
+fn package_of(file: &str) -> Option<&str> {
+    let filename = &file[file.rfind('/').map_or(0, |x| x + 1)..];
+    filename.find('_').map(|pos| &filename[..pos])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_lists_digests() {
+        let release = "Origin: Vendor\nSuite: stable\n\
+                       MD5Sum:\n 0000 10 main/binary-amd64/Packages\n\
+                       SHA256:\n aaaa 10 main/binary-amd64/Packages\n\
+                       \x20bbbb 5 main/binary-amd64/Packages.xz\n\
+                       Acquire-By-Hash: yes\n";
+
+        let digests = release_digests(release);
+        assert_eq!(digests.len(), 2);
+        assert_eq!(digests.get("main/binary-amd64/Packages.xz").map(|x| x.as_str()), Some("bbbb"));
+    }
+
+    #[test]
+    fn packages_are_parsed() {
+        let index = "Package: vendor-tool\nVersion: 2.1-1\nArchitecture: amd64\n\
+                     Filename: pool/main/v/vendor-tool/vendor-tool_2.1-1_amd64.deb\nSHA256: cccc\n\
+                     Description: a tool\n multiple lines\n\n\
+                     Package: broken\nVersion: 1\n\n";
+
+        let entries = parse_packages(index);
+        let file = "pool/main/v/vendor-tool/vendor-tool_2.1-1_amd64.deb";
+        assert_eq!(entries, vec![Entry {
+            package: "vendor-tool".to_owned(),
+            version: "2.1-1".to_owned(),
+            architecture: "amd64".to_owned(),
+            files: vec![(file.to_owned(), "cccc".to_owned())],
+        }]);
+
+        let pool = "pool/bionic/main/binary-amd64/v/vendor-tool/vendor-tool_2.1-1_amd64.deb";
+        assert_eq!(pool_path("bionic", &entries[0], file), Path::new("repo").join(pool));
+        assert_eq!(package_of(pool), Some("vendor-tool"));
+    }
+
+    #[test]
+    fn sources_are_parsed() {
+        let index = "Package: vendor-tool\nBinary: vendor-tool\nVersion: 2.1-1\n\
+                     Directory: pool/main/v/vendor-tool\n\
+                     Files:\n 0000 10 vendor-tool_2.1-1.dsc\n\
+                     Checksums-Sha256:\n aaaa 10 vendor-tool_2.1-1.dsc\n\
+                     \x20bbbb 20 vendor-tool_2.1.orig.tar.gz\n\n";
+
+        let entries = parse_sources(index);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].architecture, "source");
+        assert_eq!(entries[0].files[1].0, "pool/main/v/vendor-tool/vendor-tool_2.1.orig.tar.gz");
+        assert_eq!(
+            pool_path("bionic", &entries[0], &entries[0].files[1].0),
+            Path::new("repo/pool/bionic/main/source/v/vendor-tool/vendor-tool_2.1.orig.tar.gz")
+        );
+    }
+
+    #[test]
+    fn constraints() {
+        assert_eq!(constraint(">= 1.2"), Some(("ge", "1.2")));
+        assert_eq!(constraint("<<2.0"), Some(("lt", "2.0")));
+        assert_eq!(constraint("1.2.3-1"), Some(("eq", "1.2.3-1")));
+        assert_eq!(constraint("> 1.2"), None);
+        assert_eq!(constraint(">="), None);
+    }
+}
@@ -1,103 +1,845 @@
-mod checksum;
 mod direct;
+pub mod engine;
+pub mod fetcher;
+mod mirror;
+mod ppa;
+mod repack;
 mod request;
+mod snapshot;
 mod sources;
 mod url;
 
-use config::Config;
+use command::CommandError;
+use config::{Config, Definition, Direct, Repack, Source, SourceLocation};
+use output::{Status, Summary};
+use rayon::ThreadPoolBuilder;
+use retry::Retryable;
 use self::direct::DownloadResult;
+use self::engine::Engine;
+use self::repack::Repacked;
+use self::snapshot::SnapshotError;
+use super::dry_run::Intent;
+use super::provenance::{self, Origin, Provenance, PROVENANCE};
+use super::run;
+use super::state::{FileProvenance, OriginKind, State};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io;
-use std::path::PathBuf;
-use std::process::exit;
+use std::path::{Path, PathBuf};
 use reqwest::{self, Client};
 
-pub fn all(config: &Config) {
-    let mut package_failed = false;
+pub use self::request::reachable;
+
+/// Runs the downloads on at most `jobs` threads, or on the threads of the global pool if the
+/// downloads are not limited.
+fn limited<T: Send, F: FnOnce() -> T + Send>(jobs: Option<usize>, downloads: F) -> T {
+    match jobs.map(|jobs| ThreadPoolBuilder::new().num_threads(jobs).build()) {
+        Some(Ok(pool)) => pool.install(downloads),
+        Some(Err(why)) => {
+            warn!("unable to limit the downloads to {:?} threads: {}", jobs, why);
+            downloads()
+        }
+        None => downloads(),
+    }
+}
+
+/// Downloads every configured package, and fetches every source, recording what failed in the
+/// summary. When `offline`, only what was already downloaded is used.
+pub fn all(config: &Config, engine: &Engine, offline: bool) -> Downloads {
+    let mut downloads = Downloads::default();
+    let mut provenance = Provenance::open(Path::new(PROVENANCE));
     if let Some(ref ddl_sources) = config.direct {
-        for (id, result) in direct::parallel(ddl_sources, &config.archive)
-            .into_iter()
+        let downloaded = limited(config.download_jobs(), || {
+            direct::parallel(engine, ddl_sources, &config.archive, offline)
+        });
+
+        for (id, result) in downloaded.into_iter()
             .enumerate()
         {
             let name = &ddl_sources[id].name;
             match result {
                 Ok(DownloadResult::Downloaded(bytes)) => {
-                    info!("package '{}' successfully downloaded {} bytes", name, bytes);
+                    record_origins(&mut provenance, &ddl_sources[id], &config.archive);
+                    downloads.add(Status::Downloaded, name, format!("{} bytes", bytes));
+                }
+                Ok(DownloadResult::AlreadyExists) => {
+                    downloads.add(Status::Skipped, name, "cached".into());
                 }
                 Err(why) => {
                     error!("package '{}' failed to download: {}", name, why);
-                    package_failed = true;
+                    downloads.fail(
+                        name,
+                        &ddl_sources[id].defined,
+                        direct_origin(&ddl_sources[id]),
+                        why.kind(),
+                        &why,
+                    );
                 }
             }
         }
+
+        save_provenance(&provenance);
     }
 
-    if let Some(ref sources) = config.source {
-        for (id, result) in sources::parallel(sources)
-            .into_iter()
-            .enumerate()
-        {
-            let name = &sources[id].name;
+    if let Some(ref mirrors) = config.mirror {
+        let client = Client::new();
+        for entry in mirrors {
+            let result = if offline {
+                mirror::cached(&entry.name, &entry.url, &provenance).map(|_| None)
+            } else {
+                mirror::download(&client, engine, config, entry, &mut provenance).map(Some)
+            };
+
+            match result {
+                Ok(None) => downloads.add(Status::Skipped, &entry.name, "cached".into()),
+                Ok(Some(bytes)) => {
+                    downloads.add(Status::Downloaded, &entry.name, format!("{} bytes", bytes));
+                }
+                Err(why) => {
+                    error!("mirror '{}' failed to download: {}", entry.name, why);
+                    downloads.fail(
+                        &entry.name,
+                        &entry.defined,
+                        entry.url.clone(),
+                        why.kind(),
+                        &why,
+                    );
+                }
+            }
+        }
+
+        save_provenance(&provenance);
+    }
+
+    if let Some(ref ppas) = config.ppa {
+        let client = Client::new();
+        for entry in ppas {
+            let result = if offline {
+                mirror::cached(&entry.name, &entry.ppa, &provenance).map(|_| None)
+            } else {
+                ppa::download(&client, engine, &config.archive, entry, &mut provenance).map(Some)
+            };
+
             match result {
-                Ok(()) => {
-                    info!("package '{}' was successfully fetched", name);
+                Ok(None) => downloads.add(Status::Skipped, &entry.name, "cached".into()),
+                Ok(Some(bytes)) => {
+                    downloads.add(Status::Downloaded, &entry.name, format!("{} bytes", bytes));
                 }
+                Err(why) => {
+                    error!("PPA '{}' failed to download: {}", entry.name, why);
+                    downloads.fail(
+                        &entry.name,
+                        &entry.defined,
+                        entry.ppa.clone(),
+                        why.kind(),
+                        &why,
+                    );
+                }
+            }
+        }
+
+        save_provenance(&provenance);
+    }
+
+    if let Some(ref repacks) = config.repack {
+        for entry in repacks {
+            match repack::repack(engine, &config.archive, entry, &mut provenance, offline) {
+                Ok(Repacked::Repacked(path)) => {
+                    downloads.add(Status::Built, &entry.name, path.display().to_string());
+                }
+                Ok(Repacked::Unchanged) => {
+                    downloads.add(Status::Skipped, &entry.name, "unchanged".into());
+                }
+                Err(why) => {
+                    error!("package '{}' failed to repack: {}", entry.name, why);
+                    downloads.fail(
+                        &entry.name,
+                        &entry.defined,
+                        repack_origin(entry),
+                        why.kind(),
+                        &why,
+                    );
+                }
+            }
+        }
+
+        save_provenance(&provenance);
+    }
+
+    let sources = config.build_units();
+    if !sources.is_empty() {
+        let fetched = limited(config.download_jobs(), || {
+            sources::parallel(engine, &sources, offline)
+        });
+
+        for (id, result) in fetched.into_iter().enumerate() {
+            let name = &sources[id].name;
+            match result {
+                Ok(()) => downloads.add(Status::Fetched, name, String::new()),
                 Err(why) => {
                     error!("package '{}' failed to download: {}", name, why);
-                    package_failed = true;
+                    downloads.fail(
+                        name,
+                        &sources[id].defined,
+                        source_origin(&sources[id]),
+                        why.kind(),
+                        &why,
+                    );
+                }
+            }
+        }
+    }
+
+    if !downloads.failures.is_empty() {
+        downloads.summary.print();
+    }
+
+    downloads
+}
+
+/// The files which fetching the named packages, or every package, would download, with their
+/// lengths if their servers report them. Only the files of direct entries and the tarballs of
+/// sources are known before they are downloaded, and those which exist are not downloaded again.
+pub fn pending(config: &Config, packages: Option<&[&str]>) -> Vec<(PathBuf, Option<u64>)> {
+    let selected = |name: &str| packages.map_or(true, |packages| packages.contains(&name));
+    let mut files: Vec<(String, PathBuf)> = Vec::new();
+    let direct = config.direct.iter().flat_map(|x| x);
+    for item in direct.filter(|x| x.snapshot.is_none() && selected(&x.name)) {
+        for file_item in &item.urls {
+            match direct::locate(item, file_item, &config.archive) {
+                Ok((_, ref path)) if path.exists() => (),
+                Ok(file) => files.push(file),
+                Err(why) => warn!("unable to locate a file of {}: {}", item.name, why),
+            }
+        }
+    }
+
+    for item in config.build_units().iter().filter(|x| selected(&x.name)) {
+        if let Some(SourceLocation::URL { ref url, .. }) = item.location {
+            let path = fetcher::of(item).map(|fetcher| fetcher.resolve());
+            if let Some(path) = path.and_then(|x| if x.exists() { None } else { Some(x) }) {
+                files.push((url.clone(), path));
+            }
+        }
+    }
+
+    let client = Client::new();
+    files.into_iter()
+        .map(|(url, path)| {
+            let length = request::content_length(&client, &url).unwrap_or_else(|why| {
+                debug!("unable to determine the length of {}: {}", url, why);
+                None
+            });
+
+            (path, length)
+        })
+        .collect()
+}
+
+/// What fetching the named packages, or every package, would do, without downloading anything:
+/// the files which are not downloaded yet, as `pending` finds them, and the sources whose inputs
+/// are missing or outdated. Mirrors, PPAs, and repacked packages are not checked.
+pub fn dry_run(config: &Config, packages: Option<&[&str]>) -> Vec<Intent> {
+    let mut intents: Vec<Intent> = pending(config, packages).into_iter()
+        .map(|(path, length)| {
+            let file = path.file_name().map_or(String::new(), |x| x.to_string_lossy().into_owned());
+            Intent::Download { file, length }
+        })
+        .collect();
+
+    let selected = |name: &str| packages.map_or(true, |packages| packages.contains(&name));
+    for item in config.build_units().iter().filter(|x| selected(&x.name)) {
+        // The tarballs of sources are among the pending files.
+        let location = match item.location {
+            Some(SourceLocation::URL { .. }) => None,
+            _ => fetcher::of(item),
+        };
+
+        for fetcher in location.into_iter().chain(fetcher::debian(item)) {
+            match fetcher.outdated(&fetcher.resolve()) {
+                Ok(Some(why)) => intents.push(Intent::Fetch { name: item.name.clone(), why }),
+                Ok(None) => (),
+                Err(why) => {
+                    let why = format!("{} cannot be checked: {}", fetcher.origin(), why);
+                    intents.push(Intent::Fetch { name: item.name.clone(), why });
                 }
             }
         }
     }
 
-    if package_failed {
-        error!("exiting due to error");
-        exit(1);
+    intents
+}
+
+/// The URLs which fetching downloads from, or clones: those of direct entries, sources, repacked
+/// packages, mirrors, PPAs, and keys, and the services which snapshots are located through.
+pub fn urls(config: &Config) -> Vec<String> {
+    let mut urls = Vec::new();
+    for item in config.direct.iter().flat_map(|x| x) {
+        if item.snapshot.is_some() {
+            urls.push(snapshot::API.to_owned());
+        }
+
+        for file_item in &item.urls {
+            if let Ok((url, _)) = direct::locate(item, file_item, &config.archive) {
+                urls.push(url);
+            }
+        }
     }
+
+    for item in config.build_units() {
+        match item.location {
+            Some(SourceLocation::URL { ref url, .. })
+            | Some(SourceLocation::Git { ref url, .. }) => urls.push(url.clone()),
+            _ => (),
+        }
+
+        urls.extend(fetcher::debian(&item).map(|fetcher| fetcher.origin()));
+    }
+
+    let repack = config.repack.iter().flat_map(|x| x);
+    urls.extend(repack.filter_map(|item| item.url.clone()));
+    urls.extend(config.mirror.iter().flat_map(|x| x).map(|mirror| mirror.url.clone()));
+    if config.ppa.as_ref().map_or(false, |ppas| !ppas.is_empty()) {
+        urls.push(ppa::HOST.to_owned());
+    }
+
+    urls.extend(config.keys.iter().flat_map(|x| x).filter_map(|key| key.url.clone()));
+    urls
 }
 
+/// Downloads the named packages, recording what failed in the summary.
 // TODO: Optimize with a shrinking queue.
-pub fn packages(sources: &Config, packages: &[&str]) {
+pub fn packages(
+    sources: &Config,
+    engine: &Engine,
+    packages: &[&str],
+    offline: bool,
+) -> Downloads {
+    let mut downloads = Downloads::default();
     let mut downloaded = 0;
+    let mut provenance = Provenance::open(Path::new(PROVENANCE));
+
+    // A single client keeps its connections alive between packages from the same host.
+    let client = Client::new();
 
     if let Some(ref source) = sources.direct.as_ref() {
         for source in source.iter().filter(|s| packages.contains(&s.name.as_str())) {
-            if let Err(why) = direct::download(&Client::new(), source, &sources.archive) {
-                error!("failed to download {}: {}", &source.name, why);
-                exit(1);
+            match direct::download(engine, source, &sources.archive, offline) {
+                Ok(DownloadResult::Downloaded(bytes)) => {
+                    record_origins(&mut provenance, source, &sources.archive);
+                    save_provenance(&provenance);
+                    downloads.add(Status::Downloaded, &source.name, format!("{} bytes", bytes));
+                }
+                Ok(DownloadResult::AlreadyExists) => {
+                    downloads.add(Status::Skipped, &source.name, "cached".into());
+                }
+                Err(why) => {
+                    error!("failed to download {}: {}", &source.name, why);
+                    downloads.fail(
+                        &source.name,
+                        &source.defined,
+                        direct_origin(source),
+                        why.kind(),
+                        &why,
+                    );
+                }
             }
 
             downloaded += 1;
             if downloaded == packages.len() {
-                return;
+                return downloads;
             }
         }
     }
 
-    if let Some(ref source) = sources.source.as_ref() {
-        for source in source.iter().filter(|s| packages.contains(&s.name.as_str())) {
-            if let Err(why) = sources::download(source) {
-                error!("failed to download source {}: {}", &source.name, why);
-                exit(1);
+    if let Some(ref mirrors) = sources.mirror.as_ref() {
+        for entry in mirrors.iter().filter(|s| packages.contains(&s.name.as_str())) {
+            let result = if offline {
+                mirror::cached(&entry.name, &entry.url, &provenance).map(|_| None)
+            } else {
+                mirror::download(&client, engine, sources, entry, &mut provenance).map(Some)
+            };
+
+            save_provenance(&provenance);
+            match result {
+                Ok(None) => downloads.add(Status::Skipped, &entry.name, "cached".into()),
+                Ok(Some(bytes)) => {
+                    downloads.add(Status::Downloaded, &entry.name, format!("{} bytes", bytes));
+                }
+                Err(why) => {
+                    error!("failed to download {}: {}", &entry.name, why);
+                    downloads.fail(
+                        &entry.name,
+                        &entry.defined,
+                        entry.url.clone(),
+                        why.kind(),
+                        &why,
+                    );
+                }
+            }
+
+            downloaded += 1;
+            if downloaded == packages.len() {
+                return downloads;
+            }
+        }
+    }
+
+    if let Some(ref ppas) = sources.ppa.as_ref() {
+        for entry in ppas.iter().filter(|s| packages.contains(&s.name.as_str())) {
+            let result = if offline {
+                mirror::cached(&entry.name, &entry.ppa, &provenance).map(|_| None)
+            } else {
+                ppa::download(&client, engine, &sources.archive, entry, &mut provenance).map(Some)
+            };
+
+            save_provenance(&provenance);
+            match result {
+                Ok(None) => downloads.add(Status::Skipped, &entry.name, "cached".into()),
+                Ok(Some(bytes)) => {
+                    downloads.add(Status::Downloaded, &entry.name, format!("{} bytes", bytes));
+                }
+                Err(why) => {
+                    error!("failed to download {}: {}", &entry.name, why);
+                    downloads.fail(
+                        &entry.name,
+                        &entry.defined,
+                        entry.ppa.clone(),
+                        why.kind(),
+                        &why,
+                    );
+                }
             }
 
             downloaded += 1;
             if downloaded == packages.len() {
-                return;
+                return downloads;
             }
         }
     }
+
+    if let Some(ref repacks) = sources.repack.as_ref() {
+        for entry in repacks.iter().filter(|s| packages.contains(&s.name.as_str())) {
+            let result = repack::repack(engine, &sources.archive, entry, &mut provenance, offline);
+            save_provenance(&provenance);
+            match result {
+                Ok(Repacked::Repacked(path)) => {
+                    downloads.add(Status::Built, &entry.name, path.display().to_string());
+                }
+                Ok(Repacked::Unchanged) => {
+                    downloads.add(Status::Skipped, &entry.name, "unchanged".into());
+                }
+                Err(why) => {
+                    error!("failed to repack {}: {}", &entry.name, why);
+                    downloads.fail(
+                        &entry.name,
+                        &entry.defined,
+                        repack_origin(entry),
+                        why.kind(),
+                        &why,
+                    );
+                }
+            }
+
+            downloaded += 1;
+            if downloaded == packages.len() {
+                return downloads;
+            }
+        }
+    }
+
+    let units = sources.build_units();
+    if !units.is_empty() {
+        let selected = units.iter().filter(|s| {
+            packages.contains(&s.name.as_str()) || packages.contains(&s.base_name())
+        });
+
+        for source in selected {
+            match sources::download(engine, source, offline) {
+                Ok(()) => downloads.add(Status::Fetched, &source.name, String::new()),
+                Err(why) => {
+                    error!("failed to download source {}: {}", &source.name, why);
+                    downloads.fail(
+                        &source.name,
+                        &source.defined,
+                        source_origin(source),
+                        why.kind(),
+                        &why,
+                    );
+                }
+            }
+        }
+    }
+
+    downloads
+}
+
+/// Records that the pool files of a direct entry were downloaded for it, so that they can be
+/// verified against its checksums when the archive is published.
+fn record_origins(provenance: &mut Provenance, item: &Direct, branch: &str) {
+    match direct::files(item, branch) {
+        Ok(files) => for (file_item, path) in files {
+            if let Ok(file) = path.strip_prefix("repo") {
+                provenance.insert(
+                    file.to_string_lossy().into_owned(),
+                    Origin { entry: item.name.clone(), url: file_item.url.clone() }
+                );
+            }
+        },
+        Err(why) => warn!("failed to record the pool files of {}: {}", item.name, why),
+    }
+}
+
+/// Records the provenance of the files which the current run downloaded for the entries of the
+/// config in the state database.
+pub fn record_provenance(config: &Config) {
+    let run = match run::current() {
+        Some(run) => run,
+        None => return,
+    };
+
+    let origins = Provenance::open(Path::new(PROVENANCE));
+    let records: Vec<FileProvenance> = run.published().iter()
+        .filter_map(|path| {
+            let origin = origins.origin(&provenance::pool_path(path)?)?;
+            let (kind, config_hash, url) = entry_origin(config, origin)?;
+            let record = provenance::placed(path, &config.archive, kind)?;
+            Some(FileProvenance {
+                source: Some(origin.entry.clone()),
+                config_hash,
+                origin: Some(url),
+                ..record
+            })
+        })
+        .collect();
+
+    if records.is_empty() {
+        return;
+    }
+
+    let recorded = State::open(Path::new(".")).and_then(|mut state| state.add_provenance(&records));
+    if let Err(why) = recorded {
+        warn!("failed to record the provenance of {} downloaded files: {}", records.len(), why);
+    }
+}
+
+/// The kind of the entry of the config which a file was downloaded for, the hash of the entry,
+/// and the URL which the file was downloaded from, with the variables of direct entries replaced.
+fn entry_origin(config: &Config, origin: &Origin) -> Option<(OriginKind, Option<String>, String)> {
+    let name = origin.entry.as_str();
+    if let Some(item) = config.direct.iter().flat_map(|x| x).find(|x| x.name == name) {
+        let url = item.urls.iter()
+            .find(|file_item| file_item.url == origin.url)
+            .and_then(|file_item| direct::locate(item, file_item, &config.archive).ok())
+            .map_or_else(|| origin.url.clone(), |(url, _)| url);
+        return Some((OriginKind::Direct, provenance::config_hash(item), url));
+    }
+
+    let mirror = config.mirror.iter().flat_map(|x| x).find(|x| x.name == name)
+        .map(|entry| (OriginKind::Mirror, provenance::config_hash(entry)));
+    let ppa = || config.ppa.iter().flat_map(|x| x).find(|x| x.name == name)
+        .map(|entry| (OriginKind::Ppa, provenance::config_hash(entry)));
+    let repack = || config.repack.iter().flat_map(|x| x).find(|x| x.name == name)
+        .map(|entry| (OriginKind::Repack, provenance::config_hash(entry)));
+
+    mirror.or_else(ppa).or_else(repack).map(|(kind, hash)| (kind, hash, origin.url.clone()))
+}
+
+fn save_provenance(provenance: &Provenance) {
+    if let Err(why) = provenance.save(Path::new(PROVENANCE)) {
+        warn!("failed to record the origins of pool files in {}: {}", PROVENANCE, why);
+    }
+}
+
+/// The status of each package which was downloaded, and why those which failed did.
+#[derive(Debug, Default)]
+pub struct Downloads {
+    pub summary: Summary,
+    pub failures: DownloadFailures,
+}
+
+impl Downloads {
+    fn add(&mut self, status: Status, name: &str, detail: String) {
+        self.summary.add(status, name, detail);
+    }
+
+    fn fail(
+        &mut self,
+        name: &str,
+        defined: &Definition,
+        origin: String,
+        kind: &'static str,
+        why: &fmt::Display,
+    ) {
+        self.summary.add(Status::Failed, name, kind.to_owned());
+        self.failures.0.push(DownloadFailure {
+            name: name.to_owned(),
+            defined: defined.clone(),
+            dependents: Vec::new(),
+            origin,
+            kind,
+            why: why.to_string(),
+        });
+    }
+}
+
+/// A package which failed to download.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownloadFailure {
+    pub name: String,
+    /// Where the package is defined in the config.
+    pub defined: Definition,
+    /// The configured sources which depend on the package.
+    pub dependents: Vec<String>,
+    /// What the package is downloaded from, such as a URL.
+    pub origin: String,
+    /// What kind of failure it was, such as `network` or `checksum`.
+    pub kind: &'static str,
+    pub why: String,
+}
+
+/// The packages which failed to download, which are listed together by the kind of failure.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DownloadFailures(pub Vec<DownloadFailure>);
+
+impl DownloadFailures {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Lists the configured sources which depend on each package that failed, which cannot be
+    /// built until it is downloaded.
+    pub fn find_dependents(&mut self, config: &Config) {
+        let sources = config.source.as_ref().map_or(&[][..], |sources| sources.as_slice());
+        for failure in &mut self.0 {
+            failure.dependents = sources.iter()
+                .filter(|source| {
+                    source.depends.as_ref().map_or(false, |depends| depends.contains(&failure.name))
+                })
+                .map(|source| source.name.clone())
+                .collect();
+        }
+    }
+}
+
+impl fmt::Display for DownloadFailures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut kinds: BTreeMap<&str, Vec<&DownloadFailure>> = BTreeMap::new();
+        for failure in &self.0 {
+            kinds.entry(failure.kind).or_insert_with(Vec::new).push(failure);
+        }
+
+        write!(f, "{} packages failed to download:", self.0.len())?;
+        for (kind, failures) in kinds {
+            write!(f, "\n  {}:", kind)?;
+            for failure in failures {
+                if failure.origin.is_empty() {
+                    write!(f, "\n    {}: {}", failure.name, failure.why)?;
+                } else {
+                    write!(f, "\n    {} ({}): {}", failure.name, failure.origin, failure.why)?;
+                }
+
+                match (failure.defined.path.is_empty(), failure.dependents.is_empty()) {
+                    (true, true) => (),
+                    (false, true) => write!(f, "\n      defined at {}", failure.defined)?,
+                    (true, false) => {
+                        write!(f, "\n      needed by {}", failure.dependents.join(", "))?
+                    }
+                    (false, false) => write!(
+                        f,
+                        "\n      defined at {}, and needed by {}",
+                        failure.defined,
+                        failure.dependents.join(", ")
+                    )?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn direct_origin(item: &Direct) -> String {
+    if item.snapshot.is_some() {
+        return "snapshot.debian.org".to_owned();
+    }
+
+    item.urls.iter().map(|file| file.url.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+fn repack_origin(item: &Repack) -> String {
+    match (item.url.as_ref(), item.path.as_ref()) {
+        (Some(url), _) => url.clone(),
+        (None, Some(path)) => path.display().to_string(),
+        (None, None) => String::new(),
+    }
+}
+
+fn source_origin(item: &Source) -> String {
+    fetcher::of(item).map_or_else(String::new, |fetcher| fetcher.origin())
 }
 
 #[derive(Debug, Fail)]
 pub enum DownloadError {
     #[fail(display = "failed to open file at {:?}: {}", file, why)]
     Open { file: PathBuf, why: io::Error },
-    #[fail(display = "checksum for {} is invalid -- expected {}, but received {}", name, expected, received)]
-    ChecksumInvalid { name: String, expected: String, received: String },
-    #[fail(display = "{} command failed to execute: {}", cmd, why)]
-    CommandFailed { cmd: &'static str, why: io::Error },
-    #[fail(display = "git exited with an error on job {}", name)]
-    GitFailed { name: String },
+    #[fail(display = "checksum for {} is invalid -- expected {}, but received {}", item, expected,
+           found)]
+    ChecksumMismatch { item: String, expected: String, found: String },
+    #[fail(display = "git exited with an error on job {}: {}", name, why)]
+    GitFailed { name: String, why: CommandError },
+    #[fail(display = "failed to open the git mirror at {:?}: {}", path, why)]
+    Mirror { path: PathBuf, why: io::Error },
     #[fail(display = "failed to request data for {}: {}", name, why)]
-    Request { name: String, why: reqwest::Error }
+    Request { name: String, why: reqwest::Error },
+    #[fail(display = "failed to download {}: {}", url, why)]
+    Transfer { url: String, why: io::Error },
+    #[fail(display = "failed to download {}: the server responded with {}", url, status)]
+    Status { url: String, status: reqwest::StatusCode },
+    #[fail(display = "{} has an invalid URL: {}", name, why)]
+    Url { name: String, why: io::Error },
+    #[fail(display = "failed to download {} from snapshot.debian.org: {}", name, why)]
+    Snapshot { name: String, why: SnapshotError },
+    #[fail(display = "{} needs to download {}, but downloads are offline", name, what)]
+    Offline { name: String, what: String },
+    #[fail(display = "failed to fetch {}: {}", name, why)]
+    Command { name: String, why: CommandError },
+    #[fail(display = "the command which fetches {} did not write {:?}", name, file)]
+    NotWritten { name: String, file: PathBuf },
+    #[fail(display = "{} was not fetched, because the run was interrupted", name)]
+    Interrupted { name: String },
+}
+
+impl Retryable for DownloadError {
+    fn is_retryable(&self) -> bool {
+        match *self {
+            DownloadError::Request { ref why, .. } => why.is_retryable(),
+            DownloadError::Transfer { ref why, .. } => why.is_retryable(),
+            DownloadError::Status { status, .. } => status.is_server_error(),
+            DownloadError::GitFailed { ref why, .. } | DownloadError::Command { ref why, .. } => {
+                why.is_retryable()
+            }
+            DownloadError::Open { .. }
+            | DownloadError::ChecksumMismatch { .. }
+            | DownloadError::Mirror { .. }
+            | DownloadError::Url { .. }
+            | DownloadError::Snapshot { .. }
+            | DownloadError::Offline { .. }
+            | DownloadError::NotWritten { .. }
+            | DownloadError::Interrupted { .. } => false,
+        }
+    }
+}
+
+impl DownloadError {
+    /// What kind of failure this is, for the report of failed downloads.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            DownloadError::Open { .. } | DownloadError::Mirror { .. } => "file",
+            DownloadError::ChecksumMismatch { .. } => "checksum",
+            DownloadError::Request { .. }
+            | DownloadError::Transfer { .. }
+            | DownloadError::Status { .. }
+            | DownloadError::Snapshot { .. } => "network",
+            DownloadError::Url { .. } => "config",
+            DownloadError::Offline { .. } => "offline",
+            DownloadError::GitFailed { .. }
+            | DownloadError::Command { .. }
+            | DownloadError::NotWritten { .. } => "command",
+            DownloadError::Interrupted { .. } => "interrupted",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::Failure;
+    use toml;
+
+    #[test]
+    fn failures_are_grouped_by_kind() {
+        let failure = |name: &str, origin: &str, kind, why: &str| DownloadFailure {
+            name: name.into(),
+            defined: Definition::default(),
+            dependents: Vec::new(),
+            origin: origin.into(),
+            kind,
+            why: why.into(),
+        };
+
+        let mut failures = DownloadFailures(vec![
+            failure("pop-shop", "https://example.com/shop.tar.xz", "network", "timed out"),
+            failure("pop-icons", "", "checksum", "checksum for pop-icons is invalid"),
+            failure("linux", "https://example.com/linux.git", "network", "connection refused"),
+        ]);
+
+        assert_eq!(
+            failures.to_string(),
+            "3 packages failed to download:\n  \
+             checksum:\n    \
+             pop-icons: checksum for pop-icons is invalid\n  \
+             network:\n    \
+             pop-shop (https://example.com/shop.tar.xz): timed out\n    \
+             linux (https://example.com/linux.git): connection refused"
+        );
+
+        let config: Config = toml::from_str(
+            r#"archive = "bionic"
+version = "18.04"
+origin = "pop-os-staging-ubuntu"
+label = "Pop!_OS Staging"
+email = "debrepbuild@example.com"
+
+[[source]]
+name = "pop-desktop"
+depends = ["pop-icons", "pop-shop"]
+
+[[source]]
+name = "pop-theme"
+depends = ["pop-icons"]
+"#,
+        ).unwrap();
+
+        failures.0[1].defined = Definition {
+            file: "sources.toml",
+            path: "direct[2]".into(),
+            line: Some(40),
+        };
+        failures.find_dependents(&config);
+        assert_eq!(failures.0[2].dependents, Vec::<String>::new());
+        assert_eq!(
+            failures.to_string().lines().take(4).collect::<Vec<_>>().join("\n"),
+            "3 packages failed to download:\n  \
+             checksum:\n    \
+             pop-icons: checksum for pop-icons is invalid\n      \
+             defined at sources.toml:40 (direct[2]), and needed by pop-desktop, pop-theme"
+        );
+    }
+
+    #[test]
+    fn transient_download_errors_are_retryable() {
+        let git = |stderr: &str| {
+            let mut why = CommandError::new("git", &["pull"], Failure::Exit(1));
+            why.stderr = stderr.to_owned();
+            DownloadError::GitFailed { name: "pop-shop".into(), why }
+        };
+
+        assert!(git("fatal: The remote end hung up: Connection reset by peer").is_retryable());
+        assert!(!git("fatal: couldn't find remote ref master").is_retryable());
+
+        let checksum = DownloadError::ChecksumMismatch {
+            item: "pop-shop".into(),
+            expected: "0".into(),
+            found: "1".into(),
+        };
+        assert!(!checksum.is_retryable());
+
+        let offline = DownloadError::Offline { name: "pop-shop".into(), what: "x".into() };
+        assert!(!offline.is_retryable());
+    }
 }
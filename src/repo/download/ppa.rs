@@ -0,0 +1,50 @@
+use config::{parse_ppa, Key, Mirror, Ppa};
+use repo::keys;
+use repo::provenance::Provenance;
+use reqwest::Client;
+use std::path::Path;
+use super::engine::Engine;
+use super::mirror::{self, MirrorError};
+
+/// Where the packages of PPAs are downloaded from.
+pub const HOST: &str = "https://ppa.launchpadcontent.net/";
+
+/// Where the keys of PPAs are received from.
+const KEYSERVER: &str = "hkps://keyserver.ubuntu.com";
+
+/// Copies the packages of the PPA into the pool, verifying its indices with its pinned key.
+pub fn download(
+    client: &Client,
+    engine: &Engine,
+    archive: &str,
+    ppa: &Ppa,
+    provenance: &mut Provenance,
+) -> Result<u64, MirrorError> {
+    let (user, name) = parse_ppa(&ppa.ppa)
+        .ok_or_else(|| MirrorError::Ppa { ppa: ppa.ppa.clone() })?;
+
+    let key = Key {
+        name: ["ppa-", user, "-", name].concat(),
+        fingerprint: ppa.fingerprint.clone(),
+        keyserver: Some(KEYSERVER.to_owned()),
+        url: None,
+    };
+
+    let keyring = keys::resolve_key(Path::new("."), &key)
+        .map_err(|why| MirrorError::Key { mirror: ppa.name.clone(), why })?;
+
+    let mirror = Mirror {
+        name: ppa.name.clone(),
+        url: [HOST, user, "/", name, "/ubuntu"].concat(),
+        suite: ppa.series.clone(),
+        component: "main".to_owned(),
+        key: key.name,
+        architectures: ppa.architectures.clone(),
+        packages: ppa.packages.clone(),
+        binaries: ppa.binaries,
+        sources: ppa.sources,
+        defined: ppa.defined.clone(),
+    };
+
+    mirror::copy(client, engine, archive, &mirror, &keyring, provenance)
+}
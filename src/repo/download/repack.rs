@@ -0,0 +1,374 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use command::Cmd;
+use config::Repack;
+use misc;
+use repo::checksum::sha2_256_digest;
+use repo::provenance::{Origin, Provenance};
+use repo::run;
+use repo::version;
+use sha2::{Digest, Sha256};
+use tempdir::TempDir;
+use walkdir::WalkDir;
+use super::{request, DownloadError};
+use super::engine::Engine;
+use PACKAGE_ASSETS;
+
+/// Records the modifications which each repacked package was last built with, and its pool file.
+pub const REPACKS: &str = "record/.repack";
+
+#[derive(Debug, Fail)]
+pub enum RepackError {
+    #[fail(display = "failed to fetch the upstream package of {}: {}", name, why)]
+    Fetch { name: String, why: io::Error },
+    #[fail(display = "failed to download the upstream package of {}: {}", name, why)]
+    Download { name: String, why: DownloadError },
+    #[fail(display = "the upstream package of {} does not have the checksum {}; found {}", name,
+           expected, found)]
+    Checksum { name: String, expected: String, found: String },
+    #[fail(display = "failed to unpack the upstream package of {}: {}", name, why)]
+    Unpack { name: String, why: io::Error },
+    #[fail(display = "failed to add {:?} to {}: {}", file, name, why)]
+    File { name: String, file: PathBuf, why: io::Error },
+    #[fail(display = "the upstream package of {} has no {} field", name, field)]
+    MissingField { name: String, field: &'static str },
+    #[fail(display = "{} is not a valid version for {}", version, name)]
+    Version { name: String, version: String },
+    #[fail(display = "failed to build the repacked package of {}: {}", name, why)]
+    Build { name: String, why: io::Error },
+    #[fail(display = "failed to record the repacked package of {}: {}", name, why)]
+    Record { name: String, why: io::Error },
+    #[fail(display = "{} needs to download {}, but downloads are offline", name, url)]
+    Offline { name: String, url: String },
+}
+
+impl RepackError {
+    /// What kind of failure this is, for the report of failed downloads.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            RepackError::Fetch { .. } => "network",
+            RepackError::Download { ref why, .. } => why.kind(),
+            RepackError::Checksum { .. } => "checksum",
+            RepackError::MissingField { .. } | RepackError::Version { .. } => "config",
+            RepackError::Unpack { .. } | RepackError::Build { .. } => "command",
+            RepackError::File { .. } | RepackError::Record { .. } => "file",
+            RepackError::Offline { .. } => "offline",
+        }
+    }
+}
+
+/// Whether a package was repacked, or its modifications were already applied to its input.
+#[derive(Debug, PartialEq)]
+pub enum Repacked {
+    Repacked(PathBuf),
+    Unchanged,
+}
+
+/// Unpacks the upstream package, applies the modifications of the entry to it, and moves the
+/// rebuilt package into the pool. The package is only rebuilt when its input or modifications
+/// have changed since it was last repacked. When `offline`, a downloaded package must be cached.
+pub fn repack(
+    engine: &Engine,
+    archive: &str,
+    item: &Repack,
+    provenance: &mut Provenance,
+    offline: bool,
+) -> Result<Repacked, RepackError> {
+    let name = || item.name.clone();
+    let (deb, origin) = fetch(engine, item, offline)?;
+    let key = modification_key(item).map_err(|why| RepackError::File {
+        name: name(),
+        file: Path::new(PACKAGE_ASSETS).join(&item.name),
+        why
+    })?;
+
+    let mut records = read_records(Path::new(REPACKS));
+    let previous = records.get(&item.name).cloned();
+    if let Some((ref recorded, ref file)) = previous {
+        if recorded == &key && Path::new("repo").join(file).exists() {
+            info!("{} is already repacked with its modifications", item.name);
+            return Ok(Repacked::Unchanged);
+        }
+    }
+
+    let tempdir = TempDir::new("debrep-repack")
+        .map_err(|why| RepackError::Unpack { name: name(), why })?;
+    let root = tempdir.path().join("root");
+    Cmd::new("dpkg-deb").arg("-R").arg(&deb).arg(&root).run()
+        .map_err(|why| RepackError::Unpack { name: name(), why: why.into() })?;
+
+    for file in item.files.iter().flat_map(|files| files.iter()) {
+        let dst = root.join(file.dst.strip_prefix("/").unwrap_or(&file.dst));
+        let src = Path::new(PACKAGE_ASSETS).join(&item.name).join(&file.src);
+        let file_error = |why| RepackError::File { name: name(), file: file.dst.clone(), why };
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).map_err(&file_error)?;
+        }
+
+        fs::copy(&src, &dst).map_err(&file_error)?;
+    }
+
+    if item.files.as_ref().map_or(false, |files| !files.is_empty()) {
+        update_md5sums(&root).map_err(|why| RepackError::Build { name: name(), why })?;
+    }
+
+    let control_path = root.join("DEBIAN/control");
+    let mut control = misc::read_to_string(&control_path)
+        .map_err(|why| RepackError::Unpack { name: name(), why })?;
+
+    for (field, value) in item.control.iter().flat_map(|control| control.iter()) {
+        control = set_field(&control, field, value);
+    }
+
+    let package = required_field(&control, "Package", item)?;
+    let architecture = required_field(&control, "Architecture", item)?;
+    let upstream = required_field(&control, "Version", item)?;
+    let version = [upstream.as_str(), &item.version_suffix].concat();
+    if !version::is_valid(&version) {
+        return Err(RepackError::Version { name: name(), version });
+    }
+
+    control = set_field(&control, "Version", &version);
+    misc::write(&control_path, &control).map_err(|why| RepackError::Build { name: name(), why })?;
+
+    let file = pool_file(archive, &package, &version, &architecture);
+    let destination = Path::new("repo").join(&file);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent).map_err(|why| RepackError::Build { name: name(), why })?;
+    }
+
+    info!("repacking {} as {} {}", item.name, package, version);
+    Cmd::new("dpkg-deb")
+        .args(&["--root-owner-group", "--build"])
+        .arg(&root)
+        .arg(&destination)
+        .run()
+        .map_err(|why| RepackError::Build { name: name(), why: why.into() })?;
+
+    if let Some((_, ref old)) = previous {
+        if old != &file {
+            match fs::remove_file(Path::new("repo").join(old)) {
+                Err(ref why) if why.kind() != io::ErrorKind::NotFound => {
+                    warn!("failed to remove {}, which {} was repacked as: {}", old, item.name, why);
+                }
+                _ => (),
+            }
+            provenance.remove(old);
+        }
+    }
+
+    records.insert(item.name.clone(), (key, file.clone()));
+    write_records(Path::new(REPACKS), &records)
+        .map_err(|why| RepackError::Record { name: name(), why })?;
+    provenance.insert(file, Origin { entry: item.name.clone(), url: origin });
+
+    if let Some(run) = run::current() {
+        if let Ok(path) = destination.canonicalize() {
+            run.add_published(path);
+        }
+    }
+
+    Ok(Repacked::Repacked(destination))
+}
+
+/// Fetches the upstream package into the cache, or verifies the local package. Returns the path
+/// of the package, and where it came from.
+fn fetch(
+    engine: &Engine,
+    item: &Repack,
+    offline: bool,
+) -> Result<(PathBuf, String), RepackError> {
+    let fetch_error = |why| RepackError::Fetch { name: item.name.clone(), why };
+    let (deb, origin) = match (item.url.as_ref(), item.path.as_ref()) {
+        (Some(url), _) => {
+            let filename = &url[url.rfind('/').map_or(0, |x| x + 1)..];
+            let cached = PathBuf::from(["assets/cache/", &item.name, "_", filename].concat());
+            if offline {
+                if !cached.exists() {
+                    return Err(RepackError::Offline { name: item.name.clone(), url: url.clone() });
+                }
+            } else {
+                request::file(engine, url, Some(&item.checksum), &cached)
+                    .map_err(|why| RepackError::Download { name: item.name.clone(), why })?;
+            }
+
+            (cached, url.clone())
+        }
+        (None, Some(path)) => (path.clone(), path.to_string_lossy().into_owned()),
+        (None, None) => {
+            let why = io::Error::new(io::ErrorKind::NotFound, "no url or path is defined");
+            return Err(fetch_error(why));
+        }
+    };
+
+    let found = File::open(&deb).and_then(sha2_256_digest).map_err(&fetch_error)?;
+    if found != item.checksum {
+        return Err(RepackError::Checksum {
+            name: item.name.clone(),
+            expected: item.checksum.clone(),
+            found,
+        });
+    }
+
+    Ok((deb, origin))
+}
+
+/// Digests the checksum of the input with the modifications, including the contents of the
+/// files which are added to the package.
+fn modification_key(item: &Repack) -> io::Result<String> {
+    let mut modifications = String::new();
+    let _ = writeln!(modifications, "{}\n{}", item.checksum, item.version_suffix);
+    for (field, value) in item.control.iter().flat_map(|control| control.iter()) {
+        let _ = writeln!(modifications, "{}: {}", field, value);
+    }
+
+    for file in item.files.iter().flat_map(|files| files.iter()) {
+        let src = Path::new(PACKAGE_ASSETS).join(&item.name).join(&file.src);
+        let digest = sha2_256_digest(File::open(&src)?)?;
+        let _ = writeln!(modifications, "{} {}", digest, file.dst.display());
+    }
+
+    let mut hasher = Sha256::default();
+    hasher.input(modifications.as_bytes());
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Rewrites `DEBIAN/md5sums` with the files of the package, so that added files are listed.
+fn update_md5sums(root: &Path) -> io::Result<()> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(root).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        if entry.file_type().is_file() && !path.starts_with("DEBIAN") {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    let output = Cmd::new("md5sum").args(&files).current_dir(root).output()?;
+    misc::write(root.join("DEBIAN/md5sums"), &output.stdout)
+}
+
+fn required_field(
+    control: &str,
+    field: &'static str,
+    item: &Repack,
+) -> Result<String, RepackError> {
+    misc::control_field(control, field)
+        .ok_or_else(|| RepackError::MissingField { name: item.name.clone(), field })
+}
+
+/// Sets a field of a control file, replacing its value and continuation lines, or appending
+/// it when the control file does not have it.
+fn set_field(control: &str, field: &str, value: &str) -> String {
+    let mut output = String::with_capacity(control.len());
+    let mut replaced = false;
+    let mut skipping = false;
+    for line in control.lines() {
+        if skipping && (line.starts_with(' ') || line.starts_with('\t')) {
+            continue;
+        }
+
+        skipping = false;
+        let matches = line.find(':').map_or(false, |pos| line[..pos].eq_ignore_ascii_case(field));
+        if matches && !line.starts_with(' ') {
+            let name = &line[..line.find(':').unwrap_or(0)];
+            let _ = writeln!(output, "{}: {}", name, value);
+            replaced = true;
+            skipping = true;
+        } else if !line.is_empty() {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    if !replaced {
+        let _ = writeln!(output, "{}: {}", field, value);
+    }
+
+    output
+}
+
+/// Where the repacked package is stored, relative to `repo/`.
+fn pool_file(archive: &str, package: &str, version: &str, architecture: &str) -> String {
+    // The epoch is not a part of the file name.
+    let version = version.find(':').map_or(version, |pos| &version[pos + 1..]);
+    [
+        "pool/", archive, "/main/binary-", architecture, "/", &package[0..1], "/", package, "/",
+        package, "_", version, "_", architecture, ".deb"
+    ].concat()
+}
+
+/// The key and pool file of each repacked package, by the name of its entry.
+fn read_records(path: &Path) -> BTreeMap<String, (String, String)> {
+    let mut records = BTreeMap::new();
+    if let Ok(data) = misc::read_to_string(path) {
+        for line in data.lines() {
+            let mut fields = line.splitn(3, '\t');
+            if let (Some(name), Some(key), Some(file)) =
+                (fields.next(), fields.next(), fields.next())
+            {
+                records.insert(name.to_owned(), (key.to_owned(), file.to_owned()));
+            }
+        }
+    }
+
+    records
+}
+
+fn write_records(path: &Path, records: &BTreeMap<String, (String, String)>) -> io::Result<()> {
+    let mut data = String::new();
+    for (name, &(ref key, ref file)) in records {
+        let _ = writeln!(data, "{}\t{}\t{}", name, key, file);
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    misc::write(path, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTROL: &str = "Package: libfoo1\nVersion: 1:1.2-3\nArchitecture: amd64\n\
+                           Depends: libbar (>= 2.0),\n libbaz (= 1.2-3)\nDescription: foo\n \
+                           A library.\n";
+
+    #[test]
+    fn control_fields_are_set() {
+        assert_eq!(misc::control_field(CONTROL, "version"), Some("1:1.2-3".to_owned()));
+        assert_eq!(misc::control_field(CONTROL, "Maintainer"), None);
+
+        let control = set_field(CONTROL, "depends", "libbar (>= 1.0)");
+        assert_eq!(misc::control_field(&control, "Depends"), Some("libbar (>= 1.0)".to_owned()));
+        assert!(!control.contains("libbaz"));
+        assert!(control.ends_with("Description: foo\n A library.\n"));
+
+        let control = set_field(&control, "Maintainer", "Pop <pop@example.com>");
+        assert!(control.ends_with(" A library.\nMaintainer: Pop <pop@example.com>\n"));
+    }
+
+    #[test]
+    fn pool_files_omit_the_epoch() {
+        assert_eq!(
+            pool_file("bionic", "libfoo1", "1:1.2-3+pop1", "amd64"),
+            "pool/bionic/main/binary-amd64/l/libfoo1/libfoo1_1.2-3+pop1_amd64.deb"
+        );
+    }
+
+    #[test]
+    fn records_are_read_back() {
+        let dir = TempDir::new("debrep-repack").unwrap();
+        let path = dir.path().join("record/.repack");
+        let mut records = BTreeMap::new();
+        records.insert("libfoo1".to_owned(), ("abc".to_owned(), "pool/libfoo1.deb".to_owned()));
+        write_records(&path, &records).unwrap();
+        assert_eq!(read_records(&path), records);
+    }
+}
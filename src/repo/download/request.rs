@@ -1,51 +1,810 @@
 use std::{fs, io};
-use std::fs::File;
-use std::path::Path;
-use super::checksum::sha2_256_digest;
-use reqwest::Client;
-
-pub fn file(client: &Client, url: &str, checksum: Option<&str>, path: &Path) -> io::Result<u64> {
-    let mut file = if path.exists() {
-        if let Some(checksum) = checksum {
-            let digest = sha2_256_digest(File::open(path)?)?;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use interrupt;
+use output::format_size;
+use repo::checksum::sha2_256_digest;
+use retry;
+use reqwest::{Client, StatusCode};
+use super::DownloadError;
+use super::engine::{Blocking, Engine};
+use reqwest::header::{ContentLength, ContentRangeSpec};
+use sha2::{Digest, Sha256};
+
+/// The most bytes of a download which are held in memory at once, per connection.
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+/// How often the progress of a download is logged.
+const PROGRESS_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// A file which is being downloaded next to its destination, and is removed unless it is
+/// completed, so that files at their destinations are always complete. Partial files which
+/// downloads may resume are kept, unless they are empty, or were discarded.
+pub struct Partial {
+    path: PathBuf,
+    destination: PathBuf,
+    completed: bool,
+    resumable: bool,
+}
+
+impl Partial {
+    /// Reserves the partial file of the destination, for a command to write to.
+    pub fn new(destination: &Path) -> io::Result<Partial> {
+        if let Some(parent) = destination.parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut path = destination.as_os_str().to_owned();
+        path.push(".partial");
+        Ok(Partial {
+            path: PathBuf::from(path),
+            destination: destination.to_path_buf(),
+            completed: false,
+            resumable: false,
+        })
+    }
+
+    /// Opens the partial file of the destination to append to what an earlier download left in
+    /// it, returning how many bytes that was.
+    pub fn resume(destination: &Path) -> io::Result<(Partial, File, u64)> {
+        let mut partial = Partial::new(destination)?;
+        let file = OpenOptions::new().create(true).append(true).open(&partial.path)?;
+        let offset = file.metadata()?.len();
+        partial.resumable = true;
+        Ok((partial, file, offset))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes the file, as what it holds is not part of its destination.
+    pub fn discard(mut self) {
+        self.resumable = false;
+    }
+
+    /// Moves the file to its destination.
+    pub fn complete(mut self) -> io::Result<()> {
+        fs::rename(&self.path, &self.destination)?;
+        self.completed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+
+        let empty = fs::metadata(&self.path).map(|meta| meta.len() == 0).unwrap_or(true);
+        if !self.resumable || empty {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// How the response to a request of a file from an `offset` on is written to its partial file.
+#[derive(Debug, PartialEq)]
+enum Resume {
+    /// The whole file is written over what the partial file holds.
+    Whole,
+    /// The rest of the file is appended to it.
+    Append,
+    /// The partial file already holds the whole file.
+    Complete,
+    /// What the partial file holds is not the start of the file, such as when it is longer than
+    /// the file, and the file is downloaded again.
+    Discard,
+}
+
+/// How the response, of the status and `Content-Range`, is written to the partial file which
+/// holds `offset` bytes. Servers which do not support ranges send the whole file.
+fn resume(offset: u64, status: StatusCode, range: Option<&ContentRangeSpec>) -> Resume {
+    if offset == 0 {
+        return Resume::Whole;
+    }
+
+    match (status, range) {
+        (StatusCode::PartialContent, Some(&ContentRangeSpec::Bytes { range, .. })) => {
+            match range {
+                Some((start, _)) if start == offset => Resume::Append,
+                _ => Resume::Discard,
+            }
+        }
+        (StatusCode::PartialContent, _) => Resume::Discard,
+        (StatusCode::RangeNotSatisfiable, Some(&ContentRangeSpec::Bytes { instance_length, .. }))
+            if instance_length == Some(offset) => Resume::Complete,
+        (StatusCode::RangeNotSatisfiable, _) => Resume::Discard,
+        _ => Resume::Whole,
+    }
+}
+
+/// Copies the body to the file through a buffer of `BUFFER_SIZE`, hashing it as it is written,
+/// so that downloads are never held in memory, nor read again to be verified. `progress` is told
+/// of the bytes which were downloaded so far after each write. Returns the bytes which were
+/// copied, and their SHA256 digest.
+pub fn stream<R: Read, W: Write>(
+    body: &mut R,
+    file: &mut W,
+    progress: &mut FnMut(u64),
+) -> io::Result<(u64, String)> {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut hasher = Sha256::default();
+    let mut copied = 0;
+    loop {
+        if interrupt::is_interrupted() {
+            return Err(interrupt::error());
+        }
+
+        let read = match body.read(&mut buffer) {
+            Ok(0) => return Ok((copied, format!("{:x}", hasher.result()))),
+            Ok(read) => read,
+            Err(ref why) if why.kind() == io::ErrorKind::Interrupted => continue,
+            Err(why) => return Err(why),
+        };
+
+        hasher.input(&buffer[..read]);
+        file.write_all(&buffer[..read])?;
+        copied += read as u64;
+        progress(copied);
+    }
+}
+
+/// A file to download to its path, which is verified with its SHA256 checksum if one is known.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub url: String,
+    pub checksum: Option<String>,
+    pub path: PathBuf,
+}
+
+impl Request {
+    pub fn new(url: &str, checksum: Option<&str>, path: &Path) -> Request {
+        Request {
+            url: url.to_owned(),
+            checksum: checksum.map(|x| x.to_owned()),
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn checksum(&self) -> Option<&str> {
+        self.checksum.as_ref().map(|x| x.as_str())
+    }
+}
+
+/// Downloads the file with the engine, retrying when the request fails for a reason which may
+/// not last.
+pub fn file(
+    engine: &Engine,
+    url: &str,
+    checksum: Option<&str>,
+    path: &Path,
+) -> Result<u64, DownloadError> {
+    engine.files(&[Request::new(url, checksum, path)]).remove(0)
+}
+
+/// Downloads the file with the blocking engine, on this thread.
+pub fn download(engine: &Blocking, request: &Request) -> Result<u64, DownloadError> {
+    if downloaded(request)? {
+        return Ok(0);
+    }
+
+    retry::network(&request.url, || match fetch(engine, request, true)? {
+        Some(downloaded) => Ok(downloaded),
+        None => fetch(engine, request, false).map(|x| x.unwrap_or(0)),
+    })
+}
+
+/// Whether the file is already at its path with its checksum. A file which does not match its
+/// checksum is removed, to be downloaded again.
+pub fn downloaded(request: &Request) -> Result<bool, DownloadError> {
+    if interrupt::is_interrupted() {
+        return Err(DownloadError::Interrupted { name: request.url.clone() });
+    }
+
+    let path = &request.path;
+    let transfer_error = |why| DownloadError::Transfer { url: request.url.clone(), why };
+    if path.exists() {
+        if let Some(checksum) = request.checksum() {
+            let digest = File::open(path).and_then(sha2_256_digest).map_err(&transfer_error)?;
             if digest == checksum {
                 info!("{} is already downloaded", path.display());
-                return Ok(0);
+                return Ok(true);
             }
+
+            // A corrupted or truncated file is never left in the pool, even if it cannot be
+            // downloaded again.
+            warn!("{} does not match its checksum, so it is downloaded again", path.display());
+            fs::remove_file(path).map_err(&transfer_error)?;
+        }
+    }
+
+    Ok(false)
+}
+
+/// What the head of a response says of its body.
+pub struct Head {
+    pub status: StatusCode,
+    /// Where the request was redirected to, if it was.
+    pub url: String,
+    pub length: Option<u64>,
+    pub range: Option<ContentRangeSpec>,
+}
+
+/// The partial file of a download, which the body of the response to its request is written to.
+pub struct Download {
+    partial: Partial,
+    pub file: File,
+    /// How much of the file the partial file held before the body.
+    pub offset: u64,
+    location: String,
+    length: Option<u64>,
+}
+
+/// What is left to do once the head of the response to a download was received.
+pub enum Next {
+    /// The download finished, with the bytes which it received, or `None` if what was left of
+    /// an earlier download cannot be resumed, and it must start again.
+    Done(Option<u64>),
+    /// The body is written to the partial file, and then the download is finished with it.
+    Write(Download),
+}
+
+/// Opens the partial file of the download, to resume from what an earlier download which was
+/// cut off left in it if `resumable`, with a `Range` request from its offset. Only files with a
+/// checksum are resumed, since a file which changed on its server after the partial file was
+/// written would be resumed into a mix of both, which only its checksum tells from the file.
+/// The partial files of the others are not kept.
+pub fn open(request: &Request, resumable: bool) -> Result<Download, DownloadError> {
+    let transfer_error = |why| DownloadError::Transfer { url: request.url.clone(), why };
+    let (mut partial, file, mut offset) =
+        Partial::resume(&request.path).map_err(&transfer_error)?;
+    if !resumable || request.checksum.is_none() {
+        partial.resumable = false;
+        if offset != 0 {
+            file.set_len(0).map_err(&transfer_error)?;
+            offset = 0;
         }
+    }
 
-        fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(path)?
+    if offset == 0 {
+        info!("downloading file from {} to {}", request.url, request.path.display());
     } else {
-        if let Some(parent) = path.parent() {
-            if !parent.exists() {
-                fs::create_dir_all(parent)?;
-            }
+        info!("resuming the download of {} from {} after {}", request.path.display(),
+            request.url, format_size(offset));
+    }
+
+    Ok(Download { partial, file, offset, location: String::new(), length: None })
+}
+
+/// Decides what is done with the response to the download, by its head.
+pub fn accept(
+    request: &Request,
+    mut download: Download,
+    head: Head,
+) -> Result<Next, DownloadError> {
+    let url = &request.url;
+    let path = &request.path;
+    let transfer_error = |why| DownloadError::Transfer { url: url.clone(), why };
+    let how = resume(download.offset, head.status, head.range.as_ref());
+
+    match how {
+        Resume::Complete => {
+            // The download was cut off after its last byte, so only the checksum is left.
+            let partial = download.partial;
+            let digest = File::open(partial.path())
+                .and_then(sha2_256_digest)
+                .map_err(&transfer_error)?;
+            let checksum = request.checksum();
+            return match verify(url, &head.url, checksum, None, download.offset, &digest) {
+                Ok(()) => partial.complete().map(|_| Next::Done(Some(0))).map_err(&transfer_error),
+                Err(_) => {
+                    partial.discard();
+                    Ok(Next::Done(None))
+                }
+            };
+        }
+        Resume::Discard => {
+            warn!("{} cannot be resumed from {}, so it is downloaded again", path.display(),
+                format_size(download.offset));
+            download.partial.discard();
+            return Ok(Next::Done(None));
+        }
+        Resume::Whole | Resume::Append => (),
+    }
+
+    // What was downloaded before is kept when the server fails, to be resumed later.
+    if head.status.is_client_error() || head.status.is_server_error() {
+        return Err(DownloadError::Status { url: url.clone(), status: head.status });
+    }
+
+    if how == Resume::Whole && download.offset != 0 {
+        info!("{} sent the whole file, so it is downloaded from the start", url);
+        download.file.set_len(0).map_err(&transfer_error)?;
+        download.offset = 0;
+    }
+
+    download.location = head.url;
+    download.length = head.length;
+    Ok(Next::Write(download))
+}
+
+/// Logs the progress of a download which received `bytes` so far, if it received
+/// `PROGRESS_INTERVAL` since it was last logged.
+pub fn progress(url: &str, bytes: u64, logged: &mut u64) {
+    if bytes - *logged >= PROGRESS_INTERVAL {
+        debug!("downloaded {} MiB from {}", bytes / (1024 * 1024), url);
+        *logged = bytes;
+    }
+}
+
+/// Verifies the download once the body, of which `received` bytes with the SHA256 `digest` were
+/// written, was, and moves it to its path. Returns the bytes which were received, or `None` if
+/// it must start again.
+pub fn finish(
+    request: &Request,
+    download: Download,
+    received: u64,
+    mut digest: String,
+) -> Result<Option<u64>, DownloadError> {
+    let url = &request.url;
+    let transfer_error = |why| DownloadError::Transfer { url: url.clone(), why };
+    let partial = download.partial;
+
+    // Only the rest of a resumed file was hashed as it was received.
+    if download.offset != 0 {
+        digest = download.file.sync_all()
+            .and_then(|_| File::open(partial.path()))
+            .and_then(sha2_256_digest)
+            .map_err(&transfer_error)?;
+    }
+
+    // A partial file which is cut short is kept to be resumed, but one which is of something
+    // else is not.
+    let checksum = request.checksum();
+    match verify(url, &download.location, checksum, download.length, received, &digest) {
+        Ok(()) => partial.complete().map(|_| Some(received)).map_err(&transfer_error),
+        Err(DownloadError::ChecksumMismatch { .. }) if download.offset != 0 => {
+            warn!("{} does not match its checksum after it was resumed", request.path.display());
+            partial.discard();
+            Ok(None)
+        }
+        Err(why @ DownloadError::ChecksumMismatch { .. }) => {
+            partial.discard();
+            Err(why)
         }
-        File::create(path)?
+        Err(why) => Err(why),
+    }
+}
+
+/// Downloads the file into its partial file with the blocking engine, resuming from what an
+/// earlier download left in it if `resumable`. Returns the bytes which this download received,
+/// or `None` if what was left cannot be resumed, and it must start again.
+fn fetch(
+    engine: &Blocking,
+    request: &Request,
+    resumable: bool,
+) -> Result<Option<u64>, DownloadError> {
+    let url = &request.url;
+    let download = open(request, resumable)?;
+    let (head, mut body) = engine.get(url, download.offset)?;
+    let mut download = match accept(request, download, head)? {
+        Next::Done(done) => return Ok(done),
+        Next::Write(download) => download,
     };
 
-    info!("downloading file from {} to {}", url, path.display());
-    let downloaded = client
-        .get(url)
+    let mut logged = 0;
+    let (received, digest) = stream(&mut body, &mut download.file, &mut |bytes| {
+        progress(url, bytes, &mut logged)
+    }).map_err(|why| DownloadError::Transfer { url: url.clone(), why })?;
+
+    finish(request, download, received, digest)
+}
+
+/// Checks that the body which was received from the URL, or from the location which it
+/// redirected to, is as long as its server said, and has the checksum, if one is known.
+fn verify(
+    url: &str,
+    location: &str,
+    checksum: Option<&str>,
+    length: Option<u64>,
+    received: u64,
+    digest: &str,
+) -> Result<(), DownloadError> {
+    let name = if location.is_empty() || location == url {
+        url.to_owned()
+    } else {
+        format!("{} (redirected to {})", url, location)
+    };
+
+    if let Some(length) = length {
+        if received != length {
+            let why = io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("received {} of {} bytes", received, length)
+            );
+            return Err(DownloadError::Transfer { url: name, why });
+        }
+    }
+
+    match checksum {
+        Some(checksum) if digest != checksum => Err(DownloadError::ChecksumMismatch {
+            item: name,
+            expected: checksum.to_owned(),
+            found: digest.to_owned(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// The length of the file at the URL, if its server reports one for a `HEAD` request.
+pub fn content_length(client: &Client, url: &str) -> io::Result<Option<u64>> {
+    let response = client
+        .head(url)
         .send()
-        .map_err(|why| io::Error::new(io::ErrorKind::Other, format!("reqwest get failed: {}", why)))?
-        .copy_to(&mut file)
-        .map_err(|why| io::Error::new(io::ErrorKind::Other, format!("reqwest copy failed: {}", why)))?;
-
-    let digest = sha2_256_digest(File::open(path)?)?;
-    if let Some(checksum) = checksum {
-        if digest == checksum {
-            Ok(downloaded)
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("checksum does not match for {}", path.display())
-            ))
+        .and_then(|response| response.error_for_status())
+        .map_err(|why| {
+            io::Error::new(io::ErrorKind::Other, format!("reqwest head failed: {}", why))
+        })?;
+
+    Ok(response.headers().get::<ContentLength>().map(|length| length.0))
+}
+
+/// Whether the server of the URL answers a `HEAD` request, with any status.
+pub fn reachable(client: &Client, url: &str) -> io::Result<()> {
+    client.head(url).send().map(|_| ()).map_err(|why| {
+        io::Error::new(io::ErrorKind::Other, format!("reqwest head failed: {}", why))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use retry::Retryable;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use super::super::engine;
+    use std::thread;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    /// The size of the generated body, which is far larger than what may be held in memory.
+    const BODY_SIZE: u64 = 256 * 1024 * 1024;
+
+    /// A reader which checks that it is never asked for more than a buffer at once.
+    struct Bounded(TcpStream);
+
+    impl Read for Bounded {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            assert!(buffer.len() <= BUFFER_SIZE, "read into {} bytes", buffer.len());
+            self.0.read(buffer)
         }
-    } else {
-        Ok(downloaded)
+    }
+
+    /// A file which counts what is written to it, and checks that it is written in buffers.
+    #[derive(Default)]
+    struct Sink {
+        written: u64,
+        largest: usize,
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.written += buffer.len() as u64;
+            self.largest = self.largest.max(buffer.len());
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    /// Serves the body at every path which ends with `.deb`, from the offset of the `Range` of
+    /// each request on, as servers which support ranges do, and nothing at any other path.
+    /// Returns the URL of the body.
+    fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let found = line.split_whitespace().nth(1).map_or(false, |x| x.ends_with(".deb"));
+                let mut offset = 0;
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                        break;
+                    }
+
+                    let header = line.trim().to_lowercase();
+                    if header.starts_with("range: bytes=") {
+                        offset = header[13..].trim_right_matches('-').parse().unwrap();
+                    }
+                }
+
+                let length = body.len();
+                let head = if !found {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n".to_owned()
+                } else if offset == 0 {
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", length)
+                } else if offset >= length {
+                    offset = length;
+                    format!(
+                        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n\
+                         Content-Length: 0\r\n",
+                        length
+                    )
+                } else {
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n\
+                         Content-Length: {}\r\n",
+                        offset, length - 1, length, length - offset
+                    )
+                };
+
+                let body = if found { &body[offset..] } else { &[][..] };
+                let _ = stream
+                    .write_all(head.as_bytes())
+                    .and_then(|_| stream.write_all(b"Connection: close\r\n\r\n"))
+                    .and_then(|_| stream.write_all(body));
+            }
+        });
+
+        format!("http://{}/pop-shop_1.0_amd64.deb", address)
+    }
+
+    /// Each engine, which every download test is run with.
+    fn engines() -> Vec<(&'static str, Box<Engine>)> {
+        vec![
+            ("blocking", Box::new(Blocking::new())),
+            ("async", Box::new(engine::Async::new(2).unwrap())),
+        ]
+    }
+
+    #[test]
+    fn bodies_must_match_their_length_and_checksum() {
+        let url = "https://example.com/pop-shop_1.0_amd64.deb";
+        let digest = "0a1b2c";
+        assert!(verify(url, url, Some(digest), Some(1024), 1024, digest).is_ok());
+        assert!(verify(url, "", None, None, 1024, digest).is_ok());
+
+        match verify(url, url, None, Some(1024), 512, digest) {
+            Err(DownloadError::Transfer { ref why, .. }) => {
+                assert_eq!(why.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            other => panic!("expected a truncated transfer, found {:?}", other),
+        }
+
+        // A server which redirects to another artifact is caught by the checksum of the file.
+        let other = "https://mirror.example.com/pop-shop_0.9_amd64.deb";
+        let why = verify(url, other, Some("3d4e5f"), Some(1024), 1024, digest).unwrap_err();
+        match why {
+            DownloadError::ChecksumMismatch { ref expected, ref found, .. } => {
+                assert_eq!((expected.as_str(), found.as_str()), ("3d4e5f", digest))
+            }
+            ref other => panic!("expected a checksum mismatch, found {:?}", other),
+        }
+        assert_eq!(
+            why.to_string(),
+            format!(
+                "checksum for {} (redirected to {}) is invalid -- expected 3d4e5f, but received \
+                 0a1b2c",
+                url, other
+            )
+        );
+    }
+
+    #[test]
+    fn partial_files_are_resumed_from_where_they_were_cut_off() {
+        let dir = ::tempdir::TempDir::new("debrep-request").unwrap();
+        let destination = dir.path().join("pop-shop_1.0_amd64.deb");
+        let partial_path = dir.path().join("pop-shop_1.0_amd64.deb.partial");
+
+        // Empty partial files are not kept, but those which hold some of the file are.
+        drop(Partial::resume(&destination).unwrap());
+        assert!(!partial_path.exists());
+        let (partial, mut file, offset) = Partial::resume(&destination).unwrap();
+        assert_eq!(offset, 0);
+        file.write_all(b"!<arch>\n").unwrap();
+        drop(partial);
+        let (partial, _, offset) = Partial::resume(&destination).unwrap();
+        assert_eq!(offset, 8);
+        partial.discard();
+        assert!(!partial_path.exists());
+
+        let bytes = |start, end, length| ContentRangeSpec::Bytes {
+            range: Some((start, end)),
+            instance_length: Some(length),
+        };
+        let unsatisfied = |length| ContentRangeSpec::Bytes {
+            range: None,
+            instance_length: Some(length),
+        };
+        assert_eq!(resume(0, StatusCode::Ok, None), Resume::Whole);
+        assert_eq!(resume(512, StatusCode::Ok, None), Resume::Whole);
+        assert_eq!(
+            resume(512, StatusCode::PartialContent, Some(&bytes(512, 1023, 1024))),
+            Resume::Append
+        );
+        assert_eq!(
+            resume(512, StatusCode::PartialContent, Some(&bytes(0, 1023, 1024))),
+            Resume::Discard
+        );
+        assert_eq!(
+            resume(1024, StatusCode::RangeNotSatisfiable, Some(&unsatisfied(1024))),
+            Resume::Complete
+        );
+
+        // A partial file which is longer than the file is of another version of it.
+        assert_eq!(
+            resume(2048, StatusCode::RangeNotSatisfiable, Some(&unsatisfied(1024))),
+            Resume::Discard
+        );
+        assert_eq!(resume(512, StatusCode::NotFound, None), Resume::Whole);
+    }
+
+    #[test]
+    fn files_are_downloaded_resumed_and_verified_by_either_engine() {
+        let body: Vec<u8> = (0..300 * 1024).map(|x| (x % 251) as u8).collect();
+        let mut hasher = Sha256::default();
+        hasher.input(&body);
+        let checksum = format!("{:x}", hasher.result());
+        let checksum = checksum.as_str();
+        let url = serve(body.clone());
+
+        for (name, engine) in engines() {
+            let dir = TempDir::new("debrep-request").unwrap();
+            let path = dir.path().join("pop-shop_1.0_amd64.deb");
+            let partial = dir.path().join("pop-shop_1.0_amd64.deb.partial");
+            let length = body.len() as u64;
+            let get = |checksum: Option<&str>| file(&*engine, &url, checksum, &path);
+
+            assert_eq!(get(Some(checksum)).unwrap(), length, "{}", name);
+            assert_eq!(misc::read(&path).unwrap(), body, "{}", name);
+            assert_eq!(get(Some(checksum)).unwrap(), 0, "{}", name);
+
+            // A download which was cut off is resumed from where it was.
+            fs::remove_file(&path).unwrap();
+            misc::write(&partial, &body[..1000]).unwrap();
+            assert_eq!(get(Some(checksum)).unwrap(), length - 1000, "{}", name);
+            assert_eq!(misc::read(&path).unwrap(), body, "{}", name);
+            assert!(!partial.exists(), "{}", name);
+
+            // One which was cut off after its last byte is only verified.
+            fs::remove_file(&path).unwrap();
+            misc::write(&partial, &body).unwrap();
+            assert_eq!(get(Some(checksum)).unwrap(), 0, "{}", name);
+            assert_eq!(misc::read(&path).unwrap(), body, "{}", name);
+
+            // One which does not match its checksum is neither kept, nor retried.
+            fs::remove_file(&path).unwrap();
+            match get(Some("0a1b2c")) {
+                Err(ref why @ DownloadError::ChecksumMismatch { .. }) => {
+                    assert!(!why.is_retryable(), "{}", name)
+                }
+                other => panic!("{}: expected a checksum mismatch, found {:?}", name, other),
+            }
+            assert!(!path.exists() && !partial.exists(), "{}", name);
+
+            // One without a checksum is downloaded again, as the file may have changed since.
+            misc::write(&partial, b"changed").unwrap();
+            assert_eq!(get(None).unwrap(), length, "{}", name);
+            assert_eq!(misc::read(&path).unwrap(), body, "{}", name);
+            fs::remove_file(&path).unwrap();
+
+            let missing = url.replace(".deb", ".dsc");
+            match file(&*engine, &missing, None, &path) {
+                Err(ref why @ DownloadError::Status { .. }) => {
+                    assert!(!why.is_retryable(), "{}", name);
+                    assert_eq!(why.kind(), "network");
+                }
+                other => panic!("{}: expected a missing file, found {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn batches_are_downloaded_over_at_most_the_connections_of_the_async_engine() {
+        // Serves each connection on a thread of its own, slowly, counting how many are open.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let open = Arc::new(Mutex::new((0, 0)));
+        let counted = open.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (mut stream, open) = (stream.unwrap(), counted.clone());
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let path = line.split_whitespace().nth(1).unwrap_or("").to_owned();
+                    let found = path.ends_with(".deb");
+                    while reader.read_line(&mut line).unwrap() != 0 && line.trim() != "" {
+                        line.clear();
+                    }
+
+                    {
+                        let mut open = open.lock().unwrap();
+                        open.0 += 1;
+                        open.1 = open.1.max(open.0);
+                    }
+
+                    thread::sleep(Duration::from_millis(100));
+                    open.lock().unwrap().0 -= 1;
+                    let head = if found { "200 OK" } else { "404 Not Found" };
+                    let body = if found { "!<arch>\n" } else { "" };
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        head, body.len(), body
+                    );
+                });
+            }
+        });
+
+        let dir = TempDir::new("debrep-request").unwrap();
+        let mut batch: Vec<Request> = (0..6)
+            .map(|x| {
+                let url = format!("http://{}/pop-shop_1.{}_amd64.deb", address, x);
+                Request::new(&url, None, &dir.path().join(format!("{}.deb", x)))
+            })
+            .collect();
+        let missing = format!("http://{}/pop-shop_1.0.dsc", address);
+        batch.insert(2, Request::new(&missing, None, &dir.path().join("1.0.dsc")));
+
+        let results = engine::Async::new(2).unwrap().files(&batch);
+        assert_eq!(results.len(), 7);
+        for (request, result) in batch.iter().zip(&results) {
+            match *result {
+                Ok(bytes) => {
+                    assert_eq!(bytes, 8);
+                    assert_eq!(misc::read(&request.path).unwrap(), b"!<arch>\n");
+                }
+                Err(DownloadError::Status { ref url, .. }) => assert_eq!(url, &missing),
+                Err(ref why) => panic!("{} failed: {}", request.url, why),
+            }
+        }
+
+        assert!(results[2].is_err());
+        assert_eq!(*open.lock().unwrap(), (0, 2));
+    }
+
+    #[test]
+    fn large_downloads_are_streamed_through_a_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let chunk: Vec<u8> = (0..1024 * 1024).map(|x| (x % 251) as u8).collect();
+            let mut hasher = Sha256::default();
+            for _ in 0..BODY_SIZE / chunk.len() as u64 {
+                hasher.input(&chunk);
+                client.write_all(&chunk).unwrap();
+            }
+
+            format!("{:x}", hasher.result())
+        });
+
+        let mut body = Bounded(TcpStream::connect(address).unwrap());
+        let mut file = Sink::default();
+        let mut reported = 0;
+        let (copied, digest) = stream(&mut body, &mut file, &mut |bytes| {
+            assert!(bytes > reported);
+            reported = bytes;
+        }).unwrap();
+
+        assert_eq!(copied, BODY_SIZE);
+        assert_eq!(file.written, BODY_SIZE);
+        assert!(file.largest <= BUFFER_SIZE);
+        assert_eq!(digest, server.join().unwrap());
+        assert_eq!(reported, BODY_SIZE);
     }
 }
@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use config::{Direct, Snapshot};
+use misc;
+use repo::run;
+use reqwest::{self, Client};
+use serde_json;
+use super::{request, DownloadError};
+use super::engine::Engine;
+
+pub const API: &str = "https://snapshot.debian.org";
+
+/// API responses are stored here, since the files of a version never change.
+const CACHE: &str = "assets/cache/snapshot";
+
+#[derive(Debug, Fail)]
+pub enum SnapshotError {
+    #[fail(display = "snapshot.debian.org request to {} failed: {}", url, why)]
+    Api { url: String, why: io::Error },
+    #[fail(display = "snapshot.debian.org returned an invalid response for {}: {}", url, why)]
+    Response { url: String, why: serde_json::Error },
+    #[fail(display = "snapshot.debian.org has no {} file of {} {}", arch, package, version)]
+    NotFound { package: String, version: String, arch: String },
+    #[fail(display = "snapshot.debian.org has more than one {} file of {} {}: {}", arch, package,
+           version, files)]
+    Ambiguous { package: String, version: String, arch: String, files: String },
+    #[fail(display = "{:?} does not have the SHA1 hash {} which snapshot.debian.org reported; \
+                      found {}", file, expected, found)]
+    HashMismatch { file: PathBuf, expected: String, found: String },
+    #[fail(display = "failed to download {} to {:?}: {}", url, file, why)]
+    Download { url: String, file: PathBuf, why: io::Error },
+    #[fail(display = "failed to download {:?}: {}", file, why)]
+    Request { file: PathBuf, why: Box<DownloadError> },
+    #[fail(display = "{} needs {} from snapshot.debian.org, but downloads are offline", package,
+           url)]
+    Offline { package: String, url: String },
+}
+
+/// The files of a package in a response of the API, requested with `fileinfo=1`.
+#[derive(Debug, Deserialize)]
+struct Files {
+    result: Vec<FileHash>,
+    fileinfo: BTreeMap<String, Vec<FileInfo>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileHash {
+    hash: String,
+    architecture: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileInfo {
+    name: String,
+}
+
+/// Downloads the files of the snapshot into the pool, verifying them with the SHA1 hashes which
+/// the API reports. Returns the bytes downloaded. When `offline`, the response of the API must be
+/// cached, and the files must already be in the pool.
+pub fn download(
+    client: &Client,
+    engine: &Engine,
+    item: &Direct,
+    snapshot: &Snapshot,
+    branch: &str,
+    offline: bool,
+) -> Result<u64, SnapshotError> {
+    let source = snapshot.arch == "source";
+    let path = if source {
+        ["/mr/package/", &snapshot.package, "/", &snapshot.version, "/srcfiles?fileinfo=1"].concat()
+    } else {
+        ["/mr/binary/", &snapshot.package, "/", &snapshot.version, "/binfiles?fileinfo=1"].concat()
+    };
+
+    let files = request_files(client, &path, &item.name, offline)?;
+    let mut downloaded = 0;
+    for (hash, name) in select(&files, snapshot)? {
+        let dst = if source {
+            "/main/source/".to_owned()
+        } else {
+            ["/main/binary-", &snapshot.arch, "/"].concat()
+        };
+
+        let destination = PathBuf::from([
+            "repo/pool/", branch, &dst, &item.name[0..1], "/", &item.name, "/", &name
+        ].concat());
+
+        downloaded += download_file(engine, &hash, &destination, &item.name, offline)?;
+    }
+
+    Ok(downloaded)
+}
+
+/// Requests the files of a package from the API, unless the response was cached.
+fn request_files(
+    client: &Client,
+    path: &str,
+    package: &str,
+    offline: bool,
+) -> Result<Files, SnapshotError> {
+    let url = [API, path].concat();
+    let name = path.split('?').next().unwrap_or(path).trim_left_matches('/').replace('/', "_");
+    let cached = Path::new(CACHE).join([&name, ".json"].concat());
+    let api_error = |why: io::Error| SnapshotError::Api { url: url.clone(), why };
+
+    let data = match misc::read(&cached) {
+        Ok(data) => data,
+        Err(_) if offline => {
+            return Err(SnapshotError::Offline { package: package.to_owned(), url: url.clone() });
+        }
+        Err(_) => {
+            info!("requesting {}", url);
+            let mut response = client.get(&url).send().map_err(|why| api_error(other(why)))?;
+
+            if !response.status().is_success() {
+                return Err(api_error(io::Error::new(io::ErrorKind::NotFound, "request failed")));
+            }
+
+            let mut data = Vec::new();
+            response.copy_to(&mut data).map_err(|why| api_error(other(why)))?;
+
+            // Responses which cannot be parsed are not cached, so that they are requested again.
+            serde_json::from_slice::<Files>(&data)
+                .map_err(|why| SnapshotError::Response { url: url.clone(), why })?;
+
+            fs::create_dir_all(CACHE)
+                .and_then(|_| misc::write(&cached, &data))
+                .map_err(&api_error)?;
+            data
+        }
+    };
+
+    serde_json::from_slice(&data).map_err(|why| SnapshotError::Response { url: url.clone(), why })
+}
+
+fn other(why: reqwest::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{}", why))
+}
+
+/// The hashes and names of the files to download. Every file of a source package is downloaded,
+/// and exactly one file must match the architecture of a binary package.
+fn select(files: &Files, snapshot: &Snapshot) -> Result<Vec<(String, String)>, SnapshotError> {
+    let name = |hash: &str| {
+        files.fileinfo.get(hash).and_then(|info| info.first()).map(|info| info.name.clone())
+    };
+
+    let mut selected: Vec<(String, String)> = files.result.iter()
+        .filter(|file| {
+            snapshot.arch == "source"
+                || file.architecture.as_ref().map_or(false, |arch| arch == &snapshot.arch)
+        })
+        .filter_map(|file| name(&file.hash).map(|name| (file.hash.clone(), name)))
+        .collect();
+
+    selected.sort();
+    selected.dedup();
+
+    if selected.is_empty() {
+        return Err(SnapshotError::NotFound {
+            package: snapshot.package.clone(),
+            version: snapshot.version.clone(),
+            arch: snapshot.arch.clone(),
+        });
+    }
+
+    if snapshot.arch != "source" && selected.len() > 1 {
+        let names: Vec<&str> = selected.iter().map(|&(_, ref name)| name.as_str()).collect();
+        return Err(SnapshotError::Ambiguous {
+            package: snapshot.package.clone(),
+            version: snapshot.version.clone(),
+            arch: snapshot.arch.clone(),
+            files: names.join(", "),
+        });
+    }
+
+    Ok(selected)
+}
+
+fn download_file(
+    engine: &Engine,
+    hash: &str,
+    destination: &Path,
+    name: &str,
+    offline: bool,
+) -> Result<u64, SnapshotError> {
+    let url = [API, "/file/", hash].concat();
+    let download_error = |why: io::Error| SnapshotError::Download {
+        url: url.clone(),
+        file: destination.to_path_buf(),
+        why
+    };
+
+    if destination.exists() && sha1_digest(destination).map_err(&download_error)? == hash {
+        info!("{} is already downloaded", destination.display());
+        return Ok(0);
+    }
+
+    if offline {
+        return Err(SnapshotError::Offline { package: name.to_owned(), url });
+    }
+
+    let bytes = request::file(engine, &url, None, destination).map_err(|why| {
+        SnapshotError::Request { file: destination.to_path_buf(), why: Box::new(why) }
+    })?;
+    let found = sha1_digest(destination).map_err(&download_error)?;
+    if found != hash {
+        return Err(SnapshotError::HashMismatch {
+            file: destination.to_path_buf(),
+            expected: hash.to_owned(),
+            found,
+        });
+    }
+
+    if let Some(run) = run::current() {
+        if let Ok(path) = destination.canonicalize() {
+            run.add_published(path);
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn sha1_digest(path: &Path) -> io::Result<String> {
+    let output = Command::new("sha1sum").arg(path).output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, "sha1sum failed"));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).split_whitespace().next().unwrap_or("").to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINFILES: &str = r#"{
+        "_comment": "foo",
+        "binary": "libfoo1",
+        "binary_version": "1.2-3",
+        "result": [
+            {"architecture": "amd64", "hash": "aaaa"},
+            {"architecture": "i386", "hash": "bbbb"},
+            {"architecture": "i386", "hash": "cccc"}
+        ],
+        "fileinfo": {
+            "aaaa": [{"name": "libfoo1_1.2-3_amd64.deb", "archive_name": "debian", "size": 10}],
+            "bbbb": [{"name": "libfoo1_1.2-3_i386.deb", "archive_name": "debian", "size": 10}],
+            "cccc": [{"name": "libfoo1_1.2-3_i386.deb", "archive_name": "debian-ports", "size": 11}]
+        }
+    }"#;
+
+    fn snapshot(arch: &str) -> Snapshot {
+        Snapshot { package: "libfoo1".into(), version: "1.2-3".into(), arch: arch.into() }
+    }
+
+    #[test]
+    fn files_are_selected_by_architecture() {
+        let files: Files = serde_json::from_str(BINFILES).unwrap();
+        assert_eq!(
+            select(&files, &snapshot("amd64")).unwrap(),
+            vec![("aaaa".to_owned(), "libfoo1_1.2-3_amd64.deb".to_owned())]
+        );
+
+        match select(&files, &snapshot("i386")) {
+            Err(SnapshotError::Ambiguous { ref files, .. }) => {
+                assert_eq!(files, "libfoo1_1.2-3_i386.deb, libfoo1_1.2-3_i386.deb")
+            }
+            other => panic!("expected an ambiguous result, found {:?}", other),
+        }
+
+        match select(&files, &snapshot("arm64")) {
+            Err(SnapshotError::NotFound { .. }) => (),
+            other => panic!("expected no result, found {:?}", other),
+        }
+
+        assert_eq!(select(&files, &snapshot("source")).unwrap().len(), 3);
+    }
+}
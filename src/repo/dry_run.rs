@@ -0,0 +1,111 @@
+//! Dry runs, which report what a run would fetch and build without doing either, so that whether
+//! a repo is up to date can be checked before starting a run, or from cron. Only what is cheap to
+//! find out is checked: the lengths of the files which would be downloaded, with `HEAD`
+//! requests, the commits of the repositories of git sources, with `git ls-remote`, and the
+//! records of sources, which are compared to the changelogs and commits of their checkouts.
+//!
+//! Nothing is downloaded, built, or moved into the pool, and the workspaces of builds are not
+//! touched, so that a dry run may run beside a run which is building.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use config::Config;
+use misc;
+use output::format_size;
+use super::build::{self, Force};
+use super::commands::Phase;
+use super::download;
+use super::Selection;
+
+/// What a run would do with a package.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Intent {
+    /// A file would be downloaded, whose length is known if its server reports it.
+    Download { file: String, length: Option<u64> },
+    /// What a source is built from would be fetched again, and why.
+    Fetch { name: String, why: String },
+    /// A source would be built, and why.
+    Build { name: String, why: String },
+    /// A source may be built, since whether it is only becomes known once its tree is prepared.
+    Check { name: String, why: String },
+    /// A source would not be built, and why.
+    Skip { name: String, why: String },
+}
+
+impl Intent {
+    /// Whether the run would change the repo.
+    pub fn is_work(&self) -> bool {
+        match *self {
+            Intent::Skip { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Intent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Intent::Download { ref file, length: Some(length) } => {
+                write!(f, "would download {} ({})", file, format_size(length))
+            }
+            Intent::Download { ref file, length: None } => write!(f, "would download {}", file),
+            Intent::Fetch { ref name, ref why } => write!(f, "would fetch {}: {}", name, why),
+            Intent::Build { ref name, ref why } => write!(f, "would build {}: {}", name, why),
+            Intent::Check { ref name, ref why } => write!(f, "may build {}: {}", name, why),
+            Intent::Skip { ref name, ref why } => write!(f, "skip {}: {}", name, why),
+        }
+    }
+}
+
+/// What a run of the phases would do with the selected packages of the suite, in the repo at the
+/// working directory.
+pub fn run(config: &Config, selection: &Selection, phases: &[Phase]) -> io::Result<Vec<Intent>> {
+    let mut intents = Vec::new();
+    if phases.contains(&Phase::Fetch) {
+        intents.extend(download::dry_run(config, selection.packages()));
+    }
+
+    if phases.contains(&Phase::Build) {
+        let (items, force) = match *selection {
+            Selection::All => (config.build_units(), Force::No),
+            Selection::Packages(packages, force) => {
+                let force = if force { Force::Yes } else { Force::No };
+                (build::named(config, packages), force)
+            }
+            Selection::Rebuild(packages, reason) if packages.is_empty() => {
+                (config.build_units(), Force::Rebuild(reason))
+            }
+            Selection::Rebuild(packages, reason) => {
+                (build::named(config, packages), Force::Rebuild(reason))
+            }
+        };
+
+        let host = misc::host_architecture()?;
+        intents.extend(build::dry_run(config, Path::new("."), &host, &items, force));
+    }
+
+    Ok(intents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intents_are_lines_of_what_would_be_done() {
+        let intents = vec![
+            Intent::Download { file: "pop-icons_1.2_all.deb".into(), length: Some(14 << 20) },
+            Intent::Fetch { name: "pop-shop".into(), why: "master is at 1a2b3c".into() },
+            Intent::Skip { name: "pop-desktop".into(), why: "already built at version 1.0".into() },
+        ];
+        let lines: Vec<String> = intents.iter().map(|intent| intent.to_string()).collect();
+        assert_eq!(lines, vec![
+            "would download pop-icons_1.2_all.deb (14.0 MiB)",
+            "would fetch pop-shop: master is at 1a2b3c",
+            "skip pop-desktop: already built at version 1.0",
+        ]);
+        assert_eq!(intents.iter().filter(|intent| intent.is_work()).count(), 2);
+    }
+}
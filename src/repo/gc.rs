@@ -0,0 +1,442 @@
+//! Garbage collection of the working areas of the repo, for `debrep gc`: the archives, mirrors,
+//! and vendored dependencies of `assets/cache/`, the checkouts of `build/` and the workspaces of
+//! builds in `build/workspaces/`, and the logs of `logs/`. The pool and the dists are never
+//! collected, since packages are only removed from them by `debrep clean` and `debrep remove`.
+//!
+//! Each area is made of entries, such as a cached archive, a checkout, or a log, which are removed
+//! whole. An entry which the config still uses is always kept. Of the others, those which were
+//! last used before the `max_age` of the area are removed, and then those which were used least
+//! recently, while the area is larger than its `max_size`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config::{AreaRetention, Config, GcConfig};
+use walkdir::WalkDir;
+use super::build::{workspace_path, VENDOR_CACHE, WORKSPACES};
+use super::download::fetcher;
+use super::logs::{LATEST, LOGS};
+use super::run::{self, RUNS};
+
+const CACHE: &str = "assets/cache";
+const BUILD: &str = "build";
+
+/// Directories of the cache which hold an entry for each source, or each request to an API.
+const CACHE_GROUPS: &[&str] = &["git", "snapshot", "vendor"];
+
+/// A working area of the repo.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Area {
+    Cache,
+    Build,
+    Logs,
+}
+
+impl Area {
+    pub const ALL: &'static [Area] = &[Area::Cache, Area::Build, Area::Logs];
+
+    pub fn from_name(name: &str) -> Option<Area> {
+        Area::ALL.iter().cloned().find(|area| area.as_str() == name)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Area::Cache => "cache",
+            Area::Build => "build",
+            Area::Logs => "logs",
+        }
+    }
+
+    fn policy(self, config: &GcConfig) -> Option<&AreaRetention> {
+        match self {
+            Area::Cache => config.cache.as_ref(),
+            Area::Build => config.build.as_ref(),
+            Area::Logs => config.logs.as_ref(),
+        }
+    }
+}
+
+/// What an area held, and what its policy allowed to be removed from it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub area: Area,
+    /// Whether the area has a policy. Nothing is removed from an area without one.
+    pub limited: bool,
+    pub entries: usize,
+    pub bytes: u64,
+    /// How many entries the policy allows to be removed, and their size.
+    pub reclaimable: usize,
+    pub reclaimable_bytes: u64,
+    /// The size of what was removed, which is nothing on a dry run.
+    pub reclaimed_bytes: u64,
+}
+
+/// What is removed whole from an area, with the size of its files.
+#[derive(Debug)]
+struct Entry {
+    path: PathBuf,
+    bytes: u64,
+    /// When any of its files was last read or written.
+    used: SystemTime,
+    /// Whether the config still uses it, so that it is always kept.
+    referenced: bool,
+}
+
+impl Entry {
+    fn measure(path: PathBuf, referenced: bool) -> io::Result<Entry> {
+        let (mut bytes, mut used) = (0, UNIX_EPOCH);
+        for file in WalkDir::new(&path) {
+            let metadata = file?.metadata()?;
+            let modified = metadata.modified()?;
+            // Directories are read by the walk itself, which may update when they were accessed.
+            if metadata.is_dir() {
+                used = used.max(modified);
+            } else {
+                bytes += metadata.len();
+                used = used.max(modified).max(metadata.accessed().unwrap_or(modified));
+            }
+        }
+
+        Ok(Entry { path, bytes, used, referenced })
+    }
+}
+
+/// Collects the area of the repo at the root, by the policy of the config. On a dry run, what
+/// would be removed is only reported.
+pub fn collect(config: &Config, root: &Path, area: Area, dry_run: bool) -> io::Result<Report> {
+    let entries = match area {
+        Area::Cache => cache_entries(root, &referenced(config, root))?,
+        Area::Build => build_entries(root, &referenced(config, root))?,
+        Area::Logs => log_entries(root)?,
+    };
+
+    let policy = config.gc.as_ref().and_then(|gc| area.policy(gc));
+    let mut report = Report {
+        area,
+        limited: policy.is_some(),
+        entries: entries.len(),
+        bytes: entries.iter().map(|entry| entry.bytes).sum(),
+        reclaimable: 0,
+        reclaimable_bytes: 0,
+        reclaimed_bytes: 0,
+    };
+
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return Ok(report),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    for entry in select(&entries, policy, now) {
+        report.reclaimable += 1;
+        report.reclaimable_bytes += entry.bytes;
+        if dry_run {
+            info!("would remove {}", entry.path.display());
+            continue;
+        }
+
+        info!("removing {}", entry.path.display());
+        remove(&entry.path)?;
+        report.reclaimed_bytes += entry.bytes;
+    }
+
+    Ok(report)
+}
+
+/// The entries which the policy removes, from the one which was used least recently. `now` is in
+/// seconds since the epoch.
+fn select<'a>(entries: &'a [Entry], policy: &AreaRetention, now: u64) -> Vec<&'a Entry> {
+    let oldest = policy.max_age
+        .map(|days| UNIX_EPOCH + Duration::from_secs(now.saturating_sub(days * 24 * 60 * 60)));
+    let budget = policy.max_size.map(|mib| mib * 1024 * 1024);
+
+    let mut by_use: Vec<&Entry> = entries.iter().collect();
+    by_use.sort_by(|a, b| a.used.cmp(&b.used).then_with(|| a.path.cmp(&b.path)));
+
+    let mut total: u64 = entries.iter().map(|entry| entry.bytes).sum();
+    let mut selected = Vec::new();
+    for entry in by_use.into_iter().filter(|entry| !entry.referenced) {
+        let expired = oldest.map_or(false, |oldest| entry.used < oldest);
+        let over = budget.map_or(false, |budget| total > budget);
+        if expired || over {
+            total -= entry.bytes;
+            selected.push(entry);
+        }
+    }
+
+    selected
+}
+
+/// What the config uses in the cache, and in `build/`: the inputs and build workspaces of each
+/// source in every suite, and the upstream packages of repacks. Workspaces are referenced on
+/// every architecture, as `build/workspaces/<suite>/*/<source>`.
+fn referenced(config: &Config, root: &Path) -> HashSet<PathBuf> {
+    let suites = config.suite_names().unwrap_or_else(|| vec![config.archive.clone()]);
+    let mut paths = HashSet::new();
+    for source in config.source.iter().flat_map(|sources| sources.iter()) {
+        for suite in &suites {
+            for unit in source.units(suite) {
+                paths.insert(root.join(BUILD).join(&unit.name));
+                paths.insert(workspace_path(root, suite, "*", &unit.name));
+                paths.insert(root.join(VENDOR_CACHE).join(&unit.name));
+                paths.extend(fetcher::of(&unit).map(|fetcher| root.join(fetcher.resolve())));
+                paths.extend(fetcher::debian(&unit).map(|fetcher| root.join(fetcher.resolve())));
+            }
+        }
+
+        paths.extend(fetcher::mirror(source).map(|mirror| root.join(mirror)));
+    }
+
+    // Repacks cache their upstream packages as `<name>_<filename>`.
+    for repack in config.repack.iter().flat_map(|repacks| repacks.iter()) {
+        if let Some(ref url) = repack.url {
+            let filename = &url[url.rfind('/').map_or(0, |x| x + 1)..];
+            paths.insert(root.join(CACHE).join([&repack.name, "_", filename].concat()));
+        }
+    }
+
+    paths
+}
+
+/// The paths in a directory, which has none if it does not exist.
+fn children(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match dir.read_dir() {
+        Ok(entries) => entries.map(|entry| entry.map(|entry| entry.path())).collect(),
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(why) => Err(why),
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned())
+}
+
+fn cache_entries(root: &Path, referenced: &HashSet<PathBuf>) -> io::Result<Vec<Entry>> {
+    let mut paths = Vec::new();
+    for path in children(&root.join(CACHE))? {
+        let name = file_name(&path);
+        // Hidden files are the caches of debrep itself, such as the digests of the pool.
+        if name.starts_with('.') {
+            continue;
+        } else if path.is_dir() && CACHE_GROUPS.contains(&name.as_str()) {
+            paths.extend(children(&path)?);
+        } else {
+            paths.push(path);
+        }
+    }
+
+    paths.into_iter()
+        .map(|path| {
+            let referenced = referenced.contains(&path);
+            Entry::measure(path, referenced)
+        })
+        .collect()
+}
+
+/// The checkouts of `build/`, and the workspace of each build unit, rather than all of
+/// `build/workspaces/` as a single entry.
+fn build_entries(root: &Path, referenced: &HashSet<PathBuf>) -> io::Result<Vec<Entry>> {
+    let workspaces = root.join(WORKSPACES);
+    let mut entries = Vec::new();
+    for path in children(&root.join(BUILD))? {
+        if path != workspaces {
+            let used = referenced.contains(&path);
+            entries.push(Entry::measure(path, used)?);
+            continue;
+        }
+
+        for suite in children(&workspaces)? {
+            for arch in children(&suite)? {
+                for workspace in children(&arch)? {
+                    let key = workspace_path(root, &file_name(&suite), "*", &file_name(&workspace));
+                    let used = referenced.contains(&key);
+                    entries.push(Entry::measure(workspace, used)?);
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// The logs of each package, of which the one which `latest` points to is kept, and the logs of
+/// each run, of which those of the current run are kept.
+fn log_entries(root: &Path) -> io::Result<Vec<Entry>> {
+    let current = run::current().map(|run| run.id.clone());
+    let mut entries = Vec::new();
+    for dir in children(&root.join(LOGS))? {
+        if file_name(&dir) == RUNS {
+            for path in children(&dir)? {
+                let referenced = current.as_ref().map_or(false, |id| file_name(&path) == *id);
+                entries.push(Entry::measure(path, referenced)?);
+            }
+        } else if dir.symlink_metadata()?.is_dir() {
+            let latest = fs::read_link(dir.join(LATEST)).ok().map(|path| dir.join(path));
+            for path in children(&dir)? {
+                if file_name(&path) != LATEST {
+                    let referenced = latest.as_ref().map_or(false, |latest| *latest == path);
+                    entries.push(Entry::measure(path, referenced)?);
+                }
+            }
+        } else {
+            // A log of the layout before logs had a directory for each package.
+            entries.push(Entry::measure(dir, false)?);
+        }
+    }
+
+    Ok(entries)
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if path.symlink_metadata()?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use tempdir::TempDir;
+    use toml;
+
+    fn entry(path: &str, mib: u64, days_ago: u64, referenced: bool) -> Entry {
+        Entry {
+            path: PathBuf::from(path),
+            bytes: mib * 1024 * 1024,
+            used: UNIX_EPOCH + Duration::from_secs((100 - days_ago) * 24 * 60 * 60),
+            referenced,
+        }
+    }
+
+    fn paths(selected: Vec<&Entry>) -> Vec<&str> {
+        selected.iter().map(|entry| entry.path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn unreferenced_entries_are_removed_by_age_then_by_least_recent_use() {
+        let now = 100 * 24 * 60 * 60;
+        let entries = vec![
+            entry("old-checkout", 10, 60, true),
+            entry("old-archive", 5, 40, false),
+            entry("archive", 20, 20, false),
+            entry("mirror", 30, 10, false),
+            entry("recent", 1, 0, false),
+        ];
+
+        let by_age = AreaRetention { max_age: Some(30), max_size: None };
+        assert_eq!(paths(select(&entries, &by_age, now)), vec!["old-archive"]);
+
+        // 66 MiB must shrink to 35 MiB, and the referenced checkout is kept.
+        let by_size = AreaRetention { max_age: None, max_size: Some(35) };
+        let removed = vec!["old-archive", "archive", "mirror"];
+        assert_eq!(paths(select(&entries, &by_size, now)), removed);
+
+        let unlimited = AreaRetention::default();
+        assert!(select(&entries, &unlimited, now).is_empty());
+    }
+
+    #[test]
+    fn what_the_config_uses_is_referenced() {
+        let dir = TempDir::new("debrep-gc").unwrap();
+        let root = dir.path();
+        for path in &[
+            "assets/cache/.sha256",
+            "assets/cache/foo_foo-1.0.tar.gz",
+            "assets/cache/foo_foo-0.9.tar.gz",
+            "assets/cache/git/bar.git/HEAD",
+            "assets/cache/git/gone.git/HEAD",
+            "assets/cache/vendor/bar@stable/digest",
+            "build/bar@stable/Cargo.toml",
+            "build/removed/debian/control",
+            "build/foo_1.0_amd64.deb",
+            "build/workspaces/bionic/amd64/bar@stable/outgoing/bar@stable/Cargo.toml",
+            "build/workspaces/bionic/amd64/removed/outgoing/removed/debian/control",
+        ] {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            misc::write(&path, "data").unwrap();
+        }
+
+        let config: Config = toml::from_str(r#"
+            archive = "bionic"
+            version = "18.04"
+            origin = "o"
+            label = "l"
+            email = "e"
+
+            [[source]]
+            name = "foo"
+            location = { url = "https://example.com/foo-1.0.tar.gz", checksum = "0" }
+
+            [[source]]
+            name = "bar"
+            location = { url = "https://example.com/bar.git" }
+            variants = [{ name = "stable", tag = "1.0" }]
+        "#).unwrap();
+
+        let referenced = referenced(&config, root);
+        let kept = |entries: Vec<Entry>| {
+            let mut kept: Vec<(String, bool)> = entries.into_iter()
+                .map(|entry| {
+                    let path = entry.path.strip_prefix(root).unwrap().display().to_string();
+                    (path, entry.referenced)
+                })
+                .collect();
+            kept.sort();
+            kept
+        };
+
+        assert_eq!(kept(cache_entries(root, &referenced).unwrap()), vec![
+            ("assets/cache/foo_foo-0.9.tar.gz".to_owned(), false),
+            ("assets/cache/foo_foo-1.0.tar.gz".to_owned(), true),
+            ("assets/cache/git/bar.git".to_owned(), true),
+            ("assets/cache/git/gone.git".to_owned(), false),
+            ("assets/cache/vendor/bar@stable".to_owned(), true),
+        ]);
+
+        assert_eq!(kept(build_entries(root, &referenced).unwrap()), vec![
+            ("build/bar@stable".to_owned(), true),
+            ("build/foo_1.0_amd64.deb".to_owned(), false),
+            ("build/removed".to_owned(), false),
+            ("build/workspaces/bionic/amd64/bar@stable".to_owned(), true),
+            ("build/workspaces/bionic/amd64/removed".to_owned(), false),
+        ]);
+    }
+
+    #[test]
+    fn latest_logs_are_kept_and_dry_runs_remove_nothing() {
+        let dir = TempDir::new("debrep-gc").unwrap();
+        let root = dir.path();
+        let logs = root.join("logs/pop-shop");
+        fs::create_dir_all(&logs).unwrap();
+        fs::create_dir_all(root.join("logs/runs/1-aaaaaa")).unwrap();
+        misc::write(logs.join("1.log.gz"), "old").unwrap();
+        misc::write(logs.join("2.log.gz"), "new").unwrap();
+        ::std::os::unix::fs::symlink("2.log.gz", logs.join(LATEST)).unwrap();
+
+        let mut config: Config = toml::from_str(
+            "archive = \"bionic\"\nversion = \"18.04\"\norigin = \"o\"\nlabel = \"l\"\n\
+             email = \"e\"\n\n[gc.logs]\nmax_size = 0\n",
+        ).unwrap();
+
+        let report = collect(&config, root, Area::Logs, true).unwrap();
+        assert_eq!((report.entries, report.reclaimable), (3, 2));
+        assert_eq!((report.reclaimable_bytes, report.reclaimed_bytes), (3, 0));
+        assert!(logs.join("1.log.gz").exists());
+
+        let report = collect(&config, root, Area::Logs, false).unwrap();
+        assert_eq!(report.reclaimed_bytes, 3);
+        assert!(!logs.join("1.log.gz").exists() && !root.join("logs/runs/1-aaaaaa").exists());
+        assert!(logs.join("2.log.gz").exists());
+
+        config.gc = None;
+        let report = collect(&config, root, Area::Build, false).unwrap();
+        assert!(!report.limited && report.entries == 0);
+    }
+}
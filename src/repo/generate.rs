@@ -1,67 +1,208 @@
-use std::env;
+use std::collections::BTreeMap;
 use std::fs::{self, File};
-use std::io::{self, Write};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use deflate::write::GzEncoder;
 use deflate::Compression;
+use rayon::prelude::*;
+use walkdir::WalkDir;
 use xz2::read::XzEncoder;
 
-use config::Config;
+use command::Cmd;
+use config::{Config, DdebHandling, DEFAULT_COMPONENT};
+use misc;
+use super::checksum::{self, Digests};
+use super::deb;
+use super::keys;
+use super::pool::{self, PoolFile, PoolIndex};
+use super::provenance;
 
-/// Generates the binary files from Debian packages that exist within the pool, using
-/// `apt-ftparchive`
-pub(crate) fn generate_binary_files(config: &Config, dist_base: &str, pool_base: &str) -> io::Result<()> {
+/// The files of the dists which the `Release` file does not list: itself, and its signatures.
+const UNLISTED: &[&str] = &["Release", "InRelease", "Release.gpg"];
+
+/// Generates the binary files from Debian packages that exist within the pool, for each
+/// architecture of each component of the pool's index, from the control files of the packages.
+/// Files which are
+/// excluded are left out of the index. The base is relative to the repo, which the index lists
+/// the files relative to. Debug symbol packages are listed by the indices of the debug suite
+/// instead, if the config has one, or left out if it discards them. Components of the suite which
+/// have no packages yet have empty indices of each of its architectures.
+pub(crate) fn generate_binary_files(
+    config: &Config,
+    repo: &Path,
+    dist_base: &str,
+    pool: &PoolIndex,
+    excluded: &[String],
+) -> io::Result<()> {
     info!("generating binary files");
-    let branch = PathBuf::from([dist_base, "/main/"].concat());
-
-    for directory in fs::read_dir(pool_base)? {
-        let entry = directory?;
-        let arch = entry.file_name();
-        if &arch == "source" { continue }
-        let path = branch.join(&arch);
-        fs::create_dir_all(&path)?;
-
-        let package = Command::new("apt-ftparchive")
-            .arg("packages")
-            .arg(PathBuf::from(pool_base).join(&arch))
-            .output()
-            .map(|data| data.stdout)?;
-
-        compress("Packages", &path, &package)?;
-
-        let mut release = File::create(path.join("Release"))?;
-        writeln!(&mut release, "Archive: {}", config.archive)?;
-        writeln!(&mut release, "Version: {}", config.version)?;
-        writeln!(&mut release, "Component: main")?;
-        writeln!(&mut release, "Origin: {}", config.origin)?;
-        writeln!(&mut release, "Label: {}", config.label)?;
-        writeln!(
-            &mut release,
-            "Architecture: {}",
-            match arch.to_str().unwrap() {
-                "binary-amd64" => "amd64",
-                "binary-i386" => "i386",
-                "binary-all" => "all",
-                arch => panic!("unsupported architecture: {}", arch),
-            }
-        )?;
+    let debug_suite = config.debug_suite();
+    let suite = config.effective_suite(&config.archive);
+    let mut sections: Vec<(&str, String)> = binary_sections(pool).into_iter()
+        .map(|(component, section)| (component, section.to_owned()))
+        .collect();
+    for component in &suite.components {
+        if !pool.components().contains(&component.as_str()) {
+            let arches = suite.architectures.iter().map(|arch| ["binary-", arch].concat());
+            sections.extend(arches.map(|section| (component.as_str(), section)));
+        }
+    }
+
+    for (component, arch) in sections {
+        let arch = arch.as_str();
+        let debs: Vec<&PoolFile> = pool.files()
+            .into_iter()
+            .filter(|file| file.component == component && file.section == arch)
+            .filter(|file| file.path.extension().map_or(false, |ext| ext == "deb" || ext == "ddeb"))
+            .collect();
+
+        let package = packages_index(repo, &debs)?;
+
+        let package = if excluded.is_empty() {
+            package
+        } else {
+            provenance::exclude(&package, excluded)
+        };
+
+        let (package, debug) = match config.ddebs() {
+            DdebHandling::Main => (package, String::new()),
+            _ => split_debug_symbols(&package),
+        };
+
+        let path = repo.join(dist_base).join(component).join(arch);
+        write_binary_index(config, &config.archive, &path, package.as_bytes())?;
+
+        if let Some(ref suite) = debug_suite {
+            let path = repo.join("dists").join(suite).join(component).join(arch);
+            write_binary_index(config, suite, &path, debug.as_bytes())?;
+        }
     }
 
     Ok(())
 }
 
-pub(crate) fn generate_sources_index(dist_base: &str, pool_base: &str) -> io::Result<()> {
-    info!("generating sources index");
-    let path = PathBuf::from([dist_base, "/main/source/"].concat());
+/// The component and the `binary-<arch>` section of each architecture of each component of the
+/// pool.
+fn binary_sections(pool: &PoolIndex) -> Vec<(&str, &str)> {
+    pool.components()
+        .into_iter()
+        .flat_map(|component| {
+            pool.sections(component).into_iter().map(move |section| (component, section))
+        })
+        .filter(|&(_, section)| section.starts_with("binary-"))
+        .collect()
+}
+
+/// The `Packages` index of the packages, in the order of their paths, with the fields of their
+/// control files, and where apt downloads each of them from and verifies it with: its `Filename`
+/// relative to the repo, its `Size`, and its digests, which are listed before its `Description`,
+/// as `apt-ftparchive` lists them. A package which cannot be read is left out of the index,
+/// rather than failing the others. Packages are read and hashed in parallel.
+fn packages_index(repo: &Path, debs: &[&PoolFile]) -> io::Result<String> {
+    let paths: Vec<&Path> = debs.iter().map(|file| file.path.as_path()).collect();
+    let digests = hash(&paths);
+    let controls: Vec<io::Result<String>> =
+        paths.par_iter().map(|path| deb::control(path)).collect();
+
+    let mut index = String::new();
+    for (i, (control, digests)) in controls.into_iter().zip(digests).enumerate() {
+        let path = paths[i];
+        let control = match control {
+            Ok(control) => control,
+            Err(why) => {
+                warn!("leaving {} out of the Packages index: {}", path.display(), why);
+                continue;
+            }
+        };
+
+        let filename = path.strip_prefix(repo).unwrap_or(path);
+        let digests = digests?;
+        let fields = format!(
+            "Filename: {}\nSize: {}\nMD5sum: {}\nSHA1: {}\nSHA256: {}\n",
+            filename.display(),
+            path.metadata()?.len(),
+            digests.md5,
+            digests.sha1,
+            digests.sha256
+        );
+
+        let control = [control.trim_right_matches('\n'), "\n"].concat();
+        let at = control.find("\nDescription:").map_or(control.len(), |at| at + 1);
+        index.push_str(&control[..at]);
+        index.push_str(&fields);
+        index.push_str(&control[at..]);
+        index.push('\n');
+    }
+
+    Ok(index)
+}
+
+/// The digests of each file, in the order of the files, which are hashed in parallel.
+fn hash(paths: &[&Path]) -> Vec<io::Result<Digests>> {
+    paths.par_iter().map(|path| File::open(path).and_then(checksum::digests)).collect()
+}
+
+/// Writes the `Packages` index of an architecture of a component of the archive, at
+/// `<component>/binary-<arch>`, and its `Release` file.
+fn write_binary_index(config: &Config, archive: &str, path: &Path, index: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(path)?;
+    compress("Packages", path, index)?;
+
+    let name = |path: Option<&Path>| {
+        path.and_then(Path::file_name).map_or(String::new(), |x| x.to_string_lossy().into_owned())
+    };
+    let (arch, component) = (name(Some(path)), name(path.parent()));
+    let mut release = File::create(path.join("Release"))?;
+    writeln!(&mut release, "Archive: {}", archive)?;
+    writeln!(&mut release, "Version: {}", config.version)?;
+    writeln!(&mut release, "Component: {}", component)?;
+    writeln!(&mut release, "Origin: {}", config.origin)?;
+    writeln!(&mut release, "Label: {}", config.label)?;
+    writeln!(&mut release, "Architecture: {}", arch.trim_left_matches("binary-"))
+}
+
+/// Splits a `Packages` index into the stanzas of packages, and those of
+/// debug symbol packages.
+fn split_debug_symbols(index: &str) -> (String, String) {
+    let (mut packages, mut debug) = (String::new(), String::new());
+    for stanza in index.split("\n\n").filter(|stanza| !stanza.trim().is_empty()) {
+        let is_debug = stanza.lines()
+            .filter(|line| line.starts_with("Filename: "))
+            .any(|line| line.rsplit('/').next().map_or(false, pool::is_debug_symbols));
+
+        let output = if is_debug { &mut debug } else { &mut packages };
+        output.push_str(stanza.trim_right_matches('\n'));
+        output.push_str("\n\n");
+    }
+
+    (packages, debug)
+}
+
+/// Generates the `Sources` index of the component, from the source packages in its pool. A
+/// component without any has an empty index.
+pub(crate) fn generate_sources_index(
+    repo: &Path,
+    dist_base: &str,
+    pool_base: &str,
+    component: &str,
+) -> io::Result<()> {
+    info!("generating sources index of {}", component);
+    let path = repo.join(dist_base).join(component).join("source");
     fs::create_dir_all(&path)?;
 
-    let data = Command::new("apt-ftparchive")
-        .arg("sources")
-        .arg(PathBuf::from(pool_base).join("source"))
-        .output()
-        .map(|data| data.stdout)?;
+    let sources = PathBuf::from(pool_base).join(component).join("source");
+    let data = if repo.join(&sources).is_dir() {
+        Cmd::heavy("apt-ftparchive")
+            .arg("sources")
+            .arg(sources)
+            .current_dir(repo)
+            .output()
+            .map(|data| data.stdout)?
+    } else {
+        Vec::new()
+    };
 
     compress("Sources", &path, &data)
 }
@@ -80,104 +221,499 @@ fn compress(name: &str, path: &Path, data: &[u8]) -> io::Result<()> {
     io::copy(&mut compressor, &mut xz_file).map(|_| ())
 }
 
-/// Generates the dists release file via `apt-ftparchive`.
-pub(crate) fn generate_dists_release(config: &Config, base: &str) -> io::Result<()> {
+/// Generates a `Contents-<arch>.gz` index for each architecture of each component of the pool,
+/// which lists the packages that install each file. The packages are read in parallel, and a
+/// package which cannot be read is left out of the index, rather than failing the others.
+pub(crate) fn generate_contents(repo: &Path, dist_base: &str, pool: &PoolIndex) -> io::Result<()> {
+    info!("generating Contents indices");
+    for (component, section) in binary_sections(pool) {
+        let debs: Vec<&PoolFile> = pool.files()
+            .into_iter()
+            .filter(|file| file.component == component && file.section == section)
+            .filter(|file| file.path.extension().map_or(false, |ext| ext == "deb"))
+            .collect();
+
+        let listed: Vec<(&str, Vec<String>)> = debs.par_iter()
+            .filter_map(|file| match deb::paths(&file.path) {
+                Ok(paths) => Some((file.name.as_str(), paths)),
+                Err(why) => {
+                    warn!("leaving {} out of the Contents index: {}", file.path.display(), why);
+                    None
+                }
+            })
+            .collect();
+
+        let mut contents: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for &(name, ref paths) in &listed {
+            for path in paths {
+                let packages = contents.entry(path.as_str()).or_insert_with(Vec::new);
+                if !packages.contains(&name) {
+                    packages.push(name);
+                }
+            }
+        }
+
+        let branch = repo.join(dist_base).join(component);
+        fs::create_dir_all(&branch)?;
+        let path = branch.join(["Contents-", &section["binary-".len()..], ".gz"].concat());
+        let file = BufWriter::new(File::create(&path)?);
+        let mut compressor = GzEncoder::new(file, Compression::Best);
+        for (file, packages) in contents {
+            writeln!(&mut compressor, "{:<59} {}", file, packages.join(","))?;
+        }
+
+        compressor.finish()?.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Generates the dists release file in the `base` directory, which lists every index file under
+/// it, with its size and digests, for apt to verify the indices which it downloads with.
+pub(crate) fn generate_dists_release(config: &Config, base: &Path) -> io::Result<()> {
     info!("generating dists release files");
+    let release = release_file(config, base, now())?;
+    misc::write(base.join("Release"), release)
+}
+
+/// The `Release` file of the dists in the `base` directory, as of the date, in seconds since the
+/// Unix epoch.
+fn release_file(config: &Config, base: &Path, date: u64) -> io::Result<String> {
+    let suite = config.effective_suite(&config.archive);
+    // The archive is the codename, and its first alias the role of the suite, such as `stable`.
+    let name = suite.aliases.first().unwrap_or(&config.archive);
+    let description = config.release_description.clone().unwrap_or_else(|| {
+        format!("{} ({} {})", config.label, config.archive, config.version)
+    });
+
+    let mut release = String::new();
+    let mut field = |name: &str, value: &str| release.push_str(&[name, ": ", value, "\n"].concat());
+    field("Origin", &config.origin);
+    field("Label", &config.label);
+    field("Suite", name);
+    field("Version", &config.version);
+    field("Codename", &config.archive);
+    field("Date", &release_date(date));
+    if let Some(days) = config.release_valid_days {
+        field("Valid-Until", &release_date(date + days * 86_400));
+    }
+
+    if suite.not_automatic {
+        field("NotAutomatic", "yes");
+    }
+
+    if suite.but_automatic_upgrades {
+        field("ButAutomaticUpgrades", "yes");
+    }
+
+    field("Architectures", &suite.architectures.join(" "));
+    field("Components", &components(base)?.join(" "));
+    field("Description", &description);
+
+    let mut files = Vec::new();
+    for entry in WalkDir::new(base).min_depth(1).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry.map_err(io::Error::from)?;
+        let signed = entry.depth() == 1 && UNLISTED.iter().any(|&name| entry.file_name() == name);
+        if entry.file_type().is_file() && !signed {
+            files.push(entry.path().to_path_buf());
+        }
+    }
+
+    let paths: Vec<&Path> = files.iter().map(PathBuf::as_path).collect();
+    let sizes = paths.iter().map(|path| path.metadata().map(|meta| meta.len()));
+    let sizes = sizes.collect::<io::Result<Vec<u64>>>()?;
+    let digests = hash(&paths).into_iter().collect::<io::Result<Vec<Digests>>>()?;
+    let blocks: [(&str, fn(&Digests) -> &str); 3] = [
+        ("MD5Sum", |digests| &digests.md5),
+        ("SHA1", |digests| &digests.sha1),
+        ("SHA256", |digests| &digests.sha256),
+    ];
+
+    for &(name, digest) in &blocks {
+        release.push_str(name);
+        release.push_str(":\n");
+        for (i, path) in paths.iter().enumerate() {
+            let relative = path.strip_prefix(base).unwrap_or(path);
+            let digest = digest(&digests[i]);
+            release.push_str(&format!(" {} {:>16} {}\n", digest, sizes[i], relative.display()));
+        }
+    }
+
+    Ok(release)
+}
+
+/// The `Packages` indices of every architecture of every component of the dists of the archive,
+/// of which there are none before it is first published.
+pub(crate) fn packages_indices(repo: &Path, archive: &str) -> io::Result<Vec<PathBuf>> {
+    let base = repo.join("dists").join(archive);
+    let mut indices = Vec::new();
+    if !base.is_dir() {
+        return Ok(indices);
+    }
+
+    for component in components(&base)? {
+        let dir = base.join(component);
+        if !dir.is_dir() {
+            continue;
+        }
 
-    let cwd = env::current_dir()?;
-    env::set_current_dir(base)?;
-
-    let release = Command::new("apt-ftparchive")
-        .arg("-o")
-        .arg(format!(
-            "APT::FTPArchive::Release::Origin={}",
-            config.origin
-        ))
-        .arg("-o")
-        .arg(format!("APT::FTPArchive::Release::Label={}", config.label))
-        .arg("-o")
-        .arg(format!(
-            "APT::FTPArchive::Release::Suite={}",
-            config.archive
-        ))
-        .arg("-o")
-        .arg(format!(
-            "APT::FTPArchive::Release::Version={}",
-            config.version
-        ))
-        .arg("-o")
-        .arg(format!(
-            "APT::FTPArchive::Release::Codename={}",
-            config.archive
-        ))
-        .arg("-o")
-        .arg("APT::FTPArchive::Release::Architectures=i386 amd64 all")
-        .arg("-o")
-        .arg("APT::FTPArchive::Release::Components=main")
-        .arg("-o")
-        .arg(format!(
-            "APT::FTPArchive::Release::Description={} ({} {})",
-            config.label, config.archive, config.version
-        ))
-        .arg("release")
-        .arg(".")
-        .output()
-        .map(|data| data.stdout)?;
-
-    let mut release_file = File::create("Release")?;
-    release_file.write_all(&release)?;
-    env::set_current_dir(cwd)
-}
-
-/// Generates the `InRelease` file from the `Release` file via `gpg --clearsign`.
-pub(crate) fn gpg_in_release(email: &str, release_path: &Path, out_path: &Path) -> io::Result<()> {
+        for entry in dir.read_dir()? {
+            let index = entry?.path().join("Packages");
+            if index.is_file() {
+                indices.push(index);
+            }
+        }
+    }
+
+    indices.sort();
+    Ok(indices)
+}
+
+/// The components which the dists in the `base` directory have indices of, in order, or the
+/// default component if there are none yet.
+pub(crate) fn components(base: &Path) -> io::Result<Vec<String>> {
+    let mut components = Vec::new();
+    if base.is_dir() {
+        for entry in base.read_dir()? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                components.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    if components.is_empty() {
+        components.push(DEFAULT_COMPONENT.to_owned());
+    }
+
+    components.sort();
+    Ok(components)
+}
+
+/// The date of the time, in seconds since the Unix epoch, as the `Date` of a `Release` file,
+/// such as `Tue, 16 Oct 2018 12:00:00 UTC`.
+fn release_date(seconds: u64) -> String {
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    // The epoch was on a Thursday.
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+    let date = keys::date(seconds);
+    let month = date[5..7].parse::<usize>().unwrap_or(1);
+    let time = seconds % 86_400;
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} UTC",
+        DAYS[(seconds / 86_400 % 7) as usize],
+        &date[8..],
+        MONTHS[month - 1],
+        &date[..4],
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Points `dists/<alias>` at the dists of the suite, for each of its aliases, replacing a link
+/// which points elsewhere, or a copy of older dists. Where links cannot be created, the dists are
+/// copied instead. Links to the suite under names which are no longer aliases are removed.
+pub(crate) fn link_aliases(dists: &Path, suite: &str, aliases: &[String]) -> io::Result<()> {
+    for entry in dists.read_dir()? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let links_suite = fs::read_link(entry.path()).ok().map_or(false, |x| x == Path::new(suite));
+        if links_suite && !aliases.contains(&name) {
+            info!("removing dists/{}, which is no longer an alias of {}", name, suite);
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    for alias in aliases {
+        let path = dists.join(alias);
+        if fs::read_link(&path).ok().map_or(false, |x| x == Path::new(suite)) {
+            continue;
+        }
+
+        let temporary = dists.join([".", alias, ".partial"].concat());
+        remove(&temporary)?;
+        if let Err(why) = symlink(suite, &temporary) {
+            warn!("unable to link dists/{} to {}, so it is copied instead: {}", alias, suite, why);
+            copy_tree(&dists.join(suite), &temporary)?;
+        }
+
+        // A directory cannot be renamed over.
+        if fs::symlink_metadata(&path).map(|meta| meta.is_dir()).unwrap_or(false) {
+            fs::remove_dir_all(&path)?;
+        }
+
+        fs::rename(&temporary, &path)?;
+        info!("dists/{} is an alias of {}", alias, suite);
+    }
+
+    Ok(())
+}
+
+/// Removes the file, link, or directory, if it exists.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(why) => Err(why),
+    }
+}
+
+fn copy_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(src) {
+        let entry = entry.map_err(io::Error::from)?;
+        let path = dst.join(entry.path().strip_prefix(src).unwrap_or(entry.path()));
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&path)?;
+        } else {
+            fs::copy(entry.path(), &path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Generates the `InRelease` file from the `Release` file via `gpg --clearsign`, with a
+/// signature of each signer.
+pub(crate) fn gpg_in_release(
+    signers: &[&str],
+    release_path: &Path,
+    out_path: &Path,
+) -> io::Result<()> {
     info!("generating InRelease file");
-    let exit_status = Command::new("gpg")
-        .args(&[
-            "--clearsign",
-            "--local-user",
-            email,
-            "--batch",
-            "--yes",
-            "--digest-algo",
-            "sha512",
-            "-o",
-        ])
+    Cmd::new("gpg")
+        .arg("--clearsign")
+        .args(&local_users(signers))
+        .args(&["--batch", "--yes", "--digest-algo", "sha512", "-o"])
         .arg(out_path)
         .arg(release_path)
-        .status()?;
-
-    if exit_status.success() {
-        Ok(())
-    } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "gpg_in_release failed",
-        ))
-    }
+        .run()
+        .map_err(io::Error::from)
 }
 
-/// Generates the `Release.gpg` file from the `Release` file via `gpg -abs`
-pub(crate) fn gpg_release(email: &str, release_path: &Path, out_path: &Path) -> io::Result<()> {
+/// Generates the `Release.gpg` file from the `Release` file via `gpg --detach-sign`, with a
+/// signature of each signer.
+pub(crate) fn gpg_release(
+    signers: &[&str],
+    release_path: &Path,
+    out_path: &Path,
+) -> io::Result<()> {
     info!("generating Release.gpg file");
-    let exit_status = Command::new("gpg")
-        .args(&[
-            "-abs",
-            "--local-user",
-            email,
-            "--batch",
-            "--yes",
-            "--digest-algo",
-            "sha512",
-            "-o",
-        ])
+    Cmd::new("gpg")
+        .args(&["--detach-sign", "--armor"])
+        .args(&local_users(signers))
+        .args(&["--batch", "--yes", "--digest-algo", "sha512", "-o"])
         .arg(out_path)
         .arg(release_path)
-        .status()?;
+        .run()
+        .map_err(io::Error::from)
+}
 
-    if exit_status.success() {
-        Ok(())
-    } else {
-        Err(io::Error::new(io::ErrorKind::Other, "gpg_release failed"))
+fn local_users<'a>(signers: &[&'a str]) -> Vec<&'a str> {
+    signers.iter().flat_map(|&signer| vec!["--local-user", signer]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use super::super::deb::tests::{deb, tar};
+    use tempdir::TempDir;
+
+    #[test]
+    fn aliases_link_to_the_dists_of_their_suite() {
+        let dir = TempDir::new("debrep-aliases").unwrap();
+        let dists = dir.path();
+        fs::create_dir_all(dists.join("bionic")).unwrap();
+        fs::create_dir_all(dists.join("cosmic")).unwrap();
+        misc::write(dists.join("bionic/Release"), "Codename: bionic\n").unwrap();
+        symlink("cosmic", dists.join("stable")).unwrap();
+        symlink("bionic", dists.join("old")).unwrap();
+
+        // A copy which was made where links could not be created is replaced too.
+        copy_tree(&dists.join("bionic"), &dists.join("lts")).unwrap();
+        let aliases = vec!["stable".to_owned(), "lts".to_owned()];
+        link_aliases(dists, "bionic", &aliases).unwrap();
+
+        for alias in &aliases {
+            assert_eq!(fs::read_link(dists.join(alias)).unwrap(), Path::new("bionic"));
+            let release = misc::read_to_string(dists.join(alias).join("Release")).unwrap();
+            assert_eq!(release, "Codename: bionic\n");
+        }
+
+        assert!(fs::symlink_metadata(dists.join("old")).is_err());
+        assert!(dists.join("cosmic").is_dir());
+    }
+
+    #[test]
+    fn debug_symbols_are_split_from_the_index() {
+        let index = "Package: pop\n\
+                     Filename: pool/bionic/main/binary-amd64/p/pop/pop_1.0_amd64.deb\n\n\
+                     Package: pop-dbgsym\n\
+                     Filename: pool/bionic/main/binary-amd64/p/pop/pop-dbgsym_1.0_amd64.ddeb\n\n";
+        let (packages, debug) = split_debug_symbols(index);
+        assert!(packages.starts_with("Package: pop\n") && !packages.contains("dbgsym"));
+        assert!(debug.starts_with("Package: pop-dbgsym\n") && debug.ends_with(".ddeb\n\n"));
+    }
+
+    #[test]
+    fn packages_are_indexed_from_their_control_files() {
+        let dir = TempDir::new("debrep-packages").unwrap();
+        let repo = dir.path().join("repo");
+        let pool = repo.join("pool/bionic/main");
+        let controls = [
+            ("pop", "Package: pop\nVersion: 1.0\nArchitecture: arm64\nDescription: pop\n Pop!\n"),
+            ("pop-data", "Package: pop-data\nVersion: 1.0\nArchitecture: all\n"),
+            ("broken", ""),
+        ];
+
+        for &(package, control) in &controls {
+            let path = pool.join("binary-arm64/p").join(package);
+            fs::create_dir_all(&path).unwrap();
+            let deb = if control.is_empty() {
+                b"!<arch>\n".to_vec()
+            } else {
+                deb("control.tar", &tar(&[("./control", b'0', control.as_bytes())]))
+            };
+            misc::write(path.join([package, "_1.0_arm64.deb"].concat()), deb).unwrap();
+        }
+
+        let index = PoolIndex::open(pool.parent().unwrap()).unwrap();
+        let debs = index.files();
+        let packages = packages_index(&repo, &debs).unwrap();
+        let stanzas: Vec<&str> = packages.split("\n\n").filter(|x| !x.is_empty()).collect();
+        assert_eq!(stanzas.len(), 2);
+
+        // The fields are listed before the description, or last where there is none.
+        let lines: Vec<&str> = stanzas[0].lines().collect();
+        assert_eq!(&lines[..4], &["Package: pop", "Version: 1.0", "Architecture: arm64",
+            "Filename: pool/bionic/main/binary-arm64/p/pop/pop_1.0_arm64.deb"]);
+        let size = fs::metadata(&debs[1].path).unwrap().len();
+        assert_eq!(lines[4], format!("Size: {}", size));
+        let digests = checksum::digests(File::open(&debs[1].path).unwrap()).unwrap();
+        assert_eq!(lines[5], format!("MD5sum: {}", digests.md5));
+        assert_eq!(lines[6], format!("SHA1: {}", digests.sha1));
+        assert_eq!(lines[7], format!("SHA256: {}", digests.sha256));
+        assert_eq!(&lines[8..], &["Description: pop", " Pop!"]);
+        assert!(stanzas[1].starts_with("Package: pop-data\n"));
+        assert!(stanzas[1].lines().last().unwrap().starts_with("SHA256: "));
+
+        let path = repo.join("dists/bionic/main/binary-arm64");
+        let config: Config = ::toml::from_str(
+            "archive = \"bionic\"\nversion = \"18.04\"\norigin = \"pop-os\"\nlabel = \"Pop!_OS\"\n\
+             email = \"debrepbuild@example.com\"\n"
+        ).unwrap();
+        write_binary_index(&config, "bionic", &path, packages.as_bytes()).unwrap();
+        let release = misc::read_to_string(path.join("Release")).unwrap();
+        assert!(release.ends_with("Architecture: arm64\n"));
+        assert_eq!(misc::read_to_string(path.join("Packages")).unwrap(), packages);
+    }
+
+    #[test]
+    fn components_of_the_suite_have_indices_before_they_have_packages() {
+        let dir = TempDir::new("debrep-components").unwrap();
+        let pool = dir.path().join("pool/stable/main/binary-amd64/p/pop");
+        fs::create_dir_all(&pool).unwrap();
+        let control = "Package: pop\nVersion: 1.0\nArchitecture: amd64\n";
+        let deb = deb("control.tar", &tar(&[("./control", b'0', control.as_bytes())]));
+        misc::write(pool.join("pop_1.0_amd64.deb"), deb).unwrap();
+
+        let config: Config = ::toml::from_str(
+            "archive = \"stable\"\nversion = \"1\"\norigin = \"o\"\nlabel = \"l\"\nemail = \"e\"\n\
+             [suites.stable]\narchitectures = [\"amd64\", \"arm64\"]\n\
+             components = [\"main\", \"contrib\"]\n"
+        ).unwrap();
+        let index = PoolIndex::open(&dir.path().join("pool/stable")).unwrap();
+        generate_binary_files(&config, dir.path(), "dists/stable", &index, &[]).unwrap();
+
+        let base = dir.path().join("dists/stable");
+        assert_eq!(components(&base).unwrap(), vec!["contrib".to_owned(), "main".to_owned()]);
+        for arch in &["binary-amd64", "binary-arm64"] {
+            assert_eq!(misc::read(base.join("contrib").join(arch).join("Packages")).unwrap(), b"");
+        }
+
+        // Components with packages only have indices of the architectures which they have.
+        let packages = misc::read_to_string(base.join("main/binary-amd64/Packages")).unwrap();
+        assert!(packages.starts_with("Package: pop\n"), "{}", packages);
+        assert!(!base.join("main/binary-arm64").exists());
+    }
+
+    #[test]
+    fn release_files_list_the_indices_of_the_dists() {
+        let dir = TempDir::new("debrep-release").unwrap();
+        let base = dir.path().join("bionic");
+        fs::create_dir_all(base.join("main/binary-amd64")).unwrap();
+        misc::write(base.join("main/binary-amd64/Packages"), "Package: pop\n\n").unwrap();
+        for name in UNLISTED {
+            misc::write(base.join(name), "stale\n").unwrap();
+        }
+
+        let config: Config = ::toml::from_str(
+            "archive = \"bionic\"\nversion = \"18.04\"\norigin = \"pop-os\"\nlabel = \"Pop!_OS\"\n\
+             email = \"debrepbuild@example.com\"\narchitectures = [\"amd64\", \"all\"]\n\
+             release_valid_days = 7\n"
+        ).unwrap();
+        let release = release_file(&config, &base, 1_539_691_200).unwrap();
+        assert_eq!(
+            release,
+            "Origin: pop-os\n\
+             Label: Pop!_OS\n\
+             Suite: bionic\n\
+             Version: 18.04\n\
+             Codename: bionic\n\
+             Date: Tue, 16 Oct 2018 12:00:00 UTC\n\
+             Valid-Until: Tue, 23 Oct 2018 12:00:00 UTC\n\
+             Architectures: amd64 all\n\
+             Components: main\n\
+             Description: Pop!_OS (bionic 18.04)\n\
+             MD5Sum:\n \
+             30533b6d9a3cc6599de1284d272d6b72               14 main/binary-amd64/Packages\n\
+             SHA1:\n \
+             ec7130985a3e9b62f32dde4d940dd06e4d847911               14 main/binary-amd64/Packages\n\
+             SHA256:\n \
+             c063113b5f9e4cfdd1d4813ea469faa0ab0fc514b6758f17870cbdfd3ff2f92f               14 \
+             main/binary-amd64/Packages\n"
+        );
+    }
+
+    #[test]
+    fn contents_list_the_packages_of_each_file() {
+        let dir = TempDir::new("debrep-contents").unwrap();
+        let pool = dir.path().join("pool");
+        let packages = [
+            ("pop_1.0_amd64.deb", &[("./usr/bin/pop", b'0'), ("./usr/share/pop", b'0')][..]),
+            ("pop-extra_1.0_amd64.deb", &[("./usr/share/pop", b'0')][..]),
+        ];
+
+        for &(name, entries) in &packages {
+            let package = &name[..name.find('_').unwrap()];
+            let path = pool.join("main/binary-amd64/p").join(package);
+            fs::create_dir_all(&path).unwrap();
+            let entries: Vec<(&str, u8, &[u8])> =
+                entries.iter().map(|&(path, kind)| (path, kind, &b""[..])).collect();
+            misc::write(path.join(name), deb("data.tar", &tar(&entries))).unwrap();
+        }
+
+        let broken = pool.join("main/binary-amd64/b/broken");
+        fs::create_dir_all(&broken).unwrap();
+        misc::write(broken.join("broken_1.0_amd64.deb"), b"!<arch>\n").unwrap();
+
+        let index = PoolIndex::open(&pool).unwrap();
+        generate_contents(dir.path(), "dists", &index).unwrap();
+
+        let file = File::open(dir.path().join("dists/main/Contents-amd64.gz")).unwrap();
+        let mut contents = String::new();
+        GzDecoder::new(file).read_to_string(&mut contents).unwrap();
+        let expected =
+            format!("{:<59} pop\n{:<59} pop,pop-extra\n", "usr/bin/pop", "usr/share/pop");
+        assert_eq!(contents, expected);
     }
 }
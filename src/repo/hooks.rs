@@ -0,0 +1,111 @@
+//! The commands which the config runs at stages of a run, as `[[hook]]` entries, for what each
+//! site needs done at that point, such as updating a CMDB, or purging a CDN.
+//!
+//! Each hook of a stage is run in the order of the config, with `sh -c`, and what it writes is
+//! logged to the log of the run. A hook which fails only logs a warning, unless its `on_failure`
+//! is `fail`, in which case the stage fails, and the hooks after it are not run.
+
+use command::{Cmd, CommandError};
+use config::{Config, HookFailure, HookStage};
+use super::run;
+
+/// How long a hook may run for when its `timeout` is unset: ten minutes.
+const DEFAULT_TIMEOUT: u64 = 600;
+
+#[derive(Debug, Fail)]
+#[fail(display = "the {} hook failed: {}", stage, why)]
+pub struct HookError {
+    pub stage: &'static str,
+    pub why: CommandError,
+}
+
+/// Runs the hooks of the stage, with `DEBREP_STAGE`, `DEBREP_SUITE`, and `DEBREP_RUN` set, in
+/// addition to the variables which are given.
+pub fn run(config: &Config, stage: HookStage, vars: &[(&str, &str)]) -> Result<(), HookError> {
+    let hooks = config.hook.iter()
+        .flat_map(|hooks| hooks.iter())
+        .filter(|hook| hook.stage == stage);
+
+    for hook in hooks {
+        let run = run::current().map_or(String::new(), |run| run.id.clone());
+        let mut command = Cmd::new("sh");
+        command.args(&["-c", &hook.command])
+            .env("DEBREP_STAGE", stage.as_str())
+            .env("DEBREP_SUITE", &config.archive)
+            .env("DEBREP_RUN", &run)
+            .timeout(hook.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        for &(key, value) in vars {
+            command.env(key, value);
+        }
+
+        debug!("running the {} hook: {}", stage.as_str(), hook.command);
+        let why = match command.output() {
+            Ok(output) => {
+                let written = [output.stdout, output.stderr].concat();
+                for line in String::from_utf8_lossy(&written).lines() {
+                    info!("{} hook: {}", stage.as_str(), line);
+                }
+
+                continue;
+            }
+            Err(why) => why,
+        };
+
+        let failed = HookError { stage: stage.as_str(), why };
+        match hook.on_failure.unwrap_or(HookFailure::Warn) {
+            HookFailure::Warn => warn!("{}", failed),
+            HookFailure::Fail => return Err(failed),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use tempdir::TempDir;
+    use toml;
+
+    fn config(hooks: &str) -> Config {
+        let base = "archive = \"stable\"\nversion = \"1\"\norigin = \"o\"\nlabel = \"l\"\n\
+                    email = \"e\"\n";
+        toml::from_str(&[base, hooks].concat()).unwrap()
+    }
+
+    #[test]
+    fn hooks_of_the_stage_run_with_its_variables() {
+        let dir = TempDir::new("debrep-hooks").unwrap();
+        let out = dir.path().join("out");
+        let config = config(&format!(
+            "[[hook]]\nstage = \"post_build\"\ncommand = \"echo $DEBREP_SUITE $DEBREP_PACKAGE \
+             $DEBREP_STATUS >> {0}\"\n\n[[hook]]\nstage = \"post_publish\"\ncommand = \"echo \
+             published >> {0}\"\n",
+            out.display()
+        ));
+
+        let vars = [("DEBREP_PACKAGE", "pop-shop"), ("DEBREP_STATUS", "built")];
+        run(&config, HookStage::PostBuild, &vars).unwrap();
+        run(&config, HookStage::PreBuild, &vars).unwrap();
+        assert_eq!(misc::read_to_string(&out).unwrap(), "stable pop-shop built\n");
+    }
+
+    #[test]
+    fn failed_hooks_only_fail_the_stage_if_configured_to() {
+        let warns = config("[[hook]]\nstage = \"post_pool\"\ncommand = \"exit 3\"\n");
+        assert!(run(&warns, HookStage::PostPool, &[]).is_ok());
+
+        let fails = config(
+            "[[hook]]\nstage = \"post_pool\"\ncommand = \"exit 3\"\non_failure = \"fail\"\n",
+        );
+        let why = run(&fails, HookStage::PostPool, &[]).unwrap_err();
+        assert_eq!(why.stage, "post_pool");
+
+        let slow = config(
+            "[[hook]]\nstage = \"post_metadata\"\ncommand = \"sleep 5\"\ntimeout = 1\n\
+             on_failure = \"fail\"\n",
+        );
+        assert!(run(&slow, HookStage::PostMetadata, &[]).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! JUnit XML reports of runs, for `--report-junit`, which CI servers such as Jenkins and GitLab
+//! render natively. Each package which a run built, skipped, or failed to build is a test case, in
+//! a test suite for each suite of the repo. The class name of a case is `<suite>.<arch>`, and its
+//! name is the package.
+//!
+//! The cases are collected by the run as each package finishes, and the report is written when
+//! the run ends, even if it was interrupted, so that what was built before is still shown.
+
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use misc;
+
+/// The most bytes of the text of a failure. Longer texts keep their end, where builds fail.
+const MAX_TEXT: usize = 64 * 1024;
+
+/// What became of the package.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CaseResult {
+    Passed,
+    /// Skipped for the reason, such as that it was already built.
+    Skipped(String),
+    /// Failed with the error as its message, and the excerpt of its log, or the error, as its
+    /// text.
+    Failed { message: String, text: String },
+    /// The run was interrupted while the package was built, which is an error of the case.
+    Interrupted,
+}
+
+/// The build of a package for a suite on an architecture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestCase {
+    pub suite: String,
+    pub package: String,
+    pub architecture: String,
+    pub seconds: f64,
+    pub result: CaseResult,
+}
+
+/// Writes the report of the cases to the path.
+pub fn write(path: &Path, name: &str, cases: &[TestCase]) -> io::Result<()> {
+    misc::write(path, render(name, cases))
+}
+
+/// The JUnit XML document of the cases, with a test suite for each suite, in the order in which
+/// their first cases were added.
+pub fn render(name: &str, cases: &[TestCase]) -> String {
+    let mut suites: Vec<&str> = Vec::new();
+    for case in cases {
+        if !suites.contains(&case.suite.as_str()) {
+            suites.push(&case.suite);
+        }
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(xml, "<testsuites name=\"{}\" {}>", escape(name), counts(cases.iter()));
+    for suite in suites {
+        let of_suite = || cases.iter().filter(move |case| case.suite == suite);
+        let _ = writeln!(xml, "  <testsuite name=\"{}\" {}>", escape(suite), counts(of_suite()));
+        for case in of_suite() {
+            let _ = write!(
+                xml,
+                "    <testcase classname=\"{}.{}\" name=\"{}\" time=\"{:.3}\"",
+                escape(&case.suite),
+                escape(&case.architecture),
+                escape(&case.package),
+                case.seconds
+            );
+
+            match case.result {
+                CaseResult::Passed => xml.push_str("/>\n"),
+                CaseResult::Skipped(ref reason) => {
+                    let _ = writeln!(xml, ">\n      <skipped message=\"{}\"/>", escape(reason));
+                    xml.push_str("    </testcase>\n");
+                }
+                CaseResult::Failed { ref message, ref text } => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <failure message=\"{}\">{}</failure>",
+                        escape(message),
+                        escape(&truncate(text, MAX_TEXT))
+                    );
+                    xml.push_str("    </testcase>\n");
+                }
+                CaseResult::Interrupted => {
+                    xml.push_str(">\n      <error message=\"the run was interrupted\"/>\n");
+                    xml.push_str("    </testcase>\n");
+                }
+            }
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// The attributes which count the cases, and the time which they took.
+fn counts<'a, I: Iterator<Item = &'a TestCase>>(cases: I) -> String {
+    let (mut tests, mut failures, mut errors, mut skipped, mut time) = (0, 0, 0, 0, 0.0);
+    for case in cases {
+        tests += 1;
+        time += case.seconds;
+        match case.result {
+            CaseResult::Passed => (),
+            CaseResult::Skipped(_) => skipped += 1,
+            CaseResult::Failed { .. } => failures += 1,
+            CaseResult::Interrupted => errors += 1,
+        }
+    }
+
+    format!(
+        "tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\"",
+        tests, failures, errors, skipped, time
+    )
+}
+
+/// Escapes the text for an attribute or an element, leaving out the characters which XML 1.0
+/// does not allow, such as the escape sequences of colored output.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            '\u{fffe}' | '\u{ffff}' => (),
+            c if c < ' ' => (),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// The end of the text, of at most `max` bytes, after a line which says how much was left out.
+fn truncate(text: &str, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_owned();
+    }
+
+    let mut start = text.len() - max;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+
+    format!("[{} bytes were left out]\n{}", start, &text[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(suite: &str, package: &str, result: CaseResult) -> TestCase {
+        TestCase {
+            suite: suite.to_owned(),
+            package: package.to_owned(),
+            architecture: "amd64".to_owned(),
+            seconds: 1.5,
+            result,
+        }
+    }
+
+    #[test]
+    fn each_suite_is_a_test_suite_of_its_packages() {
+        let failure = CaseResult::Failed {
+            message: "build failed for <pop-shop>".to_owned(),
+            text: "first error:\n    \x1b[31merror\x1b[0m: expected `&str`\u{0}\n".to_owned(),
+        };
+        let cases = vec![
+            case("bionic", "pop-shop", failure),
+            case("cosmic", "pop-icons", CaseResult::Passed),
+            case("bionic", "pop-icons", CaseResult::Skipped("already built".to_owned())),
+            case("bionic", "pop-desktop", CaseResult::Interrupted),
+        ];
+
+        assert_eq!(
+            render("debrep run 1-abcdef", &cases),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuites name=\"debrep run 1-abcdef\" tests=\"4\" failures=\"1\" errors=\"1\" \
+             skipped=\"1\" time=\"6.000\">\n  \
+             <testsuite name=\"bionic\" tests=\"3\" failures=\"1\" errors=\"1\" skipped=\"1\" \
+             time=\"4.500\">\n    \
+             <testcase classname=\"bionic.amd64\" name=\"pop-shop\" time=\"1.500\">\n      \
+             <failure message=\"build failed for &lt;pop-shop&gt;\">first error:\n    \
+             [31merror[0m: expected `&amp;str`\n</failure>\n    \
+             </testcase>\n    \
+             <testcase classname=\"bionic.amd64\" name=\"pop-icons\" time=\"1.500\">\n      \
+             <skipped message=\"already built\"/>\n    \
+             </testcase>\n    \
+             <testcase classname=\"bionic.amd64\" name=\"pop-desktop\" time=\"1.500\">\n      \
+             <error message=\"the run was interrupted\"/>\n    \
+             </testcase>\n  \
+             </testsuite>\n  \
+             <testsuite name=\"cosmic\" tests=\"1\" failures=\"0\" errors=\"0\" skipped=\"0\" \
+             time=\"1.500\">\n    \
+             <testcase classname=\"cosmic.amd64\" name=\"pop-icons\" time=\"1.500\"/>\n  \
+             </testsuite>\n\
+             </testsuites>\n"
+        );
+
+        assert!(render("empty", &[]).ends_with("tests=\"0\" failures=\"0\" errors=\"0\" \
+                                               skipped=\"0\" time=\"0.000\">\n</testsuites>\n"));
+    }
+
+    #[test]
+    fn long_texts_keep_their_end() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("0123456789", 4), "[6 bytes were left out]\n6789");
+        assert_eq!(truncate("ééé", 3), "[4 bytes were left out]\né");
+    }
+}
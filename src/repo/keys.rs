@@ -0,0 +1,397 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use command::{Cmd, CommandError};
+use config::{Config, Key};
+use misc;
+use reqwest;
+use retry;
+use tempdir::TempDir;
+
+/// Each configured key is kept in its own keyring, at `keys/<name>.gpg`.
+pub const KEYS: &str = "keys";
+
+#[derive(Debug, Fail)]
+pub enum KeyError {
+    #[fail(display = "no key named {} is configured", name)]
+    Unknown { name: String },
+    #[fail(display = "key {} has neither a keyserver or URL to fetch it from", name)]
+    NoSource { name: String },
+    #[fail(display = "failed to fetch key {}: {}", name, why)]
+    Fetch { name: String, why: io::Error },
+    #[fail(display = "failed to fetch key {}: {}", name, why)]
+    Gpg { name: String, why: CommandError },
+    #[fail(display = "failed to download key {} from {}: {}", name, url, why)]
+    Download { name: String, url: String, why: reqwest::Error },
+    #[fail(display = "key {} does not have the fingerprint {}; found {}", name, expected, found)]
+    Mismatch { name: String, expected: String, found: String },
+    #[fail(display = "failed to read the keyring of {}: {}", name, why)]
+    Keyring { name: String, why: io::Error },
+}
+
+/// A key within a keyring, from the output of `gpg --with-colons`.
+#[derive(Debug, PartialEq)]
+pub struct KeyInfo {
+    pub fingerprint: String,
+    /// When the key expires, in seconds since the Unix epoch.
+    pub expires: Option<u64>,
+    pub user_id: Option<String>,
+}
+
+impl KeyInfo {
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+
+    /// When the key expires, as `YYYY-MM-DD`, or `never`.
+    pub fn expiry(&self) -> String {
+        self.expires.map_or_else(|| "never".to_owned(), date)
+    }
+}
+
+/// The key which signed the `InRelease` file of a suite.
+#[derive(Debug, PartialEq)]
+pub enum Signer {
+    Known(KeyInfo),
+    /// The public key is not in the keyring, so only its key ID is known.
+    Unknown(String),
+}
+
+/// Checks that the secret key which signs the dists is in the default keyring, and has not
+/// expired. Returns why it cannot sign otherwise.
+pub fn check_signer(signer: &str) -> Result<(), String> {
+    check_secret_key(signer, None)
+}
+
+/// Checks that the secret key is in the keyring of the GnuPG home, or else the default keyring,
+/// and has not expired.
+pub fn check_secret_key(signer: &str, home: Option<&Path>) -> Result<(), String> {
+    let mut command = Cmd::new("gpg");
+    command.args(&["--batch", "--with-colons", "--list-secret-keys", signer]);
+    if let Some(home) = home {
+        command.env("GNUPGHOME", home);
+    }
+
+    let output = command.output().map_err(|why| format!("could not be looked up: {}", why))?;
+
+    let keys = parse_keys(&String::from_utf8_lossy(&output.stdout));
+    match keys.iter().find(|key| !key.is_expired()) {
+        Some(_) => Ok(()),
+        None => match keys.first() {
+            Some(key) => Err(format!("expired on {}", key.expiry())),
+            None => Err("is not a secret key of the keyring".to_owned()),
+        },
+    }
+}
+
+/// The keys which signed the `InRelease` file, from the default keyring.
+pub fn signers(in_release: &Path) -> io::Result<Vec<Signer>> {
+    // A signature which cannot be verified still reports its key.
+    let output = Cmd::new("gpg")
+        .args(&["--batch", "--status-fd", "1", "--verify"])
+        .arg(in_release)
+        .output()
+        .map_err(io::Error::from)?;
+
+    let mut signers = Vec::new();
+    for (id, known) in parse_signatures(&String::from_utf8_lossy(&output.stdout)) {
+        let listed = if known {
+            Cmd::new("gpg")
+                .args(&["--batch", "--with-colons", "--list-keys", &id])
+                .output()
+                .ok()
+                .and_then(|output| {
+                    parse_keys(&String::from_utf8_lossy(&output.stdout)).into_iter().next()
+                })
+        } else {
+            None
+        };
+
+        signers.push(listed.map_or(Signer::Unknown(id), Signer::Known));
+    }
+
+    Ok(signers)
+}
+
+/// The primary fingerprints of the keys of valid signatures in the output of `gpg --status-fd`,
+/// and the key IDs of signatures whose key is not known, with whether the key is known.
+fn parse_signatures(status: &str) -> Vec<(String, bool)> {
+    status.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.get(1).cloned() {
+                // The primary fingerprint is last, but older versions of gpg only list the
+                // fingerprint of the signing key.
+                Some("VALIDSIG") => fields.get(11).or_else(|| fields.get(2))
+                    .map(|fingerprint| ((*fingerprint).to_owned(), true)),
+                Some("ERRSIG") => fields.get(2).map(|id| ((*id).to_owned(), false)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// The date of the time, in seconds since the Unix epoch, as `YYYY-MM-DD`.
+pub fn date(seconds: u64) -> String {
+    // The civil date of days since the epoch, from Howard Hinnant's `civil_from_days`.
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// The keyring which holds the key of the given name.
+pub fn keyring_path(root: &Path, name: &str) -> PathBuf {
+    root.join(KEYS).join([name, ".gpg"].concat())
+}
+
+fn find<'a>(config: &'a Config, name: &str) -> Result<&'a Key, KeyError> {
+    config.keys.as_ref()
+        .and_then(|keys| keys.iter().find(|key| key.name == name))
+        .ok_or_else(|| KeyError::Unknown { name: name.to_owned() })
+}
+
+/// Resolves the keyring of a configured key, fetching the key on first use. Features which verify
+/// signatures should locate their keys through this.
+pub fn resolve(config: &Config, root: &Path, name: &str) -> Result<PathBuf, KeyError> {
+    resolve_key(root, find(config, name)?)
+}
+
+/// Resolves the keyring of a key which is not in the config, such as one which is implied by
+/// another part of it, in the same way as `resolve`.
+pub fn resolve_key(root: &Path, key: &Key) -> Result<PathBuf, KeyError> {
+    let keyring = keyring_path(root, &key.name);
+    if !keyring.exists() {
+        fetch(key, &keyring)?;
+    }
+
+    verify(key, &keyring)?;
+    Ok(keyring)
+}
+
+/// Fetches a key into its keyring, replacing it if it already exists. The key is only stored if
+/// it has the configured fingerprint.
+pub fn fetch_named(config: &Config, root: &Path, name: &str) -> Result<PathBuf, KeyError> {
+    let key = find(config, name)?;
+    let keyring = keyring_path(root, name);
+    fetch(key, &keyring)?;
+    Ok(keyring)
+}
+
+fn fetch(key: &Key, keyring: &Path) -> Result<(), KeyError> {
+    let fetch_error = |why: io::Error| KeyError::Fetch { name: key.name.clone(), why };
+    let gpg_error = |why: CommandError| KeyError::Gpg { name: key.name.clone(), why };
+    let home = TempDir::new("debrep-gpg").map_err(&fetch_error)?;
+    let staging = home.path().join("staging.gpg");
+
+    match (key.keyserver.as_ref(), key.url.as_ref()) {
+        (Some(keyserver), _) => {
+            info!("fetching key {} from {}", key.name, keyserver);
+            retry::network(keyserver, || {
+                gpg(home.path(), &staging)
+                    .args(&["--keyserver", keyserver, "--recv-keys", &key.fingerprint])
+                    .run()
+            }).map_err(&gpg_error)?;
+        }
+        (None, Some(url)) => {
+            info!("fetching key {} from {}", key.name, url);
+            let download = home.path().join("download");
+            let mut file = File::create(&download).map_err(&fetch_error)?;
+            let request = || {
+                reqwest::get(url.as_str()).and_then(|response| response.error_for_status())
+            };
+            retry::network(url, request)
+                .and_then(|mut response| response.copy_to(&mut file))
+                .map_err(|why| KeyError::Download {
+                    name: key.name.clone(),
+                    url: url.clone(),
+                    why
+                })?;
+
+            gpg(home.path(), &staging).arg("--import").arg(&download).run()
+                .map_err(&gpg_error)?;
+        }
+        (None, None) => return Err(KeyError::NoSource { name: key.name.clone() }),
+    }
+
+    // Refuse to store anything unless the pinned key was among what was fetched.
+    let found = list(home.path(), &staging).map_err(&fetch_error)?;
+    if !found.iter().any(|info| same_fingerprint(&info.fingerprint, &key.fingerprint)) {
+        return Err(KeyError::Mismatch {
+            name: key.name.clone(),
+            expected: key.fingerprint.clone(),
+            found: describe(&found),
+        });
+    }
+
+    let exported = gpg(home.path(), &staging)
+        .args(&["--export", &key.fingerprint])
+        .output()
+        .map_err(&gpg_error)?;
+
+    if let Some(parent) = keyring.parent() {
+        fs::create_dir_all(parent).map_err(&fetch_error)?;
+    }
+
+    misc::write(keyring, &exported.stdout).map_err(&fetch_error)
+}
+
+/// Checks that the keyring of a key contains the key with the configured fingerprint.
+pub fn verify(key: &Key, keyring: &Path) -> Result<KeyInfo, KeyError> {
+    let home = TempDir::new("debrep-gpg")
+        .map_err(|why| KeyError::Keyring { name: key.name.clone(), why })?;
+    let found = list(home.path(), keyring)
+        .map_err(|why| KeyError::Keyring { name: key.name.clone(), why })?;
+
+    let description = describe(&found);
+    found.into_iter()
+        .find(|info| same_fingerprint(&info.fingerprint, &key.fingerprint))
+        .ok_or_else(|| KeyError::Mismatch {
+            name: key.name.clone(),
+            expected: key.fingerprint.clone(),
+            found: description,
+        })
+}
+
+/// Runs gpg with a temporary home, using only the given keyring.
+fn gpg(home: &Path, keyring: &Path) -> Cmd {
+    let mut command = Cmd::new("gpg");
+    command.arg("--homedir").arg(home)
+        .args(&["--batch", "--no-default-keyring", "--keyring"])
+        .arg(keyring);
+    command
+}
+
+fn list(home: &Path, keyring: &Path) -> io::Result<Vec<KeyInfo>> {
+    let output = gpg(home, keyring).args(&["--with-colons", "--list-keys"]).output()
+        .map_err(io::Error::from)?;
+    Ok(parse_keys(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn describe(keys: &[KeyInfo]) -> String {
+    if keys.is_empty() {
+        "no keys".to_owned()
+    } else {
+        keys.iter().map(|info| info.fingerprint.as_str()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+fn same_fingerprint(a: &str, b: &str) -> bool {
+    let normalize = |x: &str| {
+        x.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_uppercase()
+    };
+
+    normalize(a) == normalize(b)
+}
+
+/// Parses the primary keys from the output of `gpg --with-colons --list-keys`.
+fn parse_keys(output: &str) -> Vec<KeyInfo> {
+    let mut keys: Vec<KeyInfo> = Vec::new();
+    // Fingerprint and user ID records apply to the key which precedes them.
+    let mut in_primary = false;
+
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[0] {
+            "pub" | "sec" => {
+                in_primary = true;
+                keys.push(KeyInfo {
+                    fingerprint: String::new(),
+                    expires: fields.get(6).and_then(|x| x.parse::<u64>().ok()),
+                    user_id: None,
+                });
+            }
+            "sub" | "ssb" => in_primary = false,
+            "fpr" if in_primary => {
+                if let (Some(key), Some(fingerprint)) = (keys.last_mut(), fields.get(9)) {
+                    if key.fingerprint.is_empty() {
+                        key.fingerprint = (*fingerprint).to_owned();
+                    }
+                }
+            }
+            "uid" if in_primary => {
+                if let (Some(key), Some(uid)) = (keys.last_mut(), fields.get(9)) {
+                    if key.user_id.is_none() {
+                        key.user_id = Some((*uid).to_owned());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: &str = "tru::1:1531500000:0:3:1:5\n\
+        pub:e:4096:1:204DD8AEC33A7AFF:1400000000:1500000000::-:::sc::::::23::0:\n\
+        fpr:::::::::63C46DF0140D738961429F4E204DD8AEC33A7AFF:\n\
+        uid:e::::1400000000::6E6F1F5C4A4B6F9D::Example Archive Key <archive@example.com>::::::::::0:\n\
+        sub:e:4096:1:5B1D1F5E3C2B4A1A:1400000000:1500000000:::::e::::::23:\n\
+        fpr:::::::::0E5C5B7F1E0A2C3E4D9F6A8B5B1D1F5E3C2B4A1A:\n\
+        pub:-:4096:1:1111111111111111:1400000000:::-:::scESC::::::23::0:\n\
+        fpr:::::::::AAAABBBBCCCCDDDDEEEEFFFF1111111111111111:\n";
+
+    #[test]
+    fn parse_listing() {
+        let keys = parse_keys(LISTING);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].fingerprint, "63C46DF0140D738961429F4E204DD8AEC33A7AFF");
+        assert_eq!(keys[0].expires, Some(1_500_000_000));
+        assert_eq!(
+            keys[0].user_id.as_ref().map(|x| x.as_str()),
+            Some("Example Archive Key <archive@example.com>")
+        );
+        assert!(keys[0].is_expired());
+
+        assert_eq!(keys[1].fingerprint, "AAAABBBBCCCCDDDDEEEEFFFF1111111111111111");
+        assert_eq!(keys[1].expires, None);
+        assert!(!keys[1].is_expired());
+    }
+
+    #[test]
+    fn signatures_of_in_release_files() {
+        let status = "[GNUPG:] NEWSIG\n\
+            [GNUPG:] GOODSIG 204DD8AEC33A7AFF Example Archive Key <archive@example.com>\n\
+            [GNUPG:] VALIDSIG 0E5C5B7F1E0A2C3E4D9F6A8B5B1D1F5E3C2B4A1A 2018-07-13 1531500000 0 \
+            4 0 1 10 01 63C46DF0140D738961429F4E204DD8AEC33A7AFF\n\
+            [GNUPG:] NEWSIG\n\
+            [GNUPG:] ERRSIG 1111111111111111 1 10 01 1531500000 9 -\n\
+            [GNUPG:] NO_PUBKEY 1111111111111111\n";
+
+        assert_eq!(parse_signatures(status), vec![
+            ("63C46DF0140D738961429F4E204DD8AEC33A7AFF".to_owned(), true),
+            ("1111111111111111".to_owned(), false),
+        ]);
+    }
+
+    #[test]
+    fn dates_of_expiry() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(1_500_000_000), "2017-07-14");
+        assert_eq!(date(951_782_400), "2000-02-29");
+    }
+
+    #[test]
+    fn fingerprints_ignore_spacing_and_case() {
+        assert!(same_fingerprint(
+            "63C4 6DF0 140D 7389 6142  9F4E 204D D8AE C33A 7AFF",
+            "63c46df0140d738961429f4e204dd8aec33a7aff"
+        ));
+        assert!(!same_fingerprint("63C46DF0", "204DD8AE"));
+    }
+}
@@ -0,0 +1,273 @@
+//! The lock which a process holds while it changes the repo, so that two runs, or a run and a
+//! job of `debrep serve`, never build into, or publish, the same pool at once.
+//!
+//! An urgent build which is given `--preempt` asks for the lock with a file in `record/.preempt/`
+//! while it waits for it. A batch of builds which holds the lock pauses before its next source
+//! when it sees such a request, releasing the lock until every request was withdrawn, and then
+//! takes the lock again and continues where it paused. Requests are named `<pid>-<n>`, and those
+//! of processes which exited without withdrawing them are removed rather than waited for.
+
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::Duration;
+
+use interrupt;
+use libc;
+
+/// The file which is locked, which is never removed.
+pub const LOCK: &str = "record/.lock";
+
+/// The requests of urgent builds to preempt the batch which holds the lock.
+pub const PREEMPT: &str = "record/.preempt";
+
+/// How often a paused batch checks whether the urgent builds finished, in milliseconds.
+const POLL: u64 = 500;
+
+/// Tells apart the requests of the threads of one process.
+static REQUESTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Whether a batch of this process is paused for urgent builds.
+static PAUSED: AtomicBool = ATOMIC_BOOL_INIT;
+
+thread_local! {
+    /// The descriptor of the lock which this thread holds, which it releases while it pauses.
+    static HELD: Cell<Option<RawFd>> = Cell::new(None);
+    /// Whether this thread works on an urgent build, which is never paused for another.
+    static URGENT: Cell<bool> = Cell::new(false);
+}
+
+/// The lock on the repo, which is released when it is dropped, or when the process exits.
+#[derive(Debug)]
+pub struct RepoLock {
+    file: File,
+}
+
+impl RepoLock {
+    /// Locks the repo at `root`, waiting for the process which holds the lock if `wait` is set,
+    /// and otherwise returning `None` if another process holds it.
+    pub fn acquire(root: &Path, wait: bool) -> io::Result<Option<RepoLock>> {
+        let path = root.join(LOCK);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = File::create(&path)?;
+        let operation = if wait { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+        match flock(file.as_raw_fd(), operation) {
+            Ok(true) => {
+                HELD.with(|held| held.set(Some(file.as_raw_fd())));
+                Ok(Some(RepoLock { file }))
+            }
+            Ok(false) => Ok(None),
+            Err(why) => Err(why),
+        }
+    }
+
+    /// Waits for the lock, returning `None` if debrep is interrupted first.
+    pub fn wait(root: &Path) -> io::Result<Option<RepoLock>> {
+        loop {
+            if let Some(lock) = RepoLock::acquire(root, false)? {
+                return Ok(Some(lock));
+            } else if interrupt::is_interrupted() {
+                return Ok(None);
+            }
+
+            thread::sleep(Duration::from_millis(POLL));
+        }
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        HELD.with(|held| {
+            if held.get() == Some(self.file.as_raw_fd()) {
+                held.set(None);
+            }
+        });
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN); }
+    }
+}
+
+/// Locks or unlocks the file, returning false if the lock is held elsewhere and `LOCK_NB` was
+/// given.
+pub fn flock(fd: RawFd, operation: libc::c_int) -> io::Result<bool> {
+    loop {
+        if unsafe { libc::flock(fd, operation) } == 0 {
+            return Ok(true);
+        }
+
+        let why = io::Error::last_os_error();
+        match why.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EWOULDBLOCK) => return Ok(false),
+            _ => return Err(why),
+        }
+    }
+}
+
+/// The request of an urgent build to preempt the batch which holds the lock, which is withdrawn
+/// when it is dropped. The thread which made it is not paused for other urgent builds.
+#[derive(Debug)]
+pub struct Preemption {
+    path: PathBuf,
+}
+
+impl Preemption {
+    pub fn request(root: &Path) -> io::Result<Preemption> {
+        let dir = root.join(PREEMPT);
+        fs::create_dir_all(&dir)?;
+        let n = REQUESTS.fetch_add(1, Ordering::SeqCst);
+        let path = dir.join(format!("{}-{}", unsafe { libc::getpid() }, n));
+        File::create(&path)?;
+        URGENT.with(|urgent| urgent.set(true));
+        Ok(Preemption { path })
+    }
+}
+
+impl Drop for Preemption {
+    fn drop(&mut self) {
+        URGENT.with(|urgent| urgent.set(false));
+        if let Err(why) = fs::remove_file(&self.path) {
+            warn!("failed to withdraw the request to preempt builds: {}", why);
+        }
+    }
+}
+
+/// Whether an urgent build is waiting for the lock. Requests of processes which no longer run,
+/// such as those which were killed, are removed.
+fn requested(root: &Path) -> bool {
+    let entries = match fs::read_dir(root.join(PREEMPT)) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+
+    let mut requested = false;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name();
+        let pid = name.to_str()
+            .and_then(|name| name.split('-').next())
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok());
+
+        match pid {
+            Some(pid) if !is_running(pid) => {
+                info!("removing the request to preempt builds of {}, which exited", pid);
+                let _ = fs::remove_file(entry.path());
+            }
+            Some(_) => requested = true,
+            None => warn!("ignoring {}, which is not a request to preempt builds",
+                entry.path().display()),
+        }
+    }
+
+    requested
+}
+
+/// Whether the process runs, which it may do as another user.
+fn is_running(pid: libc::pid_t) -> bool {
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Whether an urgent build is waiting for the lock which this thread holds, so that the batch
+/// of this thread should pause for it.
+pub fn preempted(root: &Path) -> bool {
+    HELD.with(|held| held.get()).is_some() && !is_urgent() && requested(root)
+}
+
+/// Whether this thread works on an urgent build.
+pub fn is_urgent() -> bool {
+    URGENT.with(|urgent| urgent.get())
+}
+
+/// Whether a batch of builds of this process is paused for urgent builds.
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Releases the lock which this thread holds until every urgent build finished, or debrep was
+/// interrupted, and then takes it again. The repo may have changed in the meantime.
+pub fn pause_for_urgent(root: &Path) -> io::Result<()> {
+    let fd = match HELD.with(|held| held.get()) {
+        Some(fd) => fd,
+        None => return Ok(()),
+    };
+
+    PAUSED.store(true, Ordering::SeqCst);
+    flock(fd, libc::LOCK_UN)?;
+    while requested(root) && !interrupt::is_interrupted() {
+        thread::sleep(Duration::from_millis(POLL));
+    }
+
+    let locked = flock(fd, libc::LOCK_EX);
+    PAUSED.store(false, Ordering::SeqCst);
+    locked.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    #[test]
+    fn the_repo_is_locked_by_one_holder_at_a_time() {
+        let root = TempDir::new("debrep-lock").unwrap();
+        let held = RepoLock::acquire(root.path(), false).unwrap();
+        assert!(held.is_some());
+        assert!(RepoLock::acquire(root.path(), false).unwrap().is_none());
+
+        drop(held);
+        assert!(RepoLock::acquire(root.path(), false).unwrap().is_some());
+    }
+
+    #[test]
+    fn a_batch_pauses_until_urgent_builds_finish() {
+        let root = TempDir::new("debrep-preempt").unwrap();
+        let _held = RepoLock::acquire(root.path(), false).unwrap().unwrap();
+        assert!(!preempted(root.path()));
+
+        let path = root.path().to_path_buf();
+        let urgent = thread::spawn(move || {
+            let _request = Preemption::request(&path).unwrap();
+            let _lock = RepoLock::acquire(&path, true).unwrap().unwrap();
+            // The urgent build is not paused for its own request.
+            assert!(!preempted(&path));
+        });
+
+        while !preempted(root.path()) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        pause_for_urgent(root.path()).unwrap();
+        urgent.join().unwrap();
+        assert!(!preempted(root.path()));
+
+        // The lock was taken again.
+        assert!(RepoLock::acquire(root.path(), false).unwrap().is_none());
+    }
+
+    #[test]
+    fn requests_of_processes_which_exited_are_removed() {
+        let root = TempDir::new("debrep-preempt").unwrap();
+        let _held = RepoLock::acquire(root.path(), false).unwrap().unwrap();
+        let mut child = Command::new("true").spawn().unwrap();
+        let exited = child.id();
+        child.wait().unwrap();
+
+        let dir = root.path().join(PREEMPT);
+        fs::create_dir_all(&dir).unwrap();
+        let stale = dir.join(format!("{}-0", exited));
+        File::create(&stale).unwrap();
+        assert!(!preempted(root.path()));
+        assert!(!stale.exists());
+
+        let running = dir.join(format!("{}-0", unsafe { libc::getpid() }));
+        File::create(&running).unwrap();
+        assert!(preempted(root.path()));
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config::LogRetention;
+use deflate::write::GzEncoder;
+use deflate::Compression;
+use misc;
+use super::run::{self, RUNS};
+use walkdir::WalkDir;
+
+pub const LOGS: &str = "logs";
+
+/// Name of the symlink which points to the most recent log of a package.
+pub const LATEST: &str = "latest";
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Creates a new log file for a build of the given package, at `logs/<name>/<timestamp>.log`.
+pub fn create(root: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    let (path, file) = create_in(root, name, "")?;
+    set_latest(&path)?;
+    Ok((path, file))
+}
+
+/// Creates a log for a step which follows the build of a package, such as its tests, at
+/// `logs/<name>/<step>-<timestamp>.log`. The `latest` pointer is left on the build's log.
+pub fn create_step(root: &Path, name: &str, step: &str) -> io::Result<(PathBuf, File)> {
+    create_in(root, name, &[step, "-"].concat())
+}
+
+fn create_in(root: &Path, name: &str, prefix: &str) -> io::Result<(PathBuf, File)> {
+    let dir = root.join(LOGS).join(name);
+    migrate(&dir)?;
+    fs::create_dir_all(&dir)?;
+
+    let timestamp = now();
+    let mut path = dir.join(format!("{}{}.log", prefix, timestamp));
+    let mut attempt = 1;
+    while path.exists() || path.with_extension("log.gz").exists() {
+        path = dir.join(format!("{}{}-{}.log", prefix, timestamp, attempt));
+        attempt += 1;
+    }
+
+    let file = File::create(&path)?;
+    Ok((path, file))
+}
+
+/// The step which a log was written by, which is empty for the logs of builds.
+fn step_of(path: &Path) -> String {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+    let name = name.unwrap_or_default();
+    name.chars().take_while(|c| !c.is_digit(10)).collect()
+}
+
+/// Logs were previously written to `logs/<name>`, so move them into the package's log directory.
+fn migrate(dir: &Path) -> io::Result<()> {
+    if dir.is_file() {
+        let modified = dir.metadata()?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let temporary = dir.with_extension("migrating");
+        fs::rename(dir, &temporary)?;
+        fs::create_dir_all(dir)?;
+        fs::rename(&temporary, dir.join(format!("{}.log", modified)))?;
+    }
+
+    Ok(())
+}
+
+fn set_latest(path: &Path) -> io::Result<()> {
+    let (dir, filename) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(filename)) => (dir, filename),
+        _ => return Ok(()),
+    };
+
+    let latest = dir.join(LATEST);
+    if latest.symlink_metadata().is_ok() {
+        fs::remove_file(&latest)?;
+    }
+
+    symlink(filename, latest)
+}
+
+/// Compresses a finished log, updating the `latest` pointer, and returning the new path.
+pub fn finish(path: &Path) -> io::Result<PathBuf> {
+    let compressed = path.with_extension("log.gz");
+
+    {
+        let data = misc::read(path)?;
+        let mut file = File::create(&compressed)?;
+        let mut encoder = GzEncoder::new(&mut file, Compression::Default);
+        encoder.write_all(&data)?;
+        encoder.finish()?;
+    }
+
+    fs::remove_file(path)?;
+    set_latest(&compressed)?;
+    Ok(compressed)
+}
+
+/// The latest build log of the package, decompressed. The log must resolve to a file under the
+/// logs directory, so that a name such as `..`, or a link out of it, reads nothing else.
+pub fn latest(root: &Path, package: &str) -> io::Result<Vec<u8>> {
+    let logs = root.join(LOGS).canonicalize()?;
+    let path = logs.join(package).join(LATEST).canonicalize()?;
+    if !path.starts_with(&logs) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not under {}", path.display(), logs.display())
+        ));
+    }
+
+    read(&path)
+}
+
+/// Reads a log, decompressing it if it was compressed.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    // Resolve the `latest` symlink to the log that it points to.
+    let path = &path.canonicalize()?;
+    if path.extension().map_or(false, |ext| ext == "gz") {
+        let output = Command::new("gzip").arg("-dc").arg(path).output()?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("gzip failed: {}", String::from_utf8_lossy(&output.stderr).trim())
+            ))
+        }
+    } else {
+        misc::read(path)
+    }
+}
+
+/// The total size of the files that were removed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Reclaimed {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Removes old logs of every package according to the retention policy. The log which `latest`
+/// points to is always kept.
+pub fn expire(root: &Path, policy: &LogRetention) -> io::Result<Reclaimed> {
+    let mut reclaimed = Reclaimed::default();
+    let logs = root.join(LOGS);
+    if !logs.is_dir() {
+        return Ok(reclaimed);
+    }
+
+    let oldest = policy.max_age
+        .map(|days| UNIX_EPOCH + Duration::from_secs(now().saturating_sub(days * 24 * 60 * 60)));
+
+    for entry in logs.read_dir()? {
+        let entry = entry?;
+        let dir = entry.path();
+        if entry.file_name() == RUNS {
+            expire_runs(&dir, policy.keep, oldest, &mut reclaimed)?;
+        } else if dir.is_dir() {
+            expire_package(&dir, policy.keep, oldest, &mut reclaimed)?;
+        }
+    }
+
+    Ok(reclaimed)
+}
+
+fn is_expired(
+    position: usize,
+    modified: SystemTime,
+    keep: Option<usize>,
+    oldest: Option<SystemTime>,
+) -> bool {
+    keep.map_or(false, |keep| position >= keep) || oldest.map_or(false, |oldest| modified < oldest)
+}
+
+/// Removes the directories of old runs. The directory of the current run is always kept.
+fn expire_runs(
+    dir: &Path,
+    keep: Option<usize>,
+    oldest: Option<SystemTime>,
+    reclaimed: &mut Reclaimed,
+) -> io::Result<()> {
+    let current = run::current().map(|run| run.dir.clone());
+
+    let mut runs = Vec::new();
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            runs.push((metadata.modified()?, entry.path()));
+        }
+    }
+
+    // Newest runs first.
+    runs.sort_by(|a, b| b.cmp(a));
+
+    for (position, &(modified, ref path)) in runs.iter().enumerate() {
+        if current.as_ref().map_or(false, |current| current == path) {
+            continue;
+        }
+
+        if is_expired(position, modified, keep, oldest) {
+            info!("removing the logs of the run at {}", path.display());
+            for entry in WalkDir::new(path).into_iter().filter_map(|x| x.ok()) {
+                if let Ok(metadata) = entry.metadata() {
+                    if !metadata.is_dir() {
+                        reclaimed.files += 1;
+                        reclaimed.bytes += metadata.len();
+                    }
+                }
+            }
+
+            fs::remove_dir_all(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn expire_package(
+    dir: &Path,
+    keep: Option<usize>,
+    oldest: Option<SystemTime>,
+    reclaimed: &mut Reclaimed,
+) -> io::Result<()> {
+    let latest = fs::read_link(dir.join(LATEST)).ok().map(|path| dir.join(path));
+
+    let mut logs = Vec::new();
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name() == LATEST || !path.is_file() {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        logs.push((metadata.modified()?, metadata.len(), path));
+    }
+
+    // Newest logs first.
+    logs.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| b.2.cmp(&a.2)));
+
+    // The logs of each step are kept separately, so that the logs of tests do not push out the
+    // logs of the builds that they tested.
+    let mut positions = HashMap::new();
+    for &(modified, size, ref path) in &logs {
+        let position = positions.entry(step_of(path)).or_insert(0);
+        *position += 1;
+        let position = *position - 1;
+
+        if latest.as_ref().map_or(false, |latest| latest == path) {
+            continue;
+        }
+
+        if is_expired(position, modified, keep, oldest) {
+            info!("removing log at {}", path.display());
+            fs::remove_file(path)?;
+            reclaimed.files += 1;
+            reclaimed.bytes += size;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn compressed_logs_are_readable() {
+        let root = TempDir::new("debrep-logs").unwrap();
+        let (path, mut file) = create(root.path(), "package").unwrap();
+        file.write_all(b"sbuild output\n").unwrap();
+
+        let compressed = finish(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(read(&compressed).unwrap(), b"sbuild output\n");
+        assert_eq!(read(&root.path().join("logs/package/latest")).unwrap(), b"sbuild output\n");
+    }
+
+    #[test]
+    fn latest_logs_stay_under_the_logs_directory() {
+        let root = TempDir::new("debrep-logs").unwrap();
+        let (path, mut file) = create(root.path(), "package").unwrap();
+        file.write_all(b"sbuild output\n").unwrap();
+        finish(&path).unwrap();
+        assert_eq!(latest(root.path(), "package").unwrap(), b"sbuild output\n");
+
+        misc::write(root.path().join("secret"), b"secret").unwrap();
+        fs::create_dir(root.path().join("logs/escape")).unwrap();
+        symlink(root.path().join("secret"), root.path().join("logs/escape/latest")).unwrap();
+        let escaped = latest(root.path(), "escape").unwrap_err();
+        assert_eq!(escaped.kind(), io::ErrorKind::NotFound);
+
+        misc::write(root.path().join("latest"), b"secret").unwrap();
+        assert_eq!(latest(root.path(), "..").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn old_layout_is_migrated() {
+        let root = TempDir::new("debrep-logs").unwrap();
+        fs::create_dir(root.path().join(LOGS)).unwrap();
+        misc::write(root.path().join("logs/package"), b"old").unwrap();
+
+        create(root.path(), "package").unwrap();
+        let logs = root.path().join("logs/package").read_dir().unwrap().count();
+        assert_eq!(logs, 3);
+    }
+
+    #[test]
+    fn retention_keeps_the_newest_logs() {
+        let root = TempDir::new("debrep-logs").unwrap();
+        let dir = root.path().join("logs/package");
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["1.log.gz", "2.log.gz", "3.log.gz", "4.log.gz"] {
+            misc::write(dir.join(name), b"log").unwrap();
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+
+        symlink("2.log.gz", dir.join(LATEST)).unwrap();
+
+        let policy = LogRetention { keep: Some(1), max_age: None };
+        assert_eq!(expire(root.path(), &policy).unwrap(), Reclaimed { files: 2, bytes: 6 });
+        assert!(dir.join("4.log.gz").exists());
+        assert!(dir.join("2.log.gz").exists());
+        assert!(!dir.join("1.log.gz").exists());
+        assert!(!dir.join("3.log.gz").exists());
+    }
+
+    #[test]
+    fn retention_counts_each_step() {
+        let root = TempDir::new("debrep-logs").unwrap();
+        let (build, _) = create(root.path(), "package").unwrap();
+        ::std::thread::sleep(Duration::from_millis(10));
+        let (tests, _) = create_step(root.path(), "package", "autopkgtest").unwrap();
+        assert_eq!(step_of(&tests), "autopkgtest-");
+        assert_eq!(
+            fs::read_link(root.path().join("logs/package/latest")).unwrap(),
+            build.file_name().unwrap()
+        );
+
+        let policy = LogRetention { keep: Some(1), max_age: None };
+        assert_eq!(expire(root.path(), &policy).unwrap(), Reclaimed::default());
+        assert!(build.exists() && tests.exists());
+    }
+
+    #[test]
+    fn retention_applies_to_runs() {
+        let root = TempDir::new("debrep-logs").unwrap();
+        let runs = root.path().join("logs/runs");
+        for name in &["1-aaaaaa", "2-bbbbbb", "3-cccccc"] {
+            fs::create_dir_all(runs.join(name)).unwrap();
+            misc::write(runs.join(name).join("report"), b"report").unwrap();
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let policy = LogRetention { keep: Some(2), max_age: None };
+        assert_eq!(expire(root.path(), &policy).unwrap(), Reclaimed { files: 1, bytes: 6 });
+        assert!(!runs.join("1-aaaaaa").exists());
+        assert!(runs.join("3-cccccc").exists());
+    }
+}
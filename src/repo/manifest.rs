@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use command::Cmd;
+use misc;
+use walkdir::WalkDir;
+use super::checksum::{sha2_256_digest, HashCache};
+use super::provenance;
+use super::run::Run;
+use super::state::FileProvenance;
+
+/// A file of a manifest which does not match the repository.
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    Missing(String),
+    Mismatch(String),
+    /// A line which is not in the format of `sha256sum`.
+    Malformed(usize),
+}
+
+/// Writes `checksums-<id>.txt` into the directory of the run, in the format of `sha256sum`. It
+/// lists every file which the run added to the pool, and the index files of the archive, which
+/// were all regenerated. Paths are relative to the repo.
+pub fn write(run: &Run, repo: &Path, archive: &str, signer: Option<&str>) -> io::Result<PathBuf> {
+    let repo = repo.canonicalize()?;
+    let mut files: Vec<PathBuf> = run.published().into_iter().filter(|x| x.is_file()).collect();
+    for entry in WalkDir::new(repo.join("dists").join(archive)) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push(entry.path().to_path_buf());
+        }
+    }
+
+    files.sort();
+    files.dedup();
+
+    let mut manifest = String::new();
+    for file in &files {
+        let digest = sha2_256_digest(File::open(file)?)?;
+        let relative = file.strip_prefix(&repo).unwrap_or(file);
+        let _ = writeln!(manifest, "{}  {}", digest, relative.display());
+    }
+
+    let path = run.dir.join(format!("checksums-{}.txt", run.id));
+    misc::write(&path, manifest)?;
+    sign(&path, signer)?;
+    Ok(path)
+}
+
+/// Writes `provenance-<id>.txt` beside the checksums of the run, with where each file which the
+/// run added to the pool came from, as far as it was recorded. Nothing is written if none were.
+pub fn write_provenance(
+    run: &Run,
+    repo: &Path,
+    records: &[FileProvenance],
+    signer: Option<&str>,
+) -> io::Result<Option<PathBuf>> {
+    let repo = repo.canonicalize()?;
+    let published: HashSet<String> = run.published()
+        .iter()
+        .filter_map(|file| file.strip_prefix(&repo).ok())
+        .map(|file| file.to_string_lossy().into_owned())
+        .collect();
+
+    let mut records: Vec<FileProvenance> =
+        records.iter().filter(|x| published.contains(&x.path)).cloned().collect();
+    if records.is_empty() {
+        return Ok(None);
+    }
+
+    records.sort_by(|a, b| a.path.cmp(&b.path));
+    let path = run.dir.join(format!("provenance-{}.txt", run.id));
+    misc::write(&path, provenance::render(&records))?;
+    sign(&path, signer)?;
+    Ok(Some(path))
+}
+
+/// Writes a detached, armored signature of the file beside it, if there is a signer.
+fn sign(path: &Path, signer: Option<&str>) -> io::Result<()> {
+    if let Some(signer) = signer {
+        info!("signing {}", path.display());
+        Cmd::new("gpg")
+            .args(&["--armor", "--detach-sign", "--local-user", signer, "--batch", "--yes", "-o"])
+            .arg(path.with_extension("txt.asc"))
+            .arg(path)
+            .run()?;
+    }
+
+    Ok(())
+}
+
+/// Checks each file of a manifest against the repository, returning how many files were listed,
+/// and which of them do not match.
+pub fn check(
+    repo: &Path,
+    manifest: &Path,
+    cache: &mut HashCache,
+) -> io::Result<(usize, Vec<Problem>)> {
+    let data = misc::read_to_string(manifest)?;
+    let mut problems = Vec::new();
+    let mut listed = 0;
+
+    for (number, line) in data.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let (digest, file) = match parse_line(line) {
+            Some(entry) => entry,
+            None => {
+                problems.push(Problem::Malformed(number + 1));
+                continue;
+            }
+        };
+
+        listed += 1;
+        match cache.sha256(&repo.join(file)) {
+            Ok(found) => if found != digest {
+                problems.push(Problem::Mismatch(file.to_owned()));
+            },
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => {
+                problems.push(Problem::Missing(file.to_owned()));
+            }
+            Err(why) => return Err(why),
+        }
+    }
+
+    Ok((listed, problems))
+}
+
+/// Parses a line of `sha256sum` output, where the digest is followed by two spaces, or a space
+/// and an asterisk for binary mode.
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    if line.len() < 67 || !line.is_char_boundary(64) {
+        return None;
+    }
+
+    let (digest, rest) = line.split_at(64);
+    if !(rest.starts_with("  ") || rest.starts_with(" *")) {
+        return None;
+    }
+
+    Some((digest, &rest[2..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn manifests_are_checked() {
+        let root = TempDir::new("debrep-manifest").unwrap();
+        let repo = root.path().join("repo");
+        let deb = repo.join("pool/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb");
+        fs::create_dir_all(deb.parent().unwrap()).unwrap();
+        fs::create_dir_all(repo.join("dists/bionic")).unwrap();
+        misc::write(&deb, b"deb").unwrap();
+        misc::write(repo.join("dists/bionic/Release"), b"release").unwrap();
+
+        let run = Run::new("1-abcdef".into(), root.path().join("run"));
+        fs::create_dir_all(&run.dir).unwrap();
+        run.add_published(deb.clone());
+
+        let path = write(&run, &repo, "bionic", None).unwrap();
+        assert_eq!(path, run.dir.join("checksums-1-abcdef.txt"));
+        let manifest = misc::read_to_string(&path).unwrap();
+        let files: Vec<&str> = manifest.lines().map(|line| &line[66..]).collect();
+        assert_eq!(
+            files,
+            vec!["dists/bionic/Release", "pool/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb"]
+        );
+
+        let mut cache = HashCache::open(root.path().join("hashes"), false);
+        assert_eq!(check(&repo, &path, &mut cache).unwrap(), (2, Vec::new()));
+
+        misc::write(&deb, b"changed").unwrap();
+        fs::remove_file(repo.join("dists/bionic/Release")).unwrap();
+        misc::write(&path, [&manifest, "not a checksum\n"].concat()).unwrap();
+        assert_eq!(
+            check(&repo, &path, &mut cache).unwrap(),
+            (2, vec![
+                Problem::Missing("dists/bionic/Release".into()),
+                Problem::Mismatch("pool/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb".into()),
+                Problem::Malformed(3),
+            ])
+        );
+    }
+}
@@ -0,0 +1,515 @@
+//! Migrates packages from one suite to another, such as from `proposed` to `release`, once they
+//! have been published for long enough, are not blocked, and their tests passed.
+//!
+//! A package is a directory of the pool, where the binary packages of a configured source are
+//! migrated along with it. Its version is the newest version of its files, and only the files of
+//! that version, and the upstream tarballs which it uses, are migrated.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tempdir::TempDir;
+use toml;
+
+use config::{Config, DEFAULT_COMPONENT};
+use misc;
+use super::binaries::Binaries;
+use super::orig;
+use super::pool::{component_of, is_debug_symbols, mv_to_pool, PoolFile, PoolIndex};
+use super::version::compare;
+
+/// When each file of the pool was first published, in seconds since the Unix epoch.
+pub const PLACED: &str = "record/.placed";
+
+/// The autopkgtest result of the package of each file of the pool, which was built by debrep.
+pub const TESTS: &str = "record/.tests";
+
+/// Packages which must not migrate, and why.
+pub const BLOCKS: &str = "blocks.toml";
+
+/// Packages are migrated once they were published this many days ago, by default.
+pub const DEFAULT_MIN_AGE: u64 = 5;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// What a migration applies to.
+pub struct Migration<'a> {
+    pub from: &'a str,
+    /// Only these packages, if any are given.
+    pub only: &'a [&'a str],
+    pub skip: &'a [&'a str],
+    /// Reports what would be migrated, without migrating it.
+    pub dry_run: bool,
+}
+
+/// A value for each file of the pool, keyed on its path relative to `repo/`.
+#[derive(Debug, Default, PartialEq)]
+pub struct FileRecord {
+    files: BTreeMap<String, String>,
+}
+
+impl FileRecord {
+    /// Reads the record. Lines which cannot be parsed are ignored.
+    pub fn open(path: &Path) -> FileRecord {
+        let mut files = BTreeMap::new();
+        if let Ok(data) = misc::read_to_string(path) {
+            for line in data.lines() {
+                let mut fields = line.splitn(2, '\t');
+                if let (Some(file), Some(value)) = (fields.next(), fields.next()) {
+                    files.insert(file.to_owned(), value.to_owned());
+                }
+            }
+        }
+
+        FileRecord { files }
+    }
+
+    pub fn get(&self, file: &Path) -> Option<&str> {
+        self.files.get(&key(file)).map(|x| x.as_str())
+    }
+
+    pub fn insert(&mut self, file: &Path, value: String) {
+        self.files.insert(key(file), value);
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut data = String::new();
+        for (file, value) in &self.files {
+            let _ = writeln!(data, "{}\t{}", file, value);
+        }
+
+        misc::write(path, data)
+    }
+}
+
+/// Files are keyed on their path relative to `repo/`, as `pool/<archive>/<component>/...`.
+fn key(file: &Path) -> String {
+    file.strip_prefix("repo").unwrap_or(file).to_string_lossy().into_owned()
+}
+
+/// Records the time at which each file of the pool which was not yet published is published.
+/// Ages are taken from this record, rather than from modification times, which copies change.
+pub fn record_placements(pool: &PoolIndex) -> io::Result<()> {
+    let path = Path::new(PLACED);
+    let mut placed = FileRecord::open(path);
+    let now = now().to_string();
+    let mut changed = false;
+    for file in pool.files() {
+        if placed.get(&file.path).is_none() {
+            placed.insert(&file.path, now.clone());
+            changed = true;
+        }
+    }
+
+    if changed { placed.save(path) } else { Ok(()) }
+}
+
+/// Records the result of the tests of a package which was just placed in the pool, as `passed`,
+/// `skipped`, `none`, or `failed`.
+pub fn record_tests(placed: &[PathBuf], result: &str) -> io::Result<()> {
+    let path = Path::new(TESTS);
+    let mut tests = FileRecord::open(path);
+    for file in placed {
+        tests.insert(file, result.to_owned());
+    }
+
+    tests.save(path)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// A package which must not migrate, which `blocks.toml` lists as a `[[block]]`.
+#[derive(Debug, Deserialize)]
+pub struct Block {
+    pub package: String,
+    /// Only blocks this version, if it is set.
+    pub version: Option<String>,
+    pub reason: Option<String>,
+    /// Set once the block no longer applies.
+    #[serde(default)]
+    pub resolved: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Blocks {
+    block: Option<Vec<Block>>,
+}
+
+/// Reads the blocks which are not resolved. There are none if the file does not exist.
+pub fn open_blocks(path: &Path) -> io::Result<Vec<Block>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let blocks: Blocks = toml::from_str(&misc::read_to_string(path)?)
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why.to_string()))?;
+    Ok(blocks.block.unwrap_or_default().into_iter().filter(|block| !block.resolved).collect())
+}
+
+/// A package of the suite which is migrated from, which is newer than in the other suite.
+#[derive(Debug, PartialEq)]
+pub struct Candidate {
+    pub package: String,
+    pub version: String,
+    /// Its version in the suite which it migrates to, if it is there.
+    pub previous: Option<String>,
+    pub files: Vec<PathBuf>,
+}
+
+/// Whether a candidate migrates.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Migrates,
+    /// It was published this many days ago, which is less than needed.
+    TooYoung { days: u64, needed: u64 },
+    Blocked { reason: String },
+}
+
+/// The packages which migrated, or would have, and those which did not, and why.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub entries: Vec<(Candidate, Verdict)>,
+    pub dry_run: bool,
+}
+
+impl Report {
+    pub fn migrated(&self) -> usize {
+        self.entries.iter().filter(|&&(_, ref verdict)| *verdict == Verdict::Migrates).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(ref candidate, ref verdict) in &self.entries {
+            let from = candidate.previous.as_ref().map_or("new", |x| x.as_str());
+            let package = [&candidate.package, " ", from, " -> ", &candidate.version].concat();
+            match *verdict {
+                Verdict::Migrates if self.dry_run => writeln!(f, "would-migrate {}", package)?,
+                Verdict::Migrates => writeln!(f, "migrated {}", package)?,
+                Verdict::TooYoung { days, needed } => {
+                    writeln!(f, "too-young {}: {} of {} days", package, days, needed)?
+                }
+                Verdict::Blocked { ref reason } => writeln!(f, "blocked {}: {}", package, reason)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The version of a file of the pool, from its name, such as `1.0-1` of `foo_1.0-1_amd64.deb`,
+/// or `foo_1.0-1.dsc`. Upstream tarballs have none.
+fn file_version(filename: &str) -> Option<&str> {
+    if orig::is_orig(filename) {
+        return None;
+    }
+
+    let mut fields = filename.splitn(3, '_');
+    fields.next();
+    let version = fields.next()?;
+    if fields.next().is_some() {
+        return Some(version);
+    }
+
+    [".dsc", ".debian.tar.", ".diff.", ".tar."].iter()
+        .filter_map(|ext| version.find(ext))
+        .min()
+        .map(|end| &version[..end])
+}
+
+/// Whether the upstream tarball is used by the version.
+fn is_orig_of(filename: &str, version: &str) -> bool {
+    let upstream = version.rfind('-').map_or(version, |pos| &version[..pos]);
+    let mut fields = filename.splitn(2, '_');
+    fields.next();
+    fields.next().map_or(false, |rest| {
+        rest.starts_with(upstream) && rest[upstream.len()..].starts_with(".orig")
+    })
+}
+
+/// The files of each package of the pool, where binary packages that a configured source
+/// produced are a part of the package of that source.
+fn packages<'a>(pool: &'a PoolIndex, binaries: &Binaries) -> BTreeMap<String, Vec<&'a PoolFile>> {
+    let mut packages = BTreeMap::new();
+    for file in pool.files() {
+        let package = match binaries.source_of(&file.package) {
+            Some(source) if file.section != "source" => source.to_owned(),
+            _ => file.package.clone(),
+        };
+
+        packages.entry(package).or_insert_with(Vec::new).push(file);
+    }
+
+    packages
+}
+
+/// The newest version of the files.
+fn newest(files: &[&PoolFile]) -> io::Result<Option<String>> {
+    let mut newest: Option<String> = None;
+    for file in files {
+        let version = match file.path.file_name().and_then(|x| x.to_str()).and_then(file_version) {
+            Some(version) => version,
+            None => continue,
+        };
+
+        let newer = match newest {
+            Some(ref newest) => compare(version, "gt", newest)?,
+            None => true,
+        };
+
+        if newer {
+            newest = Some(version.to_owned());
+        }
+    }
+
+    Ok(newest)
+}
+
+/// The packages of `from` which are newer than in `to`, or are not in `to`.
+pub fn candidates(
+    from: &PoolIndex,
+    to: &PoolIndex,
+    binaries: &Binaries,
+) -> io::Result<Vec<Candidate>> {
+    let current = packages(to, binaries);
+    let mut candidates = Vec::new();
+    for (package, files) in packages(from, binaries) {
+        let version = match newest(&files)? {
+            Some(version) => version,
+            None => continue,
+        };
+
+        let previous = match current.get(&package) {
+            Some(files) => newest(files)?,
+            None => None,
+        };
+
+        if let Some(ref previous) = previous {
+            if !compare(&version, "gt", previous)? {
+                continue;
+            }
+        }
+
+        let files = files.iter()
+            .filter(|file| {
+                let name = file.path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+                file_version(name) == Some(&version) || is_orig_of(name, &version)
+            })
+            .map(|file| file.path.clone())
+            .collect();
+
+        candidates.push(Candidate { package, version, previous, files });
+    }
+
+    Ok(candidates)
+}
+
+/// The gates which candidates must pass, from the `[migration]` of the config.
+pub struct Gates<'a> {
+    pub min_age: u64,
+    pub require_tests: bool,
+    pub placed: &'a FileRecord,
+    pub tests: &'a FileRecord,
+    pub blocks: &'a [Block],
+    pub now: u64,
+}
+
+impl<'a> Gates<'a> {
+    pub fn new(
+        config: &Config,
+        placed: &'a FileRecord,
+        tests: &'a FileRecord,
+        blocks: &'a [Block],
+    ) -> Gates<'a> {
+        let settings = config.migration.as_ref();
+        Gates {
+            min_age: settings.and_then(|x| x.min_age).unwrap_or(DEFAULT_MIN_AGE),
+            require_tests: settings.and_then(|x| x.require_tests).unwrap_or(false),
+            placed,
+            tests,
+            blocks,
+            now: now(),
+        }
+    }
+
+    pub fn check(&self, candidate: &Candidate) -> Verdict {
+        let block = self.blocks.iter().find(|block| {
+            block.package == candidate.package
+                && block.version.as_ref().map_or(true, |version| *version == candidate.version)
+        });
+
+        if let Some(block) = block {
+            let reason = block.reason.clone().unwrap_or_else(|| "blocked in blocks.toml".into());
+            return Verdict::Blocked { reason };
+        }
+
+        let results: Vec<&str> = candidate.files.iter()
+            .filter_map(|file| self.tests.get(file))
+            .collect();
+        if results.contains(&"failed") {
+            return Verdict::Blocked { reason: "its tests failed".into() };
+        } else if self.require_tests && !results.iter().any(|&x| x == "passed" || x == "skipped") {
+            return Verdict::Blocked { reason: "its tests have not passed".into() };
+        }
+
+        // The package was published when the last of its files was.
+        let placed = candidate.files.iter()
+            .map(|file| self.placed.get(file).and_then(|time| time.parse::<u64>().ok()))
+            .collect::<Option<Vec<u64>>>()
+            .and_then(|times| times.into_iter().max());
+
+        let days = match placed {
+            Some(placed) => self.now.saturating_sub(placed) / DAY,
+            None => return Verdict::TooYoung { days: 0, needed: self.min_age },
+        };
+
+        if days < self.min_age {
+            Verdict::TooYoung { days, needed: self.min_age }
+        } else {
+            Verdict::Migrates
+        }
+    }
+}
+
+/// Copies the files of the candidate into the pool of the suite, in the components which they
+/// were published in, as a build would place them, with the debug symbols of its packages unless
+/// the suite discards them.
+pub fn promote(
+    candidate: &Candidate,
+    to: &mut PoolIndex,
+    debug_symbols: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let staging = TempDir::new_in("build", "migrate")?;
+    let mut components = BTreeSet::new();
+    for file in &candidate.files {
+        if let Some(name) = file.file_name().and_then(|x| x.to_str()) {
+            if debug_symbols || !is_debug_symbols(name) {
+                let component = component_of(file).unwrap_or_else(|| DEFAULT_COMPONENT.to_owned());
+                let dir = staging.path().join(&component);
+                fs::create_dir_all(&dir)?;
+                fs::copy(file, dir.join(name))?;
+                components.insert(component);
+            }
+        }
+    }
+
+    let mut placed = Vec::new();
+    for component in &components {
+        let dir = staging.path().join(component);
+        placed.extend(mv_to_pool(&dir, to, component, true, false, false)?);
+    }
+
+    Ok(placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_of_pool_files() {
+        assert_eq!(file_version("foo_1.0-1_amd64.deb"), Some("1.0-1"));
+        assert_eq!(file_version("foo_1.0-1.dsc"), Some("1.0-1"));
+        assert_eq!(file_version("foo_1.0-1.debian.tar.xz"), Some("1.0-1"));
+        assert_eq!(file_version("foo_1.0.tar.xz"), Some("1.0"));
+        assert_eq!(file_version("foo_1.0.orig.tar.gz"), None);
+        assert!(is_orig_of("foo_1.0.orig.tar.gz", "1.0-1"));
+        assert!(is_orig_of("foo_1.0.orig-docs.tar.gz", "1.0-1"));
+        assert!(!is_orig_of("foo_1.0.orig.tar.gz", "1.1-1"));
+    }
+
+    fn dsc(package: &str) -> PathBuf {
+        PathBuf::from(format!("repo/pool/p/main/source/{0}/{0}/{0}_1.dsc", package))
+    }
+
+    fn candidate(package: &str) -> Candidate {
+        Candidate {
+            package: package.into(),
+            version: "1".into(),
+            previous: None,
+            files: vec![dsc(package)],
+        }
+    }
+
+    #[test]
+    fn gates_of_candidates() {
+        let mut placed = FileRecord::default();
+        let mut tests = FileRecord::default();
+        let now = 100 * DAY;
+        placed.insert(&dsc("a"), (now - 6 * DAY).to_string());
+        placed.insert(&dsc("b"), (now - DAY).to_string());
+        placed.insert(&dsc("c"), "0".into());
+        tests.insert(&dsc("c"), "failed".into());
+        let blocks = vec![Block {
+            package: "d".into(),
+            version: None,
+            reason: Some("waiting on a fix".into()),
+            resolved: false,
+        }];
+
+        let mut gates = Gates {
+            min_age: 5,
+            require_tests: false,
+            placed: &placed,
+            tests: &tests,
+            blocks: &blocks,
+            now,
+        };
+
+        let a = candidate("a");
+        let b = candidate("b");
+        let c = candidate("c");
+        let d = candidate("d");
+        assert_eq!(gates.check(&a), Verdict::Migrates);
+        assert_eq!(gates.check(&b), Verdict::TooYoung { days: 1, needed: 5 });
+        assert_eq!(gates.check(&c), Verdict::Blocked { reason: "its tests failed".into() });
+        assert_eq!(gates.check(&d), Verdict::Blocked { reason: "waiting on a fix".into() });
+
+        gates.require_tests = true;
+        let why = "its tests have not passed".into();
+        assert_eq!(gates.check(&a), Verdict::Blocked { reason: why });
+    }
+
+    #[test]
+    fn newer_packages_are_candidates() {
+        let dir = ::tempdir::TempDir::new("debrep-migrate").unwrap();
+        let files = [
+            "proposed/main/source/f/foo/foo_1.1-1.dsc",
+            "proposed/main/source/f/foo/foo_1.1.orig.tar.gz",
+            "proposed/main/source/f/foo/foo_1.0-1.dsc",
+            "proposed/main/source/f/foo/foo_1.0.orig.tar.gz",
+            "proposed/main/binary-amd64/l/libfoo1/libfoo1_1.1-1_amd64.deb",
+            "proposed/main/source/b/bar/bar_2.0-1.dsc",
+            "release/main/source/f/foo/foo_1.0-1.dsc",
+            "release/main/source/b/bar/bar_2.0-1.dsc",
+        ];
+
+        for file in &files {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            misc::write(&path, b"").unwrap();
+        }
+
+        let binaries_path = dir.path().join("binaries");
+        misc::write(&binaries_path, "foo\tlibfoo1\n").unwrap();
+        let binaries = Binaries::open(&binaries_path);
+        let from = PoolIndex::open(&dir.path().join("proposed")).unwrap();
+        let to = PoolIndex::open(&dir.path().join("release")).unwrap();
+
+        let candidates = candidates(&from, &to, &binaries).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].package, "foo");
+        assert_eq!(candidates[0].previous, Some("1.0-1".into()));
+        let mut names: Vec<&str> = candidates[0].files.iter()
+            .map(|file| file.file_name().unwrap().to_str().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["foo_1.1-1.dsc", "foo_1.1.orig.tar.gz", "libfoo1_1.1-1_amd64.deb"]);
+    }
+}
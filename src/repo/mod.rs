@@ -1,119 +1,1633 @@
-mod download;
+mod aptsim;
+mod audit;
+mod binaries;
 mod build;
+mod checksum;
+mod commands;
+mod deb;
+mod digest;
+mod doctor;
+mod download;
+mod dry_run;
+mod gc;
 mod generate;
+mod hooks;
+mod junit;
+mod keys;
+mod lock;
+mod logs;
+mod manifest;
+mod migrate;
+mod obsolete;
+mod orig;
 mod pool;
 mod prepare;
+mod provenance;
+mod run;
+mod space;
+mod state;
+mod stats;
+mod upload;
 mod version;
 
-use std::{env, fs, io};
-use std::path::PathBuf;
-use std::process::exit;
-use config::Config;
+use std::collections::BTreeSet;
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use command::{self, Operation};
+use config::{Config, DdebHandling, DigestFormat, HookStage, Source, DEFAULT_COMPONENT};
+use interrupt;
+use misc;
+use self::aptsim::{AptState, Simulation};
+use self::audit::{PackageCache, PACKAGE_CACHE};
+use self::binaries::{Binaries, BINARIES};
+use self::checksum::{HashCache, HASHES};
+use self::digest::{Section, Snapshot};
+use self::download::engine::{self, Blocking, Engine};
+use self::hooks::HookError;
+use self::migrate::{FileRecord, Gates, Verdict};
+use self::obsolete::Obsolete;
+use self::pool::PoolIndex;
+use output::{self, Status, Summary};
+use rayon::ThreadPoolBuilder;
+
+pub use self::build::{BuildError, BuildFailures, BuildOptions, Queue, QueuedBuild, Reproduction};
+pub use self::commands::Phase;
+pub use self::doctor::{Check, Outcome};
+pub use self::download::{DownloadFailure, DownloadFailures};
+pub use self::dry_run::Intent;
+pub use self::gc::{Area as GcArea, Report as GcReport};
+pub use self::lock::{Preemption, RepoLock};
+pub use self::migrate::{Migration, Report as MigrationReport};
+pub use self::state::{Build, BuildResult, FileProvenance, OriginKind};
+pub use self::stats::{Change, Growth, Measure, Sample};
+pub use self::upload::Planned as PlannedUpload;
 
-pub enum Packages<'a> {
+/// The packages which an action applies to.
+pub enum Selection<'a> {
     All,
-    Select(&'a [&'a str], bool)
+    /// The named packages, and whether to build them even if they were already built.
+    Packages(&'a [&'a str], bool),
+    /// The named packages, or every package if none are named, which are rebuilt without
+    /// changes, and the reason for it.
+    Rebuild(&'a [&'a str], Option<&'a str>),
+}
+
+impl<'a> Selection<'a> {
+    /// The named packages, or `None` if every package is selected.
+    pub fn packages(&self) -> Option<&'a [&'a str]> {
+        match *self {
+            Selection::Packages(packages, _) => Some(packages),
+            Selection::Rebuild(packages, _) if !packages.is_empty() => Some(packages),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a configured key is present in `keys/`, and whose key it is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyState {
+    pub name: String,
+    /// One of `missing`, `expired`, `present`, or `mismatch`.
+    pub state: &'static str,
+    pub fingerprint: String,
+    pub user_id: Option<String>,
+}
+
+/// A signature of the `InRelease` file of a suite, and the state of the key which made it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuiteKeyState {
+    pub suite: String,
+    /// One of `unpublished`, `unsigned`, `unknown`, `expired`, or `valid`.
+    pub state: &'static str,
+    pub fingerprint: String,
+    pub user_id: Option<String>,
+    /// When the key expires, as `YYYY-MM-DD`, or `never`.
+    pub expires: String,
 }
 
-pub struct Repo<'a> {
+#[derive(Debug, Fail)]
+pub enum RepoError {
+    #[fail(display = "unable to create directories in current directory: {}", why)]
+    Directories { why: io::Error },
+    #[fail(display = "failed to remove expired logs: {}", why)]
+    Logs { why: io::Error },
+    #[fail(display = "failed to collect the garbage of {}: {}", area, why)]
+    Gc { area: &'static str, why: io::Error },
+    #[fail(display = "failed to clean up file: {}", why)]
+    Clean { why: io::Error },
+    #[fail(display = "failed to remove file: {}", why)]
+    Remove { why: io::Error },
+    #[fail(display = "failed to read the files of the pool: {}", why)]
+    Pool { why: io::Error },
+    #[fail(display = "{}", failures)]
+    Fetch { failures: DownloadFailures, summary: Summary },
+    #[fail(display = "each binary package must be produced by a single source: {}", conflicts)]
+    BinaryConflicts { conflicts: String },
+    #[fail(display = "{}", failures)]
+    Build { failures: BuildFailures, summary: Summary },
+    #[fail(display = "failed to generate dist files: {}", why)]
+    Release { why: ReleaseError },
+    #[fail(display = "failed to write the checksums of published files: {}", why)]
+    Manifest { why: io::Error },
+    #[fail(display = "failed to check {:?}: {}", manifest, why)]
+    CheckManifest { manifest: PathBuf, why: io::Error },
+    #[fail(display = "{} of the {} files of {:?} do not match", failed, listed, manifest)]
+    ManifestMismatch { manifest: PathBuf, failed: usize, listed: usize },
+    #[fail(display = "failed to check the upstream tarballs of the pool: {}", why)]
+    CheckPool { why: io::Error },
+    #[fail(display = "failed to audit the changelogs of the pool: {}", why)]
+    Audit { why: io::Error },
+    #[fail(display = "{} upstream tarballs or source packages of the pool do not match", failed)]
+    PoolMismatch { failed: usize },
+    #[fail(display = "{} offline", what)]
+    Offline { what: &'static str },
+    #[fail(display = "failed to read the published packages of {}: {}", archive, why)]
+    PublishedPackages { archive: String, why: io::Error },
+    #[fail(display = "failed to determine the architecture to simulate: {}", why)]
+    Architecture { why: io::Error },
+    #[fail(display = "failed to prepare the apt state for {}: {}", archive, why)]
+    AptState { archive: String, why: io::Error },
+    #[fail(display = "failed to simulate installing {}: {}", package, why)]
+    Simulate { package: String, why: io::Error },
+    #[fail(display = "{} of {} packages are not installable", failed, total)]
+    NotInstallable { failed: usize, total: usize },
+    #[fail(display = "{} keys are missing, expired, or could not be fetched", failed)]
+    Keys { failed: usize },
+    #[fail(display = "{} could not be published", suites)]
+    Unpublished { suites: String },
+    #[fail(display = "debrep cannot run until what it needs is set up:\n  {}", missing)]
+    MissingPrerequisites { missing: String },
+    #[fail(display = "the run was interrupted")]
+    Interrupted { summary: Summary },
+    #[fail(
+        display = "{} is frozen, so packages cannot be {} it; pass --override-freeze to do so \
+                   anyway",
+        suite, action
+    )]
+    Frozen { suite: String, action: &'static str },
+    #[fail(display = "failed to move the staged packages into the pool: {}", why)]
+    Unstage { why: io::Error },
+    #[fail(display = "failed to read {}: {}", file, why)]
+    Blocks { file: &'static str, why: io::Error },
+    #[fail(display = "failed to migrate packages from {}: {}", from, why)]
+    Migrate { from: String, why: io::Error },
+    #[fail(
+        display = "{} is not a suite of this repo{}; its suites are {}. Correct `archive` in \
+                   sources.toml, or add {} to `suites` to publish a new suite",
+        suite, hint, known, suite
+    )]
+    UnknownSuite { suite: String, known: String, hint: String },
+    #[fail(display = "failed to read the state of builds: {}", why)]
+    State { why: io::Error },
+    #[fail(display = "failed to read the build log of {}: {}", package, why)]
+    Log { package: String, why: io::Error },
+    #[fail(display = "failed to read the reports of runs: {}", why)]
+    Runs { why: io::Error },
+    #[fail(display = "failed to read the build queues: {}", why)]
+    Queues { why: io::Error },
+    #[fail(display = "failed to check what the run would do: {}", why)]
+    DryRun { why: io::Error },
+    #[fail(display = "failed to write the JUnit report to {:?}: {}", path, why)]
+    Junit { path: PathBuf, why: io::Error },
+    #[fail(display = "{}", why)]
+    Hook { why: HookError },
+    #[fail(
+        display = "there is not enough disk space to {}:\n  {}\nfree some, such as with `debrep \
+                   gc`, or pass --ignore-space-check to start anyway",
+        what, shortfalls
+    )]
+    Space { what: String, shortfalls: String },
+    #[fail(display = "failed to upload the built packages: {}", why)]
+    UploadState { why: io::Error },
+    #[fail(display = "{} uploads failed; the next run tries them again", failed)]
+    Upload { failed: usize },
+    #[fail(display = "{} could not be reproduced", packages)]
+    Unreproducible { packages: String },
+}
+
+impl RepoError {
+    /// Whether the error only stops its suite from being published, so that the other suites of
+    /// a run still are.
+    pub fn is_suite_only(&self) -> bool {
+        match *self {
+            RepoError::Release { why: ReleaseError::SigningKey { .. } } => true,
+            _ => false,
+        }
+    }
+}
+
+/// A repository in the current working directory, and the config which it is built from.
+pub struct Repo {
     config: Config,
-    packages: Packages<'a>
+    /// The files of the archive's pool, which are indexed when they are first needed.
+    pool: Mutex<Option<PoolIndex>>,
+    /// What files are downloaded with.
+    engine: Arc<Engine>,
 }
 
-impl<'a> Repo<'a> {
-    pub fn prepare(config: Config, packages: Packages<'a>) -> Repo<'a> {
-        if let Err(why) = prepare::create_missing_directories() {
-            error!("unable to create directories in current directory: {}", why);
-            exit(1);
+impl Repo {
+    /// Opens the repository, creating the directories which it is built in if they are missing.
+    pub fn open(config: Config) -> Result<Repo, RepoError> {
+        // A misspelled archive would otherwise be published as a new suite, with an empty pool.
+        if let Some(known) = prepare::unknown_suite(Path::new("."), &config) {
+            let hint = prepare::closest(&config.archive, &known)
+                .map_or(String::new(), |suite| [" (did you mean ", suite, "?)"].concat());
+            return Err(RepoError::UnknownSuite {
+                suite: config.archive.clone(),
+                known: known.join(", "),
+                hint,
+            });
+        }
+
+        let created = prepare::create_missing_directories(Path::new("."), Some(&config.archive))
+            .map_err(|why| RepoError::Directories { why })?;
+        for dir in created {
+            info!("created the missing {} directory", dir.display());
+        }
+
+        for &operation in Operation::ALL {
+            command::set_timeout(operation, config.subprocess_timeout(operation));
         }
 
-        Repo { config, packages }
+        command::set_priority(config.priority.as_ref().map_or_else(Vec::new, |x| x.prefix()));
+        if let Some(jobs) = config.jobs {
+            // The global pool can only be configured before it is first used.
+            if let Err(why) = ThreadPoolBuilder::new().num_threads(jobs).build_global() {
+                warn!("unable to limit debrep to {} threads: {}", jobs, why);
+            }
+        }
+
+        let engine = engine::start(config.download_engine(), config.download_connections());
+        Ok(Repo { config, pool: Mutex::new(None), engine })
+    }
+
+    /// Opens the repo to read what it recorded, or to check it, without preparing it for work:
+    /// the suite is not checked, and no directories are created.
+    pub fn read_only(config: Config) -> Repo {
+        Repo { config, pool: Mutex::new(None), engine: Arc::new(Blocking::new()) }
+    }
+
+    /// Lays out the directories of a new repository at `root`, with an example `sources.toml`
+    /// unless it already has one. Returns the directories and files which were created.
+    pub fn init(root: &Path) -> Result<Vec<PathBuf>, RepoError> {
+        prepare::init(root).map_err(|why| RepoError::Directories { why })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Checks that the external commands which the phases need for the configured features are
+    /// installed, and that the schroots which builds need exist, so that a run does not fail on
+    /// something which is missing after doing part of its work.
+    pub fn check_prerequisites(
+        &self,
+        phases: &[Phase],
+        options: BuildOptions,
+    ) -> Result<(), RepoError> {
+        let commands = commands::required(&self.config, phases, options);
+        let mut missing: Vec<String> =
+            command::missing(&commands).iter().map(|x| x.to_string()).collect();
+
+        // Without schroot, sbuild is missing too, which is already reported.
+        if command::on_path("schroot") {
+            match commands::chroots(&self.config, phases) {
+                Ok(chroots) => {
+                    let missing_chroots = commands::missing_chroots(&chroots);
+                    let existing = if missing_chroots.is_empty() {
+                        Vec::new()
+                    } else {
+                        commands::sbuild_chroots()
+                    };
+
+                    for chroot in missing_chroots {
+                        let hint = match prepare::closest(&chroot, &existing) {
+                            Some(closest) => [" (did you mean ", closest, "?)"].concat(),
+                            None if existing.is_empty() => String::new(),
+                            None => [" (sbuild has ", &existing.join(", "), ")"].concat(),
+                        };
+
+                        missing.push(format!(
+                            "the {} schroot does not exist{}; create it with sbuild-createchroot",
+                            chroot,
+                            hint
+                        ));
+                    }
+                }
+                Err(why) => warn!("failed to determine which schroots are needed: {}", why),
+            }
+        }
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(RepoError::MissingPrerequisites { missing: missing.join("\n  ") })
+        }
+    }
+
+    /// Removes logs according to the log retention policy, if one is configured.
+    pub fn clean_logs(&self) -> Result<(), RepoError> {
+        let policy = match self.config.log_retention {
+            Some(ref policy) => policy,
+            None => {
+                info!("no log retention policy is configured");
+                return Ok(());
+            }
+        };
+
+        let reclaimed = logs::expire(Path::new("."), policy)
+            .map_err(|why| RepoError::Logs { why })?;
+
+        info!("removed {} logs, reclaiming {} bytes", reclaimed.files, reclaimed.bytes);
+        Ok(())
+    }
+
+    /// Removes what the `gc` policies of the config allow from the selected working areas, or
+    /// only reports what they would remove on a dry run. The pool and the dists are never touched.
+    pub fn gc(&self, areas: &[GcArea], dry_run: bool) -> Result<Vec<GcReport>, RepoError> {
+        areas.iter()
+            .map(|&area| {
+                gc::collect(&self.config, Path::new("."), area, dry_run)
+                    .map_err(|why| RepoError::Gc { area: area.as_str(), why })
+            })
+            .collect()
     }
 
-    pub fn clean(self) -> Self {
-        if let Err(why) = prepare::package_cleanup(&self.config) {
-            error!("failed to clean up file: {}", why);
-            exit(1);
+    pub fn clean(&self, options: BuildOptions) -> Result<(), RepoError> {
+        self.check_freeze("cleaned from", options)?;
+        self.with_pool(|pool| prepare::package_cleanup(&self.config, pool))?
+            .map_err(|why| RepoError::Clean { why })
+    }
+
+    /// Removes the binary packages of the pool which the latest builds of their sources no
+    /// longer produce, and generates the dist files again if any were removed.
+    pub fn clean_obsolete(&self, options: BuildOptions) -> Result<(), RepoError> {
+        self.check_freeze("cleaned from", options)?;
+        if self.remove_obsolete()? == 0 {
+            info!("no binary packages of {} are obsolete", self.config.archive);
+            return Ok(());
         }
-        self
+
+        self.generate_metadata(options)
     }
 
-    pub fn download(self) -> Self {
-        match self.packages {
-            Packages::All => download::all(&self.config),
-            Packages::Select(ref packages, _) => {
-                download::packages(&self.config, packages)
+    /// Downloads the selected packages, and fetches the selected sources. Every selected package
+    /// is attempted, and the summary lists each of them. The packages which failed are listed
+    /// together in the error, and in the report of the run.
+    pub fn fetch(
+        &self,
+        selection: &Selection,
+        options: BuildOptions,
+    ) -> Result<Summary, RepoError> {
+        interrupted(Summary::default())?;
+        if !options.offline {
+            self.check_download_space(selection, options)?;
+        }
+
+        start_run();
+
+        // Downloads are moved into the pool without its index.
+        self.pool.lock().unwrap().take();
+        let mut downloads = match *selection {
+            Selection::All => download::all(&self.config, &*self.engine, options.offline),
+            Selection::Rebuild(packages, _) if packages.is_empty() => {
+                download::all(&self.config, &*self.engine, options.offline)
+            }
+            Selection::Packages(packages, _) | Selection::Rebuild(packages, _) => {
+                download::packages(&self.config, &*self.engine, packages, options.offline)
+            }
+        };
+
+        download::record_provenance(&self.config);
+        interrupted(downloads.summary.clone())?;
+        if downloads.failures.is_empty() {
+            return Ok(downloads.summary);
+        }
+
+        downloads.failures.find_dependents(&self.config);
+
+        if let Some(run) = run::current() {
+            if let Err(why) = run.write_report(&downloads.failures.to_string()) {
+                warn!("failed to write the report of run {}: {}", run.id, why);
             }
         }
 
-        self
+        Err(RepoError::Fetch { failures: downloads.failures, summary: downloads.summary })
     }
 
-    pub fn build(self) -> Self {
-        match self.packages {
-            Packages::All => build::all(&self.config),
-            Packages::Select(ref packages, force) => {
-                build::packages(&self.config, packages, force)
+    /// Checks that the filesystems which the selected packages are downloaded to have the space
+    /// for those of their files whose lengths are known.
+    fn check_download_space(
+        &self,
+        selection: &Selection,
+        options: BuildOptions,
+    ) -> Result<(), RepoError> {
+        let pending = download::pending(&self.config, selection.packages());
+        let unknown = pending.iter().filter(|&&(_, length)| length.is_none()).count();
+        if unknown != 0 {
+            info!("the lengths of {} downloads are unknown, so they are not counted", unknown);
+        }
+
+        let needed: Vec<(PathBuf, u64)> = pending.into_iter()
+            .filter_map(|(path, length)| length.map(|length| (path, length)))
+            .collect();
+        let what = "download the packages";
+        let headroom = self.config.space_headroom();
+        space::require(&needed, headroom, options.ignore_space_check, what)
+            .map_err(|shortfalls| RepoError::Space { what: what.to_owned(), shortfalls })
+    }
+
+    /// Builds the selected sources which were fetched, and moves them into the pool. A package
+    /// which fails to build only stops the sources which need it, unless `fail_fast` is set, in
+    /// which case no more builds are started, and nothing else is done with the pool. Either
+    /// way, the packages which failed to build are the error.
+    pub fn build(
+        &self,
+        selection: &Selection,
+        options: BuildOptions,
+    ) -> Result<Summary, RepoError> {
+        interrupted(Summary::default())?;
+        if let Some(ref policy) = self.config.log_retention {
+            if let Err(why) = logs::expire(Path::new("."), policy) {
+                warn!("failed to remove expired logs: {}", why);
+            }
+        }
+
+        let configured: Vec<&str> = self.config.source.as_ref()
+            .map_or_else(Vec::new, |sources| sources.iter().map(|x| x.name.as_str()).collect());
+        let conflicts = Binaries::open(Path::new(BINARIES)).conflicts(&configured);
+        if !conflicts.is_empty() {
+            let conflicts: Vec<String> = conflicts.iter().map(|x| x.to_string()).collect();
+            return Err(RepoError::BinaryConflicts { conflicts: conflicts.join("; ") });
+        }
+
+        start_run();
+        let build_in = |pool: &mut PoolIndex| match *selection {
+            Selection::All => build::all(&self.config, pool, &*self.engine, options),
+            Selection::Packages(packages, force) => {
+                build::packages(&self.config, pool, &*self.engine, packages, force, options)
+            }
+            Selection::Rebuild(packages, reason) => {
+                build::rebuild(&self.config, pool, &*self.engine, packages, reason, options)
             }
+        };
+
+        // The packages of a frozen suite are staged until it is released.
+        let result = if self.config.is_frozen() {
+            let mut staging = pool::open_staging(&pool::staging_root(&self.config.archive))
+                .map_err(|why| RepoError::Pool { why })?;
+            build_in(&mut staging)
+        } else {
+            self.with_pool(build_in)?
+        };
+
+        let (summary, failures) = match result {
+            Ok(summary) => (summary, BuildFailures::default()),
+            Err(mut failed) => {
+                // An interrupt, or a lack of space, ended the batch, which is the error.
+                let ended = failed.failures.0.iter().position(|&(_, ref why)| match *why {
+                    BuildError::Interrupted { .. } | BuildError::Space { .. } => true,
+                    _ => false,
+                });
+
+                if let Some(pos) = ended {
+                    return Err(match failed.failures.0.remove(pos).1 {
+                        BuildError::Space { what, shortfalls } => {
+                            RepoError::Space { what, shortfalls }
+                        }
+                        _ => RepoError::Interrupted { summary: failed.summary },
+                    });
+                }
+
+                (failed.summary, failed.failures)
+            }
+        };
+
+        if !failures.is_empty() && options.fail_fast {
+            return Err(RepoError::Build { failures, summary });
+        }
+
+        // The pool of a frozen suite does not change until it is released.
+        if self.config.remove_obsolete_binaries.unwrap_or(false) && !self.config.is_frozen() {
+            self.remove_obsolete()?;
         }
 
-        self
+        run_hooks(&self.config, HookStage::PostPool, &[])?;
+        if !failures.is_empty() {
+            return Err(RepoError::Build { failures, summary });
+        }
+
+        Ok(summary)
+    }
+
+    /// Builds the named sources again, and compares what they built with the packages of the
+    /// pool, which are left as they are.
+    pub fn reproduce(
+        &self,
+        packages: &[&str],
+        options: BuildOptions,
+    ) -> Result<Vec<Reproduction>, RepoError> {
+        interrupted(Summary::default())?;
+        let reproduced = self.with_pool(|pool| {
+            build::reproduce(&self.config, pool, &*self.engine, packages, options)
+        })?;
+
+        reproduced.map_err(|failed| {
+            RepoError::Build { failures: failed.failures, summary: failed.summary }
+        })
     }
 
-    pub fn generate(self) {
-        if let Err(why) = generate_release_files(&self.config) {
-            error!("failed to generate dist files: {}", why);
-            exit(1);
+    /// The sources of the suite whose latest build failed, or was interrupted.
+    pub fn failed(&self) -> Result<Vec<String>, RepoError> {
+        self.records(|state| state.failed(&self.config.archive))
+    }
+
+    /// Generates and signs the dist files of the pool.
+    pub fn generate_metadata(&self, options: BuildOptions) -> Result<(), RepoError> {
+        interrupted(Summary::default())?;
+        let result = self.with_pool(|pool| {
+            generate_release_files(&self.config, Path::new("repo"), pool, options).map(|()| {
+                if let Err(why) = migrate::record_placements(pool) {
+                    warn!("failed to record when the files of the pool were published: {}", why);
+                }
+            })
+        })?;
+
+        match result {
+            Ok(()) => {
+                output::status(Status::Published, &self.config.archive, "");
+                run_hooks(&self.config, HookStage::PostMetadata, &[])
+            }
+            Err(why) => {
+                output::status(Status::Failed, &self.config.archive, "");
+                Err(RepoError::Release { why })
+            }
         }
     }
 
-    pub fn remove(self) -> Self {
-        if let Packages::Select(ref packages, _) = self.packages {
-            if let Err(why) = prepare::remove(packages, &self.config.archive) {
-                error!("failed to remove file: {}", why);
-                exit(1);
+    /// Generates the dist files, and writes the checksums of the files which this run published
+    /// to its manifest, returning the path of the manifest. A frozen suite is not published, and
+    /// the packages which were staged while a suite was frozen are published once it is not.
+    pub fn publish(&self, options: BuildOptions) -> Result<Option<PathBuf>, RepoError> {
+        if self.config.is_frozen() {
+            let archive = &self.config.archive;
+            warn!(
+                "{} is frozen, so it was not published; `debrep release --suite {}` publishes \
+                 the packages which were staged for it",
+                archive,
+                archive
+            );
+            output::status(Status::Frozen, archive, "not published");
+            if let Some(run) = run::current() {
+                let staged = pool::open_staging(&pool::staging_root(archive))
+                    .map(|pool| pool.files().iter().filter(|x| x.section != "source").count())
+                    .unwrap_or(0);
+                self.digest(&run, &Section::frozen(archive, staged));
             }
+
+            return Ok(None);
         }
 
-        self
+        self.release(options)
+    }
+
+    /// Fetches, builds, and publishes the selection. The run stops after fetching if any package
+    /// failed to download, unless `keep_going` is set, in which case the sources which were
+    /// fetched are built and published, and the failed downloads are the error. The packages
+    /// which were built are published even if others failed to build, unless `fail_fast` is set.
+    pub fn update(&self, selection: &Selection, options: BuildOptions) -> Result<(), RepoError> {
+        let fetched = self.fetch(selection, options);
+        let interrupted = match fetched {
+            Err(RepoError::Interrupted { .. }) => true,
+            _ => false,
+        };
+
+        if fetched.is_err() && (interrupted || !options.keep_going) {
+            return fetched.map(|_| ());
+        }
+
+        let result = match self.build(selection, options) {
+            Ok(_) => self.publish(options).map(|_| ()),
+            Err(failed @ RepoError::Build { .. }) => {
+                if !options.fail_fast {
+                    if let Err(why) = self.publish(options) {
+                        error!("{}", why);
+                    }
+                }
+
+                Err(failed)
+            }
+            Err(why) => Err(why),
+        };
+
+        match (fetched, result) {
+            (Err(fetch), Err(why)) => {
+                error!("{}", why);
+                Err(fetch)
+            }
+            (Err(fetch), Ok(_)) => Err(fetch),
+            (Ok(_), result) => result,
+        }
+    }
+
+    /// Moves the packages which were staged while the suite was frozen into its pool, and
+    /// publishes them in a single generation of its dist files, even if it is still frozen.
+    pub fn release(&self, options: BuildOptions) -> Result<Option<PathBuf>, RepoError> {
+        self.unstage(options)?;
+        self.publish_pool(options)
+    }
+
+    fn publish_pool(&self, options: BuildOptions) -> Result<Option<PathBuf>, RepoError> {
+        let before = self.published();
+        self.generate_metadata(options)?;
+
+        let run = match run::current() {
+            Some(run) => run,
+            None => return Ok(None),
+        };
+
+        let repo = Path::new("repo").canonicalize().unwrap_or_else(|_| PathBuf::from("repo"));
+        let signer = if self.config.sign_checksums {
+            Some(self.config.email.as_str())
+        } else {
+            None
+        };
+
+        let path = manifest::write(&run, &repo, &self.config.archive, signer)
+            .map_err(|why| RepoError::Manifest { why })?;
+
+        info!("wrote the checksums of published files to {}", path.display());
+        let file = path.file_name().map_or(String::new(), |x| x.to_string_lossy().into_owned());
+        if let Err(why) = run.append_report(&["checksums ", &file].concat()) {
+            warn!("failed to write the report of run {}: {}", run.id, why);
+        }
+
+        let recorded = state::State::open(Path::new("."))
+            .and_then(|state| state.provenance(None))
+            .and_then(|records| manifest::write_provenance(&run, &repo, &records, signer));
+        match recorded {
+            Ok(Some(path)) => {
+                info!("wrote the provenance of published files to {}", path.display());
+                let file = path.file_name().unwrap_or_default().to_string_lossy();
+                if let Err(why) = run.append_report(&["provenance ", &file].concat()) {
+                    warn!("failed to write the report of run {}: {}", run.id, why);
+                }
+            }
+            Ok(None) => (),
+            Err(why) => warn!("failed to write the provenance of published files: {}", why),
+        }
+
+        let digest = match (before, self.published()) {
+            (Some(before), Some(after)) => {
+                self.digest(&run, &Section::compare(&self.config.archive, &before, &after))
+            }
+            _ => None,
+        };
+
+        let mut vars = vec![
+            ("DEBREP_REPORT", run.report_path().display().to_string()),
+            ("DEBREP_CHECKSUMS", path.display().to_string()),
+        ];
+        if let Some(digest) = digest {
+            vars.push(("DEBREP_DIGEST", digest.display().to_string()));
+        }
+
+        let vars: Vec<(&str, &str)> = vars.iter()
+            .map(|&(key, ref value)| (key, value.as_str()))
+            .collect();
+        run_hooks(&self.config, HookStage::PostPublish, &vars)?;
+
+        // An upload which fails is attempted again by the next run, rather than failing this one.
+        if !options.no_upload && !options.offline {
+            if let Err(why) = self.upload(options) {
+                warn!("{}", why);
+            }
+        }
+
+        Ok(Some(path))
+    }
+
+    /// Uploads the built packages of the suite which are queued for the archives of their
+    /// `upload_targets`.
+    pub fn upload(&self, options: BuildOptions) -> Result<(), RepoError> {
+        if options.offline {
+            return Err(RepoError::Offline { what: "packages cannot be uploaded" });
+        }
+
+        match upload::run(&self.config, Path::new(".")) {
+            Ok(0) => Ok(()),
+            Ok(failed) => Err(RepoError::Upload { failed }),
+            Err(why) => Err(RepoError::UploadState { why }),
+        }
+    }
+
+    /// The uploads of the suite which are queued, and would be attempted by `upload`.
+    pub fn planned_uploads(&self) -> Result<Vec<PlannedUpload>, RepoError> {
+        upload::planned(&self.config, Path::new("."))
+            .map_err(|why| RepoError::UploadState { why })
+    }
+
+    /// Records that the files which were copied from the pool of another suite came from where
+    /// the files that they were copied from did.
+    fn record_copies(&self, from: &str, copies: &[(PathBuf, PathBuf)]) {
+        if copies.is_empty() {
+            return;
+        }
+
+        let result = state::State::open(Path::new(".")).and_then(|mut state| {
+            let originals = state.provenance(Some(from))?;
+            state.add_provenance(&provenance::copied(&originals, copies, &self.config.archive))
+        });
+
+        if let Err(why) = result {
+            warn!("failed to record the provenance of the files from {}: {}", from, why);
+        }
+    }
+
+    /// The binary packages which the dists of the suite publish, if they can be read.
+    fn published(&self) -> Option<Snapshot> {
+        match Snapshot::read(Path::new("repo"), &self.config.archive) {
+            Ok(snapshot) => Some(snapshot),
+            Err(why) => {
+                warn!("failed to read the published packages of {}: {}", self.config.archive, why);
+                None
+            }
+        }
+    }
+
+    /// Adds the section of the suite to the digest of the run, returning the path of the digest
+    /// which hooks are given, unless the suite is unchanged and the config skips those.
+    fn digest(&self, run: &run::Run, section: &Section) -> Option<PathBuf> {
+        let config = self.config.digest.clone().unwrap_or_default();
+        let format = config.format.unwrap_or(DigestFormat::Markdown);
+        match digest::write(run, section, format) {
+            Ok(_) if section.is_unchanged() && config.skip_unchanged.unwrap_or(false) => None,
+            Ok(path) => Some(path),
+            Err(why) => {
+                warn!("failed to write the digest of run {}: {}", run.id, why);
+                None
+            }
+        }
+    }
+
+    /// Migrates the packages of another suite to this suite, once they pass the gates of the
+    /// `[migration]` config, and publishes this suite if any migrated. Returns what migrated, and
+    /// why the other packages did not, which is also written to the report of the run.
+    pub fn migrate(
+        &self,
+        migration: &Migration,
+        options: BuildOptions,
+    ) -> Result<MigrationReport, RepoError> {
+        interrupted(Summary::default())?;
+        self.check_freeze("migrated to", options)?;
+        start_run();
+        let from = PoolIndex::open(&pool::root(migration.from))
+            .map_err(|why| RepoError::Pool { why })?;
+        let binaries = Binaries::open(Path::new(BINARIES));
+        let placed = FileRecord::open(Path::new(migrate::PLACED));
+        let tests = FileRecord::open(Path::new(migrate::TESTS));
+        let blocks = migrate::open_blocks(Path::new(migrate::BLOCKS))
+            .map_err(|why| RepoError::Blocks { file: migrate::BLOCKS, why })?;
+        let gates = Gates::new(&self.config, &placed, &tests, &blocks);
+
+        let mut report = MigrationReport { entries: Vec::new(), dry_run: migration.dry_run };
+        let mut copies = Vec::new();
+        let result = self.with_pool(|pool| -> io::Result<()> {
+            for candidate in migrate::candidates(&from, pool, &binaries)? {
+                let package = candidate.package.as_str();
+                if (!migration.only.is_empty() && !migration.only.contains(&package))
+                    || migration.skip.contains(&package)
+                {
+                    continue;
+                }
+
+                let verdict = gates.check(&candidate);
+                if verdict == Verdict::Migrates && !migration.dry_run {
+                    let debug_symbols = self.config.ddebs() != DdebHandling::Discard;
+                    for placed in migrate::promote(&candidate, pool, debug_symbols)? {
+                        let name = placed.file_name();
+                        let from = candidate.files.iter().find(|x| x.file_name() == name);
+                        copies.extend(from.map(|from| (from.clone(), placed.clone())));
+                    }
+
+                    info!("migrated {} {} to {}", package, candidate.version, self.config.archive);
+                }
+
+                report.entries.push((candidate, verdict));
+            }
+
+            Ok(())
+        })?;
+
+        result.map_err(|why| RepoError::Migrate { from: migration.from.to_owned(), why })?;
+        self.record_copies(migration.from, &copies);
+        if let Some(run) = run::current() {
+            if let Err(why) = run.write_report(report.to_string().trim_right()) {
+                warn!("failed to write the report of run {}: {}", run.id, why);
+            }
+        }
+
+        if !migration.dry_run && report.migrated() != 0 {
+            // A frozen suite is published without the packages which were staged for it.
+            if self.config.is_frozen() {
+                self.publish_pool(options)?;
+            } else {
+                self.publish(options)?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Simulates installing each of the configured packages, or every published package, from the
+    /// generated dists with apt. The results are written to the report of the run.
+    pub fn simulate_install(&self, options: BuildOptions) -> Result<(), RepoError> {
+        start_run();
+        let settings = self.config.apt_sim.as_ref();
+        let repo = Path::new("repo");
+        let archive = &self.config.archive;
+
+        let packages = match settings.and_then(|settings| settings.packages.clone()) {
+            Some(packages) => packages,
+            None => aptsim::published_packages(repo, archive).map_err(|why| {
+                RepoError::PublishedPackages { archive: archive.clone(), why }
+            })?,
+        };
+
+        let arch = settings.and_then(|settings| settings.architecture.clone())
+            .map_or_else(misc::host_architecture, Ok)
+            .map_err(|why| RepoError::Architecture { why })?;
+
+        let base = settings.and_then(|settings| settings.sources.clone()).unwrap_or_default();
+        if options.offline && !base.is_empty() {
+            return Err(RepoError::Offline {
+                what: "the apt sources of the simulation cannot be updated",
+            });
+        }
+
+        let state = AptState::new(repo, archive, &base, &arch)
+            .and_then(|state| state.update().map(|_| state))
+            .map_err(|why| RepoError::AptState { archive: archive.clone(), why })?;
+
+        let mut report = Vec::new();
+        let mut failed = 0;
+        for package in &packages {
+            let simulation = state.simulate(package)
+                .map_err(|why| RepoError::Simulate { package: package.clone(), why })?;
+
+            if simulation == Simulation::Installable {
+                info!("{} is installable", package);
+            } else {
+                error!("{} is not installable: {}", package, simulation);
+                failed += 1;
+            }
+
+            report.push(format!("apt-sim {} {}", package, simulation));
+        }
+
+        if let Some(run) = run::current() {
+            if let Err(why) = run.write_report(&report.join("\n")) {
+                warn!("failed to write the report of run {}: {}", run.id, why);
+            }
+        }
+
+        if failed != 0 {
+            return Err(RepoError::NotInstallable { failed, total: packages.len() });
+        }
+
+        info!("all {} packages are installable", packages.len());
+        Ok(())
+    }
+
+    /// Checks that the upstream tarballs of the pool still match the checksums which the `.dsc`
+    /// files that use them list, since they must never change once they are published, and that
+    /// the changelogs of its packages agree with the suite and their versions.
+    pub fn check_pool(&self, options: BuildOptions) -> Result<(), RepoError> {
+        let pool = Path::new("repo/pool");
+        let mut failed = 0;
+        let mut hashes = HashCache::open(HASHES, options.no_cache);
+        let violations = orig::check_pool(pool, &mut hashes);
+        save_hashes(&hashes);
+        let violations = violations.map_err(|why| RepoError::CheckPool { why })?;
+        if violations.is_empty() {
+            info!("the upstream tarballs of the pool match their source packages");
+        } else {
+            for violation in &violations {
+                error!(
+                    "{} changed after {} was published: expected {}, but found {}",
+                    violation.tarball,
+                    violation.dsc.display(),
+                    violation.expected,
+                    violation.found
+                );
+            }
+
+            error!("{} upstream tarballs changed after they were published", violations.len());
+            failed += violations.len();
+        }
+
+        let archive = &self.config.archive;
+        let mut cache = PackageCache::open(PACKAGE_CACHE);
+        let audited = audit::audit(&pool.join(archive), archive, &mut cache);
+        if let Err(why) = cache.save() {
+            warn!("failed to save the package cache: {}", why);
+        }
+
+        let mismatches = audited.map_err(|why| RepoError::Audit { why })?;
+        if mismatches.is_empty() {
+            info!("the changelogs of the pool match {} and its packages", archive);
+        } else {
+            for (source, messages) in &mismatches {
+                error!("{}:", source);
+                for message in messages {
+                    error!("    {}", message);
+                }
+            }
+
+            error!("the changelogs of {} source packages do not match", mismatches.len());
+            failed += mismatches.len();
+        }
+
+        let obsolete = self.obsolete()?;
+        for package in &obsolete {
+            warn!(
+                "{} is obsolete: the latest build of {} no longer produces it",
+                package.path.display(),
+                package.source
+            );
+        }
+
+        if !obsolete.is_empty() {
+            warn!(
+                "{} binary packages are obsolete; `debrep clean --obsolete-binaries` removes them",
+                obsolete.len()
+            );
+        }
+
+        let unrecorded = self.unrecorded()?;
+        for file in &unrecorded {
+            info!("{} has no recorded provenance", file);
+        }
+
+        if !unrecorded.is_empty() {
+            warn!(
+                "{} files of the pool have no recorded provenance; `debrep record backfill` \
+                 records them as legacy files",
+                unrecorded.len()
+            );
+        }
+
+        if failed != 0 {
+            return Err(RepoError::PoolMismatch { failed });
+        }
+
+        Ok(())
+    }
+
+    /// Checks the files listed in a checksums manifest against the repo.
+    pub fn check_manifest(&self, path: &Path, options: BuildOptions) -> Result<(), RepoError> {
+        let mut hashes = HashCache::open(HASHES, options.no_cache);
+        let checked = manifest::check(Path::new("repo"), path, &mut hashes);
+        save_hashes(&hashes);
+        let (listed, problems) = checked
+            .map_err(|why| RepoError::CheckManifest { manifest: path.to_path_buf(), why })?;
+
+        if problems.is_empty() {
+            info!("all {} files of {} match", listed, path.display());
+            return Ok(());
+        }
+
+        for problem in &problems {
+            match *problem {
+                manifest::Problem::Missing(ref file) => error!("{} is missing", file),
+                manifest::Problem::Mismatch(ref file) => {
+                    error!("{} does not match its checksum", file)
+                }
+                manifest::Problem::Malformed(line) => {
+                    error!("line {} of {} is not a checksum", line, path.display())
+                }
+            }
+        }
+
+        Err(RepoError::ManifestMismatch {
+            manifest: path.to_path_buf(),
+            failed: problems.len(),
+            listed,
+        })
+    }
+
+    /// The configured keys, and whether each is present, expired, or does not match.
+    pub fn list_keys(&self) -> Vec<KeyState> {
+        let root = Path::new(".");
+        self.config.keys.as_ref().map_or_else(Vec::new, |configured| {
+            configured.iter().map(|key| {
+                let keyring = keys::keyring_path(root, &key.name);
+                let (state, user_id) = if !keyring.exists() {
+                    ("missing", None)
+                } else {
+                    match keys::verify(key, &keyring) {
+                        Ok(ref info) if info.is_expired() => ("expired", info.user_id.clone()),
+                        Ok(info) => ("present", info.user_id),
+                        Err(_) => ("mismatch", None),
+                    }
+                };
+
+                KeyState {
+                    name: key.name.clone(),
+                    state,
+                    fingerprint: key.fingerprint.clone(),
+                    user_id,
+                }
+            }).collect()
+        })
+    }
+
+    /// Which keys signed the current `InRelease` file of each suite, and when they expire.
+    pub fn suite_keys(&self) -> Vec<SuiteKeyState> {
+        let suites = self.config.suite_names().unwrap_or_else(|| vec![self.config.archive.clone()]);
+        let mut states = Vec::new();
+        for suite in suites {
+            let in_release = Path::new("repo/dists").join(&suite).join("InRelease");
+            let signers = if in_release.exists() {
+                keys::signers(&in_release).unwrap_or_else(|why| {
+                    warn!("failed to verify {}: {}", in_release.display(), why);
+                    Vec::new()
+                })
+            } else {
+                Vec::new()
+            };
+
+            if signers.is_empty() {
+                states.push(SuiteKeyState {
+                    state: if in_release.exists() { "unsigned" } else { "unpublished" },
+                    suite,
+                    fingerprint: String::new(),
+                    user_id: None,
+                    expires: String::new(),
+                });
+                continue;
+            }
+
+            for signer in signers {
+                states.push(match signer {
+                    keys::Signer::Known(info) => SuiteKeyState {
+                        suite: suite.clone(),
+                        state: if info.is_expired() { "expired" } else { "valid" },
+                        expires: info.expiry(),
+                        fingerprint: info.fingerprint,
+                        user_id: info.user_id,
+                    },
+                    keys::Signer::Unknown(id) => SuiteKeyState {
+                        suite: suite.clone(),
+                        state: "unknown",
+                        fingerprint: id,
+                        user_id: None,
+                        expires: String::new(),
+                    },
+                });
+            }
+        }
+
+        states
+    }
+
+    /// Fetches the named keys again, or fetches every configured key which is not yet present if
+    /// no names are given.
+    pub fn fetch_keys(&self, names: &[&str], options: BuildOptions) -> Result<(), RepoError> {
+        if options.offline {
+            return Err(RepoError::Offline { what: "keys cannot be fetched" });
+        }
+
+        let root = Path::new(".");
+        let results: Vec<(String, Result<PathBuf, keys::KeyError>)> = if names.is_empty() {
+            self.config.keys.as_ref().map_or(Vec::new(), |configured| {
+                configured.iter()
+                    .map(|key| (key.name.clone(), keys::resolve(&self.config, root, &key.name)))
+                    .collect()
+            })
+        } else {
+            names.iter()
+                .map(|&name| (name.to_owned(), keys::fetch_named(&self.config, root, name)))
+                .collect()
+        };
+
+        let mut failed = 0;
+        for (name, result) in results {
+            match result {
+                Ok(keyring) => info!("key {} is stored in {}", name, keyring.display()),
+                Err(why) => {
+                    error!("{}", why);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed != 0 {
+            return Err(RepoError::Keys { failed });
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every configured key is present, has its pinned fingerprint, and has not
+    /// expired.
+    pub fn verify_keys(&self) -> Result<(), RepoError> {
+        let mut failed = 0;
+        if let Some(ref configured) = self.config.keys {
+            for key in configured {
+                let keyring = keys::keyring_path(Path::new("."), &key.name);
+                match keys::verify(key, &keyring) {
+                    Ok(ref info) if info.is_expired() => {
+                        error!("key {} ({}) has expired", key.name, key.fingerprint);
+                        failed += 1;
+                    }
+                    Ok(_) => info!("key {} is valid", key.name),
+                    Err(why) => {
+                        error!("{}", why);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+
+        if failed != 0 {
+            return Err(RepoError::Keys { failed });
+        }
+
+        Ok(())
+    }
+
+    /// Removes the packages from the pool.
+    pub fn remove(&self, packages: &[&str], options: BuildOptions) -> Result<(), RepoError> {
+        self.check_freeze("removed from", options)?;
+        self.with_pool(|pool| prepare::remove(packages, pool))?
+            .map_err(|why| RepoError::Remove { why })
+    }
+
+    /// Moves the packages which were staged for the suite into its pool, as a build would.
+    fn unstage(&self, options: BuildOptions) -> Result<(), RepoError> {
+        let root = pool::staging_root(&self.config.archive);
+        if !root.exists() {
+            return Ok(());
+        }
+
+        let staging = pool::open_staging(&root).map_err(|why| RepoError::Pool { why })?;
+        let dirs: BTreeSet<(&str, &Path)> = staging.files()
+            .into_iter()
+            .filter_map(|file| file.path.parent().map(|dir| (file.component.as_str(), dir)))
+            .collect();
+
+        let moved = self.with_pool(|pool| -> io::Result<usize> {
+            let mut moved = 0;
+            for &(component, dir) in &dirs {
+                moved += pool::mv_to_pool(
+                    dir,
+                    pool,
+                    component,
+                    true,
+                    options.replace_orig,
+                    options.replace_published,
+                )?.len();
+            }
+
+            fs::remove_dir_all(&root).map(|_| moved)
+        })?.map_err(|why| RepoError::Unstage { why })?;
+
+        info!("moved {} staged files into the pool of {}", moved, self.config.archive);
+        Ok(())
+    }
+
+    /// The binary packages of the pool which the latest builds of their sources no longer
+    /// produce.
+    fn obsolete(&self) -> Result<Vec<Obsolete>, RepoError> {
+        let state = state::State::open(Path::new(".")).map_err(|why| RepoError::State { why })?;
+        self.with_pool(|pool| obsolete::find(&self.config, &state, pool))?
+            .map_err(|why| RepoError::State { why })
+    }
+
+    /// The files of the pool of the suite whose provenance was never recorded.
+    fn unrecorded(&self) -> Result<Vec<String>, RepoError> {
+        let archive = &self.config.archive;
+        let recorded = state::State::open(Path::new("."))
+            .and_then(|state| state.provenance(Some(archive)))
+            .map_err(|why| RepoError::State { why })?;
+        Ok(provenance::unrecorded(Path::new("repo"), archive, &recorded))
+    }
+
+    /// Records the files of the pool of the suite whose provenance was never recorded as legacy
+    /// files, whose origins are unknown, returning how many were recorded.
+    pub fn backfill_provenance(&self) -> Result<usize, RepoError> {
+        let repo = Path::new("repo");
+        let archive = &self.config.archive;
+        let records: Vec<FileProvenance> = self.unrecorded()?
+            .iter()
+            .map(|file| provenance::legacy(repo, file, archive))
+            .collect();
+
+        state::State::open(Path::new("."))
+            .and_then(|mut state| state.add_provenance(&records))
+            .map_err(|why| RepoError::State { why })?;
+        info!("recorded {} files of the pool of {} as legacy files", records.len(), archive);
+        Ok(records.len())
+    }
+
+    /// Removes the obsolete binary packages from the pool, returning how many were removed.
+    fn remove_obsolete(&self) -> Result<usize, RepoError> {
+        let obsolete = self.obsolete()?;
+        if !obsolete.is_empty() {
+            self.with_pool(|pool| obsolete::remove(&obsolete, pool))?
+                .map_err(|why| RepoError::Clean { why })?;
+        }
+
+        Ok(obsolete.len())
+    }
+
+    /// Refuses to change the pool of a frozen suite, unless the freeze is overridden.
+    fn check_freeze(&self, action: &'static str, options: BuildOptions) -> Result<(), RepoError> {
+        let suite = &self.config.archive;
+        if !self.config.is_frozen() {
+            Ok(())
+        } else if options.override_freeze {
+            warn!("{} is frozen, but the freeze is overridden", suite);
+            Ok(())
+        } else {
+            Err(RepoError::Frozen { suite: suite.clone(), action })
+        }
+    }
+
+    /// Performs the action with the index of the pool, which is walked if it was not yet.
+    fn with_pool<T, F: FnOnce(&mut PoolIndex) -> T>(&self, action: F) -> Result<T, RepoError> {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.is_none() {
+            let index = PoolIndex::open(&pool::root(&self.config.archive))
+                .map_err(|why| RepoError::Pool { why })?;
+            *pool = Some(index);
+        }
+
+        Ok(action(pool.as_mut().unwrap()))
+    }
+}
+
+/// Runs the hooks of a stage of the run, with the report of the run, if it has one.
+fn run_hooks(config: &Config, stage: HookStage, vars: &[(&str, &str)]) -> Result<(), RepoError> {
+    let report = run::current()
+        .map_or(String::new(), |run| run.report_path().display().to_string());
+    let mut vars = vars.to_vec();
+    if !vars.iter().any(|&(key, _)| key == "DEBREP_REPORT") {
+        vars.push(("DEBREP_REPORT", &report));
+    }
+
+    hooks::run(config, stage, &vars).map_err(|why| RepoError::Hook { why })
+}
+
+/// Stops a phase before it starts any more work, if the run was interrupted.
+fn interrupted(summary: Summary) -> Result<(), RepoError> {
+    if interrupt::is_interrupted() {
+        Err(RepoError::Interrupted { summary })
+    } else {
+        Ok(())
+    }
+}
+
+/// Saves the digests which were computed, so that the files are not hashed again.
+fn save_hashes(hashes: &HashCache) {
+    if let Err(why) = hashes.save() {
+        warn!("failed to save the hash cache: {}", why);
+    }
+}
+
+/// What the repo recorded of its builds and runs, and what a run of it would do.
+impl Repo {
+    /// The latest build of each package of the suite.
+    pub fn latest_builds(&self, suite: &str) -> Result<Vec<Build>, RepoError> {
+        self.records(|state| state.latest(suite))
+    }
+
+    /// Every build of the package, newest first.
+    pub fn build_history(&self, package: &str) -> Result<Vec<Build>, RepoError> {
+        self.records(|state| state.history(package))
+    }
+
+    /// What is recorded of where the files of the package came from, in every suite: the files
+    /// which its source built, and those whose names are of the package.
+    pub fn provenance(&self, package: &str) -> Result<Vec<FileProvenance>, RepoError> {
+        let prefix = [package, "_"].concat();
+        let records = self.records(|state| state.provenance(None))?;
+        Ok(records
+            .into_iter()
+            .filter(|record| {
+                let name = Path::new(&record.path).file_name().and_then(|x| x.to_str());
+                record.source.as_ref().map_or(false, |source| source == package)
+                    || name.map_or(false, |name| name.starts_with(&prefix))
+            })
+            .collect())
+    }
+
+    /// The latest `window` builds of the package in the suite, oldest first, with what was
+    /// measured of those which succeeded.
+    pub fn build_stats(
+        &self,
+        suite: &str,
+        package: &str,
+        window: usize,
+    ) -> Result<Vec<Sample>, RepoError> {
+        self.records(|state| stats::samples(state, suite, package, window))
+    }
+
+    /// How each package of the suite changed over its latest `window` builds, from the package
+    /// which grew the most by the measure.
+    pub fn build_growth(
+        &self,
+        suite: &str,
+        window: usize,
+        by: Measure,
+    ) -> Result<Vec<Growth>, RepoError> {
+        self.records(|state| stats::growth(state, suite, window, by))
+    }
+
+    /// The latest build log of the package.
+    pub fn latest_log(&self, package: &str) -> Result<Vec<u8>, RepoError> {
+        logs::latest(Path::new("."), package)
+            .map_err(|why| RepoError::Log { package: package.to_owned(), why })
+    }
+
+    /// The reports of the most recent runs, newest first, as their IDs and reports.
+    pub fn recent_runs(&self, count: usize) -> Result<Vec<(String, String)>, RepoError> {
+        run::recent(Path::new("."), count).map_err(|why| RepoError::Runs { why })
+    }
+
+    /// Checks the commands, schroots, directories, keys, free space, and servers which runs of
+    /// the config need, without building anything.
+    pub fn doctor(&self, options: BuildOptions) -> Vec<Check> {
+        doctor::run(&self.config, options)
+    }
+
+    /// What a run of the phases would fetch and build of the selected packages, without fetching
+    /// or building anything.
+    pub fn dry_run(
+        &self,
+        selection: &Selection,
+        phases: &[Phase],
+    ) -> Result<Vec<Intent>, RepoError> {
+        dry_run::run(&self.config, selection, phases).map_err(|why| RepoError::DryRun { why })
+    }
+
+    /// The order which the selected sources would be built in, at their own priorities or at
+    /// `priority`, with the priority which each is built at.
+    pub fn build_order(&self, selection: &Selection, priority: Option<i32>) -> Vec<(String, i32)> {
+        let selected = selection.packages();
+        let items: Vec<Source> = self.config.build_units().into_iter()
+            .filter(|item| selected.map_or(true, |packages| {
+                packages.contains(&item.name.as_str()) || packages.contains(&item.base_name())
+            }))
+            .collect();
+
+        build::order(&items, priority)
+    }
+
+    /// The queues of the batches which are being built, with the builds which each has yet to
+    /// start, the urgent batch first.
+    pub fn build_queues(&self) -> Result<Vec<Queue>, RepoError> {
+        // The queues of a process which exited without removing them are stale.
+        let queues = match RepoLock::acquire(Path::new("."), false) {
+            Ok(Some(_)) => Ok(Vec::new()),
+            Ok(None) => build::queue(Path::new(".")),
+            Err(why) => Err(why),
+        };
+
+        queues.map_err(|why| RepoError::Queues { why })
+    }
+
+    /// The ID of the run which this process is performing, if it is building packages.
+    pub fn current_run() -> Option<String> {
+        run::current().map(|run| run.id.clone())
+    }
+
+    /// Ends the run of this process, so that the next work starts a run of its own, as each job
+    /// of `debrep serve` does. Returns the ID of the run which ended, if one was started.
+    pub fn end_run() -> Option<String> {
+        run::end()
+    }
+
+    /// Whether a batch of builds of this process is paused for urgent builds.
+    pub fn builds_paused() -> bool {
+        lock::is_paused()
+    }
+
+    /// Writes the outcome of each package which the current run built, or skipped, to the path
+    /// as a JUnit XML report, returning how many packages it lists. Without a run, the report is
+    /// empty.
+    pub fn write_junit(path: &Path) -> Result<usize, RepoError> {
+        let (name, cases) = match run::current() {
+            Some(run) => (["debrep run ", &run.id].concat(), run.cases()),
+            None => ("debrep".to_owned(), Vec::new()),
+        };
+
+        junit::write(path, &name, &cases)
+            .map(|()| cases.len())
+            .map_err(|why| RepoError::Junit { path: path.to_path_buf(), why })
+    }
+
+    /// Reads the state of builds.
+    fn records<T, F>(&self, read: F) -> Result<T, RepoError>
+        where F: FnOnce(&state::State) -> io::Result<T>
+    {
+        state::State::open(Path::new("."))
+            .and_then(|state| read(&state))
+            .map_err(|why| RepoError::State { why })
+    }
+}
+
+impl Check {
+    /// The checklist, with the hint of each check which did not pass under it, and a count of
+    /// the checks of each outcome at the end.
+    pub fn render(checks: &[Check]) -> String {
+        doctor::render(checks)
+    }
+}
+
+impl FileProvenance {
+    /// Lists the provenance of each file, with the fields which are known of it.
+    pub fn render(records: &[FileProvenance]) -> String {
+        provenance::render(records)
+    }
+}
+
+/// Starts the run which files that are published by this process are attributed to.
+fn start_run() {
+    if run::current().is_none() {
+        match run::start(Path::new(".")) {
+            Ok(run) => info!("starting run {}", run.id),
+            Err(why) => warn!("unable to create a log directory for this run: {}", why),
+        }
     }
 }
 
 #[derive(Debug, Fail)]
 pub enum ReleaseError {
+    #[fail(display = "{} pool files do not match the checksums of their direct entries", count)]
+    Checksums { count: usize },
+    #[fail(display = "failed to verify the files of direct entries: {}", why)]
+    Verify { why: io::Error },
     #[fail(display = "failed to generate release files for binaries: {}", why)]
     Binary { why: io::Error },
     #[fail(display = "failed to generate source index: {}", why)]
     Source { why: io::Error },
+    #[fail(display = "failed to generate Contents indices: {}", why)]
+    Contents { why: io::Error },
     #[fail(display = "failed to generate dist release files for {}: {}", archive, why)]
     Dists { archive: String, why: io::Error },
+    #[fail(display = "the key {} cannot sign the dists of {}: it {}", key, suite, why)]
+    SigningKey { suite: String, key: String, why: String },
     #[fail(display = "failed to generate InRelease file: {}", why)]
     InRelease { why: io::Error },
     #[fail(display = "failed to generate Release.gpg file: {}", why)]
     ReleaseGPG { why: io::Error },
+    #[fail(display = "failed to link the aliases of the suite: {}", why)]
+    Aliases { why: io::Error },
+}
+
+/// Signs the `Release` file with each signer, as its `InRelease` and `Release.gpg` files. Without
+/// signers, the signatures of an earlier `Release` file are removed instead, as apt would reject
+/// them.
+fn sign_release(
+    signers: &[&str],
+    release: &Path,
+    in_release: &Path,
+    release_gpg: &Path,
+) -> Result<(), ReleaseError> {
+    if signers.is_empty() {
+        for path in &[in_release, release_gpg] {
+            match fs::remove_file(path) {
+                Err(ref why) if why.kind() == io::ErrorKind::NotFound => (),
+                Err(why) => return Err(ReleaseError::InRelease { why }),
+                Ok(()) => (),
+            }
+        }
+
+        return Ok(());
+    }
+
+    generate::gpg_in_release(signers, release, in_release)
+        .map_err(|why| ReleaseError::InRelease { why })?;
+    generate::gpg_release(signers, release, release_gpg)
+        .map_err(|why| ReleaseError::ReleaseGPG { why })
 }
 
-/// Generate the dist release files from the existing binary and source files.
-fn generate_release_files(sources: &Config) -> Result<(), ReleaseError> {
-    env::set_current_dir("repo").expect("unable to switch dir to repo");
+/// Generate the dist release files of the repo from the existing binary and source files.
+fn generate_release_files(
+    sources: &Config,
+    repo: &Path,
+    pool_index: &PoolIndex,
+    options: BuildOptions,
+) -> Result<(), ReleaseError> {
+    let mut hashes = HashCache::open(HASHES, options.no_cache);
+    let excluded = verify_direct(sources, repo, options.strict, &mut hashes);
+    save_hashes(&hashes);
+    let excluded = excluded?;
     let base = ["dists/", &sources.archive].concat();
-    let pool = ["pool/", &sources.archive, "/main"].concat();
-    let _ = fs::create_dir_all(&base);
+    let pool = ["pool/", &sources.archive].concat();
+    fs::create_dir_all(repo.join(&base))
+        .map_err(|why| ReleaseError::Dists { archive: sources.archive.clone(), why })?;
 
-    let release = PathBuf::from([&base, "/Release"].concat());
-    let in_release = PathBuf::from([&base, "/InRelease"].concat());
-    let release_gpg = PathBuf::from([&base, "/Release.gpg"].concat());
+    let release = repo.join([&base, "/Release"].concat());
+    let in_release = repo.join([&base, "/InRelease"].concat());
+    let release_gpg = repo.join([&base, "/Release.gpg"].concat());
 
-    generate::generate_binary_files(sources, &base, &pool).map_err(|why| ReleaseError::Binary { why })?;
-    generate::generate_sources_index(&base, &pool).map_err(|why| ReleaseError::Source { why })?;
-    generate::generate_dists_release(sources, &base).map_err(|why| ReleaseError::Dists {
+    generate::generate_binary_files(sources, repo, &base, pool_index, &excluded)
+        .map_err(|why| ReleaseError::Binary { why })?;
+    let mut components = pool_index.components();
+    let suite = sources.effective_suite(&sources.archive);
+    for component in &suite.components {
+        if !components.contains(&component.as_str()) {
+            components.push(component);
+        }
+    }
+
+    if components.is_empty() {
+        components.push(DEFAULT_COMPONENT);
+    }
+
+    for component in components {
+        generate::generate_sources_index(repo, &base, &pool, component)
+            .map_err(|why| ReleaseError::Source { why })?;
+    }
+
+    if sources.contents == Some(true) {
+        generate::generate_contents(repo, &base, pool_index)
+            .map_err(|why| ReleaseError::Contents { why })?;
+    }
+    generate::generate_dists_release(sources, &repo.join(&base)).map_err(|why| ReleaseError::Dists {
         archive: sources.archive.clone(),
         why,
     })?;
 
-    generate::gpg_in_release(&sources.email, &release, &in_release)
-        .map_err(|why| ReleaseError::InRelease { why })?;
+    // Only the suite whose key cannot sign is not published.
+    let signers = if options.unsigned { Vec::new() } else { sources.release_signers() };
+    for signer in &signers {
+        keys::check_signer(signer).map_err(|why| ReleaseError::SigningKey {
+            suite: sources.archive.clone(),
+            key: signer.to_string(),
+            why,
+        })?;
+    }
 
-    generate::gpg_release(&sources.email, &release, &release_gpg)
-        .map_err(|why| ReleaseError::ReleaseGPG { why })
+    sign_release(&signers, &release, &in_release, &release_gpg)?;
+
+    // The debug suite lists the debug symbols of the packages in the pool of the suite.
+    if let Some(suite) = sources.debug_suite() {
+        let mut debug = sources.clone();
+        debug.archive = suite;
+        let base = repo.join("dists").join(&debug.archive);
+        fs::create_dir_all(&base)
+            .and_then(|()| generate::generate_dists_release(&debug, &base))
+            .map_err(|why| ReleaseError::Dists { archive: debug.archive.clone(), why })?;
+        let (release, in_release) = (base.join("Release"), base.join("InRelease"));
+        sign_release(&signers, &release, &in_release, &base.join("Release.gpg"))?;
+    }
+
+    if options.unsigned {
+        warn!("the dists of {} are not signed, so apt only trusts them with [trusted=yes]",
+            sources.archive);
+    }
+
+    let aliases = sources.effective_suite(&sources.archive).aliases;
+    generate::link_aliases(&repo.join("dists"), &sources.archive, &aliases)
+        .map_err(|why| ReleaseError::Aliases { why })
+}
+
+/// Verifies the pool files of direct entries against their checksums, within the repo. Files which
+/// do not match are excluded from the index, or fail the run if `strict`.
+fn verify_direct(
+    sources: &Config,
+    repo: &Path,
+    strict: bool,
+    hashes: &mut HashCache,
+) -> Result<Vec<String>, ReleaseError> {
+    let provenance = provenance::Provenance::open(Path::new(provenance::PROVENANCE));
+    let mismatches = provenance::verify(sources, &provenance, repo, hashes)
+        .map_err(|why| ReleaseError::Verify { why })?;
+
+    for mismatch in &mismatches {
+        let message = format!(
+            "{} of direct entry {} does not match its checksum: expected {}, found {}",
+            mismatch.file,
+            mismatch.entry,
+            mismatch.expected,
+            mismatch.found
+        );
+
+        if strict {
+            error!("{}", message);
+        } else {
+            warn!("{}; excluding it from the index", message);
+        }
+    }
+
+    if strict && !mismatches.is_empty() {
+        return Err(ReleaseError::Checksums { count: mismatches.len() });
+    }
+
+    Ok(mismatches.into_iter().map(|mismatch| mismatch.file).collect())
 }
@@ -0,0 +1,243 @@
+//! Binary packages of the pool which their source no longer builds, such as a `foo-doc` which
+//! upstream merged into `foo`. Nothing else removes them, since the source is still configured.
+//!
+//! A binary package is obsolete when an earlier build of a source in the suite placed it in the
+//! pool, and the latest build of that source which succeeded did not, while it did build other
+//! packages for the architecture of the package. A build which only built some architectures
+//! thus never makes the packages of the others obsolete. Packages which are downloaded or copied
+//! by the config, rather than built, are never obsolete. The debug symbols of a package are only
+//! obsolete along with it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use config::Config;
+use misc;
+use super::pool::PoolIndex;
+use super::state::State;
+
+/// A binary package of the pool which its source no longer builds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Obsolete {
+    /// The source whose earlier builds produced the package.
+    pub source: String,
+    pub path: PathBuf,
+}
+
+/// The binary packages which the builds of a source placed in the pool.
+#[derive(Debug, Default)]
+struct Produced {
+    /// The ID of the latest build which succeeded.
+    latest: i64,
+    /// The names of the packages of every build.
+    ever: BTreeSet<String>,
+    /// The names of the packages of the latest build.
+    names: BTreeSet<String>,
+    /// The architectures which the latest build built packages for.
+    architectures: BTreeSet<String>,
+}
+
+/// The binary packages of the pool of the suite which their sources no longer build.
+pub fn find(config: &Config, state: &State, pool: &PoolIndex) -> io::Result<Vec<Obsolete>> {
+    let units = config.build_units();
+    let mut sources: BTreeMap<String, Produced> = BTreeMap::new();
+    for (package, build, path) in state.placed(&config.archive)? {
+        if !units.iter().any(|unit| unit.name == package) {
+            continue;
+        }
+
+        let produced = sources.entry(package).or_insert_with(Produced::default);
+        if build != produced.latest {
+            produced.latest = build;
+            produced.names.clear();
+            produced.architectures.clear();
+        }
+
+        if let Some((name, arch)) = path.as_ref().and_then(|path| binary(path)) {
+            produced.ever.insert(name.clone());
+            produced.names.insert(name);
+            produced.architectures.insert(arch);
+        }
+    }
+
+    // A package which another source took over is built by that source.
+    let current: BTreeSet<&str> = sources.values()
+        .flat_map(|produced| produced.names.iter())
+        .map(|name| name.as_str())
+        .collect();
+
+    let mut obsolete = Vec::new();
+    let mut debug_symbols = Vec::new();
+    for file in pool.files() {
+        let (name, arch) = match binary(&file.path) {
+            Some(binary) => binary,
+            None => continue,
+        };
+
+        // The debug symbols of a package are obsolete along with it, and never apart from it.
+        if name.ends_with("-dbgsym") {
+            debug_symbols.push((name[..name.len() - 7].to_owned(), arch, file.path.clone()));
+            continue;
+        }
+
+        if current.contains(name.as_str()) || is_imported(config, &name) {
+            continue;
+        }
+
+        let owner = sources.iter().find(|&(_, produced)| {
+            produced.ever.contains(&name) && produced.architectures.contains(&arch)
+        });
+
+        if let Some((source, _)) = owner {
+            let package = Obsolete { source: source.clone(), path: file.path.clone() };
+            obsolete.push((name, arch, package));
+        }
+    }
+
+    let mut symbols = Vec::new();
+    for (name, arch, path) in debug_symbols {
+        let package = obsolete.iter().find(|&&(ref x, ref y, _)| *x == name && *y == arch);
+        if let Some(&(_, _, ref package)) = package {
+            symbols.push(Obsolete { source: package.source.clone(), path });
+        }
+    }
+
+    Ok(obsolete.into_iter().map(|(_, _, package)| package).chain(symbols).collect())
+}
+
+/// Removes the obsolete packages from the pool, along with the directories which they leave
+/// empty.
+pub fn remove(obsolete: &[Obsolete], pool: &mut PoolIndex) -> io::Result<()> {
+    for package in obsolete {
+        info!("removing {}, which {} no longer builds", package.path.display(), package.source);
+        fs::remove_file(&package.path)?;
+        pool.remove(&package.path);
+        if let Some(parent) = package.path.parent() {
+            // Fails if the directory still has the other versions of the package.
+            let _ = fs::remove_dir(parent);
+        }
+    }
+
+    Ok(())
+}
+
+/// The name and architecture of a binary package, or `None` if the file is not one.
+fn binary(path: &Path) -> Option<(String, String)> {
+    let extension = path.extension()?.to_str()?;
+    if !["deb", "udeb", "ddeb"].contains(&extension) {
+        return None;
+    }
+
+    let filename = path.file_name()?.to_str()?;
+    let stem = path.file_stem()?.to_str()?;
+    let name = &filename[..filename.find('_')?];
+    Some((name.to_owned(), misc::get_arch_from_stem(stem).to_owned()))
+}
+
+/// Whether the config downloads or copies the binary package, rather than building it.
+fn is_imported(config: &Config, name: &str) -> bool {
+    let mirrored = config.mirror.iter()
+        .flat_map(|mirrors| mirrors.iter())
+        .flat_map(|mirror| mirror.packages.iter());
+    let copied = config.ppa.iter()
+        .flat_map(|ppas| ppas.iter())
+        .flat_map(|ppa| ppa.packages.iter());
+
+    config.direct_exists(name)
+        || mirrored.chain(copied).any(|package| package.name == name)
+        || config.repack.iter().flat_map(|repacks| repacks.iter()).any(|x| x.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::state::{Build, BuildResult, Built, Metrics};
+    use tempdir::TempDir;
+    use toml;
+
+    fn build(state: &mut State, package: &str, artifacts: &[PathBuf]) {
+        let build = Build {
+            suite: "bionic".into(),
+            package: package.into(),
+            architecture: "amd64".into(),
+            run: None,
+            finished: 1_539_685_200,
+            seconds: 60,
+            result: BuildResult::Built,
+            version: None,
+            log: None,
+        };
+
+        let metrics = Metrics::default();
+        let built = Built {
+            record: None,
+            environment: "",
+            artifacts,
+            metrics: &metrics,
+            provenance: &[],
+            uploads: &[],
+            dependencies: &[],
+        };
+        state.add_build(&build, Some(built)).unwrap();
+    }
+
+    #[test]
+    fn binaries_which_the_latest_build_did_not_produce_are_obsolete() {
+        let dir = TempDir::new("debrep-obsolete").unwrap();
+        let root = dir.path().join("repo/pool/bionic/main");
+        let deb = |name: &str, arch: &str| {
+            root.join(format!("binary-{}/{}/{}/{}_1.0_{}.deb", arch, &name[..1], name, name, arch))
+        };
+
+        let config: Config = toml::from_str(r#"
+            archive = "bionic"
+            version = "18.04"
+            origin = "o"
+            label = "l"
+            email = "e"
+
+            [[direct]]
+            name = "foo-extra"
+            version = "1.0"
+
+            [[source]]
+            name = "foo"
+        "#).unwrap();
+
+        let earlier = vec![
+            deb("foo", "amd64"),
+            deb("foo", "i386"),
+            deb("foo-doc", "all"),
+            deb("foo-doc-dbgsym", "all"),
+            deb("foo-data", "i386"),
+            deb("foo-extra", "all"),
+        ];
+        for path in &earlier {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            misc::write(path, "").unwrap();
+        }
+
+        let mut state = State::open(dir.path()).unwrap();
+        build(&mut state, "foo", &earlier);
+        // The latest build only built amd64 packages.
+        build(&mut state, "foo", &[deb("foo", "amd64")]);
+
+        let pool = PoolIndex::open(root.parent().unwrap()).unwrap();
+        assert_eq!(find(&config, &state, &pool).unwrap(), Vec::new());
+
+        // Once it builds `all` packages again, foo-doc is obsolete.
+        build(&mut state, "foo", &[deb("foo", "amd64"), deb("foo-common", "all")]);
+        let obsolete = find(&config, &state, &pool).unwrap();
+        assert_eq!(obsolete, vec![
+            Obsolete { source: "foo".into(), path: deb("foo-doc", "all") },
+            Obsolete { source: "foo".into(), path: deb("foo-doc-dbgsym", "all") },
+        ]);
+
+        let mut pool = pool;
+        remove(&obsolete, &mut pool).unwrap();
+        assert!(!deb("foo-doc", "all").parent().unwrap().exists());
+        assert_eq!(find(&config, &state, &pool).unwrap(), Vec::new());
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use misc;
+use walkdir::WalkDir;
+use super::checksum::{sha2_256_digest, HashCache};
+
+/// Whether the file is an upstream tarball, such as `foo_1.2.orig.tar.gz`, or one of its
+/// components, such as `foo_1.2.orig-docs.tar.xz`.
+pub fn is_orig(filename: &str) -> bool {
+    filename.contains(".orig.tar.") || (filename.contains(".orig-") && filename.contains(".tar."))
+}
+
+/// How a tarball compares to the tarball of the same name which was already published.
+#[derive(Debug, PartialEq)]
+pub enum Published {
+    /// No tarball of that name was published.
+    No,
+    Identical,
+    Different,
+}
+
+pub fn compare(tarball: &Path, published: &Path) -> io::Result<Published> {
+    if !published.exists() {
+        return Ok(Published::No);
+    }
+
+    let new = sha2_256_digest(File::open(tarball)?)?;
+    let old = sha2_256_digest(File::open(published)?)?;
+    Ok(if new == old { Published::Identical } else { Published::Different })
+}
+
+/// A `.dsc` in the pool whose upstream tarball no longer matches the checksum that it lists.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub dsc: PathBuf,
+    pub tarball: String,
+    pub expected: String,
+    pub found: String,
+}
+
+/// Checks the upstream tarballs of every `.dsc` in the pool against the checksums which the
+/// `.dsc` lists for them. Tarballs which are missing from the pool are skipped.
+pub fn check_pool(pool: &Path, cache: &mut HashCache) -> io::Result<Vec<Violation>> {
+    let mut violations = Vec::new();
+    for entry in WalkDir::new(pool) {
+        let entry = entry?;
+        let dsc = entry.path();
+        if !entry.file_type().is_file() || dsc.extension().map_or(true, |ext| ext != "dsc") {
+            continue;
+        }
+
+        let dir = dsc.parent().unwrap_or(pool);
+        for (expected, tarball) in dsc_checksums(&misc::read_to_string(dsc)?) {
+            if !is_orig(&tarball) {
+                continue;
+            }
+
+            let found = match cache.sha256(&dir.join(&tarball)) {
+                Ok(digest) => digest,
+                Err(ref why) if why.kind() == io::ErrorKind::NotFound => continue,
+                Err(why) => return Err(why),
+            };
+
+            if found != expected {
+                violations.push(Violation { dsc: dsc.to_path_buf(), tarball, expected, found });
+            }
+        }
+    }
+
+    Ok(violations)
+}
+
+/// The digests and names of the files in the `Checksums-Sha256` field of a `.dsc`.
+fn dsc_checksums(dsc: &str) -> Vec<(String, String)> {
+    dsc.lines()
+        .skip_while(|line| !line.starts_with("Checksums-Sha256:"))
+        .skip(1)
+        .take_while(|line| line.starts_with(' '))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let digest = fields.next()?;
+            let name = fields.nth(1)?;
+            Some((digest.to_owned(), name.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn upstream_tarballs() {
+        assert!(is_orig("pop-shop_1.2.orig.tar.gz"));
+        assert!(is_orig("pop-shop_1.2.orig-docs.tar.xz"));
+        assert!(!is_orig("pop-shop_1.2-1.debian.tar.xz"));
+        assert!(!is_orig("pop-shop_1.2-1.tar.xz"));
+    }
+
+    #[test]
+    fn rewritten_tarballs_are_found() {
+        let root = TempDir::new("debrep-orig").unwrap();
+        let dir = root.path().join("bionic/main/source/p/pop-shop");
+        fs::create_dir_all(&dir).unwrap();
+        let tarball = dir.join("pop-shop_1.2.orig.tar.gz");
+        misc::write(&tarball, b"original").unwrap();
+        let digest = sha2_256_digest(File::open(&tarball).unwrap()).unwrap();
+
+        let dsc = format!(
+            "Format: 3.0 (quilt)\nSource: pop-shop\n\
+             Checksums-Sha256:\n {} 8 pop-shop_1.2.orig.tar.gz\n {} 3 pop-shop_1.2-1.debian.tar.xz\n\
+             Files:\n 0 8 pop-shop_1.2.orig.tar.gz\n",
+            digest,
+            "0".repeat(64)
+        );
+        misc::write(dir.join("pop-shop_1.2-1.dsc"), &dsc).unwrap();
+        let mut cache = HashCache::open(root.path().join("hashes"), false);
+        assert!(check_pool(root.path(), &mut cache).unwrap().is_empty());
+
+        let copy = root.path().join("copy.tar.gz");
+        misc::write(&copy, b"original").unwrap();
+        assert_eq!(compare(&copy, &tarball).unwrap(), Published::Identical);
+        assert_eq!(compare(&copy, &root.path().join("missing")).unwrap(), Published::No);
+
+        misc::write(&tarball, b"regenerated").unwrap();
+        assert_eq!(compare(&copy, &tarball).unwrap(), Published::Different);
+        let violations = check_pool(root.path(), &mut cache).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].tarball, "pop-shop_1.2.orig.tar.gz");
+        assert_eq!(violations[0].expected, digest);
+    }
+}
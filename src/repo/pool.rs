@@ -1,21 +1,388 @@
 use std::{io, fs};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
 use std::path::{Path, PathBuf};
+use config::DEFAULT_COMPONENT;
+use libc;
 use misc;
+use super::orig::{self, Published};
+use super::run;
+use walkdir::WalkDir;
 
-pub fn mv_to_pool<P: AsRef<Path>>(path: P, archive: &str, keep_source: bool) -> io::Result<()> {
-    pool(path.as_ref(), archive, |src, dst| if keep_source || !is_source(src) {
-        fs::rename(src, dst)
+/// The pool of an archive, with a directory for each of its components, relative to the root of
+/// the repo.
+pub fn root(archive: &str) -> PathBuf {
+    PathBuf::from(["repo/pool/", archive].concat())
+}
+
+/// Where the packages which are built for a frozen suite are staged until it is released, in
+/// the layout of its pool.
+pub fn staging_root(archive: &str) -> PathBuf {
+    PathBuf::from(["pool-staging/", archive].concat())
+}
+
+/// Opens the packages which are staged at the root. Packages which were staged before pools had
+/// components are moved into the default component first.
+pub fn open_staging(root: &Path) -> io::Result<PoolIndex> {
+    if root.is_dir() {
+        for entry in root.read_dir()? {
+            let name = entry?.file_name();
+            let section = name.to_string_lossy().into_owned();
+            if section == "source" || section.starts_with("binary-") {
+                let component = root.join(DEFAULT_COMPONENT);
+                fs::create_dir_all(&component)?;
+                fs::rename(root.join(&name), component.join(&name))?;
+            }
+        }
+    }
+
+    PoolIndex::open(root)
+}
+
+/// A file of the pool, at `<component>/<section>/<letter>/<package>/<name>_<version>...`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolFile {
+    pub path: PathBuf,
+    /// Such as `main`, or `restricted`.
+    pub component: String,
+    /// `source`, or `binary-<arch>`.
+    pub section: String,
+    /// The directory of the file, which is named after its package.
+    pub package: String,
+    /// The name of the package of the file, which is the part of its file name before the
+    /// first `_`.
+    pub name: String,
+}
+
+/// The files in the pool of an archive, which are walked once per run, and kept up to date as
+/// files are moved in and removed, so that builds, cleanups, and the generation of the dist
+/// files do not each walk the pool again.
+#[derive(Clone, Debug, Default)]
+pub struct PoolIndex {
+    root: PathBuf,
+    /// The sections of each component.
+    sections: BTreeMap<String, BTreeSet<String>>,
+    files: BTreeMap<PathBuf, PoolFile>,
+}
+
+impl PoolIndex {
+    /// Walks the pool at `root`, which is empty if it does not exist.
+    pub fn open(root: &Path) -> io::Result<PoolIndex> {
+        let mut index = PoolIndex { root: root.to_path_buf(), ..PoolIndex::default() };
+        if !root.is_dir() {
+            return Ok(index);
+        }
+
+        for entry in WalkDir::new(root).min_depth(1).max_depth(5) {
+            let entry = entry.map_err(io::Error::from)?;
+            if entry.depth() == 1 && entry.file_type().is_dir() {
+                let component = entry.file_name().to_string_lossy().into_owned();
+                index.sections.entry(component).or_insert_with(BTreeSet::new);
+            } else if entry.depth() == 2 && entry.file_type().is_dir() {
+                let component = entry.path().parent().and_then(|x| x.file_name());
+                let component = component.map_or(String::new(), |x| x.to_string_lossy().into());
+                let section = entry.file_name().to_string_lossy().into_owned();
+                index.sections.entry(component).or_insert_with(BTreeSet::new).insert(section);
+            } else if entry.depth() == 5 && !entry.file_type().is_dir() {
+                index.insert(entry.path());
+            }
+        }
+
+        debug!("{} files are in the pool at {}", index.files.len(), root.display());
+        Ok(index)
+    }
+
+    /// Walks the pool again, after another process changed it.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        let root = self.root.clone();
+        *self = PoolIndex::open(&root)?;
+        Ok(())
+    }
+
+    /// The components of the pool, in order.
+    pub fn components(&self) -> Vec<&str> {
+        self.sections.keys().map(|x| x.as_str()).collect()
+    }
+
+    /// The sections of the component: `source`, and a `binary-<arch>` for each architecture.
+    pub fn sections(&self, component: &str) -> Vec<&str> {
+        self.sections.get(component).map_or_else(Vec::new, |sections| {
+            sections.iter().map(|x| x.as_str()).collect()
+        })
+    }
+
+    /// Every file in the pool, in the order of their paths.
+    pub fn files(&self) -> Vec<&PoolFile> {
+        self.files.values().collect()
+    }
+
+    /// The `.deb` files of the named binary packages, in the order of the names.
+    pub fn debs_of(&self, names: &[String]) -> Vec<&PoolFile> {
+        let mut debs: Vec<(usize, &PoolFile)> = self.files.values()
+            .filter(|file| file.path.extension().map_or(false, |ext| ext == "deb"))
+            .filter_map(|file| names.iter().position(|x| *x == file.name).map(|pos| (pos, file)))
+            .collect();
+
+        debs.sort_by_key(|&(pos, _)| pos);
+        debs.into_iter().map(|(_, file)| file).collect()
+    }
+
+    /// The directories of each package, in every section.
+    pub fn package_dirs(&self) -> BTreeSet<PathBuf> {
+        self.files.values()
+            .filter_map(|file| file.path.parent().map(Path::to_path_buf))
+            .collect()
+    }
+
+    /// Adds a file which was moved into the pool. Paths which are not at the depth of files of
+    /// the pool are ignored.
+    pub fn insert(&mut self, path: &Path) {
+        let file = {
+            let components: Vec<String> = match path.strip_prefix(&self.root) {
+                Ok(relative) => relative.iter().map(|x| x.to_string_lossy().into_owned()).collect(),
+                Err(_) => return,
+            };
+
+            if components.len() != 5 {
+                return;
+            }
+
+            let filename = &components[4];
+            PoolFile {
+                path: path.to_path_buf(),
+                component: components[0].clone(),
+                section: components[1].clone(),
+                package: components[3].clone(),
+                name: filename[..filename.find('_').unwrap_or(filename.len())].to_owned(),
+            }
+        };
+
+        self.sections.entry(file.component.clone())
+            .or_insert_with(BTreeSet::new)
+            .insert(file.section.clone());
+        self.files.insert(file.path.clone(), file);
+    }
+
+    /// Forgets a file which was removed from the pool.
+    pub fn remove(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Forgets the files of a package directory which was removed from the pool.
+    pub fn remove_dir(&mut self, dir: &Path) {
+        let paths: Vec<PathBuf> = self.files.keys()
+            .filter(|path| path.starts_with(dir))
+            .cloned()
+            .collect();
+
+        for path in paths {
+            self.files.remove(&path);
+        }
+    }
+}
+
+/// The component of a file of a pool, which is the directory four levels above it.
+pub fn component_of(path: &Path) -> Option<String> {
+    let dir = path.ancestors().nth(4)?;
+    dir.file_name().map(|x| x.to_string_lossy().into_owned())
+}
+
+/// Moves the files that were built into the component of the pool, adds them to its index, and
+/// returns where they were placed. Upstream tarballs which were already published are kept, and
+/// must not change unless `replace_orig` is set. Other packages must not change unless
+/// `replace_published` is set, so that a version is never published with two different contents.
+pub fn mv_to_pool<P: AsRef<Path>>(
+    path: P,
+    index: &mut PoolIndex,
+    component: &str,
+    keep_source: bool,
+    replace_orig: bool,
+    replace_published: bool,
+) -> io::Result<Vec<PathBuf>> {
+    pool(path.as_ref(), index, component, |src, dst| if keep_source || !is_source(src) {
+        let filename = src.file_name().and_then(|x| x.to_str()).unwrap_or("");
+        let (replace, flag, what) = if orig::is_orig(filename) {
+            (replace_orig, "--replace-orig", "the upstream version")
+        } else if is_package(filename) {
+            (replace_published, "--replace-published", "the version")
+        } else {
+            (true, "", "")
+        };
+
+        match orig::compare(src, dst)? {
+            Published::Identical => {
+                info!("{} was already published", filename);
+                return fs::remove_file(src).map(|_| true);
+            }
+            Published::Different if !replace => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} differs from the file which was already published; bump {}, or \
+                         pass {} to replace it",
+                        filename,
+                        what,
+                        flag
+                    )
+                ));
+            }
+            Published::Different if !flag.is_empty() => {
+                warn!("replacing the published {}", filename)
+            }
+            _ => (),
+        }
+
+        place(src, dst)?;
+        if let Some(run) = run::current() {
+            run.add_published(dst.canonicalize()?);
+        }
+        Ok(true)
     } else {
-        fs::remove_file(src)
+        fs::remove_file(src).map(|_| false)
     })
 }
 
+/// Removes the files of the source package from a directory of built files whose names the pool
+/// already has, returning how many were removed. A build which only added architectures to a
+/// version builds its source package again, which must not replace the one which the packages of
+/// the other architectures were built from, even if it differs.
+pub fn keep_published_source(dir: &Path, index: &PoolIndex) -> io::Result<usize> {
+    let mut kept = 0;
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        let filename = match path.file_name().and_then(|x| x.to_str()) {
+            Some(filename) if filename.ends_with(".dsc") || filename.contains(".tar.") => {
+                filename.to_owned()
+            }
+            _ => continue,
+        };
+
+        let published = index.files.values().any(|file| {
+            file.section == "source" && file.path.file_name() == Some(filename.as_ref())
+        });
+        if published {
+            fs::remove_file(&path)?;
+            kept += 1;
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Whether the file is one which apt downloads: a binary package, or a file of a source package.
+fn is_package(filename: &str) -> bool {
+    [".deb", ".udeb", ".ddeb", ".dsc"].iter().any(|ext| filename.ends_with(ext))
+        || filename.contains(".tar.")
+}
+
+/// Whether the file is a debug symbol package, which is a `.ddeb`, or a `-dbgsym` package of
+/// builders which name them `.deb`.
+pub fn is_debug_symbols(filename: &str) -> bool {
+    filename.ends_with(".ddeb")
+        || filename.ends_with(".deb") && filename.split('_').next().map_or(false, |name| {
+            name.ends_with("-dbgsym")
+        })
+}
+
+/// Removes the debug symbol packages of a directory of built files, before they are moved into
+/// the pool, returning how many were removed.
+pub fn discard_debug_symbols(dir: &Path) -> io::Result<usize> {
+    let mut discarded = 0;
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.file_name().and_then(|x| x.to_str()).map_or(false, is_debug_symbols) {
+            fs::remove_file(&path)?;
+            discarded += 1;
+        }
+    }
+
+    Ok(discarded)
+}
+
+/// The sizes of the binary packages which were placed in the pool, in bytes, with their debug
+/// symbols apart, which are often much larger than the packages.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sizes {
+    pub packages: u64,
+    pub debug: u64,
+}
+
+impl Sizes {
+    pub fn of(placed: &[PathBuf]) -> Sizes {
+        let mut sizes = Sizes::default();
+        for path in placed {
+            let filename = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+            let size = || fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+            if is_debug_symbols(filename) {
+                sizes.debug += size();
+            } else if filename.ends_with(".deb") || filename.ends_with(".udeb") {
+                sizes.packages += size();
+            }
+        }
+
+        sizes
+    }
+}
+
+/// Moves the file to its destination, which is synced along with its directory, so that a crash
+/// cannot leave an empty or partial file under the name of a package. Files are renamed when the
+/// build and the pool share a file system, and otherwise copied next to the destination, and
+/// renamed over it once the copy is complete.
+fn place(src: &Path, dst: &Path) -> io::Result<()> {
+    File::open(src)?.sync_all()?;
+    match fs::rename(src, dst) {
+        Ok(()) => (),
+        Err(ref why) if why.raw_os_error() == Some(libc::EXDEV) => {
+            copy(src, dst)?;
+            fs::remove_file(src)?;
+        }
+        Err(why) => return Err(why),
+    }
+
+    sync_parent(dst)
+}
+
+/// Copies the file to a temporary file beside the destination, syncs it, and renames it over the
+/// destination. The temporary file is removed if any of that fails.
+fn copy(src: &Path, dst: &Path) -> io::Result<()> {
+    let name = dst.file_name().map_or_else(String::new, |x| x.to_string_lossy().into_owned());
+    let temporary = dst.with_file_name([".", &name, ".partial"].concat());
+    let result = fs::copy(src, &temporary)
+        .and_then(|_| File::open(&temporary)?.sync_all())
+        .and_then(|_| fs::rename(&temporary, dst));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+
+    result
+}
+
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
 fn is_source(src: &Path) -> bool {
     let path = src.to_str().unwrap();
     path.ends_with(".dsc") || path.ends_with(".tar.gz") || path.ends_with(".tar.xz")
 }
 
-fn pool<F: Fn(&Path, &Path) -> io::Result<()>>(path: &Path, archive: &str, action: F) -> io::Result<()> {
+/// Performs the action on each file of the directory, with its destination in the component of
+/// the pool, and indexes the destination if the action placed the file there. Returns what was
+/// placed.
+fn pool<F>(
+    path: &Path,
+    index: &mut PoolIndex,
+    component: &str,
+    action: F,
+) -> io::Result<Vec<PathBuf>>
+    where F: Fn(&Path, &Path) -> io::Result<bool>
+{
+    let root = index.root.join(component);
+    let mut placed = Vec::new();
     for entry in path.read_dir()? {
         let entry = entry?;
         let path = entry.path();
@@ -29,11 +396,10 @@ fn pool<F: Fn(&Path, &Path) -> io::Result<()>>(path: &Path, archive: &str, actio
         if let (Some(filename), Some(filestem)) = (filename, filestem) {
             let mut package = &filename[..filename.find('_').unwrap_or(0)];
 
-            let is_source = ["dsc", "tar.xz"].into_iter().any(|ext| filename.ends_with(ext));
+            // Upstream tarballs may be compressed with gzip or bzip2, as well as xz.
+            let is_source = filename.ends_with(".dsc") || filename.contains(".tar.");
             let destination = if is_source {
-                PathBuf::from(
-                    ["repo/pool/", archive, "/main/source/", &package[0..1], "/", package].concat()
-                )
+                root.join(["source/", &package[0..1], "/", package].concat())
             } else {
                 if package.ends_with("-dbgsym") {
                     package = &package[..package.len() - 7];
@@ -41,16 +407,193 @@ fn pool<F: Fn(&Path, &Path) -> io::Result<()>>(path: &Path, archive: &str, actio
 
                 let arch = misc::get_arch_from_stem(filestem);
 
-                PathBuf::from(
-                    ["repo/pool/", archive, "/main/binary-", arch, "/", &package[0..1], "/", package].concat(),
-                )
+                root.join(["binary-", arch, "/", &package[0..1], "/", package].concat())
             };
 
             info!("creating in pool: {:?}", destination);
             fs::create_dir_all(&destination)?;
-            action(&path, &destination.join(filename))?;
+            let destination = destination.join(filename);
+            if action(&path, &destination)? {
+                index.insert(&destination);
+                placed.push(destination);
+            }
+        }
+    }
+
+    Ok(placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn the_pool_is_indexed_once_and_kept_up_to_date() {
+        let dir = TempDir::new("debrep-pool").unwrap();
+        let root = dir.path().join("repo/pool/bionic/main");
+        let restricted = dir.path().join("repo/pool/bionic/restricted");
+        let files = [
+            "binary-amd64/l/libfoo/libfoo-dev_1.0_amd64.deb",
+            "binary-amd64/l/libfoo/libfoo1_1.0_amd64.deb",
+            "binary-amd64/l/libfoo/libfoo1-dbgsym_1.0_amd64.ddeb",
+            "binary-all/p/pop-icons/pop-icons_2.0_all.deb",
+            "source/l/libfoo/libfoo_1.0.dsc",
+        ];
+        for file in &files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            misc::write(&path, b"").unwrap();
         }
+        fs::create_dir_all(root.join("binary-i386")).unwrap();
+
+        let mut index = PoolIndex::open(root.parent().unwrap()).unwrap();
+        assert_eq!(index.files().len(), files.len());
+        assert_eq!(index.components(), vec!["main"]);
+        assert_eq!(
+            index.sections("main"),
+            vec!["binary-all", "binary-amd64", "binary-i386", "source"]
+        );
+
+        // Extra packages are listed in the order of the dependencies.
+        let depends = vec!["pop-icons".to_owned(), "libfoo1".to_owned(), "libfoo".to_owned()];
+        let debs: Vec<&Path> = index.debs_of(&depends).iter().map(|x| x.path.as_path()).collect();
+        assert_eq!(debs, vec![root.join(files[3]), root.join(files[1])]);
+
+        let built = dir.path().join("build");
+        fs::create_dir_all(&built).unwrap();
+        misc::write(built.join("libfoo1_1.1_amd64.deb"), b"").unwrap();
+        misc::write(built.join("libfoo_1.1.dsc"), b"").unwrap();
+        mv_to_pool(&built, &mut index, "main", false, false, false).unwrap();
+        let new = root.join("binary-amd64/l/libfoo1/libfoo1_1.1_amd64.deb");
+        assert!(new.exists());
+        assert_eq!(index.debs_of(&depends[1..2]).last().unwrap().path, new);
+        assert_eq!(index.files().len(), files.len() + 1);
+
+        index.remove_dir(&root.join("binary-amd64/l/libfoo"));
+        let names: Vec<&str> = index.files().iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["pop-icons", "libfoo1", "libfoo"]);
+
+        // Packages of other components are placed and indexed apart.
+        misc::write(built.join("pop-codecs_1.0_amd64.deb"), b"").unwrap();
+        mv_to_pool(&built, &mut index, "restricted", false, false, false).unwrap();
+        let codecs = restricted.join("binary-amd64/p/pop-codecs/pop-codecs_1.0_amd64.deb");
+        assert!(codecs.exists());
+        assert_eq!(index.components(), vec!["main", "restricted"]);
+        assert_eq!(index.sections("restricted"), vec!["binary-amd64"]);
+        assert_eq!(index.files().last().unwrap().component, "restricted");
+        let reopened = PoolIndex::open(root.parent().unwrap()).unwrap();
+        assert_eq!(reopened.files().last().unwrap().path, codecs);
     }
 
-    Ok(())
+    #[test]
+    fn published_packages_are_not_changed_without_a_flag() {
+        let dir = TempDir::new("debrep-pool").unwrap();
+        let root = dir.path().join("pool");
+        let built = dir.path().join("build");
+        let published = root.join("main/binary-amd64/p/pop-shop/pop-shop_1.0_amd64.deb");
+        fs::create_dir_all(published.parent().unwrap()).unwrap();
+        fs::create_dir_all(&built).unwrap();
+        misc::write(&published, b"published").unwrap();
+        let mut index = PoolIndex::open(&root).unwrap();
+
+        // An identical package, and files which are not packages, are placed as they are.
+        misc::write(built.join("pop-shop_1.0_amd64.deb"), b"published").unwrap();
+        misc::write(built.join("pop-shop_1.0_amd64.changes"), b"changes").unwrap();
+        let mut placed = mv_to_pool(&built, &mut index, "main", true, false, false).unwrap();
+        placed.sort();
+        assert_eq!(placed, vec![
+            published.with_extension("changes"),
+            published.clone(),
+        ]);
+
+        misc::write(built.join("pop-shop_1.0_amd64.deb"), b"rebuilt").unwrap();
+        let why = mv_to_pool(&built, &mut index, "main", true, false, false).unwrap_err();
+        assert_eq!(why.kind(), io::ErrorKind::AlreadyExists);
+        assert!(why.to_string().contains("--replace-published"), "{}", why);
+        assert_eq!(misc::read(&published).unwrap(), b"published");
+
+        mv_to_pool(&built, &mut index, "main", true, false, true).unwrap();
+        assert_eq!(misc::read(&published).unwrap(), b"rebuilt");
+        assert_eq!(fs::read_dir(&built).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn added_architectures_keep_the_published_source() {
+        let dir = TempDir::new("debrep-pool").unwrap();
+        let root = dir.path().join("pool");
+        let built = dir.path().join("build");
+        let dsc = root.join("main/source/p/pop-shop/pop-shop_1.0.dsc");
+        fs::create_dir_all(dsc.parent().unwrap()).unwrap();
+        fs::create_dir_all(&built).unwrap();
+        misc::write(&dsc, b"published").unwrap();
+        let mut index = PoolIndex::open(&root).unwrap();
+
+        for name in &["pop-shop_1.0.dsc", "pop-shop_1.0.tar.xz", "pop-shop_1.0_i386.deb"] {
+            misc::write(built.join(name), b"rebuilt").unwrap();
+        }
+
+        assert_eq!(keep_published_source(&built, &index).unwrap(), 1);
+        let mut placed = mv_to_pool(&built, &mut index, "main", true, false, false).unwrap();
+        placed.sort();
+        assert_eq!(placed, vec![
+            root.join("main/binary-i386/p/pop-shop/pop-shop_1.0_i386.deb"),
+            root.join("main/source/p/pop-shop/pop-shop_1.0.tar.xz"),
+        ]);
+        assert_eq!(misc::read(&dsc).unwrap(), b"published");
+    }
+
+    #[test]
+    fn staged_packages_move_into_the_default_component() {
+        let dir = TempDir::new("debrep-pool").unwrap();
+        let root = dir.path().join("pool-staging/bionic");
+        let staged = root.join("binary-amd64/p/pop-shop/pop-shop_1.0_amd64.deb");
+        fs::create_dir_all(staged.parent().unwrap()).unwrap();
+        misc::write(&staged, b"").unwrap();
+
+        let index = open_staging(&root).unwrap();
+        let files = index.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].component, "main");
+        assert!(root.join("main/binary-amd64/p/pop-shop").is_dir());
+    }
+
+    #[test]
+    fn copies_are_complete_before_they_are_named() {
+        let dir = TempDir::new("debrep-pool").unwrap();
+        let (src, dst) = (dir.path().join("src.deb"), dir.path().join("dst.deb"));
+        misc::write(&src, b"package").unwrap();
+        misc::write(&dst, b"old").unwrap();
+        copy(&src, &dst).unwrap();
+        assert_eq!(misc::read(&dst).unwrap(), b"package");
+
+        // A copy which fails leaves neither a partial file, nor a changed destination.
+        assert!(copy(&dir.path().join("missing.deb"), &dst).is_err());
+        assert_eq!(misc::read(&dst).unwrap(), b"package");
+        let mut names: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["dst.deb", "src.deb"]);
+    }
+
+    #[test]
+    fn debug_symbols_are_sized_apart_and_discarded() {
+        let dir = TempDir::new("debrep-pool").unwrap();
+        let files = [
+            ("pop-shop_1.0_amd64.deb", 100),
+            ("pop-shop-dbgsym_1.0_amd64.ddeb", 4000),
+            ("libpop1-dbgsym_1.0_amd64.deb", 2000),
+            ("pop-shop_1.0_amd64.changes", 10),
+        ];
+        for &(name, size) in &files {
+            misc::write(dir.path().join(name), vec![0; size]).unwrap();
+        }
+
+        let placed: Vec<PathBuf> = files.iter().map(|&(name, _)| dir.path().join(name)).collect();
+        assert_eq!(Sizes::of(&placed), Sizes { packages: 100, debug: 6000 });
+
+        assert_eq!(discard_debug_symbols(dir.path()).unwrap(), 2);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
 }
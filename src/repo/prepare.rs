@@ -1,26 +1,160 @@
 use std::{fs, io};
 use std::path::{Path, PathBuf};
-use config::Config;
+use config::{Config, Source, SOURCES};
+use misc;
+use super::pool::PoolIndex;
 use super::version::changelog;
-use walkdir::{DirEntry, WalkDir};
 
 pub const SHARED_ASSETS: &str = "assets/share/";
 pub const PACKAGE_ASSETS: &str = "assets/packages/";
 
-pub fn create_missing_directories() -> io::Result<()> {
-    [SHARED_ASSETS, PACKAGE_ASSETS, "build", "record", "sources", "logs"].iter()
-        .map(|dir| if Path::new(dir).exists() { Ok(()) } else { fs::create_dir_all(dir) })
-        .collect::<io::Result<()>>()
+/// The directories of every repo, which are created if they are missing.
+const SKELETON: &[&str] = &[
+    SHARED_ASSETS,
+    PACKAGE_ASSETS,
+    "assets/cache",
+    "build",
+    "debian",
+    "logs",
+    "record",
+    "repo",
+    "sources",
+    "state",
+];
+
+/// The config which `debrep init` writes for new repos.
+const EXAMPLE_CONFIG: &str = include_str!("example.toml");
+
+/// Creates the directories of a repo at `root` which are missing, including the pool and dists of
+/// the archive, if it is known. Returns the directories which were created.
+pub fn create_missing_directories(root: &Path, archive: Option<&str>) -> io::Result<Vec<PathBuf>> {
+    let mut dirs: Vec<PathBuf> = SKELETON.iter().map(PathBuf::from).collect();
+    if let Some(archive) = archive {
+        dirs.push(PathBuf::from(["repo/pool/", archive, "/main"].concat()));
+        dirs.push(PathBuf::from(["repo/dists/", archive].concat()));
+    }
+
+    let mut created = Vec::new();
+    for dir in dirs {
+        let path = root.join(&dir);
+        if !path.is_dir() {
+            fs::create_dir_all(&path).map_err(|why| {
+                io::Error::new(why.kind(), format!("failed to create {}: {}", path.display(), why))
+            })?;
+            created.push(dir);
+        }
+    }
+
+    Ok(created)
+}
+
+/// The suites which have a pool in the repo at `root`.
+pub fn pools(root: &Path) -> Vec<String> {
+    let mut suites: Vec<String> = fs::read_dir(root.join("repo/pool"))
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    suites.sort();
+    suites
+}
+
+/// Checks that the archive is a suite of the repo at `root`, before its pool is created. Its
+/// suites are those of `suites` in the config if it is set, and otherwise the suites which have
+/// pools, and which sources and variants are built for. A new repo has no suites, and takes the
+/// archive as its first. Returns the known suites if the archive is not one of them.
+pub fn unknown_suite(root: &Path, config: &Config) -> Option<Vec<String>> {
+    let known = match config.suite_names() {
+        Some(suites) => suites,
+        None => {
+            let mut known = pools(root);
+            let sources = config.source.iter().flat_map(|sources| sources.iter());
+            for source in sources {
+                let variants = source.variants.iter().flat_map(|variants| variants.iter());
+                let suites = source.suites.iter()
+                    .chain(variants.filter_map(|variant| variant.suites.as_ref()));
+                for suites in suites {
+                    known.extend(suites.iter().cloned());
+                }
+            }
+
+            known.sort();
+            known.dedup();
+            if known.is_empty() {
+                return None;
+            }
+
+            known
+        }
+    };
+
+    if known.contains(&config.archive) {
+        None
+    } else {
+        Some(known)
+    }
+}
+
+/// The name which is closest to the misspelled name, if one is close enough to suggest.
+pub fn closest<'a>(name: &str, names: &'a [String]) -> Option<&'a str> {
+    names.iter()
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= 2)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+/// The edit distance between two names, counting a swap of adjacent letters as one edit.
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..a.len() + 1 {
+        table[i][0] = i;
+    }
+    for j in 0..b.len() + 1 {
+        table[0][j] = j;
+    }
+
+    for i in 1..a.len() + 1 {
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut best = (table[i - 1][j] + 1)
+                .min(table[i][j - 1] + 1)
+                .min(table[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(table[i - 2][j - 2] + 1);
+            }
+            table[i][j] = best;
+        }
+    }
+
+    table[a.len()][b.len()]
+}
+
+/// Lays out the directories of a new repo at `root`, and writes an example config to it unless
+/// it already has one. Returns what was created.
+pub fn init(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut created = create_missing_directories(root, None)?;
+    let config = root.join(SOURCES);
+    if !config.exists() {
+        misc::write(&config, EXAMPLE_CONFIG.as_bytes())?;
+        created.push(PathBuf::from(SOURCES));
+    }
+
+    Ok(created)
 }
 
-pub fn package_cleanup(config: &Config) -> io::Result<()> {
-    let path = PathBuf::from(["repo/pool/", &config.archive, "/main"].concat());
-    for entry in WalkDir::new(path).min_depth(3).max_depth(3).into_iter().filter_map(|x| x.ok()) {
-        let path = entry.path();
+pub fn package_cleanup(config: &Config, pool: &mut PoolIndex) -> io::Result<()> {
+    for path in pool.package_dirs() {
         if let Some(filename) = path.file_name().and_then(|x| x.to_str()) {
             if !config.package_exists(filename) {
                 info!("removing files at {:?}", path);
-                fs::remove_dir_all(path)?;
+                fs::remove_dir_all(&path)?;
+                pool.remove_dir(&path);
             }
         }
     }
@@ -29,14 +163,12 @@ pub fn package_cleanup(config: &Config) -> io::Result<()> {
         for source in sources {
             if source.retain != 0 {
                 if let Some("changelog") = source.build_on.as_ref().map(|x| x.as_str()) {
-                    let cpath = PathBuf::from(["debian/", &source.name, "/changelog"].concat());
-                    if cpath.exists() {
-                        let keep = changelog(&cpath, source.retain)?;
-                        for (file, version) in locate_files(&source.name, &config.archive) {
+                    if let Some(keep) = retained_versions(source, &config.archive)? {
+                        for (path, version) in locate_files(&source.name, pool) {
                             if !keep.iter().any(|x| version.as_str() == x.as_str()) {
-                                let path = file.path();
                                 info!("removing file at {:?}", path);
-                                fs::remove_file(path)?;
+                                fs::remove_file(&path)?;
+                                pool.remove(&path);
                             }
                         }
                     }
@@ -48,14 +180,36 @@ pub fn package_cleanup(config: &Config) -> io::Result<()> {
     Ok(())
 }
 
-pub fn remove(packages: &[&str], archive: &str) -> io::Result<()> {
-    let path = PathBuf::from(["repo/pool/", archive, "/main"].concat());
-    for entry in WalkDir::new(path).min_depth(3).max_depth(3).into_iter().filter_map(|x| x.ok()) {
-        let path = entry.path();
+/// The versions of the source to keep in the pool, from the changelog of each of its variants,
+/// or `None` if none of them has a changelog.
+fn retained_versions(source: &Source, archive: &str) -> io::Result<Option<Vec<String>>> {
+    let mut keep = Vec::new();
+    let mut found = false;
+    for unit in source.units(archive) {
+        let mut cpath = PathBuf::from(["debian/", &unit.name, "/changelog"].concat());
+        if !cpath.exists() {
+            cpath = PathBuf::from(["debian/", unit.base_name(), "/changelog"].concat());
+        }
+
+        if cpath.exists() {
+            found = true;
+            let suffix = unit.version_suffix.as_ref().map_or("", |x| x.as_str());
+            for version in changelog(&cpath, source.retain)? {
+                keep.push([&version, suffix].concat());
+            }
+        }
+    }
+
+    Ok(if found { Some(keep) } else { None })
+}
+
+pub fn remove(packages: &[&str], pool: &mut PoolIndex) -> io::Result<()> {
+    for path in pool.package_dirs() {
         if let Some(filename) = path.file_name().and_then(|x| x.to_str()) {
             if packages.contains(&filename) {
                 info!("removing files at {:?}", path);
-                fs::remove_dir_all(path)?;
+                fs::remove_dir_all(&path)?;
+                pool.remove_dir(&path);
             }
         }
     }
@@ -63,30 +217,18 @@ pub fn remove(packages: &[&str], archive: &str) -> io::Result<()> {
     Ok(())
 }
 
-fn locate_files(name: &str, archive: &str) -> Vec<(DirEntry, String)> {
-    let path = PathBuf::from(["repo/pool/", archive, "/"].concat());
-
-    fn matches(entry: &DirEntry, name: &str) -> bool {
-        if entry.path().is_dir() {
-            true
-        } else {
-            entry.file_name().to_str().map_or(false, |e| {
-                e.split('_').next().map_or(false, |n| {
-                    n == name || (n.ends_with("-dbgsym") && &n[..n.len() - 7] == name)
-                })
-            })
-        }
-    }
-
-    WalkDir::new(path)
+/// The files of the source in the pool, including its debug symbols, with their versions.
+fn locate_files(name: &str, pool: &PoolIndex) -> Vec<(PathBuf, String)> {
+    let dbgsym = [name, "-dbgsym"].concat();
+    pool.files()
         .into_iter()
-        .filter_entry(|e| matches(e, name))
-        .flat_map(|e| e.ok().and_then(|e| if e.path().is_file() { Some(e) } else { None }))
-        .flat_map(|e| e.file_name()
-            .to_str()
-            .and_then(|e| e.split('_').nth(1).map(|v| get_version(v).to_owned()))
-            .map(|v| (e, v))
-        ).collect()
+        .filter(|file| file.name == name || file.name == dbgsym)
+        .filter_map(|file| {
+            let filename = file.path.file_name()?.to_str()?;
+            let version = get_version(filename.split('_').nth(1)?);
+            Some((file.path.clone(), version.to_owned()))
+        })
+        .collect()
 }
 
 fn get_version(e: &str) -> &str {
@@ -98,3 +240,66 @@ fn get_version(e: &str) -> &str {
 
     e
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Suites;
+    use tempdir::TempDir;
+    use toml;
+
+    #[test]
+    fn init_lays_out_a_new_repo() {
+        let root = TempDir::new("debrep-init").unwrap();
+        let created = init(root.path()).unwrap();
+        assert_eq!(created.len(), SKELETON.len() + 1);
+        assert!(root.path().join("assets/packages").is_dir());
+
+        let example = misc::read_to_string(root.path().join(SOURCES)).unwrap();
+        let config: Config = toml::from_str(&example).unwrap();
+        assert_eq!(config.archive, "bionic");
+
+        // The config of an existing repo is kept.
+        misc::write(root.path().join(SOURCES), b"archive = \"cosmic\"\n").unwrap();
+        fs::remove_dir(root.path().join("logs")).unwrap();
+        assert_eq!(init(root.path()).unwrap(), vec![PathBuf::from("logs")]);
+        assert_eq!(
+            misc::read_to_string(root.path().join(SOURCES)).unwrap(),
+            "archive = \"cosmic\"\n"
+        );
+
+        let created = create_missing_directories(root.path(), Some("cosmic")).unwrap();
+        assert_eq!(
+            created,
+            vec![PathBuf::from("repo/pool/cosmic/main"), PathBuf::from("repo/dists/cosmic")]
+        );
+    }
+
+    #[test]
+    fn unknown_suites_are_refused() {
+        let root = TempDir::new("debrep-suites").unwrap();
+        let mut config: Config = toml::from_str(
+            "archive = \"binoic\"\nversion = \"18.04\"\norigin = \"o\"\nlabel = \"l\"\n\
+             email = \"e\"\n",
+        ).unwrap();
+
+        // A new repo has no suites yet.
+        assert_eq!(unknown_suite(root.path(), &config), None);
+
+        create_missing_directories(root.path(), Some("bionic")).unwrap();
+        create_missing_directories(root.path(), Some("cosmic")).unwrap();
+        let known = unknown_suite(root.path(), &config).unwrap();
+        assert_eq!(known, vec!["bionic".to_owned(), "cosmic".to_owned()]);
+        assert_eq!(closest(&config.archive, &known), Some("bionic"));
+        assert_eq!(closest("disco", &known), None);
+
+        config.archive = "cosmic".into();
+        assert_eq!(unknown_suite(root.path(), &config), None);
+
+        // Declared suites are the only suites, whether or not they have pools.
+        config.suites = Some(Suites::Names(vec!["bionic".into(), "disco".into()]));
+        assert!(unknown_suite(root.path(), &config).is_some());
+        config.archive = "disco".into();
+        assert_eq!(unknown_suite(root.path(), &config), None);
+    }
+}
@@ -0,0 +1,443 @@
+//! Where the files of the pool came from. The origin of each file which was downloaded for a
+//! direct or mirror entry is kept in `record/.pool`, so that it can be verified against the
+//! config when the archive is published. What auditors ask of every file, built or downloaded, is
+//! recorded in the state database when it is placed, and listed by `debrep inspect`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use config::{Config, Source, SourceLocation};
+use misc;
+use serde::Serialize;
+use serde_json;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+use super::checksum::{sha2_256_digest, HashCache};
+use super::download::fetcher;
+use super::keys;
+use super::run;
+use super::state::{FileProvenance, OriginKind};
+
+/// The version of debrep which records the provenance of files.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Records which direct or mirror entry of the config each file in the pool was downloaded for.
+pub const PROVENANCE: &str = "record/.pool";
+
+/// The direct entry, and the URL within it, that a pool file was downloaded from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub entry: String,
+    /// The URL as it is written in the config, before its variables are replaced.
+    pub url: String,
+}
+
+/// The origins of pool files, keyed on their path relative to `repo/`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Provenance {
+    files: BTreeMap<String, Origin>,
+}
+
+impl Provenance {
+    /// Reads the provenance of pool files. Lines which cannot be parsed are ignored.
+    pub fn open(path: &Path) -> Provenance {
+        let mut files = BTreeMap::new();
+        if let Ok(data) = misc::read_to_string(path) {
+            for line in data.lines() {
+                let mut fields = line.splitn(3, '\t');
+                if let (Some(file), Some(entry), Some(url)) =
+                    (fields.next(), fields.next(), fields.next())
+                {
+                    files.insert(
+                        file.to_owned(),
+                        Origin { entry: entry.to_owned(), url: url.to_owned() }
+                    );
+                }
+            }
+        }
+
+        Provenance { files }
+    }
+
+    pub fn insert(&mut self, file: String, origin: Origin) {
+        self.files.insert(file, origin);
+    }
+
+    /// The origin of a file, by its path relative to `repo/`.
+    pub fn origin(&self, file: &str) -> Option<&Origin> {
+        self.files.get(file)
+    }
+
+    pub fn remove(&mut self, file: &str) {
+        self.files.remove(file);
+    }
+
+    /// The files which were downloaded for the entry.
+    pub fn files_of(&self, entry: &str) -> Vec<String> {
+        self.files.iter()
+            .filter(|&(_, origin)| origin.entry == entry)
+            .map(|(file, _)| file.clone())
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut data = String::new();
+        for (file, origin) in &self.files {
+            let _ = writeln!(data, "{}\t{}\t{}", file, origin.entry, origin.url);
+        }
+
+        misc::write(path, data)
+    }
+}
+
+/// A pool file whose digest no longer matches the checksum of its direct entry.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub file: String,
+    pub entry: String,
+    pub expected: String,
+    pub found: String,
+}
+
+/// Hashes each pool file which was downloaded for a direct entry, and compares it to the
+/// checksum which the config currently declares for it. Files which no longer exist, or whose
+/// entries no longer declare a checksum, are skipped.
+pub fn verify(
+    config: &Config,
+    provenance: &Provenance,
+    repo: &Path,
+    cache: &mut HashCache,
+) -> io::Result<Vec<Mismatch>> {
+    let mut mismatches = Vec::new();
+    for (file, origin) in &provenance.files {
+        let expected = config.direct.as_ref()
+            .and_then(|direct| direct.iter().find(|entry| entry.name == origin.entry))
+            .and_then(|entry| entry.urls.iter().find(|path| path.url == origin.url))
+            .and_then(|path| path.checksum.as_ref());
+
+        let expected = match expected {
+            Some(expected) => expected,
+            None => continue,
+        };
+
+        let found = match cache.sha256(&repo.join(file)) {
+            Ok(digest) => digest,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => continue,
+            Err(why) => return Err(why),
+        };
+
+        if &found != expected {
+            mismatches.push(Mismatch {
+                file: file.clone(),
+                entry: origin.entry.clone(),
+                expected: expected.clone(),
+                found,
+            });
+        }
+    }
+
+    Ok(mismatches)
+}
+
+/// The provenance of a file which is placed in the pool of the suite now, by the host and the
+/// version of debrep of this process, in the current run, with its digest if it can be read.
+pub fn placed(path: &Path, suite: &str, kind: OriginKind) -> Option<FileProvenance> {
+    pool_path(path).map(|relative| record(relative, path, suite, kind))
+}
+
+fn record(relative: String, path: &Path, suite: &str, kind: OriginKind) -> FileProvenance {
+    FileProvenance {
+        path: relative,
+        suite: suite.to_owned(),
+        kind,
+        source: None,
+        config_hash: None,
+        origin: None,
+        revision: None,
+        digest: File::open(path).and_then(sha2_256_digest).ok(),
+        run: run::current().map(|run| run.id.clone()),
+        log: None,
+        host: misc::hostname().ok(),
+        debrep: Some(VERSION.to_owned()),
+        started: None,
+        recorded: now(),
+    }
+}
+
+/// The provenance of the files which a build of the source, which started at `started`, placed.
+pub fn built(
+    config: &Config,
+    item: &Source,
+    placed: &[PathBuf],
+    started: u64,
+    log: &Path,
+) -> Vec<FileProvenance> {
+    let (origin, pinned) = match item.location {
+        Some(SourceLocation::URL { ref url, .. }) => (Some(url.clone()), None),
+        Some(SourceLocation::Git { ref url, ref branch, ref tag, ref commit }) => {
+            (Some(url.clone()), commit.as_ref().or(tag.as_ref()).or(branch.as_ref()).cloned())
+        }
+        Some(SourceLocation::Archive { ref path, .. })
+        | Some(SourceLocation::Path { ref path }) => (Some(path.display().to_string()), None),
+        Some(SourceLocation::Command { ref command, .. }) => (Some(command.clone()), None),
+        None => (None, None),
+    };
+
+    let revision = fetcher::checked_out(item).or(pinned);
+    let config_hash = config_hash(item);
+    placed.iter()
+        .filter_map(|path| self::placed(path, &config.archive, OriginKind::Built))
+        .map(|record| FileProvenance {
+            source: Some(item.name.clone()),
+            config_hash: config_hash.clone(),
+            origin: origin.clone(),
+            revision: revision.clone(),
+            log: Some(log.display().to_string()),
+            started: Some(started),
+            ..record
+        })
+        .collect()
+}
+
+/// The provenance of files which were copied from others, such as by a migration, which is that
+/// of the files which they were copied from, in the suite which they were copied to.
+pub fn copied(
+    recorded: &[FileProvenance],
+    copies: &[(PathBuf, PathBuf)],
+    suite: &str,
+) -> Vec<FileProvenance> {
+    copies.iter()
+        .filter_map(|&(ref from, ref to)| {
+            let from = pool_path(from)?;
+            let original = recorded.iter().find(|record| record.path == from)?;
+            let copy = placed(to, suite, original.kind)?;
+            Some(FileProvenance { path: copy.path, suite: copy.suite, ..original.clone() })
+        })
+        .collect()
+}
+
+/// The files of the pool of the suite whose provenance was never recorded.
+pub fn unrecorded(repo: &Path, suite: &str, recorded: &[FileProvenance]) -> Vec<String> {
+    let recorded: HashSet<&str> = recorded.iter().map(|record| record.path.as_str()).collect();
+    let mut files: Vec<String> = WalkDir::new(repo.join("pool").join(suite))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            entry.path().strip_prefix(repo).ok().map(|x| x.to_string_lossy().into_owned())
+        })
+        .filter(|file| !recorded.contains(file.as_str()))
+        .collect();
+
+    files.sort();
+    files
+}
+
+/// The provenance of a file which was in the pool before provenance was recorded, whose origin
+/// is unknown.
+pub fn legacy(repo: &Path, file: &str, suite: &str) -> FileProvenance {
+    FileProvenance {
+        origin: Some("unknown".to_owned()),
+        run: None,
+        host: None,
+        debrep: None,
+        ..record(file.to_owned(), &repo.join(file), suite, OriginKind::Legacy)
+    }
+}
+
+/// The SHA-256 digest of an entry of the config, as it is configured.
+pub fn config_hash<T: Serialize>(entry: &T) -> Option<String> {
+    let data = serde_json::to_vec(entry).ok()?;
+    let mut hasher = Sha256::default();
+    hasher.input(&data);
+    Some(format!("{:x}", hasher.result()))
+}
+
+/// Lists the provenance of each file, with the fields which are known of it.
+pub fn render(records: &[FileProvenance]) -> String {
+    let mut output = String::new();
+    for record in records {
+        let date = |time: u64| format!("{} ({})", keys::date(time), time);
+        let fields = vec![
+            ("suite", Some(record.suite.clone())),
+            ("kind", Some(record.kind.as_str().to_owned())),
+            ("source", record.source.clone()),
+            ("config hash", record.config_hash.clone()),
+            ("origin", record.origin.clone()),
+            ("revision", record.revision.clone()),
+            ("sha256", record.digest.clone()),
+            ("run", record.run.clone()),
+            ("log", record.log.clone()),
+            ("host", record.host.clone()),
+            ("debrep", record.debrep.clone()),
+            ("started", record.started.map(&date)),
+            ("recorded", Some(date(record.recorded))),
+        ];
+
+        let _ = writeln!(output, "{}", record.path);
+        for (name, value) in fields {
+            if let Some(value) = value {
+                let _ = writeln!(output, "  {}: {}", name, value);
+            }
+        }
+    }
+
+    output
+}
+
+/// The path of a pool file relative to `repo/`. Files which are staged for a frozen suite are
+/// given the path which they will have in its pool.
+pub fn pool_path(path: &Path) -> Option<String> {
+    let relative = if path.is_absolute() {
+        let repo = Path::new("repo").canonicalize().ok()?;
+        path.strip_prefix(&repo).ok()?.to_path_buf()
+    } else if let Ok(staged) = path.strip_prefix("pool-staging") {
+        Path::new("pool").join(staged)
+    } else {
+        path.strip_prefix("repo").ok()?.to_path_buf()
+    };
+
+    Some(relative.to_string_lossy().into_owned())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Removes the stanzas of the given files from the output of `apt-ftparchive packages`.
+pub fn exclude(index: &str, files: &[String]) -> String {
+    let mut output = String::new();
+    for stanza in index.split("\n\n").filter(|stanza| !stanza.trim().is_empty()) {
+        let excluded = stanza.lines()
+            .filter(|line| line.starts_with("Filename: "))
+            .any(|line| files.iter().any(|file| file == &line[10..]));
+
+        if !excluded {
+            output.push_str(stanza.trim_right_matches('\n'));
+            output.push_str("\n\n");
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repo::checksum::sha2_256_digest;
+    use std::fs::{self, File};
+    use tempdir::TempDir;
+    use toml;
+
+    #[test]
+    fn stanzas_are_excluded() {
+        let index = "Package: foo\nFilename: pool/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb\n\
+                     \n\
+                     Package: bar\nFilename: pool/bionic/main/binary-amd64/b/bar/bar_1_amd64.deb\n\
+                     \n";
+
+        assert_eq!(
+            exclude(index, &["pool/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb".to_owned()]),
+            "Package: bar\nFilename: pool/bionic/main/binary-amd64/b/bar/bar_1_amd64.deb\n\n"
+        );
+        assert_eq!(exclude(index, &[]), index);
+    }
+
+    #[test]
+    fn provenance_round_trip() {
+        let dir = TempDir::new("debrep-provenance").unwrap();
+        let path = dir.path().join(".pool");
+
+        let mut provenance = Provenance::default();
+        provenance.insert(
+            "pool/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb".to_owned(),
+            Origin { entry: "foo".to_owned(), url: "https://example.com/${name}.deb".to_owned() }
+        );
+        provenance.save(&path).unwrap();
+
+        assert_eq!(Provenance::open(&path), provenance);
+        assert_eq!(Provenance::open(&dir.path().join("missing")), Provenance::default());
+    }
+
+    #[test]
+    fn direct_files_are_verified() {
+        let repo = TempDir::new("debrep-provenance").unwrap();
+        let file = "pool/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb";
+        fs::create_dir_all(repo.path().join(file).parent().unwrap()).unwrap();
+        misc::write(repo.path().join(file), b"deb").unwrap();
+
+        let config: Config = toml::from_str(&format!(
+            "archive = 'bionic'\nversion = '18.04'\norigin = 'o'\nlabel = 'l'\nemail = 'e'\n\
+             [[direct]]\nname = 'foo'\nversion = '1'\n\
+             [[direct.urls]]\nurl = 'https://example.com/foo.deb'\nchecksum = '{}'\n",
+            "0".repeat(64)
+        )).unwrap();
+
+        let mut provenance = Provenance::default();
+        let origin = Origin {
+            entry: "foo".to_owned(),
+            url: "https://example.com/foo.deb".to_owned(),
+        };
+        provenance.insert(file.to_owned(), origin.clone());
+        provenance.insert("pool/missing.deb".to_owned(), origin);
+
+        let mut cache = HashCache::open(repo.path().join("hashes"), false);
+        let mismatches = verify(&config, &provenance, repo.path(), &mut cache).unwrap();
+        assert_eq!(mismatches, vec![Mismatch {
+            file: file.to_owned(),
+            entry: "foo".to_owned(),
+            expected: "0".repeat(64),
+            found: sha2_256_digest(File::open(repo.path().join(file)).unwrap()).unwrap(),
+        }]);
+    }
+
+    #[test]
+    fn unrecorded_files_are_backfilled_and_copies_keep_their_origins() {
+        let staged = Path::new("pool-staging/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb");
+        let file = "pool/bionic/main/binary-amd64/f/foo/foo_1_amd64.deb";
+        assert_eq!(pool_path(staged), Some(file.to_owned()));
+        assert_eq!(pool_path(&Path::new("repo").join(file)), Some(file.to_owned()));
+        assert_eq!(pool_path(Path::new("build/foo_1_amd64.deb")), None);
+
+        let repo = TempDir::new("debrep-provenance").unwrap();
+        let other = "pool/bionic/main/binary-amd64/b/bar/bar_1_amd64.deb";
+        for path in &[file, other] {
+            fs::create_dir_all(repo.path().join(path).parent().unwrap()).unwrap();
+            misc::write(repo.path().join(path), b"deb").unwrap();
+        }
+
+        let built = FileProvenance {
+            source: Some("foo".to_owned()),
+            origin: Some("https://github.com/pop-os/foo".to_owned()),
+            revision: Some("abc123".to_owned()),
+            started: Some(100),
+            ..placed(&Path::new("repo").join(file), "bionic", OriginKind::Built).unwrap()
+        };
+        assert_eq!(built.run, None);
+        assert_eq!(built.debrep.as_ref().map(|x| x.as_str()), Some(VERSION));
+
+        let recorded = vec![built.clone()];
+        assert_eq!(unrecorded(repo.path(), "bionic", &recorded), vec![other.to_owned()]);
+        let legacy = legacy(repo.path(), other, "bionic");
+        assert_eq!((legacy.kind, legacy.path.as_str()), (OriginKind::Legacy, other));
+        let digest = File::open(repo.path().join(other)).and_then(sha2_256_digest).ok();
+        assert_eq!(legacy.digest, digest);
+
+        let copy = Path::new("repo/pool/cosmic/main/binary-amd64/f/foo/foo_1_amd64.deb");
+        let copies = [(Path::new("repo").join(file), copy.to_path_buf())];
+        let copies = copied(&recorded, &copies, "cosmic");
+        assert_eq!(copies.len(), 1);
+        assert_eq!(copies[0].path, "pool/cosmic/main/binary-amd64/f/foo/foo_1_amd64.deb");
+        assert_eq!((copies[0].suite.as_str(), &copies[0].revision), ("cosmic", &built.revision));
+
+        let rendered = render(&copies);
+        assert!(rendered.starts_with("pool/cosmic/main/binary-amd64/f/foo/foo_1_amd64.deb\n"));
+        assert!(rendered.contains("\n  kind: built\n"));
+        assert!(rendered.contains("\n  revision: abc123\n"));
+        assert!(!rendered.contains("  log: "));
+    }
+}
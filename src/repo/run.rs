@@ -0,0 +1,226 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use logging;
+use misc;
+use super::junit::TestCase;
+use super::logs::LOGS;
+use super::state::State;
+
+/// The directory within `logs/` which holds a directory for each run.
+pub const RUNS: &str = "runs";
+
+/// The log of debrep's own messages during the run.
+const RUN_LOG: &str = "debrep.log";
+
+/// The summary of each package's outcome.
+const REPORT: &str = "report";
+
+lazy_static! {
+    static ref CURRENT: Mutex<Option<Arc<Run>>> = Mutex::new(None);
+}
+
+/// A single invocation of debrep which builds packages. Its logs are kept in `logs/runs/<id>/`,
+/// and its ID is attached to log records and the records of packages which it built.
+#[derive(Debug)]
+pub struct Run {
+    pub id: String,
+    pub dir: PathBuf,
+    /// Files which were added to, or replaced in, the pool during the run.
+    published: Mutex<Vec<PathBuf>>,
+    /// The outcome of each package which the run built, for `--report-junit`.
+    cases: Mutex<Vec<TestCase>>,
+}
+
+/// Starts a new run, which becomes the current run of this process.
+pub fn start(root: &Path) -> io::Result<Arc<Run>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let id = format!("{}-{}", now, suffix());
+    // The directory must remain valid after the working directory is changed to the repo.
+    let dir = root.canonicalize()?.join(LOGS).join(RUNS).join(&id);
+    fs::create_dir_all(&dir)?;
+
+    let log = File::create(dir.join(RUN_LOG))?;
+    logging::set_run(&id, log);
+
+    if let Err(why) = State::open(root).and_then(|mut state| state.start_run(&id, now)) {
+        warn!("failed to record the start of run {}: {}", id, why);
+    }
+
+    let run = Arc::new(Run::new(id, dir));
+    *CURRENT.lock().unwrap() = Some(run.clone());
+    Ok(run)
+}
+
+/// The run which this process is performing, if it is building packages.
+pub fn current() -> Option<Arc<Run>> {
+    CURRENT.lock().unwrap().clone()
+}
+
+/// Ends the current run, so that the next work of this process starts a run of its own. Returns
+/// the ID of the run which ended.
+pub fn end() -> Option<String> {
+    let ended = CURRENT.lock().unwrap().take();
+    logging::end_run();
+    ended.map(|run| run.id.clone())
+}
+
+/// The reports of the most recent runs which wrote one, newest first, as their IDs and reports.
+pub fn recent(root: &Path, count: usize) -> io::Result<Vec<(String, String)>> {
+    let dir = root.join(LOGS).join(RUNS);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if entry.path().join(REPORT).is_file() {
+            ids.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    // IDs begin with the second which the run started in.
+    ids.sort_by_key(|id| {
+        let started = id.split('-').next().and_then(|x| x.parse::<u64>().ok()).unwrap_or(0);
+        (started, id.clone())
+    });
+
+    ids.into_iter()
+        .rev()
+        .take(count)
+        .map(|id| misc::read_to_string(&dir.join(&id).join(REPORT)).map(|report| (id, report)))
+        .collect()
+}
+
+/// A short random suffix which tells apart runs that were started within the same second.
+fn suffix() -> String {
+    let mut bytes = [0u8; 3];
+    match File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut bytes)) {
+        Ok(()) => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        Err(_) => format!("{:06x}", SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() & 0xFF_FFFF)
+            .unwrap_or(0)),
+    }
+}
+
+impl Run {
+    pub fn new(id: String, dir: PathBuf) -> Run {
+        Run { id, dir, published: Mutex::new(Vec::new()), cases: Mutex::new(Vec::new()) }
+    }
+
+    /// Records that a file was moved into the pool.
+    pub fn add_published(&self, path: PathBuf) {
+        self.published.lock().unwrap().push(path);
+    }
+
+    pub fn published(&self) -> Vec<PathBuf> {
+        self.published.lock().unwrap().clone()
+    }
+
+    /// Records the outcome of a package which the run built, or skipped.
+    pub fn add_case(&self, case: TestCase) {
+        self.cases.lock().unwrap().push(case);
+    }
+
+    pub fn cases(&self) -> Vec<TestCase> {
+        self.cases.lock().unwrap().clone()
+    }
+
+    /// Links to the log of a package which was built in this run.
+    pub fn link_log(&self, package: &str, log: &Path) -> io::Result<()> {
+        let filename = match log.file_name().and_then(|x| x.to_str()) {
+            Some(filename) => filename,
+            None => return Ok(()),
+        };
+
+        let extension = &filename[filename.find('.').unwrap_or(filename.len())..];
+        let link = self.dir.join([package, extension].concat());
+        if link.symlink_metadata().is_ok() {
+            fs::remove_file(&link)?;
+        }
+
+        // The link is relative, so that the logs directory can be moved.
+        symlink(Path::new("../..").join(package).join(filename), link)
+    }
+
+    /// Adds the report of a phase of this run, such as the outcome of each package which it
+    /// built, after the reports of the phases before it.
+    /// Where the summary of each package's outcome is written.
+    pub fn report_path(&self) -> PathBuf {
+        self.dir.join(REPORT)
+    }
+
+    pub fn write_report(&self, report: &str) -> io::Result<()> {
+        let path = self.dir.join(REPORT);
+        if !path.exists() {
+            return misc::write(&path, [&format!("run {}\n", self.id), report, "\n"].concat());
+        }
+
+        self.append_report(&["\n", report].concat())
+    }
+
+    /// Adds a line to the end of the report, after it was written.
+    pub fn append_report(&self, line: &str) -> io::Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(REPORT))
+            .and_then(|mut file| writeln!(file, "{}", line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn package_logs_are_linked() {
+        let root = TempDir::new("debrep-runs").unwrap();
+        let run = Run::new("1-abcdef".into(), root.path().join("logs/runs/1-abcdef"));
+        fs::create_dir_all(&run.dir).unwrap();
+        fs::create_dir_all(root.path().join("logs/package")).unwrap();
+        misc::write(root.path().join("logs/package/10.log.gz"), b"log").unwrap();
+
+        run.link_log("package", &root.path().join("logs/package/10.log.gz")).unwrap();
+        assert_eq!(misc::read(run.dir.join("package.log.gz")).unwrap(), b"log");
+        assert_eq!(suffix().len(), 6);
+    }
+
+    #[test]
+    fn recent_runs_are_newest_first() {
+        let root = TempDir::new("debrep-runs").unwrap();
+        for id in &["9-aaaaaa", "10-bbbbbb", "11-cccccc"] {
+            let run = Run::new((*id).into(), root.path().join("logs/runs").join(id));
+            fs::create_dir_all(&run.dir).unwrap();
+            run.write_report("summary:").unwrap();
+        }
+        fs::create_dir_all(root.path().join("logs/runs/12-dddddd")).unwrap();
+
+        let recent = recent(root.path(), 2).unwrap();
+        let ids: Vec<&str> = recent.iter().map(|&(ref id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["11-cccccc", "10-bbbbbb"]);
+        assert_eq!(recent[0].1, "run 11-cccccc\nsummary:\n");
+    }
+
+    #[test]
+    fn reports_of_phases_are_kept() {
+        let root = TempDir::new("debrep-runs").unwrap();
+        let run = Run::new("1-abcdef".into(), root.path().to_path_buf());
+        run.write_report("1 packages failed to download:").unwrap();
+        run.write_report("summary:").unwrap();
+        run.append_report("checksums checksums-1-abcdef.txt").unwrap();
+
+        assert_eq!(
+            misc::read_to_string(root.path().join(REPORT)).unwrap(),
+            "run 1-abcdef\n1 packages failed to download:\n\nsummary:\n\
+             checksums checksums-1-abcdef.txt\n"
+        );
+    }
+}
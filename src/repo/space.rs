@@ -0,0 +1,172 @@
+//! Estimates of the disk space which downloads and builds need, which are checked against what is
+//! free on the filesystems that they write to before they start, and before each package is
+//! built. A run which would fill a filesystem stops with an error of its own, instead of failing
+//! partway through a download, a build, or a move into the pool.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use config::{Source, SourceLocation};
+use libc;
+use output::format_size;
+use super::download::fetcher;
+use walkdir::WalkDir;
+
+/// A filesystem which lacks the space that is needed of it.
+#[derive(Debug, PartialEq)]
+pub struct Shortfall {
+    /// The first path on the filesystem which space is needed for.
+    pub path: PathBuf,
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for Shortfall {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} needs {} free on its filesystem, but {} is",
+            self.path.display(),
+            format_size(self.needed),
+            format_size(self.available)
+        )
+    }
+}
+
+/// Lists the shortfalls for an error, one on each line.
+pub fn describe(shortfalls: &[Shortfall]) -> String {
+    let shortfalls: Vec<String> = shortfalls.iter().map(|x| x.to_string()).collect();
+    shortfalls.join("\n  ")
+}
+
+/// Checks the space which is needed to do `what`, such as to build a package. Shortfalls are
+/// returned as an error, or only logged when they are `ignored`.
+pub fn require(
+    needed: &[(PathBuf, u64)],
+    headroom: u64,
+    ignored: bool,
+    what: &str,
+) -> Result<(), String> {
+    let shortfalls = match check(needed, headroom) {
+        Ok(ref shortfalls) if shortfalls.is_empty() => return Ok(()),
+        Ok(shortfalls) => describe(&shortfalls),
+        Err(why) => {
+            warn!("unable to check the disk space needed to {}: {}", what, why);
+            return Ok(());
+        }
+    };
+
+    if ignored {
+        warn!("there may not be enough disk space to {}:\n  {}", what, shortfalls);
+        Ok(())
+    } else {
+        Err(shortfalls)
+    }
+}
+
+/// The filesystems which do not have the bytes which are needed for their paths free, with the
+/// headroom to spare. What is needed for paths on the same filesystem is added up.
+pub fn check(needed: &[(PathBuf, u64)], headroom: u64) -> io::Result<Vec<Shortfall>> {
+    let mut filesystems: BTreeMap<u64, (PathBuf, u64)> = BTreeMap::new();
+    for &(ref path, bytes) in needed {
+        let device = existing(path).metadata()?.dev();
+        let entry = filesystems.entry(device).or_insert_with(|| (path.clone(), 0));
+        entry.1 = entry.1.saturating_add(bytes);
+    }
+
+    let mut shortfalls = Vec::new();
+    for (_, (path, bytes)) in filesystems {
+        let needed = bytes.saturating_add(headroom);
+        let available = available(existing(&path))?;
+        if available < needed {
+            shortfalls.push(Shortfall { path, needed, available });
+        }
+    }
+
+    Ok(shortfalls)
+}
+
+/// The bytes which are free for unprivileged users on the filesystem of the path.
+pub fn available(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, why))?;
+    let mut stats: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// The bytes of the files within the path, without following links. Paths which do not exist
+/// have none.
+pub fn size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// The bytes which the workspace of the source is expected to grow by when it is built: the size
+/// of its source tree times the multiplier, less what its `tree` in the workspace already holds.
+pub fn build(item: &Source, pwd: &Path, tree: &Path, multiplier: f64) -> u64 {
+    let source = match item.location {
+        Some(SourceLocation::Path { ref path }) => size(&pwd.join(path)),
+        _ => fetcher::of(item).map_or(0, |fetcher| size(&pwd.join(fetcher.resolve()))),
+    };
+
+    ((source as f64 * multiplier) as u64).saturating_sub(size(tree))
+}
+
+/// The path, or its closest ancestor which exists.
+fn existing(path: &Path) -> &Path {
+    let mut path = path;
+    while !path.exists() {
+        match path.parent() {
+            Some(parent) if parent != Path::new("") => path = parent,
+            _ => return Path::new("."),
+        }
+    }
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use misc;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn the_needs_of_paths_on_a_filesystem_are_added_up() {
+        let dir = TempDir::new("space").unwrap();
+        fs::create_dir(dir.path().join("build")).unwrap();
+        misc::write(dir.path().join("build/tree"), vec![0u8; 3000]).unwrap();
+        assert_eq!(size(&dir.path().join("build")), 3000);
+        assert_eq!(size(&dir.path().join("missing")), 0);
+
+        let free = available(dir.path()).unwrap();
+        let cache = dir.path().join("cache/not/created/yet.tar.xz");
+        let build = dir.path().join("build");
+        assert_eq!(check(&[(cache.clone(), 1), (build.clone(), 1)], 0).unwrap(), Vec::new());
+
+        let half = free / 2 + (1 << 29);
+        let shortfalls = check(&[(cache.clone(), half), (build, half)], 0).unwrap();
+        assert_eq!(shortfalls.len(), 1);
+        assert_eq!(shortfalls[0].path, cache);
+        assert_eq!(shortfalls[0].needed, half * 2);
+
+        let shortfalls = check(&[(cache, 0)], free + (1 << 30)).unwrap();
+        assert!(describe(&shortfalls).contains("free on its filesystem"));
+    }
+}
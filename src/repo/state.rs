@@ -0,0 +1,1049 @@
+//! The state of builds, in a single JSON file at `state/debrep.json`: what each package was last
+//! built from and with, every build with the files which it placed in the pool and what was
+//! measured of it and the build dependencies which it was built with, where each file of the pool
+//! came from, the uploads of built packages to other archives, and every run and the warnings
+//! which it logged.
+//!
+//! Each write appends the one change which it makes to `state/journal.jsonl`, as a line which is
+//! synced before the write returns, instead of rewriting the state. A crash in the middle of a
+//! write leaves a line without its end, which is ignored, and dropped by the next write. Writers
+//! lock `state/.lock` while they append, so that builds which finish at the same time never lose
+//! each other's writes, and readers share the lock while they read. Once the journal holds
+//! `COMPACT_AFTER` changes, the writer which appended the last of them writes the state into the
+//! file, which is moved over the old one, and replaces the journal with an empty one. Changes are
+//! numbered, and the file names the last which it holds, so that a crash between the two never
+//! applies a change twice.
+//!
+//! When the file is created, the record files which earlier versions of debrep kept in `record/`
+//! are imported into it. What the file of an earlier version lacks is read as empty.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use libc;
+use serde_json;
+
+use logging::Warning;
+use misc;
+use super::build::record::write_atomic;
+use super::keys;
+use super::lock;
+use super::logs::LOGS;
+use super::run::RUNS;
+
+/// The state file, relative to the root of the repo.
+pub const DATABASE: &str = "state/debrep.json";
+
+/// The changes which were made since the state file was last written, one JSON object a line.
+const JOURNAL: &str = "state/journal.jsonl";
+
+/// The file which writers of the state lock, and which readers share.
+const LOCK: &str = "state/.lock";
+
+/// How many changes the journal holds before they are written into the state file.
+const COMPACT_AFTER: u64 = 1000;
+
+/// The record files of earlier versions of debrep, which are imported into the state.
+const RECORDS: &str = "record";
+const SUITE_RECORDS: &str = "record/.suites";
+const ENVIRONMENTS: &str = "record/.environment";
+const DURATIONS: &str = "record/.durations";
+
+/// The version of the format of the state file, which newer versions of debrep increase when
+/// older versions could no longer read what they write.
+const FORMAT_VERSION: u32 = 1;
+
+/// How a build ended.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildResult {
+    Built,
+    Failed,
+    Interrupted,
+}
+
+impl BuildResult {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BuildResult::Built => "built",
+            BuildResult::Failed => "failed",
+            BuildResult::Interrupted => "interrupted",
+        }
+    }
+}
+
+/// A build of a package for a suite, which succeeded or failed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Build {
+    pub suite: String,
+    pub package: String,
+    /// The architecture of the system which built it.
+    pub architecture: String,
+    pub run: Option<String>,
+    /// When the build ended, in seconds since the Unix epoch.
+    pub finished: u64,
+    pub seconds: u64,
+    pub result: BuildResult,
+    /// The version which was built, if it is known.
+    pub version: Option<String>,
+    pub log: Option<String>,
+}
+
+impl Build {
+    /// The date which the build ended on, as `YYYY-MM-DD`, or `-` for durations which were
+    /// imported without one.
+    pub fn date(&self) -> String {
+        if self.finished == 0 { "-".to_owned() } else { keys::date(self.finished) }
+    }
+}
+
+/// What was measured of a build which succeeded.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Metrics {
+    /// The total size of the binary packages which it placed in the pool, in bytes.
+    pub deb_bytes: u64,
+    /// The total `Installed-Size` of those packages, in KiB, if it could be read.
+    pub installed_size: Option<u64>,
+    /// How many warnings were logged while the package was built.
+    pub warnings: u64,
+}
+
+/// How a file came to be in the pool.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OriginKind {
+    /// Built from a source of the config.
+    Built,
+    Direct,
+    Mirror,
+    Ppa,
+    Repack,
+    /// Already in the pool when its provenance was first recorded, so where it came from is
+    /// unknown.
+    Legacy,
+}
+
+impl OriginKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            OriginKind::Built => "built",
+            OriginKind::Direct => "direct",
+            OriginKind::Mirror => "mirror",
+            OriginKind::Ppa => "ppa",
+            OriginKind::Repack => "repack",
+            OriginKind::Legacy => "legacy",
+        }
+    }
+}
+
+/// Where a file of the pool came from. Fields which do not apply to its kind, or which were not
+/// known, are not set.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileProvenance {
+    /// The path of the file, relative to `repo/`.
+    pub path: String,
+    pub suite: String,
+    pub kind: OriginKind,
+    /// The source or entry of the config which the file was built or downloaded for.
+    pub source: Option<String>,
+    /// The SHA-256 digest of the entry as it was configured.
+    pub config_hash: Option<String>,
+    /// The URL which the file or its source was fetched from, or the path of a local source.
+    pub origin: Option<String>,
+    /// The commit, or else the tag or branch, of a source which was cloned with git.
+    pub revision: Option<String>,
+    /// The SHA-256 digest of the file when it was placed.
+    pub digest: Option<String>,
+    pub run: Option<String>,
+    /// The log of the build, relative to the repo.
+    pub log: Option<String>,
+    /// The host which placed the file.
+    pub host: Option<String>,
+    /// The version of debrep which placed the file.
+    pub debrep: Option<String>,
+    /// When the build of the file started, in seconds since the Unix epoch.
+    pub started: Option<u64>,
+    /// When the file was placed, or its provenance was backfilled.
+    pub recorded: u64,
+}
+
+/// An upload of a `.changes` file to a target, which is attempted by each run until it succeeds.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Upload {
+    pub target: String,
+    /// The `.changes` file in the spool, relative to the repo.
+    pub changes: String,
+    pub suite: String,
+    pub package: String,
+    /// How many times it failed.
+    pub attempts: u32,
+    /// Why it last failed.
+    pub error: Option<String>,
+}
+
+/// What a build which succeeded leaves behind, which is recorded along with it.
+pub struct Built<'a> {
+    /// The scope of the record of what the package was built from, and the record, if the
+    /// source has a `build_on` rule.
+    pub record: Option<(&'a str, String)>,
+    /// The environment which the package was built with.
+    pub environment: &'a str,
+    /// The files which the build placed in the pool.
+    pub artifacts: &'a [PathBuf],
+    pub metrics: &'a Metrics,
+    /// Where each of those files came from.
+    pub provenance: &'a [FileProvenance],
+    /// The targets which each `.changes` file of the build in the spool is uploaded to.
+    pub uploads: &'a [(String, String)],
+    /// The version of each build dependency which sbuild installed for the build.
+    pub dependencies: &'a [(String, String)],
+}
+
+/// Everything which the state file holds.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Tables {
+    version: u32,
+    /// The number of the last change of the journal which the tables hold.
+    applied: u64,
+    /// When each run started and finished, by its ID.
+    runs: BTreeMap<String, RunTimes>,
+    /// Every build, oldest first. The ID of a build is its position in the list, from 1.
+    builds: Vec<StoredBuild>,
+    /// The record of what each package was last built from, by its scope and name.
+    records: BTreeMap<String, BTreeMap<String, String>>,
+    /// The environment which each package was last built with.
+    environments: BTreeMap<String, String>,
+    /// Where each file of the pool came from, by its path.
+    provenance: BTreeMap<String, FileProvenance>,
+    uploads: Vec<StoredUpload>,
+    /// The warnings which each run logged, by its ID.
+    warnings: BTreeMap<String, Vec<StoredWarning>>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct RunTimes {
+    started: u64,
+    finished: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredBuild {
+    build: Build,
+    /// The files which a build that succeeded placed in the pool.
+    #[serde(default)]
+    artifacts: Vec<PathBuf>,
+    metrics: Option<Metrics>,
+    #[serde(default)]
+    dependencies: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredUpload {
+    upload: Upload,
+    /// When the build which queued it finished.
+    queued: u64,
+    /// When it succeeded.
+    uploaded: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct StoredWarning {
+    category: String,
+    package: Option<String>,
+    message: String,
+}
+
+/// A change of the state, as it is appended to the journal.
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    /// The number of the change, from 1, which is one more than that of the change before it.
+    change: u64,
+    #[serde(flatten)]
+    kind: Change,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Change {
+    Record { scope: String, package: String, record: String },
+    Build { stored: StoredBuild, left: Option<Left> },
+    Upload { target: String, changes: String, result: Result<u64, String> },
+    Provenance { records: Vec<FileProvenance> },
+    StartRun { id: String, started: u64 },
+    FinishRun { id: String, finished: u64, warnings: Vec<StoredWarning> },
+}
+
+/// What a build which succeeded left behind, besides its artifacts, metrics, and dependencies.
+#[derive(Debug, Deserialize, Serialize)]
+struct Left {
+    record: Option<(String, String)>,
+    environment: String,
+    provenance: Vec<FileProvenance>,
+    uploads: Vec<(String, String)>,
+}
+
+/// The state of the repo.
+pub struct State {
+    root: PathBuf,
+    tables: Tables,
+    /// The inode of the journal which was read, which a compaction replaces.
+    journal: Option<u64>,
+    /// How much of the journal was read, in bytes, which ends after the last complete line.
+    read: u64,
+    /// How many changes the journal holds.
+    journaled: u64,
+}
+
+impl State {
+    /// Reads the state of the repo at `root`, creating it if it does not exist, and importing
+    /// the record files of earlier versions of debrep into it when it is created.
+    pub fn open(root: &Path) -> io::Result<State> {
+        fs::create_dir_all(root.join(DATABASE).parent().unwrap_or(root))?;
+        let mut state = State {
+            root: root.to_path_buf(),
+            tables: Tables::default(),
+            journal: None,
+            read: 0,
+            journaled: 0,
+        };
+
+        {
+            let _lock = lock_state(root, libc::LOCK_SH)?;
+            state.catch_up()?;
+        }
+
+        if state.tables.version == 0 {
+            // Another debrep which creates the state at the same time waits for the lock, and
+            // then finds what this one imported.
+            let _lock = lock_state(root, libc::LOCK_EX)?;
+            state.catch_up()?;
+            if state.tables.version == 0 {
+                let imported = import(&mut state.tables, root)?;
+                state.tables.version = FORMAT_VERSION;
+                let data = serde_json::to_vec(&state.tables).map_err(error)?;
+                write_atomic(&root.join(DATABASE), &data)?;
+                if imported != 0 {
+                    info!("imported {} record files into {}", imported, DATABASE);
+                }
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Reads what was appended to the journal since it was last read, and the state file again
+    /// if the journal was compacted into it.
+    fn catch_up(&mut self) -> io::Result<()> {
+        let path = self.root.join(JOURNAL);
+        let journal = match fs::metadata(&path) {
+            Ok(metadata) => Some(metadata.ino()),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => None,
+            Err(why) => return Err(why),
+        };
+
+        if self.tables.version == 0 || journal != self.journal {
+            self.tables = read_tables(&self.root)?;
+            self.journal = journal;
+            self.read = 0;
+            self.journaled = 0;
+        }
+
+        if journal.is_none() {
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(self.read))?;
+        file.read_to_end(&mut data)?;
+
+        // A line without its end was being appended when a writer crashed.
+        let complete = data.iter().rposition(|&byte| byte == b'\n').map_or(0, |end| end + 1);
+        for line in data[..complete].split(|&byte| byte == b'\n').filter(|x| !x.is_empty()) {
+            let entry: Entry = serde_json::from_slice(line).map_err(|why| {
+                let why = format!("{} is invalid: {}", JOURNAL, why);
+                io::Error::new(io::ErrorKind::InvalidData, why)
+            })?;
+
+            // Changes which the state file already holds remain if a compaction was interrupted.
+            if entry.change > self.tables.applied {
+                self.tables.applied = entry.change;
+                apply(&mut self.tables, entry.kind);
+            }
+
+            self.journaled += 1;
+        }
+
+        self.read += complete as u64;
+        Ok(())
+    }
+
+    /// Appends the change to the journal while holding the lock of its writers, and applies it
+    /// to the state as it is on disk.
+    fn update(&mut self, kind: Change) -> io::Result<()> {
+        let _lock = lock_state(&self.root, libc::LOCK_EX)?;
+        self.catch_up()?;
+
+        let entry = Entry { change: self.tables.applied + 1, kind };
+        let mut line = serde_json::to_vec(&entry).map_err(error)?;
+        line.push(b'\n');
+
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join(JOURNAL))?;
+        if journal.metadata()?.len() != self.read {
+            journal.set_len(self.read)?;
+        }
+
+        journal.write_all(&line)?;
+        journal.sync_data()?;
+        if self.journal.is_none() {
+            self.journal = Some(journal.metadata()?.ino());
+        }
+
+        self.read += line.len() as u64;
+        self.journaled += 1;
+        self.tables.applied = entry.change;
+        apply(&mut self.tables, entry.kind);
+
+        if self.journaled >= COMPACT_AFTER {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the state with the changes of the journal into the state file, and replaces the
+    /// journal with an empty one. The lock of the writers must be held.
+    fn compact(&mut self) -> io::Result<()> {
+        let data = serde_json::to_vec(&self.tables).map_err(error)?;
+        write_atomic(&self.root.join(DATABASE), &data)?;
+        write_atomic(&self.root.join(JOURNAL), b"")?;
+        self.journal = Some(fs::metadata(self.root.join(JOURNAL))?.ino());
+        self.read = 0;
+        self.journaled = 0;
+        Ok(())
+    }
+
+    /// The record of what the package was last built from, as it was saved.
+    pub fn record(&self, scope: &str, package: &str) -> io::Result<Option<String>> {
+        Ok(self.tables.records.get(scope).and_then(|records| records.get(package)).cloned())
+    }
+
+    /// Replaces the record of what the package was last built from, such as with a record of an
+    /// earlier format which was rewritten in the current one.
+    pub fn set_record(&mut self, scope: &str, package: &str, record: &str) -> io::Result<()> {
+        self.update(Change::Record {
+            scope: scope.to_owned(),
+            package: package.to_owned(),
+            record: record.to_owned(),
+        })
+    }
+
+    /// Records a build. A build which succeeded is recorded along with what it left behind, so
+    /// that the package is only ever recorded as built with the files which it placed.
+    pub fn add_build(&mut self, build: &Build, built: Option<Built>) -> io::Result<()> {
+        let mut stored = StoredBuild {
+            build: build.clone(),
+            artifacts: Vec::new(),
+            metrics: None,
+            dependencies: BTreeMap::new(),
+        };
+
+        let left = built.map(|built| {
+            stored.artifacts = built.artifacts.to_vec();
+            stored.metrics = Some(built.metrics.clone());
+            stored.dependencies = built.dependencies.iter().cloned().collect();
+            Left {
+                record: built.record.map(|(scope, record)| (scope.to_owned(), record)),
+                environment: built.environment.to_owned(),
+                provenance: built.provenance.to_vec(),
+                uploads: built.uploads.to_vec(),
+            }
+        });
+
+        self.update(Change::Build { stored, left })
+    }
+
+    /// The latest build of each package of the suite, by the names of the packages.
+    pub fn latest(&self, suite: &str) -> io::Result<Vec<Build>> {
+        // Later builds replace earlier builds of the same package.
+        let latest: BTreeMap<&str, &Build> = self.tables.builds.iter()
+            .filter(|stored| stored.build.suite == suite)
+            .map(|stored| (stored.build.package.as_str(), &stored.build))
+            .collect();
+
+        Ok(latest.into_iter().map(|(_, build)| build.clone()).collect())
+    }
+
+    /// Every build of the package, in every suite, newest first.
+    pub fn history(&self, package: &str) -> io::Result<Vec<Build>> {
+        Ok(self.tables.builds.iter()
+            .rev()
+            .filter(|stored| stored.build.package == package)
+            .map(|stored| stored.build.clone())
+            .collect())
+    }
+
+    /// The builds of the suite, or of one package of it, oldest first, with what was measured of
+    /// those which succeeded. Builds from before metrics were recorded have none.
+    pub fn measured(
+        &self,
+        suite: &str,
+        package: Option<&str>,
+    ) -> io::Result<Vec<(Build, Option<Metrics>)>> {
+        Ok(self.tables.builds.iter()
+            .filter(|stored| {
+                stored.build.suite == suite
+                    && package.map_or(true, |package| stored.build.package == package)
+            })
+            .map(|stored| (stored.build.clone(), stored.metrics.clone()))
+            .collect())
+    }
+
+    /// The files which each build of the suite that succeeded placed in the pool, oldest build
+    /// first, as the package, the ID of the build, and the path. A build which placed no files
+    /// is listed once without a path.
+    pub fn placed(&self, suite: &str) -> io::Result<Vec<(String, i64, Option<PathBuf>)>> {
+        let mut placed = Vec::new();
+        for (id, stored) in (1..).zip(&self.tables.builds) {
+            let build = &stored.build;
+            if build.suite != suite || build.result != BuildResult::Built {
+                continue;
+            }
+
+            if stored.artifacts.is_empty() {
+                placed.push((build.package.clone(), id, None));
+            }
+
+            for artifact in &stored.artifacts {
+                placed.push((build.package.clone(), id, Some(artifact.clone())));
+            }
+        }
+
+        Ok(placed)
+    }
+
+    /// The version of each build dependency of the latest build of the package in the suite which
+    /// recorded them, if any did.
+    pub fn build_dependencies(
+        &self,
+        suite: &str,
+        package: &str,
+    ) -> io::Result<Option<BTreeMap<String, String>>> {
+        Ok(self.tables.builds.iter()
+            .rev()
+            .find(|stored| {
+                stored.build.suite == suite
+                    && stored.build.package == package
+                    && !stored.dependencies.is_empty()
+            })
+            .map(|stored| stored.dependencies.clone()))
+    }
+
+    /// The packages whose latest build in the suite failed, or was interrupted.
+    pub fn failed(&self, suite: &str) -> io::Result<Vec<String>> {
+        Ok(self.latest(suite)?.into_iter()
+            .filter(|build| build.result != BuildResult::Built)
+            .map(|build| build.package)
+            .collect())
+    }
+
+    /// How long each package took to build the last time that it was built, in seconds.
+    pub fn durations(&self) -> io::Result<HashMap<String, u64>> {
+        // Later builds replace the durations of earlier ones.
+        Ok(self.tables.builds.iter()
+            .filter(|stored| stored.build.result == BuildResult::Built)
+            .map(|stored| (stored.build.package.clone(), stored.build.seconds))
+            .collect())
+    }
+
+    /// The uploads of the suite which have not succeeded yet, oldest first.
+    pub fn pending_uploads(&self, suite: &str) -> io::Result<Vec<Upload>> {
+        let mut pending: Vec<&StoredUpload> = self.tables.uploads.iter()
+            .filter(|stored| stored.upload.suite == suite && stored.uploaded.is_none())
+            .collect();
+        pending.sort_by(|a, b| {
+            (a.queued, &a.upload.changes, &a.upload.target)
+                .cmp(&(b.queued, &b.upload.changes, &b.upload.target))
+        });
+
+        Ok(pending.into_iter().map(|stored| stored.upload.clone()).collect())
+    }
+
+    /// Records that the upload succeeded at the time, or why it failed.
+    pub fn finish_upload(
+        &mut self,
+        upload: &Upload,
+        result: Result<u64, &str>,
+    ) -> io::Result<()> {
+        self.update(Change::Upload {
+            target: upload.target.clone(),
+            changes: upload.changes.clone(),
+            result: result.map_err(String::from),
+        })
+    }
+
+    /// Whether the `.changes` file in the spool is yet to be uploaded to any of its targets.
+    pub fn is_pending(&self, changes: &str) -> io::Result<bool> {
+        Ok(self.tables.uploads.iter()
+            .any(|stored| stored.upload.changes == changes && stored.uploaded.is_none()))
+    }
+
+    /// Records where files of the pool came from, replacing what was recorded of the same paths.
+    pub fn add_provenance(&mut self, records: &[FileProvenance]) -> io::Result<()> {
+        self.update(Change::Provenance { records: records.to_vec() })
+    }
+
+    /// Where the files of the suite came from, by their paths, or the files of every suite.
+    pub fn provenance(&self, suite: Option<&str>) -> io::Result<Vec<FileProvenance>> {
+        Ok(self.tables.provenance.values()
+            .filter(|record| suite.map_or(true, |suite| record.suite == suite))
+            .cloned()
+            .collect())
+    }
+
+    /// Records that a run started.
+    pub fn start_run(&mut self, id: &str, started: u64) -> io::Result<()> {
+        self.update(Change::StartRun { id: id.to_owned(), started })
+    }
+
+    /// Records when a run finished, and the warnings which it logged, which replace those that
+    /// were recorded when an earlier phase of it finished.
+    pub fn finish_run(&mut self, id: &str, finished: u64, warnings: &[Warning]) -> io::Result<()> {
+        let warnings = warnings.iter()
+            .map(|warning| StoredWarning {
+                category: warning.category.clone(),
+                package: warning.package.clone(),
+                message: warning.message.clone(),
+            })
+            .collect();
+
+        self.update(Change::FinishRun { id: id.to_owned(), finished, warnings })
+    }
+}
+
+/// Applies a change of the journal to the tables.
+fn apply(tables: &mut Tables, change: Change) {
+    match change {
+        Change::Record { scope, package, record } => {
+            tables.records.entry(scope).or_insert_with(BTreeMap::new).insert(package, record);
+        }
+        Change::Build { stored, left } => {
+            if let Some(left) = left {
+                let build = &stored.build;
+                insert_provenance(tables, &left.provenance);
+
+                // A version which is built again is uploaded again, as if it was never attempted.
+                for (target, changes) in left.uploads {
+                    tables.uploads.retain(|x| {
+                        x.upload.target != target || x.upload.changes != changes
+                    });
+                    tables.uploads.push(StoredUpload {
+                        upload: Upload {
+                            target,
+                            changes,
+                            suite: build.suite.clone(),
+                            package: build.package.clone(),
+                            attempts: 0,
+                            error: None,
+                        },
+                        queued: build.finished,
+                        uploaded: None,
+                    });
+                }
+
+                if let Some((scope, record)) = left.record {
+                    tables.records.entry(scope)
+                        .or_insert_with(BTreeMap::new)
+                        .insert(build.package.clone(), record);
+                }
+
+                tables.environments.insert(build.package.clone(), left.environment);
+            }
+
+            tables.builds.push(stored);
+        }
+        Change::Upload { target, changes, result } => {
+            let stored = tables.uploads.iter_mut().find(|stored| {
+                stored.upload.target == target && stored.upload.changes == changes
+            });
+
+            if let Some(stored) = stored {
+                match result {
+                    Ok(time) => {
+                        stored.uploaded = Some(time);
+                        stored.upload.error = None;
+                    }
+                    Err(why) => {
+                        stored.upload.attempts += 1;
+                        stored.upload.error = Some(why);
+                    }
+                }
+            }
+        }
+        Change::Provenance { records } => insert_provenance(tables, &records),
+        Change::StartRun { id, started } => {
+            tables.runs.entry(id).or_insert(RunTimes { started, finished: None });
+        }
+        Change::FinishRun { id, finished, warnings } => {
+            if let Some(run) = tables.runs.get_mut(&id) {
+                run.finished = Some(finished);
+            }
+
+            tables.warnings.insert(id, warnings);
+        }
+    }
+}
+
+fn insert_provenance(tables: &mut Tables, records: &[FileProvenance]) {
+    for record in records {
+        tables.provenance.insert(record.path.clone(), record.clone());
+    }
+}
+
+/// Reads the state file, which is empty if it does not exist yet.
+fn read_tables(root: &Path) -> io::Result<Tables> {
+    let path = root.join(DATABASE);
+    if !path.exists() {
+        return Ok(Tables::default());
+    }
+
+    let tables: Tables = serde_json::from_slice(&misc::read(&path)?).map_err(error)?;
+    if tables.version > FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} was written by a newer version of debrep", DATABASE),
+        ));
+    }
+
+    Ok(tables)
+}
+
+/// Locks the state, exclusively for a writer or shared by readers, until the file which is
+/// returned is closed.
+fn lock_state(root: &Path, operation: libc::c_int) -> io::Result<File> {
+    let file = File::create(root.join(LOCK))?;
+    lock::flock(file.as_raw_fd(), operation)?;
+    Ok(file)
+}
+
+fn error(why: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} is invalid: {}", DATABASE, why))
+}
+
+/// Imports the record files of earlier versions of debrep, and the runs in `logs/runs/`,
+/// returning how many files were imported. The files are left where they are.
+fn import(tables: &mut Tables, root: &Path) -> io::Result<usize> {
+    let mut imported = 0;
+    let mut records = vec![(String::new(), root.join(RECORDS))];
+    for suite in files(&root.join(SUITE_RECORDS))? {
+        records.push((suite.clone(), root.join(SUITE_RECORDS).join(suite)));
+    }
+
+    for (scope, dir) in records {
+        for package in files(&dir)? {
+            if let Some(record) = read(&dir.join(&package))? {
+                tables.records.entry(scope.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(package, record);
+                imported += 1;
+            }
+        }
+    }
+
+    let environments = root.join(ENVIRONMENTS);
+    for package in files(&environments)? {
+        if let Some(environment) = read(&environments.join(&package))? {
+            tables.environments.insert(package, environment);
+            imported += 1;
+        }
+    }
+
+    // Durations were kept without their suite or run, or when the build finished.
+    if let Some(durations) = read(&root.join(DURATIONS))? {
+        for line in durations.lines() {
+            let mut fields = line.splitn(2, ' ');
+            if let (Some(Ok(seconds)), Some(package)) =
+                (fields.next().map(|x| x.parse::<u64>()), fields.next())
+            {
+                tables.builds.push(StoredBuild {
+                    build: Build {
+                        suite: String::new(),
+                        package: package.to_owned(),
+                        architecture: String::new(),
+                        run: None,
+                        finished: 0,
+                        seconds,
+                        result: BuildResult::Built,
+                        version: None,
+                        log: None,
+                    },
+                    artifacts: Vec::new(),
+                    metrics: None,
+                    dependencies: BTreeMap::new(),
+                });
+            }
+        }
+
+        imported += 1;
+    }
+
+    for id in files(&root.join(LOGS).join(RUNS))? {
+        // IDs begin with the second which the run started in.
+        let started = id.split('-').next().and_then(|x| x.parse::<u64>().ok()).unwrap_or(0);
+        tables.runs.entry(id).or_insert(RunTimes { started, finished: None });
+    }
+
+    Ok(imported)
+}
+
+/// The names of the entries of the directory which are not hidden, or none if it does not exist.
+fn files(dir: &Path) -> io::Result<Vec<String>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/// The contents of a file to import, or `None` if it is not a file, or is not UTF-8.
+fn read(path: &Path) -> io::Result<Option<String>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    match misc::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref why) if why.kind() == io::ErrorKind::InvalidData => {
+            warn!("{} was not imported into {}: it is not UTF-8", path.display(), DATABASE);
+            Ok(None)
+        }
+        Err(why) => Err(why),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn build(package: &str, result: BuildResult, seconds: u64) -> Build {
+        Build {
+            suite: "bionic".into(),
+            package: package.into(),
+            architecture: "amd64".into(),
+            run: Some("1539685200-0a1b2c".into()),
+            finished: 1_539_685_200,
+            seconds,
+            result,
+            version: None,
+            log: None,
+        }
+    }
+
+    #[test]
+    fn builds_are_recorded_with_what_they_left_behind() {
+        let dir = TempDir::new("debrep-state").unwrap();
+        let mut state = State::open(dir.path()).unwrap();
+        assert_eq!(state.record("", "pop-shop").unwrap(), None);
+
+        let artifacts = vec![PathBuf::from("repo/pool/bionic/main/source/p/pop-shop/a.dsc")];
+        let metrics = Metrics { deb_bytes: 2048, installed_size: Some(8), warnings: 1 };
+        let provenance = vec![FileProvenance {
+            path: "pool/bionic/main/source/p/pop-shop/a.dsc".into(),
+            suite: "bionic".into(),
+            kind: OriginKind::Built,
+            source: Some("pop-shop".into()),
+            config_hash: None,
+            origin: Some("https://github.com/pop-os/shop".into()),
+            revision: Some("0a1b2c".into()),
+            digest: None,
+            run: Some("1539685200-0a1b2c".into()),
+            log: None,
+            host: Some("builder".into()),
+            debrep: Some("0.1.0".into()),
+            started: Some(1_539_685_080),
+            recorded: 1_539_685_200,
+        }];
+        let changes = "uploads/bionic/pop-shop_1.0_amd64/pop-shop_1.0_amd64.changes".to_owned();
+        let uploads = vec![
+            ("internal-dak".to_owned(), changes.clone()),
+            ("ppa:system76/pop".to_owned(), changes.clone()),
+        ];
+        let dependencies = vec![("cargo".to_owned(), "0.27.0-0ubuntu1".to_owned())];
+        let built = Built {
+            record: Some(("", "debrep-record 1\n".into())),
+            environment: "TZ=UTC\n",
+            artifacts: &artifacts,
+            metrics: &metrics,
+            provenance: &provenance,
+            uploads: &uploads,
+            dependencies: &dependencies,
+        };
+        state.add_build(&build("pop-shop", BuildResult::Built, 120), Some(built)).unwrap();
+        state.add_build(&build("pop-shop", BuildResult::Failed, 30), None).unwrap();
+        state.add_build(&build("linux", BuildResult::Built, 3600), None).unwrap();
+
+        assert_eq!(state.record("", "pop-shop").unwrap(), Some("debrep-record 1\n".into()));
+        assert_eq!(state.failed("bionic").unwrap(), vec!["pop-shop".to_owned()]);
+        assert_eq!(state.failed("cosmic").unwrap(), Vec::<String>::new());
+        assert_eq!(state.durations().unwrap()["pop-shop"], 120);
+        assert_eq!(state.history("pop-shop").unwrap()[0].result, BuildResult::Failed);
+
+        // The build dependencies are those of the latest build which recorded them.
+        let last = state.build_dependencies("bionic", "pop-shop").unwrap();
+        assert_eq!(last, Some(dependencies.into_iter().collect()));
+        assert_eq!(state.build_dependencies("bionic", "linux").unwrap(), None);
+
+        let latest = state.latest("bionic").unwrap();
+        let packages: Vec<&str> = latest.iter().map(|x| x.package.as_str()).collect();
+        assert_eq!(packages, vec!["linux", "pop-shop"]);
+
+        let measured = state.measured("bionic", Some("pop-shop")).unwrap();
+        let recorded: Vec<Option<Metrics>> = measured.into_iter().map(|(_, x)| x).collect();
+        assert_eq!(recorded, vec![Some(metrics), None]);
+        assert_eq!(state.measured("bionic", None).unwrap().len(), 3);
+
+        // Provenance which is recorded again replaces what was recorded of the path.
+        assert_eq!(state.provenance(Some("bionic")).unwrap(), provenance);
+        let legacy = FileProvenance { kind: OriginKind::Legacy, ..provenance[0].clone() };
+        state.add_provenance(&[legacy.clone()]).unwrap();
+        assert_eq!(state.provenance(None).unwrap(), vec![legacy]);
+        assert_eq!(state.provenance(Some("cosmic")).unwrap(), Vec::new());
+
+        // Uploads stay pending until they succeed, counting their failures.
+        let pending = state.pending_uploads("bionic").unwrap();
+        assert_eq!(pending.len(), 2);
+        state.finish_upload(&pending[0], Err("connection refused")).unwrap();
+        state.finish_upload(&pending[0], Err("connection timed out")).unwrap();
+        state.finish_upload(&pending[1], Ok(1_539_685_300)).unwrap();
+        let pending = state.pending_uploads("bionic").unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].target.as_str(), pending[0].attempts), ("internal-dak", 2));
+        assert_eq!(pending[0].error, Some("connection timed out".to_owned()));
+        assert!(state.is_pending(&changes).unwrap());
+        state.finish_upload(&pending[0], Ok(1_539_685_400)).unwrap();
+        assert!(!state.is_pending(&changes).unwrap());
+        assert_eq!(state.pending_uploads("bionic").unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn state_of_earlier_versions_is_read() {
+        let dir = TempDir::new("debrep-state").unwrap();
+        fs::create_dir_all(dir.path().join("state")).unwrap();
+        misc::write(dir.path().join(DATABASE), r#"{"version":1,"runs":{}}"#).unwrap();
+
+        let mut state = State::open(dir.path()).unwrap();
+        state.add_build(&build("pop-shop", BuildResult::Built, 120), None).unwrap();
+        assert_eq!(state.measured("bionic", None).unwrap()[0].1, None);
+
+        // State which a newer version of debrep wrote is never overwritten.
+        misc::write(dir.path().join(DATABASE), r#"{"version":2}"#).unwrap();
+        assert!(State::open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn concurrent_writes_are_kept() {
+        let dir = TempDir::new("debrep-state").unwrap();
+        let root = dir.path().to_path_buf();
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let root = root.clone();
+                ::std::thread::spawn(move || {
+                    for _ in 0..5 {
+                        let package = format!("pop-{}", writer);
+                        let build = build(&package, BuildResult::Built, 60);
+                        State::open(&root).unwrap().add_build(&build, None).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let state = State::open(&root).unwrap();
+        assert_eq!(state.measured("bionic", None).unwrap().len(), 20);
+        assert_eq!(state.latest("bionic").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn writes_are_appended_and_compacted() {
+        let dir = TempDir::new("debrep-state").unwrap();
+        let root = dir.path();
+        let mut state = State::open(root).unwrap();
+        let created = misc::read(root.join(DATABASE)).unwrap();
+        state.add_build(&build("pop-shop", BuildResult::Built, 120), None).unwrap();
+        state.start_run("1539685200-0a1b2c", 1_539_685_200).unwrap();
+        assert_eq!(misc::read(root.join(DATABASE)).unwrap(), created);
+        assert_eq!(misc::read_to_string(root.join(JOURNAL)).unwrap().lines().count(), 2);
+
+        // A change which a crash cut off is ignored, and dropped by the next write.
+        let mut journal = OpenOptions::new().append(true).open(root.join(JOURNAL)).unwrap();
+        journal.write_all(br#"{"change":3,"kind":"build","#).unwrap();
+        let mut stale = State::open(root).unwrap();
+        assert_eq!(stale.measured("bionic", None).unwrap().len(), 1);
+        stale.add_build(&build("linux", BuildResult::Failed, 60), None).unwrap();
+        assert_eq!(State::open(root).unwrap().measured("bionic", None).unwrap().len(), 2);
+
+        // A reader of the journal which was compacted reads the state file again.
+        state.update(Change::StartRun { id: "1539685300-0a1b2c".into(), started: 0 }).unwrap();
+        state.compact().unwrap();
+        assert_eq!(misc::read(root.join(JOURNAL)).unwrap(), Vec::<u8>::new());
+        stale.add_build(&build("linux", BuildResult::Built, 60), None).unwrap();
+        let state = State::open(root).unwrap();
+        assert_eq!(state.tables.runs.len(), 2);
+        assert_eq!(state.failed("bionic").unwrap(), Vec::<String>::new());
+        assert_eq!(state.measured("bionic", None).unwrap().len(), 3);
+
+        // Changes which the state file holds are not applied again from a journal which a crash
+        // kept from being replaced.
+        let mut state = State::open(root).unwrap();
+        state.add_build(&build("pop-shop", BuildResult::Built, 90), None).unwrap();
+        let journal = misc::read(root.join(JOURNAL)).unwrap();
+        state.compact().unwrap();
+        misc::write(root.join(JOURNAL), &journal).unwrap();
+        assert_eq!(State::open(root).unwrap().measured("bionic", None).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn record_files_are_imported_once() {
+        let dir = TempDir::new("debrep-state").unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join(SUITE_RECORDS).join("cosmic")).unwrap();
+        fs::create_dir_all(root.join(ENVIRONMENTS)).unwrap();
+        misc::write(root.join(RECORDS).join("pop-shop"), "changelog\n1.0\n").unwrap();
+        misc::write(root.join(SUITE_RECORDS).join("cosmic/linux"), "changelog\n4.18\n").unwrap();
+        misc::write(root.join(ENVIRONMENTS).join("pop-shop"), "TZ=UTC\n").unwrap();
+        misc::write(root.join(DURATIONS), "120 pop-shop\ninvalid\n").unwrap();
+
+        let state = State::open(root).unwrap();
+        assert_eq!(state.record("", "pop-shop").unwrap(), Some("changelog\n1.0\n".into()));
+        assert_eq!(state.record("cosmic", "linux").unwrap(), Some("changelog\n4.18\n".into()));
+        assert_eq!(state.record("", "linux").unwrap(), None);
+        assert_eq!(state.durations().unwrap()["pop-shop"], 120);
+        drop(state);
+
+        // Files which change after the database was created are not imported again.
+        misc::write(root.join(RECORDS).join("pop-shop"), "changelog\n2.0\n").unwrap();
+        let state = State::open(root).unwrap();
+        assert_eq!(state.record("", "pop-shop").unwrap(), Some("changelog\n1.0\n".into()));
+    }
+}
@@ -0,0 +1,310 @@
+//! What is measured of each build which succeeded, which is recorded in the state database along
+//! with the build, and the reports of how it changed over the latest builds of packages, for
+//! `debrep stats`.
+//!
+//! A build is measured from the binary packages which it placed in the pool, and the warnings
+//! which were logged while it was built, which are both at hand when it is recorded. The builds
+//! of a report's window which failed, or were recorded before builds were measured, are gaps in
+//! its series, which growth is measured across.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io;
+
+use logging;
+use super::deb;
+use super::state::{Build, Metrics, State};
+
+/// Measures a build from the binary packages which it placed in the pool, and the warnings which
+/// were logged for its package.
+pub fn measure(placed: &[PathBuf], package: &str) -> Metrics {
+    let mut metrics = Metrics::default();
+    let mut installed_size = Some(0);
+    for path in placed.iter().filter(|path| is_binary(path)) {
+        metrics.deb_bytes += fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+        let size = match deb::control(path) {
+            Ok(control) => self::installed_size(&control),
+            Err(why) => {
+                debug!("failed to read the control file of {}: {}", path.display(), why);
+                None
+            }
+        };
+
+        installed_size = installed_size.and_then(|total| size.map(|size| total + size));
+    }
+
+    metrics.installed_size = installed_size;
+    metrics.warnings = logging::warnings().iter()
+        .filter(|warning| warning.package.as_ref().map_or(false, |x| x == package))
+        .count() as u64;
+    metrics
+}
+
+fn is_binary(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "deb" || ext == "udeb" || ext == "ddeb")
+}
+
+/// The `Installed-Size` of a control file, in KiB.
+fn installed_size(control: &str) -> Option<u64> {
+    control.lines()
+        .find(|line| line.starts_with("Installed-Size:"))
+        .and_then(|line| line["Installed-Size:".len()..].trim().parse().ok())
+}
+
+/// What a report measures the growth of packages by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Measure {
+    Duration,
+    DebSize,
+    InstalledSize,
+}
+
+/// A build of a package, with what was measured of it if it succeeded.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Sample {
+    pub suite: String,
+    pub package: String,
+    /// The date which the build ended on, as `YYYY-MM-DD`.
+    pub date: String,
+    pub run: Option<String>,
+    pub result: &'static str,
+    pub version: Option<String>,
+    pub seconds: u64,
+    pub deb_bytes: Option<u64>,
+    /// In KiB.
+    pub installed_size: Option<u64>,
+    pub warnings: Option<u64>,
+}
+
+impl Sample {
+    /// How many of the latest builds of each package are reported when no window is given.
+    pub const DEFAULT_WINDOW: usize = 10;
+
+    pub const CSV_HEADER: &'static str =
+        "suite,package,date,run,result,version,seconds,deb_bytes,installed_size,warnings";
+
+    fn new(build: Build, metrics: Option<Metrics>) -> Sample {
+        Sample {
+            date: build.date(),
+            suite: build.suite,
+            package: build.package,
+            run: build.run,
+            result: build.result.as_str(),
+            version: build.version,
+            seconds: build.seconds,
+            deb_bytes: metrics.as_ref().map(|x| x.deb_bytes),
+            installed_size: metrics.as_ref().and_then(|x| x.installed_size),
+            warnings: metrics.as_ref().map(|x| x.warnings),
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        let optional = |value: Option<u64>| value.map_or(String::new(), |x| x.to_string());
+        [
+            self.suite.as_str(),
+            &self.package,
+            &self.date,
+            self.run.as_ref().map_or("", |x| x.as_str()),
+            self.result,
+            self.version.as_ref().map_or("", |x| x.as_str()),
+            &self.seconds.to_string(),
+            &optional(self.deb_bytes),
+            &optional(self.installed_size),
+            &optional(self.warnings),
+        ].join(",")
+    }
+}
+
+/// How a measure changed from the first to the last measured build of a window.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Change {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl Change {
+    /// The change relative to the first build, in percent.
+    pub fn percent(&self) -> f64 {
+        (self.last as f64 - self.first as f64) * 100.0 / self.first.max(1) as f64
+    }
+}
+
+/// How a package changed over the builds of a window.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Growth {
+    pub suite: String,
+    pub package: String,
+    /// How many builds of the window were measured.
+    pub builds: usize,
+    pub seconds: Change,
+    pub deb_bytes: Change,
+    /// Unset if the size of the first or last build is unknown.
+    pub installed_size: Option<Change>,
+}
+
+impl Growth {
+    pub const CSV_HEADER: &'static str =
+        "suite,package,builds,seconds_first,seconds_last,deb_bytes_first,deb_bytes_last,\
+         installed_size_first,installed_size_last";
+
+    /// The change of the measure, if it is known.
+    pub fn change(&self, measure: Measure) -> Option<Change> {
+        match measure {
+            Measure::Duration => Some(self.seconds),
+            Measure::DebSize => Some(self.deb_bytes),
+            Measure::InstalledSize => self.installed_size,
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        let installed = |value: fn(&Change) -> u64| {
+            self.installed_size.as_ref().map_or(String::new(), |x| value(x).to_string())
+        };
+
+        [
+            self.suite.as_str(),
+            &self.package,
+            &self.builds.to_string(),
+            &self.seconds.first.to_string(),
+            &self.seconds.last.to_string(),
+            &self.deb_bytes.first.to_string(),
+            &self.deb_bytes.last.to_string(),
+            &installed(|x| x.first),
+            &installed(|x| x.last),
+        ].join(",")
+    }
+}
+
+/// The latest `window` builds of the package in the suite, oldest first.
+pub fn samples(
+    state: &State,
+    suite: &str,
+    package: &str,
+    window: usize,
+) -> io::Result<Vec<Sample>> {
+    let measured = state.measured(suite, Some(package))?;
+    let skip = measured.len().saturating_sub(window);
+    Ok(measured.into_iter()
+        .skip(skip)
+        .map(|(build, metrics)| Sample::new(build, metrics))
+        .collect())
+}
+
+/// How each package of the suite changed over its latest `window` builds, from the package
+/// which grew the most by the measure. Packages with fewer than two measured builds in their
+/// window are left out.
+pub fn growth(state: &State, suite: &str, window: usize, by: Measure) -> io::Result<Vec<Growth>> {
+    let mut packages: BTreeMap<String, Vec<(Build, Option<Metrics>)>> = BTreeMap::new();
+    for (build, metrics) in state.measured(suite, None)? {
+        packages.entry(build.package.clone()).or_insert_with(Vec::new).push((build, metrics));
+    }
+
+    let mut growth = Vec::new();
+    for (package, builds) in packages {
+        let skip = builds.len().saturating_sub(window);
+        let measured: Vec<(&Build, &Metrics)> = builds[skip..].iter()
+            .filter_map(|&(ref build, ref metrics)| metrics.as_ref().map(|x| (build, x)))
+            .collect();
+
+        if measured.len() < 2 {
+            continue;
+        }
+
+        let (first, last) = (measured[0], measured[measured.len() - 1]);
+        let installed_size = match (first.1.installed_size, last.1.installed_size) {
+            (Some(first), Some(last)) => Some(Change { first, last }),
+            _ => None,
+        };
+
+        growth.push(Growth {
+            suite: suite.to_owned(),
+            package,
+            builds: measured.len(),
+            seconds: Change { first: first.0.seconds, last: last.0.seconds },
+            deb_bytes: Change { first: first.1.deb_bytes, last: last.1.deb_bytes },
+            installed_size,
+        });
+    }
+
+    // Packages whose growth is unknown come last, in the order of their names.
+    let key = |growth: &Growth| growth.change(by).map(|change| change.percent());
+    growth.sort_by(|a, b| match (key(a), key(b)) {
+        (Some(x), Some(y)) => y.partial_cmp(&x).unwrap_or(::std::cmp::Ordering::Equal),
+        (x, y) => y.is_some().cmp(&x.is_some()),
+    });
+
+    Ok(growth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::state::{BuildResult, Built};
+    use tempdir::TempDir;
+
+    fn build(state: &mut State, package: &str, seconds: u64, metrics: Option<Metrics>) {
+        let build = Build {
+            suite: "bionic".into(),
+            package: package.into(),
+            architecture: "amd64".into(),
+            run: None,
+            finished: 1_539_685_200,
+            seconds,
+            result: if metrics.is_some() { BuildResult::Built } else { BuildResult::Failed },
+            version: None,
+            log: None,
+        };
+
+        match metrics {
+            Some(ref metrics) => {
+                let built = Built {
+                    record: None,
+                    environment: "",
+                    artifacts: &[],
+                    metrics,
+                    provenance: &[],
+                    uploads: &[],
+                    dependencies: &[],
+                };
+                state.add_build(&build, Some(built)).unwrap();
+            }
+            None => state.add_build(&build, None).unwrap(),
+        }
+    }
+
+    fn metrics(deb_bytes: u64, installed_size: u64) -> Option<Metrics> {
+        Some(Metrics { deb_bytes, installed_size: Some(installed_size), warnings: 0 })
+    }
+
+    #[test]
+    fn growth_is_measured_across_gaps_in_the_window() {
+        let dir = TempDir::new("debrep-stats").unwrap();
+        let mut state = State::open(dir.path()).unwrap();
+        build(&mut state, "linux", 3000, metrics(9000, 90_000));
+        build(&mut state, "linux", 3600, metrics(9000, 99_000));
+        build(&mut state, "linux", 60, None);
+        build(&mut state, "linux", 6000, metrics(9000, 108_000));
+        build(&mut state, "pop-shop", 100, metrics(1000, 4000));
+        build(&mut state, "pop-shop", 150, metrics(1100, 4000));
+        build(&mut state, "gone", 10, metrics(10, 10));
+
+        let growth = growth(&state, "bionic", 3, Measure::Duration).unwrap();
+        let packages: Vec<&str> = growth.iter().map(|x| x.package.as_str()).collect();
+        assert_eq!(packages, vec!["linux", "pop-shop"]);
+        assert_eq!(growth[0].builds, 2);
+        assert_eq!(growth[0].seconds, Change { first: 3600, last: 6000 });
+        assert_eq!(growth[0].installed_size.map(|x| x.percent().round()), Some(9.0));
+
+        let samples = samples(&state, "bionic", "linux", 2).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].to_csv(), "bionic,linux,2018-10-16,,failed,,60,,,");
+        assert_eq!(samples[1].deb_bytes, Some(9000));
+    }
+
+    #[test]
+    fn installed_sizes_are_read_from_control_files() {
+        assert_eq!(installed_size("Package: pop\nInstalled-Size: 42\nVersion: 1\n"), Some(42));
+        assert_eq!(installed_size("Package: pop\n"), None);
+    }
+}
@@ -0,0 +1,335 @@
+//! Uploads of the packages of sources to the archives of their `upload_targets`, as dput would
+//! upload them. When a source was built, each `.changes` file which its build wrote is copied
+//! into the spool, at `uploads/<suite>/<changes>/`, along with the files which it lists, and an
+//! upload of it to each target is queued in the state database. The files are kept in the spool
+//! until they were uploaded to every target, since the pool may not keep all of them, such as
+//! the source packages of sources which do not keep them.
+//!
+//! Uploads are attempted once the suite was published. An upload which fails is recorded with
+//! why, and attempted again by the next run, but never fails the run which published the suite.
+//! The `.changes` file is written last, since archives process an upload once it arrives.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use command::Cmd;
+use config::{Config, UploadMethod, UploadTarget};
+use interrupt;
+use misc;
+use output::{Status, Summary};
+use tempdir::TempDir;
+use super::state::State;
+
+/// Where the files which are uploaded are kept until they were uploaded to every target.
+pub const SPOOL: &str = "uploads";
+
+/// How long connecting to a target may take, in seconds.
+const CONNECT_TIMEOUT: u64 = 60;
+
+/// Copies the `.changes` files in the directory, and the files which they list, into the spool
+/// of the suite, returning the paths of the `.changes` files in the spool, relative to `pwd`.
+pub fn spool(pwd: &Path, suite: &str, dir: &Path) -> io::Result<Vec<String>> {
+    let mut spooled = Vec::new();
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|x| x.to_str()) {
+            Some(name) if name.ends_with(".changes") => name.to_owned(),
+            _ => continue,
+        };
+
+        let relative = Path::new(SPOOL).join(suite).join(&name[..name.len() - 8]);
+        let spool = pwd.join(&relative);
+        if spool.exists() {
+            fs::remove_dir_all(&spool)?;
+        }
+
+        fs::create_dir_all(&spool)?;
+        let changes = misc::read_to_string(&path)?;
+        for file in listed(&changes).into_iter().chain(Some(name.as_str())) {
+            link_or_copy(&dir.join(file), &spool.join(file))?;
+        }
+
+        spooled.push(relative.join(&name).to_string_lossy().into_owned());
+    }
+
+    spooled.sort();
+    Ok(spooled)
+}
+
+/// An upload which is waiting to be attempted, as a dry run lists it.
+#[derive(Debug)]
+pub struct Planned {
+    /// The name of the `.changes` file.
+    pub changes: String,
+    /// How many files the `.changes` file lists.
+    pub files: usize,
+    pub target: String,
+    /// Where the target is, or `None` if it is no longer configured.
+    pub url: Option<String>,
+}
+
+impl fmt::Display for Planned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.url {
+            Some(ref url) => {
+                write!(f, "{} ({} files) to {} at {}", self.changes, self.files, self.target, url)
+            }
+            None => write!(f, "{} to {}, which is no longer an upload target", self.changes,
+                self.target),
+        }
+    }
+}
+
+/// The uploads of the suite which have not succeeded yet, which `run` would attempt.
+pub fn planned(config: &Config, pwd: &Path) -> io::Result<Vec<Planned>> {
+    let state = State::open(pwd)?;
+    let pending = state.pending_uploads(&config.archive)?;
+    if pending.is_empty() {
+        info!("nothing of {} is waiting to be uploaded", config.archive);
+    }
+
+    Ok(pending.iter()
+        .map(|upload| {
+            let changes = pwd.join(&upload.changes);
+            let target = config.upload_target(&upload.target);
+            Planned {
+                changes: changes.file_name()
+                    .map_or(String::new(), |x| x.to_string_lossy().into_owned()),
+                files: files(&changes).map(|files| files.len()).unwrap_or(0),
+                target: upload.target.clone(),
+                url: target.map(|target| url(&target)),
+            }
+        })
+        .collect())
+}
+
+/// Attempts each upload of the suite which has not succeeded yet, and records how it went.
+/// Returns how many uploads failed.
+pub fn run(config: &Config, pwd: &Path) -> io::Result<usize> {
+    let mut state = State::open(pwd)?;
+    let pending = state.pending_uploads(&config.archive)?;
+    if pending.is_empty() {
+        info!("nothing of {} is waiting to be uploaded", config.archive);
+        return Ok(0);
+    }
+
+    let mut summary = Summary::default();
+    let mut failed = 0;
+    for upload in &pending {
+        if interrupt::is_interrupted() {
+            break;
+        }
+
+        let changes = pwd.join(&upload.changes);
+        let name = changes.file_name().map_or(String::new(), |x| x.to_string_lossy().into_owned());
+        let target = match config.upload_target(&upload.target) {
+            Some(target) => target,
+            None => {
+                let why = format!("{} is no longer an upload target", upload.target);
+                summary.add(Status::Failed, &name, why);
+                failed += 1;
+                continue;
+            }
+        };
+
+        if upload.attempts != 0 {
+            info!("uploading {} to {} again, after {} failed attempts", name, target.name,
+                upload.attempts);
+        }
+
+        match send(&target, &changes) {
+            Ok(()) => {
+                state.finish_upload(upload, Ok(now()))?;
+                summary.add(Status::Uploaded, &name, ["to ", &target.name].concat());
+                if !state.is_pending(&upload.changes)? {
+                    if let Some(spool) = changes.parent() {
+                        fs::remove_dir_all(spool)?;
+                    }
+                }
+            }
+            Err(why) => {
+                state.finish_upload(upload, Err(&why))?;
+                let detail = format!("to {}: {}; the next run tries again", target.name, why);
+                summary.add(Status::Failed, &name, detail);
+                failed += 1;
+            }
+        }
+    }
+
+    summary.print();
+    Ok(failed)
+}
+
+/// Uploads the `.changes` file, and the files which it lists, to the target, which the target
+/// acknowledges: FTP servers with the reply to each transfer, HTTPS servers with the status of
+/// each `PUT`, and SFTP servers with the status of each write.
+fn send(target: &UploadTarget, changes: &Path) -> Result<(), String> {
+    let text = misc::read_to_string(changes).map_err(|why| why.to_string())?;
+    if target.signed && !text.starts_with("-----BEGIN PGP SIGNED MESSAGE-----") {
+        return Err(format!("it is not signed, which {} requires", target.name));
+    }
+
+    let files = files(changes).map_err(|why| why.to_string())?;
+    if let Some(missing) = files.iter().find(|file| !file.is_file()) {
+        return Err(format!("{} is missing from the spool", missing.display()));
+    }
+
+    info!("uploading {} files to {}", files.len(), url(target));
+    match target.method {
+        UploadMethod::Ftp | UploadMethod::Https => {
+            for file in &files {
+                Cmd::new("curl")
+                    .args(&["--fail", "--silent", "--show-error", "--netrc-optional"])
+                    .args(&["--connect-timeout", &CONNECT_TIMEOUT.to_string()])
+                    .arg("--upload-file")
+                    .arg(file)
+                    .arg(url(target))
+                    .run()
+                    .map_err(|why| why.to_string())?;
+            }
+        }
+        UploadMethod::Sftp => {
+            let batch = TempDir::new("debrep-upload").map_err(|why| why.to_string())?;
+            let mut commands = format!("cd \"{}\"\n", target.incoming);
+            for file in &files {
+                commands.push_str(&format!("put \"{}\"\n", file.display()));
+            }
+
+            let path = batch.path().join("batch");
+            misc::write(&path, commands).map_err(|why| why.to_string())?;
+            let host = match target.login {
+                Some(ref login) => [login, "@", &target.fqdn].concat(),
+                None => target.fqdn.clone(),
+            };
+
+            Cmd::new("sftp")
+                .args(&["-o", "BatchMode=yes", "-o"])
+                .arg(["ConnectTimeout=", &CONNECT_TIMEOUT.to_string()].concat())
+                .arg("-b")
+                .arg(&path)
+                .arg(host)
+                .run()
+                .map_err(|why| why.to_string())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The files which are uploaded for the `.changes` file in the spool: those which it lists, and
+/// then itself.
+fn files(changes: &Path) -> io::Result<Vec<PathBuf>> {
+    let text = misc::read_to_string(changes)?;
+    let dir = changes.parent().unwrap_or_else(|| Path::new("."));
+    let mut files: Vec<PathBuf> = listed(&text).into_iter().map(|file| dir.join(file)).collect();
+    files.push(changes.to_path_buf());
+    Ok(files)
+}
+
+/// The URL of the incoming directory of the target, where credentials which are not given come
+/// from `~/.netrc`, or the keys of ssh.
+fn url(target: &UploadTarget) -> String {
+    let scheme = match target.method {
+        UploadMethod::Ftp => "ftp://",
+        UploadMethod::Sftp => "sftp://",
+        UploadMethod::Https => "https://",
+    };
+
+    let login = target.login.as_ref().map_or(String::new(), |login| [login, "@"].concat());
+    let incoming = target.incoming.trim_matches('/');
+    [scheme, &login, &target.fqdn, "/", incoming, "/"].concat()
+}
+
+/// The names of the files which the `Files` field of a `.changes` file lists.
+fn listed(changes: &str) -> Vec<&str> {
+    let mut files = Vec::new();
+    let mut in_files = false;
+    for line in changes.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if in_files {
+                files.extend(line.split_whitespace().nth(4));
+            }
+        } else {
+            in_files = line.starts_with("Files:");
+        }
+    }
+
+    files
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Links the file to the destination, or copies it where the spool is on another filesystem.
+fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::hard_link(src, dst).or_else(|_| fs::copy(src, dst).map(|_| ())).map_err(|why| {
+        io::Error::new(why.kind(), format!("failed to spool {}: {}", src.display(), why))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANGES: &str = "-----BEGIN PGP SIGNED MESSAGE-----\n\
+                           Hash: SHA512\n\n\
+                           Format: 1.8\n\
+                           Source: pop-shop\n\
+                           Checksums-Sha256:\n \
+                           ab 1024 pop-shop_1.0.dsc\n\
+                           Files:\n \
+                           0a 1024 admin optional pop-shop_1.0.dsc\n \
+                           1b 2048 admin optional pop-shop_1.0.tar.xz\n \
+                           2c 4096 admin optional pop-shop_1.0_amd64.deb\n\
+                           \n\
+                           -----BEGIN PGP SIGNATURE-----\n";
+
+    #[test]
+    fn changes_are_spooled_with_their_files() {
+        let dir = TempDir::new("debrep-upload").unwrap();
+        let outgoing = dir.path().join("outgoing");
+        fs::create_dir(&outgoing).unwrap();
+        misc::write(outgoing.join("pop-shop_1.0_amd64.changes"), CHANGES).unwrap();
+        for file in &["pop-shop_1.0.dsc", "pop-shop_1.0.tar.xz", "pop-shop_1.0_amd64.deb"] {
+            misc::write(outgoing.join(file), file).unwrap();
+        }
+
+        let spooled = spool(dir.path(), "bionic", &outgoing).unwrap();
+        assert_eq!(spooled, vec!["uploads/bionic/pop-shop_1.0_amd64/pop-shop_1.0_amd64.changes"]);
+
+        let changes = dir.path().join(&spooled[0]);
+        let files = files(&changes).unwrap();
+        let names: Vec<&str> = files.iter()
+            .map(|file| file.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec![
+            "pop-shop_1.0.dsc",
+            "pop-shop_1.0.tar.xz",
+            "pop-shop_1.0_amd64.deb",
+            "pop-shop_1.0_amd64.changes",
+        ]);
+        assert!(files.iter().all(|file| file.is_file()));
+
+        // Files which are missing are an error, rather than an upload which the archive rejects.
+        fs::remove_file(outgoing.join("pop-shop_1.0.tar.xz")).unwrap();
+        assert!(spool(dir.path(), "bionic", &outgoing).is_err());
+
+        let target = UploadTarget {
+            name: "internal-dak".to_owned(),
+            method: UploadMethod::Ftp,
+            fqdn: "dak.example.com".to_owned(),
+            incoming: "/incoming/".to_owned(),
+            login: Some("debrep".to_owned()),
+            signed: true,
+        };
+        assert_eq!(url(&target), "ftp://debrep@dak.example.com/incoming/");
+
+        misc::write(&changes, &CHANGES[35..]).unwrap();
+        let unsigned = "it is not signed, which internal-dak requires".to_owned();
+        assert_eq!(send(&target, &changes), Err(unsigned));
+    }
+}
@@ -2,6 +2,7 @@ use misc;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
 use std::path::Path;
+use std::process::Command;
 
 type Branch = String;
 type Commit = String;
@@ -18,7 +19,7 @@ pub fn git(project: &Path) -> io::Result<(Branch, Commit)> {
 
     let commit = misc::read_to_string(&project.join(&[".git/refs/heads/", &branch].concat()))?;
 
-    Ok((branch.to_owned(), commit))
+    Ok((branch.to_owned(), commit.trim().to_owned()))
 }
 
 pub fn changelog(path: &Path, retain: usize) -> io::Result<Vec<String>> {
@@ -27,6 +28,141 @@ pub fn changelog(path: &Path, retain: usize) -> io::Result<Vec<String>> {
         .map(|buf| changelog_inner(buf.lines().filter_map(|x| x.ok()), retain))
 }
 
+/// The date of the newest entry of a changelog, in seconds since the Unix epoch.
+pub fn changelog_date(path: &Path) -> io::Result<Option<u64>> {
+    File::open(path).map(BufReader::new).map(|buf| {
+        buf.lines()
+            .filter_map(|x| x.ok())
+            .find(|line| line.starts_with(" -- "))
+            .and_then(|line| line.rfind(">  ").and_then(|pos| parse_date(&line[pos + 3..])))
+    })
+}
+
+/// Parses an RFC 2822 date, such as `Mon, 16 Jul 2018 12:00:00 -0600`.
+fn parse_date(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let date = date.find(", ").map_or(date, |pos| &date[pos + 2..]);
+    let mut fields = date.split_whitespace();
+    let day = fields.next()?.parse::<i64>().ok()?;
+    let month = fields.next()?;
+    let month = MONTHS.iter().position(|&name| name == month)? as i64 + 1;
+    let year = fields.next()?.parse::<i64>().ok()?;
+
+    let mut time = fields.next()?.split(':').map(|x| x.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next().unwrap_or(Some(0))?);
+
+    let zone = fields.next()?;
+    if zone.len() != 5 {
+        return None;
+    }
+    let sign = match &zone[..1] { "+" => 1, "-" => -1, _ => return None };
+    let offset = zone[1..3].parse::<i64>().ok()? * 3600 + zone[3..].parse::<i64>().ok()? * 60;
+
+    // Days since the epoch of the civil date, from Howard Hinnant's `days_from_civil`.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second - sign * offset;
+    if seconds < 0 { None } else { Some(seconds as u64) }
+}
+
+/// The header of a changelog entry, such as `pop-shop (1.0-1) bionic; urgency=medium`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangelogEntry {
+    pub package: String,
+    pub version: String,
+    pub distributions: Vec<String>,
+}
+
+impl ChangelogEntry {
+    pub fn parse(line: &str) -> Option<ChangelogEntry> {
+        let header = line.splitn(2, ';').next()?;
+        let open = header.find(" (")?;
+        let close = open + header[open..].find(')')?;
+        Some(ChangelogEntry {
+            package: header[..open].trim().to_owned(),
+            version: header[open + 2..close].to_owned(),
+            distributions: header[close + 1..].split_whitespace().map(|x| x.to_owned()).collect(),
+        })
+    }
+
+    /// The newest entry of a changelog.
+    pub fn latest(changelog: &str) -> Option<ChangelogEntry> {
+        changelog.lines()
+            .find(|line| !line.trim().is_empty() && !line.starts_with(' '))
+            .and_then(ChangelogEntry::parse)
+    }
+}
+
+/// Compares two Debian versions with `dpkg --compare-versions`, by an operator such as `gt`.
+pub fn compare(a: &str, operator: &str, b: &str) -> io::Result<bool> {
+    Command::new("dpkg")
+        .args(&["--compare-versions", a, operator, b])
+        .status()
+        .map(|status| status.success())
+}
+
+/// Whether the version has the syntax of a Debian version, `[epoch:]upstream[-revision]`.
+pub fn is_valid(version: &str) -> bool {
+    let (epoch, rest) = match version.find(':') {
+        Some(pos) => (Some(&version[..pos]), &version[pos + 1..]),
+        None => (None, version),
+    };
+
+    let (upstream, revision) = match rest.rfind('-') {
+        Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+        None => (rest, None),
+    };
+
+    let valid_epoch = epoch.map_or(true, |epoch| {
+        !epoch.is_empty() && epoch.bytes().all(|b| b.is_ascii_digit())
+    });
+
+    let valid_revision = revision.map_or(true, |revision| {
+        !revision.is_empty()
+            && revision.bytes().all(|b| b.is_ascii_alphanumeric() || b"+.~".contains(&b))
+    });
+
+    let valid_upstream = upstream.bytes().next().map_or(false, |b| b.is_ascii_digit())
+        && upstream.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || b"+.~".contains(&b)
+                || (b == b'-' && revision.is_some())
+                || (b == b':' && epoch.is_some())
+        });
+
+    valid_epoch && valid_upstream && valid_revision
+}
+
+/// Appends the suffix to the version of the newest entry of the changelog, unless it already
+/// ends with it.
+pub fn append_suffix(path: &Path, suffix: &str) -> io::Result<()> {
+    let changelog = misc::read_to_string(path)?;
+    match with_suffix(&changelog, suffix) {
+        Some(changelog) => misc::write(path, changelog.as_bytes()),
+        None => Ok(()),
+    }
+}
+
+fn with_suffix(changelog: &str, suffix: &str) -> Option<String> {
+    let start = changelog.lines()
+        .find(|line| !line.trim().is_empty() && !line.starts_with(' '))
+        .map(|line| line.as_ptr() as usize - changelog.as_ptr() as usize)?;
+    let open = start + changelog[start..].find(" (")? + 2;
+    let close = open + changelog[open..].find(')')?;
+    if changelog[open..close].ends_with(suffix) {
+        return None;
+    }
+
+    Some([&changelog[..close], suffix, &changelog[close..]].concat())
+}
+
 fn changelog_inner<I: Iterator<Item = String>>(iter: I, retain: usize) -> Vec<String> {
     let iterator = iter.filter(|x| !x.starts_with(' '))
         .map(|x| {
@@ -48,6 +184,48 @@ fn changelog_inner<I: Iterator<Item = String>>(iter: I, retain: usize) -> Vec<St
 mod tests {
     use super::*;
 
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("Mon, 16 Jul 2018 12:00:00 -0600"), Some(1_531_764_000));
+        assert_eq!(parse_date("Thu, 01 Jan 1970 00:00:00 +0000"), Some(0));
+        assert_eq!(parse_date("29 Feb 2000 23:59:59 +0100"), Some(951_865_199));
+        assert_eq!(parse_date("Mon, 16 Jul 2018 12:00:00"), None);
+    }
+
+    #[test]
+    fn changelog_entries() {
+        assert_eq!(
+            ChangelogEntry::latest("\npop-shop (1.0-1) bionic cosmic; urgency=medium\n\n  * Hi\n"),
+            Some(ChangelogEntry {
+                package: "pop-shop".to_owned(),
+                version: "1.0-1".to_owned(),
+                distributions: vec!["bionic".to_owned(), "cosmic".to_owned()],
+            })
+        );
+        assert_eq!(ChangelogEntry::parse("not a header"), None);
+    }
+
+    #[test]
+    fn suffixes() {
+        let changelog = "pop-shop (1.0-1) bionic; urgency=medium\n\n  * Hi (again)\n";
+        let suffixed = with_suffix(changelog, "+stable").unwrap();
+        assert!(suffixed.starts_with("pop-shop (1.0-1+stable) bionic;"));
+        assert!(suffixed.ends_with("  * Hi (again)\n"));
+        assert_eq!(with_suffix(&suffixed, "+stable"), None);
+        assert_eq!(with_suffix("", "+stable"), None);
+    }
+
+    #[test]
+    fn versions() {
+        for version in &["1.0", "1:2.3-1", "1.0+dfsg-2ubuntu1~18.04.1", "2.1-1+pop1", "0pop2"] {
+            assert!(is_valid(version), "{} should be valid", version);
+        }
+
+        for version in &["", "pop1", "1.0-", "a:1.0", "1.0_1", ":1.0", "1.0 2"] {
+            assert!(!is_valid(version), "{} should be invalid", version);
+        }
+    }
+
     #[test]
     fn changelog() {
         const TEST: &str = r#"system76-cuda-9.2 (0pop2) bionic; urgency=medium
@@ -0,0 +1,199 @@
+//! Retrying operations which failed for reasons that may not last, such as a timeout.
+
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use interrupt;
+use reqwest;
+
+/// How many times network operations are attempted.
+pub const NETWORK_ATTEMPTS: u32 = 3;
+
+/// Seconds to wait before retrying a network operation, which doubles after each attempt.
+pub const NETWORK_BACKOFF: u64 = 2;
+
+/// Parts of error messages, in lowercase, which mean that the operation may succeed if it is
+/// attempted again: timeouts, connection resets, server errors, and contention for locks.
+const TRANSIENT: &[&str] = &[
+    "timed out",
+    "connection reset",
+    "reset by peer",
+    "server error",
+    "502 bad gateway",
+    "503 service unavailable",
+    "504 gateway",
+    "the requested url returned error: 5",
+    "could not get lock",
+    "unable to lock",
+    "index.lock",
+    "temporarily unavailable",
+];
+
+/// Errors which know whether the operation that failed is worth attempting again.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+/// Whether an error message describes a failure which may not last.
+pub fn is_transient(message: &str) -> bool {
+    let message = message.to_lowercase();
+    TRANSIENT.iter().any(|pattern| message.contains(pattern))
+}
+
+impl Retryable for io::Error {
+    fn is_retryable(&self) -> bool {
+        match self.kind() {
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::WouldBlock => true,
+            _ => is_transient(&self.to_string()),
+        }
+    }
+}
+
+impl Retryable for reqwest::Error {
+    fn is_retryable(&self) -> bool {
+        self.is_server_error() || is_transient(&self.to_string())
+    }
+}
+
+/// Performs the operation until it succeeds, it fails with an error which is not retryable, or
+/// it was attempted `attempts` times. The wait before each retry starts at `backoff`, and
+/// doubles after each attempt.
+pub fn retry<T, E, F>(
+    what: &str,
+    attempts: u32,
+    backoff: Duration,
+    mut operation: F,
+) -> Result<T, E>
+    where E: Retryable + fmt::Display,
+          F: FnMut() -> Result<T, E>
+{
+    let mut wait = backoff;
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Err(ref why) if should_retry(what, attempt, attempts, wait, why) => (),
+            result => return result,
+        }
+
+        thread::sleep(wait);
+        wait *= 2;
+        attempt += 1;
+    }
+}
+
+/// Whether an operation which failed on its `attempt` of `attempts` is attempted again after
+/// `wait`, which is logged if it is.
+pub fn should_retry<E>(what: &str, attempt: u32, attempts: u32, wait: Duration, why: &E) -> bool
+    where E: Retryable + fmt::Display
+{
+    if attempt >= attempts || !why.is_retryable() || interrupt::is_interrupted() {
+        return false;
+    }
+
+    warn!(
+        "attempt {} of {} to {} failed, retrying in {}s: {}",
+        attempt,
+        attempts,
+        what,
+        wait.as_secs(),
+        why
+    );
+
+    true
+}
+
+/// Retries a network operation with the default attempts and backoff.
+pub fn network<T, E, F>(what: &str, operation: F) -> Result<T, E>
+    where E: Retryable + fmt::Display,
+          F: FnMut() -> Result<T, E>
+{
+    retry(what, NETWORK_ATTEMPTS, Duration::from_secs(NETWORK_BACKOFF), operation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{CommandError, Failure};
+
+    fn exited(stderr: &str) -> CommandError {
+        let mut error = CommandError::new("git", &["fetch"], Failure::Exit(128));
+        error.stderr = stderr.to_owned();
+        error
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        let io = |kind, message: &str| io::Error::new(kind, message.to_owned());
+        assert!(io(io::ErrorKind::TimedOut, "").is_retryable());
+        assert!(io(io::ErrorKind::ConnectionReset, "").is_retryable());
+        assert!(io(io::ErrorKind::Other, "reqwest get failed: operation timed out").is_retryable());
+        assert!(!io(io::ErrorKind::NotFound, "request failed").is_retryable());
+        assert!(!io(io::ErrorKind::InvalidData, "checksum does not match for x").is_retryable());
+        assert!(!io(io::ErrorKind::PermissionDenied, "").is_retryable());
+
+        let retryable = [
+            "fatal: unable to access 'https://github.com/pop-os/shop/': The requested URL \
+             returned error: 503",
+            "error: RPC failed; curl 56 Recv failure: Connection reset by peer",
+            "fatal: unable to access 'https://github.com/pop-os/shop/': Failed to connect to \
+             github.com port 443: Connection timed out",
+            "fatal: Unable to create '/build/shop/.git/index.lock': File exists.",
+            "E: Could not get lock /var/lib/dpkg/lock - open (11: Resource temporarily \
+             unavailable)",
+        ];
+        for stderr in &retryable {
+            assert!(exited(stderr).is_retryable(), "{}", stderr);
+        }
+
+        let fatal = [
+            "fatal: couldn't find remote ref refs/heads/nope",
+            "fatal: repository 'https://github.com/pop-os/nope/' not found",
+            "fatal: Authentication failed for 'https://github.com/pop-os/shop/'",
+            "",
+        ];
+        for stderr in &fatal {
+            assert!(!exited(stderr).is_retryable(), "{}", stderr);
+        }
+
+        let none: &[&str] = &[];
+        assert!(!CommandError::new("sbuild", none, Failure::NotFound).is_retryable());
+        assert!(!CommandError::new("sbuild", none, Failure::Signal(9)).is_retryable());
+    }
+
+    #[test]
+    fn only_retryable_errors_are_retried() {
+        let mut attempts = 0;
+        let result: Result<u32, io::Error> = retry("test", 3, Duration::from_secs(0), || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        attempts = 0;
+        let result: Result<(), io::Error> = retry("test", 3, Duration::from_secs(0), || {
+            attempts += 1;
+            Err(io::Error::new(io::ErrorKind::NotFound, "missing"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        attempts = 0;
+        let result: Result<(), io::Error> = retry("test", 2, Duration::from_secs(0), || {
+            attempts += 1;
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+    }
+}
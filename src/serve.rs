@@ -0,0 +1,556 @@
+//! `debrep serve`, which keeps the config loaded, and builds packages when they are requested over
+//! HTTP, such as by a webhook handler which has no shell on the build machine.
+//!
+//! Every request must have `Authorization: Bearer <token>`, with the token of `[serve]`.
+//!
+//! - `POST /builds`, with `{"packages": [...], "suites": [...], "force": false, "priority": 0,
+//!   "preempt": false}`, queues a job which fetches, builds, and publishes the packages, as
+//!   `debrep run packages` does, and returns `{"job": <id>}`. Only `packages` must be given.
+//! - `GET /jobs` and `GET /jobs/<id>` return the state of the recent jobs, or of one, with the
+//!   position of each queued job in the queue.
+//! - `GET /runs?count=<n>` returns the reports of the most recent runs; ten by default.
+//! - `GET /logs/<package>` returns the latest build log of the package.
+//!
+//! Jobs are worked on one at a time, each while holding the repo lock, so that requests are queued
+//! instead of rejected while a job is in progress. The queued job of the highest priority is worked
+//! on next, and jobs of the same priority in the order which they were requested in. A job is at
+//! the priority which was requested, or else at the highest of its sources. A job which is
+//! requested with `preempt` is worked on straight away: the job in progress pauses after the
+//! package which it is building, and continues once the urgent job is done, in the same run. When
+//! debrep receives SIGINT or SIGTERM, it stops accepting requests, and the jobs in progress are
+//! interrupted as a run would be. Jobs which were still queued are cancelled.
+//!
+//! Each connection is answered on a thread of its own, so that a slow client does not hold up the
+//! others, with at most `MAX_CONNECTIONS` of them at once.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cli::Action;
+use debrepobuilder::{config, interrupt, BuildOptions, Preemption, Repo, RepoError, RepoLock};
+use serde_json;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8090";
+
+/// How many finished jobs are kept to be queried.
+const KEPT_JOBS: usize = 100;
+
+/// How many connections are answered at once. Those beyond it are turned away with a 503.
+const MAX_CONNECTIONS: usize = 16;
+
+/// The largest body of a request which is read.
+const MAX_BODY: usize = 64 * 1024;
+
+/// How often the listener and the worker check whether debrep was interrupted, in milliseconds.
+const POLL: u64 = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    Queued,
+    Running,
+    /// The job paused after the package which it was building, for an urgent job.
+    Paused,
+    Succeeded,
+    Failed,
+    Interrupted,
+    Cancelled,
+}
+
+/// A requested build, and what became of it.
+#[derive(Clone, Debug, Serialize)]
+struct Job {
+    id: u64,
+    packages: Vec<String>,
+    suites: Vec<String>,
+    force: bool,
+    priority: i32,
+    preempt: bool,
+    state: State,
+    /// The position of a queued job in the queue, from 1.
+    position: Option<usize>,
+    /// The run which the job was worked on in, whose logs are in `logs/runs/<run>/`.
+    run: Option<String>,
+    error: Option<String>,
+    /// Whether the priority was requested, so that the sources of the job are built at it.
+    #[serde(skip)]
+    requested_priority: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct BuildRequest {
+    packages: Vec<String>,
+    #[serde(default)]
+    suites: Vec<String>,
+    #[serde(default)]
+    force: bool,
+    priority: Option<i32>,
+    #[serde(default)]
+    preempt: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RunReport {
+    id: String,
+    report: String,
+}
+
+/// The jobs which were requested, oldest first, and the ID of the next job.
+#[derive(Debug, Default)]
+struct Jobs {
+    jobs: VecDeque<Job>,
+    next: u64,
+}
+
+impl Jobs {
+    fn get_mut(&mut self, id: u64) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    /// The queued jobs in the order which they will be worked on in: urgent jobs first, and then
+    /// by priority.
+    fn queue(&self) -> Vec<&Job> {
+        let mut queued: Vec<&Job> =
+            self.jobs.iter().filter(|job| job.state == State::Queued).collect();
+        queued.sort_by_key(|job| (!job.preempt, -job.priority, job.id));
+        queued
+    }
+
+    /// The job which the worker of urgent, or of other, jobs works on next.
+    fn next_queued(&self, urgent: bool) -> Option<Job> {
+        self.queue().into_iter().find(|job| job.preempt == urgent).cloned()
+    }
+
+    /// The jobs as they are reported, with the positions of those which are queued.
+    fn report(&self) -> Vec<Job> {
+        let positions: Vec<u64> = self.queue().iter().map(|job| job.id).collect();
+        let paused = Repo::builds_paused();
+        self.jobs.iter()
+            .map(|job| {
+                let mut job = job.clone();
+                job.position = positions.iter().position(|&id| id == job.id).map(|x| x + 1);
+                if paused && job.state == State::Running && !job.preempt {
+                    job.state = State::Paused;
+                }
+
+                job
+            })
+            .collect()
+    }
+
+    /// Forgets the oldest jobs which finished, beyond those which are kept.
+    fn trim(&mut self) {
+        while self.jobs.len() > KEPT_JOBS {
+            match self.jobs.iter().position(|job| job.state != State::Queued) {
+                Some(pos) => { self.jobs.remove(pos); }
+                None => break,
+            }
+        }
+    }
+}
+
+type Shared = Arc<(Mutex<Jobs>, Condvar)>;
+
+/// Serves requests until debrep is interrupted, and then waits for the job in progress to stop.
+pub fn serve(
+    sources: config::Config,
+    listen: Option<&str>,
+    options: BuildOptions,
+) -> Result<(), String> {
+    let settings = sources.serve.clone()
+        .ok_or_else(|| "`debrep serve` needs a [serve] table with a token".to_owned())?;
+    let address = listen.map(String::from)
+        .or_else(|| settings.listen.clone())
+        .unwrap_or_else(|| DEFAULT_LISTEN.to_owned());
+
+    let listener = TcpListener::bind(&address)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .map_err(|why| format!("failed to listen on {}: {}", address, why))?;
+    info!("listening on {}", address);
+
+    let repo = Arc::new(Repo::read_only(sources.clone()));
+    let token = Arc::new(settings.token.clone());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let shared: Shared = Arc::new((Mutex::new(Jobs::default()), Condvar::new()));
+    let workers: Vec<_> = [false, true].iter()
+        .map(|&urgent| {
+            let shared = shared.clone();
+            let sources = sources.clone();
+            thread::spawn(move || work_jobs(&sources, &shared, options, urgent))
+        })
+        .collect();
+
+    while !interrupt::is_interrupted() {
+        match listener.accept() {
+            Ok((mut stream, peer)) => {
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    let busy = error_body("too many requests are in progress");
+                    if let Err(why) = stream.set_nonblocking(false)
+                        .and_then(|_| reply(&mut stream, 503, &busy))
+                    {
+                        warn!("failed to turn away a request from {}: {}", peer, why);
+                    }
+                    continue;
+                }
+
+                let (repo, token, shared) = (repo.clone(), token.clone(), shared.clone());
+                let active = connections.clone();
+                let spawned = thread::Builder::new().name("debrep-request".into()).spawn(move || {
+                    if let Err(why) = answer(stream, &repo, &token, &shared) {
+                        warn!("failed to answer a request from {}: {}", peer, why);
+                    }
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+
+                if let Err(why) = spawned {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("failed to answer a request from {}: {}", peer, why);
+                }
+            }
+            Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(POLL))
+            }
+            Err(why) => warn!("failed to accept a connection: {}", why),
+        }
+    }
+
+    info!("no longer accepting requests; waiting for the jobs in progress to stop");
+    shared.1.notify_all();
+    for worker in workers {
+        worker.join().map_err(|_| "a worker of the jobs panicked".to_owned())?;
+    }
+
+    Ok(())
+}
+
+/// Works on each queued job in turn, of those which are urgent or of the others, until debrep is
+/// interrupted.
+fn work_jobs(sources: &config::Config, shared: &Shared, options: BuildOptions, urgent: bool) {
+    let &(ref jobs, ref queued) = &**shared;
+    loop {
+        let job = {
+            let mut guard = jobs.lock().unwrap();
+            loop {
+                if interrupt::is_interrupted() {
+                    for job in guard.jobs.iter_mut().filter(|job| job.state == State::Queued) {
+                        job.state = State::Cancelled;
+                    }
+                    return;
+                }
+
+                if let Some(job) = guard.next_queued(urgent) {
+                    guard.get_mut(job.id).unwrap().state = State::Running;
+                    break job;
+                }
+
+                guard = queued.wait_timeout(guard, Duration::from_millis(POLL)).unwrap().0;
+            }
+        };
+
+        info!("starting job {}: {}", job.id, job.packages.join(" "));
+        let (result, run) = work_job(sources, &job, options);
+
+        let mut guard = jobs.lock().unwrap();
+        if let Some(entry) = guard.get_mut(job.id) {
+            entry.run = run;
+            match result {
+                Ok(()) => entry.state = State::Succeeded,
+                Err(why) => {
+                    error!("job {} failed: {}", job.id, why);
+                    entry.state = if interrupt::is_interrupted() {
+                        State::Interrupted
+                    } else {
+                        State::Failed
+                    };
+                    entry.error = Some(why);
+                }
+            }
+        }
+        guard.trim();
+    }
+}
+
+/// Fetches, builds, and publishes the packages of the job, while holding the repo lock, and
+/// returns the run which the job was worked on in.
+fn work_job(
+    sources: &config::Config,
+    job: &Job,
+    options: BuildOptions,
+) -> (Result<(), String>, Option<String>) {
+    // An urgent job asks the job in progress to pause until it is done.
+    let _preemption = if job.preempt {
+        match Preemption::request(Path::new(".")) {
+            Ok(preemption) => Some(preemption),
+            Err(why) => {
+                return (Err(format!("failed to ask the job in progress to pause: {}", why)), None);
+            }
+        }
+    } else {
+        None
+    };
+
+    let _lock = match RepoLock::wait(Path::new(".")) {
+        Ok(Some(lock)) => lock,
+        Ok(None) => return (Err("interrupted while waiting for the repo lock".to_owned()), None),
+        Err(why) => return (Err(format!("failed to lock the repo: {}", why)), None),
+    };
+
+    // A job which preempted another is worked on in the run of the other, which ends it.
+    let joined = Repo::current_run();
+    let options = BuildOptions {
+        priority: if job.requested_priority { Some(job.priority) } else { None },
+        ..options
+    };
+
+    let packages: Vec<&str> = job.packages.iter().map(|x| x.as_str()).collect();
+    let suites: Vec<&str> = job.suites.iter().map(|x| x.as_str()).collect();
+    let result = super::plan(sources, &Action::Run(packages, job.force), &suites)
+        .and_then(|plan| super::work(plan, options).map_err(|why| why.to_string()));
+    let run = if joined.is_some() { joined } else { Repo::end_run() };
+    (result, run)
+}
+
+/// A request, as much of it as is needed.
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+    let mut reader = BufReader::new(stream.take(MAX_BODY as u64 + 16 * 1024));
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut fields = line.split_whitespace();
+    let method = fields.next().ok_or_else(|| invalid("the request has no method"))?.to_owned();
+    let target = fields.next().ok_or_else(|| invalid("the request has no path"))?;
+    let (path, query) = match target.find('?') {
+        Some(pos) => (target[..pos].to_owned(), target[pos + 1..].to_owned()),
+        None => (target.to_owned(), String::new()),
+    };
+
+    let mut authorization = None;
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+
+        let (name, value) = match header.find(':') {
+            Some(pos) => (header[..pos].trim().to_lowercase(), header[pos + 1..].trim()),
+            None => continue,
+        };
+
+        match name.as_str() {
+            "authorization" => authorization = Some(value.to_owned()),
+            "content-length" => {
+                length = value.parse().map_err(|_| invalid("the content length is invalid"))?;
+            }
+            _ => (),
+        }
+    }
+
+    if length > MAX_BODY {
+        return Err(invalid("the body of the request is too large"));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, query, authorization, body })
+}
+
+/// Compares the tokens in a time which does not depend on where they first differ.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn answer(mut stream: TcpStream, repo: &Repo, token: &str, shared: &Shared) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+    let request = match read_request(&stream) {
+        Ok(request) => request,
+        Err(why) => return reply(&mut stream, 400, &error_body(&why.to_string())),
+    };
+
+    let authorized = request.authorization.as_ref()
+        .and_then(|value| if value.starts_with("Bearer ") { Some(&value[7..]) } else { None })
+        .map_or(false, |given| same_token(given.trim(), token));
+    if !authorized {
+        return reply(&mut stream, 401, &error_body("a valid bearer token is required"));
+    }
+
+    let path: Vec<&str> = request.path.split('/').filter(|x| !x.is_empty()).collect();
+    let endpoint = (path.len(), path.get(0).cloned().unwrap_or(""));
+    match (request.method.as_str(), endpoint) {
+        ("POST", (1, "builds")) => {
+            let (status, body) = queue(repo.config(), shared, &request.body);
+            reply(&mut stream, status, &body)
+        }
+        ("GET", (1, "jobs")) => {
+            let jobs = shared.0.lock().unwrap().report();
+            reply(&mut stream, 200, &to_json(&jobs))
+        }
+        ("GET", (2, "jobs")) => {
+            let job = path[1].parse::<u64>().ok().and_then(|id| {
+                shared.0.lock().unwrap().report().into_iter().find(|job| job.id == id)
+            });
+            match job {
+                Some(job) => reply(&mut stream, 200, &to_json(&job)),
+                None => reply(&mut stream, 404, &error_body("there is no such job")),
+            }
+        }
+        ("GET", (1, "runs")) => {
+            let count = request.query.split('&')
+                .find(|pair| pair.starts_with("count="))
+                .and_then(|pair| pair[6..].parse().ok())
+                .unwrap_or(10);
+
+            match repo.recent_runs(count) {
+                Ok(runs) => {
+                    let runs: Vec<RunReport> =
+                        runs.into_iter().map(|(id, report)| RunReport { id, report }).collect();
+                    reply(&mut stream, 200, &to_json(&runs))
+                }
+                Err(why) => reply(&mut stream, 500, &error_body(&why.to_string())),
+            }
+        }
+        ("GET", (2, "logs")) if path[1].starts_with('.') => {
+            reply(&mut stream, 404, &error_body("there is no such package"))
+        }
+        ("GET", (2, "logs")) => match repo.latest_log(path[1]) {
+            Ok(log) => reply_with(&mut stream, 200, "text/plain; charset=utf-8", &log),
+            Err(RepoError::Log { ref why, .. }) if why.kind() == io::ErrorKind::NotFound => {
+                reply(&mut stream, 404, &error_body("the package has no build log"))
+            }
+            Err(why) => reply(&mut stream, 500, &error_body(&why.to_string())),
+        },
+        (_, (1, "builds")) | (_, (1, "jobs")) | (_, (2, "jobs")) | (_, (1, "runs"))
+        | (_, (2, "logs")) => reply(&mut stream, 405, &error_body("the method is not allowed")),
+        _ => reply(&mut stream, 404, &error_body("there is no such endpoint")),
+    }
+}
+
+/// Queues a job for the build which was requested, if it names packages of the config, and
+/// suites which they are built for.
+fn queue(sources: &config::Config, shared: &Shared, body: &[u8]) -> (u16, String) {
+    let request: BuildRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(why) => return (400, error_body(&format!("the request is invalid: {}", why))),
+    };
+
+    if request.packages.is_empty() {
+        return (400, error_body("no packages were given"));
+    }
+
+    let unknown = request.packages.iter()
+        .find(|package| !sources.package_exists(package.split('@').next().unwrap_or(package)));
+    if let Some(package) = unknown {
+        return (400, error_body(&format!("{} is not a package of this repo", package)));
+    }
+
+    let packages: Vec<&str> = request.packages.iter().map(|x| x.as_str()).collect();
+    let suites: Vec<&str> = request.suites.iter().map(|x| x.as_str()).collect();
+    if let Err(why) = super::plan(sources, &Action::Run(packages, request.force), &suites) {
+        return (400, error_body(&why));
+    }
+
+    // Jobs are at the highest priority of their sources, unless one was requested.
+    let priority = request.priority.unwrap_or_else(|| {
+        sources.source.iter()
+            .flat_map(|sources| sources.iter())
+            .filter(|source| {
+                request.packages.iter().any(|x| x.split('@').next() == Some(source.name.as_str()))
+            })
+            .map(|source| source.priority())
+            .max()
+            .unwrap_or(0)
+    });
+
+    let &(ref jobs, ref queued) = &**shared;
+    let mut jobs = jobs.lock().unwrap();
+    jobs.next += 1;
+    let id = jobs.next;
+    info!("queued job {} at priority {}: {}", id, priority, request.packages.join(" "));
+    jobs.jobs.push_back(Job {
+        id,
+        packages: request.packages,
+        suites: request.suites,
+        force: request.force,
+        priority,
+        preempt: request.preempt,
+        state: State::Queued,
+        position: None,
+        run: None,
+        error: None,
+        requested_priority: request.priority.is_some(),
+    });
+    jobs.trim();
+    queued.notify_all();
+
+    (202, format!("{{\"job\":{}}}", id))
+}
+
+fn to_json<T: ::serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|why| error_body(&why.to_string()))
+}
+
+fn error_body(message: &str) -> String {
+    #[derive(Serialize)]
+    struct Error<'a> {
+        error: &'a str,
+    }
+
+    serde_json::to_string(&Error { error: message }).unwrap_or_default()
+}
+
+fn reply(stream: &mut TcpStream, status: u16, body: &str) -> io::Result<()> {
+    reply_with(stream, status, "application/json", body.as_bytes())
+}
+
+fn reply_with(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    );
+    if status == 401 {
+        head.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
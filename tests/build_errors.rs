@@ -0,0 +1,67 @@
+extern crate debrepobuilder;
+extern crate tempdir;
+
+use debrepobuilder::{config, BuildError, BuildOptions, Repo, RepoError, Selection};
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use tempdir::TempDir;
+
+const SOURCES: &str = r#"archive = "bionic"
+version = "18.04"
+origin = "pop-os-staging-ubuntu"
+label = "Pop!_OS Staging"
+email = "debrepbuild@example.com"
+
+[[source]]
+name = "pop-shop"
+"#;
+
+fn build(repo: &Repo) -> Result<(), BuildError> {
+    match repo.build(&Selection::Packages(&["pop-shop"], false), BuildOptions::default()) {
+        Ok(summary) => panic!("expected pop-shop to fail to build, found {:?}", summary),
+        Err(RepoError::Build { mut failures, .. }) => {
+            assert_eq!(failures.len(), 1);
+            let (package, why) = failures.0.remove(0);
+            assert_eq!(package, "pop-shop");
+            Err(why)
+        }
+        Err(other) => panic!("expected a build error, found {:?}", other),
+    }
+}
+
+#[test]
+fn problems_with_the_build_environment_are_errors() {
+    let dir = TempDir::new("debrep-build-errors").unwrap();
+    env::set_current_dir(dir.path()).unwrap();
+    File::create("sources.toml").and_then(|mut file| file.write_all(SOURCES.as_bytes())).unwrap();
+    let repo = Repo::open(config::parse().unwrap()).unwrap();
+
+    // An asset which is a symlink to nothing is named in the error.
+    fs::create_dir_all("assets/packages/pop-shop").unwrap();
+    symlink("/nonexistent/debrep-asset", "assets/packages/pop-shop/missing").unwrap();
+    match build(&repo) {
+        Err(BuildError::AssetLink { ref file, .. }) => {
+            assert!(file.ends_with("assets/packages/pop-shop/missing"), "{:?}", file);
+        }
+        other => panic!("expected the dangling asset to be an error, found {:?}", other),
+    }
+    fs::remove_file("assets/packages/pop-shop/missing").unwrap();
+
+    // The permissions of a read-only directory are not enforced for root.
+    fs::remove_dir_all("build/workspaces").unwrap();
+    fs::set_permissions("build", fs::Permissions::from_mode(0o555)).unwrap();
+    let enforced = File::create("build/probe").is_err();
+    if enforced {
+        match build(&repo) {
+            Err(BuildError::Directory { ref path, .. }) => {
+                let workspace = path.to_string_lossy().contains("build/workspaces/bionic/");
+                assert!(workspace && path.ends_with("pop-shop"), "{:?}", path);
+            }
+            other => panic!("expected the read-only build directory to fail, found {:?}", other),
+        }
+    }
+
+    fs::set_permissions("build", fs::Permissions::from_mode(0o755)).unwrap();
+}
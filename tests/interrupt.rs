@@ -0,0 +1,45 @@
+extern crate debrepobuilder;
+extern crate tempdir;
+
+use debrepobuilder::{config, interrupt, BuildOptions, Repo, RepoError, Selection};
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use tempdir::TempDir;
+
+const SOURCES: &str = r#"archive = "bionic"
+version = "18.04"
+origin = "pop-os-staging-ubuntu"
+label = "Pop!_OS Staging"
+email = "debrepbuild@example.com"
+
+[[source]]
+name = "pop-shop"
+"#;
+
+#[test]
+fn interrupted_runs_start_no_more_work() {
+    let dir = TempDir::new("debrep-interrupt").unwrap();
+    env::set_current_dir(dir.path()).unwrap();
+    File::create("sources.toml").and_then(|mut file| file.write_all(SOURCES.as_bytes())).unwrap();
+    let repo = Repo::open(config::parse().unwrap()).unwrap();
+
+    interrupt::interrupt();
+    let selection = Selection::Packages(&["pop-shop"], false);
+    match repo.fetch(&selection, BuildOptions::default()) {
+        Err(RepoError::Interrupted { .. }) => (),
+        other => panic!("expected the fetch to be interrupted, found {:?}", other),
+    }
+
+    match repo.build(&selection, BuildOptions::default()) {
+        Err(RepoError::Interrupted { .. }) => (),
+        other => panic!("expected the build to be interrupted, found {:?}", other),
+    }
+
+    assert!(!Path::new("record/pop-shop").exists());
+    match repo.publish(BuildOptions::default()) {
+        Err(RepoError::Interrupted { .. }) => (),
+        other => panic!("expected publishing to be interrupted, found {:?}", other),
+    }
+}
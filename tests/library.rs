@@ -0,0 +1,73 @@
+extern crate debrepobuilder;
+extern crate sha2;
+extern crate tempdir;
+
+use debrepobuilder::{config, BuildOptions, Repo, RepoError, Selection, Status};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use tempdir::TempDir;
+
+const SOURCES: &str = r#"archive = "bionic"
+version = "18.04"
+origin = "pop-os-staging-ubuntu"
+label = "Pop!_OS Staging"
+email = "debrepbuild@example.com"
+
+[[source]]
+name = "pop-shop"
+
+[source.location]
+command = "echo artifact > ${path}"
+file = "pop-shop.tar.xz"
+checksum = "${checksum}"
+
+[[source]]
+name = "pop-icons"
+
+[source.location]
+command = "echo 'no such artifact: ${name}' >&2; exit 3"
+file = "pop-icons.tar.xz"
+checksum = "${checksum}"
+"#;
+
+#[test]
+fn a_repo_is_driven_through_the_library() {
+    // The library never changes the working directory, so the caller chooses the repo with it.
+    let dir = TempDir::new("debrep-library").unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    env::set_current_dir(&root).unwrap();
+
+    let checksum = format!("{:x}", Sha256::digest(b"artifact\n"));
+    File::create("sources.toml")
+        .and_then(|mut file| file.write_all(SOURCES.replace("${checksum}", &checksum).as_bytes()))
+        .unwrap();
+
+    let repo = Repo::open(config::parse().unwrap()).unwrap();
+    assert_eq!(repo.config().archive, "bionic");
+    assert!(Path::new("build").is_dir());
+
+    let options = BuildOptions::default();
+    let summary = repo.fetch(&Selection::Packages(&["pop-shop"], false), options).unwrap();
+    assert_eq!(summary.status_of("pop-shop"), Some(Status::Fetched));
+    assert!(Path::new("assets/cache/pop-shop_pop-shop.tar.xz").exists());
+
+    match repo.fetch(&Selection::All, options) {
+        Err(RepoError::Fetch { failures, summary }) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures.0[0].kind, "command");
+            assert_eq!(failures.0[0].origin, "`echo 'no such artifact: ${name}' >&2; exit 3`");
+            assert_eq!(summary.status_of("pop-shop"), Some(Status::Fetched));
+            assert_eq!(summary.status_of("pop-icons"), Some(Status::Failed));
+        }
+        other => panic!("expected pop-icons to fail to download, found {:?}", other),
+    }
+
+    // pop-icons was not fetched, so it is skipped instead of built.
+    let summary = repo.build(&Selection::Packages(&["pop-icons"], false), options).unwrap();
+    assert_eq!(summary.status_of("pop-icons"), Some(Status::Skipped));
+    assert_eq!(summary.count(Status::Built), 0);
+    assert_eq!(env::current_dir().unwrap(), root);
+}